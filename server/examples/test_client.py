@@ -19,7 +19,10 @@ PACKET_TYPE_DATA = 0x01
 PACKET_TYPE_KEEPALIVE = 0x05
 PACKET_TYPE_DISCONNECT = 0x06
 
-HEADER_SIZE = 24
+HEADER_SIZE = 26
+
+# Each packet sent gets the next sequence number; the server drops repeats
+next_sequence = 0
 
 def calculate_crc16(data):
     """Calculate CRC16-CCITT checksum"""
@@ -36,45 +39,36 @@ def calculate_crc16(data):
 
 def create_packet(packet_type, payload):
     """Create LLP packet"""
+    global next_sequence
     stream_id = 0
-    sequence_number = 0
+    sequence_number = next_sequence
+    next_sequence += 1
     timestamp = int(time.time() * 1000)
     flags = 0
 
     # Create header without checksum
     header = struct.pack(
-        '>HBHQQQB',
+        '>HBHQQBH',
         PROTOCOL_ID,
         packet_type,
         stream_id,
         sequence_number,
         timestamp,
-        flags
+        flags,
+        len(payload)
     )
 
     # Calculate checksum
     checksum = calculate_crc16(header + payload)
 
-    # Create complete header with checksum
-    header_with_checksum = struct.pack(
-        '>HBHQQBH',
-        PROTOCOL_ID,
-        packet_type,
-        stream_id,
-        sequence_number,
-        timestamp,
-        flags,
-        checksum
-    )
-
-    return header_with_checksum + payload
+    return header + struct.pack('>H', checksum) + payload
 
 def parse_packet(data):
     """Parse LLP packet"""
     if len(data) < HEADER_SIZE:
         raise ValueError(f"Packet too short: {len(data)} bytes")
 
-    header = struct.unpack('>HBHQQBH', data[:HEADER_SIZE])
+    header = struct.unpack('>HBHQQBHH', data[:HEADER_SIZE])
 
     protocol_id = header[0]
     packet_type = header[1]
@@ -82,9 +76,10 @@ def parse_packet(data):
     sequence_number = header[3]
     timestamp = header[4]
     flags = header[5]
-    checksum = header[6]
+    length = header[6]
+    checksum = header[7]
 
-    payload = data[HEADER_SIZE:]
+    payload = data[HEADER_SIZE:HEADER_SIZE + length]
 
     return {
         'protocol_id': protocol_id,
@@ -97,6 +92,22 @@ def parse_packet(data):
         'payload': payload
     }
 
+def recv_exact(sock, size):
+    """Read exactly size bytes"""
+    data = b''
+    while len(data) < size:
+        chunk = sock.recv(size - len(data))
+        if not chunk:
+            raise Exception("Connection closed by server")
+        data += chunk
+    return data
+
+def recv_packet(sock):
+    """Read one packet, framed by the length field of its header"""
+    header = recv_exact(sock, HEADER_SIZE)
+    length = struct.unpack('>H', header[22:24])[0]
+    return parse_packet(header + recv_exact(sock, length))
+
 def perform_handshake(sock):
     """Perform handshake with server"""
     print("[*] Starting handshake...")
@@ -122,13 +133,7 @@ def perform_handshake(sock):
 
     # Receive ServerHello
     print("[←] Waiting for ServerHello...")
-    data = sock.recv(4096)
-
-    if not data:
-        raise Exception("Connection closed by server")
-
-    # Parse packet
-    response = parse_packet(data)
+    response = recv_packet(sock)
 
     if response['packet_type'] != PACKET_TYPE_HANDSHAKE_RESPONSE:
         raise Exception(f"Unexpected packet type: {response['packet_type']}")
@@ -147,13 +152,12 @@ def send_keepalive(sock):
     print("[→] Sending keepalive")
     sock.sendall(packet)
 
-    # Receive response
-    data = sock.recv(4096)
-    response = parse_packet(data)
-
-    if response['packet_type'] == PACKET_TYPE_KEEPALIVE:
-        print("[✓] Keepalive response received")
-        return True
+    # Receive response, skipping config pushes and the like
+    for _ in range(8):
+        response = recv_packet(sock)
+        if response['packet_type'] == PACKET_TYPE_KEEPALIVE:
+            print("[✓] Keepalive response received")
+            return True
 
     return False
 
@@ -0,0 +1,1167 @@
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::PermissionsExt;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+use crate::admin::profile;
+use crate::admin::sessions::{self, SessionPage, SessionQuery};
+use crate::admin::top::{ThroughputSampler, TopSnapshot};
+use crate::config::Config;
+use crate::core::config_push::ConfigPublisher;
+use crate::core::connection::{Connection, ConnectionManager};
+use crate::core::diagnose::{Diagnosis, PathDiagnostics};
+use crate::core::drain::{DrainController, DrainStatus};
+use crate::core::file_transfer::{FileTransfers, TransferState, TransferStatus, STALL_TIMEOUT};
+use crate::core::management::Management;
+use crate::core::portal::{PortalReports, SessionPortal};
+use crate::core::quality::{QualityReports, UserQuality};
+use crate::core::session::SessionId;
+use crate::crypto::selftest::{self, SelfTestCheck};
+use crate::error::{LostLoveError, Result};
+use crate::logging::LogControl;
+use crate::metrics::ErrorCounters;
+use crate::network::relay::{Relay, RelayPathStats};
+use crate::network::{IpNet, SiteRoutes, UdpTransport};
+use crate::protocol::client_profile::ClientProfile;
+use crate::protocol::management::{ManagementCommand, ManagementReply};
+use crate::protocol::AnnouncedRoute;
+
+/// Minimum refresh interval accepted for streaming commands
+const MIN_STREAM_INTERVAL_MS: u64 = 100;
+
+/// Request sent by llpctl over the control socket (one JSON object per line)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    /// Stream per-session throughput sorted by bandwidth
+    Top {
+        #[serde(default = "default_top_interval_ms")]
+        interval_ms: u64,
+        #[serde(default = "default_top_limit")]
+        limit: usize,
+    },
+    /// Sessions matching filters, one page at a time
+    Sessions(SessionQuery),
+    /// Sessions whose labels match a search string
+    SearchSessions {
+        query: String,
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        cursor: Option<String>,
+    },
+    /// Switch per-packet tracing on or off for one session
+    Trace {
+        session_id: String,
+        enabled: bool,
+    },
+    /// Show or replace the active log filter
+    LogLevel {
+        #[serde(default)]
+        directives: Option<String>,
+    },
+    /// Error counts keyed by error code
+    Errors,
+    /// Re-read the config file and push changed client settings
+    Reload,
+    /// Set or remove labels on one session
+    Label {
+        session_id: String,
+        #[serde(default)]
+        set: BTreeMap<String, String>,
+        #[serde(default)]
+        remove: Vec<String>,
+    },
+    /// Refuse new handshakes and wind down existing sessions
+    Drain {
+        /// Disconnect remaining sessions after this many seconds
+        #[serde(default)]
+        deadline_secs: Option<u64>,
+        /// Server (`host:port`) refused clients should connect to instead
+        #[serde(default)]
+        redirect: Option<String>,
+    },
+    /// Report drain progress
+    DrainStatus,
+    /// Subnets learned from federated sites
+    Sites,
+    /// Traffic relayed between clients, per path
+    Relays,
+    /// Quality reported by clients, per user
+    Quality,
+    /// Captive portals reported by clients, per session
+    Portals,
+    /// Probe one session's path for round trip time, loss and path MTU
+    Diagnose { session_id: String },
+    /// Run the crypto known-answer checks again
+    Selftest,
+    /// Client profile of one user
+    ExportClient {
+        user: String,
+        /// Public host to put in the endpoints instead of `server.descriptor.hosts`
+        #[serde(default)]
+        host: Option<String>,
+    },
+    /// Send a server file to a session's client, streaming progress
+    Push {
+        session_id: String,
+        /// Absolute path on the server
+        local: PathBuf,
+        /// Path on the client
+        remote: String,
+    },
+    /// Fetch a file from a session's client, streaming progress
+    Pull {
+        session_id: String,
+        /// Path on the client
+        remote: String,
+        /// Absolute path on the server
+        local: PathBuf,
+    },
+    /// Send a signed management command to a session's client and wait for its answer
+    Manage {
+        session_id: String,
+        action: ManagementCommand,
+    },
+}
+
+/// Response written back over the control socket (one JSON object per line)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Top(TopSnapshot),
+    Sessions(SessionPage),
+    Drain(DrainStatus),
+    Errors { counts: BTreeMap<String, u64> },
+    Sites { sites: BTreeMap<String, Vec<AnnouncedRoute>> },
+    Relays { paths: Vec<RelayPathStats> },
+    Quality { users: Vec<UserQuality> },
+    Portals { sessions: Vec<SessionPortal> },
+    Diagnosis(Diagnosis),
+    Selftest { checks: Vec<SelfTestCheck> },
+    ClientProfile { profile: Box<ClientProfile> },
+    Transfer(TransferStatus),
+    ManagementReply(ManagementReply),
+    Ok { message: String },
+    Error { message: String },
+}
+
+fn default_top_interval_ms() -> u64 { 1000 }
+fn default_top_limit() -> usize { 20 }
+
+/// Local control socket serving admin commands
+pub struct ControlServer {
+    socket_path: PathBuf,
+    state: ControlState,
+}
+
+/// Server handles shared with every control client
+///
+/// Commands whose handle is missing answer with an error.
+#[derive(Clone)]
+struct ControlState {
+    connection_manager: Arc<ConnectionManager>,
+    log_control: Option<Arc<LogControl>>,
+    error_counters: Option<Arc<ErrorCounters>>,
+    config_reload: Option<ConfigReload>,
+    drain: Option<Arc<DrainController>>,
+    site_routes: Option<Arc<SiteRoutes>>,
+    relay: Option<Arc<Relay>>,
+    quality: Option<Arc<QualityReports>>,
+    portals: Option<Arc<PortalReports>>,
+    diagnose: Option<Diagnose>,
+    config: Option<Arc<Config>>,
+    file_transfers: Option<Arc<FileTransfers>>,
+    management: Option<Arc<Management>>,
+}
+
+impl ControlState {
+    fn new(connection_manager: Arc<ConnectionManager>) -> Self {
+        Self {
+            connection_manager,
+            log_control: None,
+            error_counters: None,
+            config_reload: None,
+            drain: None,
+            site_routes: None,
+            relay: None,
+            quality: None,
+            portals: None,
+            diagnose: None,
+            config: None,
+            file_transfers: None,
+            management: None,
+        }
+    }
+}
+
+/// Diagnostics and UDP transport used by the `diagnose` command
+#[derive(Clone)]
+struct Diagnose {
+    diagnostics: Arc<PathDiagnostics>,
+    udp_transport: Option<Arc<UdpTransport>>,
+}
+
+/// Config file and publisher used by the `reload` command
+#[derive(Clone)]
+struct ConfigReload {
+    publisher: Arc<ConfigPublisher>,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Create new control server
+    pub fn new(socket_path: impl Into<PathBuf>, connection_manager: Arc<ConnectionManager>) -> Self {
+        Self {
+            socket_path: socket_path.into(),
+            state: ControlState::new(connection_manager),
+        }
+    }
+
+    /// Enable the `log-level` command
+    pub fn with_log_control(mut self, log_control: Arc<LogControl>) -> Self {
+        self.state.log_control = Some(log_control);
+        self
+    }
+
+    /// Enable the `errors` command
+    pub fn with_error_counters(mut self, error_counters: Arc<ErrorCounters>) -> Self {
+        self.state.error_counters = Some(error_counters);
+        self
+    }
+
+    /// Enable the `reload` command
+    pub fn with_config_reload(mut self, publisher: Arc<ConfigPublisher>, path: impl Into<PathBuf>) -> Self {
+        self.state.config_reload = Some(ConfigReload {
+            publisher,
+            path: path.into(),
+        });
+        self
+    }
+
+    /// Enable the `drain` and `drain-status` commands
+    pub fn with_drain(mut self, drain: Arc<DrainController>) -> Self {
+        self.state.drain = Some(drain);
+        self
+    }
+
+    /// Enable the `sites` command
+    pub fn with_site_routes(mut self, site_routes: Arc<SiteRoutes>) -> Self {
+        self.state.site_routes = Some(site_routes);
+        self
+    }
+
+    /// Enable the `relays` command
+    pub fn with_relay(mut self, relay: Arc<Relay>) -> Self {
+        self.state.relay = Some(relay);
+        self
+    }
+
+    /// Enable the `diagnose` command, probing over UDP for sessions that attached it
+    pub fn with_diagnostics(
+        mut self,
+        diagnostics: Arc<PathDiagnostics>,
+        udp_transport: Option<Arc<UdpTransport>>,
+    ) -> Self {
+        self.state.diagnose = Some(Diagnose {
+            diagnostics,
+            udp_transport,
+        });
+        self
+    }
+
+    /// Enable the `quality` command
+    pub fn with_quality(mut self, quality: Arc<QualityReports>) -> Self {
+        self.state.quality = Some(quality);
+        self
+    }
+
+    /// Enable the `export-client` command
+    pub fn with_client_export(mut self, config: Arc<Config>) -> Self {
+        self.state.config = Some(config);
+        self
+    }
+
+    /// Enable the `portals` command
+    pub fn with_portals(mut self, portals: Arc<PortalReports>) -> Self {
+        self.state.portals = Some(portals);
+        self
+    }
+
+    /// Enable the `push` and `pull` commands
+    pub fn with_file_transfers(mut self, file_transfers: Arc<FileTransfers>) -> Self {
+        self.state.file_transfers = Some(file_transfers);
+        self
+    }
+
+    /// Enable the `manage` command
+    pub fn with_management(mut self, management: Arc<Management>) -> Self {
+        self.state.management = Some(management);
+        self
+    }
+
+    /// Bind the socket and serve clients until the task is cancelled
+    pub async fn run(self) -> Result<()> {
+        let listener = self.bind()?;
+
+        info!("Control socket listening on {}", self.socket_path.display());
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = self.state.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_client(stream, state).await {
+                            debug!("Control client error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to accept control connection: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Bind the Unix socket, replacing a stale socket file from a previous run
+    fn bind(&self) -> Result<UnixListener> {
+        if let Some(parent) = self.socket_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        if self.socket_path.exists() {
+            warn!("Removing stale control socket {}", self.socket_path.display());
+            std::fs::remove_file(&self.socket_path)?;
+        }
+
+        let listener = UnixListener::bind(&self.socket_path)?;
+
+        // The control socket grants full admin access, keep it owner-only
+        std::fs::set_permissions(&self.socket_path, std::fs::Permissions::from_mode(0o600))?;
+
+        Ok(listener)
+    }
+}
+
+/// Handle a single control client
+async fn handle_client(stream: UnixStream, state: ControlState) -> Result<()> {
+    let ControlState {
+        connection_manager,
+        log_control,
+        error_counters,
+        config_reload,
+        drain,
+        site_routes,
+        relay,
+        quality,
+        portals,
+        diagnose,
+        config,
+        file_transfers,
+        management,
+    } = state;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let response = ControlResponse::Error {
+                    message: format!("Invalid request: {}", e),
+                };
+                write_response(&mut writer, &response).await?;
+                continue;
+            }
+        };
+
+        debug!("Control request: {:?}", request);
+
+        match request {
+            ControlRequest::Top { interval_ms, limit } => {
+                // Streams until the client goes away
+                return stream_top(&mut writer, connection_manager, interval_ms, limit).await;
+            }
+            ControlRequest::Sessions(query) => {
+                let response = list_sessions(&connection_manager, &query).await;
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::SearchSessions { query, limit, cursor } => {
+                let query = SessionQuery {
+                    search: Some(query),
+                    limit,
+                    cursor,
+                    ..Default::default()
+                };
+                let response = list_sessions(&connection_manager, &query).await;
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Trace { session_id, enabled } => {
+                let response = set_tracing(&connection_manager, session_id, enabled);
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::LogLevel { directives } => {
+                let response = log_level(log_control.as_deref(), directives);
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Errors => {
+                let response = match &error_counters {
+                    Some(counters) => ControlResponse::Errors {
+                        counts: counters.snapshot(),
+                    },
+                    None => ControlResponse::Error {
+                        message: "Error counters are not available".to_string(),
+                    },
+                };
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Reload => {
+                let response = reload_config(config_reload.as_ref());
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Label { session_id, set, remove } => {
+                let response = label_session(&connection_manager, session_id, &set, &remove).await;
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Drain { deadline_secs, redirect } => {
+                let response = start_drain(&connection_manager, drain.as_ref(), deadline_secs, redirect);
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Sites => {
+                let response = match &site_routes {
+                    Some(site_routes) => ControlResponse::Sites {
+                        sites: site_routes.snapshot(),
+                    },
+                    None => ControlResponse::Error {
+                        message: "Federation is not enabled".to_string(),
+                    },
+                };
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Relays => {
+                let response = match &relay {
+                    Some(relay) => ControlResponse::Relays {
+                        paths: relay.snapshot(),
+                    },
+                    None => ControlResponse::Error {
+                        message: "Relaying is not enabled".to_string(),
+                    },
+                };
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Diagnose { session_id } => {
+                let response = diagnose_session(diagnose.as_ref(), &session_id).await;
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Quality => {
+                let response = match &quality {
+                    Some(quality) => ControlResponse::Quality {
+                        users: quality.snapshot(),
+                    },
+                    None => ControlResponse::Error {
+                        message: "Quality reports are not collected".to_string(),
+                    },
+                };
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Portals => {
+                let response = match &portals {
+                    Some(portals) => ControlResponse::Portals {
+                        sessions: portals.snapshot(),
+                    },
+                    None => ControlResponse::Error {
+                        message: "Captive portal reports are not collected".to_string(),
+                    },
+                };
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Selftest => {
+                let response = ControlResponse::Selftest { checks: selftest::run() };
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::ExportClient { user, host } => {
+                let response = export_client(config.as_deref(), config_reload.as_ref(), &user, host.as_deref());
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::DrainStatus => {
+                let response = match &drain {
+                    Some(drain) => ControlResponse::Drain(drain.status(connection_manager.active_count())),
+                    None => ControlResponse::Error {
+                        message: "Draining is not available".to_string(),
+                    },
+                };
+                write_response(&mut writer, &response).await?;
+            }
+            ControlRequest::Push { session_id, local, remote } => {
+                let started = start_transfer(file_transfers.as_deref(), &session_id, |transfers, session_id| {
+                    transfers.push(session_id, &local, &remote)
+                });
+                stream_transfer(&mut writer, file_transfers.as_deref(), started).await?;
+            }
+            ControlRequest::Pull { session_id, remote, local } => {
+                let started = start_transfer(file_transfers.as_deref(), &session_id, |transfers, session_id| {
+                    transfers.pull(session_id, &remote, &local)
+                });
+                stream_transfer(&mut writer, file_transfers.as_deref(), started).await?;
+            }
+            ControlRequest::Manage { session_id, action } => {
+                let response = manage_client(&connection_manager, management.as_deref(), &session_id, action).await;
+                write_response(&mut writer, &response).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// One page of sessions matching `query`
+async fn list_sessions(connection_manager: &ConnectionManager, query: &SessionQuery) -> ControlResponse {
+    match sessions::list(connection_manager, query).await {
+        Ok(page) => ControlResponse::Sessions(page),
+        Err(e) => ControlResponse::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Force per-packet tracing for a session
+fn set_tracing(connection_manager: &ConnectionManager, session_id: String, enabled: bool) -> ControlResponse {
+    match find_connection(connection_manager, &session_id) {
+        Some(connection) => {
+            connection.set_packet_tracing(enabled);
+
+            let message = format!(
+                "Packet tracing {} for session {}",
+                if enabled { "enabled" } else { "disabled" },
+                session_id
+            );
+            info!("{}", message);
+
+            ControlResponse::Ok { message }
+        }
+        None => ControlResponse::Error {
+            message: format!("Session not found: {}", session_id),
+        },
+    }
+}
+
+/// Probe a session's path, answering once the probes are done
+async fn diagnose_session(diagnose: Option<&Diagnose>, session_id: &str) -> ControlResponse {
+    let Some(diagnose) = diagnose else {
+        return ControlResponse::Error {
+            message: "Path diagnostics are not available".to_string(),
+        };
+    };
+    let Ok(parsed) = session_id.parse::<SessionId>() else {
+        return ControlResponse::Error {
+            message: format!("Session not found: {}", session_id),
+        };
+    };
+
+    match diagnose
+        .diagnostics
+        .diagnose(&parsed, diagnose.udp_transport.as_deref())
+        .await
+    {
+        Ok(diagnosis) => ControlResponse::Diagnosis(diagnosis),
+        Err(e) => ControlResponse::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Connection of a session ID given as text, None if malformed or unknown
+fn find_connection(connection_manager: &ConnectionManager, session_id: &str) -> Option<Arc<Connection>> {
+    let session_id: SessionId = session_id.parse().ok()?;
+    connection_manager.get_connection(&session_id)
+}
+
+/// Change a session's labels and report the resulting set
+async fn label_session(
+    connection_manager: &ConnectionManager,
+    session_id: String,
+    set: &BTreeMap<String, String>,
+    remove: &[String],
+) -> ControlResponse {
+    let Some(connection) = find_connection(connection_manager, &session_id) else {
+        return ControlResponse::Error {
+            message: format!("Session not found: {}", session_id),
+        };
+    };
+
+    match connection.session().update_labels(set, remove).await {
+        Ok(labels) => {
+            if !set.is_empty() || !remove.is_empty() {
+                info!("Labels of session {} changed to '{}'", session_id, labels);
+            }
+            ControlResponse::Ok {
+                message: format!("Session {} labels: {}", session_id, labels),
+            }
+        }
+        Err(e) => ControlResponse::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Put the server into draining mode
+fn start_drain(
+    connection_manager: &ConnectionManager,
+    drain: Option<&Arc<DrainController>>,
+    deadline_secs: Option<u64>,
+    redirect: Option<String>,
+) -> ControlResponse {
+    let Some(drain) = drain else {
+        return ControlResponse::Error {
+            message: "Draining is not available".to_string(),
+        };
+    };
+
+    let active_sessions = connection_manager.active_count();
+    match drain.start(active_sessions, deadline_secs.map(Duration::from_secs), redirect) {
+        Ok(()) => ControlResponse::Drain(drain.status(active_sessions)),
+        Err(e) => ControlResponse::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Show or replace the log filter
+fn log_level(log_control: Option<&LogControl>, directives: Option<String>) -> ControlResponse {
+    let Some(log_control) = log_control else {
+        return ControlResponse::Error {
+            message: "Runtime log level changes are not available".to_string(),
+        };
+    };
+
+    match directives {
+        None => ControlResponse::Ok {
+            message: format!("Log filter: {}", log_control.current()),
+        },
+        Some(directives) => match log_control.set(&directives) {
+            Ok(()) => {
+                info!("Log filter changed to '{}'", directives);
+                ControlResponse::Ok {
+                    message: format!("Log filter set to: {}", directives),
+                }
+            }
+            Err(e) => ControlResponse::Error {
+                message: e.to_string(),
+            },
+        },
+    }
+}
+
+/// Periodically write throughput snapshots to the client
+async fn stream_top<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    connection_manager: Arc<ConnectionManager>,
+    interval_ms: u64,
+    limit: usize,
+) -> Result<()> {
+    let mut sampler = ThroughputSampler::new(connection_manager);
+    let mut interval = time::interval(Duration::from_millis(interval_ms.max(MIN_STREAM_INTERVAL_MS)));
+
+    loop {
+        interval.tick().await;
+
+        let snapshot = sampler.sample(limit).await;
+
+        if let Err(e) = write_response(writer, &ControlResponse::Top(snapshot)).await {
+            debug!("Control client stopped reading top stream: {}", e);
+            return Ok(());
+        }
+    }
+}
+
+/// Send a management command, answering once the client did or gave up
+async fn manage_client(
+    connection_manager: &ConnectionManager,
+    management: Option<&Management>,
+    session_id: &str,
+    action: ManagementCommand,
+) -> ControlResponse {
+    let Some(management) = management else {
+        return ControlResponse::Error {
+            message: "Remote management is not enabled".to_string(),
+        };
+    };
+    let Some(connection) = find_connection(connection_manager, session_id) else {
+        return ControlResponse::Error {
+            message: format!("Session not found: {}", session_id),
+        };
+    };
+    if let ManagementCommand::Settings { routes, .. } = &action {
+        if let Some(Err(e)) = routes.iter().map(|route| route.parse::<IpNet>()).find(Result::is_err) {
+            return ControlResponse::Error { message: e.to_string() };
+        }
+    }
+
+    let user = connection.handshake().read().await.user().map(str::to_string);
+    match management.send(connection.session().id(), user.as_deref(), action).await {
+        Ok(reply) => ControlResponse::ManagementReply(reply),
+        Err(e) => ControlResponse::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Start a push or pull to a session given as text
+fn start_transfer(
+    file_transfers: Option<&FileTransfers>,
+    session_id: &str,
+    start: impl FnOnce(&FileTransfers, &SessionId) -> Result<watch::Receiver<TransferStatus>>,
+) -> std::result::Result<watch::Receiver<TransferStatus>, String> {
+    let file_transfers = file_transfers.ok_or_else(|| "File transfers are not available".to_string())?;
+    let parsed: SessionId = session_id
+        .parse()
+        .map_err(|_| format!("Session not found: {}", session_id))?;
+    start(file_transfers, &parsed).map_err(|e| e.to_string())
+}
+
+/// Write a transfer's progress until it completes or fails, failing it
+/// if the client stops making progress
+async fn stream_transfer<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    file_transfers: Option<&FileTransfers>,
+    started: std::result::Result<watch::Receiver<TransferStatus>, String>,
+) -> Result<()> {
+    let mut status = match started {
+        Ok(status) => status,
+        Err(message) => return write_response(writer, &ControlResponse::Error { message }).await,
+    };
+
+    loop {
+        let current = status.borrow_and_update().clone();
+        write_response(writer, &ControlResponse::Transfer(current.clone())).await?;
+        if current.state != TransferState::Active {
+            return Ok(());
+        }
+
+        // Progress between updates is coalesced
+        time::sleep(Duration::from_millis(MIN_STREAM_INTERVAL_MS)).await;
+        if time::timeout(STALL_TIMEOUT, status.changed()).await.is_err() {
+            if let Some(file_transfers) = file_transfers {
+                let reason = format!("No progress for {}s", STALL_TIMEOUT.as_secs());
+                file_transfers.fail(current.id, &reason);
+            }
+        }
+    }
+}
+
+/// Apply pushed client settings from the config file
+fn reload_config(config_reload: Option<&ConfigReload>) -> ControlResponse {
+    let Some(config_reload) = config_reload else {
+        return ControlResponse::Error {
+            message: "Config reload is not available".to_string(),
+        };
+    };
+
+    match config_reload.publisher.reload(&config_reload.path) {
+        Ok(true) => ControlResponse::Ok {
+            message: format!(
+                "Pushing config serial {} to connected clients",
+                config_reload.publisher.current().serial
+            ),
+        },
+        Ok(false) => ControlResponse::Ok {
+            message: "Pushed client settings unchanged".to_string(),
+        },
+        Err(e) => ControlResponse::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Profile of `user`, from the config file as it is now when it can be re-read
+fn export_client(
+    config: Option<&Config>,
+    config_reload: Option<&ConfigReload>,
+    user: &str,
+    host: Option<&str>,
+) -> ControlResponse {
+    let Some(config) = config else {
+        return ControlResponse::Error {
+            message: "Client export is not available".to_string(),
+        };
+    };
+
+    // Users added to the file since start can be exported without a restart
+    let current = match config_reload {
+        Some(config_reload) => match Config::load(&config_reload.path) {
+            Ok(current) => Some(current),
+            Err(e) => {
+                return ControlResponse::Error {
+                    message: format!("Failed to read {}: {:#}", config_reload.path.display(), e),
+                }
+            }
+        },
+        None => None,
+    };
+
+    match profile::client_profile(current.as_ref().unwrap_or(config), user, host) {
+        Ok(profile) => ControlResponse::ClientProfile { profile: Box::new(profile) },
+        Err(e) => ControlResponse::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Write one response line
+async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, response: &ControlResponse) -> Result<()> {
+    let mut data = serde_json::to_vec(response)
+        .map_err(|e| LostLoveError::Admin(format!("Serialization error: {}", e)))?;
+    data.push(b'\n');
+
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    fn test_socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("llp-{}-{}.sock", name, uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_request_parsing() {
+        let request: ControlRequest = serde_json::from_str(r#"{"command":"top"}"#).unwrap();
+
+        match request {
+            ControlRequest::Top { interval_ms, limit } => {
+                assert_eq!(interval_ms, 1000);
+                assert_eq!(limit, 20);
+            }
+            other => panic!("Unexpected request: {:?}", other),
+        }
+
+        assert!(matches!(
+            serde_json::from_str::<ControlRequest>(r#"{"command":"errors"}"#).unwrap(),
+            ControlRequest::Errors
+        ));
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"command":"bogus"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_request() {
+        let manager = ConnectionManager::new(10);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let _conn = manager.create_connection(addr).unwrap();
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"command":"sessions","ip":"127.0.0.0/8","sort":"bandwidth","limit":5}"#)
+                .unwrap();
+        let ControlRequest::Sessions(query) = request else {
+            panic!("Unexpected request: {:?}", request);
+        };
+        match list_sessions(&manager, &query).await {
+            ControlResponse::Sessions(page) => assert_eq!(page.matched, 1),
+            other => panic!("Unexpected response: {:?}", other),
+        }
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"command":"search-sessions","query":"team=ops"}"#).unwrap();
+        assert!(matches!(request, ControlRequest::SearchSessions { ref query, .. } if query == "team=ops"));
+
+        let query = SessionQuery {
+            state: Some("bogus".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(list_sessions(&manager, &query).await, ControlResponse::Error { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_unknown_session() {
+        use crate::core::config_push::ConfigPublisher;
+
+        assert!(matches!(diagnose_session(None, "bogus").await, ControlResponse::Error { .. }));
+
+        let publisher = ConfigPublisher::new(&Config::default_for_testing());
+        let diagnose = Diagnose {
+            diagnostics: Arc::new(PathDiagnostics::new(publisher.subscribe())),
+            udp_transport: None,
+        };
+        let response = diagnose_session(Some(&diagnose), &SessionId::new().to_string()).await;
+        assert!(matches!(response, ControlResponse::Error { message } if message.starts_with("Session not found")));
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"command":"diagnose","session_id":"abc"}"#).unwrap();
+        assert!(matches!(request, ControlRequest::Diagnose { ref session_id } if session_id == "abc"));
+    }
+
+    #[test]
+    fn test_reload_config() {
+        use crate::config::Config;
+
+        assert!(matches!(reload_config(None), ControlResponse::Error { .. }));
+
+        let path = std::env::temp_dir().join(format!("llp-control-reload-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[server]\n[network]\n[push]\ndns = [\"9.9.9.9\"]\n").unwrap();
+
+        let config_reload = ConfigReload {
+            publisher: Arc::new(ConfigPublisher::new(&Config::default_for_testing())),
+            path: path.clone(),
+        };
+
+        match reload_config(Some(&config_reload)) {
+            ControlResponse::Ok { message } => assert!(message.contains("serial 2")),
+            other => panic!("Unexpected response: {:?}", other),
+        }
+        assert!(matches!(
+            reload_config(Some(&config_reload)),
+            ControlResponse::Ok { message } if message.contains("unchanged")
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_top_over_socket() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let _conn = manager.create_connection(addr).unwrap();
+
+        let path = test_socket_path("top");
+        let server = ControlServer::new(&path, manager);
+        let listener = server.bind().unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_client(stream, server.state.clone()).await
+        });
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(b"{\"command\":\"top\",\"interval_ms\":100,\"limit\":5}\n")
+            .await
+            .unwrap();
+
+        let mut lines = BufReader::new(reader).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+
+        match serde_json::from_str::<ControlResponse>(&line).unwrap() {
+            ControlResponse::Top(snapshot) => {
+                assert_eq!(snapshot.active_sessions, 1);
+                assert_eq!(snapshot.sessions.len(), 1);
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        }
+
+        drop(lines);
+        drop(writer);
+        handle.await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_set_tracing() {
+        let manager = ConnectionManager::new(10);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        let session_id = conn.session().id().to_string();
+
+        let response = set_tracing(&manager, session_id, true);
+        assert!(matches!(response, ControlResponse::Ok { .. }));
+        assert!(conn.packet_tracing());
+
+        let response = set_tracing(&manager, "missing".to_string(), true);
+        assert!(matches!(response, ControlResponse::Error { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_label_session() {
+        let manager = ConnectionManager::new(10);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        let session_id = conn.session().id().to_string();
+
+        let request: ControlRequest = serde_json::from_str(&format!(
+            r#"{{"command":"label","session_id":"{}","set":{{"team":"ops"}}}}"#,
+            session_id
+        ))
+        .unwrap();
+        let ControlRequest::Label { session_id, set, remove } = request else {
+            panic!("Unexpected request: {:?}", request);
+        };
+
+        let response = label_session(&manager, session_id.clone(), &set, &remove).await;
+        assert!(matches!(response, ControlResponse::Ok { message } if message.ends_with("team=ops")));
+        assert_eq!(conn.session().labels().await.get("team"), Some("ops"));
+
+        let bad = BTreeMap::from([("Team".to_string(), "x".to_string())]);
+        let response = label_session(&manager, session_id, &bad, &[]).await;
+        assert!(matches!(response, ControlResponse::Error { .. }));
+
+        let response = label_session(&manager, "missing".to_string(), &set, &[]).await;
+        assert!(matches!(response, ControlResponse::Error { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_start_drain() {
+        let manager = ConnectionManager::new(10);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let _conn = manager.create_connection(addr).unwrap();
+
+        assert!(matches!(start_drain(&manager, None, None, None), ControlResponse::Error { .. }));
+
+        let drain = Arc::new(DrainController::new());
+        match start_drain(&manager, Some(&drain), Some(600), Some("llp2:8443".to_string())) {
+            ControlResponse::Drain(status) => {
+                assert!(status.draining);
+                assert_eq!(status.sessions_at_start, 1);
+                assert_eq!(status.deadline_secs_left, Some(600));
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        }
+        assert!(matches!(
+            start_drain(&manager, Some(&drain), None, None),
+            ControlResponse::Error { .. }
+        ));
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"command":"drain","deadline_secs":60}"#).unwrap();
+        assert!(matches!(request, ControlRequest::Drain { deadline_secs: Some(60), redirect: None }));
+    }
+
+    #[test]
+    fn test_log_level_without_control() {
+        let response = log_level(None, Some("debug".to_string()));
+        assert!(matches!(response, ControlResponse::Error { .. }));
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"command":"log-level","directives":"debug"}"#).unwrap();
+        assert!(matches!(
+            request,
+            ControlRequest::LogLevel { directives: Some(ref d) } if d == "debug"
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_transfer_fails() {
+        use crate::core::channels::Channels;
+
+        let started = start_transfer(None, "bogus", |_, _| unreachable!());
+        assert_eq!(started.unwrap_err(), "File transfers are not available");
+
+        let channels = Arc::new(Channels::new());
+        let transfers = FileTransfers::register(&channels).unwrap();
+        let started = start_transfer(Some(&transfers), "bogus", |_, _| unreachable!());
+        assert!(started.unwrap_err().starts_with("Session not found"));
+
+        // The client never answers the request
+        let session_id = SessionId::new();
+        let _mailbox = channels.attach(&session_id);
+        let local = std::env::temp_dir().join(format!("llp-control-pull-{}", uuid::Uuid::new_v4()));
+        let started = start_transfer(Some(&transfers), &session_id.to_string(), |transfers, session_id| {
+            transfers.pull(session_id, "/etc/hosts", &local)
+        });
+
+        let mut output = Vec::new();
+        stream_transfer(&mut output, Some(&transfers), started).await.unwrap();
+        let updates: Vec<ControlResponse> = output
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(updates.len(), 2);
+        match &updates[1] {
+            ControlResponse::Transfer(status) => {
+                assert_eq!(status.state, TransferState::Failed);
+                assert_eq!(status.error.as_deref(), Some("No progress for 30s"));
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        }
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"command":"push","session_id":"abc","local":"/srv/a.conf","remote":"/etc/a.conf"}"#)
+                .unwrap();
+        assert!(matches!(request, ControlRequest::Push { ref remote, .. } if remote == "/etc/a.conf"));
+        let _ = std::fs::remove_file(format!("{}.part", local.display()));
+    }
+
+    #[tokio::test]
+    async fn test_manage_client_checks() {
+        use crate::config::ManagementConfig;
+        use crate::core::channels::Channels;
+
+        let manager = ConnectionManager::new(10);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        let session_id = conn.session().id().to_string();
+
+        let response = manage_client(&manager, None, &session_id, ManagementCommand::Diagnostics).await;
+        assert!(matches!(response, ControlResponse::Error { message } if message.contains("not enabled")));
+
+        let config = ManagementConfig {
+            enabled: true,
+            key: "11".repeat(32),
+            audit_log: String::new(),
+            reply_timeout: 5,
+        };
+        let channels = Arc::new(Channels::new());
+        let management = Management::register(&config, &channels).unwrap().unwrap();
+
+        let response = manage_client(&manager, Some(&management), "missing", ManagementCommand::Diagnostics).await;
+        assert!(matches!(response, ControlResponse::Error { message } if message.starts_with("Session not found")));
+
+        let settings = ManagementCommand::Settings {
+            routes: vec!["10.20.0.0/33".to_string()],
+            dns: Vec::new(),
+        };
+        let response = manage_client(&manager, Some(&management), &session_id, settings).await;
+        assert!(matches!(response, ControlResponse::Error { message } if message.contains("10.20.0.0/33")));
+
+        let response = manage_client(&manager, Some(&management), &session_id, ManagementCommand::Diagnostics).await;
+        assert!(matches!(response, ControlResponse::Error { message } if message.contains("opted in")));
+
+        let request: ControlRequest = serde_json::from_str(
+            r#"{"command":"manage","session_id":"abc","action":{"command":"reconnect","delay_secs":5}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            request,
+            ControlRequest::Manage { action: ManagementCommand::Reconnect { delay_secs: 5 }, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_returns_error() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let path = test_socket_path("invalid");
+        let server = ControlServer::new(&path, manager);
+        let listener = server.bind().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_client(stream, server.state.clone()).await;
+        });
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"not json\n").await.unwrap();
+
+        let mut lines = BufReader::new(reader).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(matches!(
+            serde_json::from_str::<ControlResponse>(&line).unwrap(),
+            ControlResponse::Error { .. }
+        ));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
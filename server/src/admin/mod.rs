@@ -0,0 +1,6 @@
+pub mod control;
+pub mod top;
+pub mod profile;
+pub mod sessions;
+
+pub use control::ControlServer;
@@ -0,0 +1,134 @@
+use std::net::SocketAddr;
+
+use crate::config::Config;
+use crate::error::{LostLoveError, Result};
+use crate::network::descriptor_server::{endpoints, DESCRIPTOR_PATH};
+use crate::protocol::client_profile::{ClientProfile, HoppingProfile, KnockProfile, LockdownProfile};
+use crate::protocol::descriptor::public_key;
+use crate::protocol::management::user_key;
+
+/// Client profile of a user in `[policies]`
+///
+/// Endpoints are those of `host`, or of `server.descriptor.hosts` when no
+/// host is given. Secrets are the server-wide ones every client shares,
+/// except the management key, which is the user's own.
+pub fn client_profile(config: &Config, user: &str, host: Option<&str>) -> Result<ClientProfile> {
+    let Some(policy) = config.policies.get(user) else {
+        return Err(LostLoveError::Admin(format!("Unknown user {:?}, add [policies.{}] first", user, user)));
+    };
+
+    let hosts = match host {
+        Some(host) => vec![host.to_string()],
+        None => config.server.descriptor.hosts.clone(),
+    };
+    if hosts.is_empty() {
+        return Err(LostLoveError::Admin(
+            "No public host: pass one or set server.descriptor.hosts".to_string(),
+        ));
+    }
+
+    let server = &config.server;
+    let descriptor = &server.descriptor;
+    let descriptor_port = descriptor
+        .address
+        .parse::<SocketAddr>()
+        .map_or(80, |address| address.port());
+    // Clients only verify descriptors, the private key stays here
+    let descriptor_key = if descriptor.enabled {
+        let seed = hex::decode(&descriptor.key)
+            .map_err(|_| LostLoveError::Admin("server.descriptor.key is not hex".to_string()))?;
+        Some(hex::encode(public_key(&seed)?))
+    } else {
+        None
+    };
+    let management = &config.admin.management;
+    let management_key = if management.enabled {
+        let key = hex::decode(&management.key)
+            .map_err(|_| LostLoveError::Admin("admin.management.key is not hex".to_string()))?;
+        Some(hex::encode(&*user_key(&key, user)?))
+    } else {
+        None
+    };
+
+    Ok(ClientProfile {
+        user: user.to_string(),
+        endpoints: endpoints(config, &hosts).iter().map(ToString::to_string).collect(),
+        mtu: config.network.mtu,
+        routes: config.push.routes.clone(),
+        dns: config.push.dns.clone(),
+        server_key: server.obfuscation.enabled.then(|| server.obfuscation.server_key.clone()),
+        descriptor_url: descriptor
+            .enabled
+            .then(|| format!("http://{}:{}{}", hosts[0], descriptor_port, DESCRIPTOR_PATH)),
+        descriptor_key,
+        management_key,
+        knock: server.knock.enabled.then(|| KnockProfile {
+            port: server.knock.port,
+            secret: server.knock.secret.clone(),
+        }),
+        port_hopping: server.port_hopping.enabled.then(|| HoppingProfile {
+            secret: server.port_hopping.secret.clone(),
+            port_min: server.port_hopping.port_min,
+            port_max: server.port_hopping.port_max,
+            interval: server.port_hopping.interval,
+        }),
+        lockdown: policy.lockdown.then_some(LockdownProfile {
+            allow_lan: policy.lockdown_allow_lan,
+        }),
+        // Left for the user to fill in
+        lan: None,
+        split: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserPolicy;
+
+    fn config_with_alice() -> Config {
+        let mut config = Config::default_for_testing();
+        config.policies.insert("alice".to_string(), UserPolicy::default());
+        config
+    }
+
+    #[test]
+    fn test_profile_of_known_user() {
+        let mut config = config_with_alice();
+        config.server.descriptor.enabled = true;
+        config.server.descriptor.hosts = vec!["vpn.example.org".to_string()];
+        config.server.descriptor.key = "22".repeat(32);
+        config.server.knock.enabled = true;
+        config.server.knock.secret = "knock secret".to_string();
+
+        let profile = client_profile(&config, "alice", None).unwrap();
+        assert_eq!(profile.endpoints, ["tcp://vpn.example.org:8443"]);
+        assert_eq!(profile.mtu, config.network.mtu);
+        assert_eq!(profile.descriptor_url.as_deref(), Some("http://vpn.example.org:8080/descriptor.json"));
+        let descriptor_key = hex::encode(public_key(&[0x22; 32]).unwrap());
+        assert_eq!(profile.descriptor_key, Some(descriptor_key));
+        assert_eq!(profile.knock.unwrap().secret, "knock secret");
+        assert_eq!(profile.server_key, None);
+        assert_eq!(profile.port_hopping, None);
+        assert_eq!(profile.management_key, None);
+        assert_eq!(profile.lockdown, None);
+
+        config.admin.management.enabled = true;
+        config.admin.management.key = "33".repeat(32);
+        let alice = config.policies.get_mut("alice").unwrap();
+        alice.lockdown = true;
+        alice.lockdown_allow_lan = true;
+        let profile = client_profile(&config, "alice", Some("203.0.113.5")).unwrap();
+        assert_eq!(profile.endpoints, ["tcp://203.0.113.5:8443"]);
+        let management_key = user_key(&[0x33; 32], "alice").unwrap();
+        assert_eq!(profile.management_key, Some(hex::encode(&*management_key)));
+        assert_eq!(profile.lockdown, Some(LockdownProfile { allow_lan: true }));
+    }
+
+    #[test]
+    fn test_unknown_user_or_host() {
+        let config = config_with_alice();
+        assert!(client_profile(&config, "mallory", Some("vpn.example.org")).is_err());
+        assert!(client_profile(&config, "alice", None).is_err());
+    }
+}
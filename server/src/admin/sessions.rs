@@ -0,0 +1,485 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use crate::core::connection::ConnectionManager;
+use crate::core::session::SessionState;
+use crate::error::{LostLoveError, Result};
+use crate::network::IpNet;
+
+/// Sessions in a page when the query sets no limit
+const DEFAULT_PAGE_LIMIT: usize = 100;
+
+/// Largest page a query may ask for
+const MAX_PAGE_LIMIT: usize = 1000;
+
+/// Order of a session listing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionSort {
+    #[default]
+    Id,
+    User,
+    Uptime,
+    Bandwidth,
+    Bytes,
+}
+
+/// Filters, order and page of a `sessions` request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionQuery {
+    /// Only sessions of this user
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Only sessions whose peer or tunnel address is this address or in this subnet
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// Only sessions in this state (`handshaking`, `active`, ...)
+    #[serde(default)]
+    pub state: Option<String>,
+    /// Only sessions averaging at least this many bytes per second
+    #[serde(default)]
+    pub min_bandwidth: Option<u64>,
+    /// Label terms that must all match, see [`LabelSearch`]
+    #[serde(default)]
+    pub search: Option<String>,
+    #[serde(default)]
+    pub sort: SessionSort,
+    #[serde(default)]
+    pub descending: bool,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// One session of a listing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionEntry {
+    pub session_id: String,
+    pub user: Option<String>,
+    pub peer: String,
+    pub tunnel_address: Option<String>,
+    pub state: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Both directions averaged over the session's uptime, in bytes per second
+    pub bandwidth: u64,
+    pub uptime_secs: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// One page of a listing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionPage {
+    /// Sessions matching the filters, over all pages
+    pub matched: usize,
+    pub sessions: Vec<SessionEntry>,
+    /// Cursor of the next page, None on the last one
+    pub next_cursor: Option<String>,
+}
+
+/// A session with the fields filters look at
+struct Row {
+    entry: SessionEntry,
+    peer_ip: IpAddr,
+    tunnel_ip: Option<IpAddr>,
+    state: SessionState,
+}
+
+/// Free-text search over session labels
+///
+/// Whitespace separates terms and every term has to match: `key=value`
+/// matches that label exactly, anything else matches any label key or value
+/// containing it (ignoring case).
+#[derive(Debug, Clone, Default)]
+pub struct LabelSearch {
+    terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Exact(String, String),
+    Contains(String),
+}
+
+impl LabelSearch {
+    /// Parse a search string
+    pub fn new(search: &str) -> Self {
+        let terms = search
+            .split_whitespace()
+            .map(|term| match term.split_once('=') {
+                Some((key, value)) => Term::Exact(key.to_string(), value.to_string()),
+                None => Term::Contains(term.to_lowercase()),
+            })
+            .collect();
+        Self { terms }
+    }
+
+    /// Whether `labels` match every term
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.terms.iter().all(|term| match term {
+            Term::Exact(key, value) => labels.get(key) == Some(value),
+            Term::Contains(text) => labels
+                .iter()
+                .any(|(key, value)| key.contains(text.as_str()) || value.to_lowercase().contains(text.as_str())),
+        })
+    }
+}
+
+/// Filters of a query, parsed and checked
+struct Filter {
+    user: Option<String>,
+    net: Option<IpNet>,
+    state: Option<SessionState>,
+    min_bandwidth: u64,
+    search: LabelSearch,
+}
+
+impl Filter {
+    fn new(query: &SessionQuery) -> Result<Self> {
+        let net = query.ip.as_deref().map(parse_net).transpose()?;
+        let state = match query.state.as_deref() {
+            Some(name) => Some(
+                SessionState::ALL
+                    .into_iter()
+                    .find(|state| state.as_str() == name)
+                    .ok_or_else(|| LostLoveError::Admin(format!("Unknown session state: {}", name)))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            user: query.user.clone(),
+            net,
+            state,
+            min_bandwidth: query.min_bandwidth.unwrap_or(0),
+            search: query.search.as_deref().map(LabelSearch::new).unwrap_or_default(),
+        })
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        self.user.as_ref().is_none_or(|user| row.entry.user.as_ref() == Some(user))
+            && self
+                .net
+                .is_none_or(|net| net.contains(row.peer_ip) || row.tunnel_ip.is_some_and(|ip| net.contains(ip)))
+            && self.state.is_none_or(|state| row.state == state)
+            && row.entry.bandwidth >= self.min_bandwidth
+            && self.search.matches(&row.entry.labels)
+    }
+}
+
+/// A single address or a subnet
+fn parse_net(ip: &str) -> Result<IpNet> {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(address)) => format!("{}/32", address).parse(),
+        Ok(IpAddr::V6(address)) => format!("{}/128", address).parse(),
+        Err(_) => ip.parse(),
+    }
+}
+
+/// Value a listing is ordered by, session ID breaking ties
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum SortKey {
+    Number(u64),
+    Text(String),
+}
+
+impl SessionSort {
+    fn is_numeric(self) -> bool {
+        matches!(self, SessionSort::Uptime | SessionSort::Bandwidth | SessionSort::Bytes)
+    }
+
+    fn key(self, entry: &SessionEntry) -> SortKey {
+        match self {
+            SessionSort::Id => SortKey::Text(String::new()),
+            SessionSort::User => SortKey::Text(entry.user.clone().unwrap_or_default()),
+            SessionSort::Uptime => SortKey::Number(entry.uptime_secs),
+            SessionSort::Bandwidth => SortKey::Number(entry.bandwidth),
+            SessionSort::Bytes => SortKey::Number(entry.rx_bytes + entry.tx_bytes),
+        }
+    }
+}
+
+/// Position after the last session of a page
+///
+/// Encoded as `<sort value>/<session id>`, so the next page starts right
+/// after it even when sessions came or went in between.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Cursor {
+    key: SortKey,
+    session_id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let key = match &self.key {
+            SortKey::Number(value) => value.to_string(),
+            SortKey::Text(value) => value.clone(),
+        };
+        format!("{}/{}", key, self.session_id)
+    }
+
+    fn decode(cursor: &str, sort: SessionSort) -> Result<Self> {
+        let invalid = || LostLoveError::Admin(format!("Invalid cursor: {}", cursor));
+        let (key, session_id) = cursor.rsplit_once('/').ok_or_else(invalid)?;
+        let key = if sort.is_numeric() {
+            SortKey::Number(key.parse().map_err(|_| invalid())?)
+        } else {
+            SortKey::Text(key.to_string())
+        };
+        Ok(Self {
+            key,
+            session_id: session_id.to_string(),
+        })
+    }
+}
+
+/// Run `query` over the current sessions
+pub async fn list(connection_manager: &ConnectionManager, query: &SessionQuery) -> Result<SessionPage> {
+    let filter = Filter::new(query)?;
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|cursor| Cursor::decode(cursor, query.sort))
+        .transpose()?;
+
+    let mut rows = Vec::new();
+    for connection in connection_manager.connections() {
+        let session = connection.session();
+        let stats = session.stats().await;
+        let uptime = session.uptime();
+        let total = stats.bytes_received + stats.bytes_sent;
+        let bandwidth = if uptime.is_zero() {
+            0
+        } else {
+            (total as f64 / uptime.as_secs_f64()) as u64
+        };
+        let state = session.state().await;
+
+        rows.push(Row {
+            entry: SessionEntry {
+                session_id: session.id().to_string(),
+                user: connection.handshake().read().await.user().map(str::to_string),
+                peer: session.peer_address().to_string(),
+                tunnel_address: session.tunnel_address().map(|ip| ip.to_string()),
+                state: state.as_str().to_string(),
+                rx_bytes: stats.bytes_received,
+                tx_bytes: stats.bytes_sent,
+                bandwidth,
+                uptime_secs: uptime.as_secs(),
+                labels: session.labels().await.as_map().clone(),
+            },
+            peer_ip: session.peer_address().ip(),
+            tunnel_ip: session.tunnel_address(),
+            state,
+        });
+    }
+
+    Ok(select(rows, &filter, query, cursor))
+}
+
+/// Filter, sort and cut out the requested page
+fn select(rows: Vec<Row>, filter: &Filter, query: &SessionQuery, cursor: Option<Cursor>) -> SessionPage {
+    let mut matched: Vec<(Cursor, SessionEntry)> = rows
+        .into_iter()
+        .filter(|row| filter.matches(row))
+        .map(|row| {
+            let position = Cursor {
+                key: query.sort.key(&row.entry),
+                session_id: row.entry.session_id.clone(),
+            };
+            (position, row.entry)
+        })
+        .collect();
+
+    matched.sort_by(|(a, _), (b, _)| a.cmp(b));
+    if query.descending {
+        matched.reverse();
+    }
+
+    let total = matched.len();
+    let start = match &cursor {
+        Some(cursor) => matched.partition_point(|(position, _)| {
+            if query.descending {
+                position >= cursor
+            } else {
+                position <= cursor
+            }
+        }),
+        None => 0,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+
+    let page: Vec<_> = matched.into_iter().skip(start).take(limit).collect();
+    let next_cursor = if start + page.len() < total {
+        page.last().map(|(position, _)| position.encode())
+    } else {
+        None
+    };
+
+    SessionPage {
+        matched: total,
+        sessions: page.into_iter().map(|(_, entry)| entry).collect(),
+        next_cursor,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn row(id: &str, user: &str, peer: Ipv4Addr, bandwidth: u64, labels: &[(&str, &str)]) -> Row {
+        Row {
+            entry: SessionEntry {
+                session_id: id.to_string(),
+                user: Some(user.to_string()),
+                peer: SocketAddr::new(IpAddr::V4(peer), 40000).to_string(),
+                tunnel_address: None,
+                state: SessionState::Active.as_str().to_string(),
+                rx_bytes: 0,
+                tx_bytes: 0,
+                bandwidth,
+                uptime_secs: 0,
+                labels: labels
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            },
+            peer_ip: IpAddr::V4(peer),
+            tunnel_ip: None,
+            state: SessionState::Active,
+        }
+    }
+
+    fn rows() -> Vec<Row> {
+        vec![
+            row("a", "alice", Ipv4Addr::new(198, 51, 100, 1), 500, &[("team", "ops")]),
+            row("b", "bob", Ipv4Addr::new(198, 51, 100, 2), 2000, &[("team", "dev"), ("site", "Berlin")]),
+            row("c", "alice", Ipv4Addr::new(203, 0, 113, 7), 1000, &[]),
+            row("d", "carol", Ipv4Addr::new(198, 51, 100, 3), 0, &[("team", "ops"), ("site", "paris")]),
+        ]
+    }
+
+    fn ids(page: &SessionPage) -> Vec<&str> {
+        page.sessions.iter().map(|entry| entry.session_id.as_str()).collect()
+    }
+
+    fn run(query: SessionQuery) -> Result<SessionPage> {
+        let filter = Filter::new(&query)?;
+        let cursor = query
+            .cursor
+            .as_deref()
+            .map(|cursor| Cursor::decode(cursor, query.sort))
+            .transpose()?;
+        Ok(select(rows(), &filter, &query, cursor))
+    }
+
+    #[test]
+    fn test_filters() {
+        let page = run(SessionQuery {
+            user: Some("alice".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(ids(&page), ["a", "c"]);
+
+        let page = run(SessionQuery {
+            ip: Some("198.51.100.0/24".to_string()),
+            min_bandwidth: Some(500),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(ids(&page), ["a", "b"]);
+
+        let page = run(SessionQuery {
+            ip: Some("203.0.113.7".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(ids(&page), ["c"]);
+
+        let page = run(SessionQuery {
+            state: Some("handshaking".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(page.matched, 0);
+
+        assert!(run(SessionQuery {
+            state: Some("sleeping".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(run(SessionQuery {
+            ip: Some("not-an-ip".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_label_search() {
+        let search = |text: &str| {
+            run(SessionQuery {
+                search: Some(text.to_string()),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        assert_eq!(ids(&search("team=ops")), ["a", "d"]);
+        assert_eq!(ids(&search("berlin")), ["b"]);
+        assert_eq!(ids(&search("site team=ops")), ["d"]);
+        assert_eq!(ids(&search("team=op")).len(), 0);
+    }
+
+    #[test]
+    fn test_cursor_pagination() {
+        let mut query = SessionQuery {
+            sort: SessionSort::Bandwidth,
+            descending: true,
+            limit: Some(3),
+            ..Default::default()
+        };
+
+        let first = run(query.clone()).unwrap();
+        assert_eq!(first.matched, 4);
+        assert_eq!(ids(&first), ["b", "c", "a"]);
+        assert_eq!(first.next_cursor.as_deref(), Some("500/a"));
+
+        query.cursor = first.next_cursor;
+        let second = run(query.clone()).unwrap();
+        assert_eq!(ids(&second), ["d"]);
+        assert_eq!(second.next_cursor, None);
+
+        query.sort = SessionSort::User;
+        query.descending = false;
+        query.limit = Some(2);
+        query.cursor = None;
+        let first = run(query.clone()).unwrap();
+        assert_eq!(ids(&first), ["a", "c"]);
+        query.cursor = first.next_cursor;
+        assert_eq!(ids(&run(query.clone()).unwrap()), ["b", "d"]);
+
+        query.sort = SessionSort::Bytes;
+        query.cursor = Some("lots/a".to_string());
+        assert!(run(query).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_connections() {
+        let manager = ConnectionManager::new(10);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+
+        let page = list(&manager, &SessionQuery::default()).await.unwrap();
+        assert_eq!(page.matched, 1);
+        assert_eq!(page.sessions[0].session_id, conn.session().id().to_string());
+        assert_eq!(page.sessions[0].state, "handshaking");
+        assert_eq!(page.sessions[0].user, None);
+    }
+}
@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::core::connection::ConnectionManager;
+use crate::core::session::SessionId;
+use crate::protocol::address_discovery::Transport;
+
+/// Throughput of a single session over the last sampling interval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopEntry {
+    pub session_id: String,
+    pub peer: String,
+    pub rx_bytes_per_sec: u64,
+    pub tx_bytes_per_sec: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub uptime_secs: u64,
+    /// Transport the session's data currently uses
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl TopEntry {
+    /// Combined throughput in both directions
+    pub fn total_bytes_per_sec(&self) -> u64 {
+        self.rx_bytes_per_sec + self.tx_bytes_per_sec
+    }
+}
+
+/// One frame of the `llpctl top` view
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopSnapshot {
+    pub interval_ms: u64,
+    pub active_sessions: usize,
+    pub rx_bytes_per_sec: u64,
+    pub tx_bytes_per_sec: u64,
+    pub sessions: Vec<TopEntry>,
+}
+
+/// Byte counters remembered from the previous sample
+#[derive(Debug, Clone, Copy)]
+struct Counters {
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+/// Computes per-session throughput from deltas of SessionStats
+pub struct ThroughputSampler {
+    connection_manager: Arc<ConnectionManager>,
+    previous: HashMap<SessionId, Counters>,
+    last_sample: Option<Instant>,
+}
+
+impl ThroughputSampler {
+    /// Create new sampler
+    pub fn new(connection_manager: Arc<ConnectionManager>) -> Self {
+        Self {
+            connection_manager,
+            previous: HashMap::new(),
+            last_sample: None,
+        }
+    }
+
+    /// Take a sample and return the top `limit` sessions by bandwidth
+    ///
+    /// Sessions seen for the first time report zero throughput, since there
+    /// is no earlier sample to compute a delta against.
+    pub async fn sample(&mut self, limit: usize) -> TopSnapshot {
+        let now = Instant::now();
+        let elapsed = self.last_sample.map(|t| now.duration_since(t));
+        self.last_sample = Some(now);
+
+        let mut current = HashMap::new();
+        let mut sessions = Vec::new();
+
+        for connection in self.connection_manager.connections() {
+            let session = connection.session();
+            let stats = session.stats().await;
+            let counters = Counters {
+                rx_bytes: stats.bytes_received,
+                tx_bytes: stats.bytes_sent,
+            };
+
+            let (rx_rate, tx_rate) = match (elapsed, self.previous.get(session.id())) {
+                (Some(elapsed), Some(prev)) if !elapsed.is_zero() => {
+                    let secs = elapsed.as_secs_f64();
+                    (
+                        rate(counters.rx_bytes, prev.rx_bytes, secs),
+                        rate(counters.tx_bytes, prev.tx_bytes, secs),
+                    )
+                }
+                _ => (0, 0),
+            };
+
+            sessions.push(TopEntry {
+                session_id: session.id().to_string(),
+                peer: session.peer_address().to_string(),
+                rx_bytes_per_sec: rx_rate,
+                tx_bytes_per_sec: tx_rate,
+                rx_bytes: counters.rx_bytes,
+                tx_bytes: counters.tx_bytes,
+                uptime_secs: session.uptime().as_secs(),
+                transport: session.transport().await,
+                labels: session.labels().await.as_map().clone(),
+            });
+
+            current.insert(*session.id(), counters);
+        }
+
+        // Sessions that disappeared since the last sample are dropped here
+        self.previous = current;
+
+        let active_sessions = sessions.len();
+        let rx_bytes_per_sec = sessions.iter().map(|e| e.rx_bytes_per_sec).sum();
+        let tx_bytes_per_sec = sessions.iter().map(|e| e.tx_bytes_per_sec).sum();
+
+        sessions.sort_by(|a, b| {
+            b.total_bytes_per_sec()
+                .cmp(&a.total_bytes_per_sec())
+                .then_with(|| a.session_id.cmp(&b.session_id))
+        });
+        sessions.truncate(limit);
+
+        TopSnapshot {
+            interval_ms: elapsed.map(|e| e.as_millis() as u64).unwrap_or(0),
+            active_sessions,
+            rx_bytes_per_sec,
+            tx_bytes_per_sec,
+            sessions,
+        }
+    }
+}
+
+/// Bytes per second between two counter readings
+fn rate(current: u64, previous: u64, secs: f64) -> u64 {
+    (current.saturating_sub(previous) as f64 / secs) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_first_sample_has_zero_rate() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        conn.session().record_packet_received(1000).await;
+
+        let mut sampler = ThroughputSampler::new(manager);
+        let snapshot = sampler.sample(10).await;
+
+        assert_eq!(snapshot.active_sessions, 1);
+        assert_eq!(snapshot.sessions[0].rx_bytes, 1000);
+        assert_eq!(snapshot.sessions[0].rx_bytes_per_sec, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sorted_by_bandwidth() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let slow = manager.create_connection(addr).unwrap();
+        let fast = manager.create_connection(addr).unwrap();
+
+        let mut sampler = ThroughputSampler::new(manager);
+        sampler.sample(10).await;
+
+        slow.session().record_packet_sent(100).await;
+        fast.session().record_packet_received(100_000).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let snapshot = sampler.sample(10).await;
+        assert_eq!(snapshot.sessions[0].session_id, fast.session().id().to_string());
+        assert!(snapshot.sessions[0].rx_bytes_per_sec > 0);
+        assert!(snapshot.sessions[1].tx_bytes_per_sec > 0);
+        assert!(snapshot.rx_bytes_per_sec >= snapshot.sessions[0].rx_bytes_per_sec);
+
+        let limited = sampler.sample(1).await;
+        assert_eq!(limited.sessions.len(), 1);
+        assert_eq!(limited.active_sessions, 2);
+    }
+}
@@ -0,0 +1,1246 @@
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+// Shared with the server so generated output follows its wire format
+#[path = "../protocol/wire.rs"]
+mod wire;
+#[path = "../protocol/dissector.rs"]
+mod dissector;
+#[path = "../protocol/client_profile.rs"]
+mod client_profile;
+#[path = "../protocol/lockdown.rs"]
+mod lockdown;
+#[path = "../protocol/split_tunnel.rs"]
+mod split_tunnel;
+
+/// LostLove Server control utility
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Control socket path
+    #[arg(short, long, default_value = "/run/lostlove/control.sock")]
+    socket: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Live per-session throughput, sorted by bandwidth
+    Top {
+        /// Refresh interval in seconds
+        #[arg(short, long, default_value_t = 1.0)]
+        interval: f64,
+
+        /// Number of sessions to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        /// Print a single refresh and exit
+        #[arg(long)]
+        once: bool,
+    },
+
+    /// List sessions, filtered and sorted, one page at a time
+    Sessions {
+        /// Only sessions of this user
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Only sessions whose peer or tunnel address is in this address or subnet
+        #[arg(long)]
+        ip: Option<String>,
+
+        /// Only sessions in this state
+        #[arg(long, value_parser = ["handshaking", "active", "disconnecting", "closed"])]
+        state: Option<String>,
+
+        /// Only sessions averaging at least this many bytes per second
+        #[arg(long)]
+        min_bandwidth: Option<u64>,
+
+        /// Label search: `key=value` matches exactly, other words match any label
+        #[arg(long)]
+        search: Option<String>,
+
+        /// Sort by
+        #[arg(long, default_value = "id", value_parser = ["id", "user", "uptime", "bandwidth", "bytes"])]
+        sort: String,
+
+        /// Largest first
+        #[arg(long)]
+        desc: bool,
+
+        /// Sessions per page
+        #[arg(short = 'n', long, default_value_t = 100)]
+        limit: usize,
+
+        /// Start after this cursor (printed below the previous page)
+        #[arg(long)]
+        cursor: Option<String>,
+
+        /// Fetch every page instead of one
+        #[arg(long, conflicts_with = "cursor")]
+        all: bool,
+    },
+
+    /// Switch per-packet tracing on or off for one session
+    Trace {
+        /// Session ID (as shown by `llpctl top`)
+        session_id: String,
+
+        /// on | off
+        #[arg(value_parser = ["on", "off"])]
+        state: String,
+    },
+
+    /// Probe one session's path and print a verdict (path MTU, loss, RTT)
+    Diagnose {
+        /// Session ID (as shown by `llpctl top`)
+        session_id: String,
+    },
+
+    /// Show or change the server log filter without a restart
+    LogLevel {
+        /// Level (`debug`) or directives (`info,lostlove_server::crypto=trace`)
+        directives: Option<String>,
+    },
+
+    /// Error counts by error code since server start
+    Errors,
+
+    /// Re-read the config file and push changed routes/DNS/MTU to clients
+    Reload,
+
+    /// Show, set or remove labels of one session
+    Label {
+        /// Session ID (as shown by `llpctl top`)
+        session_id: String,
+
+        /// Labels to set, as key=value
+        #[arg(value_parser = parse_label)]
+        labels: Vec<(String, String)>,
+
+        /// Label keys to remove
+        #[arg(short, long)]
+        remove: Vec<String>,
+    },
+
+    /// Stop accepting new sessions before an upgrade, or show drain progress
+    Drain {
+        /// Disconnect remaining sessions after this many seconds
+        #[arg(short, long)]
+        deadline: Option<u64>,
+
+        /// Server (host:port) refused clients should connect to instead
+        #[arg(short, long)]
+        redirect: Option<String>,
+
+        /// Only show drain progress
+        #[arg(long, conflicts_with_all = ["deadline", "redirect"])]
+        status: bool,
+    },
+
+    /// Subnets learned from federated sites
+    Sites,
+
+    /// Traffic relayed between clients, busiest paths first
+    Relays,
+
+    /// End-to-end quality reported by clients, per user
+    Quality,
+
+    /// Captive portals reported by clients, latest first
+    Portals,
+
+    /// Re-run the server's crypto known-answer checks
+    Selftest,
+
+    /// Print a ready-to-use client config for a user in `[policies]`
+    ExportClient {
+        user: String,
+
+        /// Public host for the endpoints, instead of `server.descriptor.hosts`
+        #[arg(long)]
+        host: Option<String>,
+
+        /// Print the compact encoding (for `qrencode`) instead of TOML
+        #[arg(long)]
+        compact: bool,
+    },
+
+    /// Send a file to a connected client over its `files` channel
+    Push {
+        /// Session ID (as shown by `llpctl top`)
+        session_id: String,
+
+        /// File on the server
+        local: PathBuf,
+
+        /// Where the client stores it
+        remote: String,
+    },
+
+    /// Fetch a file from a connected client, resuming an interrupted pull
+    Pull {
+        /// Session ID (as shown by `llpctl top`)
+        session_id: String,
+
+        /// File on the client
+        remote: String,
+
+        /// Where to store it on the server
+        local: PathBuf,
+    },
+
+    /// Send a signed command to a client that accepts remote management
+    Manage {
+        /// Session ID (as shown by `llpctl top`)
+        session_id: String,
+
+        #[command(subcommand)]
+        action: ManageAction,
+    },
+
+    /// Print a Wireshark Lua dissector for the packet header
+    Dissector {
+        /// TCP port to decode as LLP
+        #[arg(short, long, default_value_t = 8443)]
+        port: u16,
+    },
+
+    /// Print kill switch firewall rules for a client profile
+    LockdownRules {
+        /// Profile from `llpctl export-client`, as TOML or compact
+        profile: PathBuf,
+
+        #[arg(short, long, default_value = "nftables", value_parser = ["nftables", "pf"])]
+        firewall: String,
+
+        /// Tunnel interface of the client
+        #[arg(short, long, default_value = "llp0")]
+        interface: String,
+
+        /// Let the local network through even if the profile doesn't
+        #[arg(long)]
+        allow_lan: bool,
+    },
+
+    /// Print a script routing only the profile's `[split]` apps through the tunnel
+    SplitRules {
+        /// Profile with a `[split]` section, as TOML or compact
+        profile: PathBuf,
+
+        /// Tunnel interface of the client
+        #[arg(short, long, default_value = "llp0")]
+        interface: String,
+    },
+
+    /// Move running processes of the profile's `[split]` apps into the tunnel
+    SplitAssign {
+        /// Profile with a `[split]` section, as TOML or compact
+        profile: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ManageAction {
+    /// Ask the client for its view of the tunnel
+    Diagnostics,
+
+    /// Make the client drop the session and connect again
+    Reconnect {
+        /// Seconds the client waits before connecting
+        #[arg(short, long, default_value_t = 0)]
+        delay: u64,
+    },
+
+    /// Override the client's routes and DNS servers until it reconnects
+    Settings {
+        /// Destination (CIDR) to route through the tunnel, repeatable
+        #[arg(short, long)]
+        route: Vec<String>,
+
+        /// DNS server, repeatable
+        #[arg(short, long)]
+        dns: Vec<String>,
+    },
+}
+
+/// Mirror of the server's `TopEntry`
+#[derive(Debug, Deserialize)]
+struct TopEntry {
+    session_id: String,
+    peer: String,
+    rx_bytes_per_sec: u64,
+    tx_bytes_per_sec: u64,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    uptime_secs: u64,
+    #[serde(default = "default_transport")]
+    transport: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+fn default_transport() -> String {
+    "tcp".to_string()
+}
+
+/// Mirror of the server's `SessionEntry`
+#[derive(Debug, Deserialize)]
+struct SessionEntry {
+    session_id: String,
+    user: Option<String>,
+    peer: String,
+    state: String,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    bandwidth: u64,
+    uptime_secs: u64,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+/// Mirror of the server's `AnnouncedRoute`
+#[derive(Debug, Deserialize)]
+struct SiteRoute {
+    prefix: String,
+    metric: u32,
+}
+
+/// Mirror of the server's `RelayPathStats`
+#[derive(Debug, Deserialize)]
+struct RelayPath {
+    source: String,
+    destination: String,
+    packets: u64,
+    bytes: u64,
+    dropped: u64,
+}
+
+/// Mirror of the server's `UserQuality`
+#[derive(Debug, Deserialize)]
+struct UserQuality {
+    user: String,
+    reports: u64,
+    seconds: u64,
+    rtt_ms: u32,
+    rtt_avg_ms: u32,
+    packets_sent: u64,
+    packets_lost: u64,
+    retransmits: u64,
+    wakeups: u64,
+}
+
+/// Mirror of the server's `SessionPortal`
+#[derive(Debug, Deserialize)]
+struct SessionPortal {
+    session_id: String,
+    user: Option<String>,
+    state: String,
+    location: Option<String>,
+    bypassed_secs: u64,
+    received_at: u64,
+}
+
+/// Mirror of the server's `SelfTestCheck`
+#[derive(Debug, Deserialize)]
+struct SelfTestCheck {
+    name: String,
+    error: Option<String>,
+}
+
+/// Mirror of the server's `ControlResponse`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Response {
+    Top {
+        interval_ms: u64,
+        active_sessions: usize,
+        rx_bytes_per_sec: u64,
+        tx_bytes_per_sec: u64,
+        sessions: Vec<TopEntry>,
+    },
+    Sessions {
+        matched: usize,
+        sessions: Vec<SessionEntry>,
+        next_cursor: Option<String>,
+    },
+    Drain {
+        draining: bool,
+        active_sessions: usize,
+        sessions_at_start: usize,
+        elapsed_secs: u64,
+        deadline_secs_left: Option<u64>,
+        redirect: Option<String>,
+    },
+    Errors {
+        counts: BTreeMap<String, u64>,
+    },
+    Sites {
+        sites: BTreeMap<String, Vec<SiteRoute>>,
+    },
+    Relays {
+        paths: Vec<RelayPath>,
+    },
+    Quality {
+        users: Vec<UserQuality>,
+    },
+    Portals {
+        sessions: Vec<SessionPortal>,
+    },
+    Diagnosis {
+        session_id: String,
+        transport: String,
+        rtt_min_ms: Option<f64>,
+        rtt_avg_ms: Option<f64>,
+        rtt_max_ms: Option<f64>,
+        probes_sent: usize,
+        probes_lost: usize,
+        path_mtu: Option<usize>,
+        verdict: Vec<String>,
+    },
+    Selftest {
+        checks: Vec<SelfTestCheck>,
+    },
+    ClientProfile {
+        profile: Box<client_profile::ClientProfile>,
+    },
+    ManagementReply {
+        id: u64,
+        ok: bool,
+        output: String,
+    },
+    Transfer {
+        direction: String,
+        local: String,
+        remote: String,
+        size: Option<u64>,
+        transferred: u64,
+        resumed_from: u64,
+        state: String,
+        error: Option<String>,
+    },
+    Ok {
+        message: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Works offline, without a server to ask
+    match &args.command {
+        Command::Dissector { port } => {
+            print!("{}", dissector::lua_dissector(*port));
+            return Ok(());
+        }
+        Command::LockdownRules { profile, firewall, interface, allow_lan } => {
+            let mut lockdown = lockdown::Lockdown::from_profile(&load_profile(profile)?, interface)?;
+            lockdown.allow_lan |= allow_lan;
+            for skipped in &lockdown.skipped {
+                eprintln!("Warning: {} can't be allowed through the kill switch", skipped);
+            }
+            match firewall.as_str() {
+                "pf" => print!("{}", lockdown.pf()),
+                _ => print!("{}", lockdown.nftables()),
+            }
+            return Ok(());
+        }
+        Command::SplitRules { profile, interface } => {
+            print!("{}", split_tunnel(profile, interface)?.script());
+            return Ok(());
+        }
+        Command::SplitAssign { profile } => {
+            let split = split_tunnel(profile, "llp0")?;
+            let pids = split_tunnel::matching_pids(Path::new("/proc"), &split.apps)?;
+            let cgroup = Path::new(split_tunnel::CGROUP_ROOT).join(split_tunnel::APPS_CGROUP);
+            let moved = split_tunnel::move_to_cgroup(&cgroup, &pids)
+                .with_context(|| format!("Failed to move processes into {}", cgroup.display()))?;
+            println!("Moved {} process(es) into {}", moved, cgroup.display());
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let stream = UnixStream::connect(&args.socket)
+        .await
+        .with_context(|| format!("Failed to connect to control socket {}", args.socket))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    match args.command {
+        Command::Top { interval, limit, once } => {
+            let request = json!({
+                "command": "top",
+                "interval_ms": (interval * 1000.0) as u64,
+                "limit": limit,
+            });
+            writer.write_all(format!("{}\n", request).as_bytes()).await?;
+
+            // The first frame has no previous sample, so rates only show up from the second
+            let mut frames = 0usize;
+
+            while let Some(line) = lines.next_line().await? {
+                let response: Response =
+                    serde_json::from_str(&line).context("Invalid response from server")?;
+                frames += 1;
+
+                if once && frames < 2 {
+                    continue;
+                }
+
+                if !once {
+                    // Clear screen and move the cursor home, like top(1)
+                    print!("\x1b[2J\x1b[H");
+                }
+                render_top(response)?;
+
+                if once {
+                    break;
+                }
+            }
+        }
+        Command::Sessions {
+            user,
+            ip,
+            state,
+            min_bandwidth,
+            search,
+            sort,
+            desc,
+            limit,
+            mut cursor,
+            all,
+        } => {
+            let mut header = true;
+            loop {
+                let request = json!({
+                    "command": "sessions",
+                    "user": user,
+                    "ip": ip,
+                    "state": state,
+                    "min_bandwidth": min_bandwidth,
+                    "search": search,
+                    "sort": sort,
+                    "descending": desc,
+                    "limit": limit,
+                    "cursor": cursor,
+                });
+                cursor = render_sessions(call(&mut writer, &mut lines, request).await?, header, !all)?;
+                header = false;
+                if !all || cursor.is_none() {
+                    break;
+                }
+            }
+        }
+        Command::Trace { session_id, state } => {
+            let request = json!({
+                "command": "trace",
+                "session_id": session_id,
+                "enabled": state == "on",
+            });
+            print_result(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Diagnose { session_id } => {
+            println!("Probing session {}, this takes up to a minute...", session_id);
+            let request = json!({ "command": "diagnose", "session_id": session_id });
+            render_diagnosis(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::LogLevel { directives } => {
+            let request = json!({
+                "command": "log-level",
+                "directives": directives,
+            });
+            print_result(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Errors => {
+            let request = json!({ "command": "errors" });
+            render_errors(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Reload => {
+            let request = json!({ "command": "reload" });
+            print_result(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Label { session_id, labels, remove } => {
+            let request = json!({
+                "command": "label",
+                "session_id": session_id,
+                "set": labels.into_iter().collect::<BTreeMap<_, _>>(),
+                "remove": remove,
+            });
+            print_result(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Drain { deadline, redirect, status } => {
+            let request = if status {
+                json!({ "command": "drain-status" })
+            } else {
+                json!({
+                    "command": "drain",
+                    "deadline_secs": deadline,
+                    "redirect": redirect,
+                })
+            };
+            render_drain(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Sites => {
+            let request = json!({ "command": "sites" });
+            render_sites(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Relays => {
+            let request = json!({ "command": "relays" });
+            render_relays(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Quality => {
+            let request = json!({ "command": "quality" });
+            render_quality(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Portals => {
+            let request = json!({ "command": "portals" });
+            render_portals(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Selftest => {
+            let request = json!({ "command": "selftest" });
+            render_selftest(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::ExportClient { user, host, compact } => {
+            let request = json!({
+                "command": "export-client",
+                "user": user,
+                "host": host,
+            });
+            render_profile(call(&mut writer, &mut lines, request).await?, compact)?;
+        }
+        Command::Push { session_id, local, remote } => {
+            // The server resolves paths from its own working directory
+            let request = json!({
+                "command": "push",
+                "session_id": session_id,
+                "local": std::env::current_dir()?.join(local),
+                "remote": remote,
+            });
+            writer.write_all(format!("{}\n", request).as_bytes()).await?;
+            render_transfer(&mut lines).await?;
+        }
+        Command::Pull { session_id, remote, local } => {
+            let request = json!({
+                "command": "pull",
+                "session_id": session_id,
+                "remote": remote,
+                "local": std::env::current_dir()?.join(local),
+            });
+            writer.write_all(format!("{}\n", request).as_bytes()).await?;
+            render_transfer(&mut lines).await?;
+        }
+        Command::Manage { session_id, action } => {
+            let action = match action {
+                ManageAction::Diagnostics => json!({ "command": "diagnostics" }),
+                ManageAction::Reconnect { delay } => json!({ "command": "reconnect", "delay_secs": delay }),
+                ManageAction::Settings { route, dns } => json!({ "command": "settings", "routes": route, "dns": dns }),
+            };
+            let request = json!({
+                "command": "manage",
+                "session_id": session_id,
+                "action": action,
+            });
+            render_management(call(&mut writer, &mut lines, request).await?)?;
+        }
+        Command::Dissector { .. }
+        | Command::LockdownRules { .. }
+        | Command::SplitRules { .. }
+        | Command::SplitAssign { .. } => unreachable!("handled before connecting"),
+    }
+
+    Ok(())
+}
+
+/// Parse a `key=value` label argument
+fn parse_label(arg: &str) -> std::result::Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected key=value, got {:?}", arg))
+}
+
+/// Send a one-shot request and wait for its response
+async fn call(
+    writer: &mut OwnedWriteHalf,
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    request: serde_json::Value,
+) -> Result<Response> {
+    writer.write_all(format!("{}\n", request).as_bytes()).await?;
+
+    let line = lines
+        .next_line()
+        .await?
+        .context("Server closed the control connection")?;
+
+    serde_json::from_str(&line).context("Invalid response from server")
+}
+
+/// Print the outcome of a one-shot command
+fn print_result(response: Response) -> Result<()> {
+    match response {
+        Response::Ok { message } => {
+            println!("{}", message);
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print one `top` frame
+fn render_top(response: Response) -> Result<()> {
+    match response {
+        Response::Top {
+            interval_ms,
+            active_sessions,
+            rx_bytes_per_sec,
+            tx_bytes_per_sec,
+            sessions,
+        } => {
+            println!(
+                "sessions: {}  rx: {}  tx: {}  (interval {} ms)",
+                active_sessions,
+                format_rate(rx_bytes_per_sec),
+                format_rate(tx_bytes_per_sec),
+                interval_ms
+            );
+            println!();
+            println!(
+                "{:<36}  {:<21}  {:<4}  {:>12}  {:>12}  {:>10}  {:>10}  {:>8}  LABELS",
+                "SESSION", "PEER", "VIA", "RX", "TX", "RX TOTAL", "TX TOTAL", "UPTIME"
+            );
+
+            for entry in sessions {
+                println!(
+                    "{:<36}  {:<21}  {:<4}  {:>12}  {:>12}  {:>10}  {:>10}  {:>8}  {}",
+                    entry.session_id,
+                    entry.peer,
+                    entry.transport,
+                    format_rate(entry.rx_bytes_per_sec),
+                    format_rate(entry.tx_bytes_per_sec),
+                    format_bytes(entry.rx_bytes),
+                    format_bytes(entry.tx_bytes),
+                    format_duration(entry.uptime_secs),
+                    format_labels(&entry.labels)
+                );
+            }
+
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print one page of sessions and return the next page's cursor
+fn render_sessions(response: Response, header: bool, show_cursor: bool) -> Result<Option<String>> {
+    match response {
+        Response::Sessions {
+            matched,
+            sessions,
+            next_cursor,
+        } => {
+            if header {
+                println!(
+                    "{:<36}  {:<16}  {:<21}  {:<13}  {:>12}  {:>10}  {:>8}  LABELS",
+                    "SESSION", "USER", "PEER", "STATE", "AVG RATE", "TOTAL", "UPTIME"
+                );
+            }
+            for entry in &sessions {
+                println!(
+                    "{:<36}  {:<16}  {:<21}  {:<13}  {:>12}  {:>10}  {:>8}  {}",
+                    entry.session_id,
+                    entry.user.as_deref().unwrap_or("-"),
+                    entry.peer,
+                    entry.state,
+                    format_rate(entry.bandwidth),
+                    format_bytes(entry.rx_bytes + entry.tx_bytes),
+                    format_duration(entry.uptime_secs),
+                    format_labels(&entry.labels)
+                );
+            }
+            if show_cursor {
+                println!();
+                println!("{} of {} matching sessions", sessions.len(), matched);
+                if let Some(cursor) = &next_cursor {
+                    println!("Next page: --cursor {}", cursor);
+                }
+            }
+            Ok(next_cursor)
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print a client profile as TOML, or in its compact encoding
+fn render_profile(response: Response, compact: bool) -> Result<()> {
+    match response {
+        Response::ClientProfile { profile } => {
+            if compact {
+                println!("{}", profile.to_compact()?);
+            } else {
+                print!("{}", profile.to_toml()?);
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+fn load_profile(path: &Path) -> Result<client_profile::ClientProfile> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read profile {}", path.display()))?;
+    read_profile(&text)
+}
+
+/// Split tunnel of a profile's `[split]` section
+fn split_tunnel(path: &Path, interface: &str) -> Result<split_tunnel::SplitTunnel> {
+    let profile = load_profile(path)?;
+    let split = profile
+        .split
+        .with_context(|| format!("Profile {} has no [split] section", path.display()))?;
+    Ok(split_tunnel::SplitTunnel::from_profile(&split, interface)?)
+}
+
+/// Profile in either encoding of `llpctl export-client`
+fn read_profile(text: &str) -> Result<client_profile::ClientProfile> {
+    if text.trim_start().starts_with(client_profile::COMPACT_PREFIX) {
+        Ok(client_profile::ClientProfile::from_compact(text)?)
+    } else {
+        toml::from_str(text).context("Invalid profile")
+    }
+}
+
+/// Print error counts, one code per line
+fn render_errors(response: Response) -> Result<()> {
+    match response {
+        Response::Errors { counts } => {
+            if counts.is_empty() {
+                println!("No errors recorded");
+            }
+            for (code, count) in counts {
+                println!("{:<24}  {:>10}", code, count);
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print learned subnets, one site per line
+fn render_sites(response: Response) -> Result<()> {
+    match response {
+        Response::Sites { sites } => {
+            if sites.is_empty() {
+                println!("No routes learned from sites");
+                return Ok(());
+            }
+            println!("{:<24}  {:<18}  {:>6}", "SITE", "PREFIX", "METRIC");
+            for (site, routes) in sites {
+                for route in routes {
+                    println!("{:<24}  {:<18}  {:>6}", site, route.prefix, route.metric);
+                }
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print relayed traffic, one path per line
+fn render_relays(response: Response) -> Result<()> {
+    match response {
+        Response::Relays { paths } => {
+            if paths.is_empty() {
+                println!("No traffic relayed");
+                return Ok(());
+            }
+            println!(
+                "{:<15}  {:<15}  {:>10}  {:>10}  {:>8}",
+                "SOURCE", "DESTINATION", "PACKETS", "BYTES", "DROPPED"
+            );
+            for path in paths {
+                println!(
+                    "{:<15}  {:<15}  {:>10}  {:>10}  {:>8}",
+                    path.source,
+                    path.destination,
+                    path.packets,
+                    format_bytes(path.bytes),
+                    path.dropped
+                );
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print the verdict, then the measurements it is based on
+fn render_diagnosis(response: Response) -> Result<()> {
+    match response {
+        Response::Diagnosis {
+            session_id,
+            transport,
+            rtt_min_ms,
+            rtt_avg_ms,
+            rtt_max_ms,
+            probes_sent,
+            probes_lost,
+            path_mtu,
+            verdict,
+        } => {
+            println!("Session {} over {}:", session_id, transport.to_uppercase());
+            for finding in verdict {
+                println!("  {}", finding);
+            }
+
+            println!();
+            if let (Some(min), Some(avg), Some(max)) = (rtt_min_ms, rtt_avg_ms, rtt_max_ms) {
+                println!("{:<10}  {:.1} / {:.1} / {:.1} ms (min / avg / max)", "RTT", min, avg, max);
+            }
+            if probes_sent > 0 {
+                println!("{:<10}  {} of {} probes", "LOST", probes_lost, probes_sent);
+            }
+            if let Some(path_mtu) = path_mtu {
+                println!("{:<10}  {} bytes", "PATH MTU", path_mtu);
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print reported quality, one user per line
+fn render_quality(response: Response) -> Result<()> {
+    match response {
+        Response::Quality { users } => {
+            if users.is_empty() {
+                println!("No quality reports received");
+                return Ok(());
+            }
+            println!(
+                "{:<16}  {:>7}  {:>8}  {:>7}  {:>7}  {:>7}  {:>8}  {:>9}",
+                "USER", "REPORTS", "COVERED", "RTT", "RTT AVG", "LOSS", "RETRANS", "WAKEUPS/H"
+            );
+            for user in users {
+                println!(
+                    "{:<16}  {:>7}  {:>8}  {:>5}ms  {:>5}ms  {:>6.2}%  {:>8}  {:>9.1}",
+                    user.user,
+                    user.reports,
+                    format_duration(user.seconds),
+                    user.rtt_ms,
+                    user.rtt_avg_ms,
+                    loss_percent(user.packets_lost, user.packets_sent),
+                    user.retransmits,
+                    user.wakeups as f64 * 3600.0 / user.seconds.max(1) as f64
+                );
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print the latest portal report of each session
+fn render_portals(response: Response) -> Result<()> {
+    match response {
+        Response::Portals { sessions } => {
+            if sessions.is_empty() {
+                println!("No captive portal reports received");
+                return Ok(());
+            }
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default();
+            println!(
+                "{:<36}  {:<16}  {:<8}  {:>8}  {:>8}  PORTAL",
+                "SESSION", "USER", "STATE", "BYPASSED", "AGE"
+            );
+            for portal in sessions {
+                println!(
+                    "{:<36}  {:<16}  {:<8}  {:>8}  {:>8}  {}",
+                    portal.session_id,
+                    portal.user.as_deref().unwrap_or("-"),
+                    portal.state,
+                    format_duration(portal.bypassed_secs),
+                    format_duration(now.saturating_sub(portal.received_at)),
+                    portal.location.as_deref().unwrap_or("-")
+                );
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print each check's result, failing if any check failed
+fn render_selftest(response: Response) -> Result<()> {
+    match response {
+        Response::Selftest { checks } => {
+            let mut failed = 0;
+            for check in checks {
+                match check.error {
+                    None => println!("{:<20}  ok", check.name),
+                    Some(error) => {
+                        failed += 1;
+                        println!("{:<20}  FAILED  {}", check.name, error);
+                    }
+                }
+            }
+            if failed > 0 {
+                anyhow::bail!("{} crypto self-test check(s) failed", failed);
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print drain progress
+fn render_drain(response: Response) -> Result<()> {
+    match response {
+        Response::Drain {
+            draining: false,
+            active_sessions,
+            ..
+        } => {
+            println!("Not draining ({} active sessions)", active_sessions);
+            Ok(())
+        }
+        Response::Drain {
+            active_sessions,
+            sessions_at_start,
+            elapsed_secs,
+            deadline_secs_left,
+            redirect,
+            ..
+        } => {
+            println!(
+                "Draining for {}: {} of {} sessions left",
+                format_duration(elapsed_secs),
+                active_sessions,
+                sessions_at_start
+            );
+            match deadline_secs_left {
+                Some(secs) => println!("Deadline in {}", format_duration(secs)),
+                None => println!("No deadline, waiting for sessions to end"),
+            }
+            if let Some(redirect) = redirect {
+                println!("New clients redirected to {}", redirect);
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Format bytes/second as bits/second, like iftop
+fn format_rate(bytes_per_sec: u64) -> String {
+    let bits = bytes_per_sec as f64 * 8.0;
+
+    if bits >= 1e9 {
+        format!("{:.2} Gb/s", bits / 1e9)
+    } else if bits >= 1e6 {
+        format!("{:.2} Mb/s", bits / 1e6)
+    } else if bits >= 1e3 {
+        format!("{:.2} Kb/s", bits / 1e3)
+    } else {
+        format!("{} b/s", bits as u64)
+    }
+}
+
+/// Format a byte count with binary units
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Print a client's answer to a management command
+fn render_management(response: Response) -> Result<()> {
+    match response {
+        Response::ManagementReply { id, ok, output } => {
+            if !ok {
+                anyhow::bail!("Client refused command {}: {}", id, output);
+            }
+            println!("Command {} done", id);
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+            Ok(())
+        }
+        Response::Error { message } => anyhow::bail!("Server error: {}", message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+/// Print a transfer's progress on one line until it ends
+async fn render_transfer(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Result<()> {
+    while let Some(line) = lines.next_line().await? {
+        let response: Response = serde_json::from_str(&line).context("Invalid response from server")?;
+        match response {
+            Response::Transfer {
+                direction,
+                local,
+                remote,
+                size,
+                transferred,
+                resumed_from,
+                state,
+                error,
+            } => {
+                let (from, to) = if direction == "push" { (&local, &remote) } else { (&remote, &local) };
+                print!("\r{} -> {}  {}", from, to, format_progress(transferred, size, resumed_from));
+                std::io::stdout().flush()?;
+
+                match state.as_str() {
+                    "complete" => {
+                        println!("  done");
+                        return Ok(());
+                    }
+                    "failed" => {
+                        println!();
+                        anyhow::bail!("Transfer failed: {}", error.unwrap_or_default());
+                    }
+                    _ => {}
+                }
+            }
+            Response::Error { message } => anyhow::bail!("Server error: {}", message),
+            other => anyhow::bail!("Unexpected response: {:?}", other),
+        }
+    }
+    anyhow::bail!("Server closed the control connection")
+}
+
+/// "1.5 KiB / 2.0 KiB (75%)", noting what an earlier attempt already moved
+fn format_progress(transferred: u64, size: Option<u64>, resumed_from: u64) -> String {
+    let mut progress = match size {
+        Some(0) => format!("{} / {} (100%)", format_bytes(0), format_bytes(0)),
+        Some(size) => format!(
+            "{} / {} ({}%)",
+            format_bytes(transferred),
+            format_bytes(size),
+            transferred * 100 / size
+        ),
+        None => format_bytes(transferred),
+    };
+    if resumed_from > 0 {
+        progress.push_str(&format!(", resumed at {}", format_bytes(resumed_from)));
+    }
+    progress
+}
+
+/// Share of sent packets that were lost, in percent
+fn loss_percent(lost: u64, sent: u64) -> f64 {
+    if sent == 0 {
+        return 0.0;
+    }
+    lost as f64 * 100.0 / sent as f64
+}
+
+/// Format labels as key=value pairs separated by commas
+fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Format seconds as h:mm:ss
+fn format_duration(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_profile() {
+        let profile = read_profile("user = \"alice\"\nendpoints = [\"udp://203.0.113.5:8443\"]\nmtu = 1400\n").unwrap();
+        assert_eq!(profile.user, "alice");
+        assert_eq!(read_profile(&profile.to_compact().unwrap()).unwrap(), profile);
+        assert!(read_profile("LLP1:???").is_err());
+    }
+
+    #[test]
+    fn test_format_rate() {
+        assert_eq!(format_rate(0), "0 b/s");
+        assert_eq!(format_rate(1_000), "8.00 Kb/s");
+        assert_eq!(format_rate(12_500_000), "100.00 Mb/s");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(parse_label("team=ops").unwrap(), ("team".to_string(), "ops".to_string()));
+        assert!(parse_label("team").is_err());
+    }
+
+    #[test]
+    fn test_loss_percent() {
+        assert_eq!(loss_percent(0, 0), 0.0);
+        assert_eq!(loss_percent(5, 200), 2.5);
+    }
+
+    #[test]
+    fn test_format_progress() {
+        assert_eq!(format_progress(0, None, 0), "0 B");
+        assert_eq!(format_progress(1536, Some(2048), 0), "1.5 KiB / 2.0 KiB (75%)");
+        assert_eq!(format_progress(0, Some(0), 0), "0 B / 0 B (100%)");
+        assert_eq!(
+            format_progress(2048, Some(2048), 1024),
+            "2.0 KiB / 2.0 KiB (100%), resumed at 1.0 KiB"
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(3725), "1:02:05");
+    }
+}
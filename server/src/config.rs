@@ -1,8 +1,17 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
 use anyhow::{Context, Result};
 
+use crate::crypto::tpm::{self, SealedSecret};
+use crate::crypto::CryptoPolicy;
+use crate::network::dns_upstream::UpstreamSpec;
+use crate::network::dscp::MAX_DSCP;
+use crate::network::ip_net::IpNet;
+use crate::protocol::{HEADER_SIZE, MAX_PAYLOAD_SIZE};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub server: ServerConfig,
@@ -11,6 +20,47 @@ pub struct Config {
     pub limits: LimitsConfig,
     #[serde(default)]
     pub monitoring: MonitoringConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub firewall: FirewallConfig,
+    #[serde(default)]
+    pub push: PushConfig,
+    #[serde(default)]
+    pub resolver: ResolverConfig,
+    /// Access policies keyed by user name
+    #[serde(default)]
+    pub policies: BTreeMap<String, UserPolicy>,
+    /// Bandwidth classes referenced by `policies.<user>.class`
+    #[serde(default)]
+    pub classes: BTreeMap<String, RateClass>,
+    #[serde(default)]
+    pub federation: FederationConfig,
+    #[serde(default)]
+    pub failover: FailoverConfig,
+    #[serde(default)]
+    pub fleet: FleetConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+}
+
+/// Keeping key material out of swap and crash dumps
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SecurityConfig {
+    /// `mlock` session key buffers and exclude them from core dumps
+    #[serde(default)]
+    pub lock_key_memory: bool,
+
+    /// Set the core size limit to zero and mark the process non-dumpable
+    #[serde(default)]
+    pub disable_core_dumps: bool,
+
+    /// Only negotiate approved algorithms (AES-256-GCM), always on in
+    /// builds with the `fips` feature
+    #[serde(default)]
+    pub fips: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -29,6 +79,247 @@ pub struct ServerConfig {
 
     #[serde(default = "default_worker_threads")]
     pub worker_threads: usize,
+
+    /// Tokio runtime flavor: "multi-thread" or "current-thread"
+    #[serde(default = "default_runtime")]
+    pub runtime: String,
+
+    /// Pin each worker thread to its own CPU (Linux)
+    #[serde(default)]
+    pub pin_workers: bool,
+
+    /// Start listeners (UDP, DNS, ICMP, knock, discovery) again after a panic
+    #[serde(default)]
+    pub restart_listeners: bool,
+
+    /// Locked while the server runs, with a lock for the TUN device next to
+    /// it, so a second instance can't take the same device, pool and NAT
+    /// rules ("" = no locking)
+    #[serde(default = "default_pid_file")]
+    pub pid_file: String,
+
+    #[serde(default)]
+    pub port_hopping: PortHoppingConfig,
+
+    #[serde(default)]
+    pub knock: KnockConfig,
+
+    #[serde(default)]
+    pub decoy: DecoyConfig,
+
+    #[serde(default)]
+    pub obfuscation: ObfuscationConfig,
+
+    #[serde(default)]
+    pub overload: OverloadConfig,
+
+    #[serde(default)]
+    pub tcp: TcpConfig,
+
+    #[serde(default)]
+    pub qos: QosConfig,
+
+    #[serde(default)]
+    pub udp: UdpConfig,
+
+    #[serde(default)]
+    pub descriptor: DescriptorConfig,
+}
+
+/// Signed JSON document listing the endpoints and key clients need, so they
+/// can bootstrap from one URL and notice when endpoints change
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DescriptorConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// HTTP listener serving `/descriptor.json`
+    #[serde(default = "default_descriptor_address")]
+    pub address: String,
+
+    /// Public names or addresses of this server, in order of preference
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    /// Ed25519 private key seed (32 bytes in hex) the descriptor is signed
+    /// with; clients are only given its public key
+    #[serde(default)]
+    pub key: String,
+
+    /// Seconds a served descriptor stays valid
+    #[serde(default = "default_descriptor_validity")]
+    pub validity: u64,
+}
+
+/// Socket options of the UDP transport
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UdpConfig {
+    /// Set don't-fragment and learn each client's path MTU from the sends
+    /// the kernel refuses as too big
+    #[serde(default = "default_true")]
+    pub dont_fragment: bool,
+
+    /// Answer from the address each datagram was sent to, for a wildcard
+    /// bind on a host with several addresses
+    #[serde(default = "default_true")]
+    pub pktinfo: bool,
+}
+
+/// DSCP marking of the outer packets, so upstream QoS still sees the class
+/// of the traffic inside the tunnel
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QosConfig {
+    /// DSCP of outer packets (0-63), unset keeps the system default
+    #[serde(default)]
+    pub dscp: Option<u8>,
+
+    /// Carry each tunneled packet's DSCP over to its outer packet on TCP
+    /// connections; UDP datagrams share a socket and keep `dscp`
+    #[serde(default)]
+    pub copy_inner: bool,
+}
+
+/// Socket options of the TCP listener and the connections it accepts
+///
+/// Tunnels carry interactive traffic inside one stream, so Nagle is off by
+/// default; keepalive notices clients that vanished behind a NAT.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TcpConfig {
+    /// Send small segments right away (TCP_NODELAY)
+    #[serde(default = "default_true")]
+    pub nodelay: bool,
+
+    /// Seconds idle before keepalive probes start, 0 turns keepalive off
+    #[serde(default = "default_keepalive_idle")]
+    pub keepalive_idle: u64,
+
+    /// Seconds between keepalive probes
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+
+    /// Unanswered probes before the connection is dropped
+    #[serde(default = "default_keepalive_count")]
+    pub keepalive_count: u32,
+
+    /// SO_SNDBUF in bytes, unset keeps the system default
+    #[serde(default)]
+    pub send_buffer: Option<usize>,
+
+    /// SO_RCVBUF in bytes, unset keeps the system default
+    #[serde(default)]
+    pub recv_buffer: Option<usize>,
+
+    /// TCP_FASTOPEN queue length on the listener, 0 turns it off
+    #[serde(default)]
+    pub fastopen_queue: u32,
+
+    /// Send the first CONFIG_UPDATE along with ServerHello to clients
+    /// offering `early_data`, before the connect hook has run
+    #[serde(default)]
+    pub early_data: bool,
+}
+
+/// Turning new sessions away while the server is saturated
+///
+/// Sessions are shed at `max_connections`, and also when the load average
+/// per CPU is above `max_load` if that is set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OverloadConfig {
+    /// 1-minute load average per CPU above which new sessions are refused
+    #[serde(default)]
+    pub max_load: Option<f64>,
+
+    /// Seconds refused clients are told to wait before retrying
+    #[serde(default = "default_overload_retry_after")]
+    pub retry_after: u64,
+}
+
+/// Encrypt the first handshake message in each direction so it looks random
+///
+/// Clients without the server key can no longer connect.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ObfuscationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// 32 bytes in hex, handed to clients like a public key
+    #[serde(default)]
+    pub server_key: String,
+}
+
+/// Backend serving connections whose first message isn't a valid handshake
+///
+/// Active probes then talk to an ordinary service (e.g. a web server)
+/// instead of seeing the connection closed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DecoyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Address of the decoy backend, e.g. `127.0.0.1:80`
+    #[serde(default)]
+    pub address: String,
+
+    /// Seconds to wait for the decoy backend to accept
+    #[serde(default = "default_decoy_connect_timeout")]
+    pub connect_timeout: u64,
+
+    /// Seconds without bytes in either direction before a proxied
+    /// connection is closed
+    #[serde(default = "default_decoy_idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+/// Single packet authorization in front of the TCP listener
+///
+/// Connections are dropped without a byte unless their source address sent
+/// a valid knock packet shortly before.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KnockConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Secret shared with clients, knocks are authenticated with it
+    #[serde(default)]
+    pub secret: String,
+
+    /// UDP port receiving knocks
+    #[serde(default = "default_knock_port")]
+    pub port: u16,
+
+    /// Seconds a knocking address may start connections for
+    #[serde(default = "default_knock_window")]
+    pub window: u64,
+
+    /// Seconds a knock's timestamp may be off from the server clock
+    #[serde(default = "default_knock_max_skew")]
+    pub max_skew: u64,
+
+    /// Also hide the port from scanners with iptables rules (Linux, root)
+    #[serde(default)]
+    pub firewall: bool,
+}
+
+/// Rotate the UDP listener across a port range on a schedule clients derive
+/// from the same secret
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PortHoppingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Secret shared with clients, the schedule can't be predicted without it
+    #[serde(default)]
+    pub secret: String,
+
+    #[serde(default = "default_hop_port_min")]
+    pub port_min: u16,
+
+    #[serde(default = "default_hop_port_max")]
+    pub port_max: u16,
+
+    /// Seconds each port is used for
+    #[serde(default = "default_hop_interval")]
+    pub interval: u64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -42,137 +333,1659 @@ pub struct NetworkConfig {
     #[serde(default = "default_mtu")]
     pub mtu: usize,
 
+    /// TUN queues, each read by its own task (more than one needs Linux)
+    #[serde(default = "default_tun_queues")]
+    pub tun_queues: usize,
+
+    /// Segmentation offload on the TUN, so bulk TCP moves in packets up to 64 KiB (Linux)
+    #[serde(default)]
+    pub tun_offload: bool,
+
     #[serde(default)]
     pub enable_ipv6: bool,
+
+    /// Drop client packets whose inner source isn't the session's leased address
+    #[serde(default = "default_true")]
+    pub validate_source: bool,
+
+    /// Clamp the MSS of tunneled TCP SYNs to fit the MTU
+    #[serde(default = "default_true")]
+    pub clamp_mss: bool,
+
+    /// Install NAT/forwarding rules for the tunnel subnet on start
+    #[serde(default)]
+    pub manage_nat: bool,
+
+    #[serde(default)]
+    pub egress: EgressConfig,
+
+    #[serde(default)]
+    pub isolation: IsolationConfig,
+
+    #[serde(default)]
+    pub relay: RelayConfig,
+
+    #[serde(default)]
+    pub p2p: P2pConfig,
+
+    #[serde(default)]
+    pub dns: DnsTunnelConfig,
+
+    #[serde(default)]
+    pub icmp: IcmpTunnelConfig,
 }
 
+/// Optional transport in ICMP echo payloads, for networks passing only ping
+///
+/// Needs a raw socket, so the server must run as root or with CAP_NET_RAW.
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct LimitsConfig {
-    #[serde(default = "default_rate_limit")]
-    pub rate_limit_per_user: u64,
+pub struct IcmpTunnelConfig {
+    #[serde(default)]
+    pub enabled: bool,
 
-    #[serde(default = "default_max_streams")]
-    pub max_streams_per_connection: usize,
+    /// Concurrent ICMP tunnels
+    #[serde(default = "default_max_tunnels")]
+    pub max_tunnels: usize,
 
-    #[serde(default = "default_connection_timeout")]
-    pub connection_timeout: u64,
+    /// Seconds without echo requests before a tunnel is closed
+    #[serde(default = "default_tunnel_idle_timeout")]
+    pub idle_timeout: u64,
 }
 
+/// Experimental DNS transport, the last resort on networks blocking all else
+///
+/// The server answers as authoritative name server of `zone`, which must be
+/// delegated to it (NS record pointing at this host).
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct MonitoringConfig {
-    #[serde(default = "default_true")]
-    pub enable_metrics: bool,
+pub struct DnsTunnelConfig {
+    #[serde(default)]
+    pub enabled: bool,
 
-    #[serde(default = "default_metrics_port")]
-    pub metrics_port: u16,
+    /// Zone delegated to this server, e.g. `t.example.com`
+    #[serde(default)]
+    pub zone: String,
 
-    #[serde(default = "default_log_level")]
-    pub log_level: String,
-}
+    /// UDP port of the name server
+    #[serde(default = "default_dns_port")]
+    pub port: u16,
 
-// Defaults
-fn default_bind_address() -> String { "0.0.0.0".to_string() }
-fn default_port() -> u16 { 8443 }
-fn default_protocol() -> String { "tcp".to_string() }
-fn default_max_connections() -> usize { 1000 }
-fn default_worker_threads() -> usize { 0 }
-fn default_tun_name() -> String { "hfp0".to_string() }
-fn default_tun_address() -> String { "10.8.0.1/24".to_string() }
-fn default_mtu() -> usize { 1400 }
-fn default_rate_limit() -> u64 { 100_000_000 }
-fn default_max_streams() -> usize { 256 }
-fn default_connection_timeout() -> u64 { 300 }
-fn default_true() -> bool { true }
-fn default_metrics_port() -> u16 { 9090 }
-fn default_log_level() -> String { "info".to_string() }
+    /// Concurrent DNS tunnels
+    #[serde(default = "default_max_tunnels")]
+    pub max_tunnels: usize,
 
-impl Default for LimitsConfig {
-    fn default() -> Self {
-        Self {
-            rate_limit_per_user: default_rate_limit(),
-            max_streams_per_connection: default_max_streams(),
-            connection_timeout: default_connection_timeout(),
-        }
-    }
+    /// Seconds without queries before a tunnel is closed
+    #[serde(default = "default_tunnel_idle_timeout")]
+    pub idle_timeout: u64,
 }
 
-impl Default for MonitoringConfig {
-    fn default() -> Self {
-        Self {
-            enable_metrics: default_true(),
-            metrics_port: default_metrics_port(),
-            log_level: default_log_level(),
-        }
-    }
-}
+/// Hole punching between clients, signaled through the server
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct P2pConfig {
+    #[serde(default)]
+    pub enabled: bool,
 
-impl Config {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = fs::read_to_string(path.as_ref())
-            .context("Failed to read configuration file")?;
+    /// Milliseconds between the punch message and the moment both sides start
+    #[serde(default = "default_punch_delay_ms")]
+    pub punch_delay_ms: u64,
 
-        let config: Config = toml::from_str(&content)
-            .context("Failed to parse configuration file")?;
+    /// Seconds a client has to answer an offer before the initiator falls back
+    #[serde(default = "default_offer_timeout")]
+    pub offer_timeout: u64,
 
-        config.validate()?;
+    /// UDP port answering address discovery requests (also without `enabled`)
+    #[serde(default)]
+    pub discovery_port: Option<u16>,
+}
 
-        Ok(config)
-    }
+/// Client-to-client relaying through the server process
+///
+/// Relayed packets never reach the kernel, so clients behind NAT can talk to
+/// each other even with `isolation` dropping tunnel-to-tunnel forwarding.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RelayConfig {
+    #[serde(default)]
+    pub enabled: bool,
 
-    fn validate(&self) -> Result<()> {
-        // Validate bind address
-        if self.server.bind_address.is_empty() {
-            anyhow::bail!("bind_address cannot be empty");
-        }
+    /// Sustained rate in bytes/second per source/destination pair (0 = unlimited)
+    #[serde(default)]
+    pub rate_limit_per_path: u64,
 
-        // Validate port
-        if self.server.port == 0 {
-            anyhow::bail!("port must be greater than 0");
-        }
+    /// Bucket size in bytes per path (default: 1s worth)
+    #[serde(default)]
+    pub burst_per_path: Option<u64>,
+}
 
-        // Validate protocol
-        if !["tcp", "udp", "both"].contains(&self.server.protocol.as_str()) {
-            anyhow::bail!("protocol must be one of: tcp, udp, both");
-        }
+/// Per-client routing isolation (Linux)
+///
+/// Each client's traffic is looked up in its own routing table holding only
+/// the egress default route, so clients can't reach each other or the
+/// server's other networks.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IsolationConfig {
+    #[serde(default)]
+    pub enabled: bool,
 
-        // Validate MTU
-        if self.network.mtu < 576 || self.network.mtu > 9000 {
-            anyhow::bail!("MTU must be between 576 and 9000");
-        }
+    /// Routing table of the client leased host address N is `table_base + N`
+    #[serde(default = "default_isolation_table_base")]
+    pub table_base: u32,
+}
 
-        Ok(())
-    }
+/// Where tunneled traffic leaves the server (multi-homed setups)
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct EgressConfig {
+    /// Outgoing interface (any if unset)
+    #[serde(default)]
+    pub interface: Option<String>,
 
-    pub fn default_for_testing() -> Self {
-        Self {
-            server: ServerConfig {
-                bind_address: "127.0.0.1".to_string(),
-                port: 8443,
-                protocol: "tcp".to_string(),
-                max_connections: 100,
-                worker_threads: 2,
-            },
-            network: NetworkConfig {
-                tun_name: "hfp0".to_string(),
-                tun_address: "10.8.0.1/24".to_string(),
-                mtu: 1400,
-                enable_ipv6: false,
-            },
-            limits: LimitsConfig::default(),
-            monitoring: MonitoringConfig::default(),
-        }
-    }
-}
+    /// Source address for NAT (masquerade if unset)
+    #[serde(default)]
+    pub snat_address: Option<Ipv4Addr>,
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Mark tunneled packets and route them via `interface` in table `fwmark`
+    #[serde(default)]
+    pub fwmark: Option<u32>,
 
-    #[test]
-    fn test_default_config() {
+    /// Next hop for the policy routing table (direct route if unset)
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LimitsConfig {
+    /// Sustained rate in bytes/second for users without a class (0 = unlimited)
+    #[serde(default = "default_rate_limit")]
+    pub rate_limit_per_user: u64,
+
+    /// Bytes a user may send at once above the sustained rate (default: 1s worth)
+    #[serde(default)]
+    pub burst_per_user: Option<u64>,
+
+    #[serde(default = "default_max_streams")]
+    pub max_streams_per_connection: usize,
+
+    #[serde(default = "default_connection_timeout")]
+    pub connection_timeout: u64,
+
+    /// Seconds in which every session is checked for `connection_timeout` once
+    #[serde(default = "default_cleanup_interval")]
+    pub cleanup_interval: u64,
+
+    /// Error packets sent per connection per second (0 = never report errors)
+    #[serde(default = "default_error_responses_per_sec")]
+    pub error_responses_per_sec: u32,
+
+    /// Extension packet types without a handler: "drop" or "error"
+    #[serde(default = "default_unknown_packet_types")]
+    pub unknown_packet_types: String,
+
+    /// Warn clients this many seconds before their allowed hours end
+    #[serde(default = "default_policy_grace_period")]
+    pub policy_grace_period: u64,
+
+    /// Warn clients once they used this share of a data quota
+    #[serde(default = "default_quota_warning_percent")]
+    pub quota_warning_percent: u8,
+
+    /// Seconds without packets before a session releases its buffers (0 = never)
+    #[serde(default = "default_hibernate_after")]
+    pub hibernate_after: u64,
+
+    /// Cap on buffers of all connections together in MiB (0 = no cap)
+    #[serde(default)]
+    pub max_memory_mb: u64,
+
+    /// Cap on one connection's buffers in KiB (0 = no cap)
+    #[serde(default)]
+    pub max_connection_memory_kb: u64,
+
+    /// Largest packet read from a client, header included
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+
+    /// Seconds each read of a started packet may wait for more bytes
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+
+    /// Seconds a client may take over one packet, the ClientHello counted
+    /// from the connection
+    #[serde(default = "default_packet_timeout")]
+    pub packet_timeout: u64,
+}
+
+/// Stateful filter for tunneled traffic
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FirewallConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Seconds an idle inner TCP/UDP flow stays open for return traffic
+    #[serde(default = "default_flow_timeout")]
+    pub flow_timeout: u64,
+
+    /// Maximum tracked flows across all sessions
+    #[serde(default = "default_max_flows")]
+    pub max_flows: usize,
+}
+
+/// Per-user access policy
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UserPolicy {
+    /// Hours (UTC) the user may be connected
+    #[serde(default)]
+    pub allowed_hours: Option<AllowedHours>,
+
+    #[serde(default)]
+    pub daily_quota_gb: Option<f64>,
+
+    #[serde(default)]
+    pub monthly_quota_gb: Option<f64>,
+
+    /// Bandwidth class (`[classes.<name>]`), default limits if unset
+    #[serde(default)]
+    pub class: Option<String>,
+
+    /// Filter the user's DNS queries through `resolver.blocklists`,
+    /// `resolver.block_by_default` if unset
+    #[serde(default)]
+    pub dns_blocking: Option<bool>,
+
+    /// Have the user's client block all traffic outside the tunnel while
+    /// it should be up
+    #[serde(default)]
+    pub lockdown: bool,
+
+    /// Let the local network through the kill switch
+    #[serde(default)]
+    pub lockdown_allow_lan: bool,
+}
+
+/// Token bucket parameters of a bandwidth class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateClass {
+    /// Sustained rate in bytes/second
+    pub rate: u64,
+
+    /// Bucket size in bytes, i.e. how far a burst may exceed the rate (default: 1s worth)
+    #[serde(default)]
+    pub burst: Option<u64>,
+}
+
+impl RateClass {
+    /// Bucket size in bytes
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.rate)
+    }
+}
+
+/// Daily window `[start, end)` in UTC hours, wrapping past midnight if start > end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AllowedHours {
+    pub start: u8,
+    pub end: u8,
+}
+
+/// DNS forwarder on the tunnel address, for clients pushed that address as
+/// their DNS server
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResolverConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// UDP address to answer on, the server's tunnel address port 53 if empty
+    #[serde(default)]
+    pub listen: String,
+
+    /// Resolvers queries are forwarded to, tried in order: `ip:port` for
+    /// plain DNS, `tls://host[:port]` for DoT, `https://host[:port][/path]` for DoH
+    #[serde(default = "default_resolver_upstream")]
+    pub upstream: Vec<String>,
+
+    /// Hosts-format files of domains to block, along with their subdomains
+    #[serde(default)]
+    pub blocklists: Vec<String>,
+
+    /// Seconds between re-reads of the blocklists
+    #[serde(default = "default_blocklist_refresh")]
+    pub blocklist_refresh: u64,
+
+    /// Answer for blocked names
+    #[serde(default)]
+    pub block_response: BlockResponse,
+
+    /// Block for users whose policy doesn't set `dns_blocking`, and for
+    /// sessions without a user
+    #[serde(default = "default_true")]
+    pub block_by_default: bool,
+}
+
+/// How the forwarder answers a query for a blocked name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockResponse {
+    /// The name doesn't exist
+    #[default]
+    Nxdomain,
+    /// 0.0.0.0 for A and :: for AAAA queries, no records for other types
+    Null,
+}
+
+/// Settings pushed to connected clients (besides the MTU and tunnel address)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PushConfig {
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+
+    /// Destinations (CIDR) clients route through the tunnel
+    #[serde(default = "default_push_routes")]
+    pub routes: Vec<String>,
+
+    /// Seconds between config renewals sent to each client
+    #[serde(default = "default_renew_interval")]
+    pub renew_interval: u64,
+
+    /// Seconds between quality reports clients send, 0 to not ask for any
+    #[serde(default = "default_quality_report_interval")]
+    pub quality_report_interval: u64,
+
+    /// Plain HTTP URLs answering 204, which clients fetch outside the
+    /// tunnel to detect captive portals; empty to not ask for probing
+    #[serde(default = "default_portal_probes")]
+    pub portal_probes: Vec<String>,
+
+    /// Longest a client may route around the tunnel to sign in to a
+    /// captive portal, seconds; 0 to never bypass the tunnel
+    #[serde(default = "default_portal_bypass")]
+    pub portal_bypass: u64,
+
+    /// Have clients route their LAN's prefixes outside the tunnel, unless
+    /// the user overrides it
+    #[serde(default)]
+    pub lan_bypass: bool,
+
+    /// Destinations (CIDR) clients route outside the tunnel
+    #[serde(default)]
+    pub exclude_routes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MonitoringConfig {
+    #[serde(default = "default_true")]
+    pub enable_metrics: bool,
+
+    #[serde(default = "default_metrics_address")]
+    pub metrics_address: String,
+
+    #[serde(default = "default_metrics_port")]
+    pub metrics_port: u16,
+
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+
+    /// Format of stdout logs: "text", or "json" with one object per line
+    #[serde(default = "default_log_format")]
+    pub log_format: String,
+
+    #[serde(default)]
+    pub traffic_breakdown: bool,
+
+    #[serde(default = "default_traffic_top_ports")]
+    pub traffic_top_ports: usize,
+
+    #[serde(default)]
+    pub packet_trace_sample_rate: u64,
+
+    /// Session label keys exported as metric labels
+    #[serde(default)]
+    pub metric_labels: Vec<String>,
+
+    /// Distinct values exported per label key, the rest are counted as "other"
+    #[serde(default = "default_metric_label_values")]
+    pub metric_label_values: usize,
+
+    #[serde(default)]
+    pub log_file: Option<LogFileConfig>,
+
+    #[serde(default)]
+    pub snmp: SnmpConfig,
+}
+
+/// AgentX subagent exposing counters to the local SNMP agent
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SnmpConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Master agent socket: a Unix socket path or `tcp:host:port`
+    #[serde(default = "default_snmp_master")]
+    pub master: String,
+
+    /// Subtree the objects are registered under, in dotted form
+    #[serde(default = "default_snmp_oid")]
+    pub oid: String,
+
+    /// Seconds to wait before reconnecting to the master agent
+    #[serde(default = "default_snmp_reconnect_interval")]
+    pub reconnect_interval: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogFileConfig {
+    pub path: String,
+
+    #[serde(default = "default_log_max_size_mb")]
+    pub max_size_mb: u64,
+
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+
+    #[serde(default = "default_true")]
+    pub compress: bool,
+}
+
+/// Site-to-site links with other LLP servers
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FederationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Name this server announces itself as (the user name of its links)
+    #[serde(default)]
+    pub site_name: String,
+
+    /// Local subnets (IPv4 CIDR) announced to peer sites
+    #[serde(default)]
+    pub announce: Vec<String>,
+
+    /// Pass routes learned from one peer on to the others
+    #[serde(default = "default_true")]
+    pub readvertise: bool,
+
+    /// Route subnets learned from peers into the TUN interface
+    #[serde(default)]
+    pub install_routes: bool,
+
+    /// Seconds between reconnect attempts of outgoing links
+    #[serde(default = "default_reconnect_interval")]
+    pub reconnect_interval: u64,
+
+    #[serde(default)]
+    pub peers: Vec<PeerSite>,
+}
+
+/// A federated site
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PeerSite {
+    /// Site name, the peer connects with it as user name
+    pub name: String,
+
+    /// Server to link to (`host:port`); unset if the peer links to us
+    #[serde(default)]
+    pub address: Option<String>,
+
+    /// Secret both sites of the link configure, proving their names
+    #[serde(default)]
+    pub secret: String,
+
+    /// Subnets (CIDR) the peer may announce routes within; routes outside
+    /// them are ignored
+    #[serde(default)]
+    pub allowed_prefixes: Vec<String>,
+}
+
+/// Hot-standby pair: the active server replicates its state to a standby,
+/// which takes over the virtual IP once the active goes silent
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FailoverConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// `active` or `standby`
+    #[serde(default = "default_failover_role")]
+    pub role: String,
+
+    /// Active: address the replication listener binds. Standby: the
+    /// active's replication address
+    #[serde(default)]
+    pub address: String,
+
+    /// Secret shared by both servers, the replication channel is
+    /// authenticated with it
+    #[serde(default)]
+    pub secret: String,
+
+    /// Address clients connect to (CIDR), held by whichever server is active
+    #[serde(default)]
+    pub virtual_ip: Option<String>,
+
+    /// Interface the virtual IP is added to
+    #[serde(default)]
+    pub interface: Option<String>,
+
+    /// Seconds between state updates, which double as heartbeats
+    #[serde(default = "default_failover_interval")]
+    pub interval: u64,
+
+    /// Updates the standby may miss before it takes over
+    #[serde(default = "default_failover_missed")]
+    pub missed_updates: u32,
+}
+
+/// Servers sharing their load, new clients are steered across them
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FleetConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Name of this server within the fleet
+    #[serde(default)]
+    pub node: String,
+
+    /// UDP port load reports from other nodes arrive on
+    #[serde(default = "default_fleet_port")]
+    pub port: u16,
+
+    /// Secret shared by all nodes, load reports are authenticated with it
+    #[serde(default)]
+    pub secret: String,
+
+    /// Seconds between load reports
+    #[serde(default = "default_fleet_interval")]
+    pub interval: u64,
+
+    /// How far above the fleet's average load a node may get before clients
+    /// hashed to it go elsewhere
+    #[serde(default = "default_fleet_load_factor")]
+    pub load_factor: f64,
+
+    #[serde(default)]
+    pub peers: Vec<FleetPeer>,
+}
+
+/// Operator scripts run on session events, session details in `LLP_*` env vars
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HooksConfig {
+    /// Run after the handshake; a non-zero exit refuses the session
+    #[serde(default)]
+    pub on_connect: Option<String>,
+
+    /// Run after a session ended
+    #[serde(default)]
+    pub on_disconnect: Option<String>,
+
+    /// Run after a client failed the handshake
+    #[serde(default)]
+    pub on_auth_fail: Option<String>,
+
+    /// Seconds a script may run before it is killed
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+
+    /// Scripts running at once, further ones wait for a slot
+    #[serde(default = "default_hook_max_concurrent")]
+    pub max_concurrent: usize,
+}
+
+/// Another server of the fleet
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FleetPeer {
+    pub name: String,
+
+    /// Address steered clients connect to (`host:port`)
+    pub address: String,
+
+    /// Where its load reports go (`host:port` of its fleet port)
+    pub report_address: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AdminConfig {
+    #[serde(default = "default_true")]
+    pub enable_control_socket: bool,
+
+    #[serde(default = "default_control_socket")]
+    pub control_socket: String,
+
+    #[serde(default)]
+    pub management: ManagementConfig,
+}
+
+/// Signed commands the operator sends to clients that opt in, such as
+/// diagnostics, reconnects and new routes or DNS
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ManagementConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Key (32 bytes in hex) each user's HMAC-SHA256 command key is derived from
+    #[serde(default)]
+    pub key: String,
+
+    /// File every command and the client's answer are appended to, empty to
+    /// only log them
+    #[serde(default = "default_management_audit_log")]
+    pub audit_log: String,
+
+    /// Seconds to wait for a client's answer
+    #[serde(default = "default_management_reply_timeout")]
+    pub reply_timeout: u64,
+}
+
+// Defaults
+fn default_bind_address() -> String { "0.0.0.0".to_string() }
+fn default_port() -> u16 { 8443 }
+fn default_protocol() -> String { "tcp".to_string() }
+fn default_hop_port_min() -> u16 { 20000 }
+fn default_hop_port_max() -> u16 { 29999 }
+fn default_hop_interval() -> u64 { 60 }
+fn default_knock_port() -> u16 { 62201 }
+fn default_knock_window() -> u64 { 30 }
+fn default_knock_max_skew() -> u64 { 30 }
+fn default_decoy_connect_timeout() -> u64 { 5 }
+fn default_decoy_idle_timeout() -> u64 { 60 }
+fn default_overload_retry_after() -> u64 { 10 }
+fn default_keepalive_idle() -> u64 { 60 }
+fn default_descriptor_address() -> String { "0.0.0.0:8080".to_string() }
+fn default_descriptor_validity() -> u64 { 86_400 }
+fn default_keepalive_interval() -> u64 { 10 }
+fn default_keepalive_count() -> u32 { 6 }
+fn default_max_connections() -> usize { 1000 }
+fn default_worker_threads() -> usize { 0 }
+fn default_runtime() -> String { "multi-thread".to_string() }
+fn default_pid_file() -> String { "/run/lostlove/server.pid".to_string() }
+fn default_tun_name() -> String { "hfp0".to_string() }
+fn default_tun_address() -> String { "10.8.0.1/24".to_string() }
+fn default_mtu() -> usize { 1400 }
+fn default_tun_queues() -> usize { 1 }
+fn default_rate_limit() -> u64 { 100_000_000 }
+fn default_max_streams() -> usize { 256 }
+fn default_connection_timeout() -> u64 { 300 }
+fn default_cleanup_interval() -> u64 { 60 }
+fn default_error_responses_per_sec() -> u32 { 10 }
+fn default_unknown_packet_types() -> String { "drop".to_string() }
+fn default_policy_grace_period() -> u64 { 300 }
+fn default_quota_warning_percent() -> u8 { 90 }
+fn default_hibernate_after() -> u64 { 60 }
+fn default_max_packet_size() -> usize { 16384 }
+fn default_read_timeout() -> u64 { 10 }
+fn default_packet_timeout() -> u64 { 30 }
+fn default_true() -> bool { true }
+fn default_metrics_address() -> String { "127.0.0.1".to_string() }
+fn default_metrics_port() -> u16 { 9090 }
+fn default_log_level() -> String { "info".to_string() }
+fn default_log_format() -> String { "text".to_string() }
+fn default_traffic_top_ports() -> usize { 5 }
+fn default_metric_label_values() -> usize { 20 }
+fn default_push_routes() -> Vec<String> { vec!["0.0.0.0/0".to_string()] }
+fn default_renew_interval() -> u64 { 3600 }
+fn default_quality_report_interval() -> u64 { 60 }
+fn default_portal_probes() -> Vec<String> {
+    vec![
+        "http://connectivitycheck.gstatic.com/generate_204".to_string(),
+        "http://cp.cloudflare.com/generate_204".to_string(),
+    ]
+}
+fn default_portal_bypass() -> u64 { 300 }
+fn default_reconnect_interval() -> u64 { 10 }
+fn default_snmp_master() -> String { "/var/agentx/master".to_string() }
+fn default_snmp_oid() -> String { "1.3.6.1.4.1.8072.9999.9999".to_string() }
+fn default_snmp_reconnect_interval() -> u64 { 15 }
+fn default_fleet_port() -> u16 { 8602 }
+fn default_fleet_interval() -> u64 { 2 }
+fn default_fleet_load_factor() -> f64 { 1.25 }
+fn default_hook_timeout() -> u64 { 10 }
+fn default_hook_max_concurrent() -> usize { 4 }
+fn default_failover_role() -> String { "active".to_string() }
+fn default_failover_interval() -> u64 { 1 }
+fn default_failover_missed() -> u32 { 3 }
+fn default_isolation_table_base() -> u32 { 1000 }
+fn default_punch_delay_ms() -> u64 { 500 }
+fn default_offer_timeout() -> u64 { 10 }
+fn default_dns_port() -> u16 { 53 }
+fn default_max_tunnels() -> usize { 64 }
+fn default_tunnel_idle_timeout() -> u64 { 120 }
+fn default_log_max_size_mb() -> u64 { 100 }
+fn default_log_max_files() -> usize { 5 }
+fn default_control_socket() -> String { "/run/lostlove/control.sock".to_string() }
+fn default_resolver_upstream() -> Vec<String> { vec!["1.1.1.1:53".to_string(), "9.9.9.9:53".to_string()] }
+fn default_blocklist_refresh() -> u64 { 3600 }
+fn default_management_audit_log() -> String { "/var/log/lostlove/management.log".to_string() }
+fn default_management_reply_timeout() -> u64 { 30 }
+fn default_flow_timeout() -> u64 { 300 }
+fn default_max_flows() -> usize { 65536 }
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit_per_user: default_rate_limit(),
+            burst_per_user: None,
+            max_streams_per_connection: default_max_streams(),
+            connection_timeout: default_connection_timeout(),
+            cleanup_interval: default_cleanup_interval(),
+            error_responses_per_sec: default_error_responses_per_sec(),
+            unknown_packet_types: default_unknown_packet_types(),
+            policy_grace_period: default_policy_grace_period(),
+            quota_warning_percent: default_quota_warning_percent(),
+            hibernate_after: default_hibernate_after(),
+            max_memory_mb: 0,
+            max_connection_memory_kb: 0,
+            max_packet_size: default_max_packet_size(),
+            read_timeout: default_read_timeout(),
+            packet_timeout: default_packet_timeout(),
+        }
+    }
+}
+
+impl Default for MonitoringConfig {
+    fn default() -> Self {
+        Self {
+            enable_metrics: default_true(),
+            metrics_address: default_metrics_address(),
+            metrics_port: default_metrics_port(),
+            log_level: default_log_level(),
+            log_format: default_log_format(),
+            traffic_breakdown: false,
+            traffic_top_ports: default_traffic_top_ports(),
+            packet_trace_sample_rate: 0,
+            metric_labels: Vec::new(),
+            metric_label_values: default_metric_label_values(),
+            log_file: None,
+            snmp: SnmpConfig::default(),
+        }
+    }
+}
+
+impl Default for SnmpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            master: default_snmp_master(),
+            oid: default_snmp_oid(),
+            reconnect_interval: default_snmp_reconnect_interval(),
+        }
+    }
+}
+
+impl Default for FirewallConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            flow_timeout: default_flow_timeout(),
+            max_flows: default_max_flows(),
+        }
+    }
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            punch_delay_ms: default_punch_delay_ms(),
+            offer_timeout: default_offer_timeout(),
+            discovery_port: None,
+        }
+    }
+}
+
+impl Default for PortHoppingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            secret: String::new(),
+            port_min: default_hop_port_min(),
+            port_max: default_hop_port_max(),
+            interval: default_hop_interval(),
+        }
+    }
+}
+
+impl Default for KnockConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            secret: String::new(),
+            port: default_knock_port(),
+            window: default_knock_window(),
+            max_skew: default_knock_max_skew(),
+            firewall: false,
+        }
+    }
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            max_load: None,
+            retry_after: default_overload_retry_after(),
+        }
+    }
+}
+
+impl Default for DescriptorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: default_descriptor_address(),
+            hosts: Vec::new(),
+            key: String::new(),
+            validity: default_descriptor_validity(),
+        }
+    }
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            dont_fragment: true,
+            pktinfo: true,
+        }
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_idle: default_keepalive_idle(),
+            keepalive_interval: default_keepalive_interval(),
+            keepalive_count: default_keepalive_count(),
+            send_buffer: None,
+            recv_buffer: None,
+            fastopen_queue: 0,
+            early_data: false,
+        }
+    }
+}
+
+impl Default for DecoyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: String::new(),
+            connect_timeout: default_decoy_connect_timeout(),
+            idle_timeout: default_decoy_idle_timeout(),
+        }
+    }
+}
+
+impl Default for DnsTunnelConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            zone: String::new(),
+            port: default_dns_port(),
+            max_tunnels: default_max_tunnels(),
+            idle_timeout: default_tunnel_idle_timeout(),
+        }
+    }
+}
+
+impl Default for IcmpTunnelConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_tunnels: default_max_tunnels(),
+            idle_timeout: default_tunnel_idle_timeout(),
+        }
+    }
+}
+
+impl Default for IsolationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            table_base: default_isolation_table_base(),
+        }
+    }
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            dns: Vec::new(),
+            routes: default_push_routes(),
+            renew_interval: default_renew_interval(),
+            quality_report_interval: default_quality_report_interval(),
+            portal_probes: default_portal_probes(),
+            portal_bypass: default_portal_bypass(),
+            lan_bypass: false,
+            exclude_routes: Vec::new(),
+        }
+    }
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: String::new(),
+            upstream: default_resolver_upstream(),
+            blocklists: Vec::new(),
+            blocklist_refresh: default_blocklist_refresh(),
+            block_response: BlockResponse::default(),
+            block_by_default: default_true(),
+        }
+    }
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            site_name: String::new(),
+            announce: Vec::new(),
+            readvertise: default_true(),
+            install_routes: false,
+            reconnect_interval: default_reconnect_interval(),
+            peers: Vec::new(),
+        }
+    }
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            role: default_failover_role(),
+            address: String::new(),
+            secret: String::new(),
+            virtual_ip: None,
+            interface: None,
+            interval: default_failover_interval(),
+            missed_updates: default_failover_missed(),
+        }
+    }
+}
+
+impl Default for FleetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            node: String::new(),
+            port: default_fleet_port(),
+            secret: String::new(),
+            interval: default_fleet_interval(),
+            load_factor: default_fleet_load_factor(),
+            peers: Vec::new(),
+        }
+    }
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            on_connect: None,
+            on_disconnect: None,
+            on_auth_fail: None,
+            timeout: default_hook_timeout(),
+            max_concurrent: default_hook_max_concurrent(),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enable_control_socket: default_true(),
+            control_socket: default_control_socket(),
+            management: ManagementConfig::default(),
+        }
+    }
+}
+
+impl Default for ManagementConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key: String::new(),
+            audit_log: default_management_audit_log(),
+            reply_timeout: default_management_reply_timeout(),
+        }
+    }
+}
+
+/// Prefix of the environment variables `Config::from_env` reads
+const ENV_PREFIX: &str = "LLP_";
+
+/// What `Config::from_env` starts from before applying the environment
+const CONTAINER_DEFAULTS: &str = r#"
+[server]
+pid_file = ""
+
+[network]
+
+[monitoring]
+metrics_address = "0.0.0.0"
+log_format = "json"
+"#;
+
+/// TOML value of an environment variable, a plain string unless it parses
+/// as a number, boolean, array or quoted string
+fn env_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .filter(|table| table.len() == 1)
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// Set `path` in `table`, creating the tables on the way
+fn set_key(table: &mut toml::Table, path: &[String], value: toml::Value) -> Result<()> {
+    let (key, parents) = path.split_last().context("empty key")?;
+    let mut table = table;
+    for parent in parents {
+        if parent.is_empty() {
+            anyhow::bail!("empty key");
+        }
+        table = table
+            .entry(parent.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .with_context(|| format!("{} is not a section", parent))?;
+    }
+    if key.is_empty() {
+        anyhow::bail!("empty key");
+    }
+    table.insert(key.clone(), value);
+    Ok(())
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path.as_ref())
+            .context("Failed to read configuration file")?;
+
+        let mut config: Config = toml::from_str(&content)
+            .context("Failed to parse configuration file")?;
+
+        config.unseal_secrets()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Configuration from `LLP_<SECTION>__<KEY>` environment variables alone
+    ///
+    /// For containers: stdout logs are JSON, metrics and health endpoints
+    /// listen on all addresses and no pid file is locked unless the
+    /// environment says otherwise.
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(std::env::vars())
+    }
+
+    fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut table: toml::Table = toml::from_str(CONTAINER_DEFAULTS).expect("container defaults are valid TOML");
+
+        for (name, value) in vars {
+            // LLP_KEYLOG_FILE and the like are not config keys
+            let Some(key) = name.strip_prefix(ENV_PREFIX).filter(|key| key.contains("__")) else {
+                continue;
+            };
+            let path: Vec<String> = key.split("__").map(str::to_lowercase).collect();
+            set_key(&mut table, &path, env_value(&value)).with_context(|| format!("Invalid {}", name))?;
+        }
+
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .context("Failed to parse configuration from environment")?;
+
+        config.unseal_secrets()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Replace `tpm:<path>` secrets by the values sealed in those files
+    fn unseal_secrets(&mut self) -> Result<()> {
+        let secrets = [
+            ("server.obfuscation.server_key", &mut self.server.obfuscation.server_key),
+            ("server.port_hopping.secret", &mut self.server.port_hopping.secret),
+            ("server.knock.secret", &mut self.server.knock.secret),
+            ("server.descriptor.key", &mut self.server.descriptor.key),
+            ("admin.management.key", &mut self.admin.management.key),
+            ("failover.secret", &mut self.failover.secret),
+            ("fleet.secret", &mut self.fleet.secret),
+        ];
+        let peer_secrets = self
+            .federation
+            .peers
+            .iter_mut()
+            .map(|peer| (format!("the secret of federation peer {}", peer.name), &mut peer.secret));
+
+        let secrets = secrets.into_iter().map(|(name, secret)| (name.to_string(), secret)).chain(peer_secrets);
+        for (name, secret) in secrets {
+            let Some(path) = tpm::sealed_path(secret) else {
+                continue;
+            };
+            let sealed = SealedSecret::load(path)
+                .and_then(|sealed| sealed.unseal())
+                .with_context(|| format!("Failed to unseal {} from {}", name, path.display()))?;
+            *secret = String::from_utf8(sealed.to_vec())
+                .with_context(|| format!("Sealed {} is not UTF-8", name))?;
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        // Validate bind address
+        if self.server.bind_address.is_empty() {
+            anyhow::bail!("bind_address cannot be empty");
+        }
+
+        // Validate port
+        if self.server.port == 0 {
+            anyhow::bail!("port must be greater than 0");
+        }
+
+        if !["text", "json"].contains(&self.monitoring.log_format.as_str()) {
+            anyhow::bail!("monitoring.log_format must be \"text\" or \"json\"");
+        }
+
+        // Validate protocol
+        if !["tcp", "udp", "both"].contains(&self.server.protocol.as_str()) {
+            anyhow::bail!("protocol must be one of: tcp, udp, both");
+        }
+
+        // Validate runtime
+        match self.server.runtime.as_str() {
+            "multi-thread" => {}
+            "current-thread" => {
+                if self.server.worker_threads > 1 {
+                    anyhow::bail!("runtime = \"current-thread\" runs a single worker, worker_threads must be 0 or 1");
+                }
+                if self.server.pin_workers {
+                    anyhow::bail!("pin_workers needs runtime = \"multi-thread\"");
+                }
+            }
+            _ => anyhow::bail!("runtime must be one of: multi-thread, current-thread"),
+        }
+        let hopping = &self.server.port_hopping;
+        if hopping.enabled {
+            if self.server.protocol == "tcp" {
+                anyhow::bail!("server.port_hopping needs protocol udp or both");
+            }
+            if hopping.secret.is_empty() {
+                anyhow::bail!("server.port_hopping.secret must be set");
+            }
+            if hopping.port_min == 0 || hopping.port_min > hopping.port_max {
+                anyhow::bail!("server.port_hopping port range is invalid");
+            }
+            if hopping.interval == 0 {
+                anyhow::bail!("server.port_hopping.interval must be greater than 0");
+            }
+        }
+        let knock = &self.server.knock;
+        if knock.enabled && (knock.secret.is_empty() || knock.window == 0) {
+            anyhow::bail!("server.knock needs a secret and a window greater than 0");
+        }
+        // Bridged clients reach the listener from loopback, past the knock
+        if knock.enabled && (self.network.dns.enabled || self.network.icmp.enabled) {
+            anyhow::bail!("server.knock can't be enabled with the DNS or ICMP transport");
+        }
+        let decoy = &self.server.decoy;
+        if decoy.enabled && decoy.address.is_empty() {
+            anyhow::bail!("server.decoy.address must be set");
+        }
+        let obfuscation = &self.server.obfuscation;
+        if obfuscation.enabled && hex::decode(&obfuscation.server_key).map_or(true, |key| key.len() != 32) {
+            anyhow::bail!("server.obfuscation.server_key must be 32 bytes in hex");
+        }
+        if obfuscation.enabled && !CryptoPolicy::from_config(&self.security).allows_obfuscation() {
+            anyhow::bail!("server.obfuscation seals with ChaCha20-Poly1305 and can't be enabled in FIPS mode");
+        }
+        let overload = &self.server.overload;
+        if overload.max_load.is_some_and(|load| load.is_nan() || load <= 0.0) {
+            anyhow::bail!("server.overload.max_load must be greater than 0");
+        }
+        if overload.retry_after == 0 {
+            anyhow::bail!("server.overload.retry_after must be greater than 0");
+        }
+        let tcp = &self.server.tcp;
+        if tcp.keepalive_idle > 0 && (tcp.keepalive_interval == 0 || tcp.keepalive_count == 0) {
+            anyhow::bail!("server.tcp.keepalive_interval and keepalive_count must be greater than 0");
+        }
+        if tcp.send_buffer == Some(0) || tcp.recv_buffer == Some(0) {
+            anyhow::bail!("server.tcp buffer sizes must be greater than 0");
+        }
+        let descriptor = &self.server.descriptor;
+        if descriptor.enabled {
+            if descriptor.hosts.is_empty() {
+                anyhow::bail!("server.descriptor.hosts cannot be empty when the descriptor is enabled");
+            }
+            if hex::decode(&descriptor.key).map_or(true, |key| key.len() != 32) {
+                anyhow::bail!("server.descriptor.key must be 32 bytes in hex");
+            }
+            if descriptor.validity == 0 {
+                anyhow::bail!("server.descriptor.validity must be greater than 0");
+            }
+        }
+        let resolver = &self.resolver;
+        if resolver.enabled {
+            if !resolver.listen.is_empty() && resolver.listen.parse::<SocketAddr>().is_err() {
+                anyhow::bail!("resolver.listen must be an ip:port, got {:?}", resolver.listen);
+            }
+            if resolver.upstream.is_empty() {
+                anyhow::bail!("resolver.upstream cannot be empty when the resolver is enabled");
+            }
+            for upstream in &resolver.upstream {
+                UpstreamSpec::parse(upstream)?;
+            }
+            if resolver.blocklist_refresh == 0 {
+                anyhow::bail!("resolver.blocklist_refresh must be greater than 0");
+            }
+        }
+        let management = &self.admin.management;
+        if management.enabled {
+            if hex::decode(&management.key).map_or(true, |key| key.len() != 32) {
+                anyhow::bail!("admin.management.key must be 32 bytes in hex");
+            }
+            if management.reply_timeout == 0 {
+                anyhow::bail!("admin.management.reply_timeout must be greater than 0");
+            }
+        }
+        if self.server.qos.dscp.is_some_and(|dscp| dscp > MAX_DSCP) {
+            anyhow::bail!("server.qos.dscp must be between 0 and {}", MAX_DSCP);
+        }
+
+        // Validate control socket
+        if self.admin.enable_control_socket && self.admin.control_socket.is_empty() {
+            anyhow::bail!("control_socket cannot be empty when the control socket is enabled");
+        }
+
+        // Validate metric labels
+        for key in &self.monitoring.metric_labels {
+            crate::core::labels::validate_key(key)?;
+        }
+        if !self.monitoring.metric_labels.is_empty() && self.monitoring.metric_label_values == 0 {
+            anyhow::bail!("monitoring.metric_label_values must be greater than 0");
+        }
+
+        // Validate file logging
+        if let Some(log_file) = &self.monitoring.log_file {
+            if log_file.path.is_empty() {
+                anyhow::bail!("log_file.path cannot be empty");
+            }
+            if log_file.max_size_mb == 0 {
+                anyhow::bail!("log_file.max_size_mb must be greater than 0");
+            }
+        }
+
+        // Validate firewall
+        if self.firewall.enabled && self.firewall.max_flows == 0 {
+            anyhow::bail!("firewall.max_flows must be greater than 0");
+        }
+        if self.firewall.enabled && !self.network.validate_source {
+            anyhow::bail!("firewall requires network.validate_source = true");
+        }
+
+        // Validate egress
+        let egress = &self.network.egress;
+        let egress_set = egress.interface.is_some()
+            || egress.snat_address.is_some()
+            || egress.fwmark.is_some()
+            || egress.gateway.is_some();
+        if egress_set && !self.network.manage_nat {
+            anyhow::bail!("network.egress requires network.manage_nat = true");
+        }
+        if egress.interface.as_deref() == Some("") {
+            anyhow::bail!("network.egress.interface cannot be empty");
+        }
+        if egress.fwmark == Some(0) {
+            anyhow::bail!("network.egress.fwmark must be greater than 0");
+        }
+        if egress.fwmark.is_some() && egress.interface.is_none() {
+            anyhow::bail!("network.egress.fwmark requires network.egress.interface");
+        }
+        if egress.gateway.is_some() && egress.fwmark.is_none() {
+            anyhow::bail!("network.egress.gateway requires network.egress.fwmark");
+        }
+
+        // Validate isolation
+        if self.network.isolation.enabled {
+            if !self.network.manage_nat || egress.interface.is_none() {
+                anyhow::bail!(
+                    "network.isolation requires network.manage_nat and network.egress.interface"
+                );
+            }
+            if egress.fwmark.is_some() {
+                anyhow::bail!("network.isolation cannot be combined with network.egress.fwmark");
+            }
+        }
+
+        // Validate pushed settings
+        if self.push.renew_interval == 0 {
+            anyhow::bail!("push.renew_interval must be greater than 0");
+        }
+        for route in &self.push.routes {
+            if route.parse::<IpNet>().is_err() {
+                anyhow::bail!("Invalid push route {:?}, expected CIDR", route);
+            }
+        }
+        for route in &self.push.exclude_routes {
+            if route.parse::<IpNet>().is_err() {
+                anyhow::bail!("Invalid push exclude route {:?}, expected CIDR", route);
+            }
+        }
+        // Portals can only intercept plain HTTP
+        if let Some(probe) = self.push.portal_probes.iter().find(|probe| !probe.starts_with("http://")) {
+            anyhow::bail!("push.portal_probes must be http:// URLs, got {:?}", probe);
+        }
+
+        // Validate federation
+        let federation = &self.federation;
+        if federation.enabled {
+            if federation.site_name.is_empty() {
+                anyhow::bail!("federation.site_name cannot be empty");
+            }
+            if federation.reconnect_interval == 0 {
+                anyhow::bail!("federation.reconnect_interval must be greater than 0");
+            }
+            for route in &federation.announce {
+                if route.parse::<IpNet>().is_err() {
+                    anyhow::bail!("Invalid federation route {:?}, expected CIDR", route);
+                }
+            }
+            for (i, peer) in federation.peers.iter().enumerate() {
+                if peer.name.is_empty() || peer.name == federation.site_name {
+                    anyhow::bail!("federation peer name must be set and differ from site_name");
+                }
+                if federation.peers[..i].iter().any(|other| other.name == peer.name) {
+                    anyhow::bail!("Duplicate federation peer {:?}", peer.name);
+                }
+                if peer.secret.is_empty() {
+                    anyhow::bail!("federation peer {:?} needs a secret", peer.name);
+                }
+                if let Some(prefix) = peer.allowed_prefixes.iter().find(|prefix| prefix.parse::<IpNet>().is_err()) {
+                    anyhow::bail!("Invalid allowed prefix {:?} of federation peer {:?}", prefix, peer.name);
+                }
+            }
+        }
+
+        // Validate failover
+        let failover = &self.failover;
+        if failover.enabled {
+            if !matches!(failover.role.as_str(), "active" | "standby") {
+                anyhow::bail!("failover.role must be \"active\" or \"standby\"");
+            }
+            if failover.address.is_empty() || failover.secret.is_empty() {
+                anyhow::bail!("failover needs an address and a secret");
+            }
+            if failover.interval == 0 || failover.missed_updates == 0 {
+                anyhow::bail!("failover.interval and failover.missed_updates must be greater than 0");
+            }
+            if let Some(virtual_ip) = &failover.virtual_ip {
+                if virtual_ip.parse::<IpNet>().is_err() {
+                    anyhow::bail!("Invalid failover.virtual_ip {:?}, expected CIDR", virtual_ip);
+                }
+                if failover.interface.is_none() {
+                    anyhow::bail!("failover.virtual_ip needs failover.interface");
+                }
+                if !cfg!(target_os = "linux") {
+                    anyhow::bail!("failover.virtual_ip is only supported on Linux");
+                }
+            }
+        }
+
+        // Validate SNMP
+        let snmp = &self.monitoring.snmp;
+        if snmp.enabled {
+            let oid = crate::metrics::snmp::parse_oid(&snmp.oid)?;
+            if !oid.starts_with(&[1, 3, 6, 1]) {
+                anyhow::bail!("monitoring.snmp.oid must be under 1.3.6.1");
+            }
+            if snmp.reconnect_interval == 0 {
+                anyhow::bail!("monitoring.snmp.reconnect_interval must be greater than 0");
+            }
+        }
+
+        // Validate fleet
+        let fleet = &self.fleet;
+        if fleet.enabled {
+            if fleet.node.is_empty() || fleet.secret.is_empty() {
+                anyhow::bail!("fleet needs a node name and a secret");
+            }
+            if fleet.interval == 0 {
+                anyhow::bail!("fleet.interval must be greater than 0");
+            }
+            if fleet.load_factor.is_nan() || fleet.load_factor < 1.0 {
+                anyhow::bail!("fleet.load_factor must be at least 1.0");
+            }
+            for (i, peer) in fleet.peers.iter().enumerate() {
+                if peer.name.is_empty() || peer.name == fleet.node {
+                    anyhow::bail!("fleet peer name must be set and differ from node");
+                }
+                if peer.address.is_empty() || peer.report_address.is_empty() {
+                    anyhow::bail!("fleet peer {:?} needs an address and a report_address", peer.name);
+                }
+                if fleet.peers[..i].iter().any(|other| other.name == peer.name) {
+                    anyhow::bail!("Duplicate fleet peer {:?}", peer.name);
+                }
+            }
+        }
+
+        // Validate hooks
+        let hooks = &self.hooks;
+        for (name, script) in [
+            ("on_connect", &hooks.on_connect),
+            ("on_disconnect", &hooks.on_disconnect),
+            ("on_auth_fail", &hooks.on_auth_fail),
+        ] {
+            if script.as_ref().is_some_and(|script| !Path::new(script).is_absolute()) {
+                anyhow::bail!("hooks.{} must be an absolute path", name);
+            }
+        }
+        if hooks.timeout == 0 || hooks.max_concurrent == 0 {
+            anyhow::bail!("hooks.timeout and hooks.max_concurrent must be greater than 0");
+        }
+
+        // Validate access policies
+        if (1..8).contains(&self.limits.max_connection_memory_kb) {
+            anyhow::bail!("limits.max_connection_memory_kb must be 0 or at least 8");
+        }
+        if self.limits.read_timeout == 0 || self.limits.packet_timeout < self.limits.read_timeout {
+            anyhow::bail!("limits.read_timeout must be greater than 0 and at most limits.packet_timeout");
+        }
+        // Data packets carry whole tunnel packets
+        if self.limits.max_packet_size < HEADER_SIZE + self.network.mtu {
+            anyhow::bail!(
+                "limits.max_packet_size must be at least network.mtu + {} bytes of header",
+                HEADER_SIZE
+            );
+        }
+        if self.limits.max_packet_size > HEADER_SIZE + MAX_PAYLOAD_SIZE {
+            anyhow::bail!(
+                "limits.max_packet_size must be at most {}, the header's length field allows no more",
+                HEADER_SIZE + MAX_PAYLOAD_SIZE
+            );
+        }
+        if self.limits.quota_warning_percent > 100 {
+            anyhow::bail!("limits.quota_warning_percent must be at most 100");
+        }
+        for (user, policy) in &self.policies {
+            if let Some(hours) = policy.allowed_hours {
+                if hours.start > 23 || hours.end > 24 || hours.start == hours.end {
+                    anyhow::bail!("policies.{}.allowed_hours must be a non-empty range of 0-24", user);
+                }
+            }
+            if let Some(class) = &policy.class {
+                if !self.classes.contains_key(class) {
+                    anyhow::bail!("policies.{}.class refers to unknown class {:?}", user, class);
+                }
+            }
+            for quota in [policy.daily_quota_gb, policy.monthly_quota_gb].into_iter().flatten() {
+                if quota.is_nan() || quota <= 0.0 {
+                    anyhow::bail!("policies.{} quotas must be greater than 0", user);
+                }
+            }
+        }
+
+        // Validate bandwidth classes
+        for (name, class) in &self.classes {
+            if class.rate == 0 || class.burst() == 0 {
+                anyhow::bail!("classes.{} rate and burst must be greater than 0", name);
+            }
+        }
+        if self.network.p2p.enabled && self.network.p2p.offer_timeout == 0 {
+            anyhow::bail!("network.p2p.offer_timeout must be greater than 0");
+        }
+        let dns = &self.network.dns;
+        if dns.enabled {
+            let zone = dns.zone.trim_end_matches('.');
+            if zone.is_empty() || zone.split('.').any(|label| label.is_empty() || label.len() > 63) {
+                anyhow::bail!("network.dns.zone must be a domain name");
+            }
+            if dns.max_tunnels == 0 || dns.idle_timeout == 0 {
+                anyhow::bail!("network.dns.max_tunnels and idle_timeout must be greater than 0");
+            }
+        }
+        let icmp = &self.network.icmp;
+        if icmp.enabled && (icmp.max_tunnels == 0 || icmp.idle_timeout == 0) {
+            anyhow::bail!("network.icmp.max_tunnels and idle_timeout must be greater than 0");
+        }
+        if self.network.relay.burst_per_path == Some(0) {
+            anyhow::bail!("network.relay.burst_per_path must be greater than 0");
+        }
+        if self.limits.burst_per_user == Some(0) {
+            anyhow::bail!("limits.burst_per_user must be greater than 0");
+        }
+        if self.limits.cleanup_interval == 0 {
+            anyhow::bail!("limits.cleanup_interval must be greater than 0");
+        }
+        if !["drop", "error"].contains(&self.limits.unknown_packet_types.as_str()) {
+            anyhow::bail!("limits.unknown_packet_types must be one of: drop, error");
+        }
+
+        // Tunnel addressing is IPv4 until the pool and TUN setup lease IPv6
+        let tun_address = self
+            .network
+            .tun_address
+            .parse::<IpNet>()
+            .map_err(|e| anyhow::anyhow!("network.tun_address: {}", e))?;
+        if tun_address.ipv4("network.tun_address").is_err() {
+            anyhow::bail!("network.tun_address must be an IPv4 CIDR, IPv6 tunnels are not supported yet");
+        }
+
+        // Validate MTU
+        if self.network.mtu < 576 || self.network.mtu > 9000 {
+            anyhow::bail!("MTU must be between 576 and 9000");
+        }
+
+        if self.network.tun_queues == 0 || self.network.tun_queues > 256 {
+            anyhow::bail!("network.tun_queues must be between 1 and 256");
+        }
+        if self.network.tun_queues > 1 && !cfg!(target_os = "linux") {
+            anyhow::bail!("network.tun_queues above 1 needs Linux multi-queue TUN");
+        }
+        if self.network.tun_offload && !cfg!(target_os = "linux") {
+            anyhow::bail!("network.tun_offload needs Linux TUN offloads");
+        }
+
+        Ok(())
+    }
+
+    pub fn default_for_testing() -> Self {
+        Self {
+            server: ServerConfig {
+                bind_address: "127.0.0.1".to_string(),
+                port: 8443,
+                protocol: "tcp".to_string(),
+                max_connections: 100,
+                worker_threads: 2,
+                runtime: "multi-thread".to_string(),
+                pin_workers: false,
+                restart_listeners: false,
+                pid_file: String::new(),
+                port_hopping: PortHoppingConfig::default(),
+                knock: KnockConfig::default(),
+                decoy: DecoyConfig::default(),
+                obfuscation: ObfuscationConfig::default(),
+                overload: OverloadConfig::default(),
+                tcp: TcpConfig::default(),
+                qos: QosConfig::default(),
+                udp: UdpConfig::default(),
+                descriptor: DescriptorConfig::default(),
+            },
+            network: NetworkConfig {
+                tun_name: "hfp0".to_string(),
+                tun_address: "10.8.0.1/24".to_string(),
+                mtu: 1400,
+                tun_queues: 1,
+                tun_offload: false,
+                enable_ipv6: false,
+                validate_source: true,
+                clamp_mss: true,
+                manage_nat: false,
+                egress: EgressConfig::default(),
+                isolation: IsolationConfig::default(),
+                relay: RelayConfig::default(),
+                p2p: P2pConfig::default(),
+                dns: DnsTunnelConfig::default(),
+                icmp: IcmpTunnelConfig::default(),
+            },
+            limits: LimitsConfig::default(),
+            monitoring: MonitoringConfig::default(),
+            admin: AdminConfig::default(),
+            firewall: FirewallConfig::default(),
+            push: PushConfig::default(),
+            resolver: ResolverConfig::default(),
+            policies: BTreeMap::new(),
+            classes: BTreeMap::new(),
+            federation: FederationConfig::default(),
+            failover: FailoverConfig::default(),
+            fleet: FleetConfig::default(),
+            hooks: HooksConfig::default(),
+            security: SecurityConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config() {
         let config = Config::default_for_testing();
         assert_eq!(config.server.port, 8443);
         assert_eq!(config.network.mtu, 1400);
+
+        let config: Config = toml::from_str("[server]\n[network]\n").unwrap();
+        assert_eq!(config.server.pid_file, "/run/lostlove/server.pid");
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let vars = [
+            ("LLP_SERVER__PORT", "9443"),
+            ("LLP_SERVER__PROTOCOL", "both"),
+            ("LLP_SERVER__PORT_HOPPING__SECRET", "\"0123\""),
+            ("LLP_NETWORK__TUN_NAME", "llp1"),
+            ("LLP_NETWORK__MTU", "1280"),
+            ("LLP_MONITORING__METRIC_LABELS", "[\"team\"]"),
+            ("LLP_KEYLOG_FILE", "/tmp/keys"),
+            ("PATH", "/usr/bin"),
+        ];
+        let config = Config::from_vars(vars.map(|(name, value)| (name.to_string(), value.to_string()))).unwrap();
+        assert_eq!(config.server.port, 9443);
+        assert_eq!(config.server.protocol, "both");
+        assert_eq!(config.server.port_hopping.secret, "0123");
+        assert_eq!(config.network.tun_name, "llp1");
+        assert_eq!(config.network.mtu, 1280);
+        assert_eq!(config.monitoring.metric_labels, ["team"]);
+
+        // Container defaults, unless overridden
+        assert_eq!(config.server.pid_file, "");
+        assert_eq!(config.monitoring.log_format, "json");
+        assert_eq!(config.monitoring.metrics_address, "0.0.0.0");
+        let config = Config::from_vars([("LLP_MONITORING__LOG_FORMAT".to_string(), "text".to_string())]).unwrap();
+        assert_eq!(config.monitoring.log_format, "text");
+
+        let vars = [("LLP_SERVER__PORT", "1"), ("LLP_SERVER__PORT__X", "1")];
+        let err = Config::from_vars(vars.map(|(name, value)| (name.to_string(), value.to_string()))).unwrap_err();
+        assert!(format!("{:#}", err).contains("LLP_SERVER__PORT__X: port is not a section"), "{:#}", err);
+        assert!(Config::from_vars([("LLP_SERVER__PORT".to_string(), "high".to_string())]).is_err());
+        let err = Config::from_vars([("LLP_MONITORING__LOG_FORMAT".to_string(), "xml".to_string())]).unwrap_err();
+        assert!(err.to_string().contains("log_format"));
     }
 
     #[test]
@@ -184,4 +1997,768 @@ mod tests {
         config.network.mtu = 100;
         assert!(config.validate().is_err());
     }
+
+    #[test]
+    fn test_tun_queues() {
+        let mut config = Config::default_for_testing();
+        config.network.tun_queues = 8;
+        assert_eq!(config.validate().is_ok(), cfg!(target_os = "linux"));
+
+        for queues in [0, 257] {
+            config.network.tun_queues = queues;
+            assert!(config.validate().is_err());
+        }
+
+        config.network.tun_queues = 1;
+        config.network.tun_offload = true;
+        assert_eq!(config.validate().is_ok(), cfg!(target_os = "linux"));
+    }
+
+    #[test]
+    fn test_log_file_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [monitoring.log_file]
+            path = "/var/log/lostlove/server.log"
+            "#,
+        )
+        .unwrap();
+
+        let log_file = config.monitoring.log_file.as_ref().unwrap();
+        assert_eq!(log_file.max_size_mb, 100);
+        assert_eq!(log_file.max_files, 5);
+        assert!(log_file.compress);
+        assert!(config.validate().is_ok());
+
+        let mut config = config;
+        config.monitoring.log_file.as_mut().unwrap().max_size_mb = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_security_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            "#,
+        )
+        .unwrap();
+        assert!(!config.security.lock_key_memory);
+        assert!(!config.security.disable_core_dumps);
+
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [security]
+            lock_key_memory = true
+            disable_core_dumps = true
+            "#,
+        )
+        .unwrap();
+        assert!(config.security.lock_key_memory);
+        assert!(config.security.disable_core_dumps);
+    }
+
+    #[test]
+    fn test_unseal_secrets() {
+        let mut config = Config::default_for_testing();
+        config.server.knock.secret = "correct horse".to_string();
+        config.unseal_secrets().unwrap();
+        assert_eq!(config.server.knock.secret, "correct horse");
+
+        config.server.knock.secret = "tpm:/nonexistent/knock.sealed".to_string();
+        let err = config.unseal_secrets().unwrap_err();
+        assert!(format!("{:#}", err).contains("server.knock.secret"));
+    }
+
+    #[test]
+    fn test_egress_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            manage_nat = true
+            [network.egress]
+            interface = "eth1"
+            snat_address = "203.0.113.7"
+            fwmark = 51
+            "#,
+        )
+        .unwrap();
+
+        let egress = &config.network.egress;
+        assert_eq!(egress.interface.as_deref(), Some("eth1"));
+        assert_eq!(egress.snat_address, Some(Ipv4Addr::new(203, 0, 113, 7)));
+        assert!(config.validate().is_ok());
+
+        // Policy routing needs an interface to route through
+        config.network.egress.interface = None;
+        assert!(config.validate().is_err());
+
+        // Egress settings have no effect without NAT management
+        let mut config = Config::default_for_testing();
+        config.network.egress.snat_address = Some(Ipv4Addr::new(203, 0, 113, 7));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_push_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [push]
+            dns = ["1.1.1.1", "2606:4700:4700::1111"]
+            routes = ["10.0.0.0/8", "fd00::/8"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.push.dns.len(), 2);
+        assert_eq!(config.push.renew_interval, 3600);
+        assert_eq!(config.push.quality_report_interval, 60);
+        assert_eq!(config.push.portal_probes.len(), 2);
+        assert_eq!(config.push.portal_bypass, 300);
+        assert!(!config.push.lan_bypass);
+        assert!(config.push.exclude_routes.is_empty());
+        assert!(config.validate().is_ok());
+
+        config.push.exclude_routes = vec!["192.168.50.0/24".to_string()];
+        assert!(config.validate().is_ok());
+        config.push.exclude_routes.push("printer.lan".to_string());
+        assert!(config.validate().is_err());
+        config.push.exclude_routes.clear();
+
+        config.push.portal_probes = vec!["https://portal.example.com/204".to_string()];
+        assert!(config.validate().is_err());
+        config.push.portal_probes.clear();
+
+        config.push.routes.push("10.0.0.0".to_string());
+        assert!(config.validate().is_err());
+
+        assert_eq!(Config::default_for_testing().push.routes, vec!["0.0.0.0/0"]);
+    }
+
+    #[test]
+    fn test_tun_address() {
+        let mut config = Config::default_for_testing();
+        assert!(config.validate().is_ok());
+
+        for invalid in ["10.8.0.1", "10.8.0.1/33", "fd00:8::1/64"] {
+            config.network.tun_address = invalid.to_string();
+            assert!(config.validate().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_policies_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [policies.alice]
+            allowed_hours = { start = 8, end = 20 }
+            daily_quota_gb = 2.5
+            [policies.bob]
+            monthly_quota_gb = 100
+            "#,
+        )
+        .unwrap();
+
+        let alice = &config.policies["alice"];
+        assert_eq!(alice.allowed_hours, Some(AllowedHours { start: 8, end: 20 }));
+        assert_eq!(alice.daily_quota_gb, Some(2.5));
+        assert_eq!(config.policies["bob"].monthly_quota_gb, Some(100.0));
+        assert!(config.validate().is_ok());
+
+        config.policies.get_mut("alice").unwrap().allowed_hours = Some(AllowedHours { start: 8, end: 8 });
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_rate_classes_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [classes.free]
+            rate = 1000000
+            [classes.pro]
+            rate = 10000000
+            burst = 50000000
+            [policies.alice]
+            class = "pro"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.classes["free"].burst(), 1_000_000);
+        assert_eq!(config.classes["pro"].burst(), 50_000_000);
+        assert!(config.validate().is_ok());
+
+        config.policies.get_mut("alice").unwrap().class = Some("gold".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_port_hopping_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            protocol = "both"
+            [server.port_hopping]
+            enabled = true
+            secret = "correct horse"
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port_hopping.port_min, 20000);
+        assert!(config.validate().is_ok());
+
+        config.server.protocol = "tcp".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_knock_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server.knock]
+            enabled = true
+            secret = "correct horse"
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.knock.port, 62201);
+        assert!(!config.server.knock.firewall);
+        assert!(config.validate().is_ok());
+
+        // Bridged transports would get past the knock
+        config.network.icmp.enabled = true;
+        assert!(config.validate().is_err());
+        config.network.icmp.enabled = false;
+
+        config.server.knock.secret.clear();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_overload_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server.overload]
+            max_load = 1.5
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.overload.max_load, Some(1.5));
+        assert_eq!(config.server.overload.retry_after, 10);
+        assert!(config.validate().is_ok());
+
+        config.server.overload.max_load = Some(0.0);
+        assert!(config.validate().is_err());
+        config.server.overload.max_load = None;
+        config.server.overload.retry_after = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_tcp_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server.tcp]
+            nodelay = false
+            recv_buffer = 262144
+            fastopen_queue = 256
+            early_data = true
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert!(!config.server.tcp.nodelay);
+        assert_eq!(config.server.tcp.keepalive_idle, 60);
+        assert_eq!(config.server.tcp.send_buffer, None);
+        assert_eq!(config.server.tcp.recv_buffer, Some(262144));
+        assert_eq!(config.server.tcp.fastopen_queue, 256);
+        assert!(config.server.tcp.early_data);
+        assert!(!TcpConfig::default().early_data);
+        assert!(config.validate().is_ok());
+
+        config.server.tcp.keepalive_count = 0;
+        assert!(config.validate().is_err());
+        config.server.tcp.keepalive_idle = 0;
+        assert!(config.validate().is_ok());
+        config.server.tcp.send_buffer = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_qos_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server.qos]
+            dscp = 46
+            copy_inner = true
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.qos.dscp, Some(46));
+        assert!(config.server.qos.copy_inner);
+        assert!(config.validate().is_ok());
+
+        config.server.qos.dscp = Some(64);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_udp_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [server.udp]
+            pktinfo = false
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.server.udp.dont_fragment);
+        assert!(!config.server.udp.pktinfo);
+    }
+
+    #[test]
+    fn test_descriptor_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server.descriptor]
+            enabled = true
+            hosts = ["vpn.example.org"]
+            key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.descriptor.address, "0.0.0.0:8080");
+        assert_eq!(config.server.descriptor.validity, 86_400);
+        assert!(config.validate().is_ok());
+
+        config.server.descriptor.key = "0001".to_string();
+        assert!(config.validate().is_err());
+        config.server.descriptor.enabled = false;
+        assert!(config.validate().is_ok());
+        config.server.descriptor.enabled = true;
+        config.server.descriptor.key = "00".repeat(32);
+        config.server.descriptor.hosts.clear();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_lockdown_policy() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [policies.alice]
+            lockdown = true
+            [policies.bob]
+            "#,
+        )
+        .unwrap();
+
+        let alice = &config.policies["alice"];
+        assert!(alice.lockdown);
+        assert!(!alice.lockdown_allow_lan);
+        assert!(!config.policies["bob"].lockdown);
+    }
+
+    #[test]
+    fn test_resolver_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [resolver]
+            enabled = true
+            blocklists = ["/etc/lostlove/ads.hosts"]
+            block_response = "null"
+            [policies.alice]
+            dns_blocking = false
+            "#,
+        )
+        .unwrap();
+
+        let resolver = &config.resolver;
+        assert_eq!(resolver.listen, "");
+        assert_eq!(resolver.upstream, ["1.1.1.1:53", "9.9.9.9:53"]);
+        assert_eq!(resolver.blocklist_refresh, 3600);
+        assert_eq!(resolver.block_response, BlockResponse::Null);
+        assert!(resolver.block_by_default);
+        assert_eq!(config.policies["alice"].dns_blocking, Some(false));
+        assert!(config.validate().is_ok());
+
+        config.resolver.upstream = vec![
+            "tls://dns.quad9.net".to_string(),
+            "https://1.1.1.1/dns-query".to_string(),
+        ];
+        assert!(config.validate().is_ok());
+        config.resolver.upstream = vec!["resolver.example.org".to_string()];
+        assert!(config.validate().is_err());
+        config.resolver.upstream.clear();
+        assert!(config.validate().is_err());
+        config.resolver.enabled = false;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_management_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [admin.management]
+            enabled = true
+            key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.admin.management.audit_log, "/var/log/lostlove/management.log");
+        assert_eq!(config.admin.management.reply_timeout, 30);
+        assert!(config.admin.enable_control_socket);
+        assert!(config.validate().is_ok());
+
+        config.admin.management.key = "0001".to_string();
+        assert!(config.validate().is_err());
+        config.admin.management.enabled = false;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_cleanup_interval() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [limits]
+            cleanup_interval = 15
+            [network]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.limits.cleanup_interval, 15);
+        assert!(config.validate().is_ok());
+
+        config.limits.cleanup_interval = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_unknown_packet_types() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [limits]
+            unknown_packet_types = "error"
+            [network]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.limits.unknown_packet_types, "error");
+        assert!(config.validate().is_ok());
+        assert_eq!(Config::default_for_testing().limits.unknown_packet_types, "drop");
+        assert_eq!(config.limits.hibernate_after, 60);
+        assert_eq!(config.limits.max_memory_mb, 0);
+
+        config.limits.unknown_packet_types = "ignore".to_string();
+        assert!(config.validate().is_err());
+
+        config.limits.unknown_packet_types = "drop".to_string();
+        config.limits.max_connection_memory_kb = 4;
+        assert!(config.validate().is_err());
+
+        config.limits.max_connection_memory_kb = 0;
+        assert_eq!(config.limits.max_packet_size, 16384);
+        config.limits.max_packet_size = config.network.mtu + HEADER_SIZE;
+        assert!(config.validate().is_ok());
+        config.limits.max_packet_size -= 1;
+        assert!(config.validate().is_err());
+
+        config.limits.max_packet_size = HEADER_SIZE + MAX_PAYLOAD_SIZE + 1;
+        assert!(config.validate().is_err());
+
+        config.limits.max_packet_size = HEADER_SIZE + MAX_PAYLOAD_SIZE;
+        assert_eq!((config.limits.read_timeout, config.limits.packet_timeout), (10, 30));
+        config.limits.packet_timeout = 5;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_runtime_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            runtime = "current-thread"
+            worker_threads = 1
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.runtime, "current-thread");
+        assert!(!config.server.pin_workers);
+        assert!(config.validate().is_ok());
+
+        config.server.worker_threads = 4;
+        assert!(config.validate().is_err());
+        config.server.worker_threads = 0;
+        config.server.pin_workers = true;
+        assert!(config.validate().is_err());
+        config.server.runtime = "multi-thread".to_string();
+        assert!(config.validate().is_ok());
+        config.server.runtime = "green-threads".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_decoy_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server.decoy]
+            enabled = true
+            address = "127.0.0.1:80"
+            [network]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.decoy.connect_timeout, 5);
+        assert!(config.validate().is_ok());
+
+        config.server.decoy.address.clear();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_obfuscation_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server.obfuscation]
+            enabled = true
+            server_key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            [network]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.validate().is_ok(), !cfg!(feature = "fips"));
+
+        config.security.fips = true;
+        assert!(config.validate().is_err());
+        config.security.fips = false;
+
+        config.server.obfuscation.server_key = "0001".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_dns_tunnel_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [network.dns]
+            enabled = true
+            zone = "t.example.com."
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.network.dns.port, 53);
+        assert_eq!(config.network.dns.max_tunnels, 64);
+        assert!(config.validate().is_ok());
+
+        config.network.dns.zone = "t..example.com".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_federation_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [federation]
+            enabled = true
+            site_name = "berlin"
+            announce = ["192.168.10.0/24"]
+            [[federation.peers]]
+            name = "paris"
+            address = "llp.paris.example.com:8443"
+            secret = "paris shared"
+            allowed_prefixes = ["192.168.20.0/24"]
+            [[federation.peers]]
+            name = "rome"
+            secret = "rome shared"
+            "#,
+        )
+        .unwrap();
+
+        assert!(!config.federation.install_routes);
+        assert!(config.federation.readvertise);
+        assert_eq!(config.federation.peers[1].address, None);
+        assert!(config.federation.peers[1].allowed_prefixes.is_empty());
+        assert!(config.validate().is_ok());
+
+        // Peers prove their names with a shared secret
+        config.federation.peers[1].secret.clear();
+        assert!(config.validate().is_err());
+        config.federation.peers[1].secret = "rome shared".to_string();
+
+        config.federation.peers[0].allowed_prefixes.push("192.168.30.0".to_string());
+        assert!(config.validate().is_err());
+        config.federation.peers[0].allowed_prefixes.pop();
+
+        config.federation.peers[1].name = "paris".to_string();
+        assert!(config.validate().is_err());
+
+        // Site routes may be IPv6 as well
+        config.federation.peers.truncate(1);
+        config.federation.announce.push("fd00::/64".to_string());
+        assert!(config.validate().is_ok());
+
+        config.federation.announce.push("fd00::/129".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_failover_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [failover]
+            enabled = true
+            role = "standby"
+            address = "192.168.1.10:8600"
+            secret = "shared"
+            virtual_ip = "203.0.113.10/24"
+            interface = "eth0"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.failover.interval, 1);
+        assert_eq!(config.failover.missed_updates, 3);
+        assert!(config.validate().is_ok());
+
+        config.failover.role = "primary".to_string();
+        assert!(config.validate().is_err());
+
+        config.failover.role = "active".to_string();
+        config.failover.interface = None;
+        assert!(config.validate().is_err());
+
+        config.failover.virtual_ip = None;
+        config.failover.secret.clear();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_fleet_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [fleet]
+            enabled = true
+            node = "fra-1"
+            secret = "shared"
+            [[fleet.peers]]
+            name = "fra-2"
+            address = "fra-2.example.com:8443"
+            report_address = "10.0.0.2:8602"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.fleet.port, 8602);
+        assert_eq!(config.fleet.load_factor, 1.25);
+        assert!(config.validate().is_ok());
+
+        config.fleet.load_factor = 0.5;
+        assert!(config.validate().is_err());
+
+        config.fleet.load_factor = 1.0;
+        config.fleet.peers[0].name = "fra-1".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_hooks_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [hooks]
+            on_connect = "/etc/lostlove/connect.sh"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.hooks.timeout, 10);
+        assert_eq!(config.hooks.max_concurrent, 4);
+        assert!(config.validate().is_ok());
+
+        config.hooks.on_disconnect = Some("disconnect.sh".to_string());
+        assert!(config.validate().is_err());
+
+        config.hooks.on_disconnect = None;
+        config.hooks.max_concurrent = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_snmp_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            [network]
+            [monitoring.snmp]
+            enabled = true
+            oid = "1.3.6.1.4.1.55555.1"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.monitoring.snmp.master, "/var/agentx/master");
+        assert_eq!(config.monitoring.snmp.reconnect_interval, 15);
+        assert!(config.validate().is_ok());
+
+        config.monitoring.snmp.oid = "2.5.4".to_string();
+        assert!(config.validate().is_err());
+
+        config.monitoring.snmp.oid = "1.3.6.1.4.x".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_metric_labels_config() {
+        let mut config = Config::default_for_testing();
+        config.monitoring.metric_labels = vec!["team".to_string()];
+        assert!(config.validate().is_ok());
+
+        config.monitoring.metric_labels.push("Tenant".to_string());
+        assert!(config.validate().is_err());
+    }
 }
@@ -0,0 +1,220 @@
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{self, Instant};
+
+use crate::config::LimitsConfig;
+use crate::error::{LostLoveError, Result};
+use crate::metrics::writer::MetricsWriter;
+
+/// Client behavior the server closes connections for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Abuse {
+    /// Connected and didn't finish its ClientHello in time
+    SlowHandshake,
+    /// Started a packet and didn't finish it in time
+    SlowPacket,
+}
+
+impl Abuse {
+    pub const ALL: [Abuse; 2] = [Abuse::SlowHandshake, Abuse::SlowPacket];
+
+    /// Label value in `llp_abuse_disconnects_total`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Abuse::SlowHandshake => "slow_handshake",
+            Abuse::SlowPacket => "slow_packet",
+        }
+    }
+
+    /// What the client didn't send in time
+    pub fn unfinished(&self) -> &'static str {
+        match self {
+            Abuse::SlowHandshake => "ClientHello",
+            Abuse::SlowPacket => "packet",
+        }
+    }
+}
+
+/// Connections closed for abuse, by kind
+#[derive(Debug, Default)]
+pub struct AbuseCounters {
+    counts: [AtomicU64; Abuse::ALL.len()],
+}
+
+impl AbuseCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, abuse: Abuse) {
+        self.counts[abuse as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the connection `error` closed, if it was closed for abuse
+    pub fn record_error(&self, error: &LostLoveError) {
+        if let LostLoveError::SlowClient { abuse, .. } = error {
+            self.record(*abuse);
+        }
+    }
+
+    pub fn count(&self, abuse: Abuse) -> u64 {
+        self.counts[abuse as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self, writer: &mut MetricsWriter) {
+        writer.header(
+            "llp_abuse_disconnects_total",
+            "Connections closed for tying up the server, by reason",
+            "counter",
+        );
+        for abuse in Abuse::ALL {
+            writer.sample("llp_abuse_disconnects_total", &[("reason", abuse.name())], self.count(abuse));
+        }
+    }
+}
+
+/// How long a client may take over one packet
+///
+/// Started once the first byte of a packet is there. Every read must make
+/// progress within `read_timeout` and the packet must be complete within
+/// `packet_timeout`, so a client trickling a byte at a time is cut off as
+/// surely as one that stalls.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketDeadline {
+    abuse: Abuse,
+    read_timeout: Duration,
+    packet_timeout: Duration,
+    complete_by: Instant,
+}
+
+impl PacketDeadline {
+    pub fn start(abuse: Abuse, limits: &LimitsConfig) -> Self {
+        Self::with_timeouts(
+            abuse,
+            Duration::from_secs(limits.read_timeout),
+            Duration::from_secs(limits.packet_timeout),
+        )
+    }
+
+    pub fn with_timeouts(abuse: Abuse, read_timeout: Duration, packet_timeout: Duration) -> Self {
+        Self {
+            abuse,
+            read_timeout,
+            packet_timeout,
+            complete_by: Instant::now() + packet_timeout,
+        }
+    }
+
+    /// Wait for one read, failing with `SlowClient` past either deadline
+    pub async fn read<T, E: Into<LostLoveError>>(
+        &self,
+        read: impl Future<Output = std::result::Result<T, E>>,
+    ) -> Result<T> {
+        let read_by = Instant::now() + self.read_timeout;
+        let (deadline, timeout) = if read_by < self.complete_by {
+            (read_by, self.read_timeout)
+        } else {
+            (self.complete_by, self.packet_timeout)
+        };
+        match time::timeout_at(deadline, read).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(LostLoveError::SlowClient {
+                abuse: self.abuse,
+                seconds: timeout.as_secs(),
+            }),
+        }
+    }
+
+    /// Fill `buf`, each read within the deadlines
+    pub async fn read_exact(&self, stream: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self.read(stream.read(&mut buf[filled..])).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            filled += n;
+        }
+        Ok(())
+    }
+
+    /// Run the whole of `read` within `packet_timeout`
+    pub async fn complete<T>(&self, read: impl Future<Output = Result<T>>) -> Result<T> {
+        match time::timeout_at(self.complete_by, read).await {
+            Ok(result) => result,
+            Err(_) => Err(LostLoveError::SlowClient {
+                abuse: self.abuse,
+                seconds: self.packet_timeout.as_secs(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_read_times_out() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let deadline = PacketDeadline::with_timeouts(Abuse::SlowPacket, Duration::from_secs(5), Duration::from_secs(30));
+
+        // Half a header, then nothing
+        client.write_all(&[0u8; 10]).await.unwrap();
+        let mut header = [0u8; 24];
+        let started = Instant::now();
+        let err = deadline.read_exact(&mut server, &mut header).await.unwrap_err();
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        assert_eq!(err.code(), "slow_client");
+        assert_eq!(err.to_string(), "Client sent no complete packet within 5s");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_trickling_client_times_out() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let deadline = PacketDeadline::with_timeouts(Abuse::SlowPacket, Duration::from_secs(5), Duration::from_secs(11));
+
+        // A byte every 4 seconds keeps each read within its deadline
+        tokio::spawn(async move {
+            loop {
+                if client.write_all(&[0]).await.is_err() {
+                    break;
+                }
+                time::sleep(Duration::from_secs(4)).await;
+            }
+        });
+        let mut header = [0u8; 24];
+        let started = Instant::now();
+        let err = deadline.read_exact(&mut server, &mut header).await.unwrap_err();
+        assert_eq!(started.elapsed(), Duration::from_secs(11));
+        assert!(matches!(err, LostLoveError::SlowClient { seconds: 11, .. }));
+
+        let counters = AbuseCounters::new();
+        counters.record_error(&err);
+        counters.record_error(&LostLoveError::RateLimited);
+        assert_eq!(counters.count(Abuse::SlowPacket), 1);
+        assert_eq!(counters.count(Abuse::SlowHandshake), 0);
+
+        let mut writer = MetricsWriter::new();
+        counters.render(&mut writer);
+        assert!(writer.finish().contains("llp_abuse_disconnects_total{reason=\"slow_packet\"} 1\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_complete_packet_within_deadline() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let deadline = PacketDeadline::with_timeouts(Abuse::SlowHandshake, Duration::from_secs(5), Duration::from_secs(30));
+
+        client.write_all(&[1u8; 24]).await.unwrap();
+        let mut header = [0u8; 24];
+        deadline.read_exact(&mut server, &mut header).await.unwrap();
+        assert_eq!(header, [1u8; 24]);
+
+        let err = deadline.complete(std::future::pending::<Result<()>>()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Client sent no complete ClientHello within 30s");
+    }
+}
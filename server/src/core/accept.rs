@@ -0,0 +1,193 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::error::{LostLoveError, Result};
+
+/// First pause after the process ran out of descriptors or buffers
+const MIN_EXHAUSTED_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Longest pause between accepts while resources stay exhausted
+const MAX_EXHAUSTED_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Pause after an unexpected accept error
+const OTHER_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Descriptors kept for everything besides client sockets (TUN, listeners, logs)
+const RESERVED_FDS: u64 = 64;
+
+/// What a failed `accept` means for the loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorKind {
+    /// Out of file descriptors or kernel memory; retrying at once would spin
+    Exhausted,
+    /// The pending connection went away before it was accepted
+    Aborted,
+    /// Anything else
+    Other,
+}
+
+impl AcceptErrorKind {
+    /// Classify an error returned by `accept`
+    pub fn of(error: &io::Error) -> Self {
+        match error.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => return Self::Exhausted,
+            Some(libc::EPROTO | libc::EPERM) => return Self::Aborted,
+            _ => {}
+        }
+        match error.kind() {
+            io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted => {
+                Self::Aborted
+            }
+            _ => Self::Other,
+        }
+    }
+
+    /// Label value in `llp_accept_failures_total`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Exhausted => "exhausted",
+            Self::Aborted => "aborted",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Accept failure counts and the backoff of the accept loop
+#[derive(Debug, Default)]
+pub struct AcceptFailures {
+    exhausted: AtomicU64,
+    aborted: AtomicU64,
+    other: AtomicU64,
+    backoff_ms: AtomicU64,
+}
+
+impl AcceptFailures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a failed accept, returns how long to pause before the next one
+    ///
+    /// Pauses double while resources stay exhausted, so the loop waits for
+    /// connections to close instead of spinning on EMFILE.
+    pub fn record(&self, kind: AcceptErrorKind) -> Option<Duration> {
+        match kind {
+            AcceptErrorKind::Exhausted => {
+                self.exhausted.fetch_add(1, Ordering::Relaxed);
+                let previous = Duration::from_millis(self.backoff_ms.load(Ordering::Relaxed));
+                let backoff = (previous * 2).clamp(MIN_EXHAUSTED_BACKOFF, MAX_EXHAUSTED_BACKOFF);
+                self.backoff_ms.store(backoff.as_millis() as u64, Ordering::Relaxed);
+                Some(backoff)
+            }
+            AcceptErrorKind::Aborted => {
+                self.aborted.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            AcceptErrorKind::Other => {
+                self.other.fetch_add(1, Ordering::Relaxed);
+                Some(OTHER_BACKOFF)
+            }
+        }
+    }
+
+    /// Start over with the shortest pause once an accept succeeds
+    pub fn reset_backoff(&self) {
+        self.backoff_ms.store(0, Ordering::Relaxed);
+    }
+
+    /// Failures by kind
+    pub fn counts(&self) -> [(AcceptErrorKind, u64); 3] {
+        [
+            (AcceptErrorKind::Exhausted, self.exhausted.load(Ordering::Relaxed)),
+            (AcceptErrorKind::Aborted, self.aborted.load(Ordering::Relaxed)),
+            (AcceptErrorKind::Other, self.other.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+/// Descriptor limit after the startup check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdLimit {
+    /// Soft limit in effect
+    pub current: u64,
+    /// Descriptors `max_connections` sessions may need
+    pub needed: u64,
+}
+
+impl FdLimit {
+    /// Whether every session can get a socket
+    pub fn is_sufficient(&self) -> bool {
+        self.current >= self.needed
+    }
+}
+
+/// Raise the soft RLIMIT_NOFILE towards what `max_connections` needs
+///
+/// The soft limit can go up to the hard one without privileges; beyond
+/// that only the operator can help (`LimitNOFILE=` under systemd).
+pub fn raise_fd_limit(max_connections: usize) -> Result<FdLimit> {
+    let needed = max_connections as u64 + RESERVED_FDS;
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    // SAFETY: plain syscall writing into a valid rlimit
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(LostLoveError::Io(io::Error::last_os_error()));
+    }
+
+    if limit.rlim_cur != libc::RLIM_INFINITY && limit.rlim_cur < needed {
+        let raised = libc::rlimit {
+            rlim_cur: needed.min(limit.rlim_max),
+            rlim_max: limit.rlim_max,
+        };
+        // SAFETY: plain syscall with a valid rlimit, within the hard limit
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit.rlim_cur = raised.rlim_cur;
+        }
+    }
+
+    Ok(FdLimit {
+        current: limit.rlim_cur,
+        needed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let errno = io::Error::from_raw_os_error;
+        assert_eq!(AcceptErrorKind::of(&errno(libc::EMFILE)), AcceptErrorKind::Exhausted);
+        assert_eq!(AcceptErrorKind::of(&errno(libc::ENFILE)), AcceptErrorKind::Exhausted);
+        assert_eq!(AcceptErrorKind::of(&errno(libc::ECONNABORTED)), AcceptErrorKind::Aborted);
+        assert_eq!(AcceptErrorKind::of(&errno(libc::EINVAL)), AcceptErrorKind::Other);
+    }
+
+    #[test]
+    fn test_exhausted_backoff() {
+        let failures = AcceptFailures::new();
+        let pauses: Vec<_> = (0..7)
+            .map(|_| failures.record(AcceptErrorKind::Exhausted).unwrap().as_millis())
+            .collect();
+        assert_eq!(pauses, [50, 100, 200, 400, 800, 1600, 2000]);
+
+        failures.reset_backoff();
+        assert_eq!(failures.record(AcceptErrorKind::Exhausted), Some(MIN_EXHAUSTED_BACKOFF));
+
+        assert_eq!(failures.record(AcceptErrorKind::Aborted), None);
+        assert_eq!(failures.record(AcceptErrorKind::Other), Some(OTHER_BACKOFF));
+        assert_eq!(failures.counts().map(|(_, count)| count), [8, 1, 1]);
+    }
+
+    #[test]
+    fn test_fd_limit() {
+        let limit = raise_fd_limit(16).unwrap();
+        assert_eq!(limit.needed, 16 + RESERVED_FDS);
+        assert!(limit.is_sufficient());
+    }
+}
@@ -0,0 +1,343 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{AllowedHours, Config, UserPolicy};
+use crate::protocol::{CutoffWarning, DisconnectReason};
+
+const SECS_PER_DAY: u64 = 86_400;
+const SECS_PER_HOUR: u64 = 3_600;
+const BYTES_PER_GB: f64 = 1_000_000_000.0;
+
+/// Outcome of checking a user against its access policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allow,
+    /// Cutoff is close, tell the client
+    Warn(CutoffWarning),
+    /// Disconnect the client
+    Cutoff(DisconnectReason),
+}
+
+/// Data used by one user in the current day and month (UTC)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserUsage {
+    day: u64,
+    month: u64,
+    pub day_bytes: u64,
+    pub month_bytes: u64,
+}
+
+impl UserUsage {
+    /// Start new periods if the day or month changed
+    fn roll(&mut self, now: u64) {
+        let day = now / SECS_PER_DAY;
+        let month = month_index(day);
+
+        if day != self.day {
+            self.day = day;
+            self.day_bytes = 0;
+        }
+        if month != self.month {
+            self.month = month;
+            self.month_bytes = 0;
+        }
+    }
+}
+
+/// Per-user traffic accounting and access policy enforcement
+///
+/// Usage is kept in memory and starts from zero on restart. Only users in
+/// `[policies]` are counted: names come from unauthenticated hellos, so
+/// counting anyone else would let clients grow the map without bound.
+/// Entries outlive sessions, as quotas span them.
+pub struct Accounting {
+    usage: DashMap<String, UserUsage>,
+    policies: BTreeMap<String, UserPolicy>,
+    grace_period: u64,
+    warning_percent: u8,
+}
+
+impl Accounting {
+    /// Create accounting from config
+    pub fn new(config: &Config) -> Self {
+        Self {
+            usage: DashMap::new(),
+            policies: config.policies.clone(),
+            grace_period: config.limits.policy_grace_period,
+            warning_percent: config.limits.quota_warning_percent,
+        }
+    }
+
+    /// Check if a user has an access policy
+    pub fn has_policy(&self, user: &str) -> bool {
+        self.policies.contains_key(user)
+    }
+
+    /// Account traffic of a user
+    pub fn record(&self, user: &str, bytes: u64) {
+        self.record_at(user, bytes, unix_now());
+    }
+
+    fn record_at(&self, user: &str, bytes: u64, now: u64) {
+        if !self.has_policy(user) {
+            return;
+        }
+        let mut usage = self.usage.entry(user.to_string()).or_default();
+        usage.roll(now);
+        usage.day_bytes += bytes;
+        usage.month_bytes += bytes;
+    }
+
+    /// Get the usage of a user in the current periods
+    pub fn usage(&self, user: &str) -> UserUsage {
+        self.usage_at(user, unix_now())
+    }
+
+    fn usage_at(&self, user: &str, now: u64) -> UserUsage {
+        let mut usage = self.usage.get(user).map(|u| *u).unwrap_or_default();
+        usage.roll(now);
+        usage
+    }
+
+    /// Usage of every user seen, for replication to a standby
+    pub fn snapshot(&self) -> BTreeMap<String, UserUsage> {
+        self.usage
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    /// Take over usage replicated from another server
+    ///
+    /// Users counted here as well keep whichever side used more in the
+    /// current periods, so nothing already counted is lost.
+    pub fn restore(&self, usage: BTreeMap<String, UserUsage>) {
+        let now = unix_now();
+        for (user, mut replicated) in usage {
+            if !self.has_policy(&user) {
+                continue;
+            }
+            replicated.roll(now);
+            let mut current = self.usage.entry(user).or_default();
+            current.roll(now);
+            current.day_bytes = current.day_bytes.max(replicated.day_bytes);
+            current.month_bytes = current.month_bytes.max(replicated.month_bytes);
+        }
+    }
+
+    /// Check a user against its access policy
+    pub fn evaluate(&self, user: &str) -> PolicyDecision {
+        self.evaluate_at(user, unix_now())
+    }
+
+    fn evaluate_at(&self, user: &str, now: u64) -> PolicyDecision {
+        let Some(policy) = self.policies.get(user) else {
+            return PolicyDecision::Allow;
+        };
+
+        let usage = self.usage_at(user, now);
+        let mut warning = None;
+
+        if let Some(hours) = policy.allowed_hours {
+            match seconds_left_in_window(hours, now) {
+                None => return PolicyDecision::Cutoff(DisconnectReason::OutsideAllowedHours),
+                Some(left) if left <= self.grace_period => {
+                    warning = Some(CutoffWarning {
+                        reason: DisconnectReason::OutsideAllowedHours,
+                        seconds_left: Some(left),
+                        bytes_left: None,
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+
+        let quotas = [
+            (policy.daily_quota_gb, usage.day_bytes, DisconnectReason::DailyQuotaExceeded),
+            (policy.monthly_quota_gb, usage.month_bytes, DisconnectReason::MonthlyQuotaExceeded),
+        ];
+
+        for (quota_gb, used, reason) in quotas {
+            let Some(quota_gb) = quota_gb else {
+                continue;
+            };
+            let quota = (quota_gb * BYTES_PER_GB) as u64;
+
+            if used >= quota {
+                return PolicyDecision::Cutoff(reason);
+            }
+            if warning.is_none() && used >= quota / 100 * self.warning_percent as u64 {
+                warning = Some(CutoffWarning {
+                    reason,
+                    seconds_left: None,
+                    bytes_left: Some(quota - used),
+                });
+            }
+        }
+
+        match warning {
+            Some(warning) => PolicyDecision::Warn(warning),
+            None => PolicyDecision::Allow,
+        }
+    }
+}
+
+/// Seconds until the allowed window ends, None when outside of it
+fn seconds_left_in_window(hours: AllowedHours, now: u64) -> Option<u64> {
+    let second_of_day = now % SECS_PER_DAY;
+    let start = hours.start as u64 * SECS_PER_HOUR;
+    let end = hours.end as u64 * SECS_PER_HOUR;
+
+    if start < end {
+        (start..end).contains(&second_of_day).then(|| end - second_of_day)
+    } else if second_of_day >= start {
+        // Window wraps past midnight
+        Some(SECS_PER_DAY - second_of_day + end)
+    } else if second_of_day < end {
+        Some(end - second_of_day)
+    } else {
+        None
+    }
+}
+
+/// Months since the epoch for a day number (proleptic Gregorian, UTC)
+fn month_index(days_since_epoch: u64) -> u64 {
+    // civil_from_days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days_since_epoch + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    year * 12 + (month - 1)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-03-15 00:00:00 UTC
+    const MARCH_15: u64 = 1_710_460_800;
+
+    fn accounting(policy: UserPolicy) -> Accounting {
+        let mut config = Config::default_for_testing();
+        config.policies.insert("alice".to_string(), policy);
+        Accounting::new(&config)
+    }
+
+    #[test]
+    fn test_month_index() {
+        assert_eq!(month_index(0), 1970 * 12);
+        assert_eq!(month_index(MARCH_15 / SECS_PER_DAY), 2024 * 12 + 2);
+        // 2024-02-29 and 2024-03-01
+        assert_eq!(month_index(MARCH_15 / SECS_PER_DAY - 15), 2024 * 12 + 1);
+        assert_eq!(month_index(MARCH_15 / SECS_PER_DAY - 14), 2024 * 12 + 2);
+    }
+
+    #[test]
+    fn test_allowed_hours() {
+        let accounting = accounting(UserPolicy {
+            allowed_hours: Some(AllowedHours { start: 8, end: 20 }),
+            ..UserPolicy::default()
+        });
+
+        assert_eq!(
+            accounting.evaluate_at("alice", MARCH_15 + 7 * SECS_PER_HOUR),
+            PolicyDecision::Cutoff(DisconnectReason::OutsideAllowedHours)
+        );
+        assert_eq!(accounting.evaluate_at("alice", MARCH_15 + 12 * SECS_PER_HOUR), PolicyDecision::Allow);
+
+        // Five minutes before the window closes
+        match accounting.evaluate_at("alice", MARCH_15 + 20 * SECS_PER_HOUR - 300) {
+            PolicyDecision::Warn(warning) => {
+                assert_eq!(warning.reason, DisconnectReason::OutsideAllowedHours);
+                assert_eq!(warning.seconds_left, Some(300));
+            }
+            other => panic!("Unexpected decision: {:?}", other),
+        }
+
+        // Users without a policy are never restricted
+        assert_eq!(accounting.evaluate_at("bob", MARCH_15), PolicyDecision::Allow);
+    }
+
+    #[test]
+    fn test_window_past_midnight() {
+        let hours = AllowedHours { start: 22, end: 6 };
+
+        assert_eq!(seconds_left_in_window(hours, MARCH_15 + 23 * SECS_PER_HOUR), Some(7 * SECS_PER_HOUR));
+        assert_eq!(seconds_left_in_window(hours, MARCH_15 + 5 * SECS_PER_HOUR), Some(SECS_PER_HOUR));
+        assert_eq!(seconds_left_in_window(hours, MARCH_15 + 12 * SECS_PER_HOUR), None);
+    }
+
+    #[test]
+    fn test_daily_quota() {
+        let accounting = accounting(UserPolicy {
+            daily_quota_gb: Some(1.0),
+            ..UserPolicy::default()
+        });
+        let now = MARCH_15 + 10 * SECS_PER_HOUR;
+
+        accounting.record_at("alice", 500_000_000, now);
+        assert_eq!(accounting.evaluate_at("alice", now), PolicyDecision::Allow);
+
+        accounting.record_at("alice", 450_000_000, now);
+        match accounting.evaluate_at("alice", now) {
+            PolicyDecision::Warn(warning) => assert_eq!(warning.bytes_left, Some(50_000_000)),
+            other => panic!("Unexpected decision: {:?}", other),
+        }
+
+        accounting.record_at("alice", 50_000_000, now);
+        assert_eq!(
+            accounting.evaluate_at("alice", now),
+            PolicyDecision::Cutoff(DisconnectReason::DailyQuotaExceeded)
+        );
+
+        // Next day starts from zero, the month keeps counting
+        let tomorrow = now + SECS_PER_DAY;
+        assert_eq!(accounting.evaluate_at("alice", tomorrow), PolicyDecision::Allow);
+        assert_eq!(accounting.usage_at("alice", tomorrow).month_bytes, 1_000_000_000);
+    }
+
+    #[test]
+    fn test_only_users_with_policy_counted() {
+        let accounting = accounting(UserPolicy::default());
+        accounting.record("alice", 100);
+        accounting.record("mallory", 100);
+
+        assert_eq!(accounting.usage("mallory").day_bytes, 0);
+        assert_eq!(accounting.snapshot().keys().collect::<Vec<_>>(), ["alice"]);
+    }
+
+    #[test]
+    fn test_restore_keeps_larger_usage() {
+        let mut config = Config::default_for_testing();
+        for user in ["alice", "bob"] {
+            config.policies.insert(user.to_string(), UserPolicy::default());
+        }
+        let active = Accounting::new(&config);
+        active.record("alice", 5_000);
+        active.record("bob", 100);
+
+        let standby = Accounting::new(&config);
+        standby.record("bob", 300);
+        let mut replicated = active.snapshot();
+        replicated.insert("mallory".to_string(), UserUsage::default());
+        standby.restore(replicated);
+
+        assert_eq!(standby.usage("alice").day_bytes, 5_000);
+        assert_eq!(standby.usage("bob").day_bytes, 300);
+        assert_eq!(standby.snapshot().len(), 2);
+    }
+}
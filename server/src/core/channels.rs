@@ -0,0 +1,231 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::core::session::SessionId;
+use crate::error::{LostLoveError, Result};
+use crate::protocol::StreamId;
+
+/// Messages the server may have waiting for one session's channels
+const MAILBOX_SIZE: usize = 64;
+
+/// Handles one named channel on the server side
+pub trait ChannelHandler: Send + Sync {
+    /// A session's client opened the channel
+    fn opened(&self, _session_id: &SessionId) {}
+
+    /// A session ended, with whatever channels it had open
+    fn closed(&self, _session_id: &SessionId) {}
+
+    /// Data the client sent on the channel; what's returned is sent back on it
+    fn receive(&self, session_id: &SessionId, data: &[u8]) -> Result<Option<Bytes>>;
+}
+
+/// Data the server sends on a session's channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMessage {
+    pub channel: String,
+    pub data: Bytes,
+}
+
+/// Named reliable streams inside sessions, for auxiliary data such as
+/// config pushes, file transfers and remote logs
+///
+/// Server code registers a handler per channel name. A client opens a
+/// channel with a StreamOpen naming it, then both sides send on the
+/// channel's stream as on any other: the client's data goes to the handler,
+/// and the server sends through `send` to sessions that have it open.
+#[derive(Default)]
+pub struct Channels {
+    handlers: RwLock<HashMap<String, Arc<dyn ChannelHandler>>>,
+    mailboxes: DashMap<SessionId, mpsc::Sender<ChannelMessage>>,
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` for channel `name`, refusing names already taken
+    pub fn register(&self, name: &str, handler: Arc<dyn ChannelHandler>) -> Result<()> {
+        validate_name(name)?;
+        let mut handlers = self.handlers.write().unwrap();
+        if handlers.contains_key(name) {
+            return Err(LostLoveError::Config(format!("Channel {} is already registered", name)));
+        }
+        handlers.insert(name.to_string(), handler);
+        Ok(())
+    }
+
+    /// Whether clients can open channel `name`
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.handlers.read().unwrap().contains_key(name)
+    }
+
+    fn handler(&self, name: &str) -> Option<Arc<dyn ChannelHandler>> {
+        self.handlers.read().unwrap().get(name).cloned()
+    }
+
+    /// Tell channel `name`'s handler a session opened it
+    pub fn opened(&self, session_id: &SessionId, name: &str) {
+        if let Some(handler) = self.handler(name) {
+            handler.opened(session_id);
+        }
+    }
+
+    /// Hand data a session's client sent on channel `name` to its handler
+    pub fn receive(&self, session_id: &SessionId, name: &str, data: &[u8]) -> Option<Bytes> {
+        let handler = self.handler(name)?;
+        match handler.receive(session_id, data) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!(code = e.code(), "Channel {} of session {} failed: {}", name, session_id, e);
+                None
+            }
+        }
+    }
+
+    /// Open the mailbox of a session
+    pub fn attach(&self, session_id: &SessionId) -> mpsc::Receiver<ChannelMessage> {
+        let (sender, receiver) = mpsc::channel(MAILBOX_SIZE);
+        self.mailboxes.insert(*session_id, sender);
+        receiver
+    }
+
+    /// Close the mailbox of a session and tell the handlers it ended
+    pub fn detach(&self, session_id: &SessionId) {
+        self.mailboxes.remove(session_id);
+        let handlers: Vec<_> = self.handlers.read().unwrap().values().cloned().collect();
+        for handler in handlers {
+            handler.closed(session_id);
+        }
+    }
+
+    /// Send data to a session on channel `name`
+    ///
+    /// Dropped by the session if its client hasn't opened the channel.
+    pub fn send(&self, session_id: &SessionId, name: &str, data: Bytes) -> Result<()> {
+        let mailbox = self
+            .mailboxes
+            .get(session_id)
+            .ok_or_else(|| LostLoveError::SessionNotFound(session_id.to_string()))?;
+        let message = ChannelMessage {
+            channel: name.to_string(),
+            data,
+        };
+        mailbox.try_send(message).map_err(|e| {
+            LostLoveError::Connection(format!("Channel {} of session {} is backed up: {}", name, session_id, e))
+        })
+    }
+}
+
+/// Channels a session's client opened, by stream
+#[derive(Debug, Default)]
+pub struct OpenChannels {
+    by_stream: HashMap<u16, String>,
+}
+
+impl OpenChannels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, stream: StreamId, name: &str) {
+        debug!("Channel {} open on {}", name, stream);
+        self.by_stream.insert(stream.value(), name.to_string());
+    }
+
+    /// Channel carried by a stream
+    pub fn name(&self, stream: StreamId) -> Option<&str> {
+        self.by_stream.get(&stream.value()).map(String::as_str)
+    }
+
+    /// Stream carrying a channel, the lowest if the client opened it twice
+    pub fn stream(&self, name: &str) -> Option<StreamId> {
+        self.by_stream
+            .iter()
+            .filter(|(_, open)| open.as_str() == name)
+            .map(|(stream, _)| StreamId::new(*stream))
+            .min_by_key(StreamId::value)
+    }
+}
+
+/// Channel names are 1-32 lowercase letters, digits and dashes
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = (1..=32).contains(&name.len())
+        && name.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-');
+    if !valid {
+        return Err(LostLoveError::Config(format!(
+            "Invalid channel name {:?}: use 1-32 lowercase letters, digits and dashes",
+            name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Keeps what it receives and answers with its length
+    #[derive(Default)]
+    struct Logs(Mutex<Vec<String>>);
+
+    impl ChannelHandler for Logs {
+        fn receive(&self, _session_id: &SessionId, data: &[u8]) -> Result<Option<Bytes>> {
+            let line = std::str::from_utf8(data).map_err(|e| LostLoveError::Network(e.to_string()))?;
+            self.0.lock().unwrap().push(line.to_string());
+            Ok(Some(Bytes::from(line.len().to_string())))
+        }
+    }
+
+    #[test]
+    fn test_channels_dispatch_to_handlers() {
+        let channels = Channels::new();
+        let logs = Arc::new(Logs::default());
+        channels.register("logs", logs.clone()).unwrap();
+        assert!(channels.register("logs", logs.clone()).is_err());
+        assert!(channels.register("Logs", logs.clone()).is_err());
+        assert!(channels.register("", logs.clone()).is_err());
+        assert!(channels.is_registered("logs") && !channels.is_registered("files"));
+
+        let session_id = SessionId::new();
+        assert_eq!(channels.receive(&session_id, "logs", b"booted"), Some(Bytes::from_static(b"6")));
+        assert_eq!(channels.receive(&session_id, "logs", &[0xff]), None);
+        assert_eq!(channels.receive(&session_id, "files", b"x"), None);
+        assert_eq!(*logs.0.lock().unwrap(), ["booted"]);
+    }
+
+    #[tokio::test]
+    async fn test_send_reaches_attached_session() {
+        let channels = Channels::new();
+        let session_id = SessionId::new();
+        assert!(channels.send(&session_id, "config", Bytes::from_static(b"{}")).is_err());
+
+        let mut mailbox = channels.attach(&session_id);
+        channels.send(&session_id, "config", Bytes::from_static(b"{}")).unwrap();
+        let message = mailbox.recv().await.unwrap();
+        assert_eq!(message.channel, "config");
+        assert_eq!(&message.data[..], b"{}");
+
+        channels.detach(&session_id);
+        assert!(channels.send(&session_id, "config", Bytes::new()).is_err());
+    }
+
+    #[test]
+    fn test_open_channels_by_stream() {
+        let mut open = OpenChannels::new();
+        open.open(StreamId::new(7), "files");
+        open.open(StreamId::new(3), "files");
+        open.open(StreamId::new(5), "logs");
+
+        assert_eq!(open.name(StreamId::new(5)), Some("logs"));
+        assert_eq!(open.name(StreamId::new(6)), None);
+        assert_eq!(open.stream("files"), Some(StreamId::new(3)));
+        assert_eq!(open.stream("config"), None);
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time for timers and timestamp checks
+///
+/// Monotonic time drives timeouts and rotation; wall-clock time is what
+/// peers' timestamps are checked against. Tests move a `ManualClock`
+/// instead of sleeping.
+pub trait Clock: Send + Sync {
+    /// Monotonic time
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time
+    fn system_time(&self) -> SystemTime;
+
+    /// Wall-clock time in seconds since the Unix epoch
+    fn unix_now(&self) -> u64 {
+        self.system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+}
+
+/// The real clocks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that only moves when told to, for tests
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<(Instant, SystemTime)>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    /// Start at the current time
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new((Instant::now(), SystemTime::now())),
+        }
+    }
+
+    /// Let time pass, moving both clocks forward
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += by;
+        now.1 += by;
+    }
+
+    /// Step the wall clock alone, as NTP or an operator would
+    pub fn set_system_time(&self, time: SystemTime) {
+        self.now.lock().unwrap().1 = time;
+    }
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap().0
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.now.lock().unwrap().1
+    }
+}
+
+/// The real clocks, shared
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let (start, wall) = (clock.now(), clock.unix_now());
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now() - start, Duration::from_secs(90));
+        assert_eq!(clock.unix_now(), wall + 90);
+    }
+
+    #[test]
+    fn test_wall_clock_step() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+
+        clock.set_system_time(UNIX_EPOCH + Duration::from_secs(1_000));
+        assert_eq!(clock.unix_now(), 1_000);
+        assert_eq!(clock.now(), start);
+    }
+}
@@ -0,0 +1,177 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::config::Config;
+use crate::error::{LostLoveError, Result};
+use crate::protocol::ClientConfig;
+
+/// Client-facing settings shared by all sessions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushedSettings {
+    pub serial: u64,
+    pub mtu: usize,
+    pub dns: Vec<IpAddr>,
+    pub routes: Vec<String>,
+    pub renew_interval: u64,
+    pub quality_report_interval: u64,
+    pub portal_probes: Vec<String>,
+    pub portal_bypass: u64,
+    pub lan_bypass: bool,
+    pub exclude_routes: Vec<String>,
+}
+
+impl PushedSettings {
+    fn from_config(config: &Config, serial: u64) -> Self {
+        Self {
+            serial,
+            mtu: config.network.mtu,
+            dns: config.push.dns.clone(),
+            routes: config.push.routes.clone(),
+            renew_interval: config.push.renew_interval,
+            quality_report_interval: config.push.quality_report_interval,
+            portal_probes: config.push.portal_probes.clone(),
+            portal_bypass: config.push.portal_bypass,
+            lan_bypass: config.push.lan_bypass,
+            exclude_routes: config.push.exclude_routes.clone(),
+        }
+    }
+
+    /// Config message for one session
+    pub fn for_session(&self, tunnel_address: Option<IpAddr>) -> ClientConfig {
+        ClientConfig {
+            serial: self.serial,
+            tunnel_address,
+            mtu: self.mtu,
+            dns: self.dns.clone(),
+            routes: self.routes.clone(),
+            renew_after: self.renew_interval,
+            quality_report_interval: self.quality_report_interval,
+            portal_probes: self.portal_probes.clone(),
+            portal_bypass: self.portal_bypass,
+            lan_bypass: self.lan_bypass,
+            exclude_routes: self.exclude_routes.clone(),
+        }
+    }
+
+    /// Interval between renewals
+    pub fn renew_interval(&self) -> Duration {
+        Duration::from_secs(self.renew_interval)
+    }
+
+    fn same_settings(&self, other: &Self) -> bool {
+        self.mtu == other.mtu
+            && self.dns == other.dns
+            && self.routes == other.routes
+            && self.renew_interval == other.renew_interval
+            && self.quality_report_interval == other.quality_report_interval
+            && self.portal_probes == other.portal_probes
+            && self.portal_bypass == other.portal_bypass
+            && self.lan_bypass == other.lan_bypass
+            && self.exclude_routes == other.exclude_routes
+    }
+}
+
+/// Publishes pushed settings to connection handlers
+///
+/// Connections subscribe and send a `ConfigUpdate` whenever the settings
+/// change, e.g. after `llpctl reload`.
+pub struct ConfigPublisher {
+    sender: watch::Sender<PushedSettings>,
+}
+
+impl ConfigPublisher {
+    /// Create publisher with the startup config
+    pub fn new(config: &Config) -> Self {
+        let (sender, _) = watch::channel(PushedSettings::from_config(config, 1));
+        Self { sender }
+    }
+
+    /// Subscribe to setting changes
+    pub fn subscribe(&self) -> watch::Receiver<PushedSettings> {
+        self.sender.subscribe()
+    }
+
+    /// Get the current settings
+    pub fn current(&self) -> PushedSettings {
+        self.sender.borrow().clone()
+    }
+
+    /// Apply a new config, returns true if pushed settings changed
+    pub fn update(&self, config: &Config) -> bool {
+        self.sender.send_if_modified(|current| {
+            let next = PushedSettings::from_config(config, current.serial + 1);
+            if current.same_settings(&next) {
+                return false;
+            }
+
+            info!(
+                "Pushing config serial {} (mtu {}, {} routes, {} DNS servers)",
+                next.serial,
+                next.mtu,
+                next.routes.len(),
+                next.dns.len()
+            );
+            *current = next;
+            true
+        })
+    }
+
+    /// Re-read the config file and apply its pushed settings
+    ///
+    /// Other settings need a restart to take effect.
+    pub fn reload(&self, path: &Path) -> Result<bool> {
+        let config = Config::load(path)
+            .map_err(|e| LostLoveError::Config(format!("Reload failed: {:#}", e)))?;
+        Ok(self.update(&config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_bumps_serial_on_change() {
+        let mut config = Config::default_for_testing();
+        let publisher = ConfigPublisher::new(&config);
+        let mut updates = publisher.subscribe();
+
+        assert!(!publisher.update(&config));
+        assert!(!updates.has_changed().unwrap());
+
+        config.network.mtu = 1280;
+        config.push.dns = vec!["1.1.1.1".parse().unwrap()];
+        assert!(publisher.update(&config));
+        assert!(updates.has_changed().unwrap());
+
+        let settings = updates.borrow_and_update().clone();
+        assert_eq!(settings.serial, 2);
+        assert_eq!(settings.mtu, 1280);
+
+        let message = settings.for_session(Some("10.8.0.2".parse().unwrap()));
+        assert_eq!(message.serial, 2);
+        assert_eq!(message.dns.len(), 1);
+        assert_eq!(message.renew_after, 3600);
+        assert_eq!(message.quality_report_interval, 60);
+        assert_eq!(message.portal_bypass, 300);
+    }
+
+    #[test]
+    fn test_reload_from_file() {
+        let path = std::env::temp_dir().join(format!("llp-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "[server]\n[network]\nmtu = 1300\n").unwrap();
+
+        let publisher = ConfigPublisher::new(&Config::default_for_testing());
+        assert!(publisher.reload(&path).unwrap());
+        assert_eq!(publisher.current().mtu, 1300);
+
+        std::fs::write(&path, "[server]\n[network]\nmtu = 10\n").unwrap();
+        assert!(publisher.reload(&path).is_err());
+        assert_eq!(publisher.current().mtu, 1300);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
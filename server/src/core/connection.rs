@@ -1,33 +1,69 @@
+use bytes::Bytes;
 use dashmap::DashMap;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use tracing::{debug, info, warn};
 
-use crate::core::session::{Session, SessionId, SessionState};
+use crate::core::clock::{self, Clock};
+use crate::core::session::{Session, SessionEvent, SessionId};
+use crate::core::stats::TrafficTotals;
+use crate::crypto::{CryptoPolicy, KeyManager};
 use crate::error::{LostLoveError, Result};
-use crate::protocol::{Handshake, HandshakeState};
+use crate::network::ip_pool::IpPool;
+use crate::protocol::handshake::SUPPORTED_CIPHER_SUITES;
+use crate::protocol::{CipherSuite, Handshake};
+
+/// Packets routed to a client that may wait for its transport; more are
+/// dropped, as a full TUN queue would
+pub const TUNNEL_QUEUE_SIZE: usize = 256;
 
 /// Connection represents a single client connection
 pub struct Connection {
     session: Arc<Session>,
     handshake: Arc<RwLock<Handshake>>,
     sequence_number: AtomicU64,
+    packet_tracing: AtomicBool,
+    hibernated: AtomicBool,
+    key_manager: OnceLock<Arc<KeyManager>>,
+    lock_key_memory: bool,
+    tunnel: OnceLock<mpsc::Sender<Bytes>>,
 }
 
 impl Connection {
     /// Create new connection
     pub fn new(peer_addr: SocketAddr) -> Self {
+        Self::from_session(Session::new(peer_addr), SUPPORTED_CIPHER_SUITES)
+    }
+
+    /// Create connection for an existing session, negotiating `cipher_suites`
+    pub fn from_session(session: Session, cipher_suites: &[CipherSuite]) -> Self {
+        let mut handshake = Handshake::new_server().with_cipher_suites(cipher_suites);
+        if let Some(address) = session.tunnel_address() {
+            handshake = handshake.with_tunnel_address(address);
+        }
+
         Self {
-            session: Arc::new(Session::new(peer_addr)),
-            handshake: Arc::new(RwLock::new(Handshake::new_server())),
+            session: Arc::new(session),
+            handshake: Arc::new(RwLock::new(handshake)),
             sequence_number: AtomicU64::new(0),
+            packet_tracing: AtomicBool::new(false),
+            hibernated: AtomicBool::new(false),
+            key_manager: OnceLock::new(),
+            lock_key_memory: false,
+            tunnel: OnceLock::new(),
         }
     }
 
+    /// Lock the key buffers of the keys attached later in memory
+    pub fn with_locked_key_memory(mut self) -> Self {
+        self.lock_key_memory = true;
+        self
+    }
+
     /// Get session
     pub fn session(&self) -> &Arc<Session> {
         &self.session
@@ -48,18 +84,137 @@ impl Connection {
         self.handshake.read().await.is_completed()
     }
 
-    /// Update activity
+    /// Mark the session active, refused unless the handshake confirmed its keys
+    pub async fn activate(&self) -> Result<()> {
+        if !self.is_handshake_completed().await {
+            return Err(LostLoveError::HandshakeFailed(
+                "Session can't become active before key confirmation".to_string(),
+            ));
+        }
+
+        self.session.apply(SessionEvent::HandshakeCompleted).await?;
+        Ok(())
+    }
+
+    /// Update activity, waking the connection if it hibernated
     pub async fn update_activity(&self) {
+        if self.hibernated.swap(false, Ordering::Relaxed) {
+            debug!("Session {} woke from hibernation", self.session.id());
+        }
         self.session.update_activity().await;
     }
+
+    /// Mark the connection idle and free its handshake's negotiation state,
+    /// returns false if it already hibernated
+    pub async fn hibernate(&self) -> bool {
+        if self.hibernated.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        self.handshake.write().await.release_negotiation();
+        true
+    }
+
+    /// Check if the connection hibernates until its next packet
+    pub fn is_hibernated(&self) -> bool {
+        self.hibernated.load(Ordering::Relaxed)
+    }
+
+    /// Enable or disable per-packet tracing for this connection
+    pub fn set_packet_tracing(&self, enabled: bool) {
+        self.packet_tracing.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Check if per-packet tracing is forced on
+    pub fn packet_tracing(&self) -> bool {
+        self.packet_tracing.load(Ordering::Relaxed)
+    }
+
+    /// Attach the keys the handshake established, once
+    pub fn attach_keys(&self, key_manager: KeyManager) -> Result<Arc<KeyManager>> {
+        if self.key_manager.get().is_some() {
+            return Err(LostLoveError::Crypto("Connection already has keys".to_string()));
+        }
+        let key_manager = if self.lock_key_memory {
+            key_manager.with_locked_memory()
+        } else {
+            key_manager
+        };
+        let key_manager = Arc::new(key_manager);
+        self.key_manager
+            .set(key_manager.clone())
+            .map_err(|_| LostLoveError::Crypto("Connection already has keys".to_string()))?;
+        Ok(key_manager)
+    }
+
+    /// Keys of this connection, None until the handshake established them
+    pub fn key_manager(&self) -> Option<&Arc<KeyManager>> {
+        self.key_manager.get()
+    }
+
+    /// Start queueing packets routed to this client, once; its transport
+    /// sends what the receiver yields
+    pub fn open_tunnel(&self) -> Result<mpsc::Receiver<Bytes>> {
+        let (sender, receiver) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+        self.tunnel
+            .set(sender)
+            .map_err(|_| LostLoveError::Connection("Connection already has a tunnel".to_string()))?;
+        Ok(receiver)
+    }
+
+    /// Queue a packet routed to this client
+    pub fn send_tunneled(&self, packet: Bytes) -> Result<()> {
+        let Some(tunnel) = self.tunnel.get() else {
+            return Err(LostLoveError::Connection("No tunnel to the client yet".to_string()));
+        };
+        tunnel.try_send(packet).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => LostLoveError::Connection("Tunnel queue full".to_string()),
+            mpsc::error::TrySendError::Closed(_) => LostLoveError::Connection("Tunnel closed".to_string()),
+        })
+    }
+}
+
+/// A connection that stays registered for as long as the guard lives
+///
+/// Dropping the guard removes the connection and frees its slot and lease,
+/// however its handler ends: returning, panicking, or being cancelled.
+#[must_use = "the connection is removed as soon as the guard is dropped"]
+pub struct ConnectionGuard {
+    connection: Arc<Connection>,
+    connections: Arc<DashMap<SessionId, Arc<Connection>>>,
+    active_count: Arc<AtomicUsize>,
+    ip_pool: Option<Arc<IpPool>>,
+}
+
+impl Deref for ConnectionGuard {
+    type Target = Arc<Connection>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        release(
+            &self.connections,
+            &self.active_count,
+            self.ip_pool.as_deref(),
+            self.connection.session().id(),
+        );
+    }
 }
 
 /// Connection Manager manages all active connections
 pub struct ConnectionManager {
     connections: Arc<DashMap<SessionId, Arc<Connection>>>,
     max_connections: usize,
-    active_count: AtomicUsize,
+    active_count: Arc<AtomicUsize>,
     total_connections: AtomicU64,
+    ip_pool: Option<Arc<IpPool>>,
+    crypto_policy: CryptoPolicy,
+    lock_key_memory: bool,
+    clock: Arc<dyn Clock>,
+    totals: Arc<TrafficTotals>,
 }
 
 impl ConnectionManager {
@@ -70,13 +225,48 @@ impl ConnectionManager {
         Self {
             connections: Arc::new(DashMap::new()),
             max_connections,
-            active_count: AtomicUsize::new(0),
+            active_count: Arc::new(AtomicUsize::new(0)),
             total_connections: AtomicU64::new(0),
+            ip_pool: None,
+            crypto_policy: CryptoPolicy::Standard,
+            lock_key_memory: false,
+            clock: clock::system(),
+            totals: Arc::new(TrafficTotals::new()),
         }
     }
 
-    /// Create new connection
-    pub fn create_connection(&self, peer_addr: SocketAddr) -> Result<Arc<Connection>> {
+    /// Lease a tunnel address from the pool to every new connection
+    pub fn with_ip_pool(mut self, ip_pool: Arc<IpPool>) -> Self {
+        self.ip_pool = Some(ip_pool);
+        self
+    }
+
+    /// Restrict the cipher suites new connections negotiate
+    pub fn with_crypto_policy(mut self, crypto_policy: CryptoPolicy) -> Self {
+        self.crypto_policy = crypto_policy;
+        self
+    }
+
+    /// Lock the session keys of new connections in memory
+    pub fn with_locked_key_memory(mut self) -> Self {
+        self.lock_key_memory = true;
+        self
+    }
+
+    /// Give new sessions `clock` instead of the system clocks
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Get the tunnel address pool
+    pub fn ip_pool(&self) -> Option<&Arc<IpPool>> {
+        self.ip_pool.as_ref()
+    }
+
+    /// Create new connection, removed again when the guard is dropped
+    pub fn create_connection(&self, peer_addr: SocketAddr) -> Result<ConnectionGuard> {
         let current = self.active_count.load(Ordering::Relaxed);
 
         if current >= self.max_connections {
@@ -87,12 +277,24 @@ impl ConnectionManager {
             return Err(LostLoveError::TooManyConnections);
         }
 
-        let connection = Arc::new(Connection::new(peer_addr));
-        let session_id = connection.session().id().clone();
+        let mut session = Session::new(peer_addr)
+            .with_clock(self.clock.clone())
+            .with_totals(self.totals.clone());
+        if let Some(ip_pool) = &self.ip_pool {
+            let lease = ip_pool.allocate(session.id())?;
+            session = session.with_tunnel_address(lease.into());
+        }
+
+        let mut connection = Connection::from_session(session, self.crypto_policy.cipher_suites());
+        if self.lock_key_memory {
+            connection = connection.with_locked_key_memory();
+        }
+        let connection = Arc::new(connection);
+        let session_id = *connection.session().id();
 
         debug!("Creating new connection: {} from {}", session_id, peer_addr);
 
-        self.connections.insert(session_id.clone(), connection.clone());
+        self.connections.insert(session_id, connection.clone());
         self.active_count.fetch_add(1, Ordering::SeqCst);
         self.total_connections.fetch_add(1, Ordering::SeqCst);
 
@@ -102,7 +304,12 @@ impl ConnectionManager {
             self.active_count.load(Ordering::Relaxed)
         );
 
-        Ok(connection)
+        Ok(ConnectionGuard {
+            connection,
+            connections: self.connections.clone(),
+            active_count: self.active_count.clone(),
+            ip_pool: self.ip_pool.clone(),
+        })
     }
 
     /// Get connection by session ID
@@ -110,22 +317,9 @@ impl ConnectionManager {
         self.connections.get(session_id).map(|r| r.value().clone())
     }
 
-    /// Remove connection
+    /// Remove connection ahead of its guard, e.g. when it went stale
     pub fn remove_connection(&self, session_id: &SessionId) -> Option<Arc<Connection>> {
-        debug!("Removing connection: {}", session_id);
-
-        let result = self.connections.remove(session_id).map(|(_, conn)| conn);
-
-        if result.is_some() {
-            self.active_count.fetch_sub(1, Ordering::SeqCst);
-            info!(
-                "Connection removed: {} (remaining: {})",
-                session_id,
-                self.active_count.load(Ordering::Relaxed)
-            );
-        }
-
-        result
+        release(&self.connections, &self.active_count, self.ip_pool.as_deref(), session_id)
     }
 
     /// Get active connections count
@@ -133,82 +327,135 @@ impl ConnectionManager {
         self.active_count.load(Ordering::Relaxed)
     }
 
+    /// Connections allowed at once
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Share of `max_connections` in use, 1.0 when full
+    pub fn saturation(&self) -> f64 {
+        if self.max_connections == 0 {
+            return 1.0;
+        }
+        self.active_count() as f64 / self.max_connections as f64
+    }
+
+    /// Cipher suites new connections negotiate
+    pub fn cipher_suites(&self) -> &'static [CipherSuite] {
+        self.crypto_policy.cipher_suites()
+    }
+
     /// Get total connections count (historical)
     pub fn total_count(&self) -> u64 {
         self.total_connections.load(Ordering::Relaxed)
     }
 
-    /// Cleanup stale connections
-    pub async fn cleanup_stale(&self, timeout: Duration) {
-        let mut to_remove = Vec::new();
-
-        for entry in self.connections.iter() {
-            let session = entry.value().session();
+    /// Cleanup stale connections among those in `slice` of `slices`,
+    /// returns how many were removed
+    ///
+    /// Sessions fall into slices by ID, so sweeping the slices in turn
+    /// checks every session once without scanning them all at once.
+    pub async fn cleanup_stale(&self, timeout: Duration, slice: usize, slices: usize) -> usize {
+        let candidates: Vec<Arc<Connection>> = self
+            .connections
+            .iter()
+            .filter(|entry| entry.key().as_bytes()[0] as usize % slices == slice)
+            .map(|entry| entry.value().clone())
+            .collect();
 
+        let mut removed = 0;
+        for connection in candidates {
+            let session = connection.session();
             if session.should_timeout(timeout).await {
-                warn!("Session {} timed out", entry.key());
-                to_remove.push(entry.key().clone());
+                warn!("Session {} timed out", session.id());
+                self.remove_connection(session.id());
+                removed += 1;
             }
         }
-
-        for session_id in to_remove {
-            self.remove_connection(&session_id);
-        }
+        removed
     }
 
     /// Get all session IDs
     pub fn get_all_sessions(&self) -> Vec<SessionId> {
         self.connections
             .iter()
-            .map(|entry| entry.key().clone())
+            .map(|entry| *entry.key())
             .collect()
     }
 
-    /// Get statistics
-    pub async fn get_stats(&self) -> ConnectionManagerStats {
-        let mut total_packets_sent = 0u64;
-        let mut total_packets_received = 0u64;
-        let mut total_bytes_sent = 0u64;
-        let mut total_bytes_received = 0u64;
-        let mut total_errors = 0u64;
-
-        for entry in self.connections.iter() {
-            let stats = entry.value().session().stats().await;
-            total_packets_sent += stats.packets_sent;
-            total_packets_received += stats.packets_received;
-            total_bytes_sent += stats.bytes_sent;
-            total_bytes_received += stats.bytes_received;
-            total_errors += stats.errors;
-        }
+    /// Get all active connections
+    pub fn connections(&self) -> Vec<Arc<Connection>> {
+        self.connections
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    /// Get statistics; traffic totals count every session since start, not
+    /// only the active ones
+    pub fn get_stats(&self) -> ConnectionManagerStats {
+        let totals = self.totals.snapshot();
 
         ConnectionManagerStats {
             active_connections: self.active_count(),
+            hibernated_connections: self.connections.iter().filter(|entry| entry.is_hibernated()).count(),
             total_connections: self.total_count(),
-            total_packets_sent,
-            total_packets_received,
-            total_bytes_sent,
-            total_bytes_received,
-            total_errors,
+            total_packets_sent: totals.packets_sent,
+            total_packets_received: totals.packets_received,
+            total_bytes_sent: totals.bytes_sent,
+            total_bytes_received: totals.bytes_received,
+            total_errors: totals.errors,
+            total_source_violations: totals.source_violations,
         }
     }
 }
 
+/// Remove a connection once, freeing its slot and lease
+fn release(
+    connections: &DashMap<SessionId, Arc<Connection>>,
+    active_count: &AtomicUsize,
+    ip_pool: Option<&IpPool>,
+    session_id: &SessionId,
+) -> Option<Arc<Connection>> {
+    debug!("Removing connection: {}", session_id);
+
+    let result = connections.remove(session_id).map(|(_, conn)| conn);
+
+    if let Some(connection) = &result {
+        if let (Some(ip_pool), Some(IpAddr::V4(lease))) = (ip_pool, connection.session().tunnel_address()) {
+            ip_pool.release(lease);
+        }
+
+        active_count.fetch_sub(1, Ordering::SeqCst);
+        info!(
+            "Connection removed: {} (remaining: {})",
+            session_id,
+            active_count.load(Ordering::Relaxed)
+        );
+    }
+
+    result
+}
+
 /// Connection manager statistics
 #[derive(Debug, Clone)]
 pub struct ConnectionManagerStats {
     pub active_connections: usize,
+    /// Active connections idle long enough to have released their buffers
+    pub hibernated_connections: usize,
     pub total_connections: u64,
     pub total_packets_sent: u64,
     pub total_packets_received: u64,
     pub total_bytes_sent: u64,
     pub total_bytes_received: u64,
     pub total_errors: u64,
+    pub total_source_violations: u64,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::Ipv4Addr;
 
     #[tokio::test]
     async fn test_connection_creation() {
@@ -229,13 +476,92 @@ mod tests {
         assert_eq!(connection.next_sequence(), 2);
     }
 
+    #[tokio::test]
+    async fn test_packet_tracing_toggle() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let connection = Connection::new(addr);
+
+        assert!(!connection.packet_tracing());
+        connection.set_packet_tracing(true);
+        assert!(connection.packet_tracing());
+    }
+
+    #[tokio::test]
+    async fn test_hibernate_until_next_packet() {
+        let manager = ConnectionManager::new(10);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        let _other = manager.create_connection(addr).unwrap();
+
+        assert!(conn.hibernate().await);
+        assert!(!conn.hibernate().await);
+        assert!(conn.is_hibernated());
+        assert!(conn.handshake().read().await.cipher_suites().is_empty());
+        assert_eq!(manager.get_stats().hibernated_connections, 1);
+
+        conn.update_activity().await;
+        assert!(!conn.is_hibernated());
+        assert_eq!(manager.get_stats().hibernated_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_crypto_policy_restricts_handshake() {
+        let manager = ConnectionManager::new(10).with_crypto_policy(CryptoPolicy::Fips);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+
+        let conn = manager.create_connection(addr).unwrap();
+        assert_eq!(conn.handshake().read().await.cipher_suites(), [CipherSuite::Aes256Gcm]);
+    }
+
+    #[tokio::test]
+    async fn test_attach_keys_once() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let connection = Connection::new(addr);
+        let key_manager = || KeyManager::new(vec![1u8; 32], [2u8; 32], [3u8; 32], true).unwrap();
+
+        assert!(connection.key_manager().is_none());
+        connection.attach_keys(key_manager()).unwrap();
+        assert!(connection.key_manager().is_some());
+        assert!(connection.attach_keys(key_manager()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attached_keys_locked_when_configured() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let key_manager = || KeyManager::new(vec![1u8; 32], [2u8; 32], [3u8; 32], true).unwrap();
+
+        let manager = ConnectionManager::new(10);
+        let connection = manager.create_connection(addr).unwrap();
+        assert!(!connection.attach_keys(key_manager()).unwrap().is_memory_locked());
+
+        let manager = ConnectionManager::new(10).with_locked_key_memory();
+        let connection = manager.create_connection(addr).unwrap();
+        assert!(connection.attach_keys(key_manager()).unwrap().is_memory_locked());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_queue() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let connection = Connection::new(addr);
+
+        assert!(connection.send_tunneled(Bytes::from_static(b"early")).is_err());
+        let mut tunnel = connection.open_tunnel().unwrap();
+        assert!(connection.open_tunnel().is_err());
+
+        for _ in 0..TUNNEL_QUEUE_SIZE {
+            connection.send_tunneled(Bytes::from_static(b"packet")).unwrap();
+        }
+        assert!(connection.send_tunneled(Bytes::from_static(b"dropped")).is_err());
+        assert_eq!(tunnel.recv().await.unwrap(), Bytes::from_static(b"packet"));
+    }
+
     #[tokio::test]
     async fn test_connection_manager() {
         let manager = ConnectionManager::new(10);
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
 
         let conn = manager.create_connection(addr).unwrap();
-        let session_id = conn.session().id().clone();
+        let session_id = *conn.session().id();
 
         assert_eq!(manager.active_count(), 1);
         assert!(manager.get_connection(&session_id).is_some());
@@ -251,8 +577,8 @@ mod tests {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
 
         // Create 2 connections (max)
-        let conn1 = manager.create_connection(addr).unwrap();
-        let conn2 = manager.create_connection(addr).unwrap();
+        let _conn1 = manager.create_connection(addr).unwrap();
+        let _conn2 = manager.create_connection(addr).unwrap();
 
         // Try to create 3rd connection (should fail)
         let result = manager.create_connection(addr);
@@ -260,6 +586,43 @@ mod tests {
         assert_eq!(manager.active_count(), 2);
     }
 
+    #[tokio::test]
+    async fn test_leases_assigned_and_released() {
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = ConnectionManager::new(10).with_ip_pool(pool.clone());
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+
+        let conn = manager.create_connection(addr).unwrap();
+        let lease = conn.session().tunnel_address().unwrap();
+        assert_eq!(lease, IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)));
+        assert_eq!(pool.leased_count(), 1);
+
+        manager.remove_connection(conn.session().id());
+        assert_eq!(pool.leased_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_guard_releases_connection() {
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = ConnectionManager::new(1).with_ip_pool(pool.clone());
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+
+        let conn = manager.create_connection(addr).unwrap();
+        let session_id = *conn.session().id();
+        drop(conn);
+        assert_eq!(manager.active_count(), 0);
+        assert_eq!(pool.leased_count(), 0);
+        assert!(manager.get_connection(&session_id).is_none());
+
+        // Removed early, the guard doesn't release it twice
+        let conn = manager.create_connection(addr).unwrap();
+        manager.remove_connection(conn.session().id());
+        let other = manager.create_connection(addr).unwrap();
+        drop(conn);
+        assert_eq!(manager.active_count(), 1);
+        assert!(manager.get_connection(other.session().id()).is_some());
+    }
+
     #[tokio::test]
     async fn test_connection_stats() {
         let manager = ConnectionManager::new(10);
@@ -271,10 +634,53 @@ mod tests {
         conn.session().record_packet_sent(100).await;
         conn.session().record_packet_received(200).await;
 
-        let stats = manager.get_stats().await;
+        let stats = manager.get_stats();
         assert_eq!(stats.active_connections, 1);
         assert_eq!(stats.total_packets_sent, 1);
         assert_eq!(stats.total_bytes_sent, 100);
         assert_eq!(stats.total_bytes_received, 200);
+
+        // Traffic of closed sessions stays in the totals
+        drop(conn);
+        let stats = manager.get_stats();
+        assert_eq!(stats.active_connections, 0);
+        assert_eq!(stats.total_bytes_sent, 100);
+    }
+
+    /// `cargo test --release bench_stats -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn bench_stats_50k_sessions() {
+        const SESSIONS: usize = 50_000;
+        const SNAPSHOTS: u32 = 1_000;
+
+        let manager = ConnectionManager::new(SESSIONS);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut conns = Vec::with_capacity(SESSIONS);
+        for i in 0..SESSIONS {
+            let conn = manager.create_connection(addr).unwrap();
+            conn.session().record_packet_sent(i % 1500).await;
+            conns.push(conn);
+        }
+
+        let start = std::time::Instant::now();
+        let mut per_session = 0;
+        for conn in &conns {
+            per_session += conn.session().stats().await.bytes_sent;
+        }
+        let locking = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let mut stats = manager.get_stats();
+        for _ in 1..SNAPSHOTS {
+            stats = manager.get_stats();
+        }
+        let sharded = start.elapsed() / SNAPSHOTS;
+
+        assert_eq!(stats.total_bytes_sent, per_session);
+        println!(
+            "{} sessions: locking every session {:?}, sharded snapshot {:?}",
+            SESSIONS, locking, sharded
+        );
     }
 }
@@ -0,0 +1,191 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+
+use crate::config::DecoyConfig;
+use crate::error::{LostLoveError, Result};
+use crate::protocol::{PacketType, PROTOCOL_ID};
+
+/// Whether bytes received so far can still be the start of a HandshakeInit
+/// packet
+///
+/// Lets the server give up on a probe after its first bytes instead of
+/// waiting for a full header a non-LLP client will never send.
+pub fn is_handshake_prefix(received: &[u8]) -> bool {
+    let [high, low] = PROTOCOL_ID.to_be_bytes();
+    received
+        .iter()
+        .zip([high, low, PacketType::HandshakeInit as u8])
+        .all(|(byte, expected)| *byte == expected)
+}
+
+/// Proxy to a decoy backend for connections that fail the first handshake
+/// message
+///
+/// The backend sees the bytes the client already sent followed by the rest
+/// of the connection, so a probe gets the backend's genuine answer.
+/// Connections idle for `idle_timeout` in both directions are closed.
+pub struct Decoy {
+    address: String,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+    active: AtomicUsize,
+}
+
+impl Decoy {
+    /// Create from config, None when disabled
+    pub fn new(config: &DecoyConfig) -> Option<Self> {
+        config.enabled.then(|| Self {
+            address: config.address.clone(),
+            connect_timeout: Duration::from_secs(config.connect_timeout),
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    /// Connections being proxied right now
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Hand a client over to the backend until either side closes or the
+    /// connection goes idle
+    pub async fn serve(&self, client: TcpStream, received: &[u8]) -> Result<()> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let result = self.proxy(client, received).await;
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
+
+    async fn proxy(&self, mut client: TcpStream, received: &[u8]) -> Result<()> {
+        let mut backend = time::timeout(self.connect_timeout, TcpStream::connect(&self.address))
+            .await
+            .map_err(|_| LostLoveError::Connection(format!("Decoy {} did not accept", self.address)))??;
+        backend.write_all(received).await?;
+
+        // Either direction moving keeps the whole connection alive
+        let activity = Mutex::new(Instant::now());
+        let (mut client_read, mut client_write) = client.split();
+        let (mut backend_read, mut backend_write) = backend.split();
+        tokio::try_join!(
+            pipe(&mut client_read, &mut backend_write, &activity, self.idle_timeout),
+            pipe(&mut backend_read, &mut client_write, &activity, self.idle_timeout),
+        )?;
+        Ok(())
+    }
+}
+
+/// Bytes proxied at once in each direction
+const COPY_CHUNK: usize = 8192;
+
+/// Copy `reader` to `writer` until `reader` is done
+///
+/// Closing the reader half-closes the writer, as `copy_bidirectional` does.
+/// Fails once nothing moved in either direction for `idle_timeout`.
+async fn pipe(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    activity: &Mutex<Instant>,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let idle = || LostLoveError::Connection("Decoy connection idle".to_string());
+    let mut buf = vec![0u8; COPY_CHUNK];
+    loop {
+        let deadline = *activity.lock().unwrap() + idle_timeout;
+        let n = match time::timeout_at(deadline, reader.read(&mut buf)).await {
+            Ok(n) => n?,
+            // The other direction may have moved meanwhile
+            Err(_) if activity.lock().unwrap().elapsed() < idle_timeout => continue,
+            Err(_) => return Err(idle()),
+        };
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        time::timeout(idle_timeout, writer.write_all(&buf[..n]))
+            .await
+            .map_err(|_| idle())??;
+        *activity.lock().unwrap() = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Packet;
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_handshake_prefix() {
+        let hello = Packet::new(PacketType::HandshakeInit, Bytes::from_static(b"{}")).serialize();
+        for len in 0..=hello.len() {
+            assert!(is_handshake_prefix(&hello[..len]));
+        }
+
+        let data = Packet::new(PacketType::Data, Bytes::new()).serialize();
+        assert!(!is_handshake_prefix(&data));
+        assert!(!is_handshake_prefix(b"GET / HTTP/1.1\r\n"));
+        assert!(!is_handshake_prefix(b"\x16\x03\x01"));
+    }
+
+    #[tokio::test]
+    async fn test_probe_reaches_backend() {
+        // Backend answering like a web server once it has the whole request
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = DecoyConfig {
+            enabled: true,
+            address: backend.local_addr().unwrap().to_string(),
+            ..DecoyConfig::default()
+        };
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut request = [0u8; 18];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"GET / HTTP/1.1\r\n\r\n");
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut probe = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        // The server read the first bytes before giving up on the handshake
+        probe.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut received = [0u8; 2];
+        accepted.read_exact(&mut received).await.unwrap();
+        let decoy = Decoy::new(&config).unwrap();
+        tokio::spawn(async move { decoy.serve(accepted, &received).await });
+
+        let mut response = Vec::new();
+        probe.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_connection_closed() {
+        // Backend that accepts and never answers
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = DecoyConfig {
+            enabled: true,
+            address: backend.local_addr().unwrap().to_string(),
+            idle_timeout: 30,
+            ..DecoyConfig::default()
+        };
+        let held = tokio::spawn(async move { backend.accept().await.unwrap() });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _probe = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+
+        let decoy = Decoy::new(&config).unwrap();
+        let err = decoy.serve(accepted, b"GET").await.unwrap_err();
+        assert_eq!(err.to_string(), "Connection error: Decoy connection idle");
+        assert_eq!(decoy.active(), 0);
+        drop(held);
+    }
+}
@@ -0,0 +1,508 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tracing::{debug, info};
+
+use crate::core::config_push::PushedSettings;
+use crate::core::session::SessionId;
+use crate::error::{LostLoveError, Result};
+use crate::network::path_mtu::{header_overhead, min_mtu};
+use crate::network::UdpTransport;
+use crate::protocol::address_discovery::Transport;
+use crate::protocol::{Packet, PacketType, PathProbe, HEADER_SIZE};
+
+/// Probes queued per session before new ones are dropped, holds a burst
+const MAILBOX_SIZE: usize = 64;
+
+/// Echoes queued for a running diagnosis before new ones are dropped
+const ECHO_QUEUE_SIZE: usize = 256;
+
+/// Probes sent one at a time to measure the round trip
+const RTT_PROBES: usize = 5;
+
+/// Bursts of back-to-back probes sent to measure loss
+const LOSS_BURSTS: usize = 4;
+const BURST_SIZE: usize = 25;
+
+/// Probes of one size sent before the size is taken as too big
+const MTU_ATTEMPTS: usize = 2;
+
+/// Largest path MTU searched for, unless the tunnel MTU needs more
+const MAX_SEARCHED_MTU: usize = 1500;
+
+/// Wait for an echo, four round trips once one was measured
+const MIN_ECHO_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Result of probing one session's path, as reported over the control socket
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Diagnosis {
+    pub session_id: String,
+    pub transport: Transport,
+    pub rtt_min_ms: Option<f64>,
+    pub rtt_avg_ms: Option<f64>,
+    pub rtt_max_ms: Option<f64>,
+    /// Probes sent in loss bursts, none over TCP
+    pub probes_sent: usize,
+    pub probes_lost: usize,
+    /// Most probes lost in a row
+    pub longest_loss_burst: usize,
+    /// Largest IP packet that made it both ways, None if not measured or
+    /// even the smallest was lost
+    pub path_mtu: Option<usize>,
+    /// The path carried the largest size searched for, it may take more
+    pub path_mtu_at_least: bool,
+    pub tunnel_mtu: usize,
+    /// Path MTU the tunnel MTU needs, for UDP
+    pub required_mtu: Option<usize>,
+    /// Human-readable findings, most important first
+    pub verdict: Vec<String>,
+}
+
+/// Where a diagnosis sends its probes
+enum ProbePath<'a> {
+    Udp {
+        transport: &'a UdpTransport,
+        peer: SocketAddr,
+    },
+    /// Through the session's data loop
+    Tcp(mpsc::Sender<Packet>),
+}
+
+/// Actively probes sessions' paths on request
+///
+/// Every session gets a mailbox its data loop drains into PathProbe packets
+/// over TCP; sessions with a UDP path are probed over it directly. Clients
+/// echo the probes, and echoes of sessions being diagnosed are passed on to
+/// the running diagnosis.
+pub struct PathDiagnostics {
+    mailboxes: DashMap<SessionId, mpsc::Sender<Packet>>,
+    runs: DashMap<SessionId, mpsc::Sender<u32>>,
+    next_probe: AtomicU32,
+    settings: watch::Receiver<PushedSettings>,
+}
+
+impl PathDiagnostics {
+    /// Create with the pushed settings, for the tunnel MTU
+    pub fn new(settings: watch::Receiver<PushedSettings>) -> Self {
+        Self {
+            mailboxes: DashMap::new(),
+            runs: DashMap::new(),
+            next_probe: AtomicU32::new(rand::random()),
+            settings,
+        }
+    }
+
+    /// Open the mailbox of a session
+    pub fn register(&self, session_id: &SessionId) -> mpsc::Receiver<Packet> {
+        let (sender, receiver) = mpsc::channel(MAILBOX_SIZE);
+        self.mailboxes.insert(*session_id, sender);
+        receiver
+    }
+
+    /// Close the mailbox of a session
+    pub fn unregister(&self, session_id: &SessionId) {
+        self.mailboxes.remove(session_id);
+    }
+
+    /// Handle a probe a session's client echoed
+    pub fn echo(&self, session_id: &SessionId, packet: &Packet) {
+        let Some(run) = self.runs.get(session_id) else {
+            debug!("Ignoring path probe from session {}, not diagnosing it", session_id);
+            return;
+        };
+        match PathProbe::from_bytes(&packet.payload) {
+            Ok(echo) => {
+                let _ = run.try_send(echo.probe);
+            }
+            Err(e) => debug!("Invalid path probe from session {}: {}", session_id, e),
+        }
+    }
+
+    /// Probe a session's path and tell what's wrong with it
+    ///
+    /// Sessions with a UDP path are probed over it for round trip time, loss
+    /// and path MTU; over TCP only the round trip time can be seen.
+    pub async fn diagnose(&self, session_id: &SessionId, udp: Option<&UdpTransport>) -> Result<Diagnosis> {
+        let path = match udp.and_then(|transport| Some((transport, transport.bound_address(session_id)?))) {
+            Some((transport, peer)) => ProbePath::Udp { transport, peer },
+            None => match self.mailboxes.get(session_id) {
+                Some(mailbox) => ProbePath::Tcp(mailbox.clone()),
+                None => return Err(LostLoveError::SessionNotFound(session_id.to_string())),
+            },
+        };
+
+        let (sender, echoes) = mpsc::channel(ECHO_QUEUE_SIZE);
+        match self.runs.entry(*session_id) {
+            Entry::Occupied(_) => {
+                return Err(LostLoveError::Admin(format!("Session {} is already being diagnosed", session_id)));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+            }
+        }
+
+        let mut run = Run {
+            session_id: *session_id,
+            path,
+            echoes,
+            next_probe: &self.next_probe,
+            timeout: MAX_ECHO_TIMEOUT,
+        };
+        let tunnel_mtu = self.settings.borrow().mtu;
+        info!("Diagnosing the path of session {}", session_id);
+        let diagnosis = run.diagnose(tunnel_mtu).await;
+
+        self.runs.remove(session_id);
+        Ok(diagnosis)
+    }
+}
+
+/// One diagnosis in progress
+struct Run<'a> {
+    session_id: SessionId,
+    path: ProbePath<'a>,
+    echoes: mpsc::Receiver<u32>,
+    next_probe: &'a AtomicU32,
+    timeout: Duration,
+}
+
+impl Run<'_> {
+    async fn diagnose(&mut self, tunnel_mtu: usize) -> Diagnosis {
+        let mut diagnosis = Diagnosis {
+            session_id: self.session_id.to_string(),
+            tunnel_mtu,
+            ..Default::default()
+        };
+
+        let mut rtts = Vec::new();
+        for _ in 0..RTT_PROBES {
+            if let Some(rtt) = self.ping(0).await {
+                rtts.push(rtt.as_secs_f64() * 1000.0);
+            }
+        }
+        if let Some(max) = rtts.iter().copied().reduce(f64::max) {
+            diagnosis.rtt_min_ms = rtts.iter().copied().reduce(f64::min);
+            diagnosis.rtt_avg_ms = Some(rtts.iter().sum::<f64>() / rtts.len() as f64);
+            diagnosis.rtt_max_ms = Some(max);
+            self.timeout = Duration::from_secs_f64(max * 4.0 / 1000.0).clamp(MIN_ECHO_TIMEOUT, MAX_ECHO_TIMEOUT);
+        }
+
+        diagnosis.transport = match &self.path {
+            ProbePath::Udp { peer, .. } => {
+                let overhead = header_overhead(peer) + HEADER_SIZE;
+                let (low, peer) = (min_mtu(peer), *peer);
+
+                if !rtts.is_empty() {
+                    self.measure_loss(&mut diagnosis).await;
+
+                    let required = tunnel_mtu + overhead;
+                    let (path_mtu, at_least) = self.search_mtu(low, required.max(MAX_SEARCHED_MTU), overhead).await;
+                    diagnosis.path_mtu = path_mtu;
+                    diagnosis.path_mtu_at_least = at_least;
+                    diagnosis.required_mtu = Some(required);
+                }
+                debug!("Probed UDP path of session {} at {}", self.session_id, peer);
+                Transport::Udp
+            }
+            ProbePath::Tcp(_) => Transport::Tcp,
+        };
+
+        diagnosis.verdict = verdict(&diagnosis, min_mtu_of(&self.path));
+        diagnosis
+    }
+
+    /// Send bursts of probes back to back and see how many come back
+    async fn measure_loss(&mut self, diagnosis: &mut Diagnosis) {
+        let mut received = Vec::with_capacity(LOSS_BURSTS * BURST_SIZE);
+        for _ in 0..LOSS_BURSTS {
+            let mut sent = Vec::with_capacity(BURST_SIZE);
+            let mut pending = HashMap::new();
+            for _ in 0..BURST_SIZE {
+                let probe = self.send(0).await;
+                if let Some(probe) = probe {
+                    pending.insert(probe, Instant::now());
+                }
+                sent.push(probe);
+            }
+
+            let answered = self.wait(pending).await;
+            received.extend(sent.iter().map(|probe| probe.is_some_and(|probe| answered.contains_key(&probe))));
+        }
+
+        diagnosis.probes_sent = received.len();
+        diagnosis.probes_lost = received.iter().filter(|received| !**received).count();
+        diagnosis.longest_loss_burst = received
+            .split(|received| *received)
+            .map(|lost| lost.len())
+            .max()
+            .unwrap_or(0);
+    }
+
+    /// Largest path MTU between `low` and `high` that probes make it through,
+    /// and whether that is `high` itself
+    async fn search_mtu(&mut self, low: usize, high: usize, overhead: usize) -> (Option<usize>, bool) {
+        if self.fits(high, overhead).await {
+            return (Some(high), true);
+        }
+        if !self.fits(low, overhead).await {
+            return (None, false);
+        }
+
+        let (mut fits, mut too_big) = (low, high);
+        while too_big - fits > 1 {
+            let size = fits + (too_big - fits) / 2;
+            if self.fits(size, overhead).await {
+                fits = size;
+            } else {
+                too_big = size;
+            }
+        }
+        (Some(fits), false)
+    }
+
+    /// Whether a probe filling a path MTU of `mtu` makes it there and back
+    async fn fits(&mut self, mtu: usize, overhead: usize) -> bool {
+        for _ in 0..MTU_ATTEMPTS {
+            let Some(probe) = self.send(mtu.saturating_sub(overhead)).await else {
+                // Refused locally as too big
+                return false;
+            };
+            if !self.wait(HashMap::from([(probe, Instant::now())])).await.is_empty() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Send one probe and time its echo
+    async fn ping(&mut self, padding: usize) -> Option<Duration> {
+        let probe = self.send(padding).await?;
+        self.wait(HashMap::from([(probe, Instant::now())])).await.remove(&probe)
+    }
+
+    /// Send a probe with `size` bytes of payload, None if it couldn't be sent
+    async fn send(&mut self, size: usize) -> Option<u32> {
+        let probe = self.next_probe.fetch_add(1, Ordering::Relaxed);
+        let packet = Packet::new(PacketType::PathProbe, PathProbe::new(probe).to_padded_bytes(size).ok()?);
+
+        match &self.path {
+            ProbePath::Udp { transport, .. } => {
+                let datagram = packet.serialize();
+                if let Err(e) = transport.send_to_session(&self.session_id, &datagram).await {
+                    debug!("Path probe of {} bytes not sent: {}", datagram.len(), e);
+                    return None;
+                }
+            }
+            ProbePath::Tcp(mailbox) => mailbox.try_send(packet).ok()?,
+        }
+        Some(probe)
+    }
+
+    /// Wait until every pending probe was echoed or the timeout passed,
+    /// returns the round trip of each echoed one
+    async fn wait(&mut self, mut pending: HashMap<u32, Instant>) -> HashMap<u32, Duration> {
+        let deadline = time::Instant::now() + self.timeout;
+        let mut answered = HashMap::new();
+        while !pending.is_empty() {
+            match time::timeout_at(deadline, self.echoes.recv()).await {
+                Ok(Some(probe)) => {
+                    if let Some(sent) = pending.remove(&probe) {
+                        answered.insert(probe, sent.elapsed());
+                    }
+                }
+                _ => break,
+            }
+        }
+        answered
+    }
+}
+
+fn min_mtu_of(path: &ProbePath) -> usize {
+    match path {
+        ProbePath::Udp { peer, .. } => min_mtu(peer),
+        ProbePath::Tcp(_) => 0,
+    }
+}
+
+/// Findings of a diagnosis in words, most important first
+fn verdict(diagnosis: &Diagnosis, min_mtu: usize) -> Vec<String> {
+    let transport = match diagnosis.transport {
+        Transport::Udp => "UDP",
+        _ => "TCP",
+    };
+    let (Some(min), Some(avg), Some(max)) = (diagnosis.rtt_min_ms, diagnosis.rtt_avg_ms, diagnosis.rtt_max_ms) else {
+        return vec![format!(
+            "no answer to {} probes over {}: the path drops the session's packets or the client doesn't echo PATH_PROBE",
+            RTT_PROBES, transport
+        )];
+    };
+
+    let mut verdict = Vec::new();
+    if let Some(required) = diagnosis.required_mtu {
+        verdict.push(match diagnosis.path_mtu {
+            None => format!("path MTU below {}: packets of that size are blackholed", min_mtu),
+            Some(path_mtu) if path_mtu < required => format!(
+                "path MTU {}, too small for the tunnel MTU {}: lower network.mtu to {}",
+                path_mtu,
+                diagnosis.tunnel_mtu,
+                diagnosis.tunnel_mtu.saturating_sub(required - path_mtu)
+            ),
+            Some(path_mtu) if diagnosis.path_mtu_at_least => {
+                format!("path MTU at least {}, fits the tunnel MTU {}", path_mtu, diagnosis.tunnel_mtu)
+            }
+            Some(path_mtu) => format!("path MTU {}, fits the tunnel MTU {}", path_mtu, diagnosis.tunnel_mtu),
+        });
+    }
+
+    if diagnosis.probes_sent > 0 {
+        let loss = diagnosis.probes_lost as f64 * 100.0 / diagnosis.probes_sent as f64;
+        verdict.push(match diagnosis.longest_loss_burst {
+            0 => format!("no loss in {} probes", diagnosis.probes_sent),
+            1 => format!("{:.1}% round-trip loss", loss),
+            burst => format!("{:.1}% round-trip loss, up to {} packets in a row", loss, burst),
+        });
+    }
+
+    verdict.push(format!("RTT {:.1} ms (min {:.1}, max {:.1})", avg, min, max));
+
+    if diagnosis.transport != Transport::Udp {
+        verdict.push("over TCP, which hides loss and path MTU: diagnose again once the client attached UDP".to_string());
+    }
+    verdict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::core::config_push::ConfigPublisher;
+    use std::sync::Arc;
+
+    fn diagnostics() -> Arc<PathDiagnostics> {
+        let publisher = ConfigPublisher::new(&Config::default_for_testing());
+        Arc::new(PathDiagnostics::new(publisher.subscribe()))
+    }
+
+    /// Echo the probes of a session's mailbox, dropping those `drop` picks
+    fn spawn_client(
+        diagnostics: Arc<PathDiagnostics>,
+        session_id: SessionId,
+        mut drop: impl FnMut(usize, &Packet) -> bool + Send + 'static,
+    ) {
+        let mut mailbox = diagnostics.register(&session_id);
+        tokio::spawn(async move {
+            let mut received = 0;
+            while let Some(probe) = mailbox.recv().await {
+                received += 1;
+                if !drop(received, &probe) {
+                    diagnostics.echo(&session_id, &probe);
+                }
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_over_tcp() {
+        let diagnostics = diagnostics();
+        let session_id = SessionId::new();
+        assert!(matches!(
+            diagnostics.diagnose(&session_id, None).await,
+            Err(LostLoveError::SessionNotFound(_))
+        ));
+
+        spawn_client(diagnostics.clone(), session_id, |_, _| false);
+        let diagnosis = diagnostics.diagnose(&session_id, None).await.unwrap();
+
+        assert_eq!(diagnosis.transport, Transport::Tcp);
+        assert!(diagnosis.rtt_avg_ms.is_some());
+        assert_eq!(diagnosis.probes_sent, 0);
+        assert_eq!(diagnosis.path_mtu, None);
+        assert!(diagnosis.verdict[0].starts_with("RTT "));
+        assert!(diagnosis.verdict[1].starts_with("over TCP"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_client() {
+        let diagnostics = diagnostics();
+        let session_id = SessionId::new();
+        spawn_client(diagnostics.clone(), session_id, |_, _| true);
+
+        let diagnosis = diagnostics.diagnose(&session_id, None).await.unwrap();
+        assert_eq!(diagnosis.rtt_avg_ms, None);
+        assert_eq!(diagnosis.verdict.len(), 1);
+        assert!(diagnosis.verdict[0].starts_with("no answer to 5 probes over TCP"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loss_and_mtu_search() {
+        let diagnostics = diagnostics();
+        let session_id = SessionId::new();
+        // Every 10th probe is lost and nothing above a 1380 byte path MTU passes
+        spawn_client(diagnostics.clone(), session_id, |received, probe| {
+            received % 10 == 0 || probe.size() + 28 > 1380
+        });
+
+        let (sender, echoes) = mpsc::channel(ECHO_QUEUE_SIZE);
+        diagnostics.runs.insert(session_id, sender);
+        let mut run = Run {
+            session_id,
+            path: ProbePath::Tcp(diagnostics.mailboxes.get(&session_id).unwrap().clone()),
+            echoes,
+            next_probe: &diagnostics.next_probe,
+            timeout: MAX_ECHO_TIMEOUT,
+        };
+
+        let mut diagnosis = Diagnosis::default();
+        run.measure_loss(&mut diagnosis).await;
+        assert_eq!((diagnosis.probes_sent, diagnosis.probes_lost), (100, 10));
+        assert_eq!(diagnosis.longest_loss_burst, 1);
+
+        // Lost probes are retried, so every 10th one doesn't skew the search
+        let overhead = 28 + HEADER_SIZE;
+        assert_eq!(run.search_mtu(576, 1500, overhead).await, (Some(1380), false));
+        assert_eq!(run.search_mtu(576, 1200, overhead).await, (Some(1200), true));
+        assert_eq!(run.search_mtu(1400, 1500, overhead).await, (None, false));
+    }
+
+    #[test]
+    fn test_verdict() {
+        let diagnosis = Diagnosis {
+            transport: Transport::Udp,
+            rtt_min_ms: Some(40.0),
+            rtt_avg_ms: Some(42.5),
+            rtt_max_ms: Some(51.0),
+            probes_sent: 100,
+            probes_lost: 2,
+            longest_loss_burst: 2,
+            path_mtu: Some(1380),
+            tunnel_mtu: 1400,
+            required_mtu: Some(1452),
+            ..Default::default()
+        };
+        assert_eq!(
+            verdict(&diagnosis, 576),
+            [
+                "path MTU 1380, too small for the tunnel MTU 1400: lower network.mtu to 1328",
+                "2.0% round-trip loss, up to 2 packets in a row",
+                "RTT 42.5 ms (min 40.0, max 51.0)",
+            ]
+        );
+
+        let blackholed = Diagnosis {
+            path_mtu: None,
+            probes_lost: 0,
+            longest_loss_burst: 0,
+            ..diagnosis
+        };
+        let verdict = verdict(&blackholed, 576);
+        assert_eq!(verdict[0], "path MTU below 576: packets of that size are blackholed");
+        assert_eq!(verdict[1], "no loss in 100 probes");
+    }
+}
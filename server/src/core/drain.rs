@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::error::{LostLoveError, Result};
+
+/// Drain progress, as reported over the control socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrainStatus {
+    pub draining: bool,
+    pub active_sessions: usize,
+    pub sessions_at_start: usize,
+    pub elapsed_secs: u64,
+    /// Seconds until remaining sessions are disconnected (None = no deadline)
+    pub deadline_secs_left: Option<u64>,
+    /// Server that refused clients are pointed to
+    pub redirect: Option<String>,
+}
+
+/// Parameters of a drain in progress
+#[derive(Debug, Clone)]
+struct Drain {
+    started_at: Instant,
+    sessions_at_start: usize,
+    deadline: Option<Instant>,
+    redirect: Option<String>,
+}
+
+/// Puts the server into draining mode for rolling upgrades
+///
+/// While draining, new handshakes are refused and existing sessions run until
+/// they end on their own or the deadline passes.
+pub struct DrainController {
+    drain: RwLock<Option<Drain>>,
+    expired: watch::Sender<bool>,
+}
+
+impl DrainController {
+    /// Create a controller that is not draining
+    pub fn new() -> Self {
+        let (expired, _) = watch::channel(false);
+
+        Self {
+            drain: RwLock::new(None),
+            expired,
+        }
+    }
+
+    /// Start draining, disconnecting remaining sessions after `deadline`
+    pub fn start(
+        self: &Arc<Self>,
+        active_sessions: usize,
+        deadline: Option<Duration>,
+        redirect: Option<String>,
+    ) -> Result<()> {
+        let mut drain = self.drain.write().unwrap();
+        if drain.is_some() {
+            return Err(LostLoveError::Admin("Server is already draining".to_string()));
+        }
+
+        let started_at = Instant::now();
+        *drain = Some(Drain {
+            started_at,
+            sessions_at_start: active_sessions,
+            deadline: deadline.map(|deadline| started_at + deadline),
+            redirect,
+        });
+
+        info!(
+            "Draining {} sessions{}",
+            active_sessions,
+            deadline
+                .map(|d| format!(", deadline in {}s", d.as_secs()))
+                .unwrap_or_default()
+        );
+
+        if let Some(deadline) = deadline {
+            let controller = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(deadline).await;
+                info!("Drain deadline reached, disconnecting remaining sessions");
+                controller.expired.send_replace(true);
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check if new handshakes are refused
+    pub fn is_draining(&self) -> bool {
+        self.drain.read().unwrap().is_some()
+    }
+
+    /// Server that refused clients should retry at
+    pub fn redirect(&self) -> Option<String> {
+        self.drain.read().unwrap().as_ref().and_then(|drain| drain.redirect.clone())
+    }
+
+    /// Notified with `true` once the drain deadline passed
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.expired.subscribe()
+    }
+
+    /// Report drain progress
+    pub fn status(&self, active_sessions: usize) -> DrainStatus {
+        let drain = self.drain.read().unwrap();
+
+        match drain.as_ref() {
+            Some(drain) => DrainStatus {
+                draining: true,
+                active_sessions,
+                sessions_at_start: drain.sessions_at_start,
+                elapsed_secs: drain.started_at.elapsed().as_secs(),
+                deadline_secs_left: drain.deadline.map(|deadline| {
+                    // Rounded up, so a drain only shows 0 once the deadline passed
+                    deadline.saturating_duration_since(Instant::now()).as_secs_f64().ceil() as u64
+                }),
+                redirect: drain.redirect.clone(),
+            },
+            None => DrainStatus {
+                draining: false,
+                active_sessions,
+                sessions_at_start: 0,
+                elapsed_secs: 0,
+                deadline_secs_left: None,
+                redirect: None,
+            },
+        }
+    }
+}
+
+impl Default for DrainController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_status() {
+        let controller = Arc::new(DrainController::new());
+        assert!(!controller.is_draining());
+        assert!(!controller.status(3).draining);
+
+        controller
+            .start(3, None, Some("llp2.example.com:8443".to_string()))
+            .unwrap();
+        assert!(controller.is_draining());
+        assert_eq!(controller.redirect().as_deref(), Some("llp2.example.com:8443"));
+        assert!(controller.start(3, None, None).is_err());
+
+        let status = controller.status(1);
+        assert_eq!(status.sessions_at_start, 3);
+        assert_eq!(status.active_sessions, 1);
+        assert_eq!(status.deadline_secs_left, None);
+    }
+
+    #[tokio::test]
+    async fn test_drain_deadline_expires() {
+        let controller = Arc::new(DrainController::new());
+        let mut expired = controller.subscribe();
+
+        controller.start(1, Some(Duration::from_millis(20)), None).unwrap();
+        assert!(!*expired.borrow());
+
+        tokio::time::timeout(Duration::from_secs(1), expired.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(*expired.borrow());
+    }
+}
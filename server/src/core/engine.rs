@@ -0,0 +1,543 @@
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::core::error_throttle::ErrorThrottle;
+use crate::core::rate_limiter::SharedBucket;
+use crate::error::{LostLoveError, Result};
+use crate::protocol::address_discovery::Transport;
+use crate::protocol::handshake::Handshake;
+use crate::protocol::registry::{ExtensionPacket, PacketRegistry, UnknownTypePolicy};
+use crate::protocol::wire::TypeRange;
+use crate::protocol::{
+    AddressReport, ErrorCode, ErrorMessage, HandshakeFailureCategory, HandshakeMessage, Packet,
+    PacketHeader, PacketType, StreamId, StreamMode,
+};
+
+/// Error responses per second when the driver sets no limit
+const DEFAULT_ERROR_LIMIT: u32 = 10;
+
+/// What handling a packet came to, in the order the driver should act
+#[derive(Debug)]
+pub enum EngineEvent {
+    /// A well-formed packet arrived, before the events it causes
+    Received { header: PacketHeader, size: usize },
+    /// Bytes were refused: unparseable, or data over the rate limit
+    Rejected(LostLoveError),
+    /// Tunnel data within the rate limit
+    Data(Packet),
+    /// Routes, peer signals and key updates for the server's subsystems
+    Control(Packet),
+    /// Send this packet to the peer
+    Transmit(Packet),
+    /// A registered extension packet was handled; send `reply` if any
+    Extension {
+        received: ExtensionPacket,
+        reply: Option<ExtensionPacket>,
+    },
+    /// The peer ended the session; the final Ack comes before this
+    Closed,
+}
+
+/// Per-session packet handling without I/O
+///
+/// Transports frame packets and hand each one to `handle`, then carry out
+/// the returned events: TCP, UDP and the test simulation all run the same
+/// decisions, and fuzzers can feed it bytes directly. Anything needing the
+/// session's async state or another subsystem comes back as an event.
+pub struct ProtocolEngine {
+    peer_address: SocketAddr,
+    transport: Transport,
+    throttle: ErrorThrottle,
+    bucket: Option<SharedBucket>,
+    registry: Arc<PacketRegistry>,
+    /// Streams opened for datagram delivery
+    datagram_streams: HashSet<u16>,
+}
+
+impl ProtocolEngine {
+    /// Engine for a peer reaching the server over `transport`
+    pub fn new(peer_address: SocketAddr, transport: Transport) -> Self {
+        Self {
+            peer_address,
+            transport,
+            throttle: ErrorThrottle::new(DEFAULT_ERROR_LIMIT),
+            bucket: None,
+            registry: Arc::new(PacketRegistry::default()),
+            datagram_streams: HashSet::new(),
+        }
+    }
+
+    /// Answer at most `per_second` refused packets with an Error, 0 for none
+    pub fn with_error_limit(mut self, per_second: u32) -> Self {
+        self.throttle = ErrorThrottle::new(per_second);
+        self
+    }
+
+    /// Drop data beyond what the user's bucket allows
+    pub fn with_rate_limit(mut self, bucket: Option<SharedBucket>) -> Self {
+        self.bucket = bucket;
+        self
+    }
+
+    /// Handle extension packet types with these handlers
+    pub fn with_registry(mut self, registry: Arc<PacketRegistry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Deliver a stream's data as its client opened it
+    pub fn set_stream_mode(&mut self, stream: StreamId, mode: StreamMode) {
+        match mode {
+            StreamMode::Datagram if !stream.is_control() => self.datagram_streams.insert(stream.value()),
+            _ => self.datagram_streams.remove(&stream.value()),
+        };
+    }
+
+    /// Whether data on a stream is acknowledged; over UDP all tunnel data
+    /// is datagrams
+    fn acknowledges(&self, stream_id: u16) -> bool {
+        self.transport != Transport::Udp && !self.datagram_streams.contains(&stream_id)
+    }
+
+    /// Handle one packet's bytes
+    pub fn handle(&mut self, bytes: &[u8]) -> Vec<EngineEvent> {
+        if let Some(&packet_type) = bytes.get(2) {
+            if matches!(TypeRange::of(packet_type), TypeRange::Extension | TypeRange::Experimental) {
+                return self.handle_extension(packet_type, bytes);
+            }
+        }
+
+        let packet = match Packet::deserialize(bytes) {
+            Ok(packet) => packet,
+            Err(e) => return self.reject(e, 0),
+        };
+
+        let mut events = vec![EngineEvent::Received {
+            header: packet.header.clone(),
+            size: packet.size(),
+        }];
+
+        match packet.header.packet_type {
+            PacketType::Data => {
+                let over_limit = self
+                    .bucket
+                    .as_ref()
+                    .is_some_and(|bucket| !bucket.lock().unwrap().try_consume(packet.size() as u64));
+                if over_limit {
+                    let sequence_number = packet.header.sequence_number;
+                    events.extend(self.reject(LostLoveError::RateLimited, sequence_number));
+                    return events;
+                }
+
+                let acknowledged = self.acknowledges(packet.header.stream_id);
+                events.push(EngineEvent::Data(packet));
+                // For Phase 1: just acknowledge reliable streams
+                if acknowledged {
+                    events.push(EngineEvent::Transmit(Packet::new(PacketType::Ack, Bytes::new())));
+                }
+            }
+            PacketType::KeepAlive => {
+                events.push(EngineEvent::Transmit(Packet::new(PacketType::KeepAlive, Bytes::new())));
+            }
+            PacketType::AddressDiscovery => {
+                // Public address of the path the packet came over
+                let report = AddressReport::new(self.peer_address, self.transport);
+                match report.to_bytes() {
+                    Ok(payload) => {
+                        events.push(EngineEvent::Transmit(Packet::new(PacketType::AddressDiscovery, payload)))
+                    }
+                    Err(e) => debug!("Failed to encode address report: {}", e),
+                }
+            }
+            PacketType::RouteAnnounce
+            | PacketType::PeerSignal
+            | PacketType::KeyUpdate
+            | PacketType::QualityReport
+            | PacketType::PathProbe
+            | PacketType::StreamOpen => {
+                events.push(EngineEvent::Control(packet));
+            }
+            PacketType::Disconnect => {
+                // A final Ack tells the peer its Disconnect arrived
+                let ack = Packet::new_with_metadata(
+                    PacketType::Ack,
+                    packet.header.stream_id,
+                    packet.header.sequence_number,
+                    Bytes::new(),
+                );
+                events.push(EngineEvent::Transmit(ack));
+                events.push(EngineEvent::Closed);
+            }
+            other => debug!("Unhandled packet type: {:?}", other),
+        }
+        events
+    }
+
+    /// Dispatch a packet outside the core types to its registered handler
+    fn handle_extension(&mut self, packet_type: u8, bytes: &[u8]) -> Vec<EngineEvent> {
+        let Some(handler) = self.registry.handler(packet_type).cloned() else {
+            let error = LostLoveError::UnsupportedPacketType(packet_type);
+            return match self.registry.unknown_policy() {
+                UnknownTypePolicy::Drop => vec![EngineEvent::Rejected(error)],
+                UnknownTypePolicy::Error => self.reject(error, 0),
+            };
+        };
+
+        let received = match ExtensionPacket::deserialize(bytes) {
+            Ok(packet) => packet,
+            Err(e) => return self.reject(e, 0),
+        };
+        let sequence_number = received.sequence_number;
+        match handler.handle(&received) {
+            Ok(reply) => vec![EngineEvent::Extension { received, reply }],
+            Err(e) => self.reject(e, sequence_number),
+        }
+    }
+
+    /// Refuse a packet, answering with an Error while the throttle allows
+    fn reject(&mut self, error: LostLoveError, sequence_number: u64) -> Vec<EngineEvent> {
+        let answer = ErrorCode::for_error(&error)
+            .filter(|_| self.throttle.allow())
+            .map(|code| ErrorMessage::new(code, sequence_number))
+            .map(|message| EngineEvent::Transmit(Packet::new(PacketType::Error, message.to_bytes())));
+
+        let mut events = vec![EngineEvent::Rejected(error)];
+        events.extend(answer);
+        events
+    }
+}
+
+/// How answering a ClientHello went
+#[derive(Debug)]
+pub struct HelloOutcome {
+    /// HandshakeResponse for the client: the ServerHello or why not
+    pub response: Packet,
+    /// Set when the handshake was refused; the response tells the client
+    pub rejected: Option<LostLoveError>,
+}
+
+/// Why a new client is sent away before its hello is answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    /// The server is draining, with where it points clients if anywhere
+    Draining(Option<String>),
+    /// A less loaded fleet node at this address takes the client
+    Steered(String),
+}
+
+/// Answer a HandshakeInit packet on the server side of `handshake`
+///
+/// A `redirect` refuses the hello and tells the client where to go instead.
+/// On success the driver confirms the keys once the response went out.
+/// Errors are for packets not worth an answer.
+pub fn answer_hello(
+    handshake: &mut Handshake,
+    packet: &Packet,
+    redirect: Option<Redirect>,
+) -> Result<HelloOutcome> {
+    match redirect {
+        Some(Redirect::Draining(retry_at)) => {
+            let failure = HandshakeMessage::draining(retry_at, handshake.cipher_suites());
+            let error = LostLoveError::HandshakeRejected {
+                category: HandshakeFailureCategory::ServerDraining,
+                reason: "Server is draining".to_string(),
+            };
+            return refusal(failure, error);
+        }
+        Some(Redirect::Steered(retry_at)) => {
+            let error = LostLoveError::HandshakeRejected {
+                category: HandshakeFailureCategory::ServerSteered,
+                reason: format!("Steered to {}", retry_at),
+            };
+            let failure = HandshakeMessage::steered(retry_at, handshake.cipher_suites());
+            return refusal(failure, error);
+        }
+        None => {}
+    }
+
+    let server_hello = if packet.header.packet_type != PacketType::HandshakeInit {
+        Err(LostLoveError::HandshakeRejected {
+            category: HandshakeFailureCategory::UnexpectedMessage,
+            reason: "Expected HandshakeInit packet".to_string(),
+        })
+    } else {
+        let client_hello = HandshakeMessage::from_bytes(&packet.payload)?;
+        handshake.process_client_hello(&client_hello)
+    };
+
+    match server_hello {
+        Ok(server_hello) => Ok(HelloOutcome {
+            response: Packet::new(PacketType::HandshakeResponse, server_hello.to_bytes()?),
+            rejected: None,
+        }),
+        Err(LostLoveError::HandshakeRejected { category, reason }) => {
+            // Tell the client why, so it can retry with something we support
+            let failure = HandshakeMessage::failure(category, reason.clone(), handshake.cipher_suites());
+            refusal(failure, LostLoveError::HandshakeRejected { category, reason })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn refusal(failure: HandshakeMessage, error: LostLoveError) -> Result<HelloOutcome> {
+    Ok(HelloOutcome {
+        response: Packet::new(PacketType::HandshakeResponse, failure.to_bytes()?),
+        rejected: Some(error),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::HEADER_SIZE;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::{Arc, Mutex};
+
+    use crate::config::RateClass;
+    use crate::core::rate_limiter::TokenBucket;
+    use crate::protocol::registry::PacketHandler;
+
+    const PEER: &str = "198.51.100.4:40000";
+
+    fn engine() -> ProtocolEngine {
+        ProtocolEngine::new(PEER.parse().unwrap(), Transport::Tcp)
+    }
+
+    fn bytes(packet_type: PacketType, payload: &'static [u8]) -> Vec<u8> {
+        Packet::new(packet_type, Bytes::from_static(payload)).serialize().to_vec()
+    }
+
+    fn transmitted(events: &[EngineEvent]) -> Vec<PacketType> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                EngineEvent::Transmit(packet) => Some(packet.header.packet_type),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_data_and_keepalive() {
+        let mut engine = engine();
+
+        let events = engine.handle(&bytes(PacketType::Data, b""));
+        assert!(matches!(events[0], EngineEvent::Received { size: HEADER_SIZE, .. }));
+        assert!(matches!(events[1], EngineEvent::Data(_)));
+        assert_eq!(transmitted(&events), [PacketType::Ack]);
+
+        let events = engine.handle(&bytes(PacketType::KeepAlive, b""));
+        assert_eq!(transmitted(&events), [PacketType::KeepAlive]);
+
+        let events = engine.handle(&bytes(PacketType::Disconnect, b""));
+        assert_eq!(transmitted(&events), [PacketType::Ack]);
+        assert!(matches!(events.last(), Some(EngineEvent::Closed)));
+    }
+
+    #[test]
+    fn test_datagram_data_not_acknowledged() {
+        let data = |stream_id| {
+            Packet::new_with_metadata(PacketType::Data, stream_id, 1, Bytes::from_static(b"ip packet"))
+                .serialize()
+                .to_vec()
+        };
+
+        let mut engine = engine();
+        engine.set_stream_mode(StreamId::new(3), StreamMode::Datagram);
+        assert!(transmitted(&engine.handle(&data(3))).is_empty());
+        assert_eq!(transmitted(&engine.handle(&data(4))), [PacketType::Ack]);
+        // The control stream stays reliable
+        engine.set_stream_mode(StreamId::CONTROL, StreamMode::Datagram);
+        assert_eq!(transmitted(&engine.handle(&data(0))), [PacketType::Ack]);
+
+        engine.set_stream_mode(StreamId::new(3), StreamMode::Reliable);
+        assert_eq!(transmitted(&engine.handle(&data(3))), [PacketType::Ack]);
+
+        // Tunnel data over UDP is always datagrams
+        let mut engine = ProtocolEngine::new(PEER.parse().unwrap(), Transport::Udp);
+        let events = engine.handle(&data(4));
+        assert!(matches!(events[1], EngineEvent::Data(_)));
+        assert!(transmitted(&events).is_empty());
+    }
+
+    #[test]
+    fn test_address_discovery_reports_transport() {
+        let mut engine = ProtocolEngine::new(PEER.parse().unwrap(), Transport::Udp);
+        let events = engine.handle(&bytes(PacketType::AddressDiscovery, b""));
+
+        let Some(EngineEvent::Transmit(packet)) = events.last() else {
+            panic!("no answer: {:?}", events);
+        };
+        let report = AddressReport::from_bytes(&packet.payload).unwrap();
+        assert_eq!(report, AddressReport::new(PEER.parse().unwrap(), Transport::Udp));
+    }
+
+    #[test]
+    fn test_control_packets_passed_on() {
+        let mut engine = engine();
+        for packet_type in [
+            PacketType::RouteAnnounce,
+            PacketType::PeerSignal,
+            PacketType::KeyUpdate,
+            PacketType::QualityReport,
+            PacketType::PathProbe,
+            PacketType::StreamOpen,
+        ] {
+            let events = engine.handle(&bytes(packet_type, b"{}"));
+            assert!(matches!(&events[1], EngineEvent::Control(packet) if packet.header.packet_type == packet_type));
+        }
+    }
+
+    #[test]
+    fn test_errors_answered_within_limit() {
+        let mut engine = engine().with_error_limit(2);
+        let mut garbage = bytes(PacketType::KeepAlive, b"");
+        garbage[HEADER_SIZE - 1] ^= 0xFF;
+
+        let answered: Vec<usize> = (0..5).map(|_| transmitted(&engine.handle(&garbage)).len()).collect();
+        assert_eq!(answered, [1, 1, 0, 0, 0]);
+        assert!(matches!(engine.handle(&garbage)[0], EngineEvent::Rejected(LostLoveError::ChecksumMismatch { .. })));
+
+        let mut silent = self::engine().with_error_limit(0);
+        assert!(transmitted(&silent.handle(&garbage)).is_empty());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let bucket = Arc::new(Mutex::new(TokenBucket::new(RateClass { rate: 1, burst: Some(40) })));
+        let mut engine = engine().with_rate_limit(Some(bucket));
+
+        let events = engine.handle(&bytes(PacketType::Data, b"0123456789"));
+        assert!(matches!(events[1], EngineEvent::Data(_)));
+
+        let events = engine.handle(&bytes(PacketType::Data, b"0123456789"));
+        assert!(matches!(events[1], EngineEvent::Rejected(LostLoveError::RateLimited)));
+        assert_eq!(transmitted(&events), [PacketType::Error]);
+    }
+
+    #[test]
+    fn test_random_bytes_never_panic() {
+        let mut rng = StdRng::seed_from_u64(196);
+        let mut engine = engine();
+        let valid = bytes(PacketType::KeyUpdate, br#"{"epoch":1}"#);
+
+        for _ in 0..20_000 {
+            let input: Vec<u8> = if rng.gen_bool(0.5) {
+                (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect()
+            } else {
+                // Mostly valid packets with a few bytes changed
+                let mut input = valid.clone();
+                for _ in 0..rng.gen_range(1..4) {
+                    let index = rng.gen_range(0..input.len());
+                    input[index] = rng.gen();
+                }
+                input
+            };
+            let events = engine.handle(&input);
+            assert!(!events.is_empty());
+        }
+    }
+
+    struct Echo;
+
+    impl PacketHandler for Echo {
+        fn handle(&self, packet: &ExtensionPacket) -> Result<Option<ExtensionPacket>> {
+            if packet.payload.is_empty() {
+                return Err(LostLoveError::MalformedInnerPacket("empty probe".to_string()));
+            }
+            Ok(Some(ExtensionPacket::new(packet.packet_type, packet.payload.clone())))
+        }
+    }
+
+    #[test]
+    fn test_extension_dispatch() {
+        let mut registry = PacketRegistry::new(UnknownTypePolicy::Drop);
+        registry.register(0x40, "echo", Arc::new(Echo)).unwrap();
+        let mut engine = engine().with_registry(Arc::new(registry));
+
+        let probe = ExtensionPacket::new(0x40, Bytes::from_static(b"ping")).serialize();
+        match &engine.handle(&probe)[..] {
+            [EngineEvent::Extension { received, reply: Some(reply) }] => {
+                assert_eq!(received.payload, reply.payload);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Handler errors are answered like any other refused packet
+        let empty = ExtensionPacket::new(0x40, Bytes::new()).serialize();
+        assert_eq!(transmitted(&engine.handle(&empty)), [PacketType::Error]);
+
+        // Unregistered types are dropped silently by default
+        let unknown = ExtensionPacket::new(0xC7, Bytes::new()).serialize();
+        let events = engine.handle(&unknown);
+        assert!(matches!(events[..], [EngineEvent::Rejected(LostLoveError::UnsupportedPacketType(0xC7))]));
+    }
+
+    #[test]
+    fn test_unknown_type_error_policy() {
+        let registry = Arc::new(PacketRegistry::new(UnknownTypePolicy::Error));
+        let mut engine = engine().with_registry(registry);
+
+        let events = engine.handle(&ExtensionPacket::new(0x41, Bytes::new()).serialize());
+        let Some(EngineEvent::Transmit(packet)) = events.last() else {
+            panic!("no answer: {:?}", events);
+        };
+        let message = ErrorMessage::from_bytes(&packet.payload).unwrap();
+        assert_eq!(message.code, ErrorCode::UnsupportedPacketType);
+
+        // Core-range types without a PacketType stay invalid, not unsupported
+        let mut core = bytes(PacketType::KeepAlive, b"");
+        core[2] = 0x3F;
+        assert!(matches!(engine.handle(&core)[0], EngineEvent::Rejected(LostLoveError::InvalidPacketType(0x3F))));
+    }
+
+    #[test]
+    fn test_answer_hello() {
+        let client_hello = {
+            let mut client = Handshake::new_client();
+            let hello = client.generate_client_hello().unwrap();
+            Packet::new(PacketType::HandshakeInit, hello.to_bytes().unwrap())
+        };
+
+        let mut server = Handshake::new_server();
+        let outcome = answer_hello(&mut server, &client_hello, None).unwrap();
+        assert!(outcome.rejected.is_none());
+        assert!(matches!(
+            HandshakeMessage::from_bytes(&outcome.response.payload).unwrap(),
+            HandshakeMessage::ServerHello { .. }
+        ));
+
+        let mut draining = Handshake::new_server();
+        let outcome =
+            answer_hello(&mut draining, &client_hello, Some(Redirect::Draining(Some("203.0.113.5:8443".into())))).unwrap();
+        assert!(matches!(
+            outcome.rejected,
+            Some(LostLoveError::HandshakeRejected { category: HandshakeFailureCategory::ServerDraining, .. })
+        ));
+        match HandshakeMessage::from_bytes(&outcome.response.payload).unwrap() {
+            HandshakeMessage::Failure { retry_at, .. } => assert_eq!(retry_at.as_deref(), Some("203.0.113.5:8443")),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let outcome =
+            answer_hello(&mut Handshake::new_server(), &client_hello, Some(Redirect::Steered("fra-2:8443".into()))).unwrap();
+        assert!(matches!(
+            outcome.rejected,
+            Some(LostLoveError::HandshakeRejected { category: HandshakeFailureCategory::ServerSteered, .. })
+        ));
+
+        let keepalive = Packet::new(PacketType::KeepAlive, Bytes::new());
+        let outcome = answer_hello(&mut Handshake::new_server(), &keepalive, None).unwrap();
+        assert!(matches!(
+            outcome.rejected,
+            Some(LostLoveError::HandshakeRejected { category: HandshakeFailureCategory::UnexpectedMessage, .. })
+        ));
+
+        let junk = Packet::new(PacketType::HandshakeInit, Bytes::from_static(b"junk"));
+        assert!(answer_hello(&mut Handshake::new_server(), &junk, None).is_err());
+    }
+}
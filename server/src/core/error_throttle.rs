@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+/// Limits how many error responses a connection may trigger per second
+///
+/// Error packets are sent in reply to garbage from the peer, so without a cap
+/// a spoofed or misbehaving client could use the server as an amplifier.
+#[derive(Debug)]
+pub struct ErrorThrottle {
+    max_per_second: u32,
+    window_start: Instant,
+    sent: u32,
+}
+
+impl ErrorThrottle {
+    /// Create new throttle; 0 disables error responses entirely
+    pub fn new(max_per_second: u32) -> Self {
+        Self {
+            max_per_second,
+            window_start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Check whether another error response may be sent now
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.sent = 0;
+        }
+
+        if self.sent < self.max_per_second {
+            self.sent += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle_limit() {
+        let mut throttle = ErrorThrottle::new(3);
+        let now = Instant::now();
+
+        assert!(throttle.allow_at(now));
+        assert!(throttle.allow_at(now));
+        assert!(throttle.allow_at(now));
+        assert!(!throttle.allow_at(now));
+
+        // A new window resets the budget
+        assert!(throttle.allow_at(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_throttle_disabled() {
+        let mut throttle = ErrorThrottle::new(0);
+        assert!(!throttle.allow());
+    }
+}
@@ -0,0 +1,493 @@
+use hkdf::hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::core::accounting::{Accounting, UserUsage};
+use crate::crypto::ct;
+use crate::crypto::rng::{self, SystemRandom};
+use crate::error::{LostLoveError, Result};
+use crate::network::ip_net::IpNet;
+#[cfg(target_os = "linux")]
+use crate::network::netlink::Netlink;
+
+/// Size of each side's connection nonce
+const NONCE_SIZE: usize = 32;
+
+/// HMAC-SHA256 tag of the standby's proof and of every frame
+const TAG_SIZE: usize = 32;
+
+/// Largest replicated state accepted
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Domain separation of the standby's proof from frame tags
+const PROOF_LABEL: &[u8] = b"llp-failover standby";
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size")
+}
+
+/// Which side of the pair this server starts as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Active,
+    Standby,
+}
+
+/// Where this server stands in the pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverState {
+    /// Holding the virtual IP and replicating to the standby
+    Active,
+    /// Following the active, `synced` once state arrived from it
+    Standby { synced: bool },
+    /// Standby that took over after the active went silent
+    TookOver,
+}
+
+/// State the active replicates on every update
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replica {
+    /// Per-user traffic in the current day and month
+    pub usage: BTreeMap<String, UserUsage>,
+}
+
+/// Authenticated frames of one replication connection
+///
+/// Tags cover both nonces and a sequence number, so frames can't be
+/// replayed into another connection or reordered within one.
+struct Channel {
+    secret: Vec<u8>,
+    nonces: [u8; 2 * NONCE_SIZE],
+    sequence: u64,
+}
+
+impl Channel {
+    fn new(secret: &[u8], active_nonce: &[u8; NONCE_SIZE], standby_nonce: &[u8; NONCE_SIZE]) -> Self {
+        let mut nonces = [0u8; 2 * NONCE_SIZE];
+        nonces[..NONCE_SIZE].copy_from_slice(active_nonce);
+        nonces[NONCE_SIZE..].copy_from_slice(standby_nonce);
+        Self {
+            secret: secret.to_vec(),
+            nonces,
+            sequence: 0,
+        }
+    }
+
+    fn tag(&self, payload: &[u8]) -> [u8; TAG_SIZE] {
+        let mut mac = mac(&self.secret);
+        mac.update(&self.nonces);
+        mac.update(&self.sequence.to_be_bytes());
+        mac.update(payload);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Length, payload and tag of the next frame
+    fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(4 + payload.len() + TAG_SIZE);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&self.tag(payload));
+        self.sequence += 1;
+        frame
+    }
+
+    /// Check the tag of the next frame
+    fn open(&mut self, payload: &[u8], tag: &[u8]) -> Result<()> {
+        if !ct::eq(&self.tag(payload), tag) {
+            return Err(LostLoveError::AuthFailed {
+                reason: "replication frame tag mismatch".to_string(),
+            });
+        }
+        self.sequence += 1;
+        Ok(())
+    }
+
+    async fn receive(&mut self, stream: &mut TcpStream) -> Result<Vec<u8>> {
+        let length = stream.read_u32().await? as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(LostLoveError::Network(format!("Replication frame of {} bytes", length)));
+        }
+        let mut frame = vec![0u8; length + TAG_SIZE];
+        stream.read_exact(&mut frame).await?;
+        let (payload, tag) = frame.split_at(length);
+        self.open(payload, tag)?;
+        frame.truncate(length);
+        Ok(frame)
+    }
+}
+
+/// Proof the standby knows the shared secret
+fn proof(secret: &[u8], active_nonce: &[u8], standby_nonce: &[u8]) -> [u8; TAG_SIZE] {
+    let mut mac = mac(secret);
+    mac.update(PROOF_LABEL);
+    mac.update(active_nonce);
+    mac.update(standby_nonce);
+    mac.finalize().into_bytes().into()
+}
+
+/// Hot-standby pair with state replication
+///
+/// The active holds the virtual IP clients connect to and sends its state
+/// to the standby every `interval`; the updates double as heartbeats. Once
+/// the standby misses `missed_updates` of them it adds the virtual IP and
+/// serves the clients, who reconnect with a new handshake. A standby that
+/// never heard from the active doesn't take over, so a mistyped address
+/// can't start a second active. There is no failback: after a takeover the
+/// old active has to come back as the standby.
+///
+/// Only accounting usage is replicated: tunnel leases belong to session IDs
+/// that don't survive the move, and there are no session tickets yet.
+pub struct Failover {
+    role: Role,
+    address: String,
+    secret: Vec<u8>,
+    virtual_ip: Option<(IpNet, String)>,
+    interval: Duration,
+    missed_updates: u32,
+    accounting: Arc<Accounting>,
+    state: watch::Sender<FailoverState>,
+    /// Whether this server added the virtual IP
+    claimed: AtomicBool,
+}
+
+impl Failover {
+    /// Create from config, None when failover is disabled
+    pub fn new(config: &Config, accounting: Arc<Accounting>) -> Result<Option<Self>> {
+        let failover = &config.failover;
+        if !failover.enabled {
+            return Ok(None);
+        }
+
+        let role = if failover.role == "standby" { Role::Standby } else { Role::Active };
+        let virtual_ip = match (&failover.virtual_ip, &failover.interface) {
+            (Some(address), Some(interface)) => Some((address.parse::<IpNet>()?, interface.clone())),
+            _ => None,
+        };
+        let state = match role {
+            Role::Active => FailoverState::Active,
+            Role::Standby => FailoverState::Standby { synced: false },
+        };
+
+        Ok(Some(Self {
+            role,
+            address: failover.address.clone(),
+            secret: failover.secret.as_bytes().to_vec(),
+            virtual_ip,
+            interval: Duration::from_secs(failover.interval),
+            missed_updates: failover.missed_updates,
+            accounting,
+            state: watch::channel(state).0,
+            claimed: AtomicBool::new(false),
+        }))
+    }
+
+    pub(crate) fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn state(&self) -> FailoverState {
+        *self.state.borrow()
+    }
+
+    /// Notified when the standby syncs or takes over
+    pub fn subscribe(&self) -> watch::Receiver<FailoverState> {
+        self.state.subscribe()
+    }
+
+    /// Replicate (active) or follow and take over when needed (standby)
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        match self.role {
+            Role::Active => {
+                self.claim_virtual_ip()?;
+                let listener = TcpListener::bind(&self.address).await?;
+                info!("Replicating state to the standby server on {}", self.address);
+                self.replicate_to(listener).await
+            }
+            Role::Standby => {
+                self.follow().await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Time without updates after which the standby takes over
+    fn deadline(&self) -> Duration {
+        self.interval * self.missed_updates
+    }
+
+    fn replica(&self) -> Replica {
+        Replica {
+            usage: self.accounting.snapshot(),
+        }
+    }
+
+    /// Serve standbys connecting to `listener`, each gets every update
+    async fn replicate_to(self: &Arc<Self>, listener: TcpListener) -> Result<()> {
+        // Replication stops with the listener, like it would if the process died
+        let mut standbys = JoinSet::new();
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let failover = self.clone();
+            standbys.spawn(async move {
+                match failover.replicate(stream).await {
+                    Ok(()) => debug!("Standby {} disconnected", peer),
+                    Err(e) => warn!(code = e.code(), "Replication to standby {} ended: {}", peer, e),
+                }
+            });
+            while standbys.try_join_next().is_some() {}
+        }
+    }
+
+    async fn replicate(&self, mut stream: TcpStream) -> Result<()> {
+        let active_nonce = rng::random_32(&SystemRandom);
+        stream.write_all(&active_nonce).await?;
+
+        let mut hello = [0u8; NONCE_SIZE + TAG_SIZE];
+        time::timeout(self.deadline(), stream.read_exact(&mut hello))
+            .await
+            .map_err(|_| LostLoveError::Network("Standby did not authenticate in time".to_string()))??;
+        let (standby_nonce, standby_proof) = hello.split_at(NONCE_SIZE);
+        if !ct::eq(&proof(&self.secret, &active_nonce, standby_nonce), standby_proof) {
+            return Err(LostLoveError::AuthFailed {
+                reason: "standby does not know the failover secret".to_string(),
+            });
+        }
+        info!("Standby {} connected", stream.peer_addr()?);
+
+        let mut channel = Channel::new(&self.secret, &active_nonce, standby_nonce.try_into().unwrap());
+        let mut updates = time::interval(self.interval);
+        loop {
+            updates.tick().await;
+            let payload = serde_json::to_vec(&self.replica())
+                .map_err(|e| LostLoveError::Network(format!("Failed to encode replica: {}", e)))?;
+            stream.write_all(&channel.seal(&payload)).await?;
+        }
+    }
+
+    /// Apply updates from the active until it stays silent, then take over
+    async fn follow(&self) {
+        let mut last_update = None;
+        loop {
+            if let Err(e) = self.sync(&mut last_update).await {
+                debug!("Replication from {} interrupted: {}", self.address, e);
+            }
+
+            if let Some(last) = last_update {
+                if last.elapsed() >= self.deadline() {
+                    warn!(
+                        "No update from the active server for {:?}, taking over",
+                        last.elapsed()
+                    );
+                    if let Err(e) = self.claim_virtual_ip() {
+                        warn!(code = e.code(), "Failed to take over the virtual IP: {}", e);
+                        time::sleep(self.interval).await;
+                        continue;
+                    }
+                    self.state.send_replace(FailoverState::TookOver);
+                    return;
+                }
+            }
+            time::sleep(self.interval).await;
+        }
+    }
+
+    /// One connection to the active, returns once it fails or goes quiet
+    async fn sync(&self, last_update: &mut Option<Instant>) -> Result<()> {
+        let timeout = |last: &Option<Instant>| match last {
+            Some(last) => self.deadline().saturating_sub(last.elapsed()),
+            None => self.deadline(),
+        };
+        let quiet = || LostLoveError::Network("No update from the active server".to_string());
+
+        let mut stream = time::timeout(timeout(last_update), TcpStream::connect(&self.address))
+            .await
+            .map_err(|_| quiet())??;
+        let mut active_nonce = [0u8; NONCE_SIZE];
+        time::timeout(timeout(last_update), stream.read_exact(&mut active_nonce))
+            .await
+            .map_err(|_| quiet())??;
+
+        let standby_nonce = rng::random_32(&SystemRandom);
+        let mut hello = standby_nonce.to_vec();
+        hello.extend_from_slice(&proof(&self.secret, &active_nonce, &standby_nonce));
+        stream.write_all(&hello).await?;
+
+        let mut channel = Channel::new(&self.secret, &active_nonce, &standby_nonce);
+        loop {
+            let payload = time::timeout(timeout(last_update), channel.receive(&mut stream))
+                .await
+                .map_err(|_| quiet())??;
+            let replica: Replica = serde_json::from_slice(&payload)
+                .map_err(|e| LostLoveError::Network(format!("Invalid replica: {}", e)))?;
+
+            self.accounting.restore(replica.usage);
+            *last_update = Some(Instant::now());
+            self.state.send_if_modified(|state| {
+                let first = *state == FailoverState::Standby { synced: false };
+                if first {
+                    info!("Synced with the active server at {}", self.address);
+                    *state = FailoverState::Standby { synced: true };
+                }
+                first
+            });
+        }
+    }
+
+    /// Add the virtual IP to its interface
+    fn claim_virtual_ip(&self) -> Result<()> {
+        let Some((address, interface)) = &self.virtual_ip else {
+            return Ok(());
+        };
+
+        #[cfg(target_os = "linux")]
+        match Netlink::open()?.add_address(interface, *address) {
+            Ok(()) => self.claimed.store(true, Ordering::Relaxed),
+            Err(LostLoveError::Netlink { errno: libc::EEXIST, .. }) => {}
+            Err(e) => return Err(e),
+        }
+        info!("Holding virtual IP {} on {}", address, interface);
+        Ok(())
+    }
+}
+
+impl Drop for Failover {
+    fn drop(&mut self) {
+        // A clean stop hands the address to the standby without waiting for
+        // its neighbours' ARP entries to expire on two holders
+        #[cfg(target_os = "linux")]
+        if let (true, Some((address, interface))) = (self.claimed.load(Ordering::Relaxed), &self.virtual_ip) {
+            if let Err(e) = Netlink::open().and_then(|netlink| netlink.delete_address(interface, *address)) {
+                warn!("Failed to remove virtual IP {} from {}: {}", address, interface, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failover(role: &str, address: &str, secret: &str) -> (Arc<Failover>, Arc<Accounting>) {
+        let mut config = Config::default_for_testing();
+        config.failover.enabled = true;
+        config.failover.role = role.to_string();
+        config.failover.address = address.to_string();
+        config.failover.secret = secret.to_string();
+        config.policies.insert("alice".to_string(), crate::config::UserPolicy::default());
+
+        let accounting = Arc::new(Accounting::new(&config));
+        let failover = Failover::new(&config, accounting.clone())
+            .unwrap()
+            .unwrap()
+            .with_interval(Duration::from_millis(20));
+        (Arc::new(failover), accounting)
+    }
+
+    async fn wait_for(failover: &Failover, state: FailoverState) {
+        let mut states = failover.subscribe();
+        time::timeout(Duration::from_secs(5), states.wait_for(|current| *current == state))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_channel_rejects_tampering() {
+        let (active_nonce, standby_nonce) = ([1u8; NONCE_SIZE], [2u8; NONCE_SIZE]);
+        let mut sender = Channel::new(b"secret", &active_nonce, &standby_nonce);
+        let mut receiver = Channel::new(b"secret", &active_nonce, &standby_nonce);
+
+        let first = sender.seal(b"first");
+        let second = sender.seal(b"second");
+        let split = |frame: &[u8]| {
+            let (payload, tag) = frame[4..].split_at(frame.len() - 4 - TAG_SIZE);
+            (payload.to_vec(), tag.to_vec())
+        };
+
+        let (payload, tag) = split(&first);
+        receiver.open(&payload, &tag).unwrap();
+        // Replaying the first frame in place of the second
+        assert!(receiver.open(&payload, &tag).is_err());
+
+        let (mut payload, tag) = split(&second);
+        payload[0] ^= 1;
+        assert!(receiver.open(&payload, &tag).is_err());
+
+        // Another connection's nonces give other tags
+        let mut other = Channel::new(b"secret", &standby_nonce, &active_nonce);
+        let (payload, tag) = split(&first);
+        assert!(other.open(&payload, &tag).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replication_and_takeover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (active, active_accounting) = failover("active", &address, "shared");
+        let (standby, standby_accounting) = failover("standby", &address, "shared");
+        active_accounting.record("alice", 5_000);
+
+        let replicating = tokio::spawn({
+            let active = active.clone();
+            async move { active.replicate_to(listener).await }
+        });
+        let following = tokio::spawn(standby.clone().run());
+
+        wait_for(&standby, FailoverState::Standby { synced: true }).await;
+        assert_eq!(standby_accounting.usage("alice").day_bytes, 5_000);
+
+        // Counters keep following the active
+        active_accounting.record("alice", 1_000);
+        time::timeout(Duration::from_secs(5), async {
+            while standby_accounting.usage("alice").day_bytes != 6_000 {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The active dies, the standby takes over within missed_updates updates
+        replicating.abort();
+        wait_for(&standby, FailoverState::TookOver).await;
+        following.await.unwrap().unwrap();
+        assert_eq!(standby_accounting.usage("alice").day_bytes, 6_000);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_secret_never_syncs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (active, active_accounting) = failover("active", &address, "shared");
+        let (standby, standby_accounting) = failover("standby", &address, "guessed");
+        active_accounting.record("alice", 5_000);
+
+        let replicating = tokio::spawn(async move { active.replicate_to(listener).await });
+        let following = tokio::spawn(standby.clone().run());
+
+        time::sleep(Duration::from_millis(200)).await;
+        // Never synced, so the rejected standby doesn't take over either
+        assert_eq!(standby.state(), FailoverState::Standby { synced: false });
+        assert!(standby_accounting.snapshot().is_empty());
+
+        replicating.abort();
+        following.abort();
+    }
+}
@@ -0,0 +1,689 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use hkdf::hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::config::{Config, PeerSite};
+use crate::core::server::{read_packet, write_packet};
+use crate::core::session::SessionId;
+use crate::crypto::ct;
+use crate::error::{LostLoveError, Result};
+use crate::network::ip_net::IpNet;
+use crate::network::nat::RuleGuard;
+use crate::network::site_routes::{route_commands, SiteRoutes};
+use crate::protocol::route_announce::{LOCAL_METRIC, MAX_METRIC};
+use crate::protocol::{
+    AnnouncedRoute, Handshake, HandshakeMessage, Packet, PacketType, RouteAnnouncement,
+};
+
+/// How often an idle outgoing link sends a keepalive
+const LINK_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Side of a link a proof comes from, so one side's proof can't be
+/// reflected back as the other's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkSide {
+    /// The site that connected as a client
+    Dialer,
+    /// The site that accepted the connection
+    Listener,
+}
+
+impl LinkSide {
+    fn label(self) -> &'static [u8] {
+        match self {
+            LinkSide::Dialer => b"llp-federation dialer",
+            LinkSide::Listener => b"llp-federation listener",
+        }
+    }
+
+    /// Side of the site at the other end
+    fn peer(self) -> Self {
+        match self {
+            LinkSide::Dialer => LinkSide::Listener,
+            LinkSide::Listener => LinkSide::Dialer,
+        }
+    }
+}
+
+/// Handshake randoms of a link; proofs over them can't be replayed on
+/// another connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkNonces {
+    pub client_random: [u8; 32],
+    pub server_random: [u8; 32],
+}
+
+/// Our end of a link with a peer site
+///
+/// Every announcement on a link carries a counter covered by its proof;
+/// each side numbers what it sends from 0 and only takes announcements
+/// numbered above the last one it accepted, so a proven announcement can't
+/// be replayed on the link.
+#[derive(Debug)]
+pub struct Link {
+    side: LinkSide,
+    nonces: LinkNonces,
+    /// Counter of our next announcement
+    sent: u64,
+    /// Counter of the last announcement accepted from the peer
+    received: Option<u64>,
+}
+
+impl Link {
+    pub fn new(side: LinkSide, nonces: LinkNonces) -> Self {
+        Self {
+            side,
+            nonces,
+            sent: 0,
+            received: None,
+        }
+    }
+}
+
+/// A configured peer site
+struct Peer {
+    name: String,
+    address: Option<String>,
+    secret: Vec<u8>,
+    allowed_prefixes: Vec<IpNet>,
+}
+
+/// Site-to-site mode: links this server with other LLP servers
+///
+/// Each side announces the subnets it reaches with a ROUTE_ANNOUNCE packet
+/// after the handshake and again whenever its route table changes. Own
+/// subnets go out at `LOCAL_METRIC`, learned ones (unless `readvertise` is
+/// off) one hop further, except back to the site they were learned from.
+/// Learned subnets go into the site route table (and kernel routes into the
+/// TUN interface) for as long as the link is up. A peer with an address is
+/// dialed by this server, acting as an ordinary client that connects with
+/// the site name as user; peers without an address dial us.
+///
+/// Anyone can claim a site's name in ClientHello, so every announcement
+/// carries a proof keyed with the secret of that peer and bound to the
+/// link's handshake randoms. Only proven links change the route table, and
+/// only within the subnets the peer is allowed.
+pub struct Federation {
+    site_name: String,
+    announce: Vec<String>,
+    /// `announce` parsed and truncated, for comparing with learned routes
+    own_prefixes: Vec<IpNet>,
+    readvertise: bool,
+    peers: Vec<Peer>,
+    reconnect_interval: Duration,
+    /// TUN interface learned routes point to, None if routes aren't installed
+    tun_name: Option<String>,
+    routes: Arc<SiteRoutes>,
+    /// Installed kernel routes by subnet
+    kernel_routes: DashMap<IpNet, RuleGuard>,
+    /// Bumped whenever the route table changes, links re-announce on it
+    changes: watch::Sender<u64>,
+    /// Session of each peer's proven incoming link
+    links: DashMap<String, SessionId>,
+}
+
+impl Federation {
+    /// Create from config, None when federation is disabled
+    pub fn new(config: &Config) -> Option<Self> {
+        let federation = &config.federation;
+        if !federation.enabled {
+            return None;
+        }
+
+        Some(Self {
+            site_name: federation.site_name.clone(),
+            announce: federation.announce.clone(),
+            // Validated with the config
+            own_prefixes: federation
+                .announce
+                .iter()
+                .filter_map(|prefix| prefix.parse::<IpNet>().ok())
+                .map(|prefix| prefix.trunc())
+                .collect(),
+            readvertise: federation.readvertise,
+            peers: federation.peers.iter().map(Peer::from_config).collect(),
+            reconnect_interval: Duration::from_secs(federation.reconnect_interval),
+            tun_name: federation
+                .install_routes
+                .then(|| config.network.tun_name.clone()),
+            routes: Arc::new(SiteRoutes::new()),
+            kernel_routes: DashMap::new(),
+            changes: watch::channel(0).0,
+            links: DashMap::new(),
+        })
+    }
+
+    /// Subnets learned from peer sites
+    pub fn routes(&self) -> &Arc<SiteRoutes> {
+        &self.routes
+    }
+
+    /// Check if a user name belongs to a configured peer site
+    ///
+    /// The name alone proves nothing, see `authenticate`.
+    pub fn is_peer(&self, name: &str) -> bool {
+        self.peer(name).is_some()
+    }
+
+    fn peer(&self, name: &str) -> Option<&Peer> {
+        self.peers.iter().find(|peer| peer.name == name)
+    }
+
+    /// Proof that an announcement, sent from `side` of a link with `peer`
+    /// whose handshake had `nonces`, comes from a site knowing their secret
+    ///
+    /// Covers the announcing site's name, its counter and its routes, so
+    /// none of them can be changed on the way.
+    fn proof(
+        &self,
+        peer: &str,
+        announcement: &RouteAnnouncement,
+        side: LinkSide,
+        nonces: &LinkNonces,
+    ) -> Result<Option<[u8; 32]>> {
+        let Some(peer) = self.peer(peer) else {
+            return Ok(None);
+        };
+        let routes = serde_json::to_vec(&announcement.routes)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+
+        let mut mac = HmacSha256::new_from_slice(&peer.secret).expect("HMAC takes keys of any size");
+        mac.update(side.label());
+        mac.update(&nonces.client_random);
+        mac.update(&nonces.server_random);
+        mac.update(&announcement.counter.to_be_bytes());
+        mac.update(&(announcement.site.len() as u32).to_be_bytes());
+        mac.update(announcement.site.as_bytes());
+        mac.update(&routes);
+        Ok(Some(mac.finalize().into_bytes().into()))
+    }
+
+    /// Check that an announcement really comes from `peer` at the other end
+    /// of `link` and is newer than the last one taken from it
+    pub fn authenticate(&self, peer: &str, announcement: &RouteAnnouncement, link: &mut Link) -> Result<()> {
+        let expected = self
+            .proof(peer, announcement, link.side.peer(), &link.nonces)?
+            .ok_or_else(|| LostLoveError::Network(format!("{} is not a peer site", peer)))?;
+        let proof = announcement.proof.as_deref().and_then(|proof| hex::decode(proof).ok());
+        if !proof.is_some_and(|proof| ct::eq(&expected, &proof)) {
+            return Err(LostLoveError::AuthFailed {
+                reason: format!("announcement of site {} has no valid proof", peer),
+            });
+        }
+        if link.received.is_some_and(|received| announcement.counter <= received) {
+            return Err(LostLoveError::AuthFailed {
+                reason: format!(
+                    "announcement {} of site {} was already superseded",
+                    announcement.counter, peer
+                ),
+            });
+        }
+        link.received = Some(announcement.counter);
+        Ok(())
+    }
+
+    /// Record `session_id` as the proven incoming link of `peer`
+    pub fn attach(&self, peer: &str, session_id: SessionId) {
+        if self.links.insert(peer.to_string(), session_id) != Some(session_id) {
+            info!("Site {} linked with session {}", peer, session_id);
+        }
+    }
+
+    /// Forget the routes of the sites whose proven link was `session_id`
+    ///
+    /// Sessions that merely claimed a site's name leave its routes alone.
+    pub fn detach(&self, session_id: &SessionId) {
+        let sites: Vec<String> = self
+            .links
+            .iter()
+            .filter(|link| link.value() == session_id)
+            .map(|link| link.key().clone())
+            .collect();
+        for site in sites {
+            if self.links.remove_if(&site, |_, linked| linked == session_id).is_some() {
+                self.forget(&site);
+            }
+        }
+    }
+
+    /// Notified whenever learned routes change
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// Announcement of the subnets reachable through this site, for one peer
+    pub fn announcement_for(&self, peer: &str) -> RouteAnnouncement {
+        let mut routes: Vec<_> = self
+            .announce
+            .iter()
+            .map(|prefix| AnnouncedRoute::new(prefix, LOCAL_METRIC))
+            .collect();
+
+        if self.readvertise {
+            // Split horizon: a route is never announced back where it came from
+            routes.extend(
+                self.routes
+                    .best_routes()
+                    .into_iter()
+                    .filter(|(route, site)| {
+                        site != peer
+                            && route.metric + 1 < MAX_METRIC
+                            && !self.is_own(&route.prefix)
+                    })
+                    .map(|(route, _)| AnnouncedRoute::new(route.prefix, route.metric + 1)),
+            );
+        }
+
+        RouteAnnouncement::new(&self.site_name, routes)
+    }
+
+    /// Take over the routes a peer site announced, replacing earlier ones
+    pub fn learn(&self, peer: &str, announcement: &RouteAnnouncement) -> Result<()> {
+        if announcement.site != peer {
+            return Err(LostLoveError::Network(format!(
+                "Site {} announced routes as {}",
+                peer, announcement.site
+            )));
+        }
+
+        let allowed = self.peer(peer).map(|peer| peer.allowed_prefixes.as_slice()).unwrap_or_default();
+        let routes: Vec<_> = announcement
+            .routes
+            .iter()
+            .filter(|route| {
+                let within = route.prefix.parse::<IpNet>().is_ok_and(|prefix| {
+                    allowed
+                        .iter()
+                        .any(|net| net.contains(prefix.network()) && prefix.prefix_len() >= net.prefix_len())
+                });
+                if !within {
+                    warn!("Ignoring route {} from site {}, outside its allowed prefixes", route.prefix, peer);
+                }
+                within
+            })
+            // Nothing is closer than a site's own subnets, which it
+            // announces at LOCAL_METRIC
+            .filter(|route| {
+                if route.metric < LOCAL_METRIC {
+                    warn!("Ignoring route {} from site {} with metric {}", route.prefix, peer, route.metric);
+                }
+                route.metric >= LOCAL_METRIC
+            })
+            // Our own subnets are never reached through another site
+            .filter(|route| !self.is_own(&route.prefix))
+            .cloned()
+            .collect();
+
+        if self.routes.replace(peer, &routes)? {
+            let summary: Vec<_> = routes
+                .iter()
+                .map(|route| format!("{} ({})", route.prefix, route.metric))
+                .collect();
+            info!("Learned routes from site {}: {}", peer, summary.join(", "));
+            self.routes_changed();
+        }
+
+        Ok(())
+    }
+
+    /// Whether a prefix is one of the subnets this site announces, however
+    /// it is written
+    fn is_own(&self, prefix: &str) -> bool {
+        prefix
+            .parse::<IpNet>()
+            .is_ok_and(|prefix| self.own_prefixes.contains(&prefix.trunc()))
+    }
+
+    /// Drop the routes of a site whose link went down
+    pub fn forget(&self, peer: &str) {
+        if self.routes.remove_site(peer) {
+            info!("Forgot routes of site {}", peer);
+            self.routes_changed();
+        }
+    }
+
+    /// Bring kernel routes in line with the table and notify the links
+    fn routes_changed(&self) {
+        if let Some(tun_name) = &self.tun_name {
+            let prefixes = self.routes.prefixes();
+            self.kernel_routes.retain(|prefix, _| prefixes.contains(prefix));
+
+            for prefix in prefixes {
+                if self.kernel_routes.contains_key(&prefix) {
+                    continue;
+                }
+                match RuleGuard::install(route_commands(std::slice::from_ref(&prefix), tun_name)) {
+                    Ok(guard) => {
+                        self.kernel_routes.insert(prefix, guard);
+                    }
+                    Err(e) => warn!("Failed to install route {}: {}", prefix, e),
+                }
+            }
+        }
+
+        self.changes.send_modify(|version| *version += 1);
+    }
+
+    /// Start outgoing links to every peer with an address
+    pub fn start(self: &Arc<Self>) {
+        for peer in &self.peers {
+            let Some(address) = peer.address.clone() else {
+                continue;
+            };
+
+            let federation = self.clone();
+            let name = peer.name.clone();
+            let span = info_span!("site_link", site = %name);
+
+            tokio::spawn(async move { federation.run_link(&name, &address).await }.instrument(span));
+        }
+    }
+
+    /// Keep a link to a peer up, reconnecting after failures
+    async fn run_link(&self, name: &str, address: &str) {
+        loop {
+            match self.link(name, address).await {
+                Ok(()) => info!("Link to site {} closed", name),
+                Err(e) => warn!(code = e.code(), "Link to site {} at {} failed: {}", name, address, e),
+            }
+
+            self.forget(name);
+            time::sleep(self.reconnect_interval).await;
+        }
+    }
+
+    /// Connect to a peer as a client and exchange routes until the link drops
+    async fn link(&self, name: &str, address: &str) -> Result<()> {
+        let mut stream = TcpStream::connect(address).await?;
+
+        let mut handshake = Handshake::new_client().with_user(&self.site_name);
+        let client_hello = handshake.generate_client_hello()?;
+        write_packet(
+            &mut stream,
+            &Packet::new(PacketType::HandshakeInit, client_hello.to_bytes()?),
+        )
+        .await?;
+
+        let response = read_packet(&mut stream).await?;
+        handshake.process_server_hello(&HandshakeMessage::from_bytes(&response.payload)?)?;
+        let nonces = match (handshake.client_random(), handshake.server_random()) {
+            (Some(client_random), Some(server_random)) => LinkNonces { client_random, server_random },
+            _ => return Err(LostLoveError::HandshakeFailed("Handshake without randoms".to_string())),
+        };
+        info!("Linked to site {} at {}", name, address);
+
+        let mut link = Link::new(LinkSide::Dialer, nonces);
+        let mut changes = self.subscribe();
+        self.announce_to(&mut stream, name, &mut link).await?;
+
+        let mut keepalive = time::interval(LINK_KEEPALIVE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                readable = stream.readable() => readable?,
+                _ = keepalive.tick() => {
+                    write_packet(&mut stream, &Packet::new(PacketType::KeepAlive, Bytes::new())).await?;
+                    continue;
+                }
+                Ok(()) = changes.changed() => {
+                    changes.borrow_and_update();
+                    self.announce_to(&mut stream, name, &mut link).await?;
+                    continue;
+                }
+            }
+
+            let packet = match read_packet(&mut stream).await {
+                Ok(packet) => packet,
+                Err(LostLoveError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            match packet.header.packet_type {
+                PacketType::RouteAnnounce => {
+                    if let Err(e) = RouteAnnouncement::from_bytes(&packet.payload).and_then(|announcement| {
+                        self.authenticate(name, &announcement, &mut link)?;
+                        self.learn(name, &announcement)
+                    }) {
+                        warn!("Rejected routes from site {}: {}", name, e);
+                    }
+                }
+                PacketType::Disconnect => return Ok(()),
+                other => debug!("Ignoring {:?} packet on link to site {}", other, name),
+            }
+        }
+    }
+
+    /// Send our current routes to a peer, proven for our end of `link`
+    pub(crate) async fn announce_to(&self, stream: &mut TcpStream, peer: &str, link: &mut Link) -> Result<usize> {
+        let mut announcement = self.announcement_for(peer).with_counter(link.sent);
+        link.sent += 1;
+        if let Some(proof) = self.proof(peer, &announcement, link.side, &link.nonces)? {
+            announcement = announcement.with_proof(&proof);
+        }
+        let packet = Packet::new(PacketType::RouteAnnounce, announcement.to_bytes()?);
+        write_packet(stream, &packet).await?;
+        Ok(packet.size())
+    }
+}
+
+impl Peer {
+    fn from_config(peer: &PeerSite) -> Self {
+        Self {
+            name: peer.name.clone(),
+            address: peer.address.clone(),
+            secret: peer.secret.as_bytes().to_vec(),
+            // Validated with the config
+            allowed_prefixes: peer.allowed_prefixes.iter().filter_map(|prefix| prefix.parse().ok()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FederationConfig;
+
+    fn route(prefix: &str, metric: u32) -> AnnouncedRoute {
+        AnnouncedRoute::new(prefix, metric)
+    }
+
+    fn test_federation() -> Federation {
+        let mut config = Config::default_for_testing();
+        config.federation = FederationConfig {
+            enabled: true,
+            site_name: "berlin".to_string(),
+            announce: vec!["192.168.10.0/24".to_string()],
+            install_routes: false,
+            peers: vec![
+                PeerSite {
+                    name: "paris".to_string(),
+                    address: None,
+                    secret: "paris shared".to_string(),
+                    allowed_prefixes: vec!["192.168.20.0/24".to_string(), "10.9.0.0/16".to_string()],
+                },
+                PeerSite {
+                    name: "rome".to_string(),
+                    address: None,
+                    secret: "rome shared".to_string(),
+                    allowed_prefixes: vec!["192.168.0.0/16".to_string()],
+                },
+            ],
+            ..FederationConfig::default()
+        };
+        Federation::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(Federation::new(&Config::default_for_testing()).is_none());
+    }
+
+    #[test]
+    fn test_learn_and_forget() {
+        let federation = test_federation();
+        assert!(federation.is_peer("paris"));
+        assert!(!federation.is_peer("alice"));
+
+        let changes = federation.subscribe();
+        let announcement = RouteAnnouncement::new("paris", vec![route("192.168.20.0/24", 1)]);
+        federation.learn("paris", &announcement).unwrap();
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(
+            federation.routes().lookup("192.168.20.7".parse().unwrap()).as_deref(),
+            Some("paris")
+        );
+
+        // A peer can only announce routes for itself
+        let spoofed = RouteAnnouncement::new("rome", vec![route("10.0.0.0/8", 1)]);
+        assert!(federation.learn("paris", &spoofed).is_err());
+
+        // Our own subnet is never learned from a peer, however it's written
+        let own = RouteAnnouncement::new(
+            "rome",
+            vec![route("192.168.10.0/24", 1), route("192.168.10.7/24", 1)],
+        );
+        federation.learn("rome", &own).unwrap();
+        assert!(!federation.routes().snapshot().contains_key("rome"));
+
+        // Nothing beats a site's own subnets
+        let closer = RouteAnnouncement::new("rome", vec![route("192.168.30.0/24", 0)]);
+        federation.learn("rome", &closer).unwrap();
+        assert!(!federation.routes().snapshot().contains_key("rome"));
+
+        federation.forget("paris");
+        assert!(federation.routes().snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_readvertised_routes() {
+        let federation = test_federation();
+        let announcement = RouteAnnouncement::new(
+            "paris",
+            vec![route("192.168.20.0/24", 1), route("10.9.0.0/16", MAX_METRIC - 1)],
+        );
+        federation.learn("paris", &announcement).unwrap();
+
+        // Rome hears about paris one hop further, the far route is unreachable
+        let to_rome = federation.announcement_for("rome");
+        assert_eq!(to_rome.site, "berlin");
+        assert_eq!(
+            to_rome.routes,
+            vec![route("192.168.10.0/24", LOCAL_METRIC), route("192.168.20.0/24", 2)]
+        );
+
+        // Split horizon: paris only hears about our own subnet
+        assert_eq!(
+            federation.announcement_for("paris").routes,
+            vec![route("192.168.10.0/24", LOCAL_METRIC)]
+        );
+    }
+
+    #[test]
+    fn test_routes_outside_allowed_prefixes_ignored() {
+        let federation = test_federation();
+        let announcement = RouteAnnouncement::new(
+            "paris",
+            vec![
+                route("192.168.20.0/25", 1),
+                route("0.0.0.0/1", 1),
+                route("128.0.0.0/1", 1),
+                route("10.9.0.0/8", 1),
+            ],
+        );
+        federation.learn("paris", &announcement).unwrap();
+        assert_eq!(federation.routes().snapshot()["paris"], vec![route("192.168.20.0/25", 1)]);
+    }
+
+    #[test]
+    fn test_authenticated_links() {
+        let federation = test_federation();
+        let nonces = LinkNonces {
+            client_random: [1u8; 32],
+            server_random: [2u8; 32],
+        };
+        let mut link = Link::new(LinkSide::Listener, nonces);
+        let announcement = RouteAnnouncement::new("paris", vec![route("192.168.20.0/24", 1)]);
+
+        // Claiming the name isn't enough
+        assert!(federation.authenticate("paris", &announcement, &mut link).is_err());
+
+        let prove = |announcement: RouteAnnouncement, side: LinkSide, nonces: &LinkNonces| {
+            let proof = federation.proof("paris", &announcement, side, nonces).unwrap().unwrap();
+            announcement.with_proof(&proof)
+        };
+        let proven = prove(announcement.clone(), LinkSide::Dialer, &nonces);
+        federation.authenticate("paris", &proven, &mut Link::new(LinkSide::Listener, nonces)).unwrap();
+
+        // Bound to the link's side, randoms and peer secret
+        assert!(federation
+            .authenticate("paris", &proven, &mut Link::new(LinkSide::Dialer, nonces))
+            .is_err());
+        let other = LinkNonces {
+            server_random: [3u8; 32],
+            ..nonces
+        };
+        assert!(federation
+            .authenticate("paris", &proven, &mut Link::new(LinkSide::Listener, other))
+            .is_err());
+        let as_rome = RouteAnnouncement {
+            site: "rome".to_string(),
+            ..proven.clone()
+        };
+        assert!(federation.authenticate("rome", &as_rome, &mut link).is_err());
+
+        // Only the proven session's end takes the routes with it
+        let linked = SessionId::new();
+        federation.learn("paris", &proven).unwrap();
+        federation.attach("paris", linked);
+        federation.detach(&SessionId::new());
+        assert!(federation.routes().snapshot().contains_key("paris"));
+        federation.detach(&linked);
+        assert!(federation.routes().snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_announcements_not_rewritten_or_replayed() {
+        let federation = test_federation();
+        let nonces = LinkNonces {
+            client_random: [1u8; 32],
+            server_random: [2u8; 32],
+        };
+        let mut link = Link::new(LinkSide::Listener, nonces);
+        let prove = |announcement: RouteAnnouncement| {
+            let proof = federation.proof("paris", &announcement, LinkSide::Dialer, &nonces).unwrap().unwrap();
+            announcement.with_proof(&proof)
+        };
+
+        let first = prove(RouteAnnouncement::new("paris", vec![route("192.168.20.0/24", 1)]).with_counter(0));
+        let second = prove(RouteAnnouncement::new("paris", vec![route("10.9.0.0/16", 1)]).with_counter(1));
+
+        // Routes and counter are covered by the proof
+        let rewritten = RouteAnnouncement {
+            routes: vec![route("192.168.20.0/25", 1)],
+            ..first.clone()
+        };
+        assert!(federation.authenticate("paris", &rewritten, &mut link).is_err());
+        let renumbered = RouteAnnouncement {
+            counter: 5,
+            ..first.clone()
+        };
+        assert!(federation.authenticate("paris", &renumbered, &mut link).is_err());
+
+        // Only increasing counters are taken
+        federation.authenticate("paris", &first, &mut link).unwrap();
+        federation.authenticate("paris", &second, &mut link).unwrap();
+        assert!(federation.authenticate("paris", &first, &mut link).is_err());
+        assert!(federation.authenticate("paris", &second, &mut link).is_err());
+    }
+}
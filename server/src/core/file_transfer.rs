@@ -0,0 +1,651 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::core::channels::{ChannelHandler, Channels};
+use crate::core::session::SessionId;
+use crate::error::{LostLoveError, Result};
+
+/// Channel file transfers run on
+pub const FILES_CHANNEL: &str = "files";
+
+/// File bytes per chunk; hex-encoded in its message it stays well within
+/// the default `limits.max_packet_size`
+pub const CHUNK_SIZE: usize = 4096;
+
+/// A transfer the client makes no progress on for this long is failed
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Message on the `files` channel, one per Data packet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum FileMessage {
+    /// The sender has a file of `size` bytes hashing to `sha256` for `path`
+    Offer { id: u64, path: String, size: u64, sha256: String },
+    /// The server wants the client's file at `path`, answered with an offer
+    Request { id: u64, path: String },
+    /// The receiver has the first `offset` bytes of the offer already
+    Resume { id: u64, offset: u64 },
+    /// File bytes from `offset`, hex-encoded
+    Chunk { id: u64, offset: u64, data: String },
+    /// The receiver has everything before `offset`; the final ack means the
+    /// whole file checked out against the offer's hash
+    Ack { id: u64, offset: u64 },
+    /// The transfer can't go on
+    Error { id: u64, message: String },
+}
+
+impl FileMessage {
+    pub fn id(&self) -> u64 {
+        match self {
+            FileMessage::Offer { id, .. }
+            | FileMessage::Request { id, .. }
+            | FileMessage::Resume { id, .. }
+            | FileMessage::Chunk { id, .. }
+            | FileMessage::Ack { id, .. }
+            | FileMessage::Error { id, .. } => *id,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data).map_err(|e| LostLoveError::Network(format!("Invalid file message: {}", e)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferDirection {
+    /// Server to client
+    Push,
+    /// Client to server
+    Pull,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferState {
+    Active,
+    Complete,
+    Failed,
+}
+
+/// Progress of one transfer, as `llpctl push` and `pull` show it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferStatus {
+    pub id: u64,
+    pub session_id: String,
+    pub direction: TransferDirection,
+    /// Path on the server
+    pub local: String,
+    /// Path on the client
+    pub remote: String,
+    /// None until the client offered its file
+    pub size: Option<u64>,
+    pub transferred: u64,
+    /// Bytes the receiver kept from an earlier attempt
+    pub resumed_from: u64,
+    pub state: TransferState,
+    pub error: Option<String>,
+}
+
+/// File transfers between the server and clients over the `files` channel
+///
+/// A push offers a server file to the client with its size and hash; the
+/// client answers with how much of it it already has and the server sends
+/// chunks from there, one per ack. A pull asks the client to offer one of
+/// its files and the server receives it the same way into `<local>.part`,
+/// so an interrupted pull resumes from what that file holds. The part file
+/// only replaces the target once the whole file matches the offered hash.
+pub struct FileTransfers {
+    channels: Weak<Channels>,
+    next_id: AtomicU64,
+    transfers: DashMap<u64, Transfer>,
+}
+
+impl FileTransfers {
+    /// Transfers on the `files` channel of `channels`
+    pub fn register(channels: &Arc<Channels>) -> Result<Arc<Self>> {
+        let transfers = Arc::new(Self {
+            channels: Arc::downgrade(channels),
+            next_id: AtomicU64::new(1),
+            transfers: DashMap::new(),
+        });
+        channels.register(FILES_CHANNEL, transfers.clone())?;
+        Ok(transfers)
+    }
+
+    /// Offer the server's file `local` to a session's client, to store at `remote`
+    pub fn push(
+        &self,
+        session_id: &SessionId,
+        local: &Path,
+        remote: &str,
+    ) -> Result<watch::Receiver<TransferStatus>> {
+        let mut file = File::open(local)?;
+        let size = file.metadata()?.len();
+        let sha256 = hash_file(&mut file)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let offer = FileMessage::Offer {
+            id,
+            path: remote.to_string(),
+            size,
+            sha256: sha256.clone(),
+        };
+        let mut transfer = Transfer::new(session_id, id, TransferDirection::Push, local, remote, file);
+        transfer.offer = Some((size, sha256));
+        transfer.status.send_modify(|status| status.size = Some(size));
+        self.start(transfer, offer)
+    }
+
+    /// Ask a session's client for its file `remote`, to store at `local`
+    pub fn pull(
+        &self,
+        session_id: &SessionId,
+        remote: &str,
+        local: &Path,
+    ) -> Result<watch::Receiver<TransferStatus>> {
+        let part = part_path(local);
+        // Opened now, so an unwritable target fails before the client is asked
+        let file = OpenOptions::new().create(true).append(true).open(&part)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let request = FileMessage::Request {
+            id,
+            path: remote.to_string(),
+        };
+        let mut transfer = Transfer::new(session_id, id, TransferDirection::Pull, local, remote, file);
+        transfer.part = Some(part);
+        self.start(transfer, request)
+    }
+
+    fn start(&self, transfer: Transfer, first: FileMessage) -> Result<watch::Receiver<TransferStatus>> {
+        let channels = self
+            .channels
+            .upgrade()
+            .ok_or_else(|| LostLoveError::Connection("Channels are shut down".to_string()))?;
+        let id = first.id();
+        let session_id = transfer.session_id;
+        let status = transfer.status.subscribe();
+
+        self.transfers.insert(id, transfer);
+        if let Err(e) = channels.send(&session_id, FILES_CHANNEL, first.to_bytes()?) {
+            self.transfers.remove(&id);
+            return Err(e);
+        }
+        Ok(status)
+    }
+
+    /// Give up on a transfer and tell the client to stop
+    pub fn fail(&self, id: u64, reason: &str) {
+        let Some((_, transfer)) = self.transfers.remove(&id) else {
+            return;
+        };
+        transfer.finish(Err(reason.to_string()));
+
+        let message = FileMessage::Error {
+            id,
+            message: reason.to_string(),
+        };
+        if let (Some(channels), Ok(data)) = (self.channels.upgrade(), message.to_bytes()) {
+            let _ = channels.send(&transfer.session_id, FILES_CHANNEL, data);
+        }
+    }
+
+    /// Transfers still going
+    pub fn active_count(&self) -> usize {
+        self.transfers.len()
+    }
+}
+
+impl ChannelHandler for FileTransfers {
+    fn receive(&self, session_id: &SessionId, data: &[u8]) -> Result<Option<Bytes>> {
+        let message = FileMessage::from_bytes(data)?;
+        let id = message.id();
+        let mut transfer = match self.transfers.get_mut(&id) {
+            // Other sessions can't steer a transfer by guessing its ID
+            Some(transfer) if transfer.session_id == *session_id => transfer,
+            _ => {
+                return Err(LostLoveError::FileTransfer {
+                    id,
+                    reason: "no such transfer on this session".to_string(),
+                })
+            }
+        };
+
+        let reply = match transfer.step(message) {
+            Ok(reply) => reply,
+            Err(e) => {
+                transfer.finish(Err(e.to_string()));
+                Some(FileMessage::Error {
+                    id,
+                    message: e.to_string(),
+                })
+            }
+        };
+        let finished = transfer.status.borrow().state != TransferState::Active;
+        drop(transfer);
+        if finished {
+            self.transfers.remove(&id);
+        }
+
+        reply.map(|reply| reply.to_bytes()).transpose()
+    }
+}
+
+/// One transfer in progress
+struct Transfer {
+    session_id: SessionId,
+    status: watch::Sender<TransferStatus>,
+    /// Read from for a push, appended to for a pull
+    file: File,
+    /// Where a pull writes until the file is complete
+    part: Option<PathBuf>,
+    /// Size and hash of the file sent
+    offer: Option<(u64, String)>,
+    /// End of the data sent or written, None until the receiver said where to start
+    position: Option<u64>,
+}
+
+impl Transfer {
+    fn new(
+        session_id: &SessionId,
+        id: u64,
+        direction: TransferDirection,
+        local: &Path,
+        remote: &str,
+        file: File,
+    ) -> Self {
+        let (status, _) = watch::channel(TransferStatus {
+            id,
+            session_id: session_id.to_string(),
+            direction,
+            local: local.display().to_string(),
+            remote: remote.to_string(),
+            size: None,
+            transferred: 0,
+            resumed_from: 0,
+            state: TransferState::Active,
+            error: None,
+        });
+        Self {
+            session_id: *session_id,
+            status,
+            file,
+            part: None,
+            offer: None,
+            position: None,
+        }
+    }
+
+    fn id(&self) -> u64 {
+        self.status.borrow().id
+    }
+
+    fn error(&self, reason: impl Into<String>) -> LostLoveError {
+        LostLoveError::FileTransfer {
+            id: self.id(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Handle one message of the client, returning the answer
+    fn step(&mut self, message: FileMessage) -> Result<Option<FileMessage>> {
+        let pushing = self.status.borrow().direction == TransferDirection::Push;
+        match message {
+            FileMessage::Error { message, .. } => {
+                self.finish(Err(format!("Client gave up: {}", message)));
+                Ok(None)
+            }
+            FileMessage::Resume { offset, .. } if pushing && self.position.is_none() => {
+                self.status.send_modify(|status| {
+                    status.resumed_from = offset;
+                    status.transferred = offset;
+                });
+                self.send_chunk(offset)
+            }
+            FileMessage::Ack { offset, .. } if pushing && self.position == Some(offset) => {
+                self.status.send_modify(|status| status.transferred = offset);
+                self.send_chunk(offset)
+            }
+            FileMessage::Offer { size, sha256, .. } if !pushing && self.offer.is_none() => self.accept_offer(size, sha256),
+            FileMessage::Chunk { offset, data, .. } if !pushing && self.position == Some(offset) => {
+                self.write_chunk(&data)
+            }
+            other => Err(self.error(format!("unexpected message {:?}", other))),
+        }
+    }
+
+    /// Chunk of a push from `offset`, or None once the client acked it all
+    fn send_chunk(&mut self, offset: u64) -> Result<Option<FileMessage>> {
+        let size = self.offer.as_ref().map_or(0, |(size, _)| *size);
+        if offset > size {
+            return Err(self.error(format!("client resumed at {} of a {} byte file", offset, size)));
+        }
+        if offset == size {
+            self.finish(Ok(()));
+            return Ok(None);
+        }
+
+        let mut data = Vec::with_capacity(CHUNK_SIZE);
+        self.file.seek(SeekFrom::Start(offset))?;
+        (&mut self.file).take(CHUNK_SIZE as u64).read_to_end(&mut data)?;
+        if data.is_empty() {
+            return Err(self.error("file shrank while being sent"));
+        }
+        self.position = Some(offset + data.len() as u64);
+
+        Ok(Some(FileMessage::Chunk {
+            id: self.id(),
+            offset,
+            data: hex::encode(data),
+        }))
+    }
+
+    /// Start a pull where the part file left off
+    fn accept_offer(&mut self, size: u64, sha256: String) -> Result<Option<FileMessage>> {
+        let mut offset = self.file.metadata()?.len();
+        if offset > size {
+            // Left by a different, longer file
+            self.file.set_len(0)?;
+            offset = 0;
+        }
+        self.offer = Some((size, sha256));
+        self.position = Some(offset);
+        self.status.send_modify(|status| {
+            status.size = Some(size);
+            status.resumed_from = offset;
+            status.transferred = offset;
+        });
+
+        if offset == size {
+            self.complete_pull()?;
+        }
+        Ok(Some(FileMessage::Resume { id: self.id(), offset }))
+    }
+
+    fn write_chunk(&mut self, data: &str) -> Result<Option<FileMessage>> {
+        let data = hex::decode(data).map_err(|e| self.error(format!("chunk isn't hex: {}", e)))?;
+        let size = self.offer.as_ref().map_or(0, |(size, _)| *size);
+        let position = self.position.unwrap_or(0) + data.len() as u64;
+        if data.is_empty() || position > size {
+            return Err(self.error(format!("chunk ends at {} of a {} byte file", position, size)));
+        }
+
+        self.file.write_all(&data)?;
+        self.position = Some(position);
+        self.status.send_modify(|status| status.transferred = position);
+
+        if position == size {
+            self.complete_pull()?;
+        }
+        Ok(Some(FileMessage::Ack { id: self.id(), offset: position }))
+    }
+
+    /// Check a fully received part file and move it into place
+    fn complete_pull(&mut self) -> Result<()> {
+        let (Some(part), Some((_, sha256))) = (&self.part, &self.offer) else {
+            return Err(self.error("nothing was offered"));
+        };
+        self.file.flush()?;
+        let received = hash_file(&mut File::open(part)?)?;
+        if received != *sha256 {
+            // Resuming from bad data would fail again the same way
+            let _ = fs::remove_file(part);
+            return Err(self.error("received file doesn't match the offered hash"));
+        }
+
+        fs::rename(part, &self.status.borrow().local)?;
+        self.finish(Ok(()));
+        Ok(())
+    }
+
+    fn finish(&self, result: std::result::Result<(), String>) {
+        self.status.send_modify(|status| match result {
+            Ok(()) => {
+                info!("File transfer {} of {} complete", status.id, status.local);
+                status.state = TransferState::Complete;
+            }
+            Err(reason) => {
+                warn!("File transfer {} of {} failed: {}", status.id, status.local, reason);
+                status.state = TransferState::Failed;
+                status.error = Some(reason);
+            }
+        });
+    }
+}
+
+/// `<path>.part`, where a pull to `path` collects the file
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Hex SHA-256 of a file's contents
+fn hash_file(file: &mut File) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 8192];
+    file.rewind()?;
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    use crate::config::Config;
+    use crate::core::server::{read_packet, write_packet};
+    use crate::protocol::{Packet, PacketType};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("llp-files-{}-{}", uuid::Uuid::new_v4(), name))
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    fn setup() -> (Arc<Channels>, Arc<FileTransfers>, SessionId, mpsc::Receiver<crate::core::channels::ChannelMessage>) {
+        let channels = Arc::new(Channels::new());
+        let transfers = FileTransfers::register(&channels).unwrap();
+        let session_id = SessionId::new();
+        let mailbox = channels.attach(&session_id);
+        (channels, transfers, session_id, mailbox)
+    }
+
+    /// Answer from the server to a client message
+    fn answer(transfers: &FileTransfers, session_id: &SessionId, message: FileMessage) -> Option<FileMessage> {
+        let reply = transfers.receive(session_id, &message.to_bytes().unwrap()).unwrap();
+        reply.map(|data| FileMessage::from_bytes(&data).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_push_resumes_from_client_offset() {
+        let (_channels, transfers, session_id, mut mailbox) = setup();
+        let contents: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let local = temp_path("push");
+        fs::write(&local, &contents).unwrap();
+
+        let status = transfers.push(&session_id, &local, "/etc/llp/site.conf").unwrap();
+        let offer = FileMessage::from_bytes(&mailbox.recv().await.unwrap().data).unwrap();
+        let FileMessage::Offer { id, path, size, sha256: hash } = offer else {
+            panic!("Unexpected message: {:?}", offer);
+        };
+        assert_eq!((path.as_str(), size), ("/etc/llp/site.conf", 10000));
+        assert_eq!(hash, sha256(&contents));
+
+        // The client kept 1000 bytes from an earlier attempt
+        let mut received = contents[..1000].to_vec();
+        let mut next = answer(&transfers, &session_id, FileMessage::Resume { id, offset: 1000 });
+        while let Some(FileMessage::Chunk { offset, data, .. }) = next {
+            assert_eq!(offset as usize, received.len());
+            received.extend(hex::decode(data).unwrap());
+            next = answer(&transfers, &session_id, FileMessage::Ack { id, offset: received.len() as u64 });
+        }
+        assert_eq!(next, None);
+        assert_eq!(received, contents);
+
+        let status = status.borrow().clone();
+        assert_eq!(status.state, TransferState::Complete);
+        assert_eq!((status.resumed_from, status.transferred), (1000, 10000));
+        assert_eq!(transfers.active_count(), 0);
+        fs::remove_file(local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_full_chunk_framed_over_tcp() {
+        let chunk = FileMessage::Chunk {
+            id: u64::MAX,
+            offset: u64::MAX,
+            data: hex::encode([0xAB; CHUNK_SIZE]),
+        };
+        let packet = Packet::new_with_metadata(PacketType::Data, 5, 0, chunk.to_bytes().unwrap());
+        assert!(packet.size() <= Config::default_for_testing().limits.max_packet_size);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        write_packet(&mut client, &packet).await.unwrap();
+        write_packet(&mut client, &packet).await.unwrap();
+
+        // Back to back, each is read whole and no further
+        for _ in 0..2 {
+            let received = read_packet(&mut stream).await.unwrap();
+            assert_eq!(FileMessage::from_bytes(&received.payload).unwrap(), chunk);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_resumes_part_file() {
+        let (_channels, transfers, session_id, mut mailbox) = setup();
+        let contents = b"[network]\nmtu = 1400\n".repeat(100);
+        let local = temp_path("pull");
+        fs::write(part_path(&local), &contents[..700]).unwrap();
+
+        let status = transfers.pull(&session_id, "/var/log/llp.log", &local).unwrap();
+        let request = FileMessage::from_bytes(&mailbox.recv().await.unwrap().data).unwrap();
+        let id = request.id();
+        assert!(matches!(request, FileMessage::Request { ref path, .. } if path == "/var/log/llp.log"));
+
+        let offer = FileMessage::Offer {
+            id,
+            path: "/var/log/llp.log".to_string(),
+            size: contents.len() as u64,
+            sha256: sha256(&contents),
+        };
+        let Some(FileMessage::Resume { offset, .. }) = answer(&transfers, &session_id, offer) else {
+            panic!("Expected a resume");
+        };
+        assert_eq!(offset, 700);
+
+        let mut offset = offset as usize;
+        while offset < contents.len() {
+            let end = (offset + CHUNK_SIZE).min(contents.len());
+            let chunk = FileMessage::Chunk {
+                id,
+                offset: offset as u64,
+                data: hex::encode(&contents[offset..end]),
+            };
+            assert_eq!(
+                answer(&transfers, &session_id, chunk),
+                Some(FileMessage::Ack { id, offset: end as u64 })
+            );
+            offset = end;
+        }
+
+        assert_eq!(status.borrow().state, TransferState::Complete);
+        assert_eq!(fs::read(&local).unwrap(), contents);
+        assert!(!part_path(&local).exists());
+        fs::remove_file(local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_pull_discarded() {
+        let (_channels, transfers, session_id, mut mailbox) = setup();
+        let local = temp_path("corrupt");
+
+        let status = transfers.pull(&session_id, "/etc/hosts", &local).unwrap();
+        let id = FileMessage::from_bytes(&mailbox.recv().await.unwrap().data).unwrap().id();
+        let offer = FileMessage::Offer {
+            id,
+            path: "/etc/hosts".to_string(),
+            size: 4,
+            sha256: sha256(b"good"),
+        };
+        answer(&transfers, &session_id, offer);
+
+        let chunk = FileMessage::Chunk {
+            id,
+            offset: 0,
+            data: hex::encode(b"evil"),
+        };
+        assert!(matches!(answer(&transfers, &session_id, chunk), Some(FileMessage::Error { .. })));
+        assert_eq!(status.borrow().state, TransferState::Failed);
+        assert!(!local.exists() && !part_path(&local).exists());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_bound_to_its_session() {
+        let (_channels, transfers, session_id, mut mailbox) = setup();
+        let local = temp_path("bound");
+        fs::write(&local, b"secret").unwrap();
+
+        let status = transfers.push(&session_id, &local, "/tmp/secret").unwrap();
+        let id = FileMessage::from_bytes(&mailbox.recv().await.unwrap().data).unwrap().id();
+
+        let resume = FileMessage::Resume { id, offset: 0 }.to_bytes().unwrap();
+        let err = transfers.receive(&SessionId::new(), &resume).unwrap_err();
+        assert_eq!(err.code(), "file_transfer");
+        // Acks must follow what was sent
+        assert!(matches!(
+            answer(&transfers, &session_id, FileMessage::Ack { id, offset: 3 }),
+            Some(FileMessage::Error { .. })
+        ));
+        assert_eq!(status.borrow().state, TransferState::Failed);
+
+        // Nothing can be pushed to a session without a mailbox
+        assert!(transfers.push(&SessionId::new(), &local, "/tmp/secret").is_err());
+        assert_eq!(transfers.active_count(), 0);
+        fs::remove_file(local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fail_tells_client() {
+        let (_channels, transfers, session_id, mut mailbox) = setup();
+        let local = temp_path("stalled");
+
+        let status = transfers.pull(&session_id, "/etc/hosts", &local).unwrap();
+        let id = FileMessage::from_bytes(&mailbox.recv().await.unwrap().data).unwrap().id();
+        transfers.fail(id, "no progress");
+
+        let error = FileMessage::from_bytes(&mailbox.recv().await.unwrap().data).unwrap();
+        assert!(matches!(error, FileMessage::Error { ref message, .. } if message == "no progress"));
+        assert_eq!(status.borrow().error.as_deref(), Some("no progress"));
+        let _ = fs::remove_file(part_path(&local));
+    }
+}
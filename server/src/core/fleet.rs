@@ -0,0 +1,408 @@
+use dashmap::DashMap;
+use hkdf::hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::config::{Config, FleetPeer};
+use crate::core::connection::ConnectionManager;
+use crate::crypto::ct;
+use crate::error::{LostLoveError, Result};
+
+/// HMAC-SHA256 tag after each report
+const TAG_SIZE: usize = 32;
+
+/// Largest report datagram accepted
+const MAX_REPORT_SIZE: usize = 1024;
+
+/// Reports a node may miss before it is left out of steering
+const MISSED_REPORTS: u32 = 3;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn tag(secret: &[u8], body: &[u8]) -> [u8; TAG_SIZE] {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.finalize().into_bytes().into()
+}
+
+/// Load a node announces to the others
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoadReport {
+    pub node: String,
+    /// Grows with every report, also across restarts
+    pub sequence: u64,
+    pub sessions: usize,
+    pub capacity: usize,
+}
+
+impl LoadReport {
+    /// JSON body followed by its tag
+    pub fn seal(&self, secret: &[u8]) -> Vec<u8> {
+        let mut datagram = serde_json::to_vec(self).expect("reports always serialize");
+        let tag = tag(secret, &datagram);
+        datagram.extend_from_slice(&tag);
+        datagram
+    }
+
+    pub fn open(datagram: &[u8], secret: &[u8]) -> Result<Self> {
+        if datagram.len() <= TAG_SIZE || datagram.len() > MAX_REPORT_SIZE {
+            return Err(LostLoveError::Network(format!("Load report of {} bytes", datagram.len())));
+        }
+        let (body, received) = datagram.split_at(datagram.len() - TAG_SIZE);
+        if !ct::eq(&tag(secret, body), received) {
+            return Err(LostLoveError::AuthFailed {
+                reason: "load report tag mismatch".to_string(),
+            });
+        }
+        serde_json::from_slice(body).map_err(|e| LostLoveError::Network(format!("Invalid load report: {}", e)))
+    }
+}
+
+/// Last report of a peer
+#[derive(Debug, Clone, Copy)]
+struct PeerLoad {
+    sequence: u64,
+    sessions: usize,
+    capacity: usize,
+    received: Instant,
+}
+
+/// A node steering may pick
+#[derive(Debug, Clone, PartialEq)]
+struct Candidate<'a> {
+    name: &'a str,
+    /// None for this server
+    address: Option<&'a str>,
+    sessions: usize,
+    capacity: usize,
+}
+
+impl Candidate<'_> {
+    fn utilization(&self, extra: usize) -> f64 {
+        if self.capacity == 0 {
+            return f64::INFINITY;
+        }
+        (self.sessions + extra) as f64 / self.capacity as f64
+    }
+}
+
+/// Rendezvous hash of a client on a node, the same on every node
+fn score(key: &str, node: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(key.as_bytes())
+        .chain_update([0])
+        .chain_update(node.as_bytes())
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Load-aware steering of new clients across a fleet
+///
+/// Every node sends its session count and capacity to the others every
+/// `interval` over UDP, authenticated with the shared secret. A new client is
+/// hashed onto the nodes (rendezvous hashing on its user, or its address
+/// without one) and goes to the first node in its order that stays within
+/// `load_factor` times the fleet's average load, so the same user keeps
+/// landing on the same node until that node fills up. When that is another
+/// node, the handshake is refused with `server-steered` and its address.
+/// Clients are only steered towards a node less loaded than this one, so
+/// slightly different views of the fleet can't bounce a client back and forth.
+pub struct Fleet {
+    node: String,
+    address: String,
+    secret: Vec<u8>,
+    peers: Vec<FleetPeer>,
+    interval: Duration,
+    load_factor: f64,
+    connection_manager: Arc<ConnectionManager>,
+    loads: DashMap<String, PeerLoad>,
+    sequence: AtomicU64,
+}
+
+impl Fleet {
+    /// Create from config, None when the fleet is disabled
+    pub fn new(config: &Config, connection_manager: Arc<ConnectionManager>) -> Option<Self> {
+        let fleet = &config.fleet;
+        if !fleet.enabled {
+            return None;
+        }
+
+        // Starting from the clock keeps sequences growing across restarts
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        Some(Self {
+            node: fleet.node.clone(),
+            address: format!("{}:{}", config.server.bind_address, fleet.port),
+            secret: fleet.secret.as_bytes().to_vec(),
+            peers: fleet.peers.clone(),
+            interval: Duration::from_secs(fleet.interval),
+            load_factor: fleet.load_factor,
+            connection_manager,
+            loads: DashMap::new(),
+            sequence: AtomicU64::new(start),
+        })
+    }
+
+    pub(crate) fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// This server's current load
+    pub fn report(&self) -> LoadReport {
+        LoadReport {
+            node: self.node.clone(),
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            sessions: self.connection_manager.active_count(),
+            capacity: self.connection_manager.max_connections(),
+        }
+    }
+
+    /// Take a peer's report, unless it is stale, replayed or from a stranger
+    pub fn record(&self, report: LoadReport) -> Result<()> {
+        if !self.peers.iter().any(|peer| peer.name == report.node) {
+            return Err(LostLoveError::Network(format!("Load report from unknown node {:?}", report.node)));
+        }
+
+        let mut entry = self.loads.entry(report.node.clone()).or_insert(PeerLoad {
+            sequence: 0,
+            sessions: 0,
+            capacity: 0,
+            received: Instant::now(),
+        });
+        if report.sequence <= entry.sequence {
+            return Err(LostLoveError::Network(format!(
+                "Replayed load report from {} ({} <= {})",
+                report.node, report.sequence, entry.sequence
+            )));
+        }
+        *entry = PeerLoad {
+            sequence: report.sequence,
+            sessions: report.sessions,
+            capacity: report.capacity,
+            received: Instant::now(),
+        };
+        Ok(())
+    }
+
+    /// Address of the node that should take a client, None to keep it here
+    pub fn steer(&self, key: &str) -> Option<String> {
+        // The client asking is counted already
+        let sessions = self.connection_manager.active_count().saturating_sub(1);
+        let capacity = self.connection_manager.max_connections();
+        self.pick(key, sessions, capacity).map(str::to_string)
+    }
+
+    fn pick(&self, key: &str, sessions: usize, capacity: usize) -> Option<&str> {
+        let fresh = self.interval * MISSED_REPORTS;
+        let mut candidates = vec![Candidate {
+            name: &self.node,
+            address: None,
+            sessions,
+            capacity,
+        }];
+        for peer in &self.peers {
+            let Some(load) = self.loads.get(&peer.name).map(|load| *load) else {
+                continue;
+            };
+            if load.received.elapsed() < fresh {
+                candidates.push(Candidate {
+                    name: &peer.name,
+                    address: Some(&peer.address),
+                    sessions: load.sessions,
+                    capacity: load.capacity,
+                });
+            }
+        }
+        if candidates.len() == 1 {
+            return None;
+        }
+
+        // Bounded loads: nobody goes past load_factor times the average,
+        // counting the new client
+        let total_sessions: usize = candidates.iter().map(|candidate| candidate.sessions).sum();
+        let total_capacity: usize = candidates.iter().map(|candidate| candidate.capacity).sum();
+        let bound = self.load_factor * (total_sessions + 1) as f64 / total_capacity.max(1) as f64;
+
+        let own = candidates[0].utilization(0);
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(score(key, candidate.name)));
+        candidates
+            .iter()
+            .find(|candidate| {
+                candidate.utilization(1) <= bound
+                    && (candidate.address.is_none() || candidate.utilization(0) < own)
+            })
+            .and_then(|candidate| candidate.address)
+    }
+
+    /// Send reports to every peer and take theirs, until cancelled
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let socket = UdpSocket::bind(&self.address).await?;
+        info!("Fleet node {} exchanging load on {}", self.node, self.address);
+
+        let mut reports = time::interval(self.interval);
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        loop {
+            tokio::select! {
+                _ = reports.tick() => {
+                    let datagram = self.report().seal(&self.secret);
+                    for peer in &self.peers {
+                        if let Err(e) = socket.send_to(&datagram, &peer.report_address).await {
+                            debug!("Failed to send load report to {}: {}", peer.name, e);
+                        }
+                    }
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let (n, from) = received?;
+                    match LoadReport::open(&buf[..n], &self.secret).and_then(|report| self.record(report)) {
+                        Ok(()) => {}
+                        Err(e) => warn!(code = e.code(), "Ignoring load report from {}: {}", from, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FleetPeer;
+
+    fn fleet(node: &str, peers: &[&str]) -> Fleet {
+        let mut config = Config::default_for_testing();
+        config.fleet.enabled = true;
+        config.fleet.node = node.to_string();
+        config.fleet.secret = "shared".to_string();
+        config.fleet.peers = peers
+            .iter()
+            .map(|name| FleetPeer {
+                name: name.to_string(),
+                address: format!("{}.example.com:8443", name),
+                report_address: "127.0.0.1:9".to_string(),
+            })
+            .collect();
+        Fleet::new(&config, Arc::new(ConnectionManager::new(100))).unwrap()
+    }
+
+    fn report(node: &str, sequence: u64, sessions: usize) -> LoadReport {
+        LoadReport {
+            node: node.to_string(),
+            sequence,
+            sessions,
+            capacity: 100,
+        }
+    }
+
+    #[test]
+    fn test_report_authentication() {
+        let datagram = report("fra-2", 1, 10).seal(b"shared");
+        assert_eq!(LoadReport::open(&datagram, b"shared").unwrap(), report("fra-2", 1, 10));
+        assert!(LoadReport::open(&datagram, b"guessed").is_err());
+
+        let mut forged = datagram.clone();
+        forged[5] ^= 1;
+        assert!(LoadReport::open(&forged, b"shared").is_err());
+    }
+
+    #[test]
+    fn test_record_rejects_replays_and_strangers() {
+        let fleet = fleet("fra-1", &["fra-2"]);
+        fleet.record(report("fra-2", 5, 10)).unwrap();
+        assert!(fleet.record(report("fra-2", 5, 0)).is_err());
+        assert!(fleet.record(report("fra-3", 9, 0)).is_err());
+        assert_eq!(fleet.loads.get("fra-2").unwrap().sessions, 10);
+    }
+
+    #[test]
+    fn test_steering() {
+        let fleet = fleet("fra-1", &["fra-2", "fra-3"]);
+        // Nobody reported yet: everyone stays
+        assert_eq!(fleet.pick("alice", 50, 100), None);
+
+        fleet.record(report("fra-2", 1, 50)).unwrap();
+        fleet.record(report("fra-3", 1, 50)).unwrap();
+
+        // Even load: users spread by hash, and the choice is stable
+        let users: Vec<String> = (0..60).map(|i| format!("user{}", i)).collect();
+        let steered = users.iter().filter(|user| fleet.pick(user, 50, 100).is_some()).count();
+        assert_eq!(steered, 0, "never steered to a node as loaded as this one");
+
+        // This node is far busier: every client hashed elsewhere leaves,
+        // those hashed here are sent to the less loaded nodes as well
+        let picks: Vec<_> = users.iter().map(|user| fleet.pick(user, 95, 100)).collect();
+        assert!(picks.iter().all(Option::is_some));
+        assert!(picks.contains(&Some("fra-2.example.com:8443")));
+        assert!(picks.contains(&Some("fra-3.example.com:8443")));
+        assert_eq!(picks, users.iter().map(|user| fleet.pick(user, 95, 100)).collect::<Vec<_>>());
+
+        // A peer busier than this node never gets clients from it
+        fleet.record(report("fra-2", 2, 100)).unwrap();
+        let picks: Vec<_> = users.iter().map(|user| fleet.pick(user, 95, 100)).collect();
+        assert!(!picks.contains(&Some("fra-2.example.com:8443")));
+        assert!(picks.contains(&Some("fra-3.example.com:8443")));
+        assert!(picks.contains(&None));
+    }
+
+    #[test]
+    fn test_stale_peers_are_ignored() {
+        let fleet = fleet("fra-1", &["fra-2"]).with_interval(Duration::from_millis(1));
+        fleet.record(report("fra-2", 1, 0)).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(fleet.pick("alice", 95, 100), None);
+    }
+
+    #[tokio::test]
+    async fn test_nodes_exchange_reports() {
+        let sockets: Vec<_> = (0..2).map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+        let ports: Vec<u16> = sockets.iter().map(|socket| socket.local_addr().unwrap().port()).collect();
+        drop(sockets);
+
+        let node = |name: &str, port: u16, peer: &str, peer_port: u16| {
+            let mut config = Config::default_for_testing();
+            config.fleet.enabled = true;
+            config.fleet.node = name.to_string();
+            config.fleet.secret = "shared".to_string();
+            config.fleet.port = port;
+            config.fleet.peers = vec![FleetPeer {
+                name: peer.to_string(),
+                address: format!("{}:8443", peer),
+                report_address: format!("127.0.0.1:{}", peer_port),
+            }];
+            Arc::new(
+                Fleet::new(&config, Arc::new(ConnectionManager::new(100)))
+                    .unwrap()
+                    .with_interval(Duration::from_millis(20)),
+            )
+        };
+        let first = node("fra-1", ports[0], "fra-2", ports[1]);
+        let second = node("fra-2", ports[1], "fra-1", ports[0]);
+        let tasks = [tokio::spawn(first.clone().run()), tokio::spawn(second.clone().run())];
+
+        time::timeout(Duration::from_secs(5), async {
+            while !(first.loads.contains_key("fra-2") && second.loads.contains_key("fra-1")) {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(first.loads.get("fra-2").unwrap().capacity, 100);
+
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
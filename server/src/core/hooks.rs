@@ -0,0 +1,228 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::config::HooksConfig;
+use crate::core::session::Session;
+use crate::error::{LostLoveError, Result};
+
+/// Bytes of a failed script's stderr kept for the log
+const MAX_STDERR: usize = 512;
+
+/// Session event a hook script runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Connect,
+    Disconnect,
+    AuthFail,
+}
+
+impl HookEvent {
+    /// Value of `LLP_EVENT`
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Connect => "connect",
+            HookEvent::Disconnect => "disconnect",
+            HookEvent::AuthFail => "auth-fail",
+        }
+    }
+}
+
+/// `LLP_*` environment variables handed to a hook script
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HookEnv(Vec<(&'static str, String)>);
+
+impl HookEnv {
+    /// Variables describing a session, set for every event
+    pub async fn for_session(session: &Session, user: Option<&str>) -> Self {
+        let transport = format!("{:?}", session.transport().await).to_lowercase();
+        let tunnel_address = session.tunnel_address().map(|address| address.to_string());
+        Self::default()
+            .with("LLP_SESSION_ID", session.id())
+            .with("LLP_PEER_ADDRESS", session.peer_address().ip())
+            .with("LLP_PEER_PORT", session.peer_address().port())
+            .with("LLP_USER", user.unwrap_or_default())
+            .with("LLP_TUNNEL_ADDRESS", tunnel_address.unwrap_or_default())
+            .with("LLP_TRANSPORT", transport)
+            .with("LLP_LABELS", session.labels().await)
+    }
+
+    pub fn with(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.0.push((name, value.to_string()));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(key, _)| *key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Operator scripts run on session events, like OpenVPN's client-connect
+///
+/// Scripts get no arguments, only the `LLP_*` environment, and are killed
+/// after the timeout. At most `max_concurrent` run at once: connect and
+/// disconnect scripts wait for a slot, auth failure scripts are skipped
+/// while all slots are busy so a flood of bad handshakes can't queue up.
+#[derive(Debug)]
+pub struct Hooks {
+    on_connect: Option<PathBuf>,
+    on_disconnect: Option<PathBuf>,
+    on_auth_fail: Option<PathBuf>,
+    timeout: Duration,
+    slots: Arc<Semaphore>,
+}
+
+impl Hooks {
+    /// Hooks of the config, None if no script is configured
+    pub fn new(config: &HooksConfig) -> Option<Self> {
+        if config.on_connect.is_none() && config.on_disconnect.is_none() && config.on_auth_fail.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            on_connect: config.on_connect.as_ref().map(PathBuf::from),
+            on_disconnect: config.on_disconnect.as_ref().map(PathBuf::from),
+            on_auth_fail: config.on_auth_fail.as_ref().map(PathBuf::from),
+            timeout: Duration::from_secs(config.timeout),
+            slots: Arc::new(Semaphore::new(config.max_concurrent)),
+        })
+    }
+
+    /// Run the connect script; an error refuses the session
+    pub async fn connect(&self, env: &HookEnv) -> Result<()> {
+        let Some(script) = &self.on_connect else {
+            return Ok(());
+        };
+        let _slot = self.slots.acquire().await.expect("hook slots are never closed");
+        self.run(HookEvent::Connect, script, env).await
+    }
+
+    /// Run the disconnect script in the background
+    pub fn disconnect(self: &Arc<Self>, env: HookEnv) {
+        if self.on_disconnect.is_none() {
+            return;
+        }
+        let hooks = self.clone();
+        tokio::spawn(async move {
+            let _slot = hooks.slots.acquire().await.expect("hook slots are never closed");
+            if let Some(script) = &hooks.on_disconnect {
+                if let Err(e) = hooks.run(HookEvent::Disconnect, script, &env).await {
+                    warn!(code = e.code(), "{}", e);
+                }
+            }
+        });
+    }
+
+    /// Run the auth failure script in the background, unless all slots are busy
+    pub fn auth_fail(self: &Arc<Self>, env: HookEnv) {
+        let Some(script) = self.on_auth_fail.clone() else {
+            return;
+        };
+        let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+            warn!("Skipping auth-fail hook for session {}, all hook slots busy", env.get("LLP_SESSION_ID").unwrap_or("?"));
+            return;
+        };
+        let hooks = self.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            if let Err(e) = hooks.run(HookEvent::AuthFail, &script, &env).await {
+                warn!(code = e.code(), "{}", e);
+            }
+        });
+    }
+
+    async fn run(&self, event: HookEvent, script: &Path, env: &HookEnv) -> Result<()> {
+        let failed = |reason: String| LostLoveError::Hook { event: event.name(), reason };
+
+        debug!("Running {} hook {}", event.name(), script.display());
+        let child = Command::new(script)
+            .env("LLP_EVENT", event.name())
+            .envs(env.0.iter().map(|(name, value)| (name, value)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| failed(format!("{}: {}", script.display(), e)))?;
+
+        // Dropping the child on timeout kills it
+        let output = match time::timeout(self.timeout, child.wait_with_output()).await {
+            Ok(output) => output.map_err(|e| failed(format!("{}: {}", script.display(), e)))?,
+            Err(_) => {
+                return Err(failed(format!(
+                    "{} killed after {}s",
+                    script.display(),
+                    self.timeout.as_secs()
+                )))
+            }
+        };
+
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr[..output.stderr.len().min(MAX_STDERR)]);
+        Err(failed(format!("{} {}: {}", script.display(), output.status, stderr.trim())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn script(name: &str, body: &str) -> String {
+        let path = std::env::temp_dir().join(format!("llp-hook-{}-{}.sh", name, uuid::Uuid::new_v4()));
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn hooks(on_connect: String, timeout: u64) -> Hooks {
+        let config = HooksConfig {
+            on_connect: Some(on_connect),
+            timeout,
+            ..HooksConfig::default()
+        };
+        Hooks::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_hook_sees_environment() {
+        let check = script(
+            "connect",
+            r#"[ "$LLP_EVENT" = connect ] && [ "$LLP_USER" = alice ] || { echo "got $LLP_EVENT $LLP_USER" >&2; exit 1; }"#,
+        );
+        let hooks = hooks(check.clone(), 5);
+
+        let env = HookEnv::default().with("LLP_USER", "alice");
+        assert!(hooks.connect(&env).await.is_ok());
+
+        let err = hooks.connect(&HookEnv::default().with("LLP_USER", "bob")).await.unwrap_err();
+        assert_eq!(err.code(), "hook");
+        assert!(err.to_string().contains("got connect bob"));
+        std::fs::remove_file(check).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_hook_times_out() {
+        let slow = script("slow", "sleep 5");
+        let hooks = hooks(slow.clone(), 1);
+
+        let started = std::time::Instant::now();
+        let err = hooks.connect(&HookEnv::default()).await.unwrap_err();
+        assert!(err.to_string().contains("killed after 1s"));
+        assert!(started.elapsed() < Duration::from_secs(3));
+        std::fs::remove_file(slow).unwrap();
+    }
+
+    #[test]
+    fn test_no_scripts_no_hooks() {
+        assert!(Hooks::new(&HooksConfig::default()).is_none());
+        assert_eq!(HookEvent::AuthFail.name(), "auth-fail");
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::error::{LostLoveError, Result};
+
+/// Attempts at locking a file another instance keeps removing under us
+const LOCK_ATTEMPTS: usize = 5;
+
+/// Locks held while the server runs, so a second instance started on the
+/// same host can't take over the TUN device, address pool and NAT rules
+///
+/// The pid file is locked for the instance itself and a `tun-<name>.lock`
+/// next to it for the device, so instances with different pid files still
+/// refuse to share a device. Both files hold the PID, config path and TUN
+/// name of their holder for the error the next instance reports. Locks are
+/// `flock`s, released by the kernel if the server dies, so a stale file left
+/// by a crash never blocks a restart.
+#[derive(Debug)]
+pub struct InstanceLock {
+    files: Vec<(PathBuf, File)>,
+}
+
+impl InstanceLock {
+    /// Lock the pid file of the config, or nothing if it is empty
+    pub fn acquire(config: &Config, config_path: &str) -> Result<Self> {
+        let mut lock = Self { files: Vec::new() };
+        if config.server.pid_file.is_empty() {
+            return Ok(lock);
+        }
+
+        let pid_file = PathBuf::from(&config.server.pid_file);
+        let tun_name = &config.network.tun_name;
+        let tun_lock = pid_file
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .join(format!("tun-{}.lock", tun_name));
+        let contents = format!("{}\nconfig {}\ntun {}\n", std::process::id(), config_path, tun_name);
+
+        // Dropping the partial lock on error releases what was taken
+        for path in [pid_file, tun_lock] {
+            let file = lock_file(&path)?;
+            write_holder(&file, &contents)?;
+            lock.files.push((path, file));
+        }
+        Ok(lock)
+    }
+
+    /// Paths locked
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(path, _)| path.as_path())
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Removed while still locked, the next instance creates a new file
+        for (path, _) in &self.files {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Open and lock `path`, or report the instance holding it
+fn lock_file(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    for _ in 0..LOCK_ATTEMPTS {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(error.into());
+            }
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            return Err(LostLoveError::InstanceLocked {
+                path: path.display().to_string(),
+                holder: describe_holder(&holder),
+            });
+        }
+
+        // The holder may have removed the file between our open and lock;
+        // a lock on the removed file protects nothing, so open it again
+        let locked = file.metadata()?;
+        match fs::metadata(path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => return Ok(file),
+            Ok(_) => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(LostLoveError::InstanceLocked {
+        path: path.display().to_string(),
+        holder: "an instance that keeps replacing it".to_string(),
+    })
+}
+
+fn write_holder(mut file: &File, contents: &str) -> Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// "pid 1234 (config /etc/lostlove/server.toml, TUN hfp0)" from a lock file
+fn describe_holder(contents: &str) -> String {
+    let mut lines = contents.lines();
+    let Some(pid) = lines.next().filter(|pid| !pid.is_empty()) else {
+        return "an instance that hasn't written its PID yet".to_string();
+    };
+    let details: Vec<String> = lines
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some(match key {
+                "tun" => format!("TUN {}", value),
+                _ => format!("{} {}", key, value),
+            })
+        })
+        .collect();
+
+    if details.is_empty() {
+        format!("pid {}", pid)
+    } else {
+        format!("pid {} ({})", pid, details.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path, tun_name: &str) -> Config {
+        let mut config = Config::default_for_testing();
+        config.server.pid_file = dir.join("server.pid").to_string_lossy().into_owned();
+        config.network.tun_name = tun_name.to_string();
+        config
+    }
+
+    #[test]
+    fn test_second_instance_refused() {
+        let dir = std::env::temp_dir().join(format!("llp-lock-{}", uuid::Uuid::new_v4()));
+        let first = InstanceLock::acquire(&config(&dir, "hfp0"), "/etc/first.toml").unwrap();
+        assert_eq!(first.paths().count(), 2);
+        assert!(fs::read_to_string(dir.join("tun-hfp0.lock")).unwrap().starts_with(&std::process::id().to_string()));
+
+        let err = InstanceLock::acquire(&config(&dir, "hfp1"), "/etc/second.toml").unwrap_err();
+        assert_eq!(err.code(), "instance_locked");
+        let expected = format!("pid {} (config /etc/first.toml, TUN hfp0)", std::process::id());
+        assert!(err.to_string().contains(&expected), "{}", err);
+
+        // Released on drop, and the files go with it
+        drop(first);
+        assert!(!dir.join("server.pid").exists());
+        let second = InstanceLock::acquire(&config(&dir, "hfp0"), "/etc/second.toml").unwrap();
+        drop(second);
+        fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn test_device_locked_across_pid_files() {
+        let dir = std::env::temp_dir().join(format!("llp-lock-{}", uuid::Uuid::new_v4()));
+        let first = InstanceLock::acquire(&config(&dir, "hfp0"), "/etc/first.toml").unwrap();
+
+        let mut other = config(&dir, "hfp0");
+        other.server.pid_file = dir.join("other.pid").to_string_lossy().into_owned();
+        let err = InstanceLock::acquire(&other, "/etc/second.toml").unwrap_err();
+        assert!(err.to_string().contains("tun-hfp0.lock"), "{}", err);
+        // The pid file taken before the device failed is released again
+        assert!(!dir.join("other.pid").exists());
+
+        let mut disabled = other;
+        disabled.server.pid_file = String::new();
+        assert_eq!(InstanceLock::acquire(&disabled, "/etc/second.toml").unwrap().paths().count(), 0);
+
+        drop(first);
+        fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::error::{LostLoveError, Result};
+
+/// Maximum labels attached to one session
+pub const MAX_LABELS: usize = 16;
+
+/// Maximum label key length (fits a Prometheus label name)
+const MAX_KEY_LEN: usize = 63;
+
+/// Maximum label value length
+const MAX_VALUE_LEN: usize = 128;
+
+/// Key/value labels attached to a session, e.g. `team=ops`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Labels(BTreeMap<String, String>);
+
+impl Labels {
+    /// Create an empty label set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a label, replacing any previous value for the key
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<()> {
+        validate_key(key)?;
+        validate_value(key, value)?;
+
+        if !self.0.contains_key(key) && self.0.len() >= MAX_LABELS {
+            return Err(LostLoveError::InvalidLabel(format!(
+                "at most {} labels per session",
+                MAX_LABELS
+            )));
+        }
+
+        self.0.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    /// Remove a label, returning its value
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.0.remove(key)
+    }
+
+    /// Get a label value
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Check if no labels are set
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get labels as a sorted map
+    pub fn as_map(&self) -> &BTreeMap<String, String> {
+        &self.0
+    }
+}
+
+impl fmt::Display for Labels {
+    /// `key=value` pairs separated by commas, as written to logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check a label key: lowercase letter, then lowercase letters, digits or `_`
+pub fn validate_key(key: &str) -> Result<()> {
+    let valid = key.len() <= MAX_KEY_LEN
+        && key.starts_with(|c: char| c.is_ascii_lowercase())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+
+    if !valid {
+        return Err(LostLoveError::InvalidLabel(format!(
+            "invalid key {:?}, expected [a-z][a-z0-9_]* up to {} characters",
+            key, MAX_KEY_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Check a label value: non-empty printable text
+fn validate_value(key: &str, value: &str) -> Result<()> {
+    if value.is_empty() || value.len() > MAX_VALUE_LEN || value.chars().any(char::is_control) {
+        return Err(LostLoveError::InvalidLabel(format!(
+            "invalid value for {:?}, expected 1 to {} printable characters",
+            key, MAX_VALUE_LEN
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_display() {
+        let mut labels = Labels::new();
+        labels.insert("team", "ops").unwrap();
+        labels.insert("device", "laptop-7").unwrap();
+        labels.insert("team", "dev").unwrap();
+
+        assert_eq!(labels.get("team"), Some("dev"));
+        assert_eq!(labels.to_string(), "device=laptop-7,team=dev");
+        assert_eq!(labels.remove("device").as_deref(), Some("laptop-7"));
+        assert_eq!(labels.to_string(), "team=dev");
+    }
+
+    #[test]
+    fn test_invalid_labels() {
+        let mut labels = Labels::new();
+        assert!(labels.insert("Team", "ops").is_err());
+        assert!(labels.insert("1team", "ops").is_err());
+        assert!(labels.insert("__name__", "ops").is_err());
+        assert!(labels.insert("team", "").is_err());
+        assert!(labels.insert("team", "a\nb").is_err());
+        assert!(labels.is_empty());
+    }
+
+    #[test]
+    fn test_label_count_is_capped() {
+        let mut labels = Labels::new();
+        for i in 0..MAX_LABELS {
+            labels.insert(&format!("k{}", i), "v").unwrap();
+        }
+
+        assert!(labels.insert("extra", "v").is_err());
+        // Replacing an existing key still works at the cap
+        labels.insert("k0", "w").unwrap();
+    }
+}
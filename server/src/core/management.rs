@@ -0,0 +1,346 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::config::ManagementConfig;
+use crate::core::channels::{ChannelHandler, Channels};
+use crate::core::session::SessionId;
+use crate::error::{LostLoveError, Result};
+use crate::network::port_hopping::unix_now;
+use crate::protocol::management::{
+    user_key, ManagementCommand, ManagementOrder, ManagementReply, SignedOrder, COMMAND_VALIDITY,
+};
+
+/// Channel clients open to accept management commands
+pub const MANAGE_CHANNEL: &str = "manage";
+
+/// Signed commands from the operator to clients that opted in
+///
+/// A client opts in by opening the `manage` channel; the server sends
+/// nothing to clients that didn't. Every command is signed with the
+/// management key from the client's profile and names the session it is
+/// for, so a client only acts on commands meant for it. Each user's key is
+/// derived from `admin.management.key` (see `user_key`). The command, the
+/// operator's request and the client's answer or silence are all written
+/// to the audit log.
+pub struct Management {
+    key: Vec<u8>,
+    reply_timeout: Duration,
+    audit: AuditLog,
+    channels: Weak<Channels>,
+    next_id: AtomicU64,
+    /// Sessions whose clients opened the channel
+    opted_in: DashMap<SessionId, ()>,
+    pending: DashMap<u64, Pending>,
+}
+
+/// A command waiting for its answer
+struct Pending {
+    session_id: SessionId,
+    reply: oneshot::Sender<ManagementReply>,
+}
+
+impl Management {
+    /// Management on the `manage` channel of `channels`, None if disabled
+    pub fn register(config: &ManagementConfig, channels: &Arc<Channels>) -> Result<Option<Arc<Self>>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let key = hex::decode(&config.key)
+            .map_err(|_| LostLoveError::Config("admin.management.key must be hex".to_string()))?;
+
+        let management = Arc::new(Self {
+            key,
+            reply_timeout: Duration::from_secs(config.reply_timeout),
+            audit: AuditLog::open(&config.audit_log)?,
+            channels: Arc::downgrade(channels),
+            next_id: AtomicU64::new(1),
+            opted_in: DashMap::new(),
+            pending: DashMap::new(),
+        });
+        channels.register(MANAGE_CHANNEL, management.clone())?;
+        Ok(Some(management))
+    }
+
+    /// Whether a session's client accepts commands
+    pub fn opted_in(&self, session_id: &SessionId) -> bool {
+        self.opted_in.contains_key(session_id)
+    }
+
+    /// Send `command` to a session's client and wait for its answer
+    pub async fn send(
+        &self,
+        session_id: &SessionId,
+        user: Option<&str>,
+        command: ManagementCommand,
+    ) -> Result<ManagementReply> {
+        if !self.opted_in(session_id) {
+            return Err(LostLoveError::Admin(format!(
+                "Session {} hasn't opted in to management",
+                session_id
+            )));
+        }
+        // Only a user's profile has the key its client verifies with
+        let Some(user_name) = user else {
+            return Err(LostLoveError::Admin(format!(
+                "Session {} has no user to sign commands for",
+                session_id
+            )));
+        };
+        let channels = self
+            .channels
+            .upgrade()
+            .ok_or_else(|| LostLoveError::Connection("Channels are shut down".to_string()))?;
+
+        let now = unix_now();
+        let order = ManagementOrder {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            session_id: session_id.to_string(),
+            issued_at: now,
+            expires_at: now + COMMAND_VALIDITY,
+            command,
+        };
+        let signed = SignedOrder::sign(&order, &user_key(&self.key, user_name)?)?;
+        let audit = |event: &'static str, reply: Option<&ManagementReply>| {
+            self.audit.record(&AuditRecord {
+                time: unix_now(),
+                id: order.id,
+                session_id: &order.session_id,
+                user,
+                command: &order.command,
+                event,
+                ok: reply.map(|reply| reply.ok),
+                output: reply.map(|reply| reply.output.as_str()),
+            });
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(
+            order.id,
+            Pending {
+                session_id: *session_id,
+                reply: sender,
+            },
+        );
+        audit("sent", None);
+        if let Err(e) = channels.send(session_id, MANAGE_CHANNEL, signed.to_bytes()?) {
+            self.pending.remove(&order.id);
+            audit("undelivered", None);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(self.reply_timeout, receiver).await {
+            Ok(Ok(reply)) => {
+                audit("answered", Some(&reply));
+                Ok(reply)
+            }
+            Ok(Err(_)) => {
+                audit("session-closed", None);
+                Err(LostLoveError::SessionNotFound(session_id.to_string()))
+            }
+            Err(_) => {
+                self.pending.remove(&order.id);
+                audit("timed-out", None);
+                Err(LostLoveError::Admin(format!(
+                    "Client didn't answer within {}s",
+                    self.reply_timeout.as_secs()
+                )))
+            }
+        }
+    }
+}
+
+impl ChannelHandler for Management {
+    fn opened(&self, session_id: &SessionId) {
+        info!("Session {} accepts management commands", session_id);
+        self.opted_in.insert(*session_id, ());
+    }
+
+    fn closed(&self, session_id: &SessionId) {
+        self.opted_in.remove(session_id);
+        // Dropping the senders wakes whoever waits for these answers
+        self.pending.retain(|_, pending| pending.session_id != *session_id);
+    }
+
+    fn receive(&self, session_id: &SessionId, data: &[u8]) -> Result<Option<Bytes>> {
+        let reply = ManagementReply::from_bytes(data)?;
+        let pending = self
+            .pending
+            .remove_if(&reply.id, |_, pending| pending.session_id == *session_id)
+            .ok_or_else(|| LostLoveError::Admin(format!("Answer to unknown management command {}", reply.id)))?;
+        let _ = pending.1.reply.send(reply);
+        Ok(None)
+    }
+}
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    time: u64,
+    id: u64,
+    session_id: &'a str,
+    user: Option<&'a str>,
+    #[serde(flatten)]
+    command: &'a ManagementCommand,
+    /// sent, undelivered, answered, timed-out or session-closed
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ok: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<&'a str>,
+}
+
+/// Append-only JSON lines file of management commands, or only the log
+struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    fn open(path: &str) -> Result<Self> {
+        if path.is_empty() {
+            return Ok(Self { file: None });
+        }
+        let path = Path::new(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    fn record(&self, record: &AuditRecord) {
+        let Ok(line) = serde_json::to_string(record) else {
+            return;
+        };
+        info!(target: "llp::management", "{}", line);
+
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            if let Err(e) = writeln!(file, "{}", line) {
+                warn!("Failed to write management audit log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::management::OrderVerifier;
+    use std::path::PathBuf;
+
+    fn setup(audit_log: &Path) -> (Arc<Channels>, Arc<Management>) {
+        let config = ManagementConfig {
+            enabled: true,
+            key: "11".repeat(32),
+            audit_log: audit_log.to_string_lossy().into_owned(),
+            reply_timeout: 5,
+        };
+        let channels = Arc::new(Channels::new());
+        let management = Management::register(&config, &channels).unwrap().unwrap();
+        (channels, management)
+    }
+
+    fn audit_path() -> PathBuf {
+        std::env::temp_dir().join(format!("llp-management-{}.log", uuid::Uuid::new_v4()))
+    }
+
+    fn audit_events(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["event"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_disabled_registers_nothing() {
+        let channels = Arc::new(Channels::new());
+        assert!(Management::register(&ManagementConfig::default(), &channels).unwrap().is_none());
+        assert!(!channels.is_registered(MANAGE_CHANNEL));
+    }
+
+    #[tokio::test]
+    async fn test_signed_command_answered_and_audited() {
+        let path = audit_path();
+        let (channels, management) = setup(&path);
+        let session_id = SessionId::new();
+        let mut mailbox = channels.attach(&session_id);
+
+        let err = management.send(&session_id, None, ManagementCommand::Diagnostics).await.unwrap_err();
+        assert!(err.to_string().contains("hasn't opted in"), "{}", err);
+        channels.opened(&session_id, MANAGE_CHANNEL);
+
+        // The client checks the signature and answers
+        let client = {
+            let channels = channels.clone();
+            tokio::spawn(async move {
+                let message = mailbox.recv().await.unwrap();
+                let signed = SignedOrder::from_bytes(&message.data).unwrap();
+                let key = user_key(&[0x11; 32], "alice").unwrap();
+                let mut verifier = OrderVerifier::new(&key, &session_id.to_string());
+                let order = verifier.verify(&signed, unix_now()).unwrap();
+                let reply = ManagementReply {
+                    id: order.id,
+                    ok: true,
+                    output: "routes: 10.20.0.0/16".to_string(),
+                };
+                channels.receive(&session_id, MANAGE_CHANNEL, &reply.to_bytes().unwrap());
+                order
+            })
+        };
+
+        // Commands are signed with the key of the session's user
+        let err = management.send(&session_id, None, ManagementCommand::Diagnostics).await.unwrap_err();
+        assert!(err.to_string().contains("no user"), "{}", err);
+
+        let reply = management
+            .send(&session_id, Some("alice"), ManagementCommand::Diagnostics)
+            .await
+            .unwrap();
+        assert!(reply.ok);
+        assert_eq!(client.await.unwrap().command, ManagementCommand::Diagnostics);
+
+        assert_eq!(audit_events(&path), ["sent", "answered"]);
+        let log = fs::read_to_string(&path).unwrap();
+        assert!(log.contains(r#""user":"alice""#) && log.contains(r#""command":"diagnostics""#), "{}", log);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_client_times_out() {
+        let path = audit_path();
+        let (channels, management) = setup(&path);
+        let session_id = SessionId::new();
+        let _mailbox = channels.attach(&session_id);
+        channels.opened(&session_id, MANAGE_CHANNEL);
+
+        let command = ManagementCommand::Reconnect { delay_secs: 5 };
+        let err = management.send(&session_id, Some("alice"), command).await.unwrap_err();
+        assert_eq!(err.to_string(), "Admin error: Client didn't answer within 5s");
+
+        // Late or foreign answers are refused
+        let reply = ManagementReply {
+            id: 1,
+            ok: true,
+            output: String::new(),
+        };
+        assert!(management.receive(&session_id, &reply.to_bytes().unwrap()).is_err());
+        assert_eq!(audit_events(&path), ["sent", "timed-out"]);
+
+        // Ending the session withdraws the opt-in
+        channels.detach(&session_id);
+        assert!(!management.opted_in(&session_id));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::config::LimitsConfig;
+use crate::metrics::writer::MetricsWriter;
+
+/// What accounted memory holds, the label of its gauge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryUse {
+    /// Buffers packets are read into on TCP connections
+    ReadBuffers,
+    /// Server bytes DNS and ICMP tunnels hold until the client polls
+    BridgeBuffers,
+}
+
+impl MemoryUse {
+    pub const ALL: [MemoryUse; 2] = [MemoryUse::ReadBuffers, MemoryUse::BridgeBuffers];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryUse::ReadBuffers => "read_buffers",
+            MemoryUse::BridgeBuffers => "bridge_buffers",
+        }
+    }
+}
+
+/// Memory held by connections, against a global and a per-connection cap
+///
+/// Connections charge what they buffer. Growth past a cap is refused, and
+/// the holder sheds load instead of allocating: bridges stop reading from
+/// the listener until the client catches up, and a server at the global cap
+/// refuses new sessions and hibernates idle ones early. Memory already
+/// allocated is accounted even when over a cap.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// Bytes for all connections together, 0 for no cap
+    limit: usize,
+    /// Bytes per charge, 0 for no cap
+    connection_limit: usize,
+    total: AtomicUsize,
+    used: [AtomicUsize; MemoryUse::ALL.len()],
+    refused: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self::with_limits(
+            config.max_memory_mb as usize * 1024 * 1024,
+            config.max_connection_memory_kb as usize * 1024,
+        )
+    }
+
+    pub fn with_limits(limit: usize, connection_limit: usize) -> Self {
+        Self {
+            limit,
+            connection_limit,
+            total: AtomicUsize::new(0),
+            used: Default::default(),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    /// Budget without caps, accounting only
+    pub fn unlimited() -> Self {
+        Self::with_limits(0, 0)
+    }
+
+    /// Empty charge for one connection's use of memory
+    pub fn charge(self: &Arc<Self>, usage: MemoryUse) -> MemoryCharge {
+        MemoryCharge {
+            budget: self.clone(),
+            usage,
+            bytes: 0,
+        }
+    }
+
+    /// Bytes charged by all connections
+    pub fn used(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Whether the global cap is reached
+    pub fn exhausted(&self) -> bool {
+        self.limit > 0 && self.used() >= self.limit
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Charges refused growth since start
+    pub fn refused_count(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, usage: MemoryUse, bytes: usize) {
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+        self.used[usage as usize].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sub(&self, usage: MemoryUse, bytes: usize) {
+        self.total.fetch_sub(bytes, Ordering::Relaxed);
+        self.used[usage as usize].fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Add `bytes` unless that takes the total over the cap
+    fn try_add(&self, usage: MemoryUse, bytes: usize) -> bool {
+        let reserved = self.total.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+            let total = total + bytes;
+            (self.limit == 0 || total <= self.limit).then_some(total)
+        });
+        if reserved.is_err() {
+            return false;
+        }
+        self.used[usage as usize].fetch_add(bytes, Ordering::Relaxed);
+        true
+    }
+
+    pub fn render(&self, writer: &mut MetricsWriter) {
+        writer.header("llp_memory_used_bytes", "Memory charged by connections, by use", "gauge");
+        for usage in MemoryUse::ALL {
+            let used = self.used[usage as usize].load(Ordering::Relaxed);
+            writer.sample("llp_memory_used_bytes", &[("use", usage.name())], used);
+        }
+        writer.gauge(
+            "llp_memory_limit_bytes",
+            "Cap on memory charged by all connections, 0 if none",
+            self.limit,
+        );
+        writer.counter(
+            "llp_memory_refused_total",
+            "Buffer growth refused for going over a memory cap",
+            self.refused_count(),
+        );
+    }
+}
+
+/// One connection's share of a `MemoryBudget`, given back when dropped
+#[derive(Debug)]
+pub struct MemoryCharge {
+    budget: Arc<MemoryBudget>,
+    usage: MemoryUse,
+    bytes: usize,
+}
+
+impl MemoryCharge {
+    /// Bytes charged
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Charge `bytes` instead; growth past either cap is refused and
+    /// leaves the charge as it was
+    pub fn try_resize(&mut self, bytes: usize) -> bool {
+        if bytes <= self.bytes {
+            self.resize(bytes);
+            return true;
+        }
+
+        let budget = &self.budget;
+        let within_connection = budget.connection_limit == 0 || bytes <= budget.connection_limit;
+        if !within_connection || !budget.try_add(self.usage, bytes - self.bytes) {
+            budget.refused.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.bytes = bytes;
+        true
+    }
+
+    /// Charge `bytes` already allocated, over the caps or not
+    pub fn resize(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.budget.add(self.usage, bytes - self.bytes);
+        } else {
+            self.budget.sub(self.usage, self.bytes - bytes);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.budget.sub(self.usage, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caps_refuse_growth() {
+        let budget = Arc::new(MemoryBudget::with_limits(100, 60));
+        let mut first = budget.charge(MemoryUse::BridgeBuffers);
+        let mut second = budget.charge(MemoryUse::BridgeBuffers);
+
+        assert!(first.try_resize(60));
+        assert!(!first.try_resize(61));
+        assert!(!second.try_resize(50));
+        assert!(second.try_resize(40));
+        assert!(budget.exhausted());
+        assert_eq!(budget.refused_count(), 2);
+
+        // Shrinking always works and makes room again
+        assert!(first.try_resize(10));
+        assert!(second.try_resize(50));
+        assert_eq!(budget.used(), 60);
+
+        drop(first);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_allocated_memory_accounted_over_cap() {
+        let budget = Arc::new(MemoryBudget::with_limits(10, 0));
+        let mut buffer = budget.charge(MemoryUse::ReadBuffers);
+        buffer.resize(4096);
+        assert!(budget.exhausted());
+
+        let mut writer = MetricsWriter::new();
+        budget.render(&mut writer);
+        let output = writer.finish();
+        assert!(output.contains("llp_memory_used_bytes{use=\"read_buffers\"} 4096\n"));
+        assert!(output.contains("llp_memory_used_bytes{use=\"bridge_buffers\"} 0\n"));
+        assert!(output.contains("llp_memory_limit_bytes 10\n"));
+    }
+}
@@ -1,7 +1,42 @@
 pub mod server;
 pub mod connection;
 pub mod session;
+pub mod packet_trace;
+pub mod error_throttle;
+pub mod config_push;
+pub mod accounting;
+pub mod rate_limiter;
+pub mod labels;
+pub mod drain;
+pub mod federation;
+pub mod failover;
+pub mod fleet;
+pub mod signaling;
+pub mod decoy;
+pub mod rekey;
+pub mod clock;
+pub mod overload;
+pub mod accept;
+pub mod runtime;
+pub mod supervisor;
+pub mod stats;
+pub mod sweeper;
+pub mod engine;
+pub mod preflight;
+pub mod quality;
+pub mod diagnose;
+pub mod hooks;
+pub mod priority;
+pub mod memory;
+pub mod instance_lock;
+pub mod abuse;
+pub mod channels;
+pub mod file_transfer;
+pub mod management;
+pub mod portal;
+// Deterministic simulation for tests only, never compiled into the server
+#[cfg(test)]
+pub mod simulation;
 
-pub use server::Server;
-pub use connection::{Connection, ConnectionManager};
-pub use session::{Session, SessionId};
+pub use preflight::Preflight;
+pub use instance_lock::InstanceLock;
@@ -0,0 +1,178 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::OverloadConfig;
+use crate::core::connection::ConnectionManager;
+use crate::core::memory::MemoryBudget;
+
+/// First pause of the accept loop once sessions are being shed
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Longest pause between accepts while saturated
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Load shedding for new sessions
+///
+/// A saturated server answers handshakes with a `server-busy` failure
+/// carrying a retry delay instead of creating connections it then fails,
+/// and slows its accept loop down until there's room again.
+pub struct Overload {
+    connection_manager: Arc<ConnectionManager>,
+    memory: Option<Arc<MemoryBudget>>,
+    max_load: Option<f64>,
+    retry_after: u64,
+    backoff_ms: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl Overload {
+    /// Create from config
+    pub fn new(config: &OverloadConfig, connection_manager: Arc<ConnectionManager>) -> Self {
+        Self {
+            connection_manager,
+            memory: None,
+            max_load: config.max_load,
+            retry_after: config.retry_after,
+            backoff_ms: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Also refuse new sessions while connection memory is at its cap
+    pub fn with_memory(mut self, memory: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Why a new session has to be refused right now, None if there's room
+    pub fn check(&self) -> Option<String> {
+        let load = self.max_load.and_then(|_| load_per_cpu());
+        self.check_with_load(load)
+    }
+
+    fn check_with_load(&self, load: Option<f64>) -> Option<String> {
+        let manager = &self.connection_manager;
+        if manager.active_count() >= manager.max_connections() {
+            return Some(format!("Server is at capacity ({} sessions)", manager.max_connections()));
+        }
+        if let Some(memory) = self.memory.as_ref().filter(|memory| memory.exhausted()) {
+            return Some(format!("Connection memory is at its cap of {} MiB", memory.limit() >> 20));
+        }
+
+        match (self.max_load, load) {
+            (Some(max_load), Some(load)) if load > max_load => {
+                Some(format!("Server load {:.2} per CPU is above {:.2}", load, max_load))
+            }
+            _ => None,
+        }
+    }
+
+    /// Seconds refused clients should wait before retrying
+    pub fn retry_after(&self) -> u64 {
+        self.retry_after
+    }
+
+    /// Count a refused session
+    pub fn record_shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sessions refused since start
+    pub fn shed_count(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Pause before the next accept: doubles while sessions are being shed,
+    /// gone as soon as there's room
+    pub fn accept_backoff(&self) -> Option<Duration> {
+        let shedding = self.check().is_some();
+        self.next_backoff(shedding)
+    }
+
+    fn next_backoff(&self, shedding: bool) -> Option<Duration> {
+        if !shedding {
+            self.backoff_ms.store(0, Ordering::Relaxed);
+            return None;
+        }
+
+        let previous = Duration::from_millis(self.backoff_ms.load(Ordering::Relaxed));
+        let backoff = (previous * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+        self.backoff_ms.store(backoff.as_millis() as u64, Ordering::Relaxed);
+        Some(backoff)
+    }
+}
+
+/// 1-minute load average divided by the number of CPUs
+fn load_per_cpu() -> Option<f64> {
+    let mut load = [0f64; 1];
+    // SAFETY: writes at most one sample into a buffer of one
+    if unsafe { libc::getloadavg(load.as_mut_ptr(), 1) } != 1 {
+        return None;
+    }
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    Some(load[0] / cpus as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(max_connections: usize, max_load: Option<f64>) -> (Arc<ConnectionManager>, Overload) {
+        let manager = Arc::new(ConnectionManager::new(max_connections));
+        let config = OverloadConfig {
+            max_load,
+            ..OverloadConfig::default()
+        };
+        (manager.clone(), Overload::new(&config, manager))
+    }
+
+    #[test]
+    fn test_shed_at_capacity() {
+        let (manager, overload) = setup(1, None);
+        assert_eq!(overload.check_with_load(None), None);
+
+        let _conn = manager.create_connection("198.51.100.4:50000".parse().unwrap()).unwrap();
+        assert!(overload.check_with_load(None).unwrap().contains("capacity"));
+        assert_eq!(manager.saturation(), 1.0);
+    }
+
+    #[test]
+    fn test_shed_at_memory_cap() {
+        use crate::core::memory::MemoryUse;
+
+        let (_, overload) = setup(10, None);
+        let memory = Arc::new(MemoryBudget::with_limits(8192, 0));
+        let overload = overload.with_memory(memory.clone());
+
+        let mut buffer = memory.charge(MemoryUse::ReadBuffers);
+        buffer.resize(4096);
+        assert_eq!(overload.check_with_load(None), None);
+        buffer.resize(8192);
+        assert!(overload.check_with_load(None).unwrap().contains("memory"));
+        drop(buffer);
+        assert_eq!(overload.check_with_load(None), None);
+    }
+
+    #[test]
+    fn test_shed_above_load() {
+        let (_, overload) = setup(10, Some(2.0));
+        assert_eq!(overload.check_with_load(Some(1.5)), None);
+        assert!(overload.check_with_load(Some(2.5)).unwrap().contains("load"));
+        // Unknown load doesn't shed
+        assert_eq!(overload.check_with_load(None), None);
+
+        let (_, overload) = setup(10, None);
+        assert_eq!(overload.check_with_load(Some(100.0)), None);
+    }
+
+    #[test]
+    fn test_accept_backoff() {
+        let (_, overload) = setup(10, None);
+        let pauses: Vec<_> = (0..9).map(|_| overload.next_backoff(true).unwrap().as_millis()).collect();
+        assert_eq!(pauses, [10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+
+        assert_eq!(overload.next_backoff(false), None);
+        assert_eq!(overload.next_backoff(true), Some(MIN_ACCEPT_BACKOFF));
+    }
+}
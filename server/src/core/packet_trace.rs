@@ -0,0 +1,69 @@
+/// How a packet should be traced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceDecision {
+    /// Not traced
+    Skip,
+    /// Picked by the sampling knob, emitted at debug level
+    Sampled,
+    /// Session has tracing switched on via the admin API, emitted at info level
+    Forced,
+}
+
+/// Decides which packets of a connection get a per-packet trace event
+///
+/// A sample rate of N traces one packet in N; 0 disables sampling. Sessions
+/// with tracing forced on trace every packet regardless of the rate.
+#[derive(Debug)]
+pub struct PacketTraceSampler {
+    sample_rate: u64,
+    seen: u64,
+}
+
+impl PacketTraceSampler {
+    /// Create new sampler
+    pub fn new(sample_rate: u64) -> Self {
+        Self { sample_rate, seen: 0 }
+    }
+
+    /// Decide whether to trace the next packet
+    pub fn sample(&mut self, forced: bool) -> TraceDecision {
+        let index = self.seen;
+        self.seen = self.seen.wrapping_add(1);
+
+        if forced {
+            TraceDecision::Forced
+        } else if self.sample_rate > 0 && index.is_multiple_of(self.sample_rate) {
+            TraceDecision::Sampled
+        } else {
+            TraceDecision::Skip
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_disabled() {
+        let mut sampler = PacketTraceSampler::new(0);
+        assert!((0..100).all(|_| sampler.sample(false) == TraceDecision::Skip));
+    }
+
+    #[test]
+    fn test_sampling_rate() {
+        let mut sampler = PacketTraceSampler::new(10);
+        let sampled = (0..100)
+            .filter(|_| sampler.sample(false) == TraceDecision::Sampled)
+            .count();
+
+        assert_eq!(sampled, 10);
+    }
+
+    #[test]
+    fn test_forced_tracing() {
+        let mut sampler = PacketTraceSampler::new(0);
+        assert_eq!(sampler.sample(true), TraceDecision::Forced);
+        assert_eq!(sampler.sample(false), TraceDecision::Skip);
+    }
+}
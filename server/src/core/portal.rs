@@ -0,0 +1,208 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::core::channels::{ChannelHandler, Channels};
+use crate::core::connection::ConnectionManager;
+use crate::core::session::SessionId;
+use crate::error::Result;
+use crate::metrics::writer::MetricsWriter;
+use crate::network::port_hopping::unix_now;
+use crate::protocol::{PortalReport, PortalState};
+
+/// Channel clients report captive portals on
+pub const PORTAL_CHANNEL: &str = "portal";
+
+/// Latest captive portal report of one session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionPortal {
+    pub session_id: String,
+    pub user: Option<String>,
+    #[serde(flatten)]
+    pub report: PortalReport,
+    /// Unix seconds the report arrived
+    pub received_at: u64,
+}
+
+/// Captive portals clients found on their networks
+///
+/// Clients probe outside the tunnel for portals themselves and may route
+/// around it for `push.portal_bypass` seconds to sign in; what they found is
+/// reported here once the tunnel is up again. Only the latest report of each
+/// session is kept, and only while the session lasts.
+pub struct PortalReports {
+    connection_manager: Arc<ConnectionManager>,
+    sessions: DashMap<SessionId, SessionPortal>,
+    /// Reports by state, in `PortalState::ALL` order
+    reports: [AtomicU64; 3],
+    bypassed_secs: AtomicU64,
+}
+
+impl PortalReports {
+    /// Reports on the `portal` channel of `channels`
+    pub fn register(
+        channels: &Arc<Channels>,
+        connection_manager: Arc<ConnectionManager>,
+    ) -> Result<Arc<Self>> {
+        let portals = Arc::new(Self {
+            connection_manager,
+            sessions: DashMap::new(),
+            reports: Default::default(),
+            bypassed_secs: AtomicU64::new(0),
+        });
+        channels.register(PORTAL_CHANNEL, portals.clone())?;
+        Ok(portals)
+    }
+
+    /// Latest report of every session, newest first
+    pub fn snapshot(&self) -> Vec<SessionPortal> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+        sessions.sort_by(|a, b| {
+            b.received_at
+                .cmp(&a.received_at)
+                .then_with(|| a.session_id.cmp(&b.session_id))
+        });
+        sessions
+    }
+
+    fn user(&self, session_id: &SessionId) -> Option<String> {
+        let connection = self.connection_manager.get_connection(session_id)?;
+        let handshake = connection.handshake().try_read().ok()?;
+        handshake.user().map(str::to_string)
+    }
+
+    pub fn render(&self, writer: &mut MetricsWriter) {
+        writer.header(
+            "llp_captive_portal_reports_total",
+            "Captive portal reports from clients, by state found",
+            "counter",
+        );
+        for (state, count) in PortalState::ALL.iter().zip(&self.reports) {
+            writer.sample(
+                "llp_captive_portal_reports_total",
+                &[("state", state.name())],
+                count.load(Ordering::Relaxed),
+            );
+        }
+        writer.counter(
+            "llp_captive_portal_bypass_seconds_total",
+            "Seconds clients routed around the tunnel to sign in to captive portals",
+            self.bypassed_secs.load(Ordering::Relaxed),
+        );
+        writer.gauge(
+            "llp_captive_portal_sessions",
+            "Sessions whose latest report found a captive portal",
+            self.sessions
+                .iter()
+                .filter(|entry| entry.report.state == PortalState::Captive)
+                .count(),
+        );
+    }
+}
+
+impl ChannelHandler for PortalReports {
+    fn closed(&self, session_id: &SessionId) {
+        self.sessions.remove(session_id);
+    }
+
+    fn receive(&self, session_id: &SessionId, data: &[u8]) -> Result<Option<Bytes>> {
+        let report = PortalReport::from_bytes(data)?;
+        self.reports[report.state as usize].fetch_add(1, Ordering::Relaxed);
+        self.bypassed_secs
+            .fetch_add(report.bypassed_secs, Ordering::Relaxed);
+        if report.state == PortalState::Captive {
+            info!(
+                "Session {} is behind a captive portal{}, bypassed the tunnel for {}s",
+                session_id,
+                report
+                    .location
+                    .as_deref()
+                    .map(|location| format!(" at {}", location))
+                    .unwrap_or_default(),
+                report.bypassed_secs
+            );
+        }
+
+        self.sessions.insert(
+            *session_id,
+            SessionPortal {
+                session_id: session_id.to_string(),
+                user: self.user(session_id),
+                report,
+                received_at: unix_now(),
+            },
+        );
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(state: PortalState, bypassed_secs: u64) -> Vec<u8> {
+        let report = PortalReport {
+            state,
+            probe: "http://cp.cloudflare.com/generate_204".to_string(),
+            location: (state == PortalState::Captive)
+                .then(|| "http://wifi.example.net/login".to_string()),
+            detected_at: 1_700_000_000,
+            bypassed_secs,
+        };
+        report.to_bytes().unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_reports_kept_per_session() {
+        let channels = Arc::new(Channels::new());
+        let portals =
+            PortalReports::register(&channels, Arc::new(ConnectionManager::new(10))).unwrap();
+        assert!(channels.is_registered(PORTAL_CHANNEL));
+        let first = SessionId::new();
+        let second = SessionId::new();
+
+        portals
+            .receive(&first, &report(PortalState::Captive, 40))
+            .unwrap();
+        portals
+            .receive(&second, &report(PortalState::Captive, 0))
+            .unwrap();
+        portals
+            .receive(&second, &report(PortalState::Open, 20))
+            .unwrap();
+        assert!(portals.receive(&second, b"{}").is_err());
+
+        let mut snapshot = portals.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        snapshot.sort_by_key(|portal| portal.report.state == PortalState::Open);
+        assert_eq!(snapshot[0].session_id, first.to_string());
+        assert_eq!(
+            snapshot[0].report.location.as_deref(),
+            Some("http://wifi.example.net/login")
+        );
+        assert_eq!(snapshot[1].report.state, PortalState::Open);
+
+        let mut writer = MetricsWriter::new();
+        portals.render(&mut writer);
+        let output = writer.finish();
+        assert!(
+            output.contains("llp_captive_portal_reports_total{state=\"captive\"} 2\n"),
+            "{}",
+            output
+        );
+        assert!(output.contains("llp_captive_portal_reports_total{state=\"offline\"} 0\n"));
+        assert!(output.contains("llp_captive_portal_bypass_seconds_total 60\n"));
+        assert!(output.contains("llp_captive_portal_sessions 1\n"));
+
+        // Reports go with their session
+        channels.detach(&first);
+        assert_eq!(portals.snapshot().len(), 1);
+    }
+}
@@ -0,0 +1,444 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::{TcpListener, UdpSocket};
+use std::path::Path;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::core::accept;
+use crate::error::{LostLoveError, Result};
+
+/// Character device every TUN interface is created through
+const TUN_DEVICE: &str = "/dev/net/tun";
+
+/// One directory per network interface
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+
+/// How a failed check affects startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Logged, the server starts anyway
+    Warning,
+    /// The server would fail later on; startup stops here
+    Fatal,
+}
+
+/// A check that did not pass
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    /// What is wrong and how to fix it
+    pub message: String,
+}
+
+/// Environment checks run before anything is bound or created
+///
+/// A missing `/dev/net/tun` or a taken port otherwise shows up as a bare
+/// EPERM or EADDRINUSE from somewhere inside a listener; here each problem
+/// is reported once, with what to change.
+#[derive(Debug, Default)]
+pub struct Preflight {
+    findings: Vec<Finding>,
+}
+
+impl Preflight {
+    /// Check the host against `config`
+    ///
+    /// Also raises the soft descriptor limit as far as `max_connections` needs.
+    pub fn run(config: &Config) -> Self {
+        let mut preflight = Self::default();
+        if cfg!(target_os = "linux") {
+            preflight.check_tun_device(Path::new(TUN_DEVICE));
+            let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+            if let Some(capabilities) = effective_capabilities(&status) {
+                preflight.check_capabilities(config, capabilities);
+            }
+            preflight.check_interfaces(config, Path::new(SYS_CLASS_NET));
+            preflight.check_forwarding(
+                config,
+                fs::read_to_string("/proc/sys/net/ipv4/ip_forward").ok().as_deref(),
+                fs::read_to_string("/proc/sys/net/ipv6/conf/all/forwarding").ok().as_deref(),
+            );
+        }
+        preflight.check_ports(config);
+        preflight.check_fd_limit(config);
+        preflight
+    }
+
+    /// Check an unprivileged container: no TUN device, capabilities or
+    /// forwarding are needed there, only ports and descriptors
+    pub fn run_in_container(config: &Config) -> Self {
+        let mut preflight = Self::default();
+        preflight.check_ports(config);
+        preflight.check_fd_limit(config);
+        preflight
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// Whether any check failed fatally
+    pub fn is_fatal(&self) -> bool {
+        self.findings.iter().any(|finding| finding.severity == Severity::Fatal)
+    }
+
+    /// Log every finding
+    pub fn log(&self) {
+        for finding in &self.findings {
+            match finding.severity {
+                Severity::Warning => warn!("Preflight {}: {}", finding.check, finding.message),
+                Severity::Fatal => error!("Preflight {}: {}", finding.check, finding.message),
+            }
+        }
+        if self.findings.is_empty() {
+            info!("Preflight checks passed");
+        }
+    }
+
+    /// Fail with the names of the fatal checks
+    pub fn into_result(self) -> Result<()> {
+        let failed: Vec<&str> = self
+            .findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Fatal)
+            .map(|finding| finding.check)
+            .collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(LostLoveError::Preflight(failed.join(", ")))
+    }
+
+    fn report(&mut self, check: &'static str, severity: Severity, message: String) {
+        self.findings.push(Finding {
+            check,
+            severity,
+            message,
+        });
+    }
+
+    fn check_tun_device(&mut self, path: &Path) {
+        let Err(e) = OpenOptions::new().read(true).write(true).open(path) else {
+            return;
+        };
+        let message = match e.kind() {
+            io::ErrorKind::NotFound => format!(
+                "{} does not exist; load the tun module (modprobe tun) or pass the device into the container (--device /dev/net/tun)",
+                path.display()
+            ),
+            io::ErrorKind::PermissionDenied => format!(
+                "{} can't be opened; run as root or with CAP_NET_ADMIN",
+                path.display()
+            ),
+            _ => format!("{} can't be opened: {}", path.display(), e),
+        };
+        self.report("tun_device", Severity::Fatal, message);
+    }
+
+    fn check_capabilities(&mut self, config: &Config, capabilities: u64) {
+        let mut needed = vec![(
+            CAP_NET_ADMIN,
+            "CAP_NET_ADMIN",
+            "to create the TUN interface and program routes".to_string(),
+        )];
+        if config.network.icmp.enabled {
+            needed.push((CAP_NET_RAW, "CAP_NET_RAW", "for the ICMP transport's raw socket".to_string()));
+        }
+        let privileged = listeners(config).into_iter().find(|(_, _, port)| (1..1024).contains(port));
+        if let Some((name, address, _)) = privileged {
+            needed.push((CAP_NET_BIND_SERVICE, "CAP_NET_BIND_SERVICE", format!("to bind {} ({})", address, name)));
+        }
+
+        for (bit, capability, reason) in needed {
+            if capabilities & (1 << bit) == 0 {
+                self.report(
+                    "capabilities",
+                    Severity::Fatal,
+                    format!(
+                        "{} is missing, it is needed {}; run as root or grant it (AmbientCapabilities= under systemd, --cap-add in a container)",
+                        capability, reason
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_interfaces(&mut self, config: &Config, interfaces: &Path) {
+        if !interfaces.is_dir() {
+            return;
+        }
+
+        let tun = interfaces.join(&config.network.tun_name);
+        if tun.exists() {
+            if tun.join("tun_flags").exists() {
+                self.report(
+                    "interfaces",
+                    Severity::Warning,
+                    format!(
+                        "TUN interface {} already exists; make sure no other server is using it",
+                        config.network.tun_name
+                    ),
+                );
+            } else {
+                self.report(
+                    "interfaces",
+                    Severity::Fatal,
+                    format!(
+                        "interface {} exists and is not a TUN device; choose another network.tun_name",
+                        config.network.tun_name
+                    ),
+                );
+            }
+        }
+
+        if let Some(egress) = &config.network.egress.interface {
+            if !interfaces.join(egress).exists() {
+                self.report(
+                    "interfaces",
+                    Severity::Fatal,
+                    format!("egress interface {} does not exist; fix network.egress.interface", egress),
+                );
+            }
+        }
+    }
+
+    /// `ipv4` and `ipv6` are the sysctl files' contents, if readable
+    fn check_forwarding(&mut self, config: &Config, ipv4: Option<&str>, ipv6: Option<&str>) {
+        // With managed NAT the server owns the forwarding setup, and clients
+        // would connect fine but reach nothing
+        let severity = if config.network.manage_nat {
+            Severity::Fatal
+        } else {
+            Severity::Warning
+        };
+
+        let mut sysctls = vec![("net.ipv4.ip_forward", ipv4)];
+        if config.network.enable_ipv6 {
+            sysctls.push(("net.ipv6.conf.all.forwarding", ipv6));
+        }
+        for (sysctl, value) in sysctls {
+            if let Some(value) = value.map(str::trim).filter(|value| *value != "1") {
+                self.report(
+                    "forwarding",
+                    severity,
+                    format!(
+                        "{} is {}, tunnel traffic won't be forwarded; enable it with sysctl -w {}=1 (and in /etc/sysctl.d to keep it)",
+                        sysctl, value, sysctl
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_ports(&mut self, config: &Config) {
+        for (name, address, port) in listeners(config) {
+            if port == 0 {
+                continue;
+            }
+            let bound = match name {
+                "udp" | "dns" => UdpSocket::bind(&address).map(drop),
+                _ => TcpListener::bind(&address).map(drop),
+            };
+            let Err(e) = bound else {
+                continue;
+            };
+
+            let message = match e.kind() {
+                io::ErrorKind::AddrInUse => format!(
+                    "{} ({}) is already in use; stop the other process or pick another port",
+                    address, name
+                ),
+                io::ErrorKind::PermissionDenied => format!(
+                    "{} ({}) can't be bound; ports below 1024 need CAP_NET_BIND_SERVICE",
+                    address, name
+                ),
+                io::ErrorKind::AddrNotAvailable => format!(
+                    "{} ({}) can't be bound; the address does not belong to this host",
+                    address, name
+                ),
+                _ => format!("{} ({}) can't be bound: {}", address, name, e),
+            };
+            self.report("ports", Severity::Fatal, message);
+        }
+    }
+
+    fn check_fd_limit(&mut self, config: &Config) {
+        match accept::raise_fd_limit(config.server.max_connections) {
+            Ok(limit) if !limit.is_sufficient() => self.report(
+                "fd_limit",
+                Severity::Warning,
+                format!(
+                    "open file limit of {} is below the {} that max_connections = {} needs; raise it (e.g. LimitNOFILE= under systemd)",
+                    limit.current, limit.needed, config.server.max_connections
+                ),
+            ),
+            Ok(_) => {}
+            Err(e) => self.report(
+                "fd_limit",
+                Severity::Warning,
+                format!("open file limit can't be read: {}", e),
+            ),
+        }
+    }
+}
+
+/// Sockets the server binds at startup: name, address and port
+fn listeners(config: &Config) -> Vec<(&'static str, String, u16)> {
+    let server = &config.server;
+    let mut listeners = vec![("tcp", format!("{}:{}", server.bind_address, server.port), server.port)];
+    if server.protocol != "tcp" {
+        listeners.push(("udp", format!("{}:{}", server.bind_address, server.port), server.port));
+    }
+    if config.network.dns.enabled {
+        let port = config.network.dns.port;
+        listeners.push(("dns", format!("{}:{}", server.bind_address, port), port));
+    }
+    let monitoring = &config.monitoring;
+    if monitoring.enable_metrics {
+        let port = monitoring.metrics_port;
+        listeners.push(("metrics", format!("{}:{}", monitoring.metrics_address, port), port));
+    }
+    listeners
+}
+
+/// Effective capability set from `/proc/self/status`
+fn effective_capabilities(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|value| u64::from_str_radix(value.trim(), 16).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checks(preflight: &Preflight) -> Vec<(&'static str, Severity)> {
+        preflight
+            .findings()
+            .iter()
+            .map(|finding| (finding.check, finding.severity))
+            .collect()
+    }
+
+    #[test]
+    fn test_effective_capabilities() {
+        let status = "Name:\tlostlove-server\nCapInh:\t0000000000000000\nCapEff:\t0000000000003000\n";
+        let capabilities = effective_capabilities(status).unwrap();
+        assert_ne!(capabilities & (1 << CAP_NET_ADMIN), 0);
+        assert_ne!(capabilities & (1 << CAP_NET_RAW), 0);
+        assert_eq!(capabilities & (1 << CAP_NET_BIND_SERVICE), 0);
+        assert_eq!(effective_capabilities("Name:\tx\n"), None);
+    }
+
+    #[test]
+    fn test_missing_capabilities() {
+        let mut config = Config::default_for_testing();
+        config.network.icmp.enabled = true;
+        config.server.port = 443;
+
+        let mut preflight = Preflight::default();
+        preflight.check_capabilities(&config, 1 << CAP_NET_ADMIN);
+        assert_eq!(checks(&preflight), [("capabilities", Severity::Fatal); 2]);
+        assert!(preflight.findings()[0].message.starts_with("CAP_NET_RAW is missing"));
+        assert!(preflight.findings()[1].message.contains("127.0.0.1:443 (tcp)"));
+
+        let mut preflight = Preflight::default();
+        preflight.check_capabilities(&config, u64::MAX);
+        assert!(preflight.findings().is_empty());
+    }
+
+    #[test]
+    fn test_missing_tun_device() {
+        let mut preflight = Preflight::default();
+        preflight.check_tun_device(Path::new("/nonexistent/net/tun"));
+        assert_eq!(checks(&preflight), [("tun_device", Severity::Fatal)]);
+        assert!(preflight.findings()[0].message.contains("modprobe tun"));
+        assert!(preflight.into_result().is_err());
+    }
+
+    #[test]
+    fn test_interfaces() {
+        let interfaces = std::env::temp_dir().join(format!("llp-preflight-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(interfaces.join("hfp0")).unwrap();
+        let mut config = Config::default_for_testing();
+        config.network.egress.interface = Some("eth1".to_string());
+
+        // A non-TUN interface by that name, and no eth1
+        let mut preflight = Preflight::default();
+        preflight.check_interfaces(&config, &interfaces);
+        assert_eq!(checks(&preflight), [("interfaces", Severity::Fatal); 2]);
+
+        // A leftover TUN device is only worth a warning
+        fs::write(interfaces.join("hfp0/tun_flags"), "0x5002\n").unwrap();
+        fs::create_dir(interfaces.join("eth1")).unwrap();
+        let mut preflight = Preflight::default();
+        preflight.check_interfaces(&config, &interfaces);
+        assert_eq!(checks(&preflight), [("interfaces", Severity::Warning)]);
+        assert!(preflight.into_result().is_ok());
+
+        fs::remove_dir_all(interfaces).unwrap();
+    }
+
+    #[test]
+    fn test_forwarding() {
+        let mut config = Config::default_for_testing();
+        let mut preflight = Preflight::default();
+        preflight.check_forwarding(&config, Some("0\n"), Some("0\n"));
+        assert_eq!(checks(&preflight), [("forwarding", Severity::Warning)]);
+
+        config.network.manage_nat = true;
+        config.network.enable_ipv6 = true;
+        let mut preflight = Preflight::default();
+        preflight.check_forwarding(&config, Some("1\n"), Some("0\n"));
+        assert_eq!(checks(&preflight), [("forwarding", Severity::Fatal)]);
+        assert!(preflight.findings()[0].message.contains("sysctl -w net.ipv6.conf.all.forwarding=1"));
+
+        // Unreadable sysctls (no /proc) are not held against the host
+        let mut preflight = Preflight::default();
+        preflight.check_forwarding(&config, None, None);
+        assert!(preflight.findings().is_empty());
+    }
+
+    #[test]
+    fn test_port_in_use() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default_for_testing();
+        config.server.port = taken.local_addr().unwrap().port();
+        config.monitoring.enable_metrics = false;
+
+        let mut preflight = Preflight::default();
+        preflight.check_ports(&config);
+        assert_eq!(checks(&preflight), [("ports", Severity::Fatal)]);
+        assert!(preflight.findings()[0].message.contains("already in use"));
+        assert_eq!(
+            preflight.into_result().unwrap_err().to_string(),
+            "Preflight checks failed: ports"
+        );
+
+        drop(taken);
+        let mut preflight = Preflight::default();
+        preflight.check_ports(&config);
+        assert!(preflight.findings().is_empty());
+    }
+
+    #[test]
+    fn test_container_skips_host_checks() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default_for_testing();
+        config.server.port = taken.local_addr().unwrap().port();
+        config.monitoring.enable_metrics = false;
+
+        // Only the taken port fails, however the test host looks
+        let preflight = Preflight::run_in_container(&config);
+        let host_checks = ["tun_device", "capabilities", "interfaces", "forwarding"];
+        assert!(preflight.findings().iter().all(|finding| !host_checks.contains(&finding.check)));
+        assert!(preflight.is_fatal());
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::protocol::stream_open::DEFAULT_WEIGHT;
+use crate::protocol::{PacketType, StreamId};
+
+/// Bytes a stream may send per round for each unit of weight, so a stream
+/// of the default weight gets about one full packet a round
+const QUANTUM_PER_WEIGHT: usize = 96;
+
+/// Scheduling class of a packet under load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Keepalives, acks, key updates and whatever else keeps a session alive
+    Control,
+    /// Tunnel data
+    Data,
+}
+
+impl Priority {
+    /// Class of a serialized packet; only tunnel data is bulk
+    pub fn of(packet: &[u8]) -> Self {
+        match packet.get(2) {
+            Some(&packet_type) if packet_type == PacketType::Data as u8 => Priority::Data,
+            _ => Priority::Control,
+        }
+    }
+}
+
+/// Queue handing out control packets strictly before data
+///
+/// Packets of one class keep their order, so a burst of data can't delay
+/// keepalives and key updates received or queued along with it.
+#[derive(Debug)]
+pub struct PriorityQueue<T> {
+    control: VecDeque<T>,
+    data: VecDeque<T>,
+}
+
+impl<T> PriorityQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            control: VecDeque::new(),
+            data: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, priority: Priority, item: T) {
+        match priority {
+            Priority::Control => self.control.push_back(item),
+            Priority::Data => self.data.push_back(item),
+        }
+    }
+
+    /// Oldest control packet, or the oldest data packet if there is none
+    pub fn pop(&mut self) -> Option<T> {
+        self.control.pop_front().or_else(|| self.data.pop_front())
+    }
+
+    pub fn len(&self) -> usize {
+        self.control.len() + self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.data.is_empty()
+    }
+}
+
+impl<T> Default for PriorityQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sender queue sharing the tunnel between streams by weight
+///
+/// Deficit round robin: every round each stream with queued packets may
+/// send its weight times `QUANTUM_PER_WEIGHT` bytes, and what it can't use
+/// on a packet carries over to the next round. Bytes, not packets, are
+/// shared out, so streams of small packets aren't starved by streams of
+/// large ones. The control stream goes strictly first, and each stream
+/// keeps its own order.
+#[derive(Debug)]
+pub struct StreamScheduler<T> {
+    control: VecDeque<T>,
+    streams: HashMap<u16, StreamQueue<T>>,
+    /// Streams with queued packets, the one whose turn it is first
+    active: VecDeque<u16>,
+}
+
+#[derive(Debug)]
+struct StreamQueue<T> {
+    weight: u16,
+    packets: VecDeque<(usize, T)>,
+    deficit: usize,
+    /// Whether the stream got its quantum this round
+    granted: bool,
+}
+
+impl<T> StreamQueue<T> {
+    fn new(weight: u16) -> Self {
+        Self {
+            weight,
+            packets: VecDeque::new(),
+            deficit: 0,
+            granted: false,
+        }
+    }
+}
+
+impl<T> StreamScheduler<T> {
+    pub fn new() -> Self {
+        Self {
+            control: VecDeque::new(),
+            streams: HashMap::new(),
+            active: VecDeque::new(),
+        }
+    }
+
+    /// Set a stream's weight, opening it if it isn't open yet
+    pub fn set_weight(&mut self, stream: StreamId, weight: u16) {
+        let weight = weight.max(1);
+        self.streams
+            .entry(stream.value())
+            .and_modify(|queue| queue.weight = weight)
+            .or_insert_with(|| StreamQueue::new(weight));
+    }
+
+    /// Weight of an open stream
+    pub fn weight(&self, stream: StreamId) -> Option<u16> {
+        self.streams.get(&stream.value()).map(|queue| queue.weight)
+    }
+
+    /// Streams opened, by `set_weight` or by queueing to them
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Queue a packet of `size` bytes; streams not opened get the default weight
+    pub fn push(&mut self, stream: StreamId, size: usize, item: T) {
+        if stream.is_control() {
+            self.control.push_back(item);
+            return;
+        }
+
+        let queue = self
+            .streams
+            .entry(stream.value())
+            .or_insert_with(|| StreamQueue::new(DEFAULT_WEIGHT));
+        if queue.packets.is_empty() {
+            self.active.push_back(stream.value());
+        }
+        queue.packets.push_back((size, item));
+    }
+
+    /// Next packet to send: control first, then the streams by weight
+    pub fn pop(&mut self) -> Option<T> {
+        if let Some(item) = self.control.pop_front() {
+            return Some(item);
+        }
+
+        loop {
+            let id = *self.active.front()?;
+            let queue = self.streams.get_mut(&id).expect("active streams are open");
+            let size = queue.packets.front().map_or(0, |(size, _)| *size);
+
+            if queue.deficit >= size {
+                queue.deficit -= size;
+                let (_, item) = queue.packets.pop_front().expect("active streams have packets");
+                if queue.packets.is_empty() {
+                    // Idle streams don't save up for later
+                    queue.deficit = 0;
+                    queue.granted = false;
+                    self.active.pop_front();
+                }
+                return Some(item);
+            }
+
+            if queue.granted {
+                // Round over for this stream, the rest carries over
+                queue.granted = false;
+                self.active.rotate_left(1);
+            } else {
+                queue.deficit += queue.weight as usize * QUANTUM_PER_WEIGHT;
+                queue.granted = true;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.control.len() + self.streams.values().map(|queue| queue.packets.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.active.is_empty()
+    }
+}
+
+impl<T> Default for StreamScheduler<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Packet;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_control_before_data() {
+        let data = Packet::new(PacketType::Data, Bytes::from_static(b"bulk")).serialize();
+        let keepalive = Packet::new(PacketType::KeepAlive, Bytes::new()).serialize();
+        assert_eq!(Priority::of(&data), Priority::Data);
+        assert_eq!(Priority::of(&keepalive), Priority::Control);
+        assert_eq!(Priority::of(b"x"), Priority::Control);
+
+        let mut queue = PriorityQueue::new();
+        queue.push(Priority::Data, "data 1");
+        queue.push(Priority::Control, "keepalive");
+        queue.push(Priority::Data, "data 2");
+        queue.push(Priority::Control, "key update");
+        assert_eq!(queue.len(), 4);
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(order, ["keepalive", "key update", "data 1", "data 2"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_streams_share_by_weight() {
+        let (ssh, backup) = (StreamId::new(1), StreamId::new(2));
+        let mut scheduler = StreamScheduler::new();
+        scheduler.set_weight(ssh, 200);
+        scheduler.set_weight(backup, 8);
+
+        for i in 0..1000 {
+            scheduler.push(backup, 1400, ("backup", i));
+            scheduler.push(ssh, 1400, ("ssh", i));
+        }
+        scheduler.push(StreamId::CONTROL, 24, ("keepalive", 0));
+        assert_eq!(scheduler.len(), 2001);
+        assert_eq!(scheduler.pop(), Some(("keepalive", 0)));
+
+        // 25 times the weight gets about 25 times the bytes
+        let sent: Vec<_> = (0..260).filter_map(|_| scheduler.pop()).collect();
+        let backup_sent = sent.iter().filter(|(stream, _)| *stream == "backup").count();
+        assert!((9..=11).contains(&backup_sent), "backup sent {}", backup_sent);
+
+        // Each stream keeps its order
+        let ssh_order: Vec<_> = sent.iter().filter(|(stream, _)| *stream == "ssh").map(|(_, i)| *i).collect();
+        assert!(ssh_order.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_idle_stream_sends_at_once() {
+        let (bulk, interactive) = (StreamId::new(1), StreamId::new(2));
+        let mut scheduler = StreamScheduler::new();
+        scheduler.set_weight(bulk, 1);
+
+        // A low weight still sends, just over several rounds
+        scheduler.push(bulk, 1400, "bulk");
+        assert_eq!(scheduler.pop(), Some("bulk"));
+
+        for _ in 0..10 {
+            scheduler.push(bulk, 1400, "bulk");
+        }
+        scheduler.push(interactive, 100, "keystroke");
+        let order: Vec<_> = std::iter::from_fn(|| scheduler.pop()).collect();
+        assert_eq!(order.iter().position(|item| *item == "keystroke"), Some(0));
+        assert_eq!(order.len(), 11);
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.weight(interactive), Some(DEFAULT_WEIGHT));
+        assert_eq!(scheduler.stream_count(), 2);
+    }
+}
@@ -0,0 +1,260 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::metrics::histogram::Histogram;
+use crate::metrics::timings::HANDSHAKE_BUCKETS;
+use crate::metrics::writer::MetricsWriter;
+use crate::protocol::QualityReport;
+
+/// End-to-end quality of one user, summed over the reports of their clients
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserQuality {
+    pub user: String,
+    pub reports: u64,
+    /// Seconds covered by all reports
+    pub seconds: u64,
+    /// Round trip time of the latest report, milliseconds
+    pub rtt_ms: u32,
+    /// Round trip time averaged over the covered seconds, milliseconds
+    pub rtt_avg_ms: u32,
+    pub packets_sent: u64,
+    pub packets_lost: u64,
+    pub retransmits: u64,
+    pub wakeups: u64,
+    /// Unix seconds of the latest report
+    pub last_report: u64,
+}
+
+/// Running sums of one user's reports
+#[derive(Debug, Default)]
+struct QualityTotals {
+    /// Open sessions of the user; the totals go with the last one
+    sessions: usize,
+    reports: u64,
+    seconds: u64,
+    rtt_ms: u32,
+    /// RTT in milliseconds times the seconds it was reported for
+    rtt_weighted: u128,
+    packets_sent: u64,
+    packets_lost: u64,
+    retransmits: u64,
+    wakeups: u64,
+    last_report: u64,
+}
+
+/// Quality reports of all clients, per user and server-wide
+///
+/// Kept in memory only, like accounting. Per-user figures are kept for users
+/// in `[policies]` while they have a session open, which the server tells
+/// with `session_started` and `session_ended`; other reports only count
+/// towards the server-wide metrics.
+#[derive(Debug)]
+pub struct QualityReports {
+    users: DashMap<String, QualityTotals>,
+    rtt: Histogram,
+    reports: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_lost: AtomicU64,
+    retransmits: AtomicU64,
+    wakeups: AtomicU64,
+}
+
+impl QualityReports {
+    pub fn new() -> Self {
+        Self {
+            users: DashMap::new(),
+            rtt: Histogram::new(HANDSHAKE_BUCKETS),
+            reports: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            packets_lost: AtomicU64::new(0),
+            retransmits: AtomicU64::new(0),
+            wakeups: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep figures for a session of a user in `[policies]`
+    pub fn session_started(&self, user: &str) {
+        self.users.entry(user.to_string()).or_default().sessions += 1;
+    }
+
+    /// Drop the user's figures when this was their last session
+    pub fn session_ended(&self, user: &str) {
+        if let Some(mut totals) = self.users.get_mut(user) {
+            totals.sessions = totals.sessions.saturating_sub(1);
+        }
+        self.users.remove_if(user, |_, totals| totals.sessions == 0);
+    }
+
+    /// Add a client's report
+    pub fn record(&self, user: Option<&str>, report: &QualityReport) {
+        self.record_at(user, report, unix_now());
+    }
+
+    fn record_at(&self, user: Option<&str>, report: &QualityReport, now: u64) {
+        self.rtt.observe(Duration::from_millis(report.rtt_ms as u64));
+        self.reports.fetch_add(1, Ordering::Relaxed);
+        self.packets_sent.fetch_add(report.packets_sent, Ordering::Relaxed);
+        self.packets_lost.fetch_add(report.packets_lost, Ordering::Relaxed);
+        self.retransmits.fetch_add(report.retransmits, Ordering::Relaxed);
+        self.wakeups.fetch_add(report.wakeups, Ordering::Relaxed);
+
+        let Some(mut totals) = user.and_then(|user| self.users.get_mut(user)) else {
+            return;
+        };
+        totals.reports += 1;
+        totals.seconds = totals.seconds.saturating_add(report.interval);
+        totals.rtt_ms = report.rtt_ms;
+        totals.rtt_weighted += report.rtt_ms as u128 * report.interval as u128;
+        totals.packets_sent = totals.packets_sent.saturating_add(report.packets_sent);
+        totals.packets_lost = totals.packets_lost.saturating_add(report.packets_lost);
+        totals.retransmits = totals.retransmits.saturating_add(report.retransmits);
+        totals.wakeups = totals.wakeups.saturating_add(report.wakeups);
+        totals.last_report = now;
+    }
+
+    /// Quality of every user that reported, sorted by user
+    pub fn snapshot(&self) -> Vec<UserQuality> {
+        let mut users: Vec<UserQuality> = self
+            .users
+            .iter()
+            .map(|entry| {
+                let totals = entry.value();
+                UserQuality {
+                    user: entry.key().clone(),
+                    reports: totals.reports,
+                    seconds: totals.seconds,
+                    rtt_ms: totals.rtt_ms,
+                    rtt_avg_ms: (totals.rtt_weighted / totals.seconds.max(1) as u128) as u32,
+                    packets_sent: totals.packets_sent,
+                    packets_lost: totals.packets_lost,
+                    retransmits: totals.retransmits,
+                    wakeups: totals.wakeups,
+                    last_report: totals.last_report,
+                }
+            })
+            .collect();
+        users.sort_by(|a, b| a.user.cmp(&b.user));
+        users
+    }
+
+    /// Write the server-wide metrics
+    pub fn render(&self, writer: &mut MetricsWriter) {
+        writer.counter(
+            "llp_client_reports_total",
+            "Quality reports received from clients",
+            self.reports.load(Ordering::Relaxed),
+        );
+        writer.histogram(
+            "llp_client_rtt_seconds",
+            "Round trip times reported by clients",
+            &self.rtt,
+        );
+        writer.counter(
+            "llp_client_packets_sent_total",
+            "Packets clients reported sending",
+            self.packets_sent.load(Ordering::Relaxed),
+        );
+        writer.counter(
+            "llp_client_packets_lost_total",
+            "Packets clients reported lost",
+            self.packets_lost.load(Ordering::Relaxed),
+        );
+        writer.counter(
+            "llp_client_retransmits_total",
+            "Packets clients reported sending again",
+            self.retransmits.load(Ordering::Relaxed),
+        );
+        writer.counter(
+            "llp_client_wakeups_total",
+            "Device wakeups clients reported for the tunnel",
+            self.wakeups.load(Ordering::Relaxed),
+        );
+    }
+}
+
+impl Default for QualityReports {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(interval: u64, rtt_ms: u32, packets_sent: u64, packets_lost: u64) -> QualityReport {
+        QualityReport {
+            interval,
+            rtt_ms,
+            packets_sent,
+            packets_lost,
+            retransmits: packets_lost,
+            wakeups: 1,
+        }
+    }
+
+    #[test]
+    fn test_aggregates_per_user() {
+        let reports = QualityReports::new();
+        reports.session_started("alice");
+        reports.session_started("bob");
+        reports.record_at(Some("bob"), &report(60, 10, 100, 0), 1_000);
+        reports.record_at(Some("alice"), &report(60, 40, 100, 2), 1_000);
+        reports.record_at(Some("alice"), &report(30, 100, 50, 1), 1_030);
+        reports.record_at(None, &report(60, 5, 10, 0), 1_030);
+
+        let users = reports.snapshot();
+        assert_eq!(users.iter().map(|u| u.user.as_str()).collect::<Vec<_>>(), ["alice", "bob"]);
+
+        let alice = &users[0];
+        assert_eq!(alice.reports, 2);
+        assert_eq!(alice.seconds, 90);
+        assert_eq!(alice.rtt_ms, 100);
+        // (40 * 60 + 100 * 30) / 90
+        assert_eq!(alice.rtt_avg_ms, 60);
+        assert_eq!((alice.packets_sent, alice.packets_lost, alice.retransmits), (150, 3, 3));
+        assert_eq!(alice.wakeups, 2);
+        assert_eq!(alice.last_report, 1_030);
+    }
+
+    #[test]
+    fn test_users_kept_while_connected() {
+        let reports = QualityReports::new();
+        reports.session_started("alice");
+        reports.session_started("alice");
+        reports.record(Some("alice"), &report(60, 30, 10, 0));
+        reports.record(Some("mallory"), &report(60, 30, 10, 0));
+        assert_eq!(reports.snapshot().len(), 1);
+
+        reports.session_ended("alice");
+        assert_eq!(reports.snapshot()[0].reports, 1);
+        reports.session_ended("alice");
+        assert!(reports.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_render_counts_anonymous_reports() {
+        let reports = QualityReports::new();
+        reports.session_started("alice");
+        reports.record(None, &report(60, 30, 10, 1));
+        reports.record(Some("alice"), &report(60, 30, 10, 0));
+
+        let mut writer = MetricsWriter::new();
+        reports.render(&mut writer);
+        let output = writer.finish();
+
+        assert!(output.contains("llp_client_reports_total 2\n"));
+        assert!(output.contains("llp_client_packets_lost_total 1\n"));
+        assert!(output.contains("llp_client_rtt_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert_eq!(reports.snapshot().len(), 1);
+    }
+}
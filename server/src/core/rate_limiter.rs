@@ -0,0 +1,189 @@
+use dashmap::DashMap;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::config::{Config, RateClass};
+
+/// Token bucket: sustained `rate` bytes/second, bursts up to `burst` bytes
+///
+/// Idle time refills the bucket up to `burst`, so the average stays at
+/// `rate` while short bursts go through at full speed.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket
+    pub fn new(class: RateClass) -> Self {
+        Self {
+            rate: class.rate,
+            burst: class.burst(),
+            tokens: class.burst() as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take tokens for a packet, false if it exceeds the limit
+    pub fn try_consume(&mut self, bytes: u64) -> bool {
+        self.try_consume_at(bytes, Instant::now())
+    }
+
+    fn try_consume_at(&mut self, bytes: u64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.last_refill = now;
+
+        if self.tokens >= bytes as f64 {
+            self.tokens -= bytes as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Shared handle to a bucket
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+/// Hands out token buckets according to users' bandwidth classes
+///
+/// All connections of a user in `[policies]` share one bucket, kept while
+/// one of them is open. Other names come from unauthenticated hellos and
+/// anyone may pick a fresh one, so those connections get their own bucket
+/// like anonymous ones.
+pub struct RateLimiter {
+    default_class: Option<RateClass>,
+    users: BTreeSet<String>,
+    user_classes: DashMap<String, RateClass>,
+    buckets: DashMap<String, SharedBucket>,
+}
+
+impl RateLimiter {
+    /// Create limiter from config
+    pub fn new(config: &Config) -> Self {
+        let limits = &config.limits;
+        let default_class = (limits.rate_limit_per_user > 0).then_some(RateClass {
+            rate: limits.rate_limit_per_user,
+            burst: limits.burst_per_user,
+        });
+
+        let user_classes = config
+            .policies
+            .iter()
+            .filter_map(|(user, policy)| {
+                let class = config.classes.get(policy.class.as_ref()?)?;
+                Some((user.clone(), *class))
+            })
+            .collect();
+
+        Self {
+            default_class,
+            users: config.policies.keys().cloned().collect(),
+            user_classes,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Bucket for a connection, None if unlimited
+    pub fn bucket_for(&self, user: Option<&str>) -> Option<SharedBucket> {
+        let Some(user) = user.filter(|user| self.users.contains(*user)) else {
+            return self.default_class.map(|class| Arc::new(Mutex::new(TokenBucket::new(class))));
+        };
+
+        let class = self
+            .user_classes
+            .get(user)
+            .map(|class| *class)
+            .or(self.default_class)?;
+
+        let bucket = self
+            .buckets
+            .entry(user.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(class))));
+        Some(bucket.clone())
+    }
+
+    /// Forget a user's bucket once no connection holds it any more
+    pub fn release(&self, user: &str) {
+        self.buckets.remove_if(user, |_, bucket| Arc::strong_count(bucket) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn test_burst_then_sustained_rate() {
+        let mut bucket = TokenBucket::new(RateClass {
+            rate: 1000,
+            burst: Some(5000),
+        });
+        let start = bucket.last_refill;
+
+        // Full burst goes through at once, then the bucket is empty
+        assert!(bucket.try_consume_at(5000, start));
+        assert!(!bucket.try_consume_at(1, start));
+
+        // Refills at the sustained rate
+        assert!(!bucket.try_consume_at(600, start + Duration::from_millis(500)));
+        assert!(bucket.try_consume_at(1000, start + Duration::from_millis(1000)));
+
+        // Long idle time refills no more than the burst
+        let later = start + Duration::from_secs(60);
+        assert!(!bucket.try_consume_at(5001, later));
+        assert!(bucket.try_consume_at(5000, later));
+    }
+
+    #[test]
+    fn test_buckets_per_class_and_user() {
+        let mut config = Config::default_for_testing();
+        config.limits.rate_limit_per_user = 1000;
+        config.classes.insert(
+            "pro".to_string(),
+            RateClass {
+                rate: 10_000,
+                burst: Some(50_000),
+            },
+        );
+        config.policies.insert(
+            "alice".to_string(),
+            UserPolicy {
+                class: Some("pro".to_string()),
+                ..UserPolicy::default()
+            },
+        );
+        let limiter = RateLimiter::new(&config);
+
+        let alice = limiter.bucket_for(Some("alice")).unwrap();
+        assert_eq!(alice.lock().unwrap().burst, 50_000);
+        // Same user, same bucket
+        assert!(Arc::ptr_eq(&alice, &limiter.bucket_for(Some("alice")).unwrap()));
+
+        let bob = limiter.bucket_for(Some("bob")).unwrap();
+        assert_eq!(bob.lock().unwrap().rate, 1000);
+
+        // Anonymous connections and users without a policy don't share
+        let first = limiter.bucket_for(None).unwrap();
+        assert!(!Arc::ptr_eq(&first, &limiter.bucket_for(None).unwrap()));
+        assert!(!Arc::ptr_eq(&bob, &limiter.bucket_for(Some("bob")).unwrap()));
+        assert_eq!(limiter.buckets.len(), 1);
+
+        // The bucket goes with the user's last connection
+        limiter.release("alice");
+        assert_eq!(limiter.buckets.len(), 1);
+        drop(alice);
+        limiter.release("alice");
+        assert!(limiter.buckets.is_empty());
+
+        config.limits.rate_limit_per_user = 0;
+        assert!(RateLimiter::new(&config).bucket_for(Some("bob")).is_none());
+    }
+}
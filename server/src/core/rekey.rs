@@ -0,0 +1,236 @@
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::core::session::SessionId;
+use crate::crypto::KeyManager;
+use crate::error::{LostLoveError, Result};
+use crate::metrics::{CryptoOperation, Timings};
+
+/// How often sessions' keys are checked for rotation
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Epochs queued per session before new ones are dropped
+const MAILBOX_SIZE: usize = 4;
+
+/// Key rotation counters since server start
+#[derive(Debug, Default)]
+pub struct RekeyStats {
+    /// Rotations started by the server when keys reached their age
+    pub scheduled: AtomicU64,
+    /// Rotations started by a client's KeyUpdate
+    pub client: AtomicU64,
+    pub failures: AtomicU64,
+}
+
+/// A session whose keys the scheduler watches
+struct Registration {
+    key_manager: Arc<KeyManager>,
+    updates: mpsc::Sender<u64>,
+}
+
+/// Drives key rotation for every session holding keys
+///
+/// A single task checks all registered sessions' `KeyManager`s; when one
+/// rotates, the new epoch lands in the session's mailbox and its data loop
+/// tells the client with a KeyUpdate packet.
+pub struct RekeyScheduler {
+    sessions: DashMap<SessionId, Registration>,
+    check_interval: Duration,
+    stats: RekeyStats,
+    timings: Arc<Timings>,
+}
+
+impl RekeyScheduler {
+    pub fn new() -> Self {
+        Self {
+            sessions: DashMap::new(),
+            check_interval: CHECK_INTERVAL,
+            stats: RekeyStats::default(),
+            timings: Arc::new(Timings::new()),
+        }
+    }
+
+    /// Record rotation times in shared histograms
+    pub fn with_timings(mut self, timings: Arc<Timings>) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    /// Counters since server start
+    pub fn stats(&self) -> &RekeyStats {
+        &self.stats
+    }
+
+    /// Watch a session's keys, returns the mailbox of its new epochs
+    pub fn register(&self, session_id: &SessionId, key_manager: Arc<KeyManager>) -> mpsc::Receiver<u64> {
+        let (updates, receiver) = mpsc::channel(MAILBOX_SIZE);
+        self.sessions.insert(
+            *session_id,
+            Registration {
+                key_manager,
+                updates,
+            },
+        );
+        receiver
+    }
+
+    /// Stop watching a session's keys
+    pub fn unregister(&self, session_id: &SessionId) {
+        self.sessions.remove(session_id);
+    }
+
+    /// Check all sessions periodically
+    pub fn start(self: &Arc<Self>) {
+        let scheduler = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(scheduler.check_interval);
+            loop {
+                interval.tick().await;
+                scheduler.check_all().await;
+            }
+        });
+    }
+
+    /// Rotate the keys of every session that is due
+    pub async fn check_all(&self) {
+        // Not holding map guards across the awaits below
+        let sessions: Vec<(SessionId, Arc<KeyManager>, mpsc::Sender<u64>)> = self
+            .sessions
+            .iter()
+            .map(|entry| {
+                let registration = entry.value();
+                (
+                    *entry.key(),
+                    registration.key_manager.clone(),
+                    registration.updates.clone(),
+                )
+            })
+            .collect();
+
+        for (session_id, key_manager, updates) in sessions {
+            let started = Instant::now();
+            let rotated = key_manager.check_rotation().await;
+            if matches!(rotated, Ok(true)) {
+                self.timings.crypto(CryptoOperation::Rekey).observe(started.elapsed());
+            }
+            match rotated {
+                Ok(true) => {
+                    let epoch = key_manager.epoch().await;
+                    self.stats.scheduled.fetch_add(1, Ordering::Relaxed);
+                    info!("Rotated keys of session {} to epoch {}", session_id, epoch);
+
+                    if updates.try_send(epoch).is_err() {
+                        warn!("Key update mailbox of session {} is full", session_id);
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                    warn!("Key rotation of session {} failed: {}", session_id, e);
+                }
+            }
+        }
+    }
+
+    /// Handle a client's KeyUpdate for `epoch`
+    ///
+    /// Returns true when the keys were rotated and the client is owed a
+    /// KeyUpdate in answer, false when it acknowledged our own rotation.
+    pub async fn peer_update(&self, session_id: &SessionId, key_manager: &KeyManager, epoch: u64) -> Result<bool> {
+        let current = key_manager.epoch().await;
+
+        if epoch == current {
+            return Ok(false);
+        }
+        if epoch != current + 1 {
+            self.stats.failures.fetch_add(1, Ordering::Relaxed);
+            return Err(LostLoveError::Crypto(format!(
+                "Key update to epoch {} while at epoch {}",
+                epoch, current
+            )));
+        }
+
+        let started = Instant::now();
+        let rotated = key_manager.rotate_keys().await;
+        self.timings.crypto(CryptoOperation::Rekey).observe(started.elapsed());
+        if let Err(e) = rotated {
+            self.stats.failures.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        self.stats.client.fetch_add(1, Ordering::Relaxed);
+        info!("Client rotated keys of session {} to epoch {}", session_id, epoch);
+
+        Ok(true)
+    }
+}
+
+impl Default for RekeyScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_manager(rotation_interval: Duration) -> Arc<KeyManager> {
+        Arc::new(
+            KeyManager::new(vec![1u8; 32], [2u8; 32], [3u8; 32], true)
+                .unwrap()
+                .with_rotation_interval(rotation_interval),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_due_sessions_rotate() {
+        let scheduler = RekeyScheduler::new();
+        let due = SessionId::new();
+        let fresh = SessionId::new();
+
+        let mut due_updates = scheduler.register(&due, key_manager(Duration::ZERO));
+        let mut fresh_updates = scheduler.register(&fresh, key_manager(Duration::from_secs(3600)));
+
+        scheduler.check_all().await;
+
+        assert_eq!(due_updates.try_recv().unwrap(), 1);
+        assert!(fresh_updates.try_recv().is_err());
+        assert_eq!(scheduler.stats().scheduled.load(Ordering::Relaxed), 1);
+
+        scheduler.unregister(&due);
+        scheduler.check_all().await;
+        assert!(due_updates.try_recv().is_err());
+
+        let rotations = scheduler.timings.crypto(CryptoOperation::Rekey).cumulative();
+        assert_eq!(rotations.last().unwrap().1, 1);
+    }
+
+    #[tokio::test]
+    async fn test_peer_update() {
+        let scheduler = RekeyScheduler::new();
+        let session_id = SessionId::new();
+        let km = key_manager(Duration::from_secs(3600));
+
+        // The client moves to epoch 1, the server follows
+        assert!(scheduler.peer_update(&session_id, &km, 1).await.unwrap());
+        assert_eq!(km.epoch().await, 1);
+
+        // An answer to our own update
+        assert!(!scheduler.peer_update(&session_id, &km, 1).await.unwrap());
+
+        // Epochs can't be skipped or go back
+        assert!(scheduler.peer_update(&session_id, &km, 3).await.is_err());
+        assert!(scheduler.peer_update(&session_id, &km, 0).await.is_err());
+        assert_eq!(km.epoch().await, 1);
+
+        let stats = scheduler.stats();
+        assert_eq!(stats.client.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.failures.load(Ordering::Relaxed), 2);
+    }
+}
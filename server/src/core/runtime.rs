@@ -0,0 +1,131 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::config::ServerConfig;
+
+/// Runtime flavor running everything on the main thread
+pub const CURRENT_THREAD: &str = "current-thread";
+
+/// Runtime flavor with a pool of worker threads
+pub const MULTI_THREAD: &str = "multi-thread";
+
+/// Build the Tokio runtime described by `[server]`
+///
+/// Packets are encrypted and decrypted on the worker threads, so their
+/// number is also how many cores crypto can use. `current-thread` keeps a
+/// tiny VPS to one thread.
+pub fn build(config: &ServerConfig) -> io::Result<Runtime> {
+    if config.runtime == CURRENT_THREAD {
+        return Builder::new_current_thread().enable_all().build();
+    }
+
+    let workers = worker_count(config);
+    let mut builder = Builder::new_multi_thread();
+    builder.worker_threads(workers).thread_name("llp-worker").enable_all();
+
+    if config.pin_workers {
+        // Threads start in order, so workers get CPUs 0..n; blocking pool
+        // threads started later carry on round robin
+        let next_cpu = Arc::new(AtomicUsize::new(0));
+        let cpus = available_cpus();
+        builder.on_thread_start(move || {
+            let cpu = next_cpu.fetch_add(1, Ordering::Relaxed) % cpus;
+            if let Err(e) = pin_current_thread(cpu) {
+                eprintln!("Failed to pin worker thread to CPU {}: {}", cpu, e);
+            }
+        });
+    }
+
+    builder.build()
+}
+
+/// Worker threads of a multi-threaded runtime, 0 meaning one per CPU
+pub fn worker_count(config: &ServerConfig) -> usize {
+    match config.worker_threads {
+        0 => available_cpus(),
+        threads => threads,
+    }
+}
+
+/// One-line summary for the startup log
+pub fn describe(config: &ServerConfig) -> String {
+    if config.runtime == CURRENT_THREAD {
+        return "current-thread runtime".to_string();
+    }
+    format!(
+        "multi-thread runtime with {} workers{}",
+        worker_count(config),
+        if config.pin_workers { " pinned to CPUs" } else { "" }
+    )
+}
+
+fn available_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    // SAFETY: cpu_set_t is plain data, zeroed is the empty set; the
+    // syscall only reads it
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "thread pinning needs Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn server_config(runtime: &str, worker_threads: usize) -> ServerConfig {
+        ServerConfig {
+            runtime: runtime.to_string(),
+            worker_threads,
+            ..Config::default_for_testing().server
+        }
+    }
+
+    #[test]
+    fn test_worker_count() {
+        assert_eq!(worker_count(&server_config(MULTI_THREAD, 3)), 3);
+        assert_eq!(worker_count(&server_config(MULTI_THREAD, 0)), available_cpus());
+        assert_eq!(describe(&server_config(MULTI_THREAD, 3)), "multi-thread runtime with 3 workers");
+        assert_eq!(describe(&server_config(CURRENT_THREAD, 0)), "current-thread runtime");
+    }
+
+    #[test]
+    fn test_build_runtimes() {
+        for config in [server_config(CURRENT_THREAD, 0), server_config(MULTI_THREAD, 2)] {
+            let runtime = build(&config).unwrap();
+            let answer = runtime.block_on(async { tokio::spawn(async { 42 }).await.unwrap() });
+            assert_eq!(answer, 42);
+        }
+    }
+
+    #[test]
+    fn test_pinned_workers() {
+        let config = ServerConfig {
+            pin_workers: true,
+            ..server_config(MULTI_THREAD, 1)
+        };
+        let runtime = build(&config).unwrap();
+        let name = runtime.block_on(async {
+            tokio::spawn(async { std::thread::current().name().map(str::to_string) })
+                .await
+                .unwrap()
+        });
+        assert_eq!(name.as_deref(), Some("llp-worker"));
+    }
+}
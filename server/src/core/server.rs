@@ -1,27 +1,163 @@
 use anyhow::Context;
 use bytes::{Bytes, BytesMut};
+use socket2::SockRef;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio::time;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
+use crate::admin::ControlServer;
 use crate::config::Config;
+use crate::core::accounting::{Accounting, PolicyDecision};
+use crate::core::channels::{Channels, OpenChannels};
+use crate::core::file_transfer::FileTransfers;
+use crate::core::management::Management;
+use crate::core::portal::PortalReports;
+use crate::core::config_push::{ConfigPublisher, PushedSettings};
 use crate::core::connection::ConnectionManager;
-use crate::core::session::SessionState;
+use crate::core::decoy::{is_handshake_prefix, Decoy};
+use crate::core::diagnose::PathDiagnostics;
+use crate::core::drain::DrainController;
+use crate::core::failover::Failover;
+use crate::core::federation::{Federation, Link, LinkNonces, LinkSide};
+use crate::core::fleet::Fleet;
+use crate::core::abuse::{Abuse, AbuseCounters, PacketDeadline};
+use crate::core::accept::{AcceptErrorKind, AcceptFailures};
+use crate::core::overload::Overload;
+use crate::core::quality::QualityReports;
+use crate::core::hooks::{HookEnv, Hooks};
+use crate::core::memory::{MemoryBudget, MemoryUse};
+use crate::core::priority::StreamScheduler;
+use crate::core::rekey::RekeyScheduler;
+use crate::core::signaling::Signaling;
+use crate::core::supervisor::Supervisor;
+use crate::core::sweeper::Sweeper;
+use crate::crypto::keylog::{KeyLog, SERVER_RANDOM_LABEL};
+use crate::crypto::obfuscation::{HandshakeObfuscation, HelloSender, OBFUSCATED_HEADER_SIZE};
+use crate::crypto::policy::CryptoPolicy;
+use crate::protocol::address_discovery::Transport;
+use crate::protocol::registry::{PacketRegistry, UnknownTypePolicy};
+use crate::core::engine::{self, EngineEvent, ProtocolEngine, Redirect};
+use crate::core::packet_trace::{PacketTraceSampler, TraceDecision};
+use crate::core::rate_limiter::RateLimiter;
+use crate::core::session::SessionEvent;
+use crate::logging::LogControl;
+use crate::metrics::{CryptoOperation, ErrorCounters, MetricsExporter, SnmpAgent, Timings};
+use crate::network::{
+    ClientIsolation, DataPath, DescriptorServer, DiscoveryServer, DnsForwarder, DnsTunnelServer, DscpMarker,
+    Firewall, IcmpTunnelServer, IpPool, KnockGate, NatRules, PacketRouter, PortSchedule, Relay, RuleGuard,
+    UdpTransport,
+};
+#[cfg(target_os = "linux")]
+use crate::network::TunDevice;
+use crate::network::tcp_tuning;
+use crate::network::offload::GSO_MAX_SIZE;
 use crate::error::{LostLoveError, Result};
-use crate::protocol::{HandshakeMessage, Packet, PacketType, HEADER_SIZE};
+use crate::protocol::{
+    DisconnectMessage, DisconnectReason, HandshakeFailureCategory, HandshakeMessage, HelloExtensions, KeyUpdate,
+    Packet, PacketType, PeerSignal, QualityReport, RouteAnnouncement, StreamId, StreamOpen, HEADER_SIZE,
+};
+use crate::protocol::wire::payload_length;
 
 /// Server shutdown signal
 type ShutdownSignal = broadcast::Receiver<()>;
 
+/// How often connected users are checked against their access policy
+const POLICY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often a session is checked for having been idle long enough to hibernate
+const HIBERNATION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a closing connection waits for the peer to close its side
+const CLOSE_LINGER: Duration = Duration::from_secs(5);
+
+/// Packets routed to a client sent after each packet read from it, so a
+/// busy upload doesn't stall the download
+const TUNNEL_BATCH: usize = 64;
+
+/// Shared state handed to every connection handler
+#[derive(Clone)]
+struct ConnectionContext {
+    connection_manager: Arc<ConnectionManager>,
+    config: Arc<Config>,
+    error_counters: Arc<ErrorCounters>,
+    timings: Arc<Timings>,
+    isolation: Option<Arc<ClientIsolation>>,
+    accounting: Arc<Accounting>,
+    quality: Arc<QualityReports>,
+    diagnostics: Arc<PathDiagnostics>,
+    channels: Arc<Channels>,
+    hooks: Option<Arc<Hooks>>,
+    rate_limiter: Arc<RateLimiter>,
+    drain: Arc<DrainController>,
+    federation: Option<Arc<Federation>>,
+    fleet: Option<Arc<Fleet>>,
+    relay: Option<Arc<Relay>>,
+    signaling: Option<Arc<Signaling>>,
+    data_path: Arc<DataPath>,
+    udp_transport: Option<Arc<UdpTransport>>,
+    dns_tunnel: Option<Arc<DnsTunnelServer>>,
+    icmp_tunnel: Option<Arc<IcmpTunnelServer>>,
+    registry: Arc<PacketRegistry>,
+    decoy: Option<Arc<Decoy>>,
+    obfuscation: Option<Arc<HandshakeObfuscation>>,
+    keylog: Option<Arc<KeyLog>>,
+    rekey: Arc<RekeyScheduler>,
+    overload: Arc<Overload>,
+    memory: Arc<MemoryBudget>,
+}
+
 /// LostLove Server
 pub struct Server {
     config: Arc<Config>,
     connection_manager: Arc<ConnectionManager>,
     shutdown_tx: broadcast::Sender<()>,
+    log_control: Option<Arc<LogControl>>,
+    error_counters: Arc<ErrorCounters>,
+    timings: Arc<Timings>,
+    firewall: Option<Arc<Firewall>>,
+    nat: Option<NatRules>,
+    isolation: Option<Arc<ClientIsolation>>,
+    config_publisher: Arc<ConfigPublisher>,
+    config_path: Option<PathBuf>,
+    accounting: Arc<Accounting>,
+    quality: Arc<QualityReports>,
+    diagnostics: Arc<PathDiagnostics>,
+    channels: Arc<Channels>,
+    file_transfers: Arc<FileTransfers>,
+    management: Option<Arc<Management>>,
+    portals: Arc<PortalReports>,
+    hooks: Option<Arc<Hooks>>,
+    rate_limiter: Arc<RateLimiter>,
+    drain: Arc<DrainController>,
+    federation: Option<Arc<Federation>>,
+    failover: Option<Arc<Failover>>,
+    fleet: Option<Arc<Fleet>>,
+    snmp: Option<Arc<SnmpAgent>>,
+    relay: Option<Arc<Relay>>,
+    signaling: Option<Arc<Signaling>>,
+    data_path: Arc<DataPath>,
+    udp_transport: Option<Arc<UdpTransport>>,
+    dns_tunnel: Option<Arc<DnsTunnelServer>>,
+    dns_forwarder: Option<Arc<DnsForwarder>>,
+    icmp_tunnel: Option<Arc<IcmpTunnelServer>>,
+    registry: Arc<PacketRegistry>,
+    knock_gate: Option<Arc<KnockGate>>,
+    decoy: Option<Arc<Decoy>>,
+    obfuscation: Option<Arc<HandshakeObfuscation>>,
+    keylog: Option<Arc<KeyLog>>,
+    rekey: Arc<RekeyScheduler>,
+    overload: Arc<Overload>,
+    memory: Arc<MemoryBudget>,
+    accept_failures: Arc<AcceptFailures>,
+    abuse: Arc<AbuseCounters>,
+    supervisor: Arc<Supervisor>,
+    sweeper: Arc<Sweeper>,
 }
 
 impl Server {
@@ -31,79 +167,572 @@ impl Server {
 
         let (shutdown_tx, _) = broadcast::channel(1);
 
-        let connection_manager = Arc::new(ConnectionManager::new(config.server.max_connections));
+        let ip_pool = Arc::new(IpPool::from_cidr(&config.network.tun_address)?);
+        info!(
+            "Leasing tunnel addresses from {} (server {})",
+            config.network.tun_address,
+            ip_pool.server_address()
+        );
+        let server_address = ip_pool.server_address();
+
+        let crypto_policy = CryptoPolicy::from_config(&config.security);
+        info!("{}", crypto_policy.attestation());
+
+        let mut connection_manager = ConnectionManager::new(config.server.max_connections)
+            .with_ip_pool(ip_pool)
+            .with_crypto_policy(crypto_policy);
+        if config.security.lock_key_memory {
+            connection_manager = connection_manager.with_locked_key_memory();
+        }
+        let connection_manager = Arc::new(connection_manager);
+
+        let firewall = if config.firewall.enabled {
+            info!("Tunnel firewall enabled (max {} flows)", config.firewall.max_flows);
+            Some(Arc::new(Firewall::new(&config.firewall)))
+        } else {
+            None
+        };
+
+        let nat = if config.network.manage_nat {
+            Some(NatRules::new(&config.network)?)
+        } else {
+            None
+        };
+
+        let isolation = ClientIsolation::new(&config.network)?.map(Arc::new);
+        if isolation.is_some() {
+            info!("Per-client routing isolation enabled");
+        }
+
+        let relay = Relay::new(&config.network.relay).map(Arc::new);
+        if relay.is_some() {
+            info!("Client-to-client relaying enabled");
+        }
+
+        let signaling =
+            Signaling::new(&config.network.p2p, connection_manager.clone()).map(Arc::new);
+        if signaling.is_some() {
+            info!("Peer-to-peer hole punching signaling enabled");
+        }
+
+        // Tunneled packets are checked on their way to the TUN device and back
+        let mut router = PacketRouter::new(connection_manager.clone())
+            .with_source_validation(config.network.validate_source)
+            .with_path_mtu(config.network.mtu)
+            .with_traffic_breakdown(config.monitoring.traffic_breakdown);
+        if config.network.clamp_mss {
+            router = router.with_mss_clamp(config.network.mtu);
+        }
+        if let Some(firewall) = &firewall {
+            router = router.with_firewall(firewall.clone());
+        }
+        if let Some(relay) = &relay {
+            router = router.with_relay(relay.clone());
+        }
+        let data_path = Arc::new(DataPath::new(router));
+
+        // Subsystems defining their own packet types register them here
+        let registry = Arc::new(PacketRegistry::new(UnknownTypePolicy::parse(
+            &config.limits.unknown_packet_types,
+        )?));
+
+        let config_publisher = Arc::new(ConfigPublisher::new(&config));
+        let diagnostics = Arc::new(PathDiagnostics::new(config_publisher.subscribe()));
+        let error_counters = Arc::new(ErrorCounters::new());
+
+        // Handshakes stay on TCP; "udp" and "both" add the UDP data path
+        let udp_transport = (config.server.protocol != "tcp").then(|| {
+            let transport = UdpTransport::new(
+                config.server.bind_address.clone(),
+                config.server.port,
+                connection_manager.clone(),
+            )
+            .with_dscp(config.server.qos.dscp)
+            .with_socket_options(&config.server.udp)
+            .with_registry(registry.clone())
+            .with_diagnostics(diagnostics.clone())
+            .with_data_path(data_path.clone())
+            .with_max_packet_size(config.limits.max_packet_size, error_counters.clone());
+            Arc::new(match PortSchedule::new(&config.server.port_hopping) {
+                Some(schedule) => transport.with_port_schedule(schedule),
+                None => transport,
+            })
+        });
+
+        // Connection buffers are charged here; at the cap, load is shed
+        let memory = Arc::new(MemoryBudget::new(&config.limits));
+
+        let dns_tunnel = if config.network.dns.enabled {
+            info!("Experimental DNS transport enabled for {}", config.network.dns.zone);
+            Some(Arc::new(DnsTunnelServer::new(
+                format!("{}:{}", config.server.bind_address, config.network.dns.port),
+                listener_target(&config)?,
+                &config.network.dns,
+            )
+            .with_memory(memory.clone())))
+        } else {
+            None
+        };
+
+        let icmp_tunnel = if config.network.icmp.enabled {
+            IcmpTunnelServer::check_capability()?;
+            info!("ICMP echo transport enabled");
+            Some(Arc::new(IcmpTunnelServer::new(
+                listener_target(&config)?,
+                &config.network.icmp,
+            )
+            .with_memory(memory.clone())))
+        } else {
+            None
+        };
+
+        let knock_gate = KnockGate::new(
+            &config.server.knock,
+            &config.server.bind_address,
+            config.server.port,
+        )
+        .map(Arc::new);
+        if knock_gate.is_some() {
+            info!("Listener hidden behind knocks on UDP port {}", config.server.knock.port);
+        }
+
+        let overload = Arc::new(
+            Overload::new(&config.server.overload, connection_manager.clone()).with_memory(memory.clone()),
+        );
+        if let Some(max_load) = config.server.overload.max_load {
+            info!("New sessions are refused above a load of {} per CPU", max_load);
+        }
+        if config.limits.max_memory_mb > 0 {
+            info!("New sessions are refused above {} MiB of connection buffers", config.limits.max_memory_mb);
+        }
+
+        let decoy = Decoy::new(&config.server.decoy).map(Arc::new);
+        if decoy.is_some() {
+            info!("Failed handshakes are proxied to decoy {}", config.server.decoy.address);
+        }
+
+        let obfuscation = if config.server.obfuscation.enabled {
+            info!("Handshake obfuscation enabled");
+            let server_key = hex::decode(&config.server.obfuscation.server_key)
+                .context("Invalid server.obfuscation.server_key")?;
+            Some(Arc::new(HandshakeObfuscation::new(&server_key)?))
+        } else {
+            None
+        };
+
+        let federation = Federation::new(&config).map(Arc::new);
+        if federation.is_some() {
+            info!(
+                "Site-to-site federation enabled as {} ({} peers)",
+                config.federation.site_name,
+                config.federation.peers.len()
+            );
+        }
+
+        let accounting = Arc::new(Accounting::new(&config));
+        let failover = Failover::new(&config, accounting.clone())?.map(Arc::new);
+        let fleet = Fleet::new(&config, connection_manager.clone()).map(Arc::new);
+        if fleet.is_some() {
+            info!(
+                "Fleet steering enabled as {} ({} peers)",
+                config.fleet.node,
+                config.fleet.peers.len()
+            );
+        }
+        if failover.is_some() {
+            info!("Failover enabled as {} ({})", config.failover.role, config.failover.address);
+        }
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        if !config.policies.is_empty() {
+            info!("Access policies configured for {} users", config.policies.len());
+        }
+
+        let supervisor = Arc::new(Supervisor::new(config.server.restart_listeners));
+        let sweeper = Arc::new(Sweeper::new(
+            connection_manager.clone(),
+            Duration::from_secs(config.limits.connection_timeout),
+            Duration::from_secs(config.limits.cleanup_interval),
+        ));
+
+        let timings = Arc::new(Timings::new());
+        let snmp = if config.monitoring.snmp.enabled {
+            let agent = SnmpAgent::new(&config.monitoring.snmp, connection_manager.clone(), error_counters.clone())?;
+            Some(Arc::new(agent))
+        } else {
+            None
+        };
+
+        let dns_forwarder = if config.resolver.enabled {
+            let forwarder =
+                DnsForwarder::new(&config, server_address.into(), connection_manager.clone())?;
+            info!("DNS forwarder enabled on {}", forwarder.address());
+            Some(Arc::new(forwarder))
+        } else {
+            None
+        };
+        let hooks = Hooks::new(&config.hooks).map(Arc::new);
+        if hooks.is_some() {
+            info!("Session event hooks enabled");
+        }
+
+        let channels = Arc::new(Channels::new());
+        let file_transfers = FileTransfers::register(&channels)?;
+        let portals = PortalReports::register(&channels, connection_manager.clone())?;
+        let management = Management::register(&config.admin.management, &channels)?;
+        if management.is_some() {
+            info!("Remote management enabled for clients that opt in");
+        }
 
         Ok(Self {
             config: Arc::new(config),
             connection_manager,
             shutdown_tx,
+            log_control: None,
+            error_counters,
+            timings: timings.clone(),
+            firewall,
+            nat,
+            isolation,
+            config_publisher,
+            config_path: None,
+            accounting,
+            quality: Arc::new(QualityReports::new()),
+            diagnostics,
+            channels,
+            file_transfers,
+            management,
+            portals,
+            hooks,
+            rate_limiter,
+            drain: Arc::new(DrainController::new()),
+            federation,
+            failover,
+            fleet,
+            snmp,
+            relay,
+            signaling,
+            data_path,
+            udp_transport,
+            dns_tunnel,
+            dns_forwarder,
+            icmp_tunnel,
+            registry,
+            knock_gate,
+            decoy,
+            obfuscation,
+            keylog: None,
+            rekey: Arc::new(RekeyScheduler::new().with_timings(timings)),
+            overload,
+            memory,
+            accept_failures: Arc::new(AcceptFailures::new()),
+            abuse: Arc::new(AbuseCounters::new()),
+            supervisor,
+            sweeper,
         })
     }
 
+    /// Allow `llpctl reload` to re-read pushed client settings from this file
+    pub fn with_config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Write session secrets to a key log for decrypting captures
+    pub fn with_keylog(mut self, keylog: KeyLog) -> Self {
+        self.keylog = Some(Arc::new(keylog));
+        self
+    }
+
+    /// Allow the control socket to change the log filter at runtime
+    pub fn with_log_control(mut self, log_control: Arc<LogControl>) -> Self {
+        self.log_control = Some(log_control);
+        self
+    }
+
     /// Run the server
     pub async fn run(&self) -> anyhow::Result<()> {
         let addr = format!("{}:{}", self.config.server.bind_address, self.config.server.port);
 
         info!("Starting TCP listener on {}", addr);
 
-        let listener = TcpListener::bind(&addr)
+        let listener = tcp_tuning::bind(&addr, &self.config.server.tcp)
             .await
             .context(format!("Failed to bind to {}", addr))?;
 
         info!("Server listening on {}", addr);
+
+        // Rules are removed again when the server stops
+        self.start_data_path()?;
+        let _knock_guard = self.install_knock_rules()?;
         info!("Max connections: {}", self.config.server.max_connections);
         info!("Protocol: {}", self.config.server.protocol);
 
         // Start background tasks
         self.start_background_tasks();
+        self.start_control_socket();
+        self.start_metrics_exporter();
+
+        if let Some(federation) = &self.federation {
+            federation.start();
+        }
+
+        if let Some(signaling) = &self.signaling {
+            signaling.start();
+        }
+
+        if let Some(fleet) = &self.fleet {
+            let fleet = fleet.clone();
+            self.supervisor.spawn_listener("Fleet load reports", move || {
+                let fleet = fleet.clone();
+                async move {
+                    if let Err(e) = fleet.run().await {
+                        error!("Fleet load report error: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(snmp) = &self.snmp {
+            let snmp = snmp.clone();
+            self.supervisor.spawn_listener("SNMP subagent", move || {
+                let snmp = snmp.clone();
+                async move {
+                    if let Err(e) = snmp.run().await {
+                        error!("SNMP subagent error: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(failover) = &self.failover {
+            let failover = failover.clone();
+            self.supervisor.spawn_listener("Failover", move || {
+                let failover = failover.clone();
+                async move {
+                    if let Err(e) = failover.run().await {
+                        error!("Failover error: {}", e);
+                    }
+                }
+            });
+        }
+
+        self.rekey.start();
+
+        // Listeners run supervised, restarted after a panic if configured
+        if let Some(port) = self.config.network.p2p.discovery_port {
+            let addr = format!("{}:{}", self.config.server.bind_address, port);
+            self.supervisor.spawn_listener("Address discovery", move || {
+                let discovery = DiscoveryServer::new(addr.clone());
+                async move {
+                    if let Err(e) = discovery.run().await {
+                        error!("Address discovery error: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(descriptor) = DescriptorServer::new(&self.config).map(Arc::new) {
+            self.supervisor.spawn_listener("Server descriptor", move || {
+                let descriptor = descriptor.clone();
+                async move {
+                    if let Err(e) = descriptor.run().await {
+                        error!("Server descriptor error: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(udp_transport) = &self.udp_transport {
+            let udp_transport = udp_transport.clone();
+            self.supervisor.spawn_listener("UDP transport", move || {
+                let udp_transport = udp_transport.clone();
+                async move {
+                    if let Err(e) = udp_transport.run().await {
+                        error!("UDP transport error: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(dns_tunnel) = &self.dns_tunnel {
+            let dns_tunnel = dns_tunnel.clone();
+            self.supervisor.spawn_listener("DNS transport", move || {
+                let dns_tunnel = dns_tunnel.clone();
+                async move {
+                    if let Err(e) = dns_tunnel.run().await {
+                        error!("DNS transport error: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(dns_forwarder) = &self.dns_forwarder {
+            let dns_forwarder = dns_forwarder.clone();
+            self.supervisor.spawn_listener("DNS forwarder", move || {
+                let dns_forwarder = dns_forwarder.clone();
+                async move {
+                    if let Err(e) = dns_forwarder.run().await {
+                        error!("DNS forwarder error: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(icmp_tunnel) = &self.icmp_tunnel {
+            let icmp_tunnel = icmp_tunnel.clone();
+            self.supervisor.spawn_listener("ICMP transport", move || {
+                let icmp_tunnel = icmp_tunnel.clone();
+                async move {
+                    if let Err(e) = icmp_tunnel.run().await {
+                        error!("ICMP transport error: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(knock_gate) = &self.knock_gate {
+            let knock_gate = knock_gate.clone();
+            self.supervisor.spawn_listener("Knock listener", move || {
+                let knock_gate = knock_gate.clone();
+                async move {
+                    if let Err(e) = knock_gate.run().await {
+                        error!("Knock listener error: {}", e);
+                    }
+                }
+            });
+        }
 
         // Main accept loop
         loop {
             match listener.accept().await {
                 Ok((stream, addr)) => {
+                    self.accept_failures.reset_backoff();
+
+                    if let Err(e) = tcp_tuning::tune(&stream, &self.config.server.tcp) {
+                        warn!("Failed to set socket options for {}: {}", addr, e);
+                    }
+                    if let Err(e) = DscpMarker::new(&self.config.server.qos).apply(&SockRef::from(&stream)) {
+                        warn!("Failed to set DSCP for {}: {}", addr, e);
+                    }
+
+                    // Without a knock the connection is dropped unanswered
+                    if self.knock_gate.as_ref().is_some_and(|gate| !gate.is_allowed(addr.ip())) {
+                        debug!("Dropping connection from {} without a knock", addr);
+                        continue;
+                    }
+
                     debug!("New TCP connection from {}", addr);
+                    let accepted = Instant::now();
 
-                    let connection_manager = self.connection_manager.clone();
-                    let config = self.config.clone();
+                    let context = self.connection_context();
+                    let error_counters = self.error_counters.clone();
+                    let abuse = self.abuse.clone();
+                    let config_updates = self.config_publisher.subscribe();
                     let mut shutdown_rx = self.shutdown_tx.subscribe();
 
-                    // Spawn connection handler
-                    tokio::spawn(async move {
-                        tokio::select! {
-                            result = handle_connection(stream, addr, connection_manager, config) => {
-                                if let Err(e) = result {
-                                    error!("Connection error from {}: {}", addr, e);
+                    // Every event of this connection carries peer, session ID and labels
+                    let span = info_span!(
+                        "connection",
+                        peer = %addr,
+                        session_id = tracing::field::Empty,
+                        labels = tracing::field::Empty
+                    );
+
+                    // Spawn connection handler; a panic ends this connection only
+                    self.supervisor.spawn(
+                        format!("Connection handler for {}", addr),
+                        async move {
+                            context.timings.accept_queue_wait.observe(accepted.elapsed());
+                            tokio::select! {
+                                result = handle_connection(stream, addr, context, config_updates, accepted) => {
+                                    if let Err(e) = result {
+                                        error_counters.record(&e);
+                                        abuse.record_error(&e);
+                                        error!(code = e.code(), "Connection error from {}: {}", addr, e);
+                                    }
+                                }
+                                _ = shutdown_rx.recv() => {
+                                    info!("Shutdown signal received, closing connection from {}", addr);
                                 }
-                            }
-                            _ = shutdown_rx.recv() => {
-                                info!("Shutdown signal received, closing connection from {}", addr);
                             }
                         }
-                    });
+                        .instrument(span),
+                    );
+
+                    // Saturated: the handler turns the client away, and
+                    // accepting slows down until there's room again
+                    if let Some(pause) = self.overload.accept_backoff() {
+                        time::sleep(pause).await;
+                    }
                 }
                 Err(e) => {
-                    error!("Failed to accept connection: {}", e);
+                    let kind = AcceptErrorKind::of(&e);
+                    let pause = self.accept_failures.record(kind);
+                    match kind {
+                        AcceptErrorKind::Exhausted => {
+                            warn!("Out of resources accepting connections, pausing {:?}: {}", pause.unwrap_or_default(), e)
+                        }
+                        AcceptErrorKind::Aborted => debug!("Connection aborted before accept: {}", e),
+                        AcceptErrorKind::Other => error!("Failed to accept connection: {}", e),
+                    }
+                    if let Some(pause) = pause {
+                        time::sleep(pause).await;
+                    }
                 }
             }
         }
     }
 
+    /// State shared with connection handlers
+    fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            connection_manager: self.connection_manager.clone(),
+            config: self.config.clone(),
+            error_counters: self.error_counters.clone(),
+            timings: self.timings.clone(),
+            isolation: self.isolation.clone(),
+            accounting: self.accounting.clone(),
+            quality: self.quality.clone(),
+            diagnostics: self.diagnostics.clone(),
+            channels: self.channels.clone(),
+            hooks: self.hooks.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            drain: self.drain.clone(),
+            federation: self.federation.clone(),
+            fleet: self.fleet.clone(),
+            relay: self.relay.clone(),
+            signaling: self.signaling.clone(),
+            data_path: self.data_path.clone(),
+            udp_transport: self.udp_transport.clone(),
+            dns_tunnel: self.dns_tunnel.clone(),
+            icmp_tunnel: self.icmp_tunnel.clone(),
+            registry: self.registry.clone(),
+            decoy: self.decoy.clone(),
+            obfuscation: self.obfuscation.clone(),
+            keylog: self.keylog.clone(),
+            rekey: self.rekey.clone(),
+            overload: self.overload.clone(),
+            memory: self.memory.clone(),
+        }
+    }
+
     /// Start background tasks
     fn start_background_tasks(&self) {
         let connection_manager = self.connection_manager.clone();
-        let timeout = Duration::from_secs(self.config.limits.connection_timeout);
+        let firewall = self.firewall.clone();
+        let sweeper = self.sweeper.clone();
 
-        // Cleanup task
+        // Cleanup task, sweeping stale connections a slice at a time
         tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(60));
-
             loop {
-                interval.tick().await;
-                debug!("Running connection cleanup task");
+                sweeper.sweep_round().await;
+                debug!("Connection cleanup round finished");
 
-                connection_manager.cleanup_stale(timeout).await;
+                if let Some(firewall) = &firewall {
+                    firewall.expire_flows();
+                }
 
-                let stats = connection_manager.get_stats().await;
+                let stats = connection_manager.get_stats();
                 info!(
                     "Server stats - Active: {}, Total: {}, Sent: {}, Received: {}",
                     stats.active_connections,
@@ -115,6 +744,147 @@ impl Server {
         });
     }
 
+    /// Start the admin control socket
+    fn start_control_socket(&self) {
+        if !self.config.admin.enable_control_socket {
+            return;
+        }
+
+        let mut control_server = ControlServer::new(
+            &self.config.admin.control_socket,
+            self.connection_manager.clone(),
+        )
+        .with_error_counters(self.error_counters.clone())
+        .with_drain(self.drain.clone())
+        .with_quality(self.quality.clone())
+        .with_diagnostics(self.diagnostics.clone(), self.udp_transport.clone())
+        .with_client_export(self.config.clone())
+        .with_file_transfers(self.file_transfers.clone())
+        .with_portals(self.portals.clone());
+
+        if let Some(federation) = &self.federation {
+            control_server = control_server.with_site_routes(federation.routes().clone());
+        }
+        if let Some(relay) = &self.relay {
+            control_server = control_server.with_relay(relay.clone());
+        }
+        if let Some(management) = &self.management {
+            control_server = control_server.with_management(management.clone());
+        }
+
+        if let Some(config_path) = &self.config_path {
+            control_server =
+                control_server.with_config_reload(self.config_publisher.clone(), config_path.clone());
+        }
+
+        if let Some(log_control) = &self.log_control {
+            control_server = control_server.with_log_control(log_control.clone());
+        }
+
+        tokio::spawn(async move {
+            if let Err(e) = control_server.run().await {
+                error!("Control socket error: {}", e);
+            }
+        });
+    }
+
+    /// Start the Prometheus metrics endpoint
+    fn start_metrics_exporter(&self) {
+        if !self.config.monitoring.enable_metrics {
+            return;
+        }
+
+        let mut exporter = MetricsExporter::new(
+            &self.config.monitoring,
+            self.connection_manager.clone(),
+            self.error_counters.clone(),
+        );
+        if let Some(relay) = &self.relay {
+            exporter = exporter.with_relay(relay.clone());
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(device) = self.data_path.device() {
+            exporter = exporter.with_tun_device(device.clone());
+        }
+        if let Some(signaling) = &self.signaling {
+            exporter = exporter.with_signaling(signaling.clone());
+        }
+        exporter = exporter.with_rekey(self.rekey.clone());
+        exporter = exporter.with_overload(self.overload.clone());
+        exporter = exporter.with_accept_failures(self.accept_failures.clone());
+        exporter = exporter.with_abuse(self.abuse.clone());
+        exporter = exporter.with_supervisor(self.supervisor.clone());
+        exporter = exporter.with_sweep_durations(self.sweeper.durations());
+        exporter = exporter.with_timings(self.timings.clone());
+        exporter = exporter.with_quality(self.quality.clone());
+        exporter = exporter.with_portals(self.portals.clone());
+        exporter = exporter.with_memory(self.memory.clone());
+        exporter = exporter.with_drain(self.drain.clone());
+        if let Some(dns_forwarder) = &self.dns_forwarder {
+            exporter = exporter.with_dns_forwarder(dns_forwarder.clone());
+        }
+
+        tokio::spawn(async move {
+            if let Err(e) = exporter.run().await {
+                error!("Metrics exporter error: {}", e);
+            }
+        });
+    }
+
+    /// Open the TUN device and route what it reads to the clients
+    ///
+    /// NAT and egress rules name the interface, so the device installs them
+    /// and installs them anew whenever it has to be re-created.
+    #[cfg(target_os = "linux")]
+    fn start_data_path(&self) -> anyhow::Result<()> {
+        let nat = self.nat.clone();
+        if nat.is_some() {
+            let egress = &self.config.network.egress;
+            info!(
+                "Tunnel egress via {} as {}",
+                egress.interface.as_deref().unwrap_or("any interface"),
+                egress
+                    .snat_address
+                    .map(|address| address.to_string())
+                    .unwrap_or_else(|| "masquerade".to_string())
+            );
+        }
+
+        let rules = move || nat.as_ref().map(|nat| nat.commands().to_vec()).unwrap_or_default();
+        let device = TunDevice::open(&self.config.network, rules).context("Failed to create the TUN device")?;
+        let device = Arc::new(device);
+        self.data_path.attach(device.clone())?;
+
+        let router = self.data_path.router().clone();
+        self.supervisor.spawn_listener("TUN device", move || device.clone().run(router.clone()));
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn start_data_path(&self) -> anyhow::Result<()> {
+        anyhow::bail!("The tunnel needs a Linux TUN device")
+    }
+
+    /// Install the firewall rules hiding the listener from unknocked addresses
+    fn install_knock_rules(&self) -> anyhow::Result<Option<RuleGuard>> {
+        let Some(knock_gate) = &self.knock_gate else {
+            return Ok(None);
+        };
+
+        let rules = knock_gate.base_rules();
+        if rules.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(RuleGuard::install(rules).context("Failed to install knock firewall rules")?))
+    }
+
+    /// Application channels, for server code to register handlers and
+    /// send to sessions
+    pub fn channels(&self) -> Arc<Channels> {
+        self.channels.clone()
+    }
+
     /// Shutdown the server
     pub fn shutdown(&self) {
         info!("Shutting down server...");
@@ -122,146 +892,1050 @@ impl Server {
     }
 }
 
+/// Address bridged transports connect to, loopback if the listener is on all interfaces
+fn listener_target(config: &Config) -> anyhow::Result<std::net::SocketAddr> {
+    let mut target: std::net::SocketAddr =
+        format!("{}:{}", config.server.bind_address, config.server.port)
+            .parse()
+            .context("Bridged transports need server.bind_address to be an IP address")?;
+    if target.ip().is_unspecified() {
+        target.set_ip(match target.ip() {
+            std::net::IpAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            std::net::IpAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    Ok(target)
+}
+
 /// Handle a single connection
 async fn handle_connection(
     mut stream: TcpStream,
     peer_addr: std::net::SocketAddr,
-    connection_manager: Arc<ConnectionManager>,
-    config: Arc<Config>,
+    context: ConnectionContext,
+    mut config_updates: watch::Receiver<PushedSettings>,
+    accepted: Instant,
 ) -> Result<()> {
     info!("Handling connection from {}", peer_addr);
 
-    // Create connection
-    let connection = connection_manager.create_connection(peer_addr)?;
-    let session_id = connection.session().id().clone();
+    // Turn clients away with a retry delay while saturated, before anything
+    // is read or handed to the decoy
+    if let Some(reason) = context.overload.check() {
+        return Err(reject_busy(&mut stream, &context, reason).await);
+    }
+    let connection_manager = &context.connection_manager;
+
+    // Probes failing the first message get the decoy instead of a closed
+    // connection; clients that connect and stall are closed
+    let hello_deadline = PacketDeadline::start(Abuse::SlowHandshake, &context.config.limits);
+    let (received, client_hello) = hello_deadline
+        .complete(async {
+            Ok(read_client_hello(&mut stream, context.obfuscation.as_deref(), &context.timings).await)
+        })
+        .await?;
+    let client_hello_packet = match (client_hello, &context.decoy) {
+        (Ok(packet), _) => packet,
+        (Err(e), Some(decoy)) => {
+            // Proxied probes take connection slots like sessions do
+            if connection_manager.active_count() + decoy.active() >= context.config.server.max_connections {
+                return Err(LostLoveError::TooManyConnections);
+            }
+            info!("Handing {} over to the decoy: {}", peer_addr, e);
+            return decoy.serve(stream, &received).await;
+        }
+        (Err(e), None) => return Err(e),
+    };
+
+    // Create connection; the guard removes it however this handler ends
+    let connection = match connection_manager.create_connection(peer_addr) {
+        Ok(connection) => connection,
+        Err(LostLoveError::TooManyConnections) => {
+            let reason = "Server is at capacity".to_string();
+            return Err(reject_busy(&mut stream, &context, reason).await);
+        }
+        Err(e) => return Err(e),
+    };
+    let session_id = *connection.session().id();
+
+    if context.dns_tunnel.as_ref().is_some_and(|dns| dns.is_tunnel(peer_addr)) {
+        connection.session().set_transport(Transport::Dns).await;
+    } else if context.icmp_tunnel.as_ref().is_some_and(|icmp| icmp.is_tunnel(peer_addr)) {
+        connection.session().set_transport(Transport::Icmp).await;
+    }
+
+    // Routing isolation lasts as long as the connection; dropped before the
+    // connection guard, its rules go before the lease can be handed out again
+    let _isolation_guard = match (&context.isolation, connection.session().tunnel_address()) {
+        (Some(isolation), Some(std::net::IpAddr::V4(lease))) => Some(isolation.attach(lease)?),
+        _ => None,
+    };
 
+    tracing::Span::current().record("session_id", tracing::field::display(&session_id));
     info!("Session {} created for {}", session_id, peer_addr);
 
-    // Perform handshake
-    match perform_handshake(&mut stream, &connection).await {
-        Ok(_) => {
+    // Clients that can use UDP attach to the session with this token
+    if let Some(udp_transport) = &context.udp_transport {
+        let token = udp_transport.issue(&session_id);
+        connection.handshake().write().await.set_transport_token(token);
+    }
+    connection
+        .handshake()
+        .write()
+        .await
+        .set_extensions(supported_extensions(&context.config, context.obfuscation.is_some()));
+
+    // Perform handshake; with early data the first config goes out with it
+    let obfuscation = context.obfuscation.as_deref();
+    let redirect = redirect_for(&context, &client_hello_packet, peer_addr);
+    let settings = config_updates.borrow_and_update().clone();
+    let early_data = match perform_handshake(
+        &mut stream,
+        &connection,
+        client_hello_packet,
+        redirect,
+        obfuscation,
+        &context.timings,
+        &settings,
+    )
+    .await
+    {
+        Ok(early_data) => {
+            context.timings.handshake.observe(accepted.elapsed());
             info!("Handshake completed for session {}", session_id);
-            connection.session().set_state(SessionState::Active).await;
+            if let Some(keylog) = &context.keylog {
+                let handshake = connection.handshake().read().await;
+                if let (Some(client_random), Some(server_random)) =
+                    (handshake.client_random(), handshake.server_random())
+                {
+                    keylog.log(SERVER_RANDOM_LABEL, &client_random, &server_random);
+                }
+            }
+            early_data
         }
         Err(e) => {
-            error!("Handshake failed for session {}: {}", session_id, e);
-            connection_manager.remove_connection(&session_id);
+            error!(code = e.code(), "Handshake failed for session {}: {}", session_id, e);
+            if let Some(hooks) = context.hooks.as_ref().filter(|_| is_auth_failure(&e)) {
+                let user = connection.handshake().read().await.user().map(str::to_string);
+                let env = HookEnv::for_session(connection.session(), user.as_deref())
+                    .await
+                    .with("LLP_ERROR_CODE", e.code())
+                    .with("LLP_ERROR", &e);
+                hooks.auth_fail(env);
+            }
+            if let Some(udp_transport) = &context.udp_transport {
+                udp_transport.release(&session_id);
+            }
             return Err(e);
         }
+    };
+
+    let user = connection.handshake().read().await.user().map(str::to_string);
+    if let Some(user) = &user {
+        info!("Session {} belongs to user {}", session_id, user);
+    }
+
+    // Labels claimed in ClientHello; invalid ones are dropped rather than fatal
+    let claimed = connection.handshake().read().await.labels().clone();
+    for (key, value) in claimed {
+        let label = BTreeMap::from([(key, value)]);
+        if let Err(e) = connection.session().update_labels(&label, &[]).await {
+            warn!("Ignoring label claimed by session {}: {}", session_id, e);
+        }
+    }
+    let labels = connection.session().labels().await;
+    if !labels.is_empty() {
+        tracing::Span::current().record("labels", tracing::field::display(&labels));
+        info!("Session {} labels: {}", session_id, labels);
+    }
+
+    // The operator's connect script may turn the session down
+    let refused = match &context.hooks {
+        Some(hooks) => {
+            let env = HookEnv::for_session(connection.session(), user.as_deref()).await;
+            hooks.connect(&env).await.err()
+        }
+        None => None,
+    };
+    let connected = refused.is_none();
+
+    // Per-user state is only kept for users in [policies], while connected
+    let policy_user = user.as_deref().filter(|user| connected && context.accounting.has_policy(user));
+    if let Some(user) = policy_user {
+        context.quality.session_started(user);
     }
 
-    // Main data loop
-    let result = handle_data_loop(&mut stream, &connection).await;
+    // Initial config push unless it went out early, then the main data loop
+    let result = match refused {
+        Some(e) => {
+            warn!(code = e.code(), "Refusing session {}: {}", session_id, e);
+            if let Err(e) = send_disconnect(&mut stream, &connection, DisconnectReason::Refused).await {
+                debug!("Failed to tell session {} it was refused: {}", session_id, e);
+            }
+            Err(e)
+        }
+        None if early_data => {
+            handle_data_loop(&mut stream, &connection, &context, user.as_deref(), config_updates, accepted, true).await
+        }
+        None => match send_config(&mut stream, &connection, &settings).await {
+            Ok(()) => {
+                handle_data_loop(&mut stream, &connection, &context, user.as_deref(), config_updates, accepted, false)
+                    .await
+            }
+            Err(e) => Err(e),
+        },
+    };
 
-    // Cleanup
+    // Cleanup; the guard removes the connection once the handler returns
     info!("Connection closed for session {}: {:?}", session_id, result);
-    connection_manager.remove_connection(&session_id);
+    if let Some(federation) = &context.federation {
+        federation.detach(&session_id);
+    }
+    if let (Some(relay), Some(address)) = (&context.relay, connection.session().tunnel_address()) {
+        relay.forget(address);
+    }
+    if let Some(signaling) = &context.signaling {
+        signaling.unregister(&session_id, connection.session().tunnel_address());
+    }
+    context.rekey.unregister(&session_id);
+    context.diagnostics.unregister(&session_id);
+    context.channels.detach(&session_id);
+    if let Some(udp_transport) = &context.udp_transport {
+        udp_transport.release(&session_id);
+    }
+    if let Some(user) = policy_user {
+        context.quality.session_ended(user);
+        context.rate_limiter.release(user);
+    }
+    // Only sessions the connect script let in get the disconnect script
+    if let Some(hooks) = context.hooks.as_ref().filter(|_| connected) {
+        let stats = connection.session().stats().await;
+        let mut env = HookEnv::for_session(connection.session(), user.as_deref())
+            .await
+            .with("LLP_BYTES_SENT", stats.bytes_sent)
+            .with("LLP_BYTES_RECEIVED", stats.bytes_received)
+            .with("LLP_DURATION", connection.session().uptime().as_secs());
+        if let Err(e) = &result {
+            env = env.with("LLP_ERROR", e);
+        }
+        hooks.disconnect(env);
+    }
 
     result
 }
 
-/// Perform handshake with client
-async fn perform_handshake(
-    stream: &mut TcpStream,
-    connection: &Arc<crate::core::connection::Connection>,
-) -> Result<()> {
-    debug!("Starting handshake for session {}", connection.session().id());
+/// Handshake errors the auth-fail hook runs for; load shedding, draining,
+/// steering and connections dropped mid-handshake aren't failed logins
+fn is_auth_failure(e: &LostLoveError) -> bool {
+    !matches!(
+        e,
+        LostLoveError::Io(_)
+            | LostLoveError::HandshakeRejected {
+                category: HandshakeFailureCategory::ServerDraining
+                    | HandshakeFailureCategory::ServerBusy
+                    | HandshakeFailureCategory::ServerSteered,
+                ..
+            }
+    )
+}
 
-    // Read ClientHello packet
-    let client_hello_packet = read_packet(stream).await?;
+/// Answer the ClientHello with `server-busy`, returns the error to end the connection with
+async fn reject_busy(stream: &mut TcpStream, context: &ConnectionContext, reason: String) -> LostLoveError {
+    let overload = &context.overload;
+    overload.record_shed();
+    warn!("Refusing new session: {}", reason);
 
-    if client_hello_packet.header.packet_type != PacketType::HandshakeInit {
-        return Err(LostLoveError::HandshakeFailed(
-            "Expected HandshakeInit packet".to_string(),
-        ));
+    let failure = HandshakeMessage::busy(
+        reason.clone(),
+        overload.retry_after(),
+        context.connection_manager.cipher_suites(),
+    );
+    let sent = match failure.to_bytes() {
+        Ok(payload) => {
+            let packet = Packet::new(PacketType::HandshakeResponse, payload);
+            write_handshake_response(stream, &packet, context.obfuscation.as_deref(), &context.timings).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = sent {
+        debug!("Failed to send busy response: {}", e);
+    }
+
+    LostLoveError::HandshakeRejected {
+        category: HandshakeFailureCategory::ServerBusy,
+        reason,
+    }
+}
+
+/// Where to send a new client instead of answering its hello, if anywhere
+fn redirect_for(
+    context: &ConnectionContext,
+    client_hello_packet: &Packet,
+    peer_addr: std::net::SocketAddr,
+) -> Option<Redirect> {
+    // A draining server takes no new sessions, point the client elsewhere
+    if context.drain.is_draining() {
+        return Some(Redirect::Draining(context.drain.redirect()));
     }
 
-    // Parse ClientHello message
-    let client_hello = HandshakeMessage::from_bytes(&client_hello_packet.payload)?;
+    // Users keep landing on the same node; clients without one by address
+    let fleet = context.fleet.as_ref()?;
+    let user = match HandshakeMessage::from_bytes(&client_hello_packet.payload) {
+        Ok(HandshakeMessage::ClientHello { user, .. }) => user,
+        _ => return None,
+    };
+    // Links between sites stay with the server they were configured for
+    if let (Some(federation), Some(user)) = (&context.federation, &user) {
+        if federation.is_peer(user) {
+            return None;
+        }
+    }
+    let key = user.unwrap_or_else(|| peer_addr.ip().to_string());
+    let address = fleet.steer(&key)?;
+    info!("Steering {} to fleet node {}", key, address);
+    Some(Redirect::Steered(address))
+}
+
+/// Perform handshake with client, returns whether `settings` went out right
+/// behind ServerHello as early data
+///
+/// Early data is sent before the connect hook runs and before the client
+/// has used its keys, so it carries only the pushed settings, never
+/// anything from the tunnel.
+async fn perform_handshake(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    client_hello_packet: Packet,
+    redirect: Option<Redirect>,
+    obfuscation: Option<&HandshakeObfuscation>,
+    timings: &Timings,
+    settings: &PushedSettings,
+) -> Result<bool> {
+    debug!("Starting handshake for session {}", connection.session().id());
 
-    // Process ClientHello and generate ServerHello
-    let server_hello = {
+    let outcome = {
         let mut handshake = connection.handshake().write().await;
-        handshake.process_client_hello(&client_hello)?
+        engine::answer_hello(&mut handshake, &client_hello_packet, redirect)?
     };
 
-    // Send ServerHello
-    let server_hello_bytes = server_hello.to_bytes()?;
-    let response_packet = Packet::new(PacketType::HandshakeResponse, server_hello_bytes);
+    write_handshake_response(stream, &outcome.response, obfuscation, timings).await?;
+    if let Some(rejected) = outcome.rejected {
+        return Err(rejected);
+    }
+    // In the same flight as ServerHello, the client can tunnel as soon as it
+    // has both
+    let early_data = connection.handshake().read().await.negotiated_extensions().early_data;
+    if early_data {
+        send_config(stream, connection, settings).await?;
+    }
 
-    write_packet(stream, &response_packet).await?;
+    // Both sides hold the session keys once ServerHello is out
+    connection.handshake().write().await.confirm_keys()?;
+    connection.activate().await?;
 
-    debug!("Handshake completed for session {}", connection.session().id());
+    debug!(
+        "Handshake completed for session {}, extensions {:?}",
+        connection.session().id(),
+        connection.handshake().read().await.negotiated_extensions()
+    );
 
-    Ok(())
+    Ok(early_data)
+}
+
+/// Handshake extensions this server accepts
+///
+/// Features without an implementation here, such as compression, FEC and
+/// resumption, are never accepted, so clients don't assume them.
+fn supported_extensions(config: &Config, obfuscated: bool) -> HelloExtensions {
+    HelloExtensions {
+        gso_max_size: config.network.tun_offload.then_some(GSO_MAX_SIZE as u32),
+        max_streams: Some(config.limits.max_streams_per_connection.min(u16::MAX as usize) as u16),
+        obfuscation: if obfuscated { vec!["handshake".to_string()] } else { Vec::new() },
+        early_data: config.server.tcp.early_data,
+        ..HelloExtensions::default()
+    }
 }
 
 /// Handle data loop
 async fn handle_data_loop(
     stream: &mut TcpStream,
     connection: &Arc<crate::core::connection::Connection>,
+    context: &ConnectionContext,
+    user: Option<&str>,
+    mut config_updates: watch::Receiver<PushedSettings>,
+    accepted: Instant,
+    early_data: bool,
 ) -> Result<()> {
+    let config = &context.config;
+    let mut first_data = Some(accepted);
+    let error_counters = &context.error_counters;
+    let accounting = &context.accounting;
+
+    let mut sampler = PacketTraceSampler::new(config.monitoring.packet_trace_sample_rate);
+    let mut engine = ProtocolEngine::new(connection.session().peer_address(), Transport::Tcp)
+        .with_error_limit(config.limits.error_responses_per_sec)
+        .with_rate_limit(context.rate_limiter.bucket_for(user))
+        .with_registry(context.registry.clone());
     let mut buffer = BytesMut::with_capacity(4096);
+    let mut buffer_charge = context.memory.charge(MemoryUse::ReadBuffers);
+    buffer_charge.resize(buffer.capacity());
+    // Replies to the client, shared between its streams by weight
+    let mut outbound = StreamScheduler::new();
+    // Tunneled packets may carry their own DSCP over to the connection
+    let mut dscp = DscpMarker::new(&config.server.qos);
+    let mut renewal = renewal_timer(&config_updates.borrow());
 
-    loop {
-        // Read packet header
-        let header_bytes = match read_exact(stream, HEADER_SIZE).await {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    debug!("Client disconnected");
-                    return Ok(());
-                }
-                return Err(LostLoveError::from(e));
-            }
-        };
+    // Only users with a policy are checked
+    let policy_user = user.filter(|user| accounting.has_policy(user));
+    let mut policy_check = time::interval_at(
+        time::Instant::now() + POLICY_CHECK_INTERVAL,
+        POLICY_CHECK_INTERVAL,
+    );
+    let mut warned = None;
+    let mut drain_expired = context.drain.subscribe();
 
-        // Parse packet
-        buffer.clear();
-        buffer.extend_from_slice(&header_bytes);
+    // Idle sessions release their buffers, which grow back with the next packet
+    let hibernate_after = Duration::from_secs(config.limits.hibernate_after);
+    let mut hibernation_check = time::interval_at(
+        time::Instant::now() + HIBERNATION_CHECK_INTERVAL,
+        HIBERNATION_CHECK_INTERVAL,
+    );
 
-        // For now, just echo back (in Phase 1 we don't have routing yet)
-        let packet = match Packet::deserialize(&buffer[..]) {
-            Ok(p) => p,
-            Err(e) => {
-                warn!("Failed to parse packet: {}", e);
-                connection.session().record_error().await;
-                continue;
-            }
-        };
+    // Peer sites get our routes again whenever they change, once their
+    // first announcement proved who they are
+    let mut peer_site = None;
+    let mut route_changes = None;
+    let mut link = None;
 
-        connection.session().record_packet_received(packet.size()).await;
-        connection.update_activity().await;
+    // Hole punching signals from other clients' sessions
+    let mut peer_signals = context
+        .signaling
+        .as_ref()
+        .filter(|_| connection.session().tunnel_address().is_some())
+        .map(|signaling| signaling.register(connection.session().id()));
 
-        debug!(
-            "Received packet: type={:?}, stream={}, seq={}",
-            packet.header.packet_type, packet.header.stream_id, packet.header.sequence_number
-        );
+    // Path probes `llpctl diagnose` sends over TCP
+    let mut path_probes = context.diagnostics.register(connection.session().id());
 
-        match packet.header.packet_type {
-            PacketType::Data => {
-                // For Phase 1: just acknowledge
-                let ack = Packet::new(PacketType::Ack, Bytes::new());
-                write_packet(stream, &ack).await?;
-                connection.session().record_packet_sent(ack.size()).await;
-            }
-            PacketType::KeepAlive => {
-                // Respond to keepalive
-                let response = Packet::new(PacketType::KeepAlive, Bytes::new());
-                write_packet(stream, &response).await?;
-                connection.session().record_packet_sent(response.size()).await;
-            }
-            PacketType::Disconnect => {
-                info!("Client requested disconnect");
-                return Ok(());
-            }
-            _ => {
-                debug!("Unhandled packet type: {:?}", packet.header.packet_type);
-            }
-        }
+    // Packets the router sends this client's way
+    let mut tunneled = connection.open_tunnel()?;
+
+    // Server data for the application channels the client opens
+    let mut channel_messages = context.channels.attach(connection.session().id());
+    let mut open_channels = OpenChannels::new();
+
+    // Rotations of this session's keys, once the handshake established some
+    let mut key_updates = connection
+        .key_manager()
+        .map(|key_manager| context.rekey.register(connection.session().id(), key_manager.clone()));
+
+    if *drain_expired.borrow_and_update() {
+        send_disconnect(stream, connection, DisconnectReason::ServerDraining).await?;
+        return Ok(());
+    }
+
+    if let Some(user) = policy_user {
+        if enforce_policy(stream, connection, accounting, user, &mut warned).await? {
+            return Ok(());
+        }
+    }
+
+    loop {
+        // Control goes first: rekeys, drain and policy cutoffs, config and
+        // signals are sent before more of the client's data is read, so a
+        // busy session can't starve them
+        tokio::select! {
+            biased;
+            Ok(()) = drain_expired.changed() => {
+                if *drain_expired.borrow_and_update() {
+                    info!("Disconnecting session at the drain deadline");
+                    send_disconnect(stream, connection, DisconnectReason::ServerDraining).await?;
+                    return Ok(());
+                }
+                continue;
+            }
+            Some(epoch) = next_key_update(&mut key_updates) => {
+                send_key_update(stream, connection, epoch).await?;
+                continue;
+            }
+            _ = policy_check.tick(), if policy_user.is_some() => {
+                if let Some(user) = policy_user {
+                    if enforce_policy(stream, connection, accounting, user, &mut warned).await? {
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
+            Ok(()) = config_updates.changed() => {
+                let settings = config_updates.borrow_and_update().clone();
+                send_config(stream, connection, &settings).await?;
+                renewal = renewal_timer(&settings);
+                continue;
+            }
+            _ = renewal.tick() => {
+                let settings = config_updates.borrow().clone();
+                send_config(stream, connection, &settings).await?;
+                continue;
+            }
+            Some(signal) = next_peer_signal(&mut peer_signals) => {
+                let packet = Packet::new(PacketType::PeerSignal, signal.to_bytes()?);
+                send_packet(stream, connection, packet).await?;
+                continue;
+            }
+            Some(packet) = path_probes.recv() => {
+                send_packet(stream, connection, packet).await?;
+                continue;
+            }
+            Some(message) = channel_messages.recv() => {
+                match open_channels.stream(&message.channel) {
+                    Some(channel_stream) => {
+                        let packet =
+                            Packet::new_with_metadata(PacketType::Data, channel_stream.value(), 0, message.data);
+                        outbound.push(channel_stream, packet.size(), packet);
+                        send_queued(stream, connection, &mut outbound).await?;
+                    }
+                    None => debug!(
+                        "Dropping data for channel {}, session {} hasn't opened it",
+                        message.channel,
+                        connection.session().id()
+                    ),
+                }
+                continue;
+            }
+            Some(()) = next_route_change(&mut route_changes) => {
+                if let (Some(federation), Some(site), Some(link)) = (&context.federation, peer_site, link.as_mut()) {
+                    let size = federation.announce_to(stream, site, link).await?;
+                    connection.session().record_packet_sent(size).await;
+                }
+                continue;
+            }
+            _ = hibernation_check.tick(), if !hibernate_after.is_zero() || context.memory.limit() > 0 => {
+                // Short of memory, sessions idle for a check interval hibernate early
+                let idle_limit = if context.memory.exhausted() {
+                    Some(HIBERNATION_CHECK_INTERVAL)
+                } else {
+                    Some(hibernate_after).filter(|after| !after.is_zero())
+                };
+                if let Some(idle_limit) = idle_limit {
+                    if !connection.is_hibernated()
+                        && connection.session().time_since_activity().await >= idle_limit
+                    {
+                        hibernate(connection, context, &mut buffer).await;
+                        buffer_charge.resize(buffer.capacity());
+                    }
+                }
+                continue;
+            }
+            readable = stream.readable() => readable?,
+            Some(packet) = tunneled.recv() => {
+                send_tunneled(stream, connection, context, &mut dscp, packet).await?;
+                continue;
+            }
+        }
+
+        // Read packet header; once its first byte is in, the client has to
+        // finish the packet in time
+        let mut header_bytes = [0u8; HEADER_SIZE];
+        let started = stream.read(&mut header_bytes).await?;
+        if started == 0 {
+            debug!("Client disconnected");
+            return Ok(());
+        }
+        let deadline = PacketDeadline::start(Abuse::SlowPacket, &config.limits);
+        deadline.read_exact(stream, &mut header_bytes[started..]).await?;
+
+        buffer.clear();
+        buffer.extend_from_slice(&header_bytes);
+        deadline
+            .read(read_payload_within(stream, &mut buffer, config.limits.max_packet_size))
+            .await?;
+        buffer_charge.resize(buffer.capacity());
+        let read_at = Instant::now();
+
+        for event in engine.handle(&buffer) {
+            match event {
+                EngineEvent::Received { header, size } => {
+                    // A replayed packet goes no further than this
+                    if let Err(e) = connection.session().check_sequence(&header).await {
+                        error_counters.record(&e);
+                        debug!(code = e.code(), "Dropped packet: {}", e);
+                        break;
+                    }
+                    connection.session().record_packet_received(size).await;
+                    connection.update_activity().await;
+
+                    match sampler.sample(connection.packet_tracing()) {
+                        TraceDecision::Forced => info!(
+                            target: "llp::packet",
+                            "Received packet: type={:?}, stream={}, seq={}, size={}",
+                            header.packet_type,
+                            header.stream_id,
+                            header.sequence_number,
+                            size
+                        ),
+                        TraceDecision::Sampled => debug!(
+                            target: "llp::packet",
+                            "Received packet: type={:?}, stream={}, seq={}, size={}",
+                            header.packet_type,
+                            header.stream_id,
+                            header.sequence_number,
+                            size
+                        ),
+                        TraceDecision::Skip => {}
+                    }
+                }
+                EngineEvent::Rejected(e) => {
+                    error_counters.record(&e);
+                    if matches!(e, LostLoveError::RateLimited) {
+                        debug!(code = e.code(), "Dropped packet over the rate limit");
+                    } else {
+                        warn!(code = e.code(), "Failed to parse packet: {}", e);
+                        connection.session().record_error().await;
+                    }
+                }
+                EngineEvent::Data(packet) => {
+                    // Channel data is for the server, not the tunnel
+                    let data_stream = StreamId::new(packet.header.stream_id);
+                    if let Some(name) = open_channels.name(data_stream) {
+                        let session_id = connection.session().id();
+                        if let Some(reply) = context.channels.receive(session_id, name, &packet.payload) {
+                            let reply = Packet::new_with_metadata(PacketType::Data, data_stream.value(), 0, reply);
+                            outbound.push(data_stream, reply.size(), reply);
+                        }
+                        continue;
+                    }
+
+                    if let Some(accepted) = first_data.take() {
+                        context.timings.first_data(early_data).observe(read_at.duration_since(accepted));
+                    }
+
+                    // Data over TCP again means the client gave up on UDP
+                    if connection.session().transport().await == Transport::Udp {
+                        connection.session().set_transport(Transport::Tcp).await;
+                        info!("Session {} fell back to TCP", connection.session().id());
+                    }
+
+                    if let Some(user) = user {
+                        accounting.record(user, packet.size() as u64);
+                    }
+                    if let Some(user) = policy_user {
+                        if enforce_policy(stream, connection, accounting, user, &mut warned).await? {
+                            return Ok(());
+                        }
+                    }
+
+                    if !packet.payload.is_empty() {
+                        let session_id = connection.session().id();
+                        if let Err(e) = context.data_path.forward(session_id, &packet.payload).await {
+                            context.error_counters.record(&e);
+                            debug!(code = e.code(), "Dropped tunneled packet: {}", e);
+                        }
+                    }
+                }
+                EngineEvent::Control(packet) => match packet.header.packet_type {
+                    PacketType::RouteAnnounce => {
+                        let federation = context.federation.as_deref();
+                        if exchange_routes(stream, connection, federation, user, &mut link, &packet).await? && peer_site.is_none() {
+                            peer_site = user;
+                            route_changes = federation.map(Federation::subscribe);
+                        }
+                    }
+                    PacketType::PeerSignal => {
+                        handle_peer_signal(connection, context.signaling.as_deref(), &packet);
+                    }
+                    PacketType::KeyUpdate => {
+                        handle_key_update(stream, connection, &context.rekey, &packet).await?;
+                    }
+                    PacketType::QualityReport => match QualityReport::from_bytes(&packet.payload) {
+                        Ok(report) => context.quality.record(user, &report),
+                        Err(e) => debug!("Invalid quality report from session {}: {}", connection.session().id(), e),
+                    },
+                    PacketType::PathProbe => context.diagnostics.echo(connection.session().id(), &packet),
+                    PacketType::StreamOpen => {
+                        open_stream(connection, context, &mut outbound, &mut engine, &mut open_channels, &packet)
+                    }
+                    _ => {}
+                },
+                EngineEvent::Transmit(packet) => {
+                    let stream_id = match packet.header.packet_type {
+                        PacketType::Data => StreamId::new(packet.header.stream_id),
+                        _ => StreamId::CONTROL,
+                    };
+                    outbound.push(stream_id, packet.size(), packet);
+                }
+                EngineEvent::Extension { received, reply } => {
+                    connection.session().record_packet_received(HEADER_SIZE + received.payload.len()).await;
+                    connection.update_activity().await;
+                    if let Some(reply) = reply {
+                        let bytes = reply.serialize();
+                        stream.write_all(&bytes).await?;
+                        stream.flush().await?;
+                        connection.session().record_packet_sent(bytes.len()).await;
+                    }
+                }
+                EngineEvent::Closed => {
+                    // The final Ack was queued before this
+                    send_queued(stream, connection, &mut outbound).await?;
+                    info!("Client requested disconnect");
+                    connection.session().apply(SessionEvent::Disconnect).await?;
+                    close_gracefully(stream, CLOSE_LINGER).await;
+                    return Ok(());
+                }
+            }
+        }
+        send_queued(stream, connection, &mut outbound).await?;
+        context.timings.packet_processing.observe(read_at.elapsed());
+
+        for _ in 0..TUNNEL_BATCH {
+            let Ok(packet) = tunneled.try_recv() else {
+                break;
+            };
+            send_tunneled(stream, connection, context, &mut dscp, packet).await?;
+        }
+    }
+}
+
+/// Send a packet routed to the client, over UDP while the session uses it
+///
+/// UDP datagrams share the transport's socket and keep its fixed DSCP; on
+/// the connection's own socket `dscp` marks each packet.
+async fn send_tunneled(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    context: &ConnectionContext,
+    dscp: &mut DscpMarker,
+    payload: Bytes,
+) -> Result<()> {
+    let packet = connection.session().stamp(Packet::new(PacketType::Data, payload)).await?;
+    if let Some(udp_transport) = &context.udp_transport {
+        if connection.session().transport().await == Transport::Udp {
+            let datagram = packet.serialize();
+            match udp_transport.send_to_session(connection.session().id(), &datagram).await {
+                Ok(()) => {
+                    connection.session().record_packet_sent(datagram.len()).await;
+                    return Ok(());
+                }
+                Err(e) => debug!("Sending over UDP failed, using TCP: {}", e),
+            }
+        }
+    }
+
+    if let Err(e) = dscp.mark(&SockRef::from(&*stream), &packet.payload) {
+        debug!("Failed to set DSCP: {}", e);
+    }
+    write_packet(stream, &packet).await?;
+    connection.session().record_packet_sent(packet.size()).await;
+    Ok(())
+}
+
+/// Write the client's queued packets, in the order the scheduler picks
+async fn send_queued(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    outbound: &mut StreamScheduler<Packet>,
+) -> Result<()> {
+    while let Some(packet) = outbound.pop() {
+        send_packet(stream, connection, packet).await?;
+    }
+    Ok(())
+}
+
+/// Open a client's stream, or change its weight
+fn open_stream(
+    connection: &Arc<crate::core::connection::Connection>,
+    context: &ConnectionContext,
+    outbound: &mut StreamScheduler<Packet>,
+    engine: &mut ProtocolEngine,
+    open_channels: &mut OpenChannels,
+    packet: &Packet,
+) {
+    let config = &context.config;
+    let session = connection.session();
+    let stream = StreamId::new(packet.header.stream_id);
+    if stream.is_control() {
+        warn!("Session {} tried to open the control stream", session.id());
+        return;
+    }
+    let open = match StreamOpen::from_bytes(&packet.payload) {
+        Ok(open) => open,
+        Err(e) => {
+            warn!("Invalid stream open from session {}: {}", session.id(), e);
+            return;
+        }
+    };
+
+    if let Some(name) = open.channel.as_deref().filter(|name| !context.channels.is_registered(name)) {
+        warn!("Session {} asked for unknown channel {}", session.id(), name);
+        return;
+    }
+
+    if outbound.weight(stream).is_none() && outbound.stream_count() >= config.limits.max_streams_per_connection {
+        warn!(
+            "Session {} is at its limit of {} streams, not opening {}",
+            session.id(),
+            config.limits.max_streams_per_connection,
+            stream
+        );
+        return;
+    }
+    if outbound.weight(stream).is_none() {
+        engine.set_stream_mode(stream, open.mode);
+        if let Some(name) = &open.channel {
+            open_channels.open(stream, name);
+            context.channels.opened(session.id(), name);
+        }
+    }
+    outbound.set_weight(stream, open.weight);
+    debug!("Session {} opened {} with weight {}, {:?}", session.id(), stream, open.weight, open.mode);
+}
+
+/// Release what an idle session holds until its next packet wakes it
+async fn hibernate(
+    connection: &Arc<crate::core::connection::Connection>,
+    context: &ConnectionContext,
+    buffer: &mut BytesMut,
+) {
+    if !connection.hibernate().await {
+        return;
+    }
+    *buffer = BytesMut::new();
+    if let Some(udp_transport) = &context.udp_transport {
+        udp_transport.hibernate(connection.session().id());
+    }
+    debug!("Session {} hibernating", connection.session().id());
+}
+
+/// Apply a user's access policy, returns true once the session was cut off
+///
+/// Each kind of cutoff is announced with one Warning packet beforehand.
+async fn enforce_policy(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    accounting: &Accounting,
+    user: &str,
+    warned: &mut Option<DisconnectReason>,
+) -> Result<bool> {
+    match accounting.evaluate(user) {
+        PolicyDecision::Allow => Ok(false),
+        PolicyDecision::Warn(warning) => {
+            if *warned != Some(warning.reason) {
+                info!("Warning user {} of upcoming cutoff: {}", user, warning.reason.as_str());
+                *warned = Some(warning.reason);
+
+                let packet = Packet::new(PacketType::Warning, warning.to_bytes()?);
+                send_packet(stream, connection, packet).await?;
+            }
+            Ok(false)
+        }
+        PolicyDecision::Cutoff(reason) => {
+            info!("Disconnecting user {}: {}", user, reason.as_str());
+            send_disconnect(stream, connection, reason).await?;
+
+            Ok(true)
+        }
+    }
+}
+
+/// Wait for the next route table change, forever without federation
+async fn next_route_change(changes: &mut Option<watch::Receiver<u64>>) -> Option<()> {
+    match changes {
+        Some(changes) => {
+            changes.changed().await.ok()?;
+            changes.borrow_and_update();
+            Some(())
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for the next hole punching signal, forever without signaling
+async fn next_peer_signal(signals: &mut Option<mpsc::Receiver<PeerSignal>>) -> Option<PeerSignal> {
+    match signals {
+        Some(signals) => signals.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for the next key rotation, forever for sessions without keys
+async fn next_key_update(updates: &mut Option<mpsc::Receiver<u64>>) -> Option<u64> {
+    match updates {
+        Some(updates) => updates.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Tell the client the session moved to the keys of `epoch`
+async fn send_key_update(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    epoch: u64,
+) -> Result<()> {
+    connection.session().set_key_epoch(epoch).await?;
+
+    let packet = Packet::new(PacketType::KeyUpdate, KeyUpdate::new(epoch).to_bytes()?);
+    send_packet(stream, connection, packet).await?;
+    Ok(())
+}
+
+/// Follow a client's key rotation, or take its answer to ours
+async fn handle_key_update(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    rekey: &RekeyScheduler,
+    packet: &Packet,
+) -> Result<()> {
+    let session = connection.session();
+    let Some(key_manager) = connection.key_manager() else {
+        debug!("Ignoring key update, session {} has no keys", session.id());
+        return Ok(());
+    };
+
+    let update = match KeyUpdate::from_bytes(&packet.payload) {
+        Ok(update) => update,
+        Err(e) => {
+            warn!("Invalid key update from session {}: {}", session.id(), e);
+            return Ok(());
+        }
+    };
+
+    match rekey.peer_update(session.id(), key_manager, update.epoch).await {
+        Ok(true) => send_key_update(stream, connection, update.epoch).await,
+        Ok(false) => Ok(()),
+        Err(e) => {
+            warn!("Rejected key update from session {}: {}", session.id(), e);
+            Ok(())
+        }
+    }
+}
+
+/// Pass a client's hole punching signal to the signaling hub
+fn handle_peer_signal(
+    connection: &Arc<crate::core::connection::Connection>,
+    signaling: Option<&Signaling>,
+    packet: &Packet,
+) {
+    let session = connection.session();
+    let (Some(signaling), Some(address)) = (signaling, session.tunnel_address()) else {
+        debug!("Ignoring peer signal, hole punching is not enabled");
+        return;
+    };
+
+    match PeerSignal::from_bytes(&packet.payload) {
+        Ok(signal) => signaling.handle(address, session.peer_address().ip(), signal),
+        Err(e) => warn!("Invalid peer signal from session {}: {}", session.id(), e),
     }
 }
 
+/// Learn a peer site's routes and answer with our own, returns whether the
+/// announcement proved the peer's name
+async fn exchange_routes(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    federation: Option<&Federation>,
+    user: Option<&str>,
+    link: &mut Option<Link>,
+    packet: &Packet,
+) -> Result<bool> {
+    let (Some(federation), Some(site)) = (federation, user) else {
+        warn!("Ignoring route announcement from a client");
+        return Ok(false);
+    };
+    if !federation.is_peer(site) {
+        warn!("Ignoring route announcement from {}, not a peer site", site);
+        return Ok(false);
+    }
+
+    let link = match link {
+        Some(link) => link,
+        None => link.insert(Link::new(LinkSide::Listener, link_nonces(connection).await?)),
+    };
+    if let Err(e) = RouteAnnouncement::from_bytes(&packet.payload).and_then(|announcement| {
+        federation.authenticate(site, &announcement, link)?;
+        federation.learn(site, &announcement)?;
+        federation.attach(site, *connection.session().id());
+        Ok(())
+    }) {
+        warn!(code = e.code(), "Rejected routes from site {}: {}", site, e);
+        return Ok(false);
+    }
+
+    let size = federation.announce_to(stream, site, link).await?;
+    connection.session().record_packet_sent(size).await;
+
+    Ok(true)
+}
+
+/// Handshake randoms of a session, which federation proofs are bound to
+async fn link_nonces(connection: &crate::core::connection::Connection) -> Result<LinkNonces> {
+    let handshake = connection.handshake().read().await;
+    match (handshake.client_random(), handshake.server_random()) {
+        (Some(client_random), Some(server_random)) => Ok(LinkNonces { client_random, server_random }),
+        _ => Err(LostLoveError::HandshakeFailed("Session has no handshake randoms".to_string())),
+    }
+}
+
+/// Tell the client why the server ends its session, then close in order
+async fn send_disconnect(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    reason: DisconnectReason,
+) -> Result<()> {
+    let message = DisconnectMessage::new(reason);
+    let packet = Packet::new(PacketType::Disconnect, message.to_bytes()?);
+
+    send_packet(stream, connection, packet).await?;
+    connection.session().apply(SessionEvent::Disconnect).await?;
+    close_gracefully(stream, CLOSE_LINGER).await;
+
+    Ok(())
+}
+
+/// Close a connection without losing what either side still has in flight
+///
+/// Our side is half-closed once everything written is flushed, so the peer
+/// reads all of it before EOF. Whatever the peer still sends is read and
+/// dropped until it closes too, or `linger` passes: closing with unread
+/// bytes makes the kernel answer with a reset, and a reset throws away data
+/// the peer hasn't read yet.
+async fn close_gracefully(stream: &mut TcpStream, linger: Duration) {
+    if let Err(e) = stream.shutdown().await {
+        debug!("Failed to half-close connection: {}", e);
+        return;
+    }
+
+    let mut discard = [0u8; 1024];
+    let drained = time::timeout(linger, async {
+        while let Ok(n) = stream.read(&mut discard).await {
+            if n == 0 {
+                break;
+            }
+        }
+    })
+    .await;
+    if drained.is_err() {
+        debug!("Peer didn't close within {}s of our half-close", linger.as_secs());
+    }
+}
+
+/// Timer for the next config renewal
+fn renewal_timer(settings: &PushedSettings) -> time::Interval {
+    let period = settings.renew_interval();
+    time::interval_at(time::Instant::now() + period, period)
+}
+
+/// Push the current client settings
+async fn send_config(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    settings: &PushedSettings,
+) -> Result<()> {
+    let message = settings.for_session(connection.session().tunnel_address());
+    let packet = Packet::new(PacketType::ConfigUpdate, message.to_bytes()?);
+
+    send_packet(stream, connection, packet).await?;
+    debug!("Sent config serial {} to client", settings.serial);
+
+    Ok(())
+}
+
 /// Read exact number of bytes from stream
 async fn read_exact(stream: &mut TcpStream, len: usize) -> std::io::Result<Vec<u8>> {
     let mut buf = vec![0u8; len];
@@ -270,28 +1944,147 @@ async fn read_exact(stream: &mut TcpStream, len: usize) -> std::io::Result<Vec<u
 }
 
 /// Read a complete packet from stream
-async fn read_packet(stream: &mut TcpStream) -> Result<Packet> {
-    // Read header
+pub(crate) async fn read_packet(stream: &mut TcpStream) -> Result<Packet> {
     let header_bytes = read_exact(stream, HEADER_SIZE).await?;
-
-    // Parse header to get payload length (for now we read remaining data)
-    // In a real implementation, we'd include length in the header
     let mut buf = BytesMut::from(&header_bytes[..]);
+    read_payload(stream, &mut buf).await?;
 
-    // For Phase 1, we assume small payloads that fit in one read
-    // Read up to 4KB of payload
-    let mut payload_buf = vec![0u8; 4096];
-    let n = stream.read(&mut payload_buf).await?;
+    Packet::deserialize(buf)
+}
 
-    if n > 0 {
-        buf.extend_from_slice(&payload_buf[..n]);
+/// Read the ClientHello packet, returning the bytes read along with it
+async fn read_client_hello(
+    stream: &mut TcpStream,
+    obfuscation: Option<&HandshakeObfuscation>,
+    timings: &Timings,
+) -> (Vec<u8>, Result<Packet>) {
+    let mut received = Vec::with_capacity(HEADER_SIZE);
+    let client_hello = match obfuscation {
+        Some(obfuscation) => read_obfuscated_hello(stream, obfuscation, timings, &mut received).await,
+        None => read_plain_hello(stream, &mut received).await,
     }
+    .and_then(|bytes| {
+        let packet = Packet::deserialize(Bytes::from(bytes))?;
+        HandshakeMessage::from_bytes(&packet.payload)?;
+        Ok(packet)
+    });
+    (received, client_hello)
+}
 
-    Packet::deserialize(buf)
+/// Read a HandshakeInit packet sent as is
+///
+/// Fails as soon as the bytes can't start one, so probes speaking another
+/// protocol aren't kept waiting for a full header.
+async fn read_plain_hello(stream: &mut TcpStream, received: &mut Vec<u8>) -> Result<Vec<u8>> {
+    read_until(stream, received, HEADER_SIZE, is_handshake_prefix).await?;
+
+    let mut packet = BytesMut::from(&received[..]);
+    read_payload(stream, &mut packet).await?;
+    received.extend_from_slice(&packet[HEADER_SIZE..]);
+    Ok(packet.to_vec())
+}
+
+/// Read an obfuscated HandshakeInit packet
+async fn read_obfuscated_hello(
+    stream: &mut TcpStream,
+    obfuscation: &HandshakeObfuscation,
+    timings: &Timings,
+    received: &mut Vec<u8>,
+) -> Result<Vec<u8>> {
+    read_until(stream, received, OBFUSCATED_HEADER_SIZE, |_| true).await?;
+    let body_len = timings.time_crypto(CryptoOperation::HelloOpen, || {
+        obfuscation.open_header(HelloSender::Client, received)
+    })?;
+
+    read_until(stream, received, OBFUSCATED_HEADER_SIZE + body_len, |_| true).await?;
+    let (header, body) = received.split_at(OBFUSCATED_HEADER_SIZE);
+    timings.time_crypto(CryptoOperation::HelloOpen, || {
+        obfuscation.open_body(HelloSender::Client, header, body)
+    })
+}
+
+/// Read until `received` holds `len` bytes, stopping early once `plausible`
+/// rejects the bytes so far
+async fn read_until(
+    stream: &mut TcpStream,
+    received: &mut Vec<u8>,
+    len: usize,
+    plausible: impl Fn(&[u8]) -> bool,
+) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    while received.len() < len {
+        let want = (len - received.len()).min(buf.len());
+        let n = stream.read(&mut buf[..want]).await?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        received.extend_from_slice(&buf[..n]);
+
+        if !plausible(received) {
+            return Err(LostLoveError::HandshakeRejected {
+                category: HandshakeFailureCategory::Malformed,
+                reason: "Not a LostLove handshake".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Append the payload following the header in `buf`
+async fn read_payload(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<()> {
+    read_payload_within(stream, buf, usize::MAX).await
+}
+
+/// Append the payload the header in `buf` declares, refusing packets over
+/// `limit` bytes before reading any of it
+///
+/// The stream can't be framed again after an oversized packet, so the
+/// error ends the connection.
+async fn read_payload_within(stream: &mut TcpStream, buf: &mut BytesMut, limit: usize) -> Result<()> {
+    let header: &[u8; HEADER_SIZE] = buf[..HEADER_SIZE].try_into().expect("buffer starts with a header");
+    let size = HEADER_SIZE + payload_length(header);
+    if size > limit {
+        return Err(LostLoveError::PacketTooLarge { size, limit });
+    }
+
+    buf.resize(size, 0);
+    stream.read_exact(&mut buf[HEADER_SIZE..]).await?;
+    Ok(())
+}
+
+/// Send a HandshakeResponse, obfuscated like the ClientHello when enabled
+async fn write_handshake_response(
+    stream: &mut TcpStream,
+    packet: &Packet,
+    obfuscation: Option<&HandshakeObfuscation>,
+    timings: &Timings,
+) -> Result<()> {
+    let Some(obfuscation) = obfuscation else {
+        return write_packet(stream, packet).await;
+    };
+
+    let sealed = timings.time_crypto(CryptoOperation::HelloSeal, || {
+        obfuscation.seal(HelloSender::Server, &packet.serialize())
+    })?;
+    stream.write_all(&sealed).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Number a packet of the session, write it and count it as sent
+async fn send_packet(
+    stream: &mut TcpStream,
+    connection: &Arc<crate::core::connection::Connection>,
+    packet: Packet,
+) -> Result<()> {
+    let packet = connection.session().stamp(packet).await?;
+    write_packet(stream, &packet).await?;
+    connection.session().record_packet_sent(packet.size()).await;
+    Ok(())
 }
 
 /// Write packet to stream
-async fn write_packet(stream: &mut TcpStream, packet: &Packet) -> Result<()> {
+pub(crate) async fn write_packet(stream: &mut TcpStream, packet: &Packet) -> Result<()> {
     let data = packet.serialize();
     stream.write_all(&data).await?;
     stream.flush().await?;
@@ -302,6 +2095,7 @@ async fn write_packet(stream: &mut TcpStream, packet: &Packet) -> Result<()> {
 mod tests {
     use super::*;
     use crate::config::Config;
+    use tokio::net::TcpListener;
 
     #[tokio::test]
     async fn test_server_creation() {
@@ -310,4 +2104,242 @@ mod tests {
 
         assert_eq!(server.connection_manager.active_count(), 0);
     }
+
+    #[test]
+    fn test_supported_extensions() {
+        let mut config = Config::default_for_testing();
+        config.limits.max_streams_per_connection = 100_000;
+
+        let supported = supported_extensions(&config, true);
+        assert_eq!(supported.max_streams, Some(u16::MAX));
+        assert_eq!(supported.obfuscation, ["handshake"]);
+        assert!(supported.compression.is_empty() && !supported.fec && !supported.resumption);
+        assert!(supported_extensions(&config, false).obfuscation.is_empty());
+        assert_eq!(supported.gso_max_size, None);
+
+        config.network.tun_offload = true;
+        assert_eq!(supported_extensions(&config, false).gso_max_size, Some(65535));
+
+        assert!(!supported.early_data);
+        config.server.tcp.early_data = true;
+        assert!(supported_extensions(&config, false).early_data);
+    }
+
+    /// What a client reads after its ClientHello, and whether the server
+    /// sent early data
+    async fn handshake_with(offer_early_data: bool) -> (Vec<Packet>, bool) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, peer_addr) = listener.accept().await.unwrap();
+
+        let mut config = Config::default_for_testing();
+        config.server.tcp.early_data = true;
+        let manager = ConnectionManager::new(10);
+        let connection = manager.create_connection(peer_addr).unwrap();
+        connection.handshake().write().await.set_extensions(supported_extensions(&config, false));
+
+        let hello = HandshakeMessage::ClientHello {
+            client_random: [0u8; 32],
+            protocol_version: 1,
+            cipher_suites: vec![crate::protocol::handshake::CipherSuite::Aes256Gcm],
+            user: None,
+            labels: BTreeMap::new(),
+            extensions: HelloExtensions {
+                early_data: offer_early_data,
+                ..HelloExtensions::default()
+            },
+        };
+        let hello = Packet::new(PacketType::HandshakeInit, hello.to_bytes().unwrap());
+        let settings = ConfigPublisher::new(&config).current();
+        let early_data = perform_handshake(&mut stream, &connection, hello, None, None, &Timings::new(), &settings)
+            .await
+            .unwrap();
+        let config = settings.for_session(connection.session().tunnel_address()).to_bytes().unwrap();
+        drop(stream);
+
+        // Both may arrive in one read, so split at the known config
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        let mut packets = Vec::new();
+        let config_at = received.len().saturating_sub(HEADER_SIZE + config.len());
+        match Packet::deserialize(&received[config_at..]) {
+            Ok(packet) if early_data => {
+                packets.push(Packet::deserialize(&received[..config_at]).unwrap());
+                packets.push(packet);
+            }
+            _ => packets.push(Packet::deserialize(&received[..]).unwrap()),
+        }
+        (packets, early_data)
+    }
+
+    #[tokio::test]
+    async fn test_early_data_follows_server_hello() {
+        let (packets, early_data) = handshake_with(true).await;
+        assert!(early_data);
+        let types: Vec<_> = packets.iter().map(|packet| packet.header.packet_type).collect();
+        assert_eq!(types, [PacketType::HandshakeResponse, PacketType::ConfigUpdate]);
+        let config = crate::protocol::ClientConfig::from_bytes(&packets[1].payload).unwrap();
+        assert_eq!(config.routes, ["0.0.0.0/0"]);
+
+        // Clients not offering it get ServerHello alone
+        let (packets, early_data) = handshake_with(false).await;
+        assert!(!early_data);
+        assert_eq!(packets[0].header.packet_type, PacketType::HandshakeResponse);
+    }
+
+    #[tokio::test]
+    async fn test_tunneled_packets_carry_inner_dscp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, peer_addr) = listener.accept().await.unwrap();
+
+        let mut config = Config::default_for_testing();
+        config.server.qos.copy_inner = true;
+        let context = Server::new(config).await.unwrap().connection_context();
+        let connection = context.connection_manager.create_connection(peer_addr).unwrap();
+        let mut dscp = DscpMarker::new(&context.config.server.qos);
+
+        // IPv4 header with expedited forwarding
+        let mut inner = vec![0u8; 20];
+        inner[0] = 0x45;
+        inner[1] = 46 << 2;
+        send_tunneled(&mut stream, &connection, &context, &mut dscp, Bytes::from(inner))
+            .await
+            .unwrap();
+        assert_eq!(SockRef::from(&stream).tos().unwrap(), 46 << 2);
+    }
+
+    /// Bytes a client sends, as read by the server
+    async fn read_first(sent: &[u8]) -> (Vec<u8>, Result<Packet>) {
+        read_first_with(sent, None).await
+    }
+
+    async fn read_first_with(
+        sent: &[u8],
+        obfuscation: Option<&HandshakeObfuscation>,
+    ) -> (Vec<u8>, Result<Packet>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        client.write_all(sent).await.unwrap();
+        read_client_hello(&mut stream, obfuscation, &Timings::new()).await
+    }
+
+    #[tokio::test]
+    async fn test_oversized_payload_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let header = |payload: &[u8]| Packet::new(PacketType::Data, Bytes::copy_from_slice(payload)).serialize();
+
+        // The declared length is read however the bytes arrive
+        let payload = [7u8; 100];
+        client.write_all(&payload[..30]).await.unwrap();
+        let mut buffer = BytesMut::from(&header(&payload)[..HEADER_SIZE]);
+        let reader = tokio::spawn(async move {
+            read_payload_within(&mut stream, &mut buffer, HEADER_SIZE + 100).await.unwrap();
+            (stream, buffer)
+        });
+        client.write_all(&payload[30..]).await.unwrap();
+        let (mut stream, buffer) = reader.await.unwrap();
+        assert_eq!(Packet::deserialize(buffer).unwrap().payload, &payload[..]);
+
+        // Refused on the header, before the payload is read
+        let mut buffer = BytesMut::from(&header(&[7u8; 101])[..HEADER_SIZE]);
+        let err = read_payload_within(&mut stream, &mut buffer, HEADER_SIZE + 100).await.unwrap_err();
+        assert_eq!(err.code(), "packet_too_large");
+        assert_eq!(buffer.len(), HEADER_SIZE);
+    }
+
+    /// What a client reads of a reply when the server closes after it
+    ///
+    /// The client has pipelined bytes the server never reads, as when it
+    /// sends more data right behind its Disconnect.
+    async fn read_reply_before_close(orderly: bool) -> (Vec<u8>, std::io::Result<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        client.write_all(b"pipelined").await.unwrap();
+        let server = tokio::spawn(async move {
+            stream.write_all(&[7u8; 256 * 1024]).await.unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+            if orderly {
+                close_gracefully(&mut stream, CLOSE_LINGER).await;
+            }
+        });
+
+        // Read nothing until the server has closed
+        time::sleep(Duration::from_millis(200)).await;
+        let mut received = Vec::new();
+        let result = client.read_to_end(&mut received).await;
+        drop(client);
+        server.await.unwrap();
+        (received, result)
+    }
+
+    #[tokio::test]
+    async fn test_abrupt_close_loses_reply() {
+        let (received, result) = read_reply_before_close(false).await;
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(received.len() < 256 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_orderly_close_delivers_reply() {
+        let started = Instant::now();
+        let (received, result) = read_reply_before_close(true).await;
+        assert_eq!(result.unwrap(), 256 * 1024);
+        assert_eq!(received, vec![7u8; 256 * 1024]);
+        // The server stops lingering once the client closes
+        assert!(started.elapsed() < CLOSE_LINGER);
+    }
+
+    fn client_hello() -> BytesMut {
+        let hello = HandshakeMessage::ClientHello {
+            client_random: [0u8; 32],
+            protocol_version: 1,
+            cipher_suites: vec![crate::protocol::handshake::CipherSuite::Aes256Gcm],
+            user: None,
+            labels: BTreeMap::new(),
+            extensions: Default::default(),
+        };
+        Packet::new(PacketType::HandshakeInit, hello.to_bytes().unwrap()).serialize()
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        let packet = client_hello();
+
+        let (received, client_hello) = read_first(&packet).await;
+        assert_eq!(received, packet.to_vec());
+        assert_eq!(client_hello.unwrap().header.packet_type, PacketType::HandshakeInit);
+
+        // A probe is given up on after its first bytes, which are kept for the decoy
+        let (received, client_hello) = read_first(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(received, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(client_hello.is_err());
+
+        let junk = Packet::new(PacketType::HandshakeInit, Bytes::from_static(b"junk")).serialize();
+        let (received, client_hello) = read_first(&junk).await;
+        assert_eq!(received, junk.to_vec());
+        assert!(client_hello.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_obfuscated_client_hello() {
+        let obfuscation = HandshakeObfuscation::new(b"server key").unwrap();
+        let packet = client_hello();
+        let sealed = obfuscation.seal(HelloSender::Client, &packet).unwrap();
+
+        let (received, client_hello) = read_first_with(&sealed, Some(&obfuscation)).await;
+        assert_eq!(received, sealed);
+        assert_eq!(client_hello.unwrap().header.packet_type, PacketType::HandshakeInit);
+
+        // Plain handshakes are refused once obfuscation is on
+        let (_, client_hello) = read_first_with(&packet, Some(&obfuscation)).await;
+        assert!(client_hello.is_err());
+    }
 }
@@ -1,32 +1,83 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::sync::Mutex;
 
-/// Session identifier
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct SessionId(String);
+use crate::core::clock::{self, Clock};
+use crate::core::labels::Labels;
+use crate::core::stats::TrafficTotals;
+use crate::crypto::SequenceSpaces;
+use crate::error::{LostLoveError, Result};
+use crate::network::inner_packet::{InnerPacket, IpProtocol};
+use crate::protocol::address_discovery::Transport;
+use crate::protocol::{Packet, PacketHeader, PacketType};
+
+/// Maximum distinct destination ports tracked per session
+const MAX_TRACKED_PORTS: usize = 64;
+
+/// Session identifier, a random UUID kept as its 16 bytes
+///
+/// Copying it costs nothing, so map keys and log lines don't allocate; it
+/// only becomes the familiar hyphenated string when displayed, and on the
+/// wire (the ServerHello's `session_id`) where clients expect that string.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId([u8; 16]);
 
 impl SessionId {
     /// Create new session ID
     pub fn new() -> Self {
-        SessionId(uuid::Uuid::new_v4().to_string())
+        SessionId(uuid::Uuid::new_v4().into_bytes())
     }
 
-    /// Create from string
-    pub fn from_string(s: String) -> Self {
-        SessionId(s)
+    /// Create from the 16 bytes of a UUID
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        SessionId(bytes)
     }
 
-    /// Get string representation
-    pub fn as_str(&self) -> &str {
+    /// Get the raw bytes
+    pub fn as_bytes(&self) -> &[u8; 16] {
         &self.0
     }
 }
 
 impl fmt::Display for SessionId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        fmt::Display::fmt(uuid::Uuid::from_bytes_ref(&self.0).as_hyphenated(), f)
+    }
+}
+
+impl fmt::Debug for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionId({})", self)
+    }
+}
+
+impl FromStr for SessionId {
+    type Err = LostLoveError;
+
+    /// Parse the hyphenated form shown in logs and `llpctl sessions`;
+    /// anything else can't name a session
+    fn from_str(s: &str) -> Result<Self> {
+        uuid::Uuid::parse_str(s)
+            .map(|uuid| SessionId(uuid.into_bytes()))
+            .map_err(|_| LostLoveError::SessionNotFound(s.to_string()))
+    }
+}
+
+impl Serialize for SessionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SessionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse()
+            .map_err(|_| serde::de::Error::custom(format!("invalid session ID {:?}", text)))
     }
 }
 
@@ -36,14 +87,7 @@ impl Default for SessionId {
     }
 }
 
-/// Session state
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum SessionState {
-    Handshaking,
-    Active,
-    Disconnecting,
-    Closed,
-}
+pub use crate::protocol::state_machine::{SessionEvent, SessionState};
 
 /// Session statistics
 #[derive(Debug, Clone, Default)]
@@ -53,6 +97,72 @@ pub struct SessionStats {
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub errors: u64,
+    /// Packets dropped for not carrying the leased source address
+    pub source_violations: u64,
+}
+
+/// Packet and byte counter for one inner protocol
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProtocolCounter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl ProtocolCounter {
+    fn record(&mut self, size: usize) {
+        self.packets += 1;
+        self.bytes += size as u64;
+    }
+}
+
+/// Breakdown of client-originated traffic by inner protocol and destination port
+#[derive(Debug, Clone, Default)]
+pub struct TrafficBreakdown {
+    pub tcp: ProtocolCounter,
+    pub udp: ProtocolCounter,
+    pub icmp: ProtocolCounter,
+    pub other: ProtocolCounter,
+    /// Packets per (protocol, destination port), capped at MAX_TRACKED_PORTS entries
+    pub destination_ports: HashMap<(IpProtocol, u16), u64>,
+    /// Packets to ports that did not fit in the tracking table
+    pub untracked_port_packets: u64,
+}
+
+impl TrafficBreakdown {
+    /// Account one inner packet
+    pub fn record(&mut self, packet: &InnerPacket, size: usize) {
+        match packet.protocol {
+            IpProtocol::Tcp => self.tcp.record(size),
+            IpProtocol::Udp => self.udp.record(size),
+            IpProtocol::Icmp | IpProtocol::Icmpv6 => self.icmp.record(size),
+            IpProtocol::Other(_) => self.other.record(size),
+        }
+
+        if let Some(port) = packet.destination_port {
+            let key = (packet.protocol, port);
+
+            if let Some(count) = self.destination_ports.get_mut(&key) {
+                *count += 1;
+            } else if self.destination_ports.len() < MAX_TRACKED_PORTS {
+                self.destination_ports.insert(key, 1);
+            } else {
+                self.untracked_port_packets += 1;
+            }
+        }
+    }
+
+    /// Get the busiest destination ports by packet count
+    pub fn top_ports(&self, limit: usize) -> Vec<((IpProtocol, u16), u64)> {
+        let mut ports: Vec<_> = self
+            .destination_ports
+            .iter()
+            .map(|(key, count)| (*key, *count))
+            .collect();
+
+        ports.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0 .1.cmp(&b.0 .1)));
+        ports.truncate(limit);
+        ports
+    }
 }
 
 /// Session data
@@ -60,9 +170,19 @@ pub struct Session {
     id: SessionId,
     state: Arc<Mutex<SessionState>>,
     stats: Arc<Mutex<SessionStats>>,
+    traffic: Arc<Mutex<TrafficBreakdown>>,
+    labels: Arc<Mutex<Labels>>,
     created_at: SystemTime,
     last_activity: Arc<Mutex<Instant>>,
+    transport: Arc<Mutex<Transport>>,
+    /// Key epoch of the session's `KeyManager`, to resume it at, with the
+    /// sequence numbers used under its keys
+    sequences: Arc<Mutex<SequenceSpaces>>,
     peer_address: std::net::SocketAddr,
+    tunnel_address: Option<std::net::IpAddr>,
+    clock: Arc<dyn Clock>,
+    /// Server-wide totals this session's traffic also counts towards
+    totals: Option<Arc<TrafficTotals>>,
 }
 
 impl Session {
@@ -72,12 +192,39 @@ impl Session {
             id: SessionId::new(),
             state: Arc::new(Mutex::new(SessionState::Handshaking)),
             stats: Arc::new(Mutex::new(SessionStats::default())),
+            traffic: Arc::new(Mutex::new(TrafficBreakdown::default())),
+            labels: Arc::new(Mutex::new(Labels::new())),
             created_at: SystemTime::now(),
             last_activity: Arc::new(Mutex::new(Instant::now())),
+            transport: Arc::new(Mutex::new(Transport::Tcp)),
+            sequences: Arc::new(Mutex::new(SequenceSpaces::new(0))),
             peer_address,
+            tunnel_address: None,
+            clock: clock::system(),
+            totals: None,
         }
     }
 
+    /// Measure uptime and idleness on `clock` instead of the system clocks
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.created_at = clock.system_time();
+        self.last_activity = Arc::new(Mutex::new(clock.now()));
+        self.clock = clock;
+        self
+    }
+
+    /// Also count this session's traffic in `totals`
+    pub fn with_totals(mut self, totals: Arc<TrafficTotals>) -> Self {
+        self.totals = Some(totals);
+        self
+    }
+
+    /// Set the tunnel address leased to this session
+    pub fn with_tunnel_address(mut self, address: std::net::IpAddr) -> Self {
+        self.tunnel_address = Some(address);
+        self
+    }
+
     /// Get session ID
     pub fn id(&self) -> &SessionId {
         &self.id
@@ -88,35 +235,106 @@ impl Session {
         self.peer_address
     }
 
+    /// Get leased tunnel address
+    pub fn tunnel_address(&self) -> Option<std::net::IpAddr> {
+        self.tunnel_address
+    }
+
     /// Get current state
     pub async fn state(&self) -> SessionState {
         *self.state.lock().await
     }
 
-    /// Set state
-    pub async fn set_state(&self, new_state: SessionState) {
-        *self.state.lock().await = new_state;
+    /// Move the lifecycle on by `event`, failing if it's not allowed now
+    ///
+    /// Servers go through `Connection::activate`, which also checks the
+    /// handshake, rather than applying `HandshakeCompleted` directly.
+    pub async fn apply(&self, event: SessionEvent) -> Result<SessionState> {
+        let mut state = self.state.lock().await;
+        let next = state.next(event).ok_or_else(|| {
+            LostLoveError::Connection(format!("Invalid session transition: {:?} in {:?}", event, *state))
+        })?;
+        *state = next;
+        Ok(next)
     }
 
     /// Update last activity timestamp
     pub async fn update_activity(&self) {
-        *self.last_activity.lock().await = Instant::now();
+        *self.last_activity.lock().await = self.clock.now();
+    }
+
+    /// Get the transport the client's data currently arrives on
+    pub async fn transport(&self) -> Transport {
+        *self.transport.lock().await
+    }
+
+    /// Switch transport, returns true if it changed
+    pub async fn set_transport(&self, transport: Transport) -> bool {
+        let mut current = self.transport.lock().await;
+        let changed = *current != transport;
+        *current = transport;
+        changed
+    }
+
+    /// Get the key epoch recorded for this session
+    pub async fn key_epoch(&self) -> u64 {
+        self.sequences.lock().await.epoch()
+    }
+
+    /// Record a key rotation; epochs only move forward, and each one
+    /// starts its sequence numbers again
+    pub async fn set_key_epoch(&self, epoch: u64) -> Result<()> {
+        let mut sequences = self.sequences.lock().await;
+        if epoch == sequences.epoch() {
+            return Ok(());
+        }
+        sequences.rekey(epoch)
+    }
+
+    /// Sequence number for the next packet sent under the current keys
+    pub async fn next_sequence(&self) -> Result<u64> {
+        self.sequences.lock().await.next_send()
+    }
+
+    /// Number a packet for sending under the current keys
+    ///
+    /// Acks keep the number of the packet they acknowledge.
+    pub async fn stamp(&self, packet: Packet) -> Result<Packet> {
+        if packet.header.packet_type == PacketType::Ack {
+            return Ok(packet);
+        }
+        let mut sequences = self.sequences.lock().await;
+        let mut header = packet.header;
+        header.sequence_number = sequences.next_send()?;
+        header.set_key_epoch(sequences.epoch());
+        Ok(Packet::with_header(header, packet.payload))
+    }
+
+    /// Accept a received packet's sequence number once for its key epoch
+    pub async fn check_sequence(&self, header: &PacketHeader) -> Result<u64> {
+        self.sequences.lock().await.check_received(header)
     }
 
     /// Get time since last activity
     pub async fn time_since_activity(&self) -> std::time::Duration {
-        self.last_activity.lock().await.elapsed()
+        self.clock
+            .now()
+            .saturating_duration_since(*self.last_activity.lock().await)
     }
 
     /// Get session uptime
     pub fn uptime(&self) -> std::time::Duration {
-        SystemTime::now()
+        self.clock
+            .system_time()
             .duration_since(self.created_at)
             .unwrap_or_default()
     }
 
     /// Update statistics - packet sent
     pub async fn record_packet_sent(&self, size: usize) {
+        if let Some(totals) = &self.totals {
+            totals.record_sent(&self.id, size);
+        }
         let mut stats = self.stats.lock().await;
         stats.packets_sent += 1;
         stats.bytes_sent += size as u64;
@@ -124,6 +342,9 @@ impl Session {
 
     /// Update statistics - packet received
     pub async fn record_packet_received(&self, size: usize) {
+        if let Some(totals) = &self.totals {
+            totals.record_received(&self.id, size);
+        }
         let mut stats = self.stats.lock().await;
         stats.packets_received += 1;
         stats.bytes_received += size as u64;
@@ -131,15 +352,58 @@ impl Session {
 
     /// Update statistics - error
     pub async fn record_error(&self) {
+        if let Some(totals) = &self.totals {
+            totals.record_error(&self.id);
+        }
         let mut stats = self.stats.lock().await;
         stats.errors += 1;
     }
 
+    /// Update statistics - packet with a foreign source address dropped
+    pub async fn record_source_violation(&self) {
+        if let Some(totals) = &self.totals {
+            totals.record_source_violation(&self.id);
+        }
+        let mut stats = self.stats.lock().await;
+        stats.source_violations += 1;
+    }
+
     /// Get statistics snapshot
     pub async fn stats(&self) -> SessionStats {
         self.stats.lock().await.clone()
     }
 
+    /// Update traffic breakdown - inner packet from client
+    pub async fn record_inner_packet(&self, packet: &InnerPacket, size: usize) {
+        self.traffic.lock().await.record(packet, size);
+    }
+
+    /// Get traffic breakdown snapshot
+    pub async fn traffic(&self) -> TrafficBreakdown {
+        self.traffic.lock().await.clone()
+    }
+
+    /// Get labels snapshot
+    pub async fn labels(&self) -> Labels {
+        self.labels.lock().await.clone()
+    }
+
+    /// Set and remove labels in one step; nothing changes if any label is invalid
+    pub async fn update_labels(&self, set: &BTreeMap<String, String>, remove: &[String]) -> Result<Labels> {
+        let mut labels = self.labels.lock().await;
+        let mut updated = labels.clone();
+
+        for key in remove {
+            updated.remove(key);
+        }
+        for (key, value) in set {
+            updated.insert(key, value)?;
+        }
+
+        *labels = updated.clone();
+        Ok(updated)
+    }
+
     /// Check if session is active
     pub async fn is_active(&self) -> bool {
         *self.state.lock().await == SessionState::Active
@@ -164,8 +428,82 @@ impl fmt::Debug for Session {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::UNIX_EPOCH;
+    use bytes::Bytes;
+    use dashmap::DashMap;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
+    /// Counts allocations per thread, so tests running alongside don't
+    /// disturb a measurement
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    #[test]
+    fn test_session_id_round_trip() {
+        let id = SessionId::from_bytes(*b"\x6f\x9a\x1c\x20\x4e\x11\x4b\x7d\x8a\x02\x5e\x33\xc1\x0d\x99\xf4");
+        assert_eq!(id.to_string(), "6f9a1c20-4e11-4b7d-8a02-5e33c10d99f4");
+        assert_eq!("6f9a1c20-4e11-4b7d-8a02-5e33c10d99f4".parse::<SessionId>().unwrap(), id);
+        assert!("not-a-session".parse::<SessionId>().is_err());
+        assert_eq!(std::mem::size_of::<SessionId>(), 16);
+
+        // Hyphenated on the wire, as before it was kept as bytes
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, "\"6f9a1c20-4e11-4b7d-8a02-5e33c10d99f4\"");
+        assert_eq!(serde_json::from_str::<SessionId>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<SessionId>("[1,2,3]").is_err());
+    }
+
+    /// Keying 10k sessions by the bytes allocates nothing per lookup, where
+    /// the UUID strings they replaced allocated on every cloned key
+    #[test]
+    fn test_session_id_allocations() {
+        const SESSIONS: usize = 10_000;
+        let ids: Vec<SessionId> = (0..SESSIONS).map(|_| SessionId::new()).collect();
+        let names: Vec<String> = ids.iter().map(ToString::to_string).collect();
+        let by_id: DashMap<SessionId, usize> = ids.iter().copied().zip(0..).collect();
+        let by_name: DashMap<String, usize> = names.iter().cloned().zip(0..).collect();
+
+        let copied = allocations(|| {
+            for id in &ids {
+                let key = *id;
+                assert!(by_id.contains_key(&key));
+            }
+        });
+        let cloned = allocations(|| {
+            for name in &names {
+                let key = name.clone();
+                assert!(by_name.contains_key(&key));
+            }
+        });
+        assert_eq!(copied, 0);
+        assert!(cloned >= SESSIONS, "{} allocations for {} string keys", cloned, SESSIONS);
+    }
+
     #[tokio::test]
     async fn test_session_creation() {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
@@ -175,14 +513,79 @@ mod tests {
         assert_eq!(session.peer_address(), addr);
     }
 
+    #[tokio::test]
+    async fn test_key_epoch_only_moves_forward() {
+        let session = Session::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080));
+        assert_eq!(session.key_epoch().await, 0);
+
+        assert_eq!(session.next_sequence().await.unwrap(), 0);
+        assert_eq!(session.next_sequence().await.unwrap(), 1);
+
+        session.set_key_epoch(2).await.unwrap();
+        assert_eq!(session.next_sequence().await.unwrap(), 0);
+        // Repeating the epoch keeps its sequence space
+        session.set_key_epoch(2).await.unwrap();
+        assert_eq!(session.next_sequence().await.unwrap(), 1);
+        assert!(session.set_key_epoch(1).await.is_err());
+        assert_eq!(session.key_epoch().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_stamp_numbers_sent_packets() {
+        let session = Session::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080));
+        session.set_key_epoch(1).await.unwrap();
+
+        let keepalive = Packet::new(PacketType::KeepAlive, Bytes::new());
+        for sequence_number in 0..3 {
+            let packet = session.stamp(keepalive.clone()).await.unwrap();
+            assert_eq!(packet.header.sequence_number, sequence_number);
+            assert!(packet.header.key_phase());
+            assert!(packet.header.verify_checksum(&packet.payload));
+        }
+
+        let ack = Packet::new_with_metadata(PacketType::Ack, 0, 41, Bytes::new());
+        assert_eq!(session.stamp(ack.clone()).await.unwrap(), ack);
+        assert_eq!(session.next_sequence().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_sequence_refused() {
+        let session = Session::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080));
+        let packet = Packet::new_with_metadata(PacketType::Data, 1, 7, Bytes::new());
+
+        assert_eq!(session.check_sequence(&packet.header).await.unwrap(), 0);
+        assert!(matches!(
+            session.check_sequence(&packet.header).await,
+            Err(LostLoveError::InvalidSequence(7))
+        ));
+    }
+
     #[tokio::test]
     async fn test_session_state_transition() {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
         let session = Session::new(addr);
 
-        session.set_state(SessionState::Active).await;
+        session.apply(SessionEvent::HandshakeCompleted).await.unwrap();
         assert_eq!(session.state().await, SessionState::Active);
         assert!(session.is_active().await);
+
+        // A session is activated once and never goes back
+        assert!(session.apply(SessionEvent::HandshakeCompleted).await.is_err());
+        session.apply(SessionEvent::Disconnect).await.unwrap();
+        session.apply(SessionEvent::Close).await.unwrap();
+        assert!(session.apply(SessionEvent::Disconnect).await.is_err());
+        assert_eq!(session.state().await, SessionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_session_transport_switch() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let session = Session::new(addr);
+
+        assert_eq!(session.transport().await, Transport::Tcp);
+        assert!(session.set_transport(Transport::Udp).await);
+        assert!(!session.set_transport(Transport::Udp).await);
+        assert_eq!(session.transport().await, Transport::Udp);
     }
 
     #[tokio::test]
@@ -200,6 +603,64 @@ mod tests {
         assert_eq!(stats.bytes_received, 200);
     }
 
+    #[tokio::test]
+    async fn test_traffic_breakdown() {
+        use crate::network::inner_packet::tests::ipv4_packet;
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let session = Session::new(addr);
+
+        for (protocol, port) in [(6, 443), (6, 443), (6, 22), (17, 53), (1, 0)] {
+            let data = ipv4_packet(protocol, [10, 8, 0, 2], [1, 1, 1, 1], (40000, port));
+            let packet = InnerPacket::parse(&data).unwrap();
+            session.record_inner_packet(&packet, data.len()).await;
+        }
+
+        let traffic = session.traffic().await;
+        assert_eq!(traffic.tcp.packets, 3);
+        assert_eq!(traffic.tcp.bytes, 120);
+        assert_eq!(traffic.udp.packets, 1);
+        assert_eq!(traffic.icmp.packets, 1);
+
+        let top = traffic.top_ports(2);
+        assert_eq!(top[0], ((IpProtocol::Tcp, 443), 2));
+        assert_eq!(top.len(), 2);
+    }
+
+    #[test]
+    fn test_tracked_ports_are_capped() {
+        use crate::network::inner_packet::tests::ipv4_packet;
+
+        let mut traffic = TrafficBreakdown::default();
+        for port in 0..(MAX_TRACKED_PORTS as u16 + 10) {
+            let data = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (40000, port));
+            traffic.record(&InnerPacket::parse(&data).unwrap(), data.len());
+        }
+
+        assert_eq!(traffic.destination_ports.len(), MAX_TRACKED_PORTS);
+        assert_eq!(traffic.untracked_port_packets, 10);
+    }
+
+    #[tokio::test]
+    async fn test_update_labels() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let session = Session::new(addr);
+
+        let set = BTreeMap::from([("team".to_string(), "ops".to_string())]);
+        session.update_labels(&set, &[]).await.unwrap();
+
+        // One bad label rejects the whole update
+        let bad = BTreeMap::from([
+            ("device".to_string(), "phone".to_string()),
+            ("Bad".to_string(), "x".to_string()),
+        ]);
+        assert!(session.update_labels(&bad, &["team".to_string()]).await.is_err());
+        assert_eq!(session.labels().await.to_string(), "team=ops");
+
+        let labels = session.update_labels(&BTreeMap::new(), &["team".to_string()]).await.unwrap();
+        assert!(labels.is_empty());
+    }
+
     #[tokio::test]
     async fn test_session_activity() {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
@@ -212,4 +673,25 @@ mod tests {
         let duration = session.time_since_activity().await;
         assert!(duration >= std::time::Duration::from_millis(100));
     }
+
+    #[tokio::test]
+    async fn test_session_follows_clock() {
+        use crate::core::clock::ManualClock;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::new());
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let session = Session::new(addr).with_clock(clock.clone());
+
+        clock.advance(Duration::from_secs(3 * 24 * 3600));
+        assert_eq!(session.uptime(), Duration::from_secs(3 * 24 * 3600));
+        assert!(session.should_timeout(Duration::from_secs(300)).await);
+
+        session.update_activity().await;
+        assert_eq!(session.time_since_activity().await, Duration::ZERO);
+
+        // A wall clock stepped back doesn't turn into negative uptime
+        clock.set_system_time(UNIX_EPOCH);
+        assert_eq!(session.uptime(), Duration::ZERO);
+    }
 }
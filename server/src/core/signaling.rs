@@ -0,0 +1,425 @@
+use dashmap::DashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{debug, info};
+
+use crate::config::P2pConfig;
+use crate::core::connection::ConnectionManager;
+use crate::core::session::SessionId;
+use crate::protocol::packet::current_timestamp;
+use crate::protocol::peer_signal::MAX_CANDIDATES;
+use crate::protocol::{FallbackReason, PeerSignal};
+
+/// Signals queued per session before new ones are dropped
+const MAILBOX_SIZE: usize = 16;
+
+/// Hole punching counters since server start
+#[derive(Debug, Default)]
+pub struct SignalingStats {
+    pub offers: AtomicU64,
+    pub punches: AtomicU64,
+    pub fallbacks: AtomicU64,
+    pub direct: AtomicU64,
+    pub relayed: AtomicU64,
+}
+
+/// An offer waiting for the peer's answer
+#[derive(Debug)]
+struct PendingOffer {
+    candidates: Vec<SocketAddr>,
+    created_at: Instant,
+}
+
+/// Coordinates UDP hole punching between clients
+///
+/// Every session with a tunnel address gets a mailbox its data loop drains
+/// into PeerSignal packets. The server adds each client's public address to
+/// the candidates it claims (assuming the NAT keeps the port), passes offers
+/// on and schedules both sides to punch at the same moment.
+pub struct Signaling {
+    connection_manager: Arc<ConnectionManager>,
+    mailboxes: DashMap<SessionId, mpsc::Sender<PeerSignal>>,
+    /// Offers by (initiator, peer) tunnel address
+    pending: DashMap<(IpAddr, IpAddr), PendingOffer>,
+    punch_delay: Duration,
+    offer_timeout: Duration,
+    stats: SignalingStats,
+}
+
+impl Signaling {
+    /// Create from config, None when hole punching is disabled
+    pub fn new(config: &P2pConfig, connection_manager: Arc<ConnectionManager>) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        Some(Self {
+            connection_manager,
+            mailboxes: DashMap::new(),
+            pending: DashMap::new(),
+            punch_delay: Duration::from_millis(config.punch_delay_ms),
+            offer_timeout: Duration::from_secs(config.offer_timeout),
+            stats: SignalingStats::default(),
+        })
+    }
+
+    /// Counters since server start
+    pub fn stats(&self) -> &SignalingStats {
+        &self.stats
+    }
+
+    /// Open the mailbox of a session
+    pub fn register(&self, session_id: &SessionId) -> mpsc::Receiver<PeerSignal> {
+        let (sender, receiver) = mpsc::channel(MAILBOX_SIZE);
+        self.mailboxes.insert(*session_id, sender);
+        receiver
+    }
+
+    /// Close the mailbox of a session and drop its offers
+    pub fn unregister(&self, session_id: &SessionId, address: Option<IpAddr>) {
+        self.mailboxes.remove(session_id);
+        if let Some(address) = address {
+            self.pending
+                .retain(|(initiator, peer), _| *initiator != address && *peer != address);
+        }
+    }
+
+    /// Fall back offers that weren't answered in time
+    pub fn start(self: &Arc<Self>) {
+        let signaling = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(signaling.offer_timeout);
+            loop {
+                interval.tick().await;
+                signaling.expire_offers();
+            }
+        });
+    }
+
+    fn expire_offers(&self) {
+        let mut expired = Vec::new();
+        self.pending.retain(|key, offer| {
+            let alive = offer.created_at.elapsed() < self.offer_timeout;
+            if !alive {
+                expired.push(*key);
+            }
+            alive
+        });
+
+        for (initiator, peer) in expired {
+            self.fall_back(initiator, peer, FallbackReason::Timeout);
+        }
+    }
+
+    /// Handle a signal from the client holding `from`
+    ///
+    /// `observed` is the client's public address as seen by the server.
+    pub fn handle(&self, from: IpAddr, observed: IpAddr, signal: PeerSignal) {
+        match signal {
+            PeerSignal::Connect { peer, candidates } => {
+                if peer == from {
+                    return;
+                }
+                let candidates = with_reflexive(candidates, observed);
+                self.pending.insert(
+                    (from, peer),
+                    PendingOffer {
+                        candidates: candidates.clone(),
+                        created_at: Instant::now(),
+                    },
+                );
+
+                if self.send(peer, PeerSignal::Offer { peer: from, candidates }) {
+                    self.stats.offers.fetch_add(1, Ordering::Relaxed);
+                    debug!("Passed hole punching offer {} -> {}", from, peer);
+                } else {
+                    self.pending.remove(&(from, peer));
+                    self.fall_back(from, peer, FallbackReason::PeerNotFound);
+                }
+            }
+            PeerSignal::Answer {
+                peer,
+                accept,
+                candidates,
+            } => {
+                let Some((_, offer)) = self.pending.remove(&(peer, from)) else {
+                    debug!("Answer from {} to {} without an offer", from, peer);
+                    return;
+                };
+
+                if offer.created_at.elapsed() >= self.offer_timeout {
+                    self.fall_back(peer, from, FallbackReason::Timeout);
+                } else if !accept {
+                    self.fall_back(peer, from, FallbackReason::Declined);
+                } else {
+                    self.schedule_punch(peer, offer.candidates, from, with_reflexive(candidates, observed));
+                }
+            }
+            PeerSignal::Outcome { peer, direct } => {
+                let counter = if direct {
+                    &self.stats.direct
+                } else {
+                    &self.stats.relayed
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                info!(
+                    "Hole punching {} -> {} {}",
+                    from,
+                    peer,
+                    if direct { "succeeded" } else { "failed, using relay" }
+                );
+            }
+            other => debug!("Ignoring server-side signal {:?} from {}", other, from),
+        }
+    }
+
+    /// Tell both clients to start punching at the same moment
+    fn schedule_punch(
+        &self,
+        initiator: IpAddr,
+        initiator_candidates: Vec<SocketAddr>,
+        peer: IpAddr,
+        peer_candidates: Vec<SocketAddr>,
+    ) {
+        let punch_at = current_timestamp() + self.punch_delay.as_millis() as u64;
+        self.stats.punches.fetch_add(1, Ordering::Relaxed);
+        debug!("Scheduling hole punching {} <-> {} at {}", initiator, peer, punch_at);
+
+        self.send(
+            initiator,
+            PeerSignal::Punch {
+                peer,
+                candidates: peer_candidates,
+                punch_at,
+            },
+        );
+        self.send(
+            peer,
+            PeerSignal::Punch {
+                peer: initiator,
+                candidates: initiator_candidates,
+                punch_at,
+            },
+        );
+    }
+
+    fn fall_back(&self, initiator: IpAddr, peer: IpAddr, reason: FallbackReason) {
+        self.stats.fallbacks.fetch_add(1, Ordering::Relaxed);
+        debug!("Hole punching {} -> {} falls back to relay: {:?}", initiator, peer, reason);
+        self.send(initiator, PeerSignal::Fallback { peer, reason });
+    }
+
+    /// Queue a signal for the session holding a tunnel address
+    fn send(&self, address: IpAddr, signal: PeerSignal) -> bool {
+        let IpAddr::V4(lease) = address else {
+            return false;
+        };
+        let Some(session_id) = self
+            .connection_manager
+            .ip_pool()
+            .and_then(|pool| pool.lease_holder(lease))
+        else {
+            return false;
+        };
+        let Some(mailbox) = self.mailboxes.get(&session_id) else {
+            return false;
+        };
+
+        if let Err(e) = mailbox.try_send(signal) {
+            debug!("Dropped signal to {}: {}", address, e);
+            return false;
+        }
+        true
+    }
+}
+
+/// Claimed candidates plus the same ports on the observed public address
+fn with_reflexive(mut candidates: Vec<SocketAddr>, observed: IpAddr) -> Vec<SocketAddr> {
+    candidates.truncate(MAX_CANDIDATES);
+
+    let ports: Vec<_> = candidates.iter().map(SocketAddr::port).collect();
+    for port in ports {
+        let reflexive = SocketAddr::new(observed, port);
+        if !candidates.contains(&reflexive) {
+            candidates.push(reflexive);
+        }
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::connection::ConnectionGuard;
+    use crate::network::IpPool;
+    use std::net::Ipv4Addr;
+
+    fn address(host: u8) -> IpAddr {
+        IpAddr::from([10, 8, 0, host])
+    }
+
+    fn public(host: u8) -> IpAddr {
+        IpAddr::from([203, 0, 113, host])
+    }
+
+    fn candidate(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Signaling with two connected clients at 10.8.0.2 and 10.8.0.3
+    fn setup(
+        offer_timeout: u64,
+    ) -> (
+        Signaling,
+        mpsc::Receiver<PeerSignal>,
+        mpsc::Receiver<PeerSignal>,
+        [ConnectionGuard; 2],
+    ) {
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let config = P2pConfig {
+            enabled: true,
+            offer_timeout,
+            ..P2pConfig::default()
+        };
+        let signaling = Signaling::new(&config, manager.clone()).unwrap();
+
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080);
+        let alice = manager.create_connection(peer).unwrap();
+        let bob = manager.create_connection(peer).unwrap();
+        let alice_mailbox = signaling.register(alice.session().id());
+        let bob_mailbox = signaling.register(bob.session().id());
+
+        (signaling, alice_mailbox, bob_mailbox, [alice, bob])
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        assert!(Signaling::new(&P2pConfig::default(), manager).is_none());
+    }
+
+    #[test]
+    fn test_with_reflexive() {
+        let candidates = with_reflexive(vec![candidate("192.168.1.20:40000")], public(7));
+        assert_eq!(
+            candidates,
+            vec![candidate("192.168.1.20:40000"), candidate("203.0.113.7:40000")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offer_answer_punch() {
+        let (signaling, mut alice, mut bob, _connections) = setup(10);
+
+        signaling.handle(
+            address(2),
+            public(2),
+            PeerSignal::Connect {
+                peer: address(3),
+                candidates: vec![candidate("192.168.1.20:40000")],
+            },
+        );
+        let PeerSignal::Offer { peer, candidates } = bob.try_recv().unwrap() else {
+            panic!("expected an offer");
+        };
+        assert_eq!(peer, address(2));
+        assert!(candidates.contains(&candidate("203.0.113.2:40000")));
+
+        signaling.handle(
+            address(3),
+            public(3),
+            PeerSignal::Answer {
+                peer: address(2),
+                accept: true,
+                candidates: vec![candidate("10.0.0.5:50000")],
+            },
+        );
+
+        let (PeerSignal::Punch { punch_at: a, candidates, .. }, PeerSignal::Punch { punch_at: b, .. }) =
+            (alice.try_recv().unwrap(), bob.try_recv().unwrap())
+        else {
+            panic!("expected punch messages");
+        };
+        assert_eq!(a, b);
+        assert!(candidates.contains(&candidate("203.0.113.3:50000")));
+        assert_eq!(signaling.stats().punches.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fallbacks() {
+        let (signaling, mut alice, _bob, _connections) = setup(10);
+
+        // Nobody holds 10.8.0.9
+        signaling.handle(
+            address(2),
+            public(2),
+            PeerSignal::Connect {
+                peer: address(9),
+                candidates: vec![],
+            },
+        );
+        assert_eq!(
+            alice.try_recv().unwrap(),
+            PeerSignal::Fallback {
+                peer: address(9),
+                reason: FallbackReason::PeerNotFound,
+            }
+        );
+
+        // Declined offer
+        signaling.handle(
+            address(2),
+            public(2),
+            PeerSignal::Connect {
+                peer: address(3),
+                candidates: vec![],
+            },
+        );
+        signaling.handle(
+            address(3),
+            public(3),
+            PeerSignal::Answer {
+                peer: address(2),
+                accept: false,
+                candidates: vec![],
+            },
+        );
+        assert_eq!(
+            alice.try_recv().unwrap(),
+            PeerSignal::Fallback {
+                peer: address(3),
+                reason: FallbackReason::Declined,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_offer_times_out() {
+        let (signaling, mut alice, _bob, _connections) = setup(0);
+
+        signaling.handle(
+            address(2),
+            public(2),
+            PeerSignal::Connect {
+                peer: address(3),
+                candidates: vec![],
+            },
+        );
+        signaling.expire_offers();
+
+        assert_eq!(
+            alice.try_recv().unwrap(),
+            PeerSignal::Fallback {
+                peer: address(3),
+                reason: FallbackReason::Timeout,
+            }
+        );
+    }
+}
@@ -0,0 +1,434 @@
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::core::clock::{Clock, ManualClock};
+use crate::core::connection::{ConnectionGuard, ConnectionManager};
+use crate::core::engine;
+use crate::core::rekey::RekeyScheduler;
+use crate::core::session::SessionId;
+use crate::crypto::rng::SeededRandom;
+use crate::crypto::KeyManager;
+use crate::error::Result;
+use crate::network::chaos::{Chaos, ChaosRates};
+use crate::network::UdpTransport;
+use crate::protocol::handshake::{Handshake, HandshakeMessage};
+use crate::protocol::{Packet, PacketType, TransportAttach};
+
+/// Secret both sides derive session keys from, until the handshake agrees one
+const SHARED_SECRET: [u8; 32] = [0x42; 32];
+
+/// Arrival time, send order, source, destination and contents
+type InFlight = Reverse<(Instant, u64, SocketAddr, SocketAddr, Vec<u8>)>;
+
+/// A datagram taken off the simulated network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub datagram: Vec<u8>,
+}
+
+/// In-memory datagram network on virtual time
+///
+/// Datagrams arrive after the latency plus a random jitter, so they
+/// reorder on their own; a `Chaos` per link adds loss, duplication,
+/// reordering and corruption. Taking the next delivery moves the clock to
+/// its arrival time, so hours of traffic run in as long as the work takes.
+pub struct SimNetwork {
+    clock: Arc<ManualClock>,
+    rng: StdRng,
+    latency: Duration,
+    jitter: Duration,
+    chaos: Option<ChaosRates>,
+    links: BTreeMap<(SocketAddr, SocketAddr), Chaos>,
+    in_flight: BinaryHeap<InFlight>,
+    sent: u64,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            clock: Arc::new(ManualClock::new()),
+            rng: StdRng::seed_from_u64(seed),
+            latency: Duration::from_millis(20),
+            jitter: Duration::ZERO,
+            chaos: None,
+            links: BTreeMap::new(),
+            in_flight: BinaryHeap::new(),
+            sent: 0,
+        }
+    }
+
+    /// One-way delay of every datagram, plus up to `jitter`
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    /// Impair datagrams, each link seeded from the network's own seed
+    pub fn with_chaos(mut self, rates: ChaosRates) -> Self {
+        self.chaos = Some(rates);
+        self
+    }
+
+    /// The virtual clock
+    pub fn clock(&self) -> Arc<ManualClock> {
+        self.clock.clone()
+    }
+
+    /// Put a datagram on the wire
+    pub fn send(&mut self, from: SocketAddr, to: SocketAddr, datagram: &[u8]) {
+        let delivered = match self.chaos {
+            Some(rates) => {
+                let rng = &mut self.rng;
+                let link = self.links.entry((from, to)).or_insert_with(|| Chaos::new(rates, rng.gen()));
+                link.pass(datagram)
+            }
+            None => vec![datagram.to_vec()],
+        };
+        for datagram in delivered {
+            self.schedule(from, to, datagram);
+        }
+    }
+
+    fn schedule(&mut self, from: SocketAddr, to: SocketAddr, datagram: Vec<u8>) {
+        let jitter = self.jitter.mul_f64(self.rng.gen());
+        let at = self.clock.now() + self.latency + jitter;
+        self.sent += 1;
+        self.in_flight.push(Reverse((at, self.sent, from, to, datagram)));
+    }
+
+    /// Next datagram to arrive, moving the clock to its arrival
+    ///
+    /// Datagrams held back for reordering go out once nothing else is in
+    /// flight, like late stragglers.
+    pub fn next(&mut self) -> Option<Delivery> {
+        if self.in_flight.is_empty() {
+            let held: Vec<_> = self
+                .links
+                .iter_mut()
+                .filter_map(|(&(from, to), link)| link.flush().map(|datagram| (from, to, datagram)))
+                .collect();
+            for (from, to, datagram) in held {
+                self.schedule(from, to, datagram);
+            }
+        }
+
+        let Reverse((at, _, from, to, datagram)) = self.in_flight.pop()?;
+        let now = self.clock.now();
+        if at > now {
+            self.clock.advance(at - now);
+        }
+        Some(Delivery { from, to, datagram })
+    }
+
+    /// Let time pass with nothing arriving
+    pub fn advance(&mut self, by: Duration) {
+        self.clock.advance(by);
+    }
+}
+
+/// Notable things that happened, in order, to compare runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimEvent {
+    Connected { client: usize },
+    Attached { client: usize, at: Duration },
+    Acked { client: usize },
+    Rekeyed { client: usize, epoch: u64 },
+}
+
+/// A client of the simulation, holding what a real client would
+pub struct SimClient {
+    pub address: SocketAddr,
+    pub session_id: SessionId,
+    pub token: String,
+    pub keys: Arc<KeyManager>,
+    pub attached: bool,
+    updates: mpsc::Receiver<u64>,
+}
+
+/// The server's session machinery against simulated clients
+///
+/// Handshakes run over a reliable in-order channel, the way TCP carries
+/// them; attaches and data go over the `SimNetwork`. Everything is driven
+/// by the test, so a seed replays a run exactly.
+pub struct Simulation {
+    pub network: SimNetwork,
+    pub server: SocketAddr,
+    pub manager: Arc<ConnectionManager>,
+    pub transport: UdpTransport,
+    pub rekey: RekeyScheduler,
+    pub clients: Vec<SimClient>,
+    pub events: Vec<SimEvent>,
+    rotation_interval: Duration,
+    random: Arc<SeededRandom>,
+    guards: Vec<ConnectionGuard>,
+    started: Instant,
+}
+
+impl Simulation {
+    pub fn new(network: SimNetwork, seed: u64) -> Self {
+        let clock: Arc<dyn Clock> = network.clock();
+        let manager = Arc::new(ConnectionManager::new(usize::MAX).with_clock(clock));
+        let started = network.clock().now();
+
+        Self {
+            network,
+            server: SocketAddr::from(([192, 0, 2, 1], 8443)),
+            transport: UdpTransport::new("192.0.2.1", 8443, manager.clone()),
+            manager,
+            rekey: RekeyScheduler::new(),
+            clients: Vec::new(),
+            events: Vec::new(),
+            rotation_interval: Duration::from_secs(30 * 60),
+            random: Arc::new(SeededRandom::new(seed)),
+            guards: Vec::new(),
+            started,
+        }
+    }
+
+    /// Rotate session keys after `interval` of virtual time
+    pub fn with_rotation_interval(mut self, interval: Duration) -> Self {
+        self.rotation_interval = interval;
+        self
+    }
+
+    /// Handshake `count` new clients, each from its own address
+    pub async fn connect(&mut self, count: usize) -> Result<()> {
+        for _ in 0..count {
+            let client = self.clients.len();
+            let address = client_address(client, 0);
+            let guard = self.manager.create_connection(address)?;
+            let connection = guard.clone();
+            let session_id = *connection.session().id();
+            let token = self.transport.issue(&session_id);
+
+            let mut handshake = Handshake::new_client().with_random(self.random.clone());
+            let client_hello = handshake.generate_client_hello()?;
+            let client_hello = Packet::new(PacketType::HandshakeInit, client_hello.to_bytes()?);
+            let outcome = {
+                let mut server = connection.handshake().write().await;
+                server.set_transport_token(token.clone());
+                let outcome = engine::answer_hello(&mut server, &client_hello, None)?;
+                server.confirm_keys()?;
+                outcome
+            };
+            connection.activate().await?;
+            handshake.process_server_hello(&HandshakeMessage::from_bytes(&outcome.response.payload)?)?;
+
+            let (client_random, server_random) = (
+                handshake.client_random().expect("sent ClientHello"),
+                handshake.server_random().expect("got ServerHello"),
+            );
+            let key_manager = |clock: Arc<dyn Clock>| -> Result<KeyManager> {
+                Ok(KeyManager::new(SHARED_SECRET.to_vec(), client_random, server_random, true)?
+                    .with_rotation_interval(self.rotation_interval)
+                    .with_clock(clock))
+            };
+            let server_keys = connection.attach_keys(key_manager(self.network.clock())?)?;
+            let updates = self.rekey.register(&session_id, server_keys);
+
+            self.clients.push(SimClient {
+                address,
+                session_id,
+                token: handshake.transport_token().unwrap_or_default().to_string(),
+                // Clients only rotate when told to
+                keys: Arc::new(key_manager(self.network.clock())?),
+                attached: false,
+                updates,
+            });
+            self.guards.push(guard);
+            self.events.push(SimEvent::Connected { client });
+        }
+        Ok(())
+    }
+
+    /// Send an attach from every client that isn't attached yet
+    pub fn attach_pending(&mut self) {
+        for client in self.clients.iter().filter(|client| !client.attached) {
+            let attach = TransportAttach::new(&client.token).to_bytes().expect("token serializes");
+            let packet = Packet::new(PacketType::TransportAttach, attach);
+            self.network.send(client.address, self.server, &packet.serialize());
+        }
+    }
+
+    /// Move a client to a new address and attach from there
+    pub fn migrate(&mut self, client: usize, generation: u16) {
+        self.clients[client].address = client_address(client, generation);
+        self.clients[client].attached = false;
+    }
+
+    /// Send a data packet from a client
+    pub fn send_data(&mut self, client: usize, payload: &'static [u8]) {
+        let packet = Packet::new(PacketType::Data, Bytes::from_static(payload));
+        let address = self.clients[client].address;
+        self.network.send(address, self.server, &packet.serialize());
+    }
+
+    /// Deliver datagrams until none are left in flight
+    pub async fn run_until_idle(&mut self) {
+        while let Some(delivery) = self.network.next() {
+            if delivery.to == self.server {
+                if let Some(reply) = self.transport.handle(&delivery.datagram, delivery.from).await {
+                    self.network.send(self.server, delivery.from, &reply);
+                }
+                continue;
+            }
+
+            let Some(client) = self.clients.iter().position(|client| client.address == delivery.to) else {
+                continue;
+            };
+            let Ok(packet) = Packet::deserialize(&delivery.datagram[..]) else {
+                continue;
+            };
+            match packet.header.packet_type {
+                PacketType::TransportAttach if !self.clients[client].attached => {
+                    self.clients[client].attached = true;
+                    let at = self.network.clock().now() - self.started;
+                    self.events.push(SimEvent::Attached { client, at });
+                }
+                PacketType::Ack => self.events.push(SimEvent::Acked { client }),
+                _ => {}
+            }
+        }
+    }
+
+    /// Attach every client, retrying lost attaches up to `rounds` times
+    pub async fn attach_all(&mut self, rounds: usize) -> bool {
+        for _ in 0..rounds {
+            if self.clients.iter().all(|client| client.attached) {
+                return true;
+            }
+            self.attach_pending();
+            self.run_until_idle().await;
+        }
+        self.clients.iter().all(|client| client.attached)
+    }
+
+    /// Let `by` of virtual time pass, rotating the keys that are due and
+    /// carrying the new epochs to the clients as their KeyUpdates would
+    pub async fn advance(&mut self, by: Duration) -> Result<()> {
+        self.network.advance(by);
+        self.rekey.check_all().await;
+
+        for (index, client) in self.clients.iter_mut().enumerate() {
+            while let Ok(epoch) = client.updates.try_recv() {
+                while client.keys.epoch().await < epoch {
+                    client.keys.rotate_keys().await?;
+                }
+                self.events.push(SimEvent::Rekeyed { client: index, epoch });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Address of a client on its `generation`th network
+fn client_address(client: usize, generation: u16) -> SocketAddr {
+    let host = client as u32 + 1;
+    let ip = Ipv4Addr::new(10, (host >> 16) as u8, (host >> 8) as u8, host as u8);
+    SocketAddr::new(IpAddr::V4(ip), 40000 + generation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOSSY: ChaosRates = ChaosRates {
+        drop: 0.1,
+        duplicate: 0.05,
+        reorder: 0.05,
+        corrupt: 0.02,
+    };
+
+    fn lossy_network(seed: u64) -> SimNetwork {
+        SimNetwork::new(seed)
+            .with_latency(Duration::from_millis(40), Duration::from_millis(30))
+            .with_chaos(LOSSY)
+    }
+
+    #[tokio::test]
+    async fn test_a_thousand_clients_attach() {
+        let mut simulation = Simulation::new(lossy_network(195), 195);
+        simulation.connect(1_000).await.unwrap();
+
+        assert!(simulation.attach_all(20).await);
+        assert_eq!(simulation.manager.active_count(), 1_000);
+        for client in &simulation.clients {
+            assert_eq!(simulation.transport.bound_address(&client.session_id), Some(client.address));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_same_seed_same_run() {
+        async fn run(seed: u64) -> Vec<SimEvent> {
+            let mut simulation = Simulation::new(lossy_network(seed), seed);
+            simulation.connect(200).await.unwrap();
+            simulation.attach_all(20).await;
+            for client in 0..200 {
+                simulation.send_data(client, b"payload");
+            }
+            simulation.run_until_idle().await;
+            simulation.events
+        }
+
+        let events = run(7).await;
+        assert_eq!(events, run(7).await);
+        assert_ne!(events, run(8).await);
+    }
+
+    #[tokio::test]
+    async fn test_migrations() {
+        let mut simulation = Simulation::new(lossy_network(3), 3);
+        simulation.connect(500).await.unwrap();
+        assert!(simulation.attach_all(20).await);
+
+        let old: Vec<SocketAddr> = simulation.clients.iter().map(|client| client.address).collect();
+        for client in (0..500).step_by(2) {
+            simulation.migrate(client, 1);
+        }
+        assert!(simulation.attach_all(20).await);
+
+        for (index, client) in simulation.clients.iter().enumerate() {
+            assert_eq!(simulation.transport.bound_address(&client.session_id), Some(client.address));
+            assert_eq!(client.address == old[index], index % 2 == 1);
+        }
+
+        // Datagrams from an address a session moved away from are ignored
+        let stale = Packet::new(PacketType::KeepAlive, Bytes::new()).serialize();
+        assert!(simulation.transport.handle(&stale, old[0]).await.is_none());
+        assert!(simulation.transport.handle(&stale, old[1]).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rotations_over_hours() {
+        let mut simulation = Simulation::new(SimNetwork::new(5), 5).with_rotation_interval(Duration::from_secs(600));
+        simulation.connect(200).await.unwrap();
+
+        // Two hours of virtual time in one-minute steps
+        for _ in 0..120 {
+            simulation.advance(Duration::from_secs(60)).await.unwrap();
+        }
+
+        for client in &simulation.clients {
+            let server = simulation.manager.get_connection(&client.session_id).unwrap();
+            let server_keys = server.key_manager().unwrap();
+            assert_eq!(server_keys.epoch().await, 12);
+            assert_eq!(client.keys.epoch().await, 12);
+            assert_eq!(
+                client.keys.get_keys().await.master_secret[..],
+                server_keys.get_keys().await.master_secret[..]
+            );
+        }
+        let rekeys = simulation.events.iter().filter(|event| matches!(event, SimEvent::Rekeyed { .. })).count();
+        assert_eq!(rekeys, 2_400);
+    }
+}
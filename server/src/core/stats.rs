@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::core::session::{SessionId, SessionStats};
+
+/// Shards of the traffic totals; sessions spread over them by ID
+const SHARDS: usize = 16;
+
+/// One shard, on a cache line of its own so sessions on different shards
+/// don't contend
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct Shard {
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    errors: AtomicU64,
+    source_violations: AtomicU64,
+}
+
+/// Traffic of all sessions since start, in sharded atomic counters
+///
+/// Sessions add to their shard as packets pass, so a snapshot sums a fixed
+/// number of shards instead of locking every session.
+#[derive(Debug, Default)]
+pub struct TrafficTotals {
+    shards: [Shard; SHARDS],
+}
+
+impl TrafficTotals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_sent(&self, session_id: &SessionId, size: usize) {
+        let shard = self.shard(session_id);
+        shard.packets_sent.fetch_add(1, Ordering::Relaxed);
+        shard.bytes_sent.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, session_id: &SessionId, size: usize) {
+        let shard = self.shard(session_id);
+        shard.packets_received.fetch_add(1, Ordering::Relaxed);
+        shard.bytes_received.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, session_id: &SessionId) {
+        self.shard(session_id).errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_source_violation(&self, session_id: &SessionId) {
+        self.shard(session_id).source_violations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sum of all shards
+    pub fn snapshot(&self) -> SessionStats {
+        let mut totals = SessionStats::default();
+        for shard in &self.shards {
+            totals.packets_sent += shard.packets_sent.load(Ordering::Relaxed);
+            totals.packets_received += shard.packets_received.load(Ordering::Relaxed);
+            totals.bytes_sent += shard.bytes_sent.load(Ordering::Relaxed);
+            totals.bytes_received += shard.bytes_received.load(Ordering::Relaxed);
+            totals.errors += shard.errors.load(Ordering::Relaxed);
+            totals.source_violations += shard.source_violations.load(Ordering::Relaxed);
+        }
+        totals
+    }
+
+    fn shard(&self, session_id: &SessionId) -> &Shard {
+        // Session IDs are random, any byte spreads them evenly
+        &self.shards[session_id.as_bytes()[15] as usize % SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_totals_across_shards() {
+        let totals = TrafficTotals::new();
+        let sessions: Vec<_> = (0..64u8).map(|i| SessionId::from_bytes([i; 16])).collect();
+
+        for session in &sessions {
+            totals.record_sent(session, 100);
+            totals.record_received(session, 50);
+        }
+        totals.record_error(&sessions[3]);
+        totals.record_source_violation(&sessions[7]);
+
+        let snapshot = totals.snapshot();
+        assert_eq!(snapshot.packets_sent, 64);
+        assert_eq!(snapshot.bytes_sent, 6400);
+        assert_eq!(snapshot.packets_received, 64);
+        assert_eq!(snapshot.bytes_received, 3200);
+        assert_eq!(snapshot.errors, 1);
+        assert_eq!(snapshot.source_violations, 1);
+    }
+
+    #[test]
+    fn test_concurrent_recording() {
+        let totals = Arc::new(TrafficTotals::new());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let totals = totals.clone();
+                std::thread::spawn(move || {
+                    let session = SessionId::new();
+                    for _ in 0..10_000 {
+                        totals.record_sent(&session, 10);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let snapshot = totals.snapshot();
+        assert_eq!(snapshot.packets_sent, 80_000);
+        assert_eq!(snapshot.bytes_sent, 800_000);
+    }
+}
@@ -0,0 +1,153 @@
+use std::any::Any;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Pause before a panicked listener is started again
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Contains panics of connection handlers and listeners
+///
+/// Each supervised future runs in a task of its own; a panic ends that task
+/// only, gets logged and counted, and a listener may be started again.
+pub struct Supervisor {
+    restart_listeners: bool,
+    restart_delay: Duration,
+    panics: AtomicU64,
+}
+
+impl Supervisor {
+    pub fn new(restart_listeners: bool) -> Self {
+        Self {
+            restart_listeners,
+            restart_delay: RESTART_DELAY,
+            panics: AtomicU64::new(0),
+        }
+    }
+
+    /// Run a connection handler, logging it as `name` if it panics
+    pub fn spawn<F>(self: &Arc<Self>, name: String, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = tokio::spawn(task).await {
+                if e.is_panic() {
+                    supervisor.record_panic(&name, e.into_panic());
+                }
+            }
+        });
+    }
+
+    /// Run a listener built by `start`, starting a fresh one after a panic
+    /// when restarts are enabled
+    pub fn spawn_listener<F, Fut>(self: &Arc<Self>, name: &'static str, start: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match tokio::spawn(start()).await {
+                    Err(e) if e.is_panic() => supervisor.record_panic(name, e.into_panic()),
+                    _ => return,
+                }
+                if !supervisor.restart_listeners {
+                    return;
+                }
+                tokio::time::sleep(supervisor.restart_delay).await;
+                info!("Restarting {}", name);
+            }
+        });
+    }
+
+    /// Panics contained since start
+    pub fn panic_count(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    fn record_panic(&self, name: &str, payload: Box<dyn Any + Send>) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+        error!("{} panicked: {}", name, panic_message(payload.as_ref()));
+    }
+}
+
+/// Text of a panic, if it was raised with one
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "non-string panic payload"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::connection::ConnectionManager;
+    use std::sync::atomic::AtomicUsize;
+
+    async fn settle(supervisor: &Supervisor, panics: u64) {
+        for _ in 0..100 {
+            if supervisor.panic_count() == panics {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("expected {} panics, saw {}", panics, supervisor.panic_count());
+    }
+
+    #[tokio::test]
+    async fn test_panicking_handler_releases_connection() {
+        let manager = Arc::new(ConnectionManager::new(1));
+        let supervisor = Arc::new(Supervisor::new(false));
+
+        let connection = manager.create_connection("198.51.100.7:40000".parse().unwrap()).unwrap();
+        supervisor.spawn("Connection from 198.51.100.7:40000".to_string(), async move {
+            let _connection = connection;
+            panic!("handler bug");
+        });
+
+        settle(&supervisor, 1).await;
+        assert_eq!(manager.active_count(), 0);
+        assert!(manager.create_connection("198.51.100.8:40000".parse().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_listener_restart() {
+        for (restart, expected_starts) in [(false, 1), (true, 2)] {
+            let supervisor = Arc::new(Supervisor {
+                restart_delay: Duration::from_millis(1),
+                ..Supervisor::new(restart)
+            });
+            let starts = Arc::new(AtomicUsize::new(0));
+
+            let counter = starts.clone();
+            supervisor.spawn_listener("test listener", move || {
+                let start = counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if start == 0 {
+                        panic!("listener bug");
+                    }
+                }
+            });
+
+            settle(&supervisor, 1).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(starts.load(Ordering::SeqCst), expected_starts);
+        }
+    }
+
+    #[test]
+    fn test_panic_message() {
+        assert_eq!(panic_message(&"static"), "static");
+        assert_eq!(panic_message(&"owned".to_string()), "owned");
+        assert_eq!(panic_message(&42), "non-string panic payload");
+    }
+}
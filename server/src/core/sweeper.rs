@@ -0,0 +1,98 @@
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+use crate::core::connection::ConnectionManager;
+use crate::metrics::histogram::{Histogram, DURATION_BUCKETS};
+
+/// Slices the sessions are swept in, one per tenth of the interval
+const SLICES: usize = 10;
+
+/// Removes stale connections a slice at a time
+///
+/// Each round spreads its slices over the cleanup interval with a little
+/// jitter, so no tick scans every session and servers started together
+/// drift apart.
+pub struct Sweeper {
+    connection_manager: Arc<ConnectionManager>,
+    timeout: Duration,
+    interval: Duration,
+    durations: Arc<Histogram>,
+}
+
+impl Sweeper {
+    pub fn new(connection_manager: Arc<ConnectionManager>, timeout: Duration, interval: Duration) -> Self {
+        Self {
+            connection_manager,
+            timeout,
+            interval,
+            durations: Arc::new(Histogram::new(DURATION_BUCKETS)),
+        }
+    }
+
+    /// How long each slice took to sweep
+    pub fn durations(&self) -> Arc<Histogram> {
+        self.durations.clone()
+    }
+
+    /// Sweep every slice once, taking about one cleanup interval
+    pub async fn sweep_round(&self) {
+        for slice in 0..SLICES {
+            time::sleep(self.slice_pause()).await;
+            self.sweep_slice(slice).await;
+        }
+    }
+
+    /// Remove the stale connections of one slice, returns how many
+    async fn sweep_slice(&self, slice: usize) -> usize {
+        let start = Instant::now();
+        let removed = self.connection_manager.cleanup_stale(self.timeout, slice, SLICES).await;
+        self.durations.observe(start.elapsed());
+        removed
+    }
+
+    /// A slice's share of the interval, give or take 10%
+    fn slice_pause(&self) -> Duration {
+        (self.interval / SLICES as u32).mul_f64(rand::thread_rng().gen_range(0.9..1.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::ManualClock;
+
+    #[tokio::test]
+    async fn test_sweep_all_slices() {
+        let clock = Arc::new(ManualClock::new());
+        let manager = Arc::new(ConnectionManager::new(100).with_clock(clock.clone()));
+        let _conns: Vec<_> = (0..40)
+            .map(|_| manager.create_connection("198.51.100.9:50000".parse().unwrap()).unwrap())
+            .collect();
+        let sweeper = Sweeper::new(manager.clone(), Duration::from_secs(300), Duration::from_secs(60));
+
+        assert_eq!(sweeper.sweep_slice(0).await, 0);
+
+        clock.advance(Duration::from_secs(301));
+        let mut removed = 0;
+        for slice in 0..SLICES {
+            removed += sweeper.sweep_slice(slice).await;
+        }
+        assert_eq!(removed, 40);
+        assert_eq!(manager.active_count(), 0);
+
+        let observed = sweeper.durations().cumulative().last().unwrap().1;
+        assert_eq!(observed, SLICES as u64 + 1);
+    }
+
+    #[test]
+    fn test_slice_pause() {
+        let manager = Arc::new(ConnectionManager::new(1));
+        let sweeper = Sweeper::new(manager, Duration::from_secs(300), Duration::from_secs(60));
+        for _ in 0..100 {
+            let pause = sweeper.slice_pause();
+            assert!(pause >= Duration::from_millis(5400) && pause <= Duration::from_millis(6600));
+        }
+    }
+}
@@ -1,10 +1,10 @@
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, AeadInPlace, KeyInit, OsRng},
     Aes256Gcm, Key, Nonce,
 };
 use zeroize::Zeroizing;
 
-use crate::error::{LostLoveError, Result};
+use crate::error::{CryptoOp, LostLoveError, Result};
 
 /// AES-256-GCM encryptor
 pub struct AesEncryptor {
@@ -38,7 +38,10 @@ impl AesEncryptor {
 
         self.cipher
             .encrypt(nonce, plaintext)
-            .map_err(|e| LostLoveError::Connection(format!("AES-GCM encryption failed: {}", e)))
+            .map_err(|_| LostLoveError::AeadFailure {
+                cipher: "AES-256-GCM",
+                op: CryptoOp::Encrypt,
+            })
     }
 
     /// Decrypt data
@@ -47,7 +50,10 @@ impl AesEncryptor {
 
         self.cipher
             .decrypt(nonce, ciphertext)
-            .map_err(|e| LostLoveError::Connection(format!("AES-GCM decryption failed: {}", e)))
+            .map_err(|_| LostLoveError::AeadFailure {
+                cipher: "AES-256-GCM",
+                op: CryptoOp::Decrypt,
+            })
     }
 
     /// Encrypt in-place (modifies the buffer)
@@ -56,7 +62,10 @@ impl AesEncryptor {
 
         self.cipher
             .encrypt_in_place(nonce_obj, b"", buffer)
-            .map_err(|e| LostLoveError::Connection(format!("AES-GCM encryption failed: {}", e)))
+            .map_err(|_| LostLoveError::AeadFailure {
+                cipher: "AES-256-GCM",
+                op: CryptoOp::Encrypt,
+            })
     }
 
     /// Decrypt in-place (modifies the buffer)
@@ -65,7 +74,10 @@ impl AesEncryptor {
 
         self.cipher
             .decrypt_in_place(nonce_obj, b"", buffer)
-            .map_err(|e| LostLoveError::Connection(format!("AES-GCM decryption failed: {}", e)))
+            .map_err(|_| LostLoveError::AeadFailure {
+                cipher: "AES-256-GCM",
+                op: CryptoOp::Decrypt,
+            })
     }
 
     /// Get key size
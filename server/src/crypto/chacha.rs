@@ -1,10 +1,10 @@
 use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, AeadInPlace, KeyInit, OsRng},
     ChaCha20Poly1305, Key, Nonce,
 };
 use zeroize::Zeroizing;
 
-use crate::error::{LostLoveError, Result};
+use crate::error::{CryptoOp, LostLoveError, Result};
 
 /// ChaCha20-Poly1305 encryptor
 pub struct ChaChaEncryptor {
@@ -38,7 +38,10 @@ impl ChaChaEncryptor {
 
         self.cipher
             .encrypt(nonce, plaintext)
-            .map_err(|e| LostLoveError::Connection(format!("ChaCha20 encryption failed: {}", e)))
+            .map_err(|_| LostLoveError::AeadFailure {
+                cipher: "ChaCha20-Poly1305",
+                op: CryptoOp::Encrypt,
+            })
     }
 
     /// Decrypt data
@@ -47,7 +50,10 @@ impl ChaChaEncryptor {
 
         self.cipher
             .decrypt(nonce, ciphertext)
-            .map_err(|e| LostLoveError::Connection(format!("ChaCha20 decryption failed: {}", e)))
+            .map_err(|_| LostLoveError::AeadFailure {
+                cipher: "ChaCha20-Poly1305",
+                op: CryptoOp::Decrypt,
+            })
     }
 
     /// Encrypt in-place (modifies the buffer)
@@ -56,7 +62,10 @@ impl ChaChaEncryptor {
 
         self.cipher
             .encrypt_in_place(nonce_obj, b"", buffer)
-            .map_err(|e| LostLoveError::Connection(format!("ChaCha20 encryption failed: {}", e)))
+            .map_err(|_| LostLoveError::AeadFailure {
+                cipher: "ChaCha20-Poly1305",
+                op: CryptoOp::Encrypt,
+            })
     }
 
     /// Decrypt in-place (modifies the buffer)
@@ -65,7 +74,10 @@ impl ChaChaEncryptor {
 
         self.cipher
             .decrypt_in_place(nonce_obj, b"", buffer)
-            .map_err(|e| LostLoveError::Connection(format!("ChaCha20 decryption failed: {}", e)))
+            .map_err(|_| LostLoveError::AeadFailure {
+                cipher: "ChaCha20-Poly1305",
+                op: CryptoOp::Decrypt,
+            })
     }
 
     /// Get key size
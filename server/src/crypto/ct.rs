@@ -0,0 +1,21 @@
+use subtle::ConstantTimeEq;
+
+/// Compare secrets (tags, tokens, keys) in time independent of their contents
+///
+/// Only the lengths may leak, so compare values of a public, fixed length.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eq() {
+        assert!(eq(b"secret tag", b"secret tag"));
+        assert!(!eq(b"secret tag", b"secret tah"));
+        assert!(!eq(b"secret tag", b"secret"));
+        assert!(eq(b"", b""));
+    }
+}
@@ -1,10 +1,13 @@
 use crate::crypto::{AesEncryptor, ChaChaEncryptor};
-use crate::error::{LostLoveError, Result};
+use crate::error::Result;
 use zeroize::Zeroizing;
 
 /// Hybrid Symmetric Encryption (HSE)
 /// Combines ChaCha20-Poly1305 and AES-256-GCM for double encryption
-/// Formula: HSE = ChaCha20(data) ⊕ AES256(data)
+/// Formula: HSE = AES256(ChaCha20(data)), both under the same nonce
+///
+/// Data stays protected as long as either cipher holds; the result is
+/// 32 bytes longer than the plaintext (both authentication tags).
 pub struct HSEEncryptor {
     chacha: ChaChaEncryptor,
     aes: AesEncryptor,
@@ -22,119 +25,19 @@ impl HSEEncryptor {
     /// Encrypt data using hybrid encryption
     /// Process:
     /// 1. Encrypt with ChaCha20-Poly1305
-    /// 2. Encrypt with AES-256-GCM
-    /// 3. XOR the two ciphertexts together
+    /// 2. Encrypt the ChaCha ciphertext with AES-256-GCM
     pub fn encrypt(&self, plaintext: &[u8], nonce: &[u8; 12]) -> Result<Vec<u8>> {
-        // Encrypt with both algorithms
         let chacha_encrypted = self.chacha.encrypt(plaintext, nonce)?;
-        let aes_encrypted = self.aes.encrypt(plaintext, nonce)?;
-
-        // Ensure both ciphertexts are the same length
-        if chacha_encrypted.len() != aes_encrypted.len() {
-            return Err(LostLoveError::Crypto(
-                "Ciphertext length mismatch in HSE".to_string(),
-            ));
-        }
-
-        // XOR the two ciphertexts
-        let mut result = Vec::with_capacity(chacha_encrypted.len());
-        for (c1, c2) in chacha_encrypted.iter().zip(aes_encrypted.iter()) {
-            result.push(c1 ^ c2);
-        }
-
-        Ok(result)
+        self.aes.encrypt(&chacha_encrypted, nonce)
     }
 
     /// Decrypt data using hybrid decryption
     /// Process:
-    /// 1. XOR with AES ciphertext to recover ChaCha ciphertext
-    /// 2. XOR with ChaCha ciphertext to recover AES ciphertext
-    /// 3. Decrypt both and verify they match
+    /// 1. Decrypt and verify the AES-256-GCM layer
+    /// 2. Decrypt and verify the ChaCha20-Poly1305 layer
     pub fn decrypt(&self, ciphertext: &[u8], nonce: &[u8; 12]) -> Result<Vec<u8>> {
-        // We need to try both algorithms separately since we have XORed data
-        // C_combined = C_chacha ⊕ C_aes
-        // To decrypt:
-        // 1. Decrypt with ChaCha: D_chacha(C_combined ⊕ E_aes(plaintext))
-        // 2. Decrypt with AES: D_aes(C_combined ⊕ E_chacha(plaintext))
-        //
-        // Since we don't know the plaintext, we need to use a different approach:
-        // We'll use the property that both encryptions should produce the same plaintext
-
-        // For now, we'll use a brute-force approach with length estimation
-        // In a real implementation, we'd need to store metadata about the original length
-
-        // Estimate plaintext length (ciphertext - auth tag overhead)
-        // ChaCha20-Poly1305 adds 16 bytes, AES-GCM adds 16 bytes
-        let estimated_plaintext_len = if ciphertext.len() > 32 {
-            ciphertext.len() - 32
-        } else {
-            return Err(LostLoveError::Crypto(
-                "HSE ciphertext too short".to_string(),
-            ));
-        };
-
-        // Try different plaintext lengths around the estimate
-        for plaintext_len in (estimated_plaintext_len.saturating_sub(10))
-            ..=(estimated_plaintext_len + 10)
-        {
-            if let Ok(plaintext) = self.try_decrypt_with_length(ciphertext, nonce, plaintext_len)
-            {
-                return Ok(plaintext);
-            }
-        }
-
-        Err(LostLoveError::Crypto(
-            "HSE decryption failed: could not find valid plaintext".to_string(),
-        ))
-    }
-
-    /// Try to decrypt with a specific plaintext length
-    fn try_decrypt_with_length(
-        &self,
-        combined_ciphertext: &[u8],
-        nonce: &[u8; 12],
-        plaintext_len: usize,
-    ) -> Result<Vec<u8>> {
-        // Try to decrypt as if the combined ciphertext is valid
-        // We'll create dummy plaintexts and check if they work
-
-        // Create a test plaintext of the specified length
-        let test_plaintext = vec![0u8; plaintext_len];
-
-        // Encrypt test plaintext with both algorithms
-        let chacha_test = self.chacha.encrypt(&test_plaintext, nonce)?;
-        let aes_test = self.aes.encrypt(&test_plaintext, nonce)?;
-
-        // Check if the lengths match
-        if chacha_test.len() != combined_ciphertext.len()
-            || aes_test.len() != combined_ciphertext.len()
-        {
-            return Err(LostLoveError::Crypto("Length mismatch".to_string()));
-        }
-
-        // XOR combined ciphertext with AES test to get ChaCha ciphertext
-        let mut chacha_ciphertext = Vec::with_capacity(combined_ciphertext.len());
-        for (combined, aes_byte) in combined_ciphertext.iter().zip(aes_test.iter()) {
-            chacha_ciphertext.push(combined ^ aes_byte);
-        }
-
-        // Try to decrypt the ChaCha ciphertext
-        if let Ok(plaintext1) = self.chacha.decrypt(&chacha_ciphertext, nonce) {
-            // Now verify with AES
-            let mut aes_ciphertext = Vec::with_capacity(combined_ciphertext.len());
-            for (combined, chacha_byte) in combined_ciphertext.iter().zip(chacha_test.iter()) {
-                aes_ciphertext.push(combined ^ chacha_byte);
-            }
-
-            if let Ok(plaintext2) = self.aes.decrypt(&aes_ciphertext, nonce) {
-                // Both should produce the same plaintext
-                if plaintext1 == plaintext2 {
-                    return Ok(plaintext1);
-                }
-            }
-        }
-
-        Err(LostLoveError::Crypto("Decryption failed".to_string()))
+        let chacha_encrypted = self.aes.decrypt(ciphertext, nonce)?;
+        self.chacha.decrypt(&chacha_encrypted, nonce)
     }
 
     /// Generate random keys for HSE
@@ -271,4 +174,31 @@ mod tests {
         assert_eq!(key1.len(), 32);
         assert_eq!(key2.len(), 32);
     }
+
+    #[test]
+    fn test_hse_known_answers() {
+        let vectors: Vec<Vec<&str>> = include_str!("testdata/hse_vectors.txt")
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert!(!vectors.is_empty());
+
+        let bytes = |field: &str| match field {
+            "-" => Vec::new(),
+            field => hex::decode(field).unwrap(),
+        };
+
+        for fields in vectors {
+            let chacha_key: [u8; 32] = bytes(fields[1]).try_into().unwrap();
+            let aes_key: [u8; 32] = bytes(fields[2]).try_into().unwrap();
+            let nonce: [u8; 12] = bytes(fields[3]).try_into().unwrap();
+            let plaintext = bytes(fields[4]);
+            let ciphertext = bytes(fields[5]);
+
+            let hse = HSEEncryptor::new(&chacha_key, &aes_key);
+            assert_eq!(hse.encrypt(&plaintext, &nonce).unwrap(), ciphertext, "{}", fields[0]);
+            assert_eq!(hse.decrypt(&ciphertext, &nonce).unwrap(), plaintext, "{}", fields[0]);
+        }
+    }
 }
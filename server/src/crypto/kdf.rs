@@ -16,7 +16,7 @@ pub fn derive_keys(
     let mut okm = Zeroizing::new(vec![0u8; output_length]);
 
     hk.expand(info, &mut okm)
-        .map_err(|_| LostLoveError::Connection("HKDF key derivation failed".to_string()))?;
+        .map_err(|_| LostLoveError::KeyDerivation("HKDF expand failed".to_string()))?;
 
     Ok(okm)
 }
@@ -40,9 +40,22 @@ pub fn derive_session_keys(
         64,
     )?;
 
+    session_keys_from_master(&master_secret)
+}
+
+/// Derive the cipher keys from a 64-byte master secret
+///
+/// Every buffer holding key bytes is zeroized when dropped; keys are copied
+/// straight into their final buffers rather than through plain arrays left
+/// behind on the stack.
+pub fn session_keys_from_master(master_secret: &[u8]) -> Result<SessionKeys> {
+    if master_secret.len() != 64 {
+        return Err(LostLoveError::KeyDerivation("Invalid master secret length".to_string()));
+    }
+
     // Derive ChaCha20 key (32 bytes)
     let chacha_key = derive_keys(
-        &master_secret,
+        master_secret,
         &[],
         b"LLP-chacha20-key",
         32,
@@ -50,30 +63,46 @@ pub fn derive_session_keys(
 
     // Derive AES key (32 bytes)
     let aes_key = derive_keys(
-        &master_secret,
+        master_secret,
         &[],
         b"LLP-aes-key",
         32,
     )?;
 
-    // Convert to fixed-size arrays
-    let chacha_key_array: [u8; 32] = chacha_key[..]
-        .try_into()
-        .map_err(|_| LostLoveError::Connection("Invalid key length".to_string()))?;
+    let mut keys = SessionKeys {
+        chacha_key: Zeroizing::new([0u8; 32]),
+        aes_key: Zeroizing::new([0u8; 32]),
+        master_secret: Zeroizing::new([0u8; 64]),
+    };
+    keys.chacha_key.copy_from_slice(&chacha_key);
+    keys.aes_key.copy_from_slice(&aes_key);
+    keys.master_secret.copy_from_slice(master_secret);
 
-    let aes_key_array: [u8; 32] = aes_key[..]
-        .try_into()
-        .map_err(|_| LostLoveError::Connection("Invalid key length".to_string()))?;
+    Ok(keys)
+}
+
+/// HKDF info for the nonce base of packets sent by the client
+pub const CLIENT_NONCE_INFO: &[u8] = b"LLP-v1-client-nonce";
+/// HKDF info for the nonce base of packets sent by the server
+pub const SERVER_NONCE_INFO: &[u8] = b"LLP-v1-server-nonce";
 
-    let master_secret_array: [u8; 64] = master_secret[..]
+/// Derive a direction's 12-byte nonce base from the master secret
+pub fn derive_nonce_base(master_secret: &[u8], info: &[u8]) -> Result<[u8; 12]> {
+    let base = derive_keys(master_secret, &[], info, 12)?;
+
+    base[..]
         .try_into()
-        .map_err(|_| LostLoveError::Connection("Invalid master secret length".to_string()))?;
+        .map_err(|_| LostLoveError::KeyDerivation("Invalid nonce base length".to_string()))
+}
 
-    Ok(SessionKeys {
-        chacha_key: Zeroizing::new(chacha_key_array),
-        aes_key: Zeroizing::new(aes_key_array),
-        master_secret: Zeroizing::new(master_secret_array),
-    })
+/// Nonce for a packet: the base XORed with the big-endian sequence
+/// number in its last 8 bytes (as in TLS 1.3), unique per direction
+pub fn packet_nonce(base: &[u8; 12], sequence: u64) -> [u8; 12] {
+    let mut nonce = *base;
+    for (byte, seq) in nonce[4..].iter_mut().zip(sequence.to_be_bytes()) {
+        *byte ^= seq;
+    }
+    nonce
 }
 
 /// Session keys derived from handshake
@@ -183,4 +212,81 @@ mod tests {
             assert_eq!(key.len(), length);
         }
     }
+
+    /// Vector lines split into fields, comments skipped
+    fn vectors(data: &str) -> Vec<Vec<&str>> {
+        data.lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.split_whitespace().collect())
+            .collect()
+    }
+
+    fn bytes(field: &str) -> Vec<u8> {
+        match field {
+            "-" => Vec::new(),
+            field => hex::decode(field).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_kdf_known_answers() {
+        let vectors = vectors(include_str!("testdata/kdf_vectors.txt"));
+        assert!(!vectors.is_empty());
+
+        for fields in vectors {
+            let okm = derive_keys(
+                &bytes(fields[1]),
+                &bytes(fields[2]),
+                &bytes(fields[3]),
+                fields[4].parse().unwrap(),
+            )
+            .unwrap();
+            assert_eq!(hex::encode(&*okm), fields[5], "{}", fields[0]);
+        }
+    }
+
+    #[test]
+    fn test_session_key_known_answers() {
+        let vectors = vectors(include_str!("testdata/session_key_vectors.txt"));
+        assert!(!vectors.is_empty());
+
+        for fields in vectors {
+            let client_random: [u8; 32] = bytes(fields[2]).try_into().unwrap();
+            let server_random: [u8; 32] = bytes(fields[3]).try_into().unwrap();
+            let keys = derive_session_keys(&bytes(fields[1]), &client_random, &server_random).unwrap();
+
+            assert_eq!(hex::encode(*keys.master_secret), fields[4], "{}", fields[0]);
+            assert_eq!(hex::encode(*keys.chacha_key), fields[5], "{}", fields[0]);
+            assert_eq!(hex::encode(*keys.aes_key), fields[6], "{}", fields[0]);
+
+            let client = derive_nonce_base(&*keys.master_secret, CLIENT_NONCE_INFO).unwrap();
+            let server = derive_nonce_base(&*keys.master_secret, SERVER_NONCE_INFO).unwrap();
+            assert_eq!(hex::encode(client), fields[7], "{}", fields[0]);
+            assert_eq!(hex::encode(server), fields[8], "{}", fields[0]);
+        }
+    }
+
+    #[test]
+    fn test_nonce_known_answers() {
+        let vectors = vectors(include_str!("testdata/nonce_vectors.txt"));
+        assert!(!vectors.is_empty());
+
+        for fields in vectors {
+            let base: [u8; 12] = bytes(fields[1]).try_into().unwrap();
+            let nonce = packet_nonce(&base, fields[2].parse().unwrap());
+            assert_eq!(hex::encode(nonce), fields[3], "{}", fields[0]);
+        }
+    }
+
+    #[test]
+    fn test_packet_nonces_unique_per_direction() {
+        let master_secret = [7u8; 64];
+        let client = derive_nonce_base(&master_secret, CLIENT_NONCE_INFO).unwrap();
+        let server = derive_nonce_base(&master_secret, SERVER_NONCE_INFO).unwrap();
+        assert_ne!(client, server);
+
+        let nonces: std::collections::HashSet<[u8; 12]> =
+            (0..1000).map(|sequence| packet_nonce(&client, sequence)).collect();
+        assert_eq!(nonces.len(), 1000);
+    }
 }
@@ -0,0 +1,116 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing::{error, warn};
+
+use crate::error::Result;
+
+/// Environment variable naming the key log file
+pub const KEYLOG_ENV: &str = "LLP_KEYLOG_FILE";
+
+/// Label of the line carrying the server random
+pub const SERVER_RANDOM_LABEL: &str = "SERVER_RANDOM";
+/// Label of the line carrying a session's master secret
+pub const MASTER_SECRET_LABEL: &str = "MASTER_SECRET";
+/// Label of the line carrying the master secret after a key rotation
+pub const ROTATED_MASTER_SECRET_LABEL: &str = "ROTATED_MASTER_SECRET";
+
+/// Session secrets written for decrypting one's own captures
+///
+/// Like NSS's `SSLKEYLOGFILE`, every line is
+/// `<label> <client_random hex> <secret hex>`; a session is identified by
+/// its client random, which travels in the clear in ClientHello. Anyone
+/// holding the file can decrypt the logged sessions, so it's for protocol
+/// development only.
+pub struct KeyLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl KeyLog {
+    /// Open the file named by `LLP_KEYLOG_FILE`, if set
+    ///
+    /// Release builds ignore the variable unless `allow_in_release` is set,
+    /// so a leftover environment can't leak production keys.
+    pub fn from_env(allow_in_release: bool) -> Option<Self> {
+        let path = std::env::var_os(KEYLOG_ENV)?;
+
+        if !cfg!(debug_assertions) && !allow_in_release {
+            error!(
+                "{} is set but ignored in release builds; pass --allow-keylog to write session secrets",
+                KEYLOG_ENV
+            );
+            return None;
+        }
+
+        match Self::open(&path) {
+            Ok(keylog) => Some(keylog),
+            Err(e) => {
+                error!("Failed to open key log {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Append to a key log file, created readable by the owner only
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).mode(0o600).open(&path)?;
+
+        warn!(
+            "Writing session secrets to {}: anyone with this file can decrypt captured traffic",
+            path.display()
+        );
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record a secret of the session with `client_random`
+    ///
+    /// Failures are logged rather than returned, the session goes on.
+    pub fn log(&self, label: &str, client_random: &[u8; 32], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex::encode(client_random), hex::encode(secret));
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            warn!("Failed to write key log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_lines() {
+        let path = std::env::temp_dir().join(format!("llp-keylog-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let keylog = KeyLog::open(&path).unwrap();
+        keylog.log(SERVER_RANDOM_LABEL, &[1u8; 32], &[2u8; 32]);
+        keylog.log(MASTER_SECRET_LABEL, &[1u8; 32], &[0xab; 4]);
+
+        // Appends to an existing file
+        KeyLog::open(&path).unwrap().log(ROTATED_MASTER_SECRET_LABEL, &[3u8; 32], &[0xcd; 2]);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], format!("SERVER_RANDOM {} {}", "01".repeat(32), "02".repeat(32)));
+        assert_eq!(lines[1], format!("MASTER_SECRET {} abababab", "01".repeat(32)));
+        assert_eq!(lines[2], format!("ROTATED_MASTER_SECRET {} cdcd", "03".repeat(32)));
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,13 @@
-use crate::crypto::kdf::{derive_session_keys, SessionKeys as DerivedSessionKeys};
+use crate::crypto::kdf::{derive_keys, derive_session_keys, session_keys_from_master};
+use crate::crypto::keylog::{KeyLog, MASTER_SECRET_LABEL, ROTATED_MASTER_SECRET_LABEL};
+use crate::crypto::memlock::{self, LockedRegion};
+use crate::core::clock::{self, Clock};
 use crate::crypto::HSEEncryptor;
 use crate::error::Result;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
+use tracing::warn;
 use zeroize::Zeroizing;
 
 pub use crate::crypto::kdf::SessionKeys;
@@ -19,6 +23,8 @@ pub struct KeyManager {
     previous_keys: Arc<RwLock<Option<SessionKeys>>>,
     /// Time when keys were last rotated
     last_rotation: Arc<RwLock<Instant>>,
+    /// Key epoch: 0 for the handshake keys, one more per rotation
+    epoch: Arc<RwLock<u64>>,
     /// Shared secret for key derivation
     shared_secret: Zeroizing<Vec<u8>>,
     /// Client random value
@@ -27,6 +33,14 @@ pub struct KeyManager {
     server_random: [u8; 32],
     /// Enable automatic key rotation
     auto_rotation: bool,
+    /// Age of the keys at which `check_rotation` rotates them
+    rotation_interval: Duration,
+    /// Key log receiving the master secrets
+    keylog: Option<Arc<KeyLog>>,
+    /// Pages of the key buffers kept out of swap and core dumps
+    locked: Vec<LockedRegion>,
+    /// Time source for the rotation interval
+    clock: Arc<dyn Clock>,
 }
 
 impl KeyManager {
@@ -37,19 +51,94 @@ impl KeyManager {
         server_random: [u8; 32],
         auto_rotation: bool,
     ) -> Result<Self> {
-        let keys = derive_session_keys(&shared_secret, &client_random, &server_random)?;
+        Self::resume(shared_secret, client_random, server_random, auto_rotation, 0)
+    }
+
+    /// Rebuild a key manager at the epoch persisted with the session
+    pub fn resume(
+        shared_secret: Vec<u8>,
+        client_random: [u8; 32],
+        server_random: [u8; 32],
+        auto_rotation: bool,
+        epoch: u64,
+    ) -> Result<Self> {
+        let keys = epoch_keys(&shared_secret, &client_random, &server_random, epoch)?;
+        let previous_keys = match epoch.checked_sub(1) {
+            Some(previous) => Some(epoch_keys(&shared_secret, &client_random, &server_random, previous)?),
+            None => None,
+        };
 
         Ok(Self {
             current_keys: Arc::new(RwLock::new(keys)),
-            previous_keys: Arc::new(RwLock::new(None)),
+            previous_keys: Arc::new(RwLock::new(previous_keys)),
             last_rotation: Arc::new(RwLock::new(Instant::now())),
+            epoch: Arc::new(RwLock::new(epoch)),
             shared_secret: Zeroizing::new(shared_secret),
             client_random,
             server_random,
             auto_rotation,
+            rotation_interval: KEY_ROTATION_INTERVAL,
+            keylog: None,
+            locked: Vec::new(),
+            clock: clock::system(),
         })
     }
 
+    /// Rotate automatically after `interval` instead of every 30 minutes
+    pub fn with_rotation_interval(mut self, interval: Duration) -> Self {
+        self.rotation_interval = interval;
+        self
+    }
+
+    /// Measure the rotation interval on `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        if let Ok(mut last_rotation) = self.last_rotation.try_write() {
+            *last_rotation = clock.now();
+        }
+        self.clock = clock;
+        self
+    }
+
+    /// Write this session's master secrets to a key log
+    pub fn with_keylog(mut self, keylog: Arc<KeyLog>) -> Self {
+        if let Ok(keys) = self.current_keys.try_read() {
+            keylog.log(MASTER_SECRET_LABEL, &self.client_random, &keys.master_secret[..]);
+        }
+        self.keylog = Some(keylog);
+        self
+    }
+
+    /// Keep the key buffers out of swap and core dumps
+    ///
+    /// Keys are replaced in place on rotation, so the locked pages keep
+    /// covering them. Copies handed out by `get_keys` and the cipher states
+    /// aren't locked. Best effort: failing to lock (usually a low
+    /// `RLIMIT_MEMLOCK`) is logged and the keys stay usable.
+    pub fn with_locked_memory(mut self) -> Self {
+        let (current_keys, previous_keys) = (self.current_keys.clone(), self.previous_keys.clone());
+        let regions = match (current_keys.try_read(), previous_keys.try_read()) {
+            (Ok(current), Ok(previous)) => [
+                memlock::lock(&*current),
+                memlock::lock(&*previous),
+                memlock::lock(&self.shared_secret[..]),
+            ],
+            _ => return self,
+        };
+
+        for region in regions {
+            match region {
+                Ok(region) => self.locked.push(region),
+                Err(e) => warn!("Session keys may be swapped out: {}", e),
+            }
+        }
+        self
+    }
+
+    /// Check if any key buffer is locked in memory
+    pub fn is_memory_locked(&self) -> bool {
+        !self.locked.is_empty()
+    }
+
     /// Get current session keys
     pub async fn get_keys(&self) -> SessionKeys {
         let keys = self.current_keys.read().await;
@@ -69,9 +158,9 @@ impl KeyManager {
         }
 
         let last_rotation = *self.last_rotation.read().await;
-        let elapsed = last_rotation.elapsed();
+        let elapsed = self.clock.now().saturating_duration_since(last_rotation);
 
-        if elapsed >= KEY_ROTATION_INTERVAL {
+        if elapsed >= self.rotation_interval {
             self.rotate_keys().await?;
             Ok(true)
         } else {
@@ -81,49 +170,20 @@ impl KeyManager {
 
     /// Force key rotation
     pub async fn rotate_keys(&self) -> Result<()> {
-        // Derive new keys with updated info string
-        let rotation_count = self.get_rotation_count().await;
-        let info = format!("LLP-v1-rotation-{}", rotation_count);
-
-        let new_keys = crate::crypto::kdf::derive_keys(
+        // Held until the new keys are in place, so concurrent rotations
+        // each get their own epoch
+        let mut epoch = self.epoch.write().await;
+        let next_epoch = *epoch + 1;
+        let rotated_keys = epoch_keys(
             &self.shared_secret,
-            &[],
-            info.as_bytes(),
-            64,
+            &self.client_random,
+            &self.server_random,
+            next_epoch,
         )?;
 
-        // Derive ChaCha and AES keys from the rotated master secret
-        let chacha_key = crate::crypto::kdf::derive_keys(
-            &new_keys,
-            &[],
-            b"LLP-chacha20-key",
-            32,
-        )?;
-
-        let aes_key = crate::crypto::kdf::derive_keys(
-            &new_keys,
-            &[],
-            b"LLP-aes-key",
-            32,
-        )?;
-
-        let chacha_key_array: [u8; 32] = chacha_key[..]
-            .try_into()
-            .map_err(|_| crate::error::LostLoveError::Connection("Invalid key length".to_string()))?;
-
-        let aes_key_array: [u8; 32] = aes_key[..]
-            .try_into()
-            .map_err(|_| crate::error::LostLoveError::Connection("Invalid key length".to_string()))?;
-
-        let master_secret_array: [u8; 64] = new_keys[..]
-            .try_into()
-            .map_err(|_| crate::error::LostLoveError::Connection("Invalid master secret length".to_string()))?;
-
-        let rotated_keys = SessionKeys {
-            chacha_key: Zeroizing::new(chacha_key_array),
-            aes_key: Zeroizing::new(aes_key_array),
-            master_secret: Zeroizing::new(master_secret_array),
-        };
+        if let Some(keylog) = &self.keylog {
+            keylog.log(ROTATED_MASTER_SECRET_LABEL, &self.client_random, &rotated_keys.master_secret[..]);
+        }
 
         // Store current keys as previous
         let current = self.current_keys.read().await.clone();
@@ -133,7 +193,8 @@ impl KeyManager {
         *self.current_keys.write().await = rotated_keys;
 
         // Update rotation time
-        *self.last_rotation.write().await = Instant::now();
+        *self.last_rotation.write().await = self.clock.now();
+        *epoch = next_epoch;
 
         Ok(())
     }
@@ -163,9 +224,10 @@ impl KeyManager {
             }
         }
 
-        Err(crate::error::LostLoveError::Crypto(
-            "Decryption failed with both current and previous keys".to_string(),
-        ))
+        Err(crate::error::LostLoveError::AeadFailure {
+            cipher: "HSE",
+            op: crate::error::CryptoOp::Decrypt,
+        })
     }
 
     /// Get time until next key rotation
@@ -175,16 +237,28 @@ impl KeyManager {
         }
 
         let last_rotation = *self.last_rotation.read().await;
-        let elapsed = last_rotation.elapsed();
+        let elapsed = self.clock.now().saturating_duration_since(last_rotation);
 
-        KEY_ROTATION_INTERVAL.saturating_sub(elapsed)
+        self.rotation_interval.saturating_sub(elapsed)
     }
 
-    /// Get number of key rotations performed
-    async fn get_rotation_count(&self) -> u64 {
-        let last_rotation = *self.last_rotation.read().await;
-        let total_time = last_rotation.elapsed();
-        (total_time.as_secs() / KEY_ROTATION_INTERVAL.as_secs()) + 1
+    /// Current key epoch, persisted with the session
+    pub async fn epoch(&self) -> u64 {
+        *self.epoch.read().await
+    }
+
+    /// Encryptor for packets carrying `key_phase`
+    ///
+    /// The phase is the epoch's low bit, so it picks between the current
+    /// keys and the previous ones still used by packets in flight.
+    pub async fn hse_for_key_phase(&self, key_phase: bool) -> Option<HSEEncryptor> {
+        if key_phase == (self.epoch().await & 1 == 1) {
+            return Some(self.get_hse_encryptor().await);
+        }
+
+        self.get_previous_keys()
+            .await
+            .map(|keys| HSEEncryptor::new(&keys.chacha_key, &keys.aes_key))
     }
 
     /// Clear all keys (called on disconnect)
@@ -194,6 +268,26 @@ impl KeyManager {
     }
 }
 
+/// Keys of an epoch: the handshake's for 0, rotated ones after that
+///
+/// Rotated keys come from the shared secret with the epoch in the KDF info,
+/// so every epoch's keys are distinct and can be rebuilt after a restart.
+fn epoch_keys(
+    shared_secret: &[u8],
+    client_random: &[u8; 32],
+    server_random: &[u8; 32],
+    epoch: u64,
+) -> Result<SessionKeys> {
+    if epoch == 0 {
+        return derive_session_keys(shared_secret, client_random, server_random);
+    }
+
+    let info = format!("LLP-v1-rotation-{}", epoch);
+    let master_secret = derive_keys(shared_secret, &[], info.as_bytes(), 64)?;
+
+    session_keys_from_master(&master_secret)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -313,6 +407,46 @@ mod tests {
         assert!(time_left <= KEY_ROTATION_INTERVAL);
     }
 
+    #[tokio::test]
+    async fn test_locked_keys_rotate() {
+        let km = create_test_key_manager().with_locked_memory();
+        assert_eq!(km.locked.len(), 3);
+
+        let before = km.get_keys().await;
+        km.rotate_keys().await.unwrap();
+        assert_ne!(&*km.get_keys().await.chacha_key, &*before.chacha_key);
+        assert_eq!(&*km.get_previous_keys().await.unwrap().chacha_key, &*before.chacha_key);
+    }
+
+    #[tokio::test]
+    async fn test_check_rotation_after_interval() {
+        let km = KeyManager::new(vec![1u8; 32], [2u8; 32], [3u8; 32], true)
+            .unwrap()
+            .with_rotation_interval(Duration::ZERO);
+
+        assert!(km.check_rotation().await.unwrap());
+        assert!(km.check_rotation().await.unwrap());
+        assert_eq!(km.epoch().await, 2);
+        assert_eq!(km.time_until_rotation().await, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_follows_clock() {
+        let clock = Arc::new(crate::core::clock::ManualClock::new());
+        let km = KeyManager::new(vec![1u8; 32], [2u8; 32], [3u8; 32], true)
+            .unwrap()
+            .with_rotation_interval(Duration::from_secs(60))
+            .with_clock(clock.clone());
+
+        clock.advance(Duration::from_secs(59));
+        assert!(!km.check_rotation().await.unwrap());
+        assert_eq!(km.time_until_rotation().await, Duration::from_secs(1));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(km.check_rotation().await.unwrap());
+        assert_eq!(km.time_until_rotation().await, Duration::from_secs(60));
+    }
+
     #[tokio::test]
     async fn test_clear_keys() {
         let km = create_test_key_manager();
@@ -345,4 +479,84 @@ mod tests {
             previous_keys = current_keys;
         }
     }
+
+    #[tokio::test]
+    async fn test_keylog_records_master_secrets() {
+        let path = std::env::temp_dir().join(format!("llp-keys-keylog-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let keylog = Arc::new(KeyLog::open(&path).unwrap());
+
+        let km = create_test_key_manager().with_keylog(keylog);
+        let initial = km.get_keys().await;
+        km.rotate_keys().await.unwrap();
+        let rotated = km.get_keys().await;
+
+        let client_random = hex::encode([2u8; 32]);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                format!("MASTER_SECRET {} {}", client_random, hex::encode(*initial.master_secret)),
+                format!("ROTATED_MASTER_SECRET {} {}", client_random, hex::encode(*rotated.master_secret)),
+            ]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_epoch_counts_manual_rotations() {
+        let km = create_test_key_manager();
+        assert_eq!(km.epoch().await, 0);
+
+        let mut seen = vec![km.get_keys().await.master_secret];
+        for epoch in 1..=3 {
+            km.rotate_keys().await.unwrap();
+            assert_eq!(km.epoch().await, epoch);
+
+            // Every epoch's keys are distinct
+            let master_secret = km.get_keys().await.master_secret;
+            assert!(!seen.contains(&master_secret));
+            seen.push(master_secret);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_at_epoch() {
+        let km = create_test_key_manager();
+        km.rotate_keys().await.unwrap();
+        km.rotate_keys().await.unwrap();
+
+        let resumed = KeyManager::resume(vec![1u8; 32], [2u8; 32], [3u8; 32], false, 2).unwrap();
+        assert_eq!(resumed.epoch().await, 2);
+        assert_eq!(*resumed.get_keys().await.master_secret, *km.get_keys().await.master_secret);
+        assert_eq!(
+            *resumed.get_previous_keys().await.unwrap().master_secret,
+            *km.get_previous_keys().await.unwrap().master_secret
+        );
+
+        // Rotating on continues the sequence
+        km.rotate_keys().await.unwrap();
+        resumed.rotate_keys().await.unwrap();
+        assert_eq!(*resumed.get_keys().await.chacha_key, *km.get_keys().await.chacha_key);
+    }
+
+    #[tokio::test]
+    async fn test_hse_for_key_phase() {
+        let km = create_test_key_manager();
+        let nonce = [0u8; 12];
+        let old = km.get_hse_encryptor().await.encrypt(b"epoch 0", &nonce).unwrap();
+
+        // No previous keys before the first rotation
+        assert!(km.hse_for_key_phase(true).await.is_none());
+
+        km.rotate_keys().await.unwrap();
+        let new = km.get_hse_encryptor().await.encrypt(b"epoch 1", &nonce).unwrap();
+
+        let current = km.hse_for_key_phase(true).await.unwrap();
+        assert_eq!(current.decrypt(&new, &nonce).unwrap(), b"epoch 1");
+        let previous = km.hse_for_key_phase(false).await.unwrap();
+        assert_eq!(previous.decrypt(&old, &nonce).unwrap(), b"epoch 0");
+    }
 }
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+
+use crate::error::{LostLoveError, Result};
+
+/// Locked pages by address, with the number of regions on each
+///
+/// `mlock` doesn't count: unlocking a page unlocks it for every buffer on
+/// it, so a page is only unlocked once its last region is dropped.
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Memory kept out of swap and core dumps until dropped
+#[derive(Debug)]
+pub struct LockedRegion {
+    pages: Vec<usize>,
+}
+
+/// Lock the pages holding `value` into memory and exclude them from core dumps
+///
+/// Whole pages are affected, so unrelated data sharing them is locked too.
+/// The region must be dropped before `value` moves; locking a value that
+/// moves around (a stack copy) protects only its current location.
+pub fn lock<T: ?Sized>(value: &T) -> Result<LockedRegion> {
+    let start = value as *const T as *const u8 as usize;
+    let len = std::mem::size_of_val(value);
+    let page_size = page_size();
+
+    let mut locked = LOCKED_PAGES.lock().unwrap();
+    let mut region = LockedRegion { pages: Vec::new() };
+
+    for page in pages(start, len, page_size) {
+        let count = locked.entry(page).or_insert(0);
+        if *count == 0 {
+            // SAFETY: the page is mapped, it holds part of `value`
+            if unsafe { libc::mlock(page as *const libc::c_void, page_size) } != 0 {
+                let err = io::Error::last_os_error();
+                locked.remove(&page);
+                release(&mut locked, &region.pages, page_size);
+                region.pages.clear();
+                return Err(LostLoveError::Crypto(format!("Failed to lock key memory: {}", err)));
+            }
+            advise(page, page_size, Dump::Exclude);
+        }
+        *count += 1;
+        region.pages.push(page);
+    }
+
+    Ok(region)
+}
+
+impl Drop for LockedRegion {
+    fn drop(&mut self) {
+        release(&mut LOCKED_PAGES.lock().unwrap(), &self.pages, page_size());
+    }
+}
+
+/// Stop the process from ever writing a core dump
+///
+/// Sets the core size limit to zero, which a crash handler can't raise
+/// again, and clears the dumpable flag, which also keeps unprivileged
+/// debuggers from attaching.
+pub fn disable_core_dumps() -> Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    // SAFETY: plain syscall with a valid rlimit
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        return Err(LostLoveError::Io(io::Error::last_os_error()));
+    }
+
+    #[cfg(target_os = "linux")]
+    // SAFETY: plain syscall without pointers
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(LostLoveError::Io(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+/// Bytes the process may lock, None when unlimited
+pub fn lock_limit() -> Result<Option<u64>> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    // SAFETY: plain syscall writing into a valid rlimit
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return Err(LostLoveError::Io(io::Error::last_os_error()));
+    }
+
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        Ok(None)
+    } else {
+        Ok(Some(limit.rlim_cur))
+    }
+}
+
+/// Size of a memory page, the unit memory is locked in
+pub fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Start addresses of the pages covering `len` bytes from `start`
+fn pages(start: usize, len: usize, page_size: usize) -> impl Iterator<Item = usize> {
+    let first = start & !(page_size - 1);
+    let end = start + len.max(1);
+    (first..end).step_by(page_size)
+}
+
+/// Drop one region from each page, unlocking pages no region is left on
+fn release(locked: &mut BTreeMap<usize, usize>, pages: &[usize], page_size: usize) {
+    for page in pages {
+        let Some(count) = locked.get_mut(page) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(page);
+            advise(*page, page_size, Dump::Include);
+            // SAFETY: only changes the page's residency; failure (the page
+            // was unmapped meanwhile) leaves nothing to undo
+            unsafe {
+                libc::munlock(*page as *const libc::c_void, page_size);
+            }
+        }
+    }
+}
+
+enum Dump {
+    Exclude,
+    Include,
+}
+
+#[cfg(target_os = "linux")]
+fn advise(page: usize, page_size: usize, dump: Dump) {
+    let advice = match dump {
+        Dump::Exclude => libc::MADV_DONTDUMP,
+        Dump::Include => libc::MADV_DODUMP,
+    };
+    // SAFETY: page is aligned and was locked by us; the advice only affects
+    // core dumps
+    unsafe {
+        libc::madvise(page as *mut libc::c_void, page_size, advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise(_page: usize, _page_size: usize, _dump: Dump) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer alone on its page, so other tests can't lock it too
+    #[repr(align(65536))]
+    struct Page([u8; 64]);
+
+    fn lock_count(value: &[u8]) -> usize {
+        let page = value.as_ptr() as usize & !(page_size() - 1);
+        LOCKED_PAGES.lock().unwrap().get(&page).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_pages() {
+        assert_eq!(pages(4096, 32, 4096).collect::<Vec<_>>(), vec![4096]);
+        assert_eq!(pages(4100, 4096, 4096).collect::<Vec<_>>(), vec![4096, 8192]);
+        assert_eq!(pages(8191, 2, 4096).collect::<Vec<_>>(), vec![4096, 8192]);
+        assert_eq!(pages(4096, 0, 4096).collect::<Vec<_>>(), vec![4096]);
+    }
+
+    #[test]
+    fn test_shared_page_stays_locked() {
+        let page = Box::new(Page([7u8; 64]));
+        let (first, second) = page.0.split_at(32);
+
+        let first_region = lock(first).unwrap();
+        let second_region = lock(second).unwrap();
+        assert_eq!(lock_count(first), 2);
+
+        drop(first_region);
+        assert_eq!(lock_count(second), 1);
+
+        drop(second_region);
+        assert_eq!(lock_count(second), 0);
+    }
+}
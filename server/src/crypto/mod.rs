@@ -1,11 +1,25 @@
 pub mod chacha;
 pub mod aes;
+pub mod ct;
 pub mod hse;
 pub mod kdf;
 pub mod keys;
+pub mod keylog;
+pub mod memlock;
+pub mod obfuscation;
+pub mod policy;
+pub mod rng;
+pub mod selftest;
+pub mod sequence;
+pub mod tpm;
 
 pub use chacha::ChaChaEncryptor;
 pub use aes::AesEncryptor;
 pub use hse::HSEEncryptor;
-pub use kdf::{derive_keys, derive_session_keys};
-pub use keys::{KeyManager, SessionKeys};
+pub use kdf::derive_keys;
+pub use keys::KeyManager;
+pub use keylog::KeyLog;
+pub use policy::CryptoPolicy;
+pub use selftest::selftest;
+pub use sequence::SequenceSpaces;
+pub use tpm::SealedSecret;
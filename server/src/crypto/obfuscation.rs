@@ -0,0 +1,182 @@
+use rand::Rng;
+
+use crate::crypto::{derive_keys, ChaChaEncryptor};
+use crate::error::{LostLoveError, Result};
+
+/// Nonce, encrypted lengths and their tag
+pub const OBFUSCATED_HEADER_SIZE: usize = 12 + 4 + TAG_SIZE;
+
+const TAG_SIZE: usize = 16;
+
+/// Most random padding added after a handshake packet
+const MAX_PADDING: usize = 255;
+
+/// Side sending an obfuscated handshake message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelloSender {
+    Client,
+    Server,
+}
+
+impl HelloSender {
+    fn info(self) -> &'static [u8] {
+        match self {
+            HelloSender::Client => b"LLP-v1-obfs-client-hello",
+            HelloSender::Server => b"LLP-v1-obfs-server-hello",
+        }
+    }
+}
+
+/// Outer layer hiding the first handshake message in each direction
+///
+/// Keys are derived from the server key that clients get along with the
+/// server address, so only they can produce a ClientHello the server
+/// accepts (as WireGuard's mac1 uses the responder's public key). On the
+/// wire a message is
+///
+/// ```text
+/// nonce (12) | AEAD(packet_len u16 | padding_len u16) | AEAD(packet | padding)
+/// ```
+///
+/// with the body sealed under the nonce with its last bit flipped. Without
+/// the key every byte is indistinguishable from random, including the total
+/// length thanks to the padding.
+pub struct HandshakeObfuscation {
+    client: ChaChaEncryptor,
+    server: ChaChaEncryptor,
+}
+
+impl HandshakeObfuscation {
+    /// Derive both directions' keys from the server key
+    pub fn new(server_key: &[u8]) -> Result<Self> {
+        let cipher = |sender: HelloSender| -> Result<ChaChaEncryptor> {
+            let key = derive_keys(server_key, b"", sender.info(), 32)?;
+            let key: [u8; 32] = key[..].try_into().expect("derived 32 bytes");
+            Ok(ChaChaEncryptor::new(&key))
+        };
+
+        Ok(Self {
+            client: cipher(HelloSender::Client)?,
+            server: cipher(HelloSender::Server)?,
+        })
+    }
+
+    fn cipher(&self, sender: HelloSender) -> &ChaChaEncryptor {
+        match sender {
+            HelloSender::Client => &self.client,
+            HelloSender::Server => &self.server,
+        }
+    }
+
+    /// Obfuscate a serialized handshake packet
+    pub fn seal(&self, sender: HelloSender, packet: &[u8]) -> Result<Vec<u8>> {
+        let packet_len = u16::try_from(packet.len())
+            .map_err(|_| LostLoveError::Crypto("Handshake packet too large to obfuscate".to_string()))?;
+        let mut rng = rand::thread_rng();
+        let padding_len = rng.gen_range(0..=MAX_PADDING);
+
+        let nonce = ChaChaEncryptor::generate_nonce();
+        let mut lengths = packet_len.to_be_bytes().to_vec();
+        lengths.extend_from_slice(&(padding_len as u16).to_be_bytes());
+
+        let mut body = packet.to_vec();
+        body.resize(packet.len() + padding_len, 0);
+
+        let cipher = self.cipher(sender);
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&cipher.encrypt(&lengths, &nonce)?);
+        sealed.extend_from_slice(&cipher.encrypt(&body, &body_nonce(&nonce))?);
+        Ok(sealed)
+    }
+
+    /// Size of the body following an obfuscated header
+    pub fn open_header(&self, sender: HelloSender, header: &[u8]) -> Result<usize> {
+        let (nonce, lengths) = split_header(header)?;
+        let lengths = self.cipher(sender).decrypt(lengths, &nonce)?;
+
+        let packet_len = u16::from_be_bytes([lengths[0], lengths[1]]) as usize;
+        let padding_len = u16::from_be_bytes([lengths[2], lengths[3]]) as usize;
+        Ok(packet_len + padding_len + TAG_SIZE)
+    }
+
+    /// Recover the handshake packet from an obfuscated header and body
+    pub fn open_body(&self, sender: HelloSender, header: &[u8], body: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, lengths) = split_header(header)?;
+        let cipher = self.cipher(sender);
+        let lengths = cipher.decrypt(lengths, &nonce)?;
+
+        let mut packet = cipher.decrypt(body, &body_nonce(&nonce))?;
+        packet.truncate(u16::from_be_bytes([lengths[0], lengths[1]]) as usize);
+        Ok(packet)
+    }
+}
+
+fn split_header(header: &[u8]) -> Result<([u8; 12], &[u8])> {
+    if header.len() != OBFUSCATED_HEADER_SIZE {
+        return Err(LostLoveError::InsufficientData {
+            expected: OBFUSCATED_HEADER_SIZE,
+            actual: header.len(),
+        });
+    }
+
+    let (nonce, lengths) = header.split_at(12);
+    Ok((nonce.try_into().unwrap(), lengths))
+}
+
+fn body_nonce(nonce: &[u8; 12]) -> [u8; 12] {
+    let mut body_nonce = *nonce;
+    body_nonce[11] ^= 1;
+    body_nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET: &[u8] = b"\x4c\x4c\x03 a handshake init packet";
+
+    fn open(obfuscation: &HandshakeObfuscation, sender: HelloSender, sealed: &[u8]) -> Result<Vec<u8>> {
+        let (header, body) = sealed.split_at(OBFUSCATED_HEADER_SIZE);
+        assert_eq!(obfuscation.open_header(sender, header)?, body.len());
+        obfuscation.open_body(sender, header, body)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let server = HandshakeObfuscation::new(b"server key").unwrap();
+        let client = HandshakeObfuscation::new(b"server key").unwrap();
+
+        let sealed = client.seal(HelloSender::Client, PACKET).unwrap();
+        assert_eq!(open(&server, HelloSender::Client, &sealed).unwrap(), PACKET);
+
+        let sealed = server.seal(HelloSender::Server, PACKET).unwrap();
+        assert_eq!(open(&client, HelloSender::Server, &sealed).unwrap(), PACKET);
+    }
+
+    #[test]
+    fn test_wrong_key_or_direction_rejected() {
+        let server = HandshakeObfuscation::new(b"server key").unwrap();
+        let other = HandshakeObfuscation::new(b"other key").unwrap();
+
+        let sealed = other.seal(HelloSender::Client, PACKET).unwrap();
+        assert!(server.open_header(HelloSender::Client, &sealed[..OBFUSCATED_HEADER_SIZE]).is_err());
+
+        // A server message can't be reflected back as a client one
+        let sealed = server.seal(HelloSender::Server, PACKET).unwrap();
+        assert!(server.open_header(HelloSender::Client, &sealed[..OBFUSCATED_HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_sealed_messages_look_random() {
+        let obfuscation = HandshakeObfuscation::new(b"server key").unwrap();
+        let sealed: Vec<Vec<u8>> = (0..20)
+            .map(|_| obfuscation.seal(HelloSender::Client, PACKET).unwrap())
+            .collect();
+
+        assert!(sealed.iter().all(|message| !message.windows(PACKET.len()).any(|w| w == PACKET)));
+        assert_ne!(sealed[0][..32], sealed[1][..32]);
+        // Padding hides the packet length
+        let lengths: std::collections::BTreeSet<usize> = sealed.iter().map(Vec::len).collect();
+        assert!(lengths.len() > 1);
+    }
+}
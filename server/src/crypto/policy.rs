@@ -0,0 +1,100 @@
+use crate::config::SecurityConfig;
+use crate::protocol::handshake::{CipherSuite, SUPPORTED_CIPHER_SUITES};
+
+/// Cipher suites allowed in FIPS mode
+const FIPS_CIPHER_SUITES: &[CipherSuite] = &[CipherSuite::Aes256Gcm];
+
+/// Which algorithms sessions may negotiate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoPolicy {
+    /// Every supported cipher suite
+    Standard,
+    /// Only approved algorithms: AES-256-GCM with HKDF-SHA512 keys
+    ///
+    /// HSE and ChaCha20-Poly1305 aren't offered, clients that insist on
+    /// them are refused with `no-common-cipher-suite`. Handshake
+    /// obfuscation seals with ChaCha20-Poly1305, so it can't be enabled.
+    Fips,
+}
+
+impl CryptoPolicy {
+    /// FIPS when the build has the `fips` feature or `security.fips` is set
+    pub fn from_config(config: &SecurityConfig) -> Self {
+        if cfg!(feature = "fips") || config.fips {
+            CryptoPolicy::Fips
+        } else {
+            CryptoPolicy::Standard
+        }
+    }
+
+    /// Negotiable cipher suites, most preferred first
+    pub fn cipher_suites(self) -> &'static [CipherSuite] {
+        match self {
+            CryptoPolicy::Standard => SUPPORTED_CIPHER_SUITES,
+            CryptoPolicy::Fips => FIPS_CIPHER_SUITES,
+        }
+    }
+
+    /// Check whether sessions may use `suite`
+    pub fn allows(self, suite: CipherSuite) -> bool {
+        self.cipher_suites().contains(&suite)
+    }
+
+    /// Check whether handshakes may be obfuscated, which uses ChaCha20-Poly1305
+    pub fn allows_obfuscation(self) -> bool {
+        self == CryptoPolicy::Standard
+    }
+
+    /// Line logged at startup recording what the server will negotiate
+    ///
+    /// The implementations are RustCrypto's, which aren't a validated
+    /// module; the line says so rather than claiming certification.
+    pub fn attestation(self) -> String {
+        let suites: Vec<&str> = self.cipher_suites().iter().map(|suite| suite.name()).collect();
+
+        format!(
+            "Crypto policy: mode={} build_fips={} cipher_suites={} obfuscation={} kdf=HKDF-SHA512 key_bits=256 module=RustCrypto (not CMVP validated)",
+            match self {
+                CryptoPolicy::Standard => "standard",
+                CryptoPolicy::Fips => "fips",
+            },
+            cfg!(feature = "fips"),
+            suites.join(","),
+            if self.allows_obfuscation() { "chacha20-poly1305" } else { "refused" },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fips_allows_only_aes_gcm() {
+        assert!(CryptoPolicy::Fips.allows(CipherSuite::Aes256Gcm));
+        assert!(!CryptoPolicy::Fips.allows(CipherSuite::Hse));
+        assert!(!CryptoPolicy::Fips.allows(CipherSuite::ChaCha20Poly1305));
+        assert!(CryptoPolicy::Standard.allows(CipherSuite::Hse));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let fips = SecurityConfig {
+            fips: true,
+            ..SecurityConfig::default()
+        };
+        assert_eq!(CryptoPolicy::from_config(&fips), CryptoPolicy::Fips);
+
+        let expected = if cfg!(feature = "fips") { CryptoPolicy::Fips } else { CryptoPolicy::Standard };
+        assert_eq!(CryptoPolicy::from_config(&SecurityConfig::default()), expected);
+    }
+
+    #[test]
+    fn test_attestation() {
+        let line = CryptoPolicy::Fips.attestation();
+        assert!(line.contains("mode=fips"));
+        assert!(line.contains("cipher_suites=aes-256-gcm "));
+        assert!(line.contains("obfuscation=refused "));
+        assert!(CryptoPolicy::Standard.attestation().contains("cipher_suites=hse,chacha20-poly1305,aes-256-gcm "));
+    }
+}
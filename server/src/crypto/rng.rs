@@ -0,0 +1,76 @@
+#[cfg(test)]
+use rand::rngs::StdRng;
+use rand::RngCore;
+#[cfg(test)]
+use rand::SeedableRng;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+
+/// Where handshake randoms and session IDs come from
+///
+/// The system source is used everywhere outside tests; a seeded source
+/// makes a handshake replay byte for byte in tests.
+pub trait RandomSource: Send + Sync {
+    /// Fill `dest` with random bytes
+    fn fill(&self, dest: &mut [u8]);
+}
+
+/// Random bytes from the OS-seeded thread RNG
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemRandom;
+
+impl RandomSource for SystemRandom {
+    fn fill(&self, dest: &mut [u8]) {
+        rand::thread_rng().fill_bytes(dest);
+    }
+}
+
+/// Reproducible random bytes from a fixed seed, for tests only
+#[cfg(test)]
+#[derive(Debug)]
+pub struct SeededRandom(Mutex<StdRng>);
+
+#[cfg(test)]
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+#[cfg(test)]
+impl RandomSource for SeededRandom {
+    fn fill(&self, dest: &mut [u8]) {
+        self.0.lock().unwrap().fill_bytes(dest);
+    }
+}
+
+/// The system source, shared
+pub fn system() -> Arc<dyn RandomSource> {
+    Arc::new(SystemRandom)
+}
+
+/// 32 random bytes, the size of a handshake random
+pub fn random_32(source: &dyn RandomSource) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    source.fill(&mut bytes);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_is_reproducible() {
+        let (first, second) = (SeededRandom::new(7), SeededRandom::new(7));
+        assert_eq!(random_32(&first), random_32(&second));
+        assert_eq!(random_32(&first), random_32(&second));
+        assert_ne!(random_32(&first), random_32(&SeededRandom::new(8)));
+    }
+
+    #[test]
+    fn test_system_varies() {
+        assert_ne!(random_32(&SystemRandom), random_32(&SystemRandom));
+    }
+}
@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::kdf::{derive_keys, derive_nonce_base, derive_session_keys, packet_nonce, CLIENT_NONCE_INFO};
+use crate::crypto::{AesEncryptor, ChaChaEncryptor, HSEEncryptor};
+use crate::error::{LostLoveError, Result};
+
+// Known answers from an independent implementation, mostly also in
+// crypto/testdata; never regenerate from the Rust code
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const NONCE: &str = "000102030405060708090a0b";
+const PLAINTEXT: &str = "4c4c502073656c662d74657374";
+const CHACHA_CIPHERTEXT: &str = "c5b758205a72c9269af75a80ecdc64cf0473cdfb97bee9cabdc88a9ad4";
+const AES_CIPHERTEXT: &str = "0b4e863bb680ae7da035f2f8c51e3759ef8f544e9411286861a54f218b";
+const HSE_CIPHERTEXT: &str = "13301b736452682e401abe62a112abe75e138ee2ba7949644cdeaac73f1e7151bb0ed30d54db470542255142c263b90136111f669d988b27a1";
+const HSE_PLAINTEXT: &str = "48656c6c6f2c204c6f73744c6f76652050726f746f636f6c21";
+const HKDF_OKM: &str = "7eac2b4fc4e02264ec189277a53e97a5a6ae5f23a51afc846ceb60a30df96286";
+const SHARED_SECRET: &str = "7368617265645f7365637265745f66726f6d5f6b65795f65786368616e6765";
+const MASTER_SECRET: &str = "cce2d8afb69767d0c1563c1e4f7c7eae475fe0380a779f5c53e0c73a92459db395dbbf2fe9481edc7035d96effe3e8308f16c06adf942c8d7671639f9aeafb25";
+const SESSION_CHACHA_KEY: &str = "4397891001e5f6ffa6962f0b5055f79cf5afc50b4eb39e4eedc932f0813840b7";
+const CLIENT_NONCE_BASE: &str = "b4881b2a1dfa3ad61cf14859";
+
+/// Outcome of one known-answer check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelfTestCheck {
+    pub name: String,
+    /// Why the check failed, None if it passed
+    pub error: Option<String>,
+}
+
+/// A known-answer check, failing with what came out wrong
+type Check = fn() -> Result<()>;
+
+/// Run every known-answer check
+pub fn run() -> Vec<SelfTestCheck> {
+    let checks: [(&str, Check); 6] = [
+        ("chacha20-poly1305", check_chacha),
+        ("aes-256-gcm", check_aes),
+        ("hse", check_hse),
+        ("hkdf-sha512", check_hkdf),
+        ("session-keys", check_session_keys),
+        ("packet-nonce", check_packet_nonce),
+    ];
+
+    checks
+        .into_iter()
+        .map(|(name, check)| SelfTestCheck {
+            name: name.to_string(),
+            error: check().err().map(|e| e.to_string()),
+        })
+        .collect()
+}
+
+/// Check the crypto primitives against known answers
+///
+/// Run before serving so a miscompiled or misdetected cipher backend
+/// (a broken SIMD path, say) stops the server instead of producing
+/// traffic no peer can read, or worse, that anyone can.
+pub fn selftest() -> Result<()> {
+    let failed: Vec<String> = run()
+        .into_iter()
+        .filter_map(|check| check.error.map(|error| format!("{}: {}", check.name, error)))
+        .collect();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(LostLoveError::Crypto(format!("Crypto self-test failed: {}", failed.join("; "))))
+    }
+}
+
+fn check_chacha() -> Result<()> {
+    let cipher = ChaChaEncryptor::new(&array(KEY));
+    check_aead(
+        |plaintext| cipher.encrypt(plaintext, &array(NONCE)),
+        |ciphertext| cipher.decrypt(ciphertext, &array(NONCE)),
+        PLAINTEXT,
+        CHACHA_CIPHERTEXT,
+    )
+}
+
+fn check_aes() -> Result<()> {
+    let cipher = AesEncryptor::new(&array(KEY));
+    check_aead(
+        |plaintext| cipher.encrypt(plaintext, &array(NONCE)),
+        |ciphertext| cipher.decrypt(ciphertext, &array(NONCE)),
+        PLAINTEXT,
+        AES_CIPHERTEXT,
+    )
+}
+
+fn check_hse() -> Result<()> {
+    let cipher = HSEEncryptor::new(&[1u8; 32], &[2u8; 32]);
+    check_aead(
+        |plaintext| cipher.encrypt(plaintext, &[0u8; 12]),
+        |ciphertext| cipher.decrypt(ciphertext, &[0u8; 12]),
+        HSE_PLAINTEXT,
+        HSE_CIPHERTEXT,
+    )
+}
+
+/// Encryption gives the known ciphertext, which decrypts back and stops
+/// decrypting once tampered with
+fn check_aead(
+    encrypt: impl Fn(&[u8]) -> Result<Vec<u8>>,
+    decrypt: impl Fn(&[u8]) -> Result<Vec<u8>>,
+    plaintext: &str,
+    ciphertext: &str,
+) -> Result<()> {
+    let (plaintext, ciphertext) = (bytes(plaintext), bytes(ciphertext));
+    expect("ciphertext", &encrypt(&plaintext)?, &ciphertext)?;
+    expect("plaintext", &decrypt(&ciphertext)?, &plaintext)?;
+
+    let mut tampered = ciphertext;
+    tampered[0] ^= 1;
+    if decrypt(&tampered).is_ok() {
+        return Err(LostLoveError::Crypto("tampered ciphertext was accepted".to_string()));
+    }
+    Ok(())
+}
+
+fn check_hkdf() -> Result<()> {
+    let okm = derive_keys(b"test_secret", b"test_salt", b"test_info", 32)?;
+    expect("output", &okm, &bytes(HKDF_OKM))
+}
+
+fn check_session_keys() -> Result<()> {
+    let keys = derive_session_keys(&bytes(SHARED_SECRET), &[1u8; 32], &[2u8; 32])?;
+    expect("master secret", &keys.master_secret[..], &bytes(MASTER_SECRET))?;
+    expect("chacha key", &keys.chacha_key[..], &bytes(SESSION_CHACHA_KEY))?;
+
+    let base = derive_nonce_base(&keys.master_secret[..], CLIENT_NONCE_INFO)?;
+    expect("client nonce base", &base, &bytes(CLIENT_NONCE_BASE))
+}
+
+fn check_packet_nonce() -> Result<()> {
+    let nonce = packet_nonce(&[0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab], u64::MAX);
+    expect("nonce", &nonce, &bytes("a0a1a2a35b5a595857565554"))
+}
+
+fn expect(what: &str, actual: &[u8], expected: &[u8]) -> Result<()> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(LostLoveError::Crypto(format!(
+            "wrong {}: got {}, expected {}",
+            what,
+            hex::encode(actual),
+            hex::encode(expected)
+        )))
+    }
+}
+
+fn bytes(data: &str) -> Vec<u8> {
+    hex::decode(data).expect("self-test vectors are hex")
+}
+
+fn array<const N: usize>(data: &str) -> [u8; N] {
+    bytes(data).try_into().expect("self-test vector has the right length")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        let checks = run();
+        assert_eq!(checks.len(), 6);
+        assert!(checks.iter().all(|check| check.error.is_none()), "{:?}", checks);
+        assert!(selftest().is_ok());
+    }
+
+    #[test]
+    fn test_broken_cipher_detected() {
+        let cipher = ChaChaEncryptor::new(&array(KEY));
+        // Stands in for a backend producing wrong output
+        let result = check_aead(
+            |plaintext| cipher.encrypt(&plaintext[1..], &array(NONCE)),
+            |ciphertext| cipher.decrypt(ciphertext, &array(NONCE)),
+            PLAINTEXT,
+            CHACHA_CIPHERTEXT,
+        );
+        assert!(result.unwrap_err().to_string().contains("wrong ciphertext"));
+
+        // And one that accepts anything
+        let result = check_aead(
+            |plaintext| cipher.encrypt(plaintext, &array(NONCE)),
+            |_| Ok(bytes(PLAINTEXT)),
+            PLAINTEXT,
+            CHACHA_CIPHERTEXT,
+        );
+        assert!(result.unwrap_err().to_string().contains("tampered"));
+    }
+}
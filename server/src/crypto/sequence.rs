@@ -0,0 +1,206 @@
+use crate::error::{LostLoveError, Result};
+use crate::protocol::PacketHeader;
+
+/// Reserved: never sent, so a used-up space stays used up
+const EXHAUSTED: u64 = u64::MAX;
+
+/// Packets behind the highest one received that may still arrive
+const REPLAY_WINDOW: u64 = 64;
+
+/// Sequence numbers of a session, one space per key epoch and direction
+///
+/// Nonces are derived from the sequence number, so a number may be used
+/// once per key. Every key epoch starts a fresh space at 0 in each
+/// direction, and the key phase bit of a packet says which epoch's space
+/// its number belongs to. Numbers never wrap: once a space is used up the
+/// sender refuses to send until the keys rotate, which they do by age long
+/// before that. The receiver keeps the current epoch's space and the
+/// previous one for packets still in flight, each with a window against
+/// replays and packets too far behind.
+#[derive(Debug, Clone)]
+pub struct SequenceSpaces {
+    epoch: u64,
+    next_send: u64,
+    current: ReceiveWindow,
+    previous: Option<ReceiveWindow>,
+}
+
+impl SequenceSpaces {
+    /// Spaces of key `epoch`, 0 for the handshake keys
+    pub fn new(epoch: u64) -> Self {
+        Self {
+            epoch,
+            next_send: 0,
+            current: ReceiveWindow::new(epoch),
+            previous: None,
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Move to the spaces of a later key `epoch`, starting again at 0
+    pub fn rekey(&mut self, epoch: u64) -> Result<()> {
+        if epoch <= self.epoch {
+            return Err(LostLoveError::Crypto(format!(
+                "Key epoch can't go back from {} to {}",
+                self.epoch, epoch
+            )));
+        }
+        let current = std::mem::replace(&mut self.current, ReceiveWindow::new(epoch));
+        // Packets of older epochs share a phase bit with the new one
+        self.previous = (current.epoch + 1 == epoch).then_some(current);
+        self.epoch = epoch;
+        self.next_send = 0;
+        Ok(())
+    }
+
+    /// Number for the next packet sent, or an error until the keys rotate
+    pub fn next_send(&mut self) -> Result<u64> {
+        if self.next_send == EXHAUSTED {
+            return Err(LostLoveError::SequenceExhausted { epoch: self.epoch });
+        }
+        let sequence = self.next_send;
+        self.next_send += 1;
+        Ok(sequence)
+    }
+
+    /// Accept a received packet's number once, returning its key epoch
+    pub fn check_received(&mut self, header: &PacketHeader) -> Result<u64> {
+        let sequence = header.sequence_number;
+        let window = if header.key_phase() == (self.epoch & 1 == 1) {
+            &mut self.current
+        } else {
+            self.previous.as_mut().ok_or(LostLoveError::InvalidSequence(sequence))?
+        };
+        window.accept(sequence)?;
+        Ok(window.epoch)
+    }
+}
+
+/// Numbers received in one key epoch: the highest and a bitmap behind it
+#[derive(Debug, Clone)]
+struct ReceiveWindow {
+    epoch: u64,
+    highest: Option<u64>,
+    /// Bit `n` set if `highest - n` was received
+    seen: u64,
+}
+
+impl ReceiveWindow {
+    fn new(epoch: u64) -> Self {
+        Self {
+            epoch,
+            highest: None,
+            seen: 0,
+        }
+    }
+
+    fn accept(&mut self, sequence: u64) -> Result<()> {
+        if sequence == EXHAUSTED {
+            return Err(LostLoveError::InvalidSequence(sequence));
+        }
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.seen = 1;
+            return Ok(());
+        };
+
+        if sequence > highest {
+            let ahead = sequence - highest;
+            self.seen = if ahead >= REPLAY_WINDOW { 0 } else { self.seen << ahead };
+            self.seen |= 1;
+            self.highest = Some(sequence);
+            return Ok(());
+        }
+
+        let behind = highest - sequence;
+        if behind >= REPLAY_WINDOW || self.seen & (1 << behind) != 0 {
+            return Err(LostLoveError::InvalidSequence(sequence));
+        }
+        self.seen |= 1 << behind;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PacketType;
+
+    fn header(epoch: u64, sequence_number: u64) -> PacketHeader {
+        let mut header = PacketHeader::new(PacketType::Data);
+        header.sequence_number = sequence_number;
+        header.set_key_epoch(epoch);
+        header
+    }
+
+    #[test]
+    fn test_send_space_resets_on_rekey() {
+        let mut spaces = SequenceSpaces::new(0);
+        assert_eq!(spaces.next_send().unwrap(), 0);
+        assert_eq!(spaces.next_send().unwrap(), 1);
+
+        spaces.rekey(1).unwrap();
+        assert_eq!(spaces.epoch(), 1);
+        assert_eq!(spaces.next_send().unwrap(), 0);
+        assert!(spaces.rekey(1).is_err());
+        assert!(spaces.rekey(0).is_err());
+    }
+
+    #[test]
+    fn test_exhausted_space_stays_exhausted() {
+        let mut spaces = SequenceSpaces::new(3);
+        spaces.next_send = EXHAUSTED - 1;
+        assert_eq!(spaces.next_send().unwrap(), EXHAUSTED - 1);
+        for _ in 0..2 {
+            let err = spaces.next_send().unwrap_err();
+            assert_eq!(err.code(), "sequence_exhausted");
+            assert!(matches!(err, LostLoveError::SequenceExhausted { epoch: 3 }));
+        }
+
+        // Only new keys give new numbers
+        spaces.rekey(4).unwrap();
+        assert_eq!(spaces.next_send().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_replays_and_stale_packets_refused() {
+        let mut spaces = SequenceSpaces::new(0);
+        for sequence in [5, 3, 4, 100] {
+            assert_eq!(spaces.check_received(&header(0, sequence)).unwrap(), 0);
+        }
+        assert!(spaces.check_received(&header(0, 100)).is_err());
+        assert!(spaces.check_received(&header(0, 5)).is_err());
+        // In the window and not seen yet
+        spaces.check_received(&header(0, 37)).unwrap();
+        assert!(spaces.check_received(&header(0, 36)).is_err());
+        assert!(spaces.check_received(&header(0, EXHAUSTED)).is_err());
+    }
+
+    #[test]
+    fn test_received_numbers_scoped_to_epoch() {
+        let mut spaces = SequenceSpaces::new(0);
+        // No previous epoch to take the other phase yet
+        assert!(spaces.check_received(&header(1, 0)).is_err());
+        spaces.check_received(&header(0, 7)).unwrap();
+
+        spaces.rekey(1).unwrap();
+        // The same number again, under the new keys
+        assert_eq!(spaces.check_received(&header(1, 7)).unwrap(), 1);
+        // Late packets of the old epoch still count against its own space
+        assert_eq!(spaces.check_received(&header(0, 8)).unwrap(), 0);
+        assert!(spaces.check_received(&header(0, 7)).is_err());
+
+        // Two epochs on, epoch 1's phase belongs to epoch 3
+        spaces.rekey(2).unwrap();
+        spaces.rekey(3).unwrap();
+        assert_eq!(spaces.check_received(&header(3, 7)).unwrap(), 3);
+        assert_eq!(spaces.check_received(&header(2, 0)).unwrap(), 2);
+
+        // Skipping an epoch leaves nothing in flight to accept
+        spaces.rekey(5).unwrap();
+        assert!(spaces.check_received(&header(4, 1)).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use zeroize::Zeroizing;
+
+use crate::error::{LostLoveError, Result};
+
+/// Config values of the form `tpm:<path>` name a sealed secret file
+pub const SEALED_PREFIX: &str = "tpm:";
+
+/// PCRs a secret is bound to unless chosen otherwise: firmware and Secure Boot state
+pub const DEFAULT_PCRS: &str = "sha256:0,7";
+
+/// Path of the sealed file if `value` refers to one
+pub fn sealed_path(value: &str) -> Option<&Path> {
+    value.strip_prefix(SEALED_PREFIX).map(Path::new)
+}
+
+/// A secret sealed to this machine's TPM 2.0 under a PCR policy
+///
+/// The key pair blobs are encrypted by the TPM's storage key and only load
+/// on the TPM that created them, and the secret only unseals while the PCRs
+/// hold the values they had when sealing. A copied disk image is useless
+/// elsewhere, and so is a tampered boot chain. Uses `tpm2-tools`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedSecret {
+    /// PCR selection of the policy, e.g. `sha256:0,7`
+    pub pcrs: String,
+    /// TPM2B_PUBLIC of the sealed object, hex
+    public: String,
+    /// TPM2B_PRIVATE of the sealed object, hex
+    private: String,
+}
+
+impl SealedSecret {
+    /// Seal `secret` under the current values of `pcrs`
+    pub fn seal(secret: &[u8], pcrs: &str) -> Result<Self> {
+        validate_pcrs(pcrs)?;
+        let workdir = Workdir::new()?;
+
+        for step in seal_steps(&workdir.0, pcrs) {
+            let input = (step.program == "tpm2_create").then_some(secret);
+            step.run(input)?;
+        }
+
+        Ok(Self {
+            pcrs: pcrs.to_string(),
+            public: hex::encode(std::fs::read(workdir.0.join("seal.pub"))?),
+            private: hex::encode(std::fs::read(workdir.0.join("seal.priv"))?),
+        })
+    }
+
+    /// Recover the secret, fails unless the PCRs still match
+    pub fn unseal(&self) -> Result<Zeroizing<Vec<u8>>> {
+        validate_pcrs(&self.pcrs)?;
+        let workdir = Workdir::new()?;
+
+        let blob = |data: &str| {
+            hex::decode(data).map_err(|e| LostLoveError::Crypto(format!("Corrupt sealed secret: {}", e)))
+        };
+        std::fs::write(workdir.0.join("seal.pub"), blob(&self.public)?)?;
+        std::fs::write(workdir.0.join("seal.priv"), blob(&self.private)?)?;
+
+        let steps = unseal_steps(&workdir.0, &self.pcrs);
+        let (unseal, load) = steps.split_last().expect("unseal has steps");
+        for step in load {
+            step.run(None)?;
+        }
+        Ok(Zeroizing::new(unseal.run(None)?))
+    }
+
+    /// Read a sealed secret file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let data = std::fs::read(path.as_ref())?;
+        serde_json::from_slice(&data).map_err(|e| {
+            LostLoveError::Crypto(format!("Invalid sealed secret {}: {}", path.as_ref().display(), e))
+        })
+    }
+
+    /// Write as a sealed secret file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| LostLoveError::Crypto(format!("Serialization error: {}", e)))?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+}
+
+/// Check a PCR selection such as `sha256:0,7`
+pub fn validate_pcrs(pcrs: &str) -> Result<()> {
+    let invalid = || LostLoveError::Crypto(format!("Invalid PCR selection {:?}, expected e.g. sha256:0,7", pcrs));
+
+    let (bank, indexes) = pcrs.split_once(':').ok_or_else(invalid)?;
+    if !matches!(bank, "sha1" | "sha256" | "sha384") {
+        return Err(invalid());
+    }
+    for index in indexes.split(',') {
+        match index.parse::<u8>() {
+            Ok(index) if index < 24 => {}
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(())
+}
+
+/// One `tpm2-tools` invocation
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TpmCommand {
+    program: &'static str,
+    args: Vec<String>,
+}
+
+impl TpmCommand {
+    fn new(program: &'static str, args: &[&str]) -> Self {
+        Self {
+            program,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    /// Run with `input` on stdin, returns stdout
+    fn run(&self, input: Option<&[u8]>) -> Result<Vec<u8>> {
+        let mut child = Command::new(self.program)
+            .args(&self.args)
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| LostLoveError::Crypto(format!("Failed to run {} (is tpm2-tools installed?): {}", self.program, e)))?;
+
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input)?;
+        }
+
+        let output = child.wait_with_output()?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(LostLoveError::Crypto(format!(
+                "{} failed: {}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+/// Primary key under the owner hierarchy, recreated identically every time
+fn create_primary(dir: &Path) -> TpmCommand {
+    TpmCommand::new("tpm2_createprimary", &["-C", "o", "-c", &path(dir, "primary.ctx")])
+}
+
+fn seal_steps(dir: &Path, pcrs: &str) -> Vec<TpmCommand> {
+    vec![
+        create_primary(dir),
+        TpmCommand::new("tpm2_pcrread", &["-o", &path(dir, "pcrs.bin"), pcrs]),
+        TpmCommand::new(
+            "tpm2_createpolicy",
+            &["--policy-pcr", "-l", pcrs, "-f", &path(dir, "pcrs.bin"), "-L", &path(dir, "policy.digest")],
+        ),
+        TpmCommand::new(
+            "tpm2_create",
+            &[
+                "-C", &path(dir, "primary.ctx"),
+                "-L", &path(dir, "policy.digest"),
+                "-i", "-",
+                "-u", &path(dir, "seal.pub"),
+                "-r", &path(dir, "seal.priv"),
+            ],
+        ),
+    ]
+}
+
+fn unseal_steps(dir: &Path, pcrs: &str) -> Vec<TpmCommand> {
+    vec![
+        create_primary(dir),
+        TpmCommand::new(
+            "tpm2_load",
+            &[
+                "-C", &path(dir, "primary.ctx"),
+                "-u", &path(dir, "seal.pub"),
+                "-r", &path(dir, "seal.priv"),
+                "-c", &path(dir, "seal.ctx"),
+            ],
+        ),
+        TpmCommand::new(
+            "tpm2_unseal",
+            &["-c", &path(dir, "seal.ctx"), "-p", &format!("pcr:{}", pcrs)],
+        ),
+    ]
+}
+
+fn path(dir: &Path, name: &str) -> String {
+    dir.join(name).display().to_string()
+}
+
+/// Private scratch directory for the TPM object files, removed on drop
+struct Workdir(PathBuf);
+
+impl Workdir {
+    fn new() -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "llp-tpm-{}-{}",
+            std::process::id(),
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for Workdir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_path() {
+        assert_eq!(sealed_path("tpm:/etc/lostlove/knock.sealed"), Some(Path::new("/etc/lostlove/knock.sealed")));
+        assert_eq!(sealed_path("correct horse"), None);
+    }
+
+    #[test]
+    fn test_validate_pcrs() {
+        assert!(validate_pcrs(DEFAULT_PCRS).is_ok());
+        assert!(validate_pcrs("sha1:23").is_ok());
+        assert!(validate_pcrs("sha256:24").is_err());
+        assert!(validate_pcrs("md5:0").is_err());
+        assert!(validate_pcrs("sha256:").is_err());
+        assert!(validate_pcrs("0,7").is_err());
+    }
+
+    #[test]
+    fn test_steps() {
+        let dir = Path::new("/tmp/w");
+
+        let seal = seal_steps(dir, "sha256:0,7");
+        let programs: Vec<_> = seal.iter().map(|step| step.program).collect();
+        assert_eq!(programs, ["tpm2_createprimary", "tpm2_pcrread", "tpm2_createpolicy", "tpm2_create"]);
+        assert_eq!(seal[1].args, ["-o", "/tmp/w/pcrs.bin", "sha256:0,7"]);
+        assert!(seal[3].args.windows(2).any(|pair| pair == ["-i", "-"]));
+
+        let unseal = unseal_steps(dir, "sha256:0,7");
+        assert_eq!(unseal[0], seal[0]);
+        assert_eq!(unseal[2].args, ["-c", "/tmp/w/seal.ctx", "-p", "pcr:sha256:0,7"]);
+    }
+
+    #[test]
+    fn test_sealed_file_roundtrip() {
+        let sealed = SealedSecret {
+            pcrs: DEFAULT_PCRS.to_string(),
+            public: "0001".to_string(),
+            private: "0203".to_string(),
+        };
+        let path = std::env::temp_dir().join(format!("llp-sealed-{}.json", std::process::id()));
+
+        sealed.save(&path).unwrap();
+        assert_eq!(SealedSecret::load(&path).unwrap(), sealed);
+
+        std::fs::write(&path, b"not json").unwrap();
+        assert!(SealedSecret::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
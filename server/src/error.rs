@@ -1,5 +1,25 @@
+use std::fmt;
 use thiserror::Error;
 
+use crate::core::abuse::Abuse;
+use crate::protocol::handshake::HandshakeFailureCategory;
+
+/// Direction of a failed AEAD operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoOp {
+    Encrypt,
+    Decrypt,
+}
+
+impl fmt::Display for CryptoOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoOp::Encrypt => write!(f, "encryption"),
+            CryptoOp::Decrypt => write!(f, "decryption"),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum LostLoveError {
     #[error("IO error: {0}")]
@@ -11,15 +31,24 @@ pub enum LostLoveError {
     #[error("Invalid packet type: {0}")]
     InvalidPacketType(u8),
 
+    #[error("No handler for packet type {0:#04x}")]
+    UnsupportedPacketType(u8),
+
     #[error("Insufficient data: expected {expected}, got {actual}")]
     InsufficientData { expected: usize, actual: usize },
 
     #[error("Checksum mismatch: expected {expected:04x}, got {actual:04x}")]
     ChecksumMismatch { expected: u16, actual: u16 },
 
+    #[error("Header declares a {length} byte payload, got {actual}")]
+    LengthMismatch { length: usize, actual: usize },
+
     #[error("Invalid sequence number: {0}")]
     InvalidSequence(u64),
 
+    #[error("Sequence numbers of key epoch {epoch} are used up, keys must rotate first")]
+    SequenceExhausted { epoch: u64 },
+
     #[error("Timestamp too old: {0}")]
     TimestampTooOld(u64),
 
@@ -38,8 +67,141 @@ pub enum LostLoveError {
     #[error("Network error: {0}")]
     Network(String),
 
+    #[error("Netlink {request} failed: {}", std::io::Error::from_raw_os_error(*errno))]
+    Netlink { request: String, errno: i32 },
+
+    #[error("Preflight checks failed: {0}")]
+    Preflight(String),
+
     #[error("Handshake failed: {0}")]
     HandshakeFailed(String),
+
+    #[error("Handshake rejected ({category:?}): {reason}")]
+    HandshakeRejected {
+        category: HandshakeFailureCategory,
+        reason: String,
+    },
+
+    #[error("Malformed inner packet: {0}")]
+    MalformedInnerPacket(String),
+
+    #[error("Admin error: {0}")]
+    Admin(String),
+
+    #[error("Invalid label: {0}")]
+    InvalidLabel(String),
+
+    #[error("Invalid CIDR {cidr:?}: {reason}")]
+    InvalidCidr { cidr: String, reason: &'static str },
+
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+
+    #[error("{cipher} {op} failed")]
+    AeadFailure { cipher: &'static str, op: CryptoOp },
+
+    #[error("Key derivation failed: {0}")]
+    KeyDerivation(String),
+
+    #[error("Authentication failed: {reason}")]
+    AuthFailed { reason: String },
+
+    #[error("Rate limited")]
+    RateLimited,
+
+    #[error("Packet dropped by firewall: {0}")]
+    Filtered(&'static str),
+
+    #[error("Packet of {size} bytes exceeds path MTU {mtu}")]
+    PacketTooBig { size: usize, mtu: usize },
+
+    #[error("Source address {actual} does not match leased address {leased}")]
+    SourceAddressViolation {
+        leased: std::net::IpAddr,
+        actual: std::net::IpAddr,
+    },
+
+    #[error("{event} hook failed: {reason}")]
+    Hook { event: &'static str, reason: String },
+
+    #[error("Packet of {size} bytes exceeds the {limit} byte limit")]
+    PacketTooLarge { size: usize, limit: usize },
+
+    #[error("Client sent no complete {} within {seconds}s", .abuse.unfinished())]
+    SlowClient { abuse: Abuse, seconds: u64 },
+
+    #[error("Another instance holds {path}: {holder}")]
+    InstanceLocked { path: String, holder: String },
+
+    #[error("File transfer {id} failed: {reason}")]
+    FileTransfer { id: u64, reason: String },
+}
+
+impl LostLoveError {
+    /// Stable machine-readable code, used as the admin API and metrics label
+    pub fn code(&self) -> &'static str {
+        match self {
+            LostLoveError::Io(_) => "io",
+            LostLoveError::InvalidProtocolId(_) => "invalid_protocol_id",
+            LostLoveError::InvalidPacketType(_) => "invalid_packet_type",
+            LostLoveError::UnsupportedPacketType(_) => "unsupported_packet_type",
+            LostLoveError::InsufficientData { .. } => "insufficient_data",
+            LostLoveError::ChecksumMismatch { .. } => "checksum_mismatch",
+            LostLoveError::LengthMismatch { .. } => "length_mismatch",
+            LostLoveError::InvalidSequence(_) => "invalid_sequence",
+            LostLoveError::SequenceExhausted { .. } => "sequence_exhausted",
+            LostLoveError::TimestampTooOld(_) => "timestamp_too_old",
+            LostLoveError::Connection(_) => "connection",
+            LostLoveError::TooManyConnections => "too_many_connections",
+            LostLoveError::SessionNotFound(_) => "session_not_found",
+            LostLoveError::Config(_) => "config",
+            LostLoveError::Network(_) => "network",
+            LostLoveError::Netlink { .. } => "netlink",
+            LostLoveError::Preflight(_) => "preflight",
+            LostLoveError::HandshakeFailed(_) => "handshake_failed",
+            LostLoveError::HandshakeRejected { .. } => "handshake_rejected",
+            LostLoveError::MalformedInnerPacket(_) => "malformed_inner_packet",
+            LostLoveError::Admin(_) => "admin",
+            LostLoveError::InvalidLabel(_) => "invalid_label",
+            LostLoveError::InvalidCidr { .. } => "invalid_cidr",
+            LostLoveError::Crypto(_) => "crypto",
+            LostLoveError::AeadFailure { .. } => "aead_failure",
+            LostLoveError::KeyDerivation(_) => "key_derivation",
+            LostLoveError::AuthFailed { .. } => "auth_failed",
+            LostLoveError::RateLimited => "rate_limited",
+            LostLoveError::Filtered(_) => "filtered",
+            LostLoveError::SourceAddressViolation { .. } => "source_address_violation",
+            LostLoveError::PacketTooBig { .. } => "packet_too_big",
+            LostLoveError::Hook { .. } => "hook",
+            LostLoveError::PacketTooLarge { .. } => "packet_too_large",
+            LostLoveError::SlowClient { .. } => "slow_client",
+            LostLoveError::InstanceLocked { .. } => "instance_locked",
+            LostLoveError::FileTransfer { .. } => "file_transfer",
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, LostLoveError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        let err = LostLoveError::AeadFailure {
+            cipher: "AES-256-GCM",
+            op: CryptoOp::Decrypt,
+        };
+        assert_eq!(err.code(), "aead_failure");
+        assert_eq!(err.to_string(), "AES-256-GCM decryption failed");
+
+        let err = LostLoveError::AuthFailed {
+            reason: "bad PSK".to_string(),
+        };
+        assert_eq!(err.code(), "auth_failed");
+        assert_eq!(err.to_string(), "Authentication failed: bad PSK");
+
+        assert_eq!(LostLoveError::RateLimited.code(), "rate_limited");
+    }
+}
@@ -0,0 +1,127 @@
+pub mod rotation;
+
+use std::sync::Mutex;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+use crate::config::LogFileConfig;
+use crate::error::{LostLoveError, Result};
+use crate::logging::rotation::RotatingFile;
+
+/// Handle to the reloadable filter layer
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Runtime control over the active log filter
+///
+/// Directives use the `EnvFilter` syntax: a bare level (`debug`) or
+/// comma-separated `target=level` pairs (`info,lostlove_server::crypto=trace`).
+pub struct LogControl {
+    handle: FilterHandle,
+    current: Mutex<String>,
+    /// Keeps the background file writer alive (flushes on drop)
+    _file_guard: Option<WorkerGuard>,
+}
+
+impl LogControl {
+    fn new(handle: FilterHandle, directives: &str, file_guard: Option<WorkerGuard>) -> Self {
+        Self {
+            handle,
+            current: Mutex::new(directives.to_string()),
+            _file_guard: file_guard,
+        }
+    }
+
+    /// Get the currently active directives
+    pub fn current(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Replace the active filter
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(directives)
+            .map_err(|e| LostLoveError::Config(format!("Invalid log directives '{}': {}", directives, e)))?;
+
+        self.handle
+            .reload(filter)
+            .map_err(|e| LostLoveError::Admin(format!("Failed to reload log filter: {}", e)))?;
+
+        *self.current.lock().unwrap() = directives.to_string();
+        Ok(())
+    }
+}
+
+/// Install the global subscriber and return its filter control
+///
+/// Logs always go to stdout, as text or one JSON object per line; with
+/// `log_file` set they are also written to a size-rotated file from a
+/// background thread.
+pub fn init(directives: &str, json: bool, log_file: Option<&LogFileConfig>) -> Result<LogControl> {
+    let (directives, filter) = match EnvFilter::try_new(directives) {
+        Ok(filter) => (directives, filter),
+        Err(_) => ("info", EnvFilter::new("info")),
+    };
+
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let (file_layer, file_guard) = match log_file {
+        Some(config) => {
+            let file = RotatingFile::open(
+                &config.path,
+                config.max_size_mb * 1024 * 1024,
+                config.max_files,
+                config.compress,
+            )?;
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            let layer = fmt::layer()
+                .with_ansi(false)
+                .with_thread_ids(true)
+                .with_writer(writer);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let (text_layer, json_layer) = if json {
+        (None, Some(fmt::layer().json().with_current_span(false).with_span_list(false)))
+    } else {
+        (Some(fmt::layer().with_target(false).with_thread_ids(true)), None)
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text_layer)
+        .with(json_layer)
+        .with(file_layer)
+        .init();
+
+    Ok(LogControl::new(handle, directives, file_guard))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_directives() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _subscriber = Registry::default().with(filter);
+        let control = LogControl::new(handle, "info", None);
+
+        assert_eq!(control.current(), "info");
+
+        control.set("debug,lostlove_server::crypto=trace").unwrap();
+        assert_eq!(control.current(), "debug,lostlove_server::crypto=trace");
+    }
+
+    #[test]
+    fn test_invalid_directives_rejected() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _subscriber = Registry::default().with(filter);
+        let control = LogControl::new(handle, "info", None);
+
+        assert!(control.set("lostlove_server=notalevel").is_err());
+        assert_eq!(control.current(), "info");
+    }
+}
@@ -0,0 +1,177 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Log file that rotates once it grows past a size limit
+///
+/// Rotated files are named `<path>.1` (newest) up to `<path>.<max_files>`
+/// (oldest), with a `.gz` suffix when compression is enabled. Anything older
+/// than `max_files` is deleted.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    compress: bool,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Open (or create) the log file for appending
+    pub fn open(path: impl Into<PathBuf>, max_size: u64, max_files: usize, compress: bool) -> io::Result<Self> {
+        let path = path.into();
+
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            compress,
+            file,
+            size,
+        })
+    }
+
+    /// Path of the n-th rotated file
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let suffix = if self.compress { ".gz" } else { "" };
+        PathBuf::from(format!("{}.{}{}", self.path.display(), index, suffix))
+    }
+
+    /// Shift rotated files, move the active file to `.1` and start a fresh one
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            // No retention: just truncate
+            self.file = File::create(&self.path)?;
+            self.size = 0;
+            return Ok(());
+        }
+
+        let oldest = self.rotated_path(self.max_files);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+
+        if self.compress {
+            compress_file(&self.path, &self.rotated_path(1))?;
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Gzip `source` into `destination`
+fn compress_file(source: &Path, destination: &Path) -> io::Result<()> {
+    let mut input = File::open(source)?;
+    let mut encoder = GzEncoder::new(File::create(destination)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("llp-log-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_rotation_and_retention() {
+        let dir = test_dir("plain");
+        let path = dir.join("server.log");
+        let mut log = RotatingFile::open(&path, 10, 2, false).unwrap();
+
+        for line in ["aaaaaaaa\n", "bbbbbbbb\n", "cccccccc\n", "dddddddd\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        log.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddddd\n");
+        assert_eq!(fs::read_to_string(dir.join("server.log.1")).unwrap(), "cccccccc\n");
+        assert_eq!(fs::read_to_string(dir.join("server.log.2")).unwrap(), "bbbbbbbb\n");
+        assert!(!dir.join("server.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_rotation() {
+        let dir = test_dir("gz");
+        let path = dir.join("server.log");
+        let mut log = RotatingFile::open(&path, 10, 3, true).unwrap();
+
+        log.write_all(b"first line\n").unwrap();
+        log.write_all(b"second\n").unwrap();
+        log.flush().unwrap();
+
+        let mut decoded = String::new();
+        GzDecoder::new(File::open(dir.join("server.log.1.gz")).unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+
+        assert_eq!(decoded, "first line\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_existing_file_size_counts() {
+        let dir = test_dir("existing");
+        let path = dir.join("server.log");
+        fs::write(&path, "0123456789").unwrap();
+
+        let mut log = RotatingFile::open(&path, 10, 1, false).unwrap();
+        log.write_all(b"new\n").unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("server.log.1")).unwrap(), "0123456789");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
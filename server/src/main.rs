@@ -1,16 +1,35 @@
 use anyhow::Result;
-use clap::Parser;
-use tracing::{info, error};
-use tracing_subscriber;
+use clap::{Parser, ValueEnum};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, error, warn};
+use zeroize::Zeroizing;
 
 mod protocol;
+mod crypto;
 mod core;
 mod network;
 mod config;
 mod error;
+mod admin;
+mod metrics;
+mod logging;
 
+use crate::core::{runtime, InstanceLock, Preflight};
 use crate::core::server::Server;
 use crate::config::Config;
+use crate::crypto::{memlock, KeyLog, SealedSecret};
+
+/// How the server is deployed
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Config file, TUN device and host checks
+    Host,
+    /// Unprivileged 12-factor container: config from LLP_* variables only,
+    /// JSON logs on stdout, /healthz and /readyz, no TUN device
+    Container,
+}
 
 /// LostLove Protocol VPN Server
 #[derive(Parser, Debug)]
@@ -20,40 +39,121 @@ struct Args {
     #[arg(short, long, default_value = "/etc/lostlove/server.toml")]
     config: String,
 
+    /// Deployment mode; container ignores --config
+    #[arg(long, value_enum, default_value = "host")]
+    mode: Mode,
+
     /// Check configuration and exit
     #[arg(long)]
     check_config: bool,
 
-    /// Log level (trace, debug, info, warn, error)
+    /// Log level (trace, debug, info, warn, error) or filter directives
     #[arg(short, long, default_value = "info")]
     log_level: String,
+
+    /// Honor LLP_KEYLOG_FILE in release builds (writes session secrets!)
+    #[arg(long)]
+    allow_keylog: bool,
+
+    /// Start even if preflight checks fail (they are still logged)
+    #[arg(long)]
+    ignore_preflight: bool,
+
+    /// Seal a secret read from stdin to this machine's TPM into FILE and exit
+    #[arg(long, value_name = "FILE")]
+    seal: Option<PathBuf>,
+
+    /// PCRs a sealed secret is bound to
+    #[arg(long, default_value = crypto::tpm::DEFAULT_PCRS)]
+    seal_pcrs: String,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Initialize logging
-    let log_level = args.log_level.parse().unwrap_or(tracing::Level::INFO);
-    tracing_subscriber::fmt()
-        .with_max_level(log_level)
-        .with_target(false)
-        .with_thread_ids(true)
-        .init();
+    // Sealing runs before the config is loaded, it may be what the config needs
+    if let Some(path) = &args.seal {
+        return seal_secret(path, &args.seal_pcrs);
+    }
+
+    // Load configuration (file logging settings and the runtime shape live in it)
+    let config = match args.mode {
+        Mode::Host => Config::load(&args.config)?,
+        Mode::Container => Config::from_env()?,
+    };
 
-    info!("LostLove Server v{}", env!("CARGO_PKG_VERSION"));
-    info!("Loading configuration from: {}", args.config);
+    runtime::build(&config.server)?.block_on(serve(args, config))
+}
+
+async fn serve(args: Args, config: Config) -> Result<()> {
+    // Initialize logging (filter can be changed at runtime via llpctl)
+    let log_control = Arc::new(logging::init(
+        &args.log_level,
+        config.monitoring.log_format == "json",
+        config.monitoring.log_file.as_ref(),
+    )?);
 
-    // Load configuration
-    let config = Config::load(&args.config)?;
+    info!("LostLove Server v{}", env!("CARGO_PKG_VERSION"));
+    match args.mode {
+        Mode::Host => info!("Loaded configuration from: {}", args.config),
+        Mode::Container => info!("Loaded configuration from LLP_* environment variables"),
+    }
+    info!("Using a {}", runtime::describe(&config.server));
 
     if args.check_config {
         info!("Configuration is valid!");
         return Ok(());
     }
 
+    // A second instance would fight this one over the TUN device, pool and NAT rules
+    let _instance_lock = InstanceLock::acquire(&config, &args.config)?;
+
+    // Refuse to serve with a cipher or KDF that gives wrong answers
+    crypto::selftest()?;
+    info!("Crypto self-test passed");
+
+    // Keep key material out of crash dumps and swap on hardened hosts
+    if config.security.disable_core_dumps {
+        memlock::disable_core_dumps()?;
+        info!("Core dumps disabled");
+    }
+    if config.security.lock_key_memory {
+        // Current and previous keys plus the shared secret, a page each at worst
+        let needed = config.server.max_connections as u64 * 3 * memlock::page_size() as u64;
+        if let Some(limit) = memlock::lock_limit()?.filter(|limit| *limit < needed) {
+            warn!(
+                "RLIMIT_MEMLOCK of {} bytes can't lock the keys of {} sessions ({} bytes needed); keys beyond it may be swapped out",
+                limit, config.server.max_connections, needed
+            );
+        }
+    }
+
+    // Missing devices, capabilities or ports fail here instead of deep in a listener
+    let preflight = match args.mode {
+        Mode::Host => Preflight::run(&config),
+        Mode::Container => Preflight::run_in_container(&config),
+    };
+    preflight.log();
+    if args.ignore_preflight && preflight.is_fatal() {
+        warn!("Starting despite failed preflight checks (--ignore-preflight)");
+    } else {
+        preflight.into_result()?;
+    }
+
     // Create and start server
-    let server = Server::new(config).await?;
+    let mut server = Server::new(config)
+        .await?
+        .with_log_control(log_control);
+
+    // Containers are reconfigured by restarting them with new variables
+    if args.mode == Mode::Host {
+        server = server.with_config_path(&args.config);
+    }
+
+    // Session secrets for decrypting captures during protocol development
+    if let Some(keylog) = KeyLog::from_env(args.allow_keylog) {
+        server = server.with_keylog(keylog);
+    }
 
     info!("Starting server...");
 
@@ -65,3 +165,13 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Seal stdin for use as a `tpm:<path>` config value
+fn seal_secret(path: &Path, pcrs: &str) -> Result<()> {
+    let mut secret = Zeroizing::new(String::new());
+    std::io::stdin().read_to_string(&mut secret)?;
+
+    SealedSecret::seal(secret.trim_end().as_bytes(), pcrs)?.save(path)?;
+    println!("Sealed under PCRs {}, refer to it as \"tpm:{}\"", pcrs, path.display());
+    Ok(())
+}
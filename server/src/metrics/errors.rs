@@ -0,0 +1,60 @@
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::LostLoveError;
+
+/// Error counters keyed by `LostLoveError::code()`
+#[derive(Debug, Default)]
+pub struct ErrorCounters {
+    counts: DashMap<&'static str, AtomicU64>,
+}
+
+impl ErrorCounters {
+    /// Create empty counters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one occurrence of an error
+    pub fn record(&self, error: &LostLoveError) {
+        self.counts
+            .entry(error.code())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current counts, sorted by code
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.counts
+            .iter()
+            .map(|entry| (entry.key().to_string(), entry.value().load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CryptoOp;
+
+    #[test]
+    fn test_counts_by_code() {
+        let counters = ErrorCounters::new();
+
+        counters.record(&LostLoveError::RateLimited);
+        counters.record(&LostLoveError::AeadFailure {
+            cipher: "ChaCha20-Poly1305",
+            op: CryptoOp::Decrypt,
+        });
+        counters.record(&LostLoveError::AeadFailure {
+            cipher: "AES-256-GCM",
+            op: CryptoOp::Decrypt,
+        });
+
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.get("aead_failure"), Some(&2));
+        assert_eq!(snapshot.get("rate_limited"), Some(&1));
+        assert_eq!(snapshot.len(), 2);
+    }
+}
@@ -0,0 +1,928 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+use crate::config::MonitoringConfig;
+use crate::core::abuse::AbuseCounters;
+use crate::core::accept::AcceptFailures;
+use crate::core::drain::DrainController;
+use crate::core::connection::ConnectionManager;
+use crate::core::overload::Overload;
+use crate::core::portal::PortalReports;
+use crate::core::quality::QualityReports;
+use crate::core::memory::MemoryBudget;
+use crate::core::rekey::RekeyScheduler;
+use crate::core::signaling::Signaling;
+use crate::core::supervisor::Supervisor;
+use crate::error::Result;
+use crate::metrics::errors::ErrorCounters;
+use crate::metrics::histogram::Histogram;
+use crate::metrics::timings::Timings;
+use crate::metrics::writer::MetricsWriter;
+use crate::network::{DnsForwarder, Relay};
+#[cfg(target_os = "linux")]
+use crate::network::TunDevice;
+
+/// Maximum size of an HTTP request head we are willing to buffer
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Prometheus metrics endpoint
+pub struct MetricsExporter {
+    address: String,
+    connection_manager: Arc<ConnectionManager>,
+    error_counters: Arc<ErrorCounters>,
+    traffic_breakdown: bool,
+    traffic_top_ports: usize,
+    metric_labels: Vec<String>,
+    metric_label_values: usize,
+    relay: Option<Arc<Relay>>,
+    #[cfg(target_os = "linux")]
+    tun_device: Option<Arc<TunDevice>>,
+    signaling: Option<Arc<Signaling>>,
+    rekey: Option<Arc<RekeyScheduler>>,
+    overload: Option<Arc<Overload>>,
+    accept_failures: Option<Arc<AcceptFailures>>,
+    abuse: Option<Arc<AbuseCounters>>,
+    supervisor: Option<Arc<Supervisor>>,
+    sweep_durations: Option<Arc<Histogram>>,
+    timings: Option<Arc<Timings>>,
+    quality: Option<Arc<QualityReports>>,
+    portals: Option<Arc<PortalReports>>,
+    memory: Option<Arc<MemoryBudget>>,
+    drain: Option<Arc<DrainController>>,
+    dns_forwarder: Option<Arc<DnsForwarder>>,
+}
+
+/// Sessions and bytes of all sessions sharing one label value
+#[derive(Debug, Clone, Copy, Default)]
+struct LabelTotals {
+    sessions: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl MetricsExporter {
+    /// Create new exporter from monitoring config
+    pub fn new(
+        config: &MonitoringConfig,
+        connection_manager: Arc<ConnectionManager>,
+        error_counters: Arc<ErrorCounters>,
+    ) -> Self {
+        Self {
+            address: format!("{}:{}", config.metrics_address, config.metrics_port),
+            connection_manager,
+            error_counters,
+            traffic_breakdown: config.traffic_breakdown,
+            traffic_top_ports: config.traffic_top_ports,
+            metric_labels: config.metric_labels.clone(),
+            metric_label_values: config.metric_label_values,
+            relay: None,
+            #[cfg(target_os = "linux")]
+            tun_device: None,
+            signaling: None,
+            rekey: None,
+            overload: None,
+            accept_failures: None,
+            abuse: None,
+            supervisor: None,
+            sweep_durations: None,
+            timings: None,
+            quality: None,
+            portals: None,
+            memory: None,
+            drain: None,
+            dns_forwarder: None,
+        }
+    }
+
+    /// Export per-path counters of client-to-client relaying
+    pub fn with_relay(mut self, relay: Arc<Relay>) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    /// Export lifecycle events and per-queue counters of the TUN device
+    #[cfg(target_os = "linux")]
+    pub fn with_tun_device(mut self, tun_device: Arc<TunDevice>) -> Self {
+        self.tun_device = Some(tun_device);
+        self
+    }
+
+    /// Serve `/metrics`, `/healthz` and `/readyz` until the task is cancelled
+    pub async fn run(self) -> Result<()> {
+        let listener = TcpListener::bind(&self.address).await?;
+        let exporter = Arc::new(self);
+
+        info!("Metrics available at http://{}/metrics", exporter.address);
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let exporter = exporter.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = exporter.serve(stream).await {
+                            debug!("Metrics request from {} failed: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to accept metrics connection: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Answer a single HTTP request
+    async fn serve(&self, mut stream: TcpStream) -> Result<()> {
+        let mut request = Vec::with_capacity(1024);
+        let mut buf = [0u8; 1024];
+
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        let request_line = String::from_utf8_lossy(&request);
+        let path = request_line.split_whitespace().nth(1).unwrap_or("");
+
+        let response = match path {
+            "/metrics" => {
+                let body = self.render().await;
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
+            // Answering at all means the runtime is alive
+            "/healthz" => health_response("200 OK", "ok"),
+            "/readyz" => match self.not_ready() {
+                None => health_response("200 OK", "ready"),
+                Some(reason) => health_response("503 Service Unavailable", &reason),
+            },
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        };
+
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Why the server shouldn't get new clients right now, None if it should
+    fn not_ready(&self) -> Option<String> {
+        if self.drain.as_ref().is_some_and(|drain| drain.is_draining()) {
+            return Some("draining".to_string());
+        }
+        self.overload.as_ref().and_then(|overload| overload.check())
+    }
+
+    /// Report not ready while draining, for `/readyz`
+    pub fn with_drain(mut self, drain: Arc<DrainController>) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    /// Export hole punching counters
+    pub fn with_signaling(mut self, signaling: Arc<Signaling>) -> Self {
+        self.signaling = Some(signaling);
+        self
+    }
+
+    /// Export key rotation counters
+    pub fn with_rekey(mut self, rekey: Arc<RekeyScheduler>) -> Self {
+        self.rekey = Some(rekey);
+        self
+    }
+
+    /// Export load shedding counters
+    pub fn with_overload(mut self, overload: Arc<Overload>) -> Self {
+        self.overload = Some(overload);
+        self
+    }
+
+    /// Export failed accepts
+    pub fn with_accept_failures(mut self, accept_failures: Arc<AcceptFailures>) -> Self {
+        self.accept_failures = Some(accept_failures);
+        self
+    }
+
+    /// Export connections closed for abuse
+    pub fn with_abuse(mut self, abuse: Arc<AbuseCounters>) -> Self {
+        self.abuse = Some(abuse);
+        self
+    }
+
+    /// Export panics of connection handlers and listeners
+    pub fn with_supervisor(mut self, supervisor: Arc<Supervisor>) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    /// Export how long stale-connection sweeps take
+    pub fn with_sweep_durations(mut self, sweep_durations: Arc<Histogram>) -> Self {
+        self.sweep_durations = Some(sweep_durations);
+        self
+    }
+
+    /// Export latency histograms of handshakes, packets and crypto
+    pub fn with_timings(mut self, timings: Arc<Timings>) -> Self {
+        self.timings = Some(timings);
+        self
+    }
+
+    /// Export the quality clients report in-band
+    pub fn with_quality(mut self, quality: Arc<QualityReports>) -> Self {
+        self.quality = Some(quality);
+        self
+    }
+
+    /// Export captive portals clients report
+    pub fn with_portals(mut self, portals: Arc<PortalReports>) -> Self {
+        self.portals = Some(portals);
+        self
+    }
+
+    /// Export memory charged by connections and its caps
+    pub fn with_memory(mut self, memory: Arc<MemoryBudget>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Export queries answered by the DNS forwarder and blocked names
+    pub fn with_dns_forwarder(mut self, dns_forwarder: Arc<DnsForwarder>) -> Self {
+        self.dns_forwarder = Some(dns_forwarder);
+        self
+    }
+
+    /// Render all metrics in the Prometheus text format
+    pub async fn render(&self) -> String {
+        let mut writer = MetricsWriter::new();
+        let stats = self.connection_manager.get_stats();
+
+        writer.gauge("llp_sessions_active", "Active sessions", stats.active_connections);
+        writer.gauge(
+            "llp_sessions_hibernated",
+            "Active sessions idle long enough to have released their buffers",
+            stats.hibernated_connections,
+        );
+        writer.gauge(
+            "llp_sessions_hot",
+            "Active sessions not hibernated",
+            stats.active_connections.saturating_sub(stats.hibernated_connections),
+        );
+        writer.gauge(
+            "llp_connection_saturation",
+            "Share of max_connections in use, 1 when new sessions are refused",
+            self.connection_manager.saturation(),
+        );
+        writer.counter("llp_connections_total", "Connections accepted since start", stats.total_connections);
+        writer.counter("llp_packets_sent_total", "Packets sent to clients", stats.total_packets_sent);
+        writer.counter("llp_packets_received_total", "Packets received from clients", stats.total_packets_received);
+        writer.counter("llp_bytes_sent_total", "Bytes sent to clients", stats.total_bytes_sent);
+        writer.counter("llp_bytes_received_total", "Bytes received from clients", stats.total_bytes_received);
+        writer.counter("llp_errors_total", "Packet processing errors", stats.total_errors);
+        writer.counter(
+            "llp_source_violations_total",
+            "Client packets dropped for a source address other than the session's lease",
+            stats.total_source_violations,
+        );
+
+        writer.header("llp_errors_by_code_total", "Errors by error code", "counter");
+        for (code, count) in self.error_counters.snapshot() {
+            writer.sample("llp_errors_by_code_total", &[("code", &code)], count);
+        }
+
+        if !self.metric_labels.is_empty() {
+            self.render_session_labels(&mut writer).await;
+        }
+
+        if self.traffic_breakdown {
+            self.render_traffic_breakdown(&mut writer).await;
+        }
+
+        if let Some(relay) = &self.relay {
+            render_relay_paths(&mut writer, relay);
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(tun_device) = &self.tun_device {
+            render_tun_device(&mut writer, tun_device);
+        }
+
+        if let Some(signaling) = &self.signaling {
+            let stats = signaling.stats();
+            writer.counter(
+                "llp_p2p_offers_total",
+                "Hole punching offers passed between clients",
+                stats.offers.load(Ordering::Relaxed),
+            );
+            writer.counter(
+                "llp_p2p_punches_total",
+                "Hole punching attempts scheduled",
+                stats.punches.load(Ordering::Relaxed),
+            );
+            writer.counter(
+                "llp_p2p_fallbacks_total",
+                "Hole punching requests answered with a fallback to the relay",
+                stats.fallbacks.load(Ordering::Relaxed),
+            );
+            writer.header("llp_p2p_outcomes_total", "Hole punching results reported by clients", "counter");
+            writer.sample("llp_p2p_outcomes_total", &[("result", "direct")], stats.direct.load(Ordering::Relaxed));
+            writer.sample("llp_p2p_outcomes_total", &[("result", "relayed")], stats.relayed.load(Ordering::Relaxed));
+        }
+
+        if let Some(rekey) = &self.rekey {
+            let stats = rekey.stats();
+            writer.header("llp_key_rotations_total", "Session key rotations by initiator", "counter");
+            writer.sample("llp_key_rotations_total", &[("initiator", "server")], stats.scheduled.load(Ordering::Relaxed));
+            writer.sample("llp_key_rotations_total", &[("initiator", "client")], stats.client.load(Ordering::Relaxed));
+            writer.counter(
+                "llp_key_rotation_failures_total",
+                "Key rotations that failed or were rejected",
+                stats.failures.load(Ordering::Relaxed),
+            );
+        }
+
+        if let Some(overload) = &self.overload {
+            writer.counter(
+                "llp_sessions_shed_total",
+                "New sessions refused with server-busy",
+                overload.shed_count(),
+            );
+        }
+
+        if let Some(accept_failures) = &self.accept_failures {
+            writer.header("llp_accept_failures_total", "Failed accepts on the listener by cause", "counter");
+            for (kind, count) in accept_failures.counts() {
+                writer.sample("llp_accept_failures_total", &[("kind", kind.name())], count);
+            }
+        }
+
+        if let Some(abuse) = &self.abuse {
+            abuse.render(&mut writer);
+        }
+
+        if let Some(supervisor) = &self.supervisor {
+            writer.counter(
+                "llp_task_panics_total",
+                "Connection handlers and listeners that panicked",
+                supervisor.panic_count(),
+            );
+        }
+
+        if let Some(sweep_durations) = &self.sweep_durations {
+            writer.histogram(
+                "llp_sweep_duration_seconds",
+                "Time to sweep one slice of the sessions for stale connections",
+                sweep_durations,
+            );
+        }
+
+        if let Some(timings) = &self.timings {
+            timings.render(&mut writer);
+        }
+
+        if let Some(quality) = &self.quality {
+            quality.render(&mut writer);
+        }
+
+        if let Some(portals) = &self.portals {
+            portals.render(&mut writer);
+        }
+
+        if let Some(memory) = &self.memory {
+            memory.render(&mut writer);
+        }
+
+        if let Some(dns_forwarder) = &self.dns_forwarder {
+            dns_forwarder.render(&mut writer);
+        }
+
+        writer.finish()
+    }
+
+    /// Sessions and traffic grouped by the configured session label keys
+    ///
+    /// Traffic is that of the sessions active now, so it drops when sessions
+    /// end and is exported as gauges.
+    ///
+    /// Only the `metric_label_values` most common values of each key get their
+    /// own series, the rest are folded into "other" to bound cardinality.
+    async fn render_session_labels(&self, writer: &mut MetricsWriter) {
+        let mut groups: Vec<(&str, Vec<(String, LabelTotals)>)> = Vec::new();
+        let mut sessions = Vec::new();
+        for connection in self.connection_manager.connections() {
+            let session = connection.session();
+            sessions.push((session.labels().await, session.stats().await));
+        }
+
+        for key in &self.metric_labels {
+            let mut totals: HashMap<String, LabelTotals> = HashMap::new();
+            for (labels, stats) in &sessions {
+                let Some(value) = labels.get(key) else {
+                    continue;
+                };
+                let entry = totals.entry(value.to_string()).or_default();
+                entry.sessions += 1;
+                entry.bytes_sent += stats.bytes_sent;
+                entry.bytes_received += stats.bytes_received;
+            }
+
+            let mut values: Vec<_> = totals.into_iter().collect();
+            values.sort_by(|a, b| b.1.sessions.cmp(&a.1.sessions).then_with(|| a.0.cmp(&b.0)));
+
+            if values.len() > self.metric_label_values {
+                let overflow = values.split_off(self.metric_label_values.saturating_sub(1));
+                let mut other = LabelTotals::default();
+                for (_, totals) in overflow {
+                    other.sessions += totals.sessions;
+                    other.bytes_sent += totals.bytes_sent;
+                    other.bytes_received += totals.bytes_received;
+                }
+                values.push(("other".to_string(), other));
+            }
+
+            groups.push((key, values));
+        }
+
+        write_label_series(
+            writer,
+            "llp_label_sessions_active",
+            "Active sessions by session label",
+            "gauge",
+            &groups,
+            |t| t.sessions,
+        );
+        write_label_series(
+            writer,
+            "llp_label_bytes_sent",
+            "Bytes sent to active sessions by session label",
+            "gauge",
+            &groups,
+            |t| t.bytes_sent,
+        );
+        write_label_series(
+            writer,
+            "llp_label_bytes_received",
+            "Bytes received from active sessions by session label",
+            "gauge",
+            &groups,
+            |t| t.bytes_received,
+        );
+    }
+
+    /// Per-session inner protocol and destination port counters
+    async fn render_traffic_breakdown(&self, writer: &mut MetricsWriter) {
+        let mut breakdowns = Vec::new();
+        for connection in self.connection_manager.connections() {
+            let session = connection.session();
+            breakdowns.push((session.id().to_string(), session.traffic().await));
+        }
+
+        writer.header(
+            "llp_session_inner_packets_total",
+            "Client packets by inner protocol",
+            "counter",
+        );
+        for (session_id, traffic) in &breakdowns {
+            for (protocol, counter) in [
+                ("tcp", traffic.tcp),
+                ("udp", traffic.udp),
+                ("icmp", traffic.icmp),
+                ("other", traffic.other),
+            ] {
+                writer.sample(
+                    "llp_session_inner_packets_total",
+                    &[("session_id", session_id), ("protocol", protocol)],
+                    counter.packets,
+                );
+            }
+        }
+
+        writer.header(
+            "llp_session_inner_bytes_total",
+            "Client bytes by inner protocol",
+            "counter",
+        );
+        for (session_id, traffic) in &breakdowns {
+            for (protocol, counter) in [
+                ("tcp", traffic.tcp),
+                ("udp", traffic.udp),
+                ("icmp", traffic.icmp),
+                ("other", traffic.other),
+            ] {
+                writer.sample(
+                    "llp_session_inner_bytes_total",
+                    &[("session_id", session_id), ("protocol", protocol)],
+                    counter.bytes,
+                );
+            }
+        }
+
+        writer.header(
+            "llp_session_destination_port_packets",
+            "Client packets to the busiest destination ports",
+            "gauge",
+        );
+        for (session_id, traffic) in &breakdowns {
+            for ((protocol, port), packets) in traffic.top_ports(self.traffic_top_ports) {
+                writer.sample(
+                    "llp_session_destination_port_packets",
+                    &[
+                        ("session_id", session_id),
+                        ("protocol", &protocol.to_string()),
+                        ("port", &port.to_string()),
+                    ],
+                    packets,
+                );
+            }
+        }
+    }
+}
+
+/// One metric family with a sample per (label key, value)
+/// Plain text answer of a health endpoint
+fn health_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        body.len() + 1,
+        body
+    )
+}
+
+fn write_label_series(
+    writer: &mut MetricsWriter,
+    name: &str,
+    help: &str,
+    metric_type: &str,
+    groups: &[(&str, Vec<(String, LabelTotals)>)],
+    value: impl Fn(&LabelTotals) -> u64,
+) {
+    writer.header(name, help, metric_type);
+    for (key, values) in groups {
+        for (label_value, totals) in values {
+            writer.sample(name, &[("label", key), ("value", label_value)], value(totals));
+        }
+    }
+}
+
+/// Per-path byte and drop counters of client-to-client relaying
+fn render_relay_paths(writer: &mut MetricsWriter, relay: &Relay) {
+    let paths = relay.snapshot();
+    let labels: Vec<_> = paths
+        .iter()
+        .map(|path| (path.source.to_string(), path.destination.to_string()))
+        .collect();
+
+    writer.header("llp_relay_bytes_total", "Bytes relayed between clients by path", "counter");
+    for (path, (source, destination)) in paths.iter().zip(&labels) {
+        writer.sample(
+            "llp_relay_bytes_total",
+            &[("source", source), ("destination", destination)],
+            path.bytes,
+        );
+    }
+
+    writer.header(
+        "llp_relay_dropped_total",
+        "Relayed packets dropped by the path rate limit",
+        "counter",
+    );
+    for (path, (source, destination)) in paths.iter().zip(&labels) {
+        writer.sample(
+            "llp_relay_dropped_total",
+            &[("source", source), ("destination", destination)],
+            path.dropped,
+        );
+    }
+}
+
+/// Lifecycle events and per-queue packet, byte and drop counters of the TUN device
+///
+/// Queue counters start over when the device is re-created.
+#[cfg(target_os = "linux")]
+fn render_tun_device(writer: &mut MetricsWriter, tun_device: &TunDevice) {
+    let stats = tun_device.stats();
+    writer.header("llp_tun_device_events_total", "TUN device losses and re-creations", "counter");
+    for (event, count) in [
+        ("lost", &stats.lost),
+        ("recreated", &stats.recreated),
+        ("recreate_failed", &stats.failed),
+    ] {
+        writer.sample("llp_tun_device_events_total", &[("event", event)], count.load(Ordering::Relaxed));
+    }
+
+    let queues = tun_device.queues().map(|queues| queues.snapshot()).unwrap_or_default();
+    let labels: Vec<_> = queues.iter().map(|queue| queue.queue.to_string()).collect();
+
+    writer.header("llp_tun_queue_packets_total", "Packets moved through each TUN queue", "counter");
+    for (queue, label) in queues.iter().zip(&labels) {
+        writer.sample("llp_tun_queue_packets_total", &[("queue", label), ("direction", "read")], queue.packets_read);
+        writer.sample("llp_tun_queue_packets_total", &[("queue", label), ("direction", "write")], queue.packets_written);
+    }
+
+    writer.header("llp_tun_queue_bytes_total", "Bytes moved through each TUN queue", "counter");
+    for (queue, label) in queues.iter().zip(&labels) {
+        writer.sample("llp_tun_queue_bytes_total", &[("queue", label), ("direction", "read")], queue.bytes_read);
+        writer.sample("llp_tun_queue_bytes_total", &[("queue", label), ("direction", "write")], queue.bytes_written);
+    }
+
+    writer.header(
+        "llp_tun_queue_dropped_total",
+        "Packets read from a TUN queue that no session took",
+        "counter",
+    );
+    for (queue, label) in queues.iter().zip(&labels) {
+        writer.sample("llp_tun_queue_dropped_total", &[("queue", label)], queue.dropped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::LostLoveError;
+    use crate::network::inner_packet::tests::ipv4_packet;
+    use crate::network::inner_packet::InnerPacket;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    fn test_config(traffic_breakdown: bool) -> MonitoringConfig {
+        MonitoringConfig {
+            metrics_port: 0,
+            traffic_breakdown,
+            ..MonitoringConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_render_basic_metrics() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        conn.session().record_packet_sent(100).await;
+
+        let exporter = MetricsExporter::new(&test_config(false), manager, Arc::new(ErrorCounters::new()));
+        let output = exporter.render().await;
+
+        assert!(output.contains("llp_sessions_active 1\n"));
+        assert!(output.contains("llp_connection_saturation 0.1\n"));
+        assert!(output.contains("llp_bytes_sent_total 100\n"));
+        assert!(!output.contains("llp_session_inner_packets_total"));
+    }
+
+    #[tokio::test]
+    async fn test_render_sessions_shed() {
+        use crate::config::OverloadConfig;
+        use crate::core::overload::Overload;
+
+        let manager = Arc::new(ConnectionManager::new(10));
+        let overload = Arc::new(Overload::new(&OverloadConfig::default(), manager.clone()));
+        overload.record_shed();
+
+        let exporter = MetricsExporter::new(&test_config(false), manager, Arc::new(ErrorCounters::new()))
+            .with_overload(overload);
+        assert!(exporter.render().await.contains("llp_sessions_shed_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_accept_failures() {
+        use crate::core::accept::{AcceptErrorKind, AcceptFailures};
+
+        let failures = Arc::new(AcceptFailures::new());
+        failures.record(AcceptErrorKind::Exhausted);
+
+        let exporter = MetricsExporter::new(
+            &test_config(false),
+            Arc::new(ConnectionManager::new(10)),
+            Arc::new(ErrorCounters::new()),
+        )
+        .with_accept_failures(failures);
+        let output = exporter.render().await;
+        assert!(output.contains("llp_accept_failures_total{kind=\"exhausted\"} 1\n"));
+        assert!(output.contains("llp_accept_failures_total{kind=\"aborted\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_task_panics() {
+        let exporter = MetricsExporter::new(
+            &test_config(false),
+            Arc::new(ConnectionManager::new(10)),
+            Arc::new(ErrorCounters::new()),
+        )
+        .with_supervisor(Arc::new(Supervisor::new(false)));
+        assert!(exporter.render().await.contains("llp_task_panics_total 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_sweep_durations() {
+        let durations = Arc::new(Histogram::new(&[0.01]));
+        durations.observe(Duration::from_millis(2));
+
+        let exporter = MetricsExporter::new(
+            &test_config(false),
+            Arc::new(ConnectionManager::new(10)),
+            Arc::new(ErrorCounters::new()),
+        )
+        .with_sweep_durations(durations);
+        let output = exporter.render().await;
+        assert!(output.contains("llp_sweep_duration_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(output.contains("llp_sweep_duration_seconds_count 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_error_codes() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let errors = Arc::new(ErrorCounters::new());
+        errors.record(&LostLoveError::AuthFailed {
+            reason: "unknown key".to_string(),
+        });
+
+        let exporter = MetricsExporter::new(&test_config(false), manager, errors);
+        let output = exporter.render().await;
+
+        assert!(output.contains("llp_errors_by_code_total{code=\"auth_failed\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_session_labels() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut conns = Vec::new();
+        for team in ["ops", "ops", "dev", "qa"] {
+            let conn = manager.create_connection(addr).unwrap();
+            let labels = std::collections::BTreeMap::from([("team".to_string(), team.to_string())]);
+            conn.session().update_labels(&labels, &[]).await.unwrap();
+            conn.session().record_packet_sent(100).await;
+            conns.push(conn);
+        }
+        conns.push(manager.create_connection(addr).unwrap());
+
+        let config = MonitoringConfig {
+            metric_labels: vec!["team".to_string()],
+            metric_label_values: 2,
+            ..test_config(false)
+        };
+        let exporter = MetricsExporter::new(&config, manager, Arc::new(ErrorCounters::new()));
+        let output = exporter.render().await;
+
+        assert!(output.contains("llp_label_sessions_active{label=\"team\",value=\"ops\"} 2\n"));
+        assert!(output.contains("llp_label_sessions_active{label=\"team\",value=\"other\"} 2\n"));
+        assert!(output.contains("llp_label_bytes_sent{label=\"team\",value=\"ops\"} 200\n"));
+        assert!(!output.contains("value=\"dev\""));
+    }
+
+    #[tokio::test]
+    async fn test_render_traffic_breakdown() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        let session_id = conn.session().id().to_string();
+
+        let data = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (40000, 53));
+        let packet = InnerPacket::parse(&data).unwrap();
+        conn.session().record_inner_packet(&packet, data.len()).await;
+
+        let exporter = MetricsExporter::new(&test_config(true), manager, Arc::new(ErrorCounters::new()));
+        let output = exporter.render().await;
+
+        assert!(output.contains(&format!(
+            "llp_session_inner_packets_total{{session_id=\"{}\",protocol=\"udp\"}} 1\n",
+            session_id
+        )));
+        assert!(output.contains(&format!(
+            "llp_session_destination_port_packets{{session_id=\"{}\",protocol=\"udp\",port=\"53\"}} 1\n",
+            session_id
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_render_relay_paths() {
+        use crate::config::RelayConfig;
+
+        let relay = Arc::new(
+            Relay::new(&RelayConfig {
+                enabled: true,
+                ..RelayConfig::default()
+            })
+            .unwrap(),
+        );
+        relay
+            .admit("10.8.0.2".parse().unwrap(), "10.8.0.3".parse().unwrap(), 1200)
+            .unwrap();
+
+        let manager = Arc::new(ConnectionManager::new(10));
+        let exporter = MetricsExporter::new(&test_config(false), manager, Arc::new(ErrorCounters::new()))
+            .with_relay(relay);
+        let output = exporter.render().await;
+
+        assert!(output.contains(
+            "llp_relay_bytes_total{source=\"10.8.0.2\",destination=\"10.8.0.3\"} 1200\n"
+        ));
+        assert!(output.contains(
+            "llp_relay_dropped_total{source=\"10.8.0.2\",destination=\"10.8.0.3\"} 0\n"
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_render_tun_device() {
+        use crate::network::tun_queue::TunQueues;
+        use std::os::fd::OwnedFd;
+        use std::os::unix::net::UnixDatagram;
+
+        let (queue, peer) = UnixDatagram::pair().unwrap();
+        queue.set_nonblocking(true).unwrap();
+        let queue = std::sync::Mutex::new(Some(OwnedFd::from(queue)));
+        let opener = move || TunQueues::from_fds("llp-test", 1400, queue.lock().unwrap().take().into_iter().collect());
+        let tun_device = Arc::new(TunDevice::with_opener("llp-test", opener, Vec::new).unwrap());
+        tun_device.queues().unwrap().write(0, &[0x45; 60]).await.unwrap();
+        tun_device.stats().lost.fetch_add(1, Ordering::Relaxed);
+
+        let manager = Arc::new(ConnectionManager::new(10));
+        let exporter = MetricsExporter::new(&test_config(false), manager, Arc::new(ErrorCounters::new()))
+            .with_tun_device(tun_device);
+        let output = exporter.render().await;
+        drop(peer);
+
+        assert!(output.contains("llp_tun_device_events_total{event=\"lost\"} 1\n"));
+        assert!(output.contains("llp_tun_device_events_total{event=\"recreated\"} 0\n"));
+        assert!(output.contains("llp_tun_queue_packets_total{queue=\"0\",direction=\"write\"} 1\n"));
+        assert!(output.contains("llp_tun_queue_bytes_total{queue=\"0\",direction=\"write\"} 60\n"));
+        assert!(output.contains("llp_tun_queue_dropped_total{queue=\"0\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_key_rotations() {
+        let rekey = Arc::new(RekeyScheduler::new());
+        rekey.stats().scheduled.fetch_add(2, Ordering::Relaxed);
+        rekey.stats().client.fetch_add(1, Ordering::Relaxed);
+
+        let manager = Arc::new(ConnectionManager::new(10));
+        let exporter = MetricsExporter::new(&test_config(false), manager, Arc::new(ErrorCounters::new()))
+            .with_rekey(rekey);
+        let output = exporter.render().await;
+
+        assert!(output.contains("llp_key_rotations_total{initiator=\"server\"} 2\n"));
+        assert!(output.contains("llp_key_rotations_total{initiator=\"client\"} 1\n"));
+        assert!(output.contains("llp_key_rotation_failures_total 0\n"));
+    }
+
+    async fn get(exporter: Arc<MetricsExporter>, path: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            exporter.serve(stream).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_http_endpoint() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let exporter = Arc::new(MetricsExporter::new(&test_config(false), manager, Arc::new(ErrorCounters::new())));
+
+        let response = get(exporter, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("llp_sessions_active 0"));
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let drain = Arc::new(DrainController::new());
+        let exporter = Arc::new(
+            MetricsExporter::new(&test_config(false), manager, Arc::new(ErrorCounters::new()))
+                .with_drain(drain.clone()),
+        );
+
+        assert!(get(exporter.clone(), "/healthz").await.starts_with("HTTP/1.1 200 OK"));
+        let response = get(exporter.clone(), "/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("\r\n\r\nready\n"), "{}", response);
+
+        // Draining servers stay alive but stop taking clients
+        drain.start(0, None, None).unwrap();
+        let response = get(exporter.clone(), "/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(response.ends_with("draining\n"));
+        assert!(get(exporter.clone(), "/healthz").await.starts_with("HTTP/1.1 200 OK"));
+        assert!(get(exporter, "/nope").await.starts_with("HTTP/1.1 404"));
+    }
+}
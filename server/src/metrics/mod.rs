@@ -0,0 +1,11 @@
+pub mod errors;
+pub mod exporter;
+pub mod histogram;
+pub mod snmp;
+pub mod timings;
+pub mod writer;
+
+pub use errors::ErrorCounters;
+pub use exporter::MetricsExporter;
+pub use snmp::SnmpAgent;
+pub use timings::{CryptoOperation, Timings};
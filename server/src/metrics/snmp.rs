@@ -0,0 +1,739 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::config::SnmpConfig;
+use crate::core::connection::ConnectionManager;
+use crate::error::{LostLoveError, Result};
+use crate::metrics::errors::ErrorCounters;
+
+/// AgentX protocol version (RFC 2741)
+const AGENTX_VERSION: u8 = 1;
+
+/// Size of the fixed PDU header
+const HEADER_SIZE: usize = 20;
+
+/// Largest PDU payload accepted from the master agent
+const MAX_PAYLOAD: usize = 64 * 1024;
+
+/// Seconds the master agent waits for our answers
+const TIMEOUT_SECS: u8 = 5;
+
+const PDU_OPEN: u8 = 1;
+const PDU_CLOSE: u8 = 2;
+const PDU_REGISTER: u8 = 3;
+const PDU_GET: u8 = 5;
+const PDU_GET_NEXT: u8 = 6;
+const PDU_GET_BULK: u8 = 7;
+const PDU_TEST_SET: u8 = 8;
+const PDU_COMMIT_SET: u8 = 9;
+const PDU_UNDO_SET: u8 = 10;
+const PDU_CLEANUP_SET: u8 = 11;
+const PDU_RESPONSE: u8 = 18;
+
+const FLAG_NON_DEFAULT_CONTEXT: u8 = 0x08;
+const FLAG_NETWORK_BYTE_ORDER: u8 = 0x10;
+
+const NO_ERROR: u16 = 0;
+const NOT_WRITABLE: u16 = 17;
+const PARSE_ERROR: u16 = 266;
+
+/// Object identifier as its sub-identifiers
+pub type Oid = Vec<u32>;
+
+/// Parse a dotted OID such as `1.3.6.1.4.1.8072`
+pub fn parse_oid(text: &str) -> Result<Oid> {
+    let oid: Oid = text
+        .trim_start_matches('.')
+        .split('.')
+        .map(|part| part.parse())
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|_| LostLoveError::Config(format!("Invalid OID: {}", text)))?;
+    if oid.len() < 2 || oid.len() > 100 {
+        return Err(LostLoveError::Config(format!("Invalid OID: {}", text)));
+    }
+    Ok(oid)
+}
+
+/// Value of a variable binding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    OctetString(Vec<u8>),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Counter64(u64),
+    NoSuchObject,
+    EndOfMibView,
+}
+
+impl Value {
+    fn type_code(&self) -> u16 {
+        match self {
+            Value::OctetString(_) => 4,
+            Value::Gauge32(_) => 66,
+            Value::TimeTicks(_) => 67,
+            Value::Counter64(_) => 70,
+            Value::NoSuchObject => 128,
+            Value::EndOfMibView => 130,
+        }
+    }
+}
+
+/// Fixed header of an AgentX PDU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    pdu_type: u8,
+    flags: u8,
+    session_id: u32,
+    transaction_id: u32,
+    packet_id: u32,
+}
+
+/// OID range of a Get, GetNext or GetBulk
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SearchRange {
+    start: Oid,
+    include: bool,
+    end: Oid,
+}
+
+/// Builds a PDU in network byte order
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(header: Header) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&[
+            AGENTX_VERSION,
+            header.pdu_type,
+            header.flags | FLAG_NETWORK_BYTE_ORDER,
+            0,
+        ]);
+        buf.extend_from_slice(&header.session_id.to_be_bytes());
+        buf.extend_from_slice(&header.transaction_id.to_be_bytes());
+        buf.extend_from_slice(&header.packet_id.to_be_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        Self { buf }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn oid(&mut self, oid: &[u32], include: bool) {
+        self.buf.extend_from_slice(&[oid.len() as u8, 0, include as u8, 0]);
+        for subid in oid {
+            self.u32(*subid);
+        }
+    }
+
+    fn octets(&mut self, octets: &[u8]) {
+        self.u32(octets.len() as u32);
+        self.buf.extend_from_slice(octets);
+        self.buf.resize(self.buf.len().next_multiple_of(4), 0);
+    }
+
+    fn varbind(&mut self, name: &[u32], value: &Value) {
+        self.u16(value.type_code());
+        self.u16(0);
+        self.oid(name, false);
+        match value {
+            Value::OctetString(octets) => self.octets(octets),
+            Value::Gauge32(value) | Value::TimeTicks(value) => self.u32(*value),
+            Value::Counter64(value) => self.u64(*value),
+            Value::NoSuchObject | Value::EndOfMibView => {}
+        }
+    }
+
+    /// Fill in the payload length and return the PDU
+    fn finish(mut self) -> Vec<u8> {
+        let payload_len = (self.buf.len() - HEADER_SIZE) as u32;
+        self.buf[16..20].copy_from_slice(&payload_len.to_be_bytes());
+        self.buf
+    }
+}
+
+/// Reads a PDU payload in the byte order its header announced
+struct Decoder<'a> {
+    buf: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8], flags: u8) -> Self {
+        Self {
+            buf,
+            big_endian: flags & FLAG_NETWORK_BYTE_ORDER != 0,
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.buf.len() < N {
+            return Err(LostLoveError::InsufficientData {
+                expected: N,
+                actual: self.buf.len(),
+            });
+        }
+        let (bytes, rest) = self.buf.split_at(N);
+        self.buf = rest;
+        Ok(bytes.try_into().expect("split at N"))
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take()?;
+        Ok(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take()?;
+        Ok(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    /// An OID and its include flag
+    fn oid(&mut self) -> Result<(Oid, bool)> {
+        let [n_subid, prefix, include, _] = self.take()?;
+        let mut oid = Vec::with_capacity(n_subid as usize + 5);
+        if prefix != 0 {
+            oid.extend_from_slice(&[1, 3, 6, 1, prefix as u32]);
+        }
+        for _ in 0..n_subid {
+            oid.push(self.u32()?);
+        }
+        Ok((oid, include != 0))
+    }
+
+    fn octets(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        let padded = len.next_multiple_of(4);
+        if self.buf.len() < padded {
+            return Err(LostLoveError::InsufficientData {
+                expected: padded,
+                actual: self.buf.len(),
+            });
+        }
+        let octets = self.buf[..len].to_vec();
+        self.buf = &self.buf[padded..];
+        Ok(octets)
+    }
+
+    fn ranges(&mut self) -> Result<Vec<SearchRange>> {
+        let mut ranges = Vec::new();
+        while !self.buf.is_empty() {
+            let (start, include) = self.oid()?;
+            let (end, _) = self.oid()?;
+            ranges.push(SearchRange { start, include, end });
+        }
+        Ok(ranges)
+    }
+}
+
+/// AgentX subagent serving server counters to the local SNMP agent
+///
+/// Registers the configured subtree with the master agent (snmpd with
+/// `master agentx`) and answers its Get, GetNext and GetBulk requests; all
+/// objects are read-only.
+pub struct SnmpAgent {
+    master: String,
+    base: Oid,
+    reconnect_interval: Duration,
+    connection_manager: Arc<ConnectionManager>,
+    error_counters: Arc<ErrorCounters>,
+    started: Instant,
+}
+
+impl SnmpAgent {
+    /// Create from the `[monitoring.snmp]` config
+    pub fn new(
+        config: &SnmpConfig,
+        connection_manager: Arc<ConnectionManager>,
+        error_counters: Arc<ErrorCounters>,
+    ) -> Result<Self> {
+        Ok(Self {
+            master: config.master.clone(),
+            base: parse_oid(&config.oid)?,
+            reconnect_interval: Duration::from_secs(config.reconnect_interval),
+            connection_manager,
+            error_counters,
+            started: Instant::now(),
+        })
+    }
+
+    /// Keep a session with the master agent, reconnecting when it ends
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        loop {
+            let result = if let Some(address) = self.master.strip_prefix("tcp:") {
+                match TcpStream::connect(address).await {
+                    Ok(stream) => self.serve(stream).await,
+                    Err(e) => Err(e.into()),
+                }
+            } else {
+                match UnixStream::connect(&self.master).await {
+                    Ok(stream) => self.serve(stream).await,
+                    Err(e) => Err(e.into()),
+                }
+            };
+
+            match result {
+                Ok(()) => info!("SNMP master agent closed the AgentX session"),
+                Err(e) => warn!("AgentX session with {} failed: {}", self.master, e),
+            }
+            time::sleep(self.reconnect_interval).await;
+        }
+    }
+
+    /// Open a session, register the subtree and answer requests until it ends
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S) -> Result<()> {
+        let mut open = Encoder::new(self.header(PDU_OPEN, 0, 1));
+        open.u8(TIMEOUT_SECS);
+        open.buf.extend_from_slice(&[0; 3]);
+        open.oid(&[], false);
+        open.octets(b"LostLove server");
+        stream.write_all(&open.finish()).await?;
+
+        let (header, payload) = read_pdu(&mut stream).await?;
+        let session_id = header.session_id;
+        expect_success(header, &payload, "Open")?;
+
+        let mut register = Encoder::new(self.header(PDU_REGISTER, session_id, 2));
+        register.buf.extend_from_slice(&[0, 127, 0, 0]);
+        register.oid(&self.base, false);
+        stream.write_all(&register.finish()).await?;
+
+        let (header, payload) = read_pdu(&mut stream).await?;
+        expect_success(header, &payload, "Register")?;
+        info!(
+            "Registered SNMP subtree {} with the master agent at {}",
+            format_oid(&self.base),
+            self.master
+        );
+
+        loop {
+            let (header, payload) = read_pdu(&mut stream).await?;
+            if header.pdu_type == PDU_CLOSE {
+                return Ok(());
+            }
+            if let Some(response) = self.answer(header, &payload) {
+                stream.write_all(&response).await?;
+            }
+        }
+    }
+
+    /// Response to one request from the master agent, None if it needs none
+    fn answer(&self, request: Header, payload: &[u8]) -> Option<Vec<u8>> {
+        let result = match request.pdu_type {
+            PDU_GET | PDU_GET_NEXT | PDU_GET_BULK => self.lookup(request, payload),
+            PDU_TEST_SET => Err(NOT_WRITABLE),
+            PDU_COMMIT_SET | PDU_UNDO_SET => Ok(Vec::new()),
+            PDU_CLEANUP_SET | PDU_RESPONSE => return None,
+            other => {
+                debug!("Ignoring AgentX PDU type {}", other);
+                return None;
+            }
+        };
+
+        let mut response = Encoder::new(Header {
+            pdu_type: PDU_RESPONSE,
+            flags: 0,
+            ..request
+        });
+        response.u32(self.uptime_ticks());
+        match result {
+            Ok(varbinds) => {
+                response.u16(NO_ERROR);
+                response.u16(0);
+                for (name, value) in &varbinds {
+                    response.varbind(name, value);
+                }
+            }
+            Err(error) => {
+                response.u16(error);
+                response.u16(if error == NOT_WRITABLE { 1 } else { 0 });
+            }
+        }
+        Some(response.finish())
+    }
+
+    /// Variable bindings answering a Get, GetNext or GetBulk
+    fn lookup(&self, request: Header, payload: &[u8]) -> std::result::Result<Vec<(Oid, Value)>, u16> {
+        let mut decoder = Decoder::new(payload, request.flags);
+        if request.flags & FLAG_NON_DEFAULT_CONTEXT != 0 {
+            decoder.octets().map_err(|_| PARSE_ERROR)?;
+        }
+        let repetitions = if request.pdu_type == PDU_GET_BULK {
+            let non_repeaters = decoder.u16().map_err(|_| PARSE_ERROR)?;
+            let max_repetitions = decoder.u16().map_err(|_| PARSE_ERROR)?;
+            Some((non_repeaters as usize, max_repetitions as usize))
+        } else {
+            None
+        };
+        let ranges = decoder.ranges().map_err(|_| PARSE_ERROR)?;
+
+        let objects = self.objects();
+        let varbinds = match (request.pdu_type, repetitions) {
+            (PDU_GET, _) => ranges
+                .into_iter()
+                .map(|range| {
+                    let value = objects.get(&range.start).cloned().unwrap_or(Value::NoSuchObject);
+                    (range.start, value)
+                })
+                .collect(),
+            (_, None) => ranges.iter().map(|range| next(&objects, range)).collect(),
+            (_, Some((non_repeaters, max_repetitions))) => {
+                bulk(&objects, &ranges, non_repeaters, max_repetitions)
+            }
+        };
+        Ok(varbinds)
+    }
+
+    /// Current value of every object, keyed by full OID
+    ///
+    /// `base.1.n.0` are scalars; `base.2.1.{1,2}.<code>` is the table of
+    /// error counts, indexed by the error code as a length-prefixed string.
+    fn objects(&self) -> BTreeMap<Oid, Value> {
+        let stats = self.connection_manager.get_stats();
+        let scalars = [
+            Value::Gauge32(stats.active_connections.min(u32::MAX as usize) as u32),
+            Value::Gauge32(self.connection_manager.max_connections().min(u32::MAX as usize) as u32),
+            Value::Counter64(stats.total_connections),
+            Value::Counter64(stats.total_bytes_received),
+            Value::Counter64(stats.total_bytes_sent),
+            Value::Counter64(stats.total_packets_received),
+            Value::Counter64(stats.total_packets_sent),
+            Value::Counter64(stats.total_errors),
+            Value::TimeTicks(self.uptime_ticks()),
+        ];
+
+        let mut objects = BTreeMap::new();
+        for (i, value) in scalars.into_iter().enumerate() {
+            objects.insert(self.oid(&[1, i as u32 + 1, 0]), value);
+        }
+        for (code, count) in self.error_counters.snapshot() {
+            let index: Vec<u32> = std::iter::once(code.len() as u32)
+                .chain(code.bytes().map(u32::from))
+                .collect();
+            objects.insert(self.oid(&[&[2, 1, 1][..], &index].concat()), Value::OctetString(code.into_bytes()));
+            objects.insert(self.oid(&[&[2, 1, 2][..], &index].concat()), Value::Counter64(count));
+        }
+        objects
+    }
+
+    /// `base` followed by `suffix`
+    fn oid(&self, suffix: &[u32]) -> Oid {
+        [&self.base[..], suffix].concat()
+    }
+
+    /// Hundredths of a second since start, as sysUpTime
+    fn uptime_ticks(&self) -> u32 {
+        (self.started.elapsed().as_millis() / 10) as u32
+    }
+
+    fn header(&self, pdu_type: u8, session_id: u32, packet_id: u32) -> Header {
+        Header {
+            pdu_type,
+            flags: 0,
+            session_id,
+            transaction_id: 0,
+            packet_id,
+        }
+    }
+}
+
+/// First object after the start of `range`, or endOfMibView
+fn next(objects: &BTreeMap<Oid, Value>, range: &SearchRange) -> (Oid, Value) {
+    objects
+        .range(range.start.clone()..)
+        .find(|(name, _)| range.include || **name != range.start)
+        .filter(|(name, _)| range.end.is_empty() || **name < range.end)
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .unwrap_or_else(|| (range.start.clone(), Value::EndOfMibView))
+}
+
+/// GetBulk: the non-repeaters once, then the rest up to `max_repetitions`
+/// times, row by row
+fn bulk(
+    objects: &BTreeMap<Oid, Value>,
+    ranges: &[SearchRange],
+    non_repeaters: usize,
+    max_repetitions: usize,
+) -> Vec<(Oid, Value)> {
+    let non_repeaters = non_repeaters.min(ranges.len());
+    let mut varbinds: Vec<_> = ranges[..non_repeaters].iter().map(|range| next(objects, range)).collect();
+
+    let mut repeaters = ranges[non_repeaters..].to_vec();
+    for _ in 0..max_repetitions {
+        if repeaters.is_empty() {
+            break;
+        }
+        let row: Vec<_> = repeaters.iter().map(|range| next(objects, range)).collect();
+        let done = row.iter().all(|(_, value)| *value == Value::EndOfMibView);
+        for (range, (name, _)) in repeaters.iter_mut().zip(&row) {
+            range.start = name.clone();
+            range.include = false;
+        }
+        varbinds.extend(row);
+        if done {
+            break;
+        }
+    }
+    varbinds
+}
+
+/// Read one PDU, returning its header and payload
+async fn read_pdu<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Header, Vec<u8>)> {
+    let mut raw = [0u8; HEADER_SIZE];
+    stream.read_exact(&mut raw).await?;
+    if raw[0] != AGENTX_VERSION {
+        return Err(LostLoveError::Network(format!("Unsupported AgentX version {}", raw[0])));
+    }
+
+    let flags = raw[2];
+    let mut decoder = Decoder::new(&raw[4..], flags);
+    let header = Header {
+        pdu_type: raw[1],
+        flags,
+        session_id: decoder.u32()?,
+        transaction_id: decoder.u32()?,
+        packet_id: decoder.u32()?,
+    };
+    let payload_len = decoder.u32()? as usize;
+    if payload_len > MAX_PAYLOAD {
+        return Err(LostLoveError::Network(format!("AgentX PDU of {} bytes is too large", payload_len)));
+    }
+
+    let mut payload = vec![0u8; payload_len];
+    stream.read_exact(&mut payload).await?;
+    Ok((header, payload))
+}
+
+/// Check the master agent's Response to our `what`
+fn expect_success(header: Header, payload: &[u8], what: &str) -> Result<()> {
+    if header.pdu_type != PDU_RESPONSE {
+        return Err(LostLoveError::Network(format!(
+            "Expected an AgentX Response to {}, got PDU type {}",
+            what, header.pdu_type
+        )));
+    }
+    let mut decoder = Decoder::new(payload, header.flags);
+    let _uptime = decoder.u32()?;
+    match decoder.u16()? {
+        NO_ERROR => Ok(()),
+        error => Err(LostLoveError::Network(format!("AgentX {} refused with error {}", what, error))),
+    }
+}
+
+/// Dotted form of an OID
+fn format_oid(oid: &[u32]) -> String {
+    oid.iter().map(u32::to_string).collect::<Vec<_>>().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    const BASE: [u32; 9] = [1, 3, 6, 1, 4, 1, 8072, 9999, 9999];
+
+    fn agent() -> (SnmpAgent, Arc<ConnectionManager>) {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let errors = Arc::new(ErrorCounters::new());
+        errors.record(&LostLoveError::RateLimited);
+        let config = SnmpConfig {
+            enabled: true,
+            ..SnmpConfig::default()
+        };
+        (SnmpAgent::new(&config, manager.clone(), errors).unwrap(), manager)
+    }
+
+    fn request(pdu_type: u8, build: impl FnOnce(&mut Encoder)) -> (Header, Vec<u8>) {
+        let header = Header {
+            pdu_type,
+            flags: FLAG_NETWORK_BYTE_ORDER,
+            session_id: 7,
+            transaction_id: 3,
+            packet_id: 9,
+        };
+        let mut encoder = Encoder::new(header);
+        build(&mut encoder);
+        (header, encoder.finish()[HEADER_SIZE..].to_vec())
+    }
+
+    /// Variable bindings of a Response PDU
+    fn varbinds(response: &[u8]) -> (u16, Vec<(Oid, u16)>) {
+        let mut decoder = Decoder::new(&response[HEADER_SIZE..], response[2]);
+        decoder.u32().unwrap();
+        let error = decoder.u16().unwrap();
+        decoder.u16().unwrap();
+
+        let mut varbinds = Vec::new();
+        while !decoder.buf.is_empty() {
+            let type_code = decoder.u16().unwrap();
+            decoder.u16().unwrap();
+            let (name, _) = decoder.oid().unwrap();
+            match type_code {
+                4 => drop(decoder.octets().unwrap()),
+                66 | 67 => drop(decoder.u32().unwrap()),
+                70 => drop(decoder.take::<8>().unwrap()),
+                _ => {}
+            }
+            varbinds.push((name, type_code));
+        }
+        (error, varbinds)
+    }
+
+    #[test]
+    fn test_parse_oid() {
+        assert_eq!(parse_oid(".1.3.6.1.4.1.8072").unwrap(), vec![1, 3, 6, 1, 4, 1, 8072]);
+        assert!(parse_oid("1.3.x").is_err());
+        assert!(parse_oid("1").is_err());
+    }
+
+    #[test]
+    fn test_oid_prefix_and_byte_order() {
+        // 1.3.6.1.4.1.42 with prefix compression, little endian
+        let mut payload = vec![2, 4, 1, 0];
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&42u32.to_le_bytes());
+
+        let mut decoder = Decoder::new(&payload, 0);
+        assert_eq!(decoder.oid().unwrap(), (vec![1, 3, 6, 1, 4, 1, 42], true));
+    }
+
+    #[test]
+    fn test_get_and_walk() {
+        let (agent, manager) = agent();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080);
+        let _conn = manager.create_connection(addr).unwrap();
+
+        let sessions: Oid = [&BASE[..], &[1, 1, 0]].concat();
+        let (header, payload) = request(PDU_GET, |encoder| {
+            encoder.oid(&sessions, false);
+            encoder.oid(&[], false);
+            encoder.oid(&[1, 3, 6, 1, 2], false);
+            encoder.oid(&[], false);
+        });
+        let response = agent.answer(header, &payload).unwrap();
+        assert_eq!(response[1], PDU_RESPONSE);
+        assert_eq!(&response[4..16], &[0, 0, 0, 7, 0, 0, 0, 3, 0, 0, 0, 9]);
+        let (error, bindings) = varbinds(&response);
+        assert_eq!(error, NO_ERROR);
+        assert_eq!(bindings, [(sessions.clone(), 66), (vec![1, 3, 6, 1, 2], 128)]);
+
+        let objects = agent.objects();
+        assert_eq!(objects.get(&sessions), Some(&Value::Gauge32(1)));
+
+        // Walking from the base visits every object, then runs off the end
+        let mut walked = Vec::new();
+        let mut cursor = BASE.to_vec();
+        loop {
+            let (header, payload) = request(PDU_GET_NEXT, |encoder| {
+                encoder.oid(&cursor, false);
+                encoder.oid(&[], false);
+            });
+            let (_, bindings) = varbinds(&agent.answer(header, &payload).unwrap());
+            let (name, type_code) = bindings[0].clone();
+            if type_code == 130 {
+                break;
+            }
+            walked.push(name.clone());
+            cursor = name;
+        }
+        assert_eq!(walked, objects.keys().cloned().collect::<Vec<_>>());
+
+        let code = "rate_limited";
+        let index: Vec<u32> = std::iter::once(code.len() as u32).chain(code.bytes().map(u32::from)).collect();
+        let count_oid = [&BASE[..], &[2, 1, 2], &index].concat();
+        assert_eq!(objects.get(&count_oid), Some(&Value::Counter64(1)));
+    }
+
+    #[test]
+    fn test_get_bulk_and_set() {
+        let (agent, _manager) = agent();
+
+        let (header, payload) = request(PDU_GET_BULK, |encoder| {
+            encoder.u16(0);
+            encoder.u16(3);
+            encoder.oid(&[&BASE[..], &[1]].concat(), false);
+            encoder.oid(&[], false);
+        });
+        let (_, bindings) = varbinds(&agent.answer(header, &payload).unwrap());
+        let names: Vec<_> = bindings.iter().map(|(name, _)| name[BASE.len()..].to_vec()).collect();
+        assert_eq!(names, [vec![1, 1, 0], vec![1, 2, 0], vec![1, 3, 0]]);
+
+        let (header, payload) = request(PDU_TEST_SET, |_| {});
+        let (error, _) = varbinds(&agent.answer(header, &payload).unwrap());
+        assert_eq!(error, NOT_WRITABLE);
+        assert!(agent.answer(request(PDU_CLEANUP_SET, |_| {}).0, &[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_with_master() {
+        let (agent, _manager) = agent();
+        let (subagent, mut master) = tokio::io::duplex(4096);
+        let session = tokio::spawn(async move { agent.serve(subagent).await });
+
+        let reply = |header: Header, session_id: u32| {
+            let mut response = Encoder::new(Header {
+                pdu_type: PDU_RESPONSE,
+                session_id,
+                ..header
+            });
+            response.u32(0);
+            response.u16(NO_ERROR);
+            response.u16(0);
+            response.finish()
+        };
+
+        let (open, _) = read_pdu(&mut master).await.unwrap();
+        assert_eq!(open.pdu_type, PDU_OPEN);
+        master.write_all(&reply(open, 42)).await.unwrap();
+
+        let (register, payload) = read_pdu(&mut master).await.unwrap();
+        assert_eq!((register.pdu_type, register.session_id), (PDU_REGISTER, 42));
+        let mut decoder = Decoder::new(&payload[4..], register.flags);
+        assert_eq!(decoder.oid().unwrap().0, BASE.to_vec());
+        master.write_all(&reply(register, 42)).await.unwrap();
+
+        let (header, payload) = request(PDU_GET_NEXT, |encoder| {
+            encoder.oid(&BASE, false);
+            encoder.oid(&[], false);
+        });
+        let mut get_next = Encoder::new(Header { session_id: 42, ..header });
+        get_next.buf.extend_from_slice(&payload);
+        master.write_all(&get_next.finish()).await.unwrap();
+
+        let (response, payload) = read_pdu(&mut master).await.unwrap();
+        assert_eq!((response.pdu_type, response.session_id), (PDU_RESPONSE, 42));
+        let mut full = vec![0u8; HEADER_SIZE];
+        full[2] = response.flags;
+        full.extend_from_slice(&payload);
+        let (_, bindings) = varbinds(&full);
+        assert_eq!(bindings[0], ([&BASE[..], &[1, 1, 0]].concat(), 66));
+
+        let mut close = Encoder::new(Header {
+            pdu_type: PDU_CLOSE,
+            session_id: 42,
+            ..header
+        });
+        close.buf.extend_from_slice(&[1, 0, 0, 0]);
+        master.write_all(&close.finish()).await.unwrap();
+        session.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::Instant;
+
+use crate::metrics::histogram::Histogram;
+use crate::metrics::writer::MetricsWriter;
+
+/// Bucket bounds in seconds for handshakes, from accept to confirmed keys
+pub const HANDSHAKE_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Bucket bounds in seconds for per-packet and per-operation work
+pub const FAST_BUCKETS: &[f64] = &[
+    0.000_005, 0.000_01, 0.000_025, 0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025,
+    0.1,
+];
+
+/// Cryptographic work the server times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoOperation {
+    /// Decrypting an obfuscated ClientHello
+    HelloOpen,
+    /// Encrypting an obfuscated handshake response
+    HelloSeal,
+    /// Deriving the next epoch's session keys
+    Rekey,
+}
+
+impl CryptoOperation {
+    pub const ALL: [CryptoOperation; 3] = [
+        CryptoOperation::HelloOpen,
+        CryptoOperation::HelloSeal,
+        CryptoOperation::Rekey,
+    ];
+
+    /// Value of the `operation` label
+    pub fn name(self) -> &'static str {
+        match self {
+            CryptoOperation::HelloOpen => "hello_open",
+            CryptoOperation::HelloSeal => "hello_seal",
+            CryptoOperation::Rekey => "rekey",
+        }
+    }
+}
+
+/// Latency histograms of the connection path, shared by all connections
+#[derive(Debug)]
+pub struct Timings {
+    /// From accepting the connection until the session's keys are confirmed
+    pub handshake: Histogram,
+    /// From a packet being read until all its effects are handled
+    pub packet_processing: Histogram,
+    /// From accepting the connection until its handler starts running
+    pub accept_queue_wait: Histogram,
+    /// From accepting the connection until the first tunneled Data packet,
+    /// without and with early data
+    first_data: [Histogram; 2],
+    crypto: [Histogram; CryptoOperation::ALL.len()],
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self {
+            handshake: Histogram::new(HANDSHAKE_BUCKETS),
+            packet_processing: Histogram::new(FAST_BUCKETS),
+            accept_queue_wait: Histogram::new(FAST_BUCKETS),
+            first_data: std::array::from_fn(|_| Histogram::new(HANDSHAKE_BUCKETS)),
+            crypto: std::array::from_fn(|_| Histogram::new(FAST_BUCKETS)),
+        }
+    }
+
+    /// Histogram of one kind of crypto operation
+    pub fn crypto(&self, operation: CryptoOperation) -> &Histogram {
+        &self.crypto[operation as usize]
+    }
+
+    /// Time to the first tunneled byte of sessions with or without early data
+    pub fn first_data(&self, early_data: bool) -> &Histogram {
+        &self.first_data[early_data as usize]
+    }
+
+    /// Run `work` and record how long it took as `operation`
+    pub fn time_crypto<T>(&self, operation: CryptoOperation, work: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = work();
+        self.crypto(operation).observe(started.elapsed());
+        result
+    }
+
+    /// Write all histograms
+    pub fn render(&self, writer: &mut MetricsWriter) {
+        writer.histogram(
+            "llp_handshake_duration_seconds",
+            "Time from accepting a connection until its session keys are confirmed",
+            &self.handshake,
+        );
+        writer.histogram(
+            "llp_packet_processing_seconds",
+            "Time from reading a client packet until it has been handled",
+            &self.packet_processing,
+        );
+        writer.histogram(
+            "llp_accept_queue_wait_seconds",
+            "Time accepted connections wait for their handler to run",
+            &self.accept_queue_wait,
+        );
+
+        writer.header(
+            "llp_first_data_seconds",
+            "Time from accepting a connection until the client's first tunneled Data packet",
+            "histogram",
+        );
+        for early_data in [false, true] {
+            writer.histogram_series(
+                "llp_first_data_seconds",
+                &[("early_data", if early_data { "true" } else { "false" })],
+                self.first_data(early_data),
+            );
+        }
+
+        writer.header(
+            "llp_crypto_operation_seconds",
+            "Time spent in cryptographic operations by operation",
+            "histogram",
+        );
+        for operation in CryptoOperation::ALL {
+            writer.histogram_series(
+                "llp_crypto_operation_seconds",
+                &[("operation", operation.name())],
+                self.crypto(operation),
+            );
+        }
+    }
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let timings = Timings::new();
+        timings.handshake.observe(Duration::from_millis(3));
+        timings.first_data(true).observe(Duration::from_millis(20));
+        let value = timings.time_crypto(CryptoOperation::Rekey, || 7);
+        assert_eq!(value, 7);
+
+        let mut writer = MetricsWriter::new();
+        timings.render(&mut writer);
+        let output = writer.finish();
+
+        assert!(output.contains("llp_handshake_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(output.contains("llp_handshake_duration_seconds_count 1\n"));
+        assert!(output.contains("llp_first_data_seconds_bucket{early_data=\"true\",le=\"0.025\"} 1\n"));
+        assert!(output.contains("llp_first_data_seconds_count{early_data=\"false\"} 0\n"));
+        assert!(output.contains("llp_crypto_operation_seconds_count{operation=\"rekey\"} 1\n"));
+        assert!(output.contains("llp_crypto_operation_seconds_count{operation=\"hello_open\"} 0\n"));
+        assert_eq!(output.matches("# TYPE llp_crypto_operation_seconds histogram").count(), 1);
+    }
+
+    #[test]
+    fn test_buckets_ascend() {
+        for buckets in [HANDSHAKE_BUCKETS, FAST_BUCKETS] {
+            assert!(buckets.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt::Write;
+
+use crate::metrics::histogram::Histogram;
+
+/// Builder for the Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct MetricsWriter {
+    output: String,
+}
+
+impl MetricsWriter {
+    /// Create new writer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write HELP and TYPE lines for a metric family
+    pub fn header(&mut self, name: &str, help: &str, metric_type: &str) {
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} {}", name, metric_type);
+    }
+
+    /// Write a single sample with optional labels
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.output.push_str(name);
+
+        if !labels.is_empty() {
+            self.output.push('{');
+            for (i, (key, val)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.output.push(',');
+                }
+                let _ = write!(self.output, "{}=\"{}\"", key, escape_label(val));
+            }
+            self.output.push('}');
+        }
+
+        let _ = writeln!(self.output, " {}", value);
+    }
+
+    /// Write an unlabeled gauge
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl std::fmt::Display) {
+        self.header(name, help, "gauge");
+        self.sample(name, &[], value);
+    }
+
+    /// Write an unlabeled counter
+    pub fn counter(&mut self, name: &str, help: &str, value: impl std::fmt::Display) {
+        self.header(name, help, "counter");
+        self.sample(name, &[], value);
+    }
+
+    /// Write a histogram as `_bucket`, `_sum` and `_count` samples
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+        self.histogram_series(name, &[], histogram);
+    }
+
+    /// Write the samples of one labeled series of a histogram family
+    pub fn histogram_series(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket = format!("{}_bucket", name);
+        let mut count = 0;
+        for (bound, cumulative) in histogram.cumulative() {
+            let le = if bound.is_infinite() { "+Inf".to_string() } else { bound.to_string() };
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(&bucket, &bucket_labels, cumulative);
+            count = cumulative;
+        }
+        self.sample(&format!("{}_sum", name), labels, histogram.sum());
+        self.sample(&format!("{}_count", name), labels, count);
+    }
+
+    /// Finish and return the rendered text
+    pub fn finish(self) -> String {
+        self.output
+    }
+}
+
+/// Escape a label value per the exposition format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gauge_and_labels() {
+        let mut writer = MetricsWriter::new();
+        writer.gauge("llp_sessions_active", "Active sessions", 3);
+        writer.header("llp_test_total", "Test", "counter");
+        writer.sample("llp_test_total", &[("protocol", "tcp"), ("port", "443")], 7);
+
+        let output = writer.finish();
+        assert!(output.contains("# TYPE llp_sessions_active gauge\nllp_sessions_active 3\n"));
+        assert!(output.contains("llp_test_total{protocol=\"tcp\",port=\"443\"} 7\n"));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(&[0.01, 0.1]);
+        histogram.observe(std::time::Duration::from_millis(5));
+        histogram.observe(std::time::Duration::from_millis(50));
+
+        let mut writer = MetricsWriter::new();
+        writer.histogram("llp_test_seconds", "Test", &histogram);
+        let output = writer.finish();
+        assert!(output.contains("# TYPE llp_test_seconds histogram\n"));
+        assert!(output.contains("llp_test_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(output.contains("llp_test_seconds_bucket{le=\"0.1\"} 2\n"));
+        assert!(output.contains("llp_test_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(output.contains("llp_test_seconds_sum 0.055\n"));
+        assert!(output.contains("llp_test_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn test_label_escaping() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
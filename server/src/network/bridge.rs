@@ -0,0 +1,327 @@
+use bytes::BytesMut;
+use dashmap::DashSet;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::core::memory::{MemoryBudget, MemoryCharge, MemoryUse};
+use crate::error::{LostLoveError, Result};
+
+/// Server bytes held per tunnel until the client polls them
+const MAX_BUFFERED: usize = 64 * 1024;
+
+/// Bytes read from the listener at once
+const READ_CHUNK: usize = 4096;
+
+/// Server bytes waiting for the client, and the memory they are charged
+struct Downstream {
+    bytes: BytesMut,
+    /// Buffered bytes plus room for the read in progress
+    charge: MemoryCharge,
+}
+
+/// One client's tunnel, bridged to a loopback connection to the listener
+///
+/// Locked on its own while a request is served, so a tunnel stalled on a
+/// write doesn't hold up the others.
+struct Tunnel {
+    writer: OwnedWriteHalf,
+    downstream: Arc<Mutex<Downstream>>,
+    closed: Arc<AtomicBool>,
+    local_address: SocketAddr,
+    /// Sequence number and answer of the last request, replayed on retries
+    last: Option<(u32, Vec<u8>)>,
+    last_seen: Instant,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Bridges request/response transports to the server's own TCP listener
+///
+/// Transports that can only answer what the client asks (DNS, ICMP echo)
+/// carry the bytes of an ordinary LLP connection: each request brings client
+/// bytes and takes back whatever the server sent since, so the handshake and
+/// everything after it work unchanged. Clients send one request at a time
+/// and repeat the sequence number when retrying.
+pub struct LoopbackBridge<K> {
+    name: &'static str,
+    target: SocketAddr,
+    max_tunnels: usize,
+    idle_timeout: Duration,
+    tunnels: Mutex<HashMap<K, Arc<tokio::sync::Mutex<Tunnel>>>>,
+    local_addresses: DashSet<SocketAddr>,
+    memory: Arc<MemoryBudget>,
+}
+
+impl<K: Eq + Hash + Clone + Display> LoopbackBridge<K> {
+    /// Create bridge to `target`, `name` is the transport shown in logs
+    pub fn new(name: &'static str, target: SocketAddr, max_tunnels: usize, idle_timeout: Duration) -> Self {
+        Self {
+            name,
+            target,
+            max_tunnels,
+            idle_timeout,
+            tunnels: Mutex::new(HashMap::new()),
+            local_addresses: DashSet::new(),
+            memory: Arc::new(MemoryBudget::unlimited()),
+        }
+    }
+
+    /// Charge buffered server bytes to `memory`, pausing tunnels at its caps
+    pub fn with_memory(mut self, memory: Arc<MemoryBudget>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Whether a connection to the listener comes from one of the tunnels
+    pub fn is_tunnel(&self, peer: SocketAddr) -> bool {
+        self.local_addresses.contains(&peer)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Pass client bytes on and take up to `budget` server bytes
+    ///
+    /// Ok(None) once the server side closed the connection and everything
+    /// was delivered, so the client stops polling.
+    pub async fn exchange(&self, id: &K, seq: u32, data: &[u8], budget: usize) -> Result<Option<Vec<u8>>> {
+        let handle = self.tunnel(id).await?;
+        let mut tunnel = handle.lock().await;
+        tunnel.last_seen = Instant::now();
+
+        if let Some((last_seq, answer)) = &tunnel.last {
+            if *last_seq == seq {
+                return Ok(Some(answer.clone()));
+            }
+        }
+
+        if !data.is_empty() {
+            if let Err(e) = tunnel.writer.write_all(data).await {
+                self.close(id, &handle, &tunnel);
+                return Err(e.into());
+            }
+        }
+
+        let answer = {
+            let mut downstream = tunnel.downstream.lock().unwrap();
+            let n = downstream.bytes.len().min(budget);
+            let answer = downstream.bytes.split_to(n).to_vec();
+            let held = (downstream.bytes.len() + READ_CHUNK).min(downstream.charge.bytes());
+            downstream.charge.resize(held);
+            answer
+        };
+        if answer.is_empty() && tunnel.closed.load(Ordering::Relaxed) {
+            self.close(id, &handle, &tunnel);
+            return Ok(None);
+        }
+
+        tunnel.last = Some((seq, answer.clone()));
+        Ok(Some(answer))
+    }
+
+    /// Close tunnels the client stopped polling
+    ///
+    /// Tunnels serving a request right now aren't idle.
+    pub fn expire_idle(&self) {
+        self.tunnels.lock().unwrap().retain(|id, tunnel| {
+            let Ok(tunnel) = tunnel.try_lock() else {
+                return true;
+            };
+            if tunnel.last_seen.elapsed() <= self.idle_timeout {
+                return true;
+            }
+            warn!("{} tunnel {} idle, closing", self.name, id);
+            self.local_addresses.remove(&tunnel.local_address);
+            false
+        });
+    }
+
+    /// Tunnel of a client, opened on its first request
+    async fn tunnel(&self, id: &K) -> Result<Arc<tokio::sync::Mutex<Tunnel>>> {
+        {
+            let tunnels = self.tunnels.lock().unwrap();
+            if let Some(tunnel) = tunnels.get(id) {
+                return Ok(tunnel.clone());
+            }
+            if tunnels.len() >= self.max_tunnels {
+                return Err(LostLoveError::TooManyConnections);
+            }
+        }
+
+        // Connect without the map locked; a concurrent first request of the
+        // same client may win, its tunnel is kept
+        let opened = self.open().await?;
+        let mut tunnels = self.tunnels.lock().unwrap();
+        if let Some(tunnel) = tunnels.get(id) {
+            self.local_addresses.remove(&opened.local_address);
+            return Ok(tunnel.clone());
+        }
+        if tunnels.len() >= self.max_tunnels {
+            self.local_addresses.remove(&opened.local_address);
+            return Err(LostLoveError::TooManyConnections);
+        }
+        info!("{} tunnel {} opened", self.name, id);
+        let tunnel = Arc::new(tokio::sync::Mutex::new(opened));
+        tunnels.insert(id.clone(), tunnel.clone());
+        Ok(tunnel)
+    }
+
+    /// Connect a new tunnel to the listener
+    async fn open(&self) -> Result<Tunnel> {
+        let stream = TcpStream::connect(self.target).await?;
+        let local_address = stream.local_addr()?;
+        self.local_addresses.insert(local_address);
+
+        let (mut reader, writer) = stream.into_split();
+        let downstream = Arc::new(Mutex::new(Downstream {
+            bytes: BytesMut::new(),
+            charge: self.memory.charge(MemoryUse::BridgeBuffers),
+        }));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let reader = {
+            let downstream = downstream.clone();
+            let closed = closed.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; READ_CHUNK];
+                loop {
+                    // Stop reading while the client is behind or memory is
+                    // short, TCP pushes back
+                    let room = {
+                        let mut downstream = downstream.lock().unwrap();
+                        let wanted = downstream.bytes.len() + READ_CHUNK;
+                        downstream.bytes.len() < MAX_BUFFERED && downstream.charge.try_resize(wanted)
+                    };
+                    if !room {
+                        time::sleep(Duration::from_millis(50)).await;
+                        continue;
+                    }
+                    match reader.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            let mut downstream = downstream.lock().unwrap();
+                            downstream.bytes.extend_from_slice(&buf[..n]);
+                            let buffered = downstream.bytes.len();
+                            downstream.charge.resize(buffered);
+                        }
+                    }
+                }
+                closed.store(true, Ordering::Relaxed);
+            })
+        };
+
+        Ok(Tunnel {
+            writer,
+            downstream,
+            closed,
+            local_address,
+            last: None,
+            last_seen: Instant::now(),
+            reader,
+        })
+    }
+
+    /// Forget a client's tunnel, unless it was already replaced
+    fn close(&self, id: &K, handle: &Arc<tokio::sync::Mutex<Tunnel>>, tunnel: &Tunnel) {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        if tunnels.get(id).is_some_and(|entry| Arc::ptr_eq(entry, handle)) {
+            tunnels.remove(id);
+            info!("{} tunnel {} closed", self.name, id);
+        }
+        self.local_addresses.remove(&tunnel.local_address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_bridge_to_listener() {
+        // Echo server standing in for the LLP listener
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let (mut stream, peer) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&buf[..n]).await.unwrap();
+            peer
+        });
+
+        let bridge = LoopbackBridge::new("test", target, 1, Duration::from_secs(60));
+        let mut answer = bridge.exchange(&1u16, 1, b"hello", 3).await.unwrap().unwrap();
+
+        let peer = accepted.await.unwrap();
+        assert!(bridge.is_tunnel(peer));
+        assert!(matches!(
+            bridge.exchange(&2u16, 1, b"", 3).await,
+            Err(LostLoveError::TooManyConnections)
+        ));
+
+        // Poll until the echo arrives, a retry then replays the same answer
+        for seq in 2..50 {
+            if !answer.is_empty() {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+            answer = bridge.exchange(&1u16, seq, b"", 3).await.unwrap().unwrap();
+            if !answer.is_empty() {
+                assert_eq!(bridge.exchange(&1u16, seq, b"", 3).await.unwrap().unwrap(), answer);
+            }
+        }
+        assert_eq!(answer, b"hel");
+
+        // The rest, then the close once the listener side hung up
+        let mut rest = Vec::new();
+        for seq in 50..100 {
+            match bridge.exchange(&1u16, seq, b"", 3).await.unwrap() {
+                Some(bytes) => rest.extend(bytes),
+                None => break,
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(rest, b"lo");
+        assert!(!bridge.is_tunnel(peer));
+    }
+
+    #[tokio::test]
+    async fn test_busy_tunnel_does_not_block_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let _accepting = tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+
+        let bridge = LoopbackBridge::new("test", target, 2, Duration::from_secs(60));
+        bridge.exchange(&1u16, 1, b"", 16).await.unwrap();
+
+        // A request stuck on tunnel 1 leaves tunnel 2 and expiry alone
+        let first = bridge.tunnel(&1u16).await.unwrap();
+        let _busy = first.lock().await;
+        let answer = time::timeout(Duration::from_secs(5), bridge.exchange(&2u16, 1, b"", 16)).await;
+        assert_eq!(answer.unwrap().unwrap(), Some(Vec::new()));
+        bridge.expire_idle();
+    }
+}
@@ -0,0 +1,342 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// Probability of each impairment, from 0.0 to 1.0
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChaosRates {
+    pub drop: f64,
+    pub duplicate: f64,
+    /// Hold a datagram back until the next one has been delivered
+    pub reorder: f64,
+    /// Flip one random bit
+    pub corrupt: f64,
+}
+
+/// What a `Chaos` did so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChaosStats {
+    pub received: u64,
+    pub delivered: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub corrupted: u64,
+}
+
+/// Impairs datagrams like a bad network would, reproducibly from a seed
+pub struct Chaos {
+    rates: ChaosRates,
+    rng: StdRng,
+    held: Option<Vec<u8>>,
+    stats: ChaosStats,
+}
+
+impl Chaos {
+    pub fn new(rates: ChaosRates, seed: u64) -> Self {
+        Self {
+            rates,
+            rng: StdRng::seed_from_u64(seed),
+            held: None,
+            stats: ChaosStats::default(),
+        }
+    }
+
+    /// Datagrams that come out for one going in, in delivery order
+    pub fn pass(&mut self, datagram: &[u8]) -> Vec<Vec<u8>> {
+        self.stats.received += 1;
+        let previous = self.held.take();
+        let mut delivered = Vec::new();
+
+        if self.rng.gen_bool(self.rates.drop) {
+            self.stats.dropped += 1;
+        } else {
+            let mut datagram = datagram.to_vec();
+            if !datagram.is_empty() && self.rng.gen_bool(self.rates.corrupt) {
+                let bit = self.rng.gen_range(0..datagram.len() * 8);
+                datagram[bit / 8] ^= 1 << (bit % 8);
+                self.stats.corrupted += 1;
+            }
+            if self.rng.gen_bool(self.rates.duplicate) {
+                delivered.push(datagram.clone());
+                self.stats.duplicated += 1;
+            }
+            if previous.is_none() && self.rng.gen_bool(self.rates.reorder) {
+                self.held = Some(datagram);
+                self.stats.reordered += 1;
+            } else {
+                delivered.push(datagram);
+            }
+        }
+
+        delivered.extend(previous);
+        self.stats.delivered += delivered.len() as u64;
+        delivered
+    }
+
+    /// Release a datagram still held back for reordering
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        let held = self.held.take();
+        self.stats.delivered += held.is_some() as u64;
+        held
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        self.stats
+    }
+}
+
+/// UDP relay in front of a server, impairing both directions
+///
+/// Clients send to `address()`; answers go back to whichever client sent
+/// last.
+pub struct ChaosProxy {
+    address: SocketAddr,
+    to_server: Arc<Mutex<Chaos>>,
+    to_client: Arc<Mutex<Chaos>>,
+    task: JoinHandle<()>,
+}
+
+impl ChaosProxy {
+    pub async fn start(server: SocketAddr, rates: ChaosRates, seed: u64) -> std::io::Result<Self> {
+        let front = UdpSocket::bind("127.0.0.1:0").await?;
+        let back = UdpSocket::bind("127.0.0.1:0").await?;
+        back.connect(server).await?;
+
+        let address = front.local_addr()?;
+        let to_server = Arc::new(Mutex::new(Chaos::new(rates, seed)));
+        let to_client = Arc::new(Mutex::new(Chaos::new(rates, seed.wrapping_add(1))));
+
+        let (upstream, downstream) = (to_server.clone(), to_client.clone());
+        let task = tokio::spawn(async move {
+            let mut client = None;
+            let mut from_client = vec![0u8; 65536];
+            let mut from_server = vec![0u8; 65536];
+            loop {
+                tokio::select! {
+                    Ok((n, peer)) = front.recv_from(&mut from_client) => {
+                        client = Some(peer);
+                        let delivered = upstream.lock().unwrap().pass(&from_client[..n]);
+                        for datagram in delivered {
+                            let _ = back.send(&datagram).await;
+                        }
+                    }
+                    Ok(n) = back.recv(&mut from_server) => {
+                        let Some(client) = client else { continue };
+                        let delivered = downstream.lock().unwrap().pass(&from_server[..n]);
+                        for datagram in delivered {
+                            let _ = front.send_to(&datagram, client).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            address,
+            to_server,
+            to_client,
+            task,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stats towards the server and towards the client
+    pub fn stats(&self) -> (ChaosStats, ChaosStats) {
+        (
+            self.to_server.lock().unwrap().stats(),
+            self.to_client.lock().unwrap().stats(),
+        )
+    }
+}
+
+impl Drop for ChaosProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::collections::HashSet;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use crate::config::KnockConfig;
+    use crate::core::connection::ConnectionManager;
+    use crate::network::knock::{knock_packet, KnockGate};
+    use crate::network::port_hopping::unix_now;
+    use crate::network::UdpTransport;
+    use crate::protocol::{Packet, PacketType, TransportAttach};
+
+    const LOSSY: ChaosRates = ChaosRates {
+        drop: 0.2,
+        duplicate: 0.1,
+        reorder: 0.1,
+        corrupt: 0.05,
+    };
+
+    fn datagram(packet_type: PacketType, sequence_number: u64, payload: &'static [u8]) -> Vec<u8> {
+        Packet::new_with_metadata(packet_type, 1, sequence_number, Bytes::from_static(payload))
+            .serialize()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_rates_and_reproducibility() {
+        let mut chaos = Chaos::new(LOSSY, 194);
+        let mut delivered: Vec<Vec<u8>> = (0..10_000u64)
+            .flat_map(|i| chaos.pass(&i.to_be_bytes()))
+            .collect();
+        delivered.extend(chaos.flush());
+
+        let stats = chaos.stats();
+        assert_eq!(stats.received, 10_000);
+        assert!((1_800..2_200).contains(&stats.dropped), "{:?}", stats);
+        assert!((600..1_000).contains(&stats.duplicated), "{:?}", stats);
+        assert!((250..550).contains(&stats.corrupted), "{:?}", stats);
+        assert_eq!(stats.delivered, delivered.len() as u64);
+        assert_eq!(stats.delivered, stats.received - stats.dropped + stats.duplicated);
+
+        let out_of_order = delivered
+            .windows(2)
+            .filter(|pair| pair[1] < pair[0])
+            .count();
+        assert!(out_of_order as u64 >= stats.reordered / 2, "{:?}", stats);
+
+        let mut again = Chaos::new(LOSSY, 194);
+        let replayed: Vec<Vec<u8>> = (0..100u64).flat_map(|i| again.pass(&i.to_be_bytes())).collect();
+        assert_eq!(replayed[..], delivered[..replayed.len()]);
+    }
+
+    #[test]
+    fn test_clean_link_is_transparent() {
+        let mut chaos = Chaos::new(ChaosRates::default(), 1);
+        for i in 0..100u64 {
+            assert_eq!(chaos.pass(&i.to_be_bytes()), [i.to_be_bytes().to_vec()]);
+        }
+        assert_eq!(chaos.flush(), None);
+    }
+
+    #[test]
+    fn test_corruption_caught_by_checksum() {
+        let corrupt_all = ChaosRates {
+            corrupt: 1.0,
+            ..ChaosRates::default()
+        };
+        let mut chaos = Chaos::new(corrupt_all, 7);
+        for i in 0..1_000 {
+            for damaged in chaos.pass(&datagram(PacketType::Data, i, b"some payload")) {
+                assert!(Packet::deserialize(&damaged[..]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_knock_replays_rejected() {
+        let config = KnockConfig {
+            enabled: true,
+            secret: "correct horse".to_string(),
+            ..KnockConfig::default()
+        };
+        let gate = KnockGate::new(&config, "127.0.0.1", 8443).unwrap();
+        let duplicating = ChaosRates {
+            duplicate: 0.5,
+            reorder: 0.2,
+            ..ChaosRates::default()
+        };
+        let mut chaos = Chaos::new(duplicating, 11);
+        let client: IpAddr = "198.51.100.4".parse().unwrap();
+
+        let now = unix_now();
+        let mut accepted = 0;
+        for nonce in 0..200u8 {
+            let knock = knock_packet(b"correct horse", now, [nonce; 16]);
+            for delivered in chaos.pass(&knock).into_iter().chain(chaos.flush()) {
+                accepted += gate.knock(client, &delivered).is_ok() as u32;
+            }
+        }
+        assert_eq!(accepted, 200);
+    }
+
+    #[tokio::test]
+    async fn test_transport_counts_intact_datagrams() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let connection = manager.create_connection("198.51.100.4:50000".parse().unwrap()).unwrap();
+        let session_id = *connection.session().id();
+        let transport = UdpTransport::new("127.0.0.1", 0, manager);
+        let token = transport.issue(&session_id);
+        let peer: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+
+        let attach = Packet::new(PacketType::TransportAttach, TransportAttach::new(&token).to_bytes().unwrap());
+        transport.handle(&attach.serialize(), peer).await.unwrap();
+
+        let mut chaos = Chaos::new(LOSSY, 42);
+        let mut intact = 0;
+        let mut seen = HashSet::new();
+        for i in 0..500 {
+            for delivered in chaos.pass(&datagram(PacketType::KeepAlive, i, b"payload")) {
+                let reply = transport.handle(&delivered, peer).await;
+                // Damaged and duplicated datagrams are dropped silently, the rest answered
+                let fresh = Packet::deserialize(&delivered[..])
+                    .is_ok_and(|packet| seen.insert(packet.header.sequence_number));
+                assert_eq!(reply.is_some(), fresh);
+                intact += reply.is_some() as u64;
+            }
+        }
+        if let Some(delivered) = chaos.flush() {
+            intact += transport.handle(&delivered, peer).await.is_some() as u64;
+        }
+
+        let stats = chaos.stats();
+        assert!(intact < stats.delivered && intact > 0);
+        assert_eq!(connection.session().stats().await.packets_received, intact);
+    }
+
+    #[tokio::test]
+    async fn test_attach_through_lossy_proxy() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let connection = manager.create_connection("127.0.0.1:50000".parse().unwrap()).unwrap();
+        let session_id = *connection.session().id();
+        let transport = Arc::new(UdpTransport::new("127.0.0.1", 0, manager));
+        let token = transport.issue(&session_id);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = socket.local_addr().unwrap();
+        let server = transport.clone();
+        let serving = tokio::spawn(async move { server.serve(socket).await });
+
+        let proxy = ChaosProxy::start(server_address, LOSSY, 3).await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let attach = Packet::new(PacketType::TransportAttach, TransportAttach::new(&token).to_bytes().unwrap());
+
+        // Retry like a client would until an intact answer comes back
+        let mut buf = [0u8; 256];
+        let mut attached = false;
+        for _ in 0..50 {
+            client.send_to(&attach.serialize(), proxy.address()).await.unwrap();
+            if let Ok(Ok(n)) = tokio::time::timeout(Duration::from_millis(50), client.recv(&mut buf)).await {
+                if let Ok(reply) = Packet::deserialize(&buf[..n]) {
+                    assert_eq!(reply.header.packet_type, PacketType::TransportAttach);
+                    attached = true;
+                    break;
+                }
+            }
+        }
+        assert!(attached);
+        // The server sees the proxy, not the client
+        let bound = transport.bound_address(&session_id).unwrap();
+        assert_ne!(bound, client.local_addr().unwrap());
+        assert!(proxy.stats().0.received >= 1);
+
+        serving.abort();
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::sync::OnceLock;
+
+use crate::core::session::SessionId;
+use crate::error::{LostLoveError, Result};
+use crate::network::router::PacketRouter;
+#[cfg(target_os = "linux")]
+use crate::network::tun_device::TunDevice;
+
+/// Tunneled packets between the transports and the TUN device
+///
+/// Client packets pass the router's checks and are written to the device,
+/// or relayed to the client holding their destination. Packets read from
+/// the device go through the router to the connection of the session
+/// holding their destination, whose transport sends them on.
+pub struct DataPath {
+    router: Arc<PacketRouter>,
+    #[cfg(target_os = "linux")]
+    device: OnceLock<Arc<TunDevice>>,
+}
+
+impl DataPath {
+    pub fn new(router: PacketRouter) -> Self {
+        Self {
+            router: Arc::new(router),
+            #[cfg(target_os = "linux")]
+            device: OnceLock::new(),
+        }
+    }
+
+    pub fn router(&self) -> &Arc<PacketRouter> {
+        &self.router
+    }
+
+    /// Write client packets to `device` from now on, once
+    #[cfg(target_os = "linux")]
+    pub fn attach(&self, device: Arc<TunDevice>) -> Result<()> {
+        self.device
+            .set(device)
+            .map_err(|_| LostLoveError::Network("Data path already has a TUN device".to_string()))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn device(&self) -> Option<&Arc<TunDevice>> {
+        self.device.get()
+    }
+
+    /// Forward a packet a client sent through the tunnel
+    pub async fn forward(&self, session_id: &SessionId, packet: &[u8]) -> Result<()> {
+        let packet = self.router.route_to_tun(packet, session_id).await?;
+        if let Some(target) = self.router.relay_target(&packet, session_id) {
+            return self.router.route_p2p(&packet, session_id, &target).await;
+        }
+        self.write(session_id, &packet).await
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn write(&self, session_id: &SessionId, packet: &[u8]) -> Result<()> {
+        let device = self
+            .device
+            .get()
+            .ok_or_else(|| LostLoveError::Network("No TUN device yet".to_string()))?;
+        let queues = device
+            .queues()
+            .ok_or_else(|| LostLoveError::Network(format!("TUN interface {} is being re-created", device.name())))?;
+        // Sticking to one queue keeps a session's packets in order
+        let queue = session_id.as_bytes()[0] as usize % queues.len();
+        queues.write(queue, packet).await?;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn write(&self, _session_id: &SessionId, _packet: &[u8]) -> Result<()> {
+        Err(LostLoveError::Network("No TUN device on this platform".to_string()))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::Mutex;
+
+    use crate::config::RelayConfig;
+    use crate::core::connection::ConnectionManager;
+    use crate::core::session::SessionEvent;
+    use crate::network::inner_packet::tests::ipv4_packet;
+    use crate::network::relay::Relay;
+    use crate::network::tun_queue::TunQueues;
+    use crate::network::IpPool;
+
+    #[tokio::test]
+    async fn test_client_packets_reach_device_or_peer() {
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let alice = manager.create_connection(addr).unwrap();
+        let bob = manager.create_connection(addr).unwrap();
+        bob.session().apply(SessionEvent::HandshakeCompleted).await.unwrap();
+        let mut bob_tunnel = bob.open_tunnel().unwrap();
+
+        let relay = Relay::new(&RelayConfig {
+            enabled: true,
+            rate_limit_per_path: 1_000_000,
+            burst_per_path: None,
+        })
+        .unwrap();
+        let data_path = DataPath::new(PacketRouter::new(manager.clone()).with_relay(Arc::new(relay)));
+        let to_internet = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (5000, 53));
+        assert!(data_path.forward(alice.session().id(), &to_internet).await.is_err());
+
+        let (queue, peer) = UnixDatagram::pair().unwrap();
+        queue.set_nonblocking(true).unwrap();
+        let queue = Mutex::new(Some(OwnedFd::from(queue)));
+        let opener = move || TunQueues::from_fds("llp-test", 1400, queue.lock().unwrap().take().into_iter().collect());
+        data_path
+            .attach(Arc::new(TunDevice::with_opener("llp-test", opener, Vec::new).unwrap()))
+            .unwrap();
+
+        data_path.forward(alice.session().id(), &to_internet).await.unwrap();
+        let mut buf = [0u8; 1500];
+        let len = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &to_internet[..]);
+
+        // Another client's address is relayed instead
+        let to_bob = ipv4_packet(17, [10, 8, 0, 2], [10, 8, 0, 3], (5000, 5000));
+        data_path.forward(alice.session().id(), &to_bob).await.unwrap();
+        assert_eq!(bob_tunnel.try_recv().unwrap(), &to_bob[..]);
+
+        // Spoofed sources go nowhere
+        let spoofed = ipv4_packet(17, [10, 8, 0, 9], [1, 1, 1, 1], (5000, 53));
+        assert!(data_path.forward(alice.session().id(), &spoofed).await.is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::network::port_hopping::unix_now;
+use crate::protocol::descriptor::{
+    Endpoint, EndpointTransport, ServerDescriptor, SignedDescriptor, DESCRIPTOR_VERSION,
+};
+
+/// Path the signed descriptor is served at
+pub const DESCRIPTOR_PATH: &str = "/descriptor.json";
+
+/// Maximum size of an HTTP request head we are willing to buffer
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Serves the signed server descriptor over plain HTTP
+///
+/// The signature, not the transport, is what clients trust, so the
+/// document may also be mirrored to any static host. It holds nothing
+/// secret: the obfuscation key is only handed out in client profiles.
+pub struct DescriptorServer {
+    address: String,
+    key: Vec<u8>,
+    validity: u64,
+    endpoints: Vec<Endpoint>,
+}
+
+impl DescriptorServer {
+    /// Create from `[server.descriptor]`, None when it is disabled
+    pub fn new(config: &Config) -> Option<Self> {
+        let descriptor = &config.server.descriptor;
+        if !descriptor.enabled {
+            return None;
+        }
+
+        Some(Self {
+            address: descriptor.address.clone(),
+            key: hex::decode(&descriptor.key).ok()?,
+            validity: descriptor.validity,
+            endpoints: endpoints(config, &descriptor.hosts),
+        })
+    }
+
+    /// Descriptor issued at unix time `now`
+    pub fn descriptor(&self, now: u64) -> ServerDescriptor {
+        ServerDescriptor {
+            version: DESCRIPTOR_VERSION,
+            issued_at: now,
+            expires_at: now + self.validity,
+            endpoints: self.endpoints.clone(),
+        }
+    }
+
+    /// Serve the descriptor until the task is cancelled
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let listener = TcpListener::bind(&self.address).await?;
+        info!("Server descriptor available at http://{}{}", self.address, DESCRIPTOR_PATH);
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = server.serve(stream).await {
+                            debug!("Descriptor request from {} failed: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to accept descriptor connection: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Answer a single HTTP request
+    async fn serve(&self, mut stream: TcpStream) -> Result<()> {
+        let mut request = Vec::with_capacity(1024);
+        let mut buf = [0u8; 1024];
+
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        let request_line = String::from_utf8_lossy(&request);
+        let path = request_line.split_whitespace().nth(1).unwrap_or("");
+
+        let response = if path == DESCRIPTOR_PATH {
+            let body = SignedDescriptor::sign(&self.descriptor(unix_now()), &self.key)?.to_json()?;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nCache-Control: no-cache\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        };
+
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Endpoints of every public host, TCP first since handshakes run over it
+pub(crate) fn endpoints(config: &Config, hosts: &[String]) -> Vec<Endpoint> {
+    let server = &config.server;
+    let mut endpoints = Vec::new();
+    for host in hosts {
+        let endpoint = |port, transport| Endpoint {
+            host: host.clone(),
+            port,
+            transport,
+        };
+        endpoints.push(endpoint(server.port, EndpointTransport::Tcp));
+        // Hopping ports are derived from the shared secret, not published
+        if server.protocol != "tcp" && !server.port_hopping.enabled {
+            endpoints.push(endpoint(server.port, EndpointTransport::Udp));
+        }
+        if config.network.icmp.enabled {
+            endpoints.push(endpoint(0, EndpointTransport::Icmp));
+        }
+    }
+    if config.network.dns.enabled {
+        endpoints.push(Endpoint {
+            host: config.network.dns.zone.clone(),
+            port: config.network.dns.port,
+            transport: EndpointTransport::Dns,
+        });
+    }
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::descriptor::public_key;
+
+    fn descriptor_config() -> Config {
+        let mut config = Config::default_for_testing();
+        config.server.obfuscation.enabled = true;
+        config.server.obfuscation.server_key = "33".repeat(32);
+        config.server.protocol = "both".to_string();
+        config.server.descriptor.enabled = true;
+        config.server.descriptor.address = "127.0.0.1:0".to_string();
+        config.server.descriptor.hosts = vec!["vpn.example.org".to_string(), "203.0.113.5".to_string()];
+        config.server.descriptor.key = "11".repeat(32);
+        config
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let server = DescriptorServer::new(&descriptor_config()).unwrap();
+        let descriptor = server.descriptor(1_000);
+
+        assert_eq!(descriptor.expires_at, 1_000 + 86_400);
+        let listed: Vec<_> = descriptor
+            .endpoints
+            .iter()
+            .map(|endpoint| (endpoint.host.as_str(), endpoint.transport))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("vpn.example.org", EndpointTransport::Tcp),
+                ("vpn.example.org", EndpointTransport::Udp),
+                ("203.0.113.5", EndpointTransport::Tcp),
+                ("203.0.113.5", EndpointTransport::Udp),
+            ]
+        );
+
+        assert!(DescriptorServer::new(&Config::default_for_testing()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_serve_signed_descriptor() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = DescriptorServer::new(&descriptor_config()).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.serve(stream).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /descriptor.json HTTP/1.1\r\nHost: vpn.example.org\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        let signed = SignedDescriptor::from_json(body.as_bytes()).unwrap();
+        assert!(!signed.payload.contains(&"33".repeat(32)));
+        let key = public_key(&[0x11; 32]).unwrap();
+        let descriptor = signed.verify(&key, unix_now()).unwrap();
+        assert_eq!(descriptor.endpoints.len(), 4);
+    }
+}
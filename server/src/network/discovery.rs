@@ -0,0 +1,114 @@
+use bytes::Bytes;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+use crate::error::Result;
+use crate::protocol::address_discovery::{AddressReport, Transport};
+use crate::protocol::{Packet, PacketType};
+
+/// Largest request datagram read
+const MAX_REQUEST_SIZE: usize = 512;
+
+/// UDP endpoint telling clients their public address and port (STUN-like)
+///
+/// Unauthenticated, so an answer is never larger than its request: clients
+/// pad the request payload (128 bytes is enough) and spoofed requests can't
+/// be used for amplification.
+pub struct DiscoveryServer {
+    address: String,
+}
+
+impl DiscoveryServer {
+    /// Create endpoint listening on `address`
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+        }
+    }
+
+    /// Answer requests until the task is cancelled
+    pub async fn run(self) -> Result<()> {
+        let socket = UdpSocket::bind(&self.address).await?;
+        info!("Address discovery listening on udp://{}", self.address);
+
+        let mut buf = [0u8; MAX_REQUEST_SIZE];
+        loop {
+            let (n, peer) = socket.recv_from(&mut buf).await?;
+
+            match answer(&buf[..n], peer) {
+                Some(reply) => {
+                    if let Err(e) = socket.send_to(&reply, peer).await {
+                        debug!("Failed to answer address discovery from {}: {}", peer, e);
+                    }
+                }
+                None => debug!("Ignoring {} byte datagram from {}", n, peer),
+            }
+        }
+    }
+}
+
+/// Reply to one request datagram, None if it isn't a valid, large enough request
+pub fn answer(request: &[u8], peer: SocketAddr) -> Option<Bytes> {
+    let packet = Packet::deserialize(request).ok()?;
+    if packet.header.packet_type != PacketType::AddressDiscovery {
+        return None;
+    }
+
+    let report = AddressReport::new(peer, Transport::Udp).to_bytes().ok()?;
+    let reply = Packet::new(PacketType::AddressDiscovery, report).serialize().freeze();
+    (reply.len() <= request.len()).then_some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(padding: usize) -> Vec<u8> {
+        Packet::new(PacketType::AddressDiscovery, Bytes::from(vec![0u8; padding]))
+            .serialize()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_answer_reports_peer() {
+        let peer: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let reply = answer(&request(128), peer).unwrap();
+
+        let packet = Packet::deserialize(&reply[..]).unwrap();
+        let report = AddressReport::from_bytes(&packet.payload).unwrap();
+        assert_eq!(report, AddressReport::new(peer, Transport::Udp));
+    }
+
+    #[test]
+    fn test_no_amplification() {
+        let peer: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        assert!(answer(&request(0), peer).is_none());
+        assert!(answer(b"garbage", peer).is_none());
+
+        let keepalive = Packet::new(PacketType::KeepAlive, Bytes::from(vec![0u8; 128]));
+        assert!(answer(&keepalive.serialize(), peer).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_udp_endpoint() {
+        let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = probe.local_addr().unwrap();
+        drop(probe);
+
+        tokio::spawn(DiscoveryServer::new(address.to_string()).run());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&request(128), address).await.unwrap();
+
+        let mut buf = [0u8; MAX_REQUEST_SIZE];
+        let (n, _) = tokio::time::timeout(std::time::Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = Packet::deserialize(&buf[..n]).unwrap();
+        let report = AddressReport::from_bytes(&packet.payload).unwrap();
+        assert_eq!(report.address, client.local_addr().unwrap());
+    }
+}
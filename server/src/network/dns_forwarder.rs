@@ -0,0 +1,562 @@
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::config::{BlockResponse, Config};
+use crate::core::connection::ConnectionManager;
+use crate::error::{LostLoveError, Result};
+use crate::metrics::writer::MetricsWriter;
+use crate::network::dns_tunnel::{
+    parse_question, Question, CLASS_IN, MAX_MESSAGE_SIZE, RCODE_NXDOMAIN, RCODE_SERVFAIL,
+};
+use crate::network::dns_upstream::{Upstream, MAX_RELAYED_SIZE};
+
+/// TTL of the answers for blocked names
+const BLOCKED_TTL: u32 = 300;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// Names hosts files map for the local machine, never blocked
+const HOSTS_BUILTINS: [&str; 6] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// Domains to block, each along with its subdomains
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    domains: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Domains of a hosts file (`0.0.0.0 ads.example.com`) or a plain list
+    /// of one domain per line, `#` starting a comment in either
+    pub fn parse(text: &str) -> Self {
+        let mut list = Self::default();
+        list.extend(text);
+        list
+    }
+
+    /// Domains of all `paths` together
+    pub fn load(paths: &[String]) -> Result<Self> {
+        let mut list = Self::default();
+        for path in paths {
+            let text = std::fs::read_to_string(path).map_err(|e| {
+                LostLoveError::Config(format!("Failed to read blocklist {}: {}", path, e))
+            })?;
+            list.extend(&text);
+        }
+        Ok(list)
+    }
+
+    fn extend(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace().peekable();
+            // The address of a hosts line doesn't matter, every name on it is blocked
+            if words
+                .peek()
+                .is_some_and(|word| word.parse::<IpAddr>().is_ok())
+            {
+                words.next();
+            }
+            for name in words {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                if !name.is_empty() && !HOSTS_BUILTINS.contains(&name.as_str()) {
+                    self.domains.insert(name);
+                }
+            }
+        }
+    }
+
+    /// Whether the name of `labels` or a domain above it is listed
+    pub fn blocks(&self, labels: &[String]) -> bool {
+        !self.domains.is_empty()
+            && (0..labels.len()).any(|start| self.domains.contains(&labels[start..].join(".")))
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+}
+
+/// DNS forwarder on the tunnel address, for clients pushed that address as
+/// their resolver
+///
+/// Queries go to the upstream resolvers in order until one answers, and are
+/// answered with SERVFAIL if none does. Upstreams may be plain UDP, DoT or
+/// DoH, so queries can stay encrypted beyond the server too. Names on the blocklists are answered
+/// right away, with NXDOMAIN or a null address, for sessions whose user has
+/// blocking on. The lists are read again every `blocklist_refresh` seconds,
+/// keeping the previous ones if that fails.
+pub struct DnsForwarder {
+    address: SocketAddr,
+    upstream: Vec<Upstream>,
+    block_response: BlockResponse,
+    block_by_default: bool,
+    /// Users whose policy sets `dns_blocking`
+    user_blocking: BTreeMap<String, bool>,
+    blocklist_paths: Vec<String>,
+    refresh: Duration,
+    blocklist: RwLock<Arc<Blocklist>>,
+    connection_manager: Arc<ConnectionManager>,
+    forwarded: AtomicU64,
+    blocked: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl DnsForwarder {
+    /// Forwarder of `config.resolver`, answering on `server_address` port 53
+    /// unless it sets an address
+    pub fn new(
+        config: &Config,
+        server_address: IpAddr,
+        connection_manager: Arc<ConnectionManager>,
+    ) -> Result<Self> {
+        let resolver = &config.resolver;
+        let address = if resolver.listen.is_empty() {
+            SocketAddr::new(server_address, 53)
+        } else {
+            parse_address("resolver.listen", &resolver.listen)?
+        };
+        let upstream = resolver
+            .upstream
+            .iter()
+            .map(|upstream| Upstream::parse(upstream))
+            .collect::<Result<_>>()?;
+        let user_blocking = config
+            .policies
+            .iter()
+            .filter_map(|(user, policy)| Some((user.clone(), policy.dns_blocking?)))
+            .collect();
+
+        Ok(Self {
+            address,
+            upstream,
+            block_response: resolver.block_response,
+            block_by_default: resolver.block_by_default,
+            user_blocking,
+            blocklist_paths: resolver.blocklists.clone(),
+            refresh: Duration::from_secs(resolver.blocklist_refresh),
+            blocklist: RwLock::new(Arc::new(Blocklist::load(&resolver.blocklists)?)),
+            connection_manager,
+            forwarded: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    fn blocklist(&self) -> Arc<Blocklist> {
+        self.blocklist.read().unwrap().clone()
+    }
+
+    /// Read the blocklists again, keeping the current ones on failure
+    pub fn reload(&self) {
+        match Blocklist::load(&self.blocklist_paths) {
+            Ok(blocklist) => {
+                debug!("Blocking {} domains", blocklist.len());
+                *self.blocklist.write().unwrap() = Arc::new(blocklist);
+            }
+            Err(e) => warn!(code = e.code(), "Keeping the previous blocklists: {}", e),
+        }
+    }
+
+    /// Answer queries until the task is cancelled
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let socket = Arc::new(UdpSocket::bind(self.address).await?);
+        info!(
+            "DNS forwarder on udp://{} blocking {} domains",
+            self.address,
+            self.blocklist().len()
+        );
+
+        let mut buf = [0u8; MAX_RELAYED_SIZE];
+        let mut refresh = time::interval_at(time::Instant::now() + self.refresh, self.refresh);
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (n, peer) = received?;
+                    let query = buf[..n].to_vec();
+                    let forwarder = self.clone();
+                    let socket = socket.clone();
+                    // Upstream round trips overlap instead of queueing
+                    tokio::spawn(async move {
+                        let Some(reply) = forwarder.handle(&query, peer.ip()).await else {
+                            debug!("Ignoring {} byte datagram from {}", query.len(), peer);
+                            return;
+                        };
+                        if let Err(e) = socket.send_to(&reply, peer).await {
+                            debug!("Failed to answer DNS query from {}: {}", peer, e);
+                        }
+                    });
+                }
+                _ = refresh.tick() => self.reload(),
+            }
+        }
+    }
+
+    /// Answer one query of `client`, None if it isn't a query at all
+    pub async fn handle(&self, query: &[u8], client: IpAddr) -> Option<Vec<u8>> {
+        // Standard queries only: QR clear, opcode 0, exactly one question
+        if query.len() < 12 || query[2] & 0xF8 != 0 || query[4..6] != [0, 1] {
+            return None;
+        }
+        let question = parse_question(query)?;
+
+        if self.blocklist().blocks(&question.labels) && self.blocking_for(client).await {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+            debug!("Blocked {} for {}", question.labels.join("."), client);
+            return Some(blocked_response(query, &question, self.block_response));
+        }
+
+        match self.forward(query).await {
+            Some(reply) => {
+                self.forwarded.fetch_add(1, Ordering::Relaxed);
+                Some(reply)
+            }
+            None => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                Some(answer(query, &question, RCODE_SERVFAIL, None))
+            }
+        }
+    }
+
+    /// Whether the user of the session leasing `client` has blocking on
+    async fn blocking_for(&self, client: IpAddr) -> bool {
+        let user = async {
+            let IpAddr::V4(address) = client else {
+                return None;
+            };
+            let session_id = self.connection_manager.ip_pool()?.lease_holder(address)?;
+            let connection = self.connection_manager.get_connection(&session_id)?;
+            let user = connection
+                .handshake()
+                .read()
+                .await
+                .user()
+                .map(str::to_string);
+            user
+        }
+        .await;
+
+        user.and_then(|user| self.user_blocking.get(&user).copied())
+            .unwrap_or(self.block_by_default)
+    }
+
+    /// Upstream's answer to `query`, None if no resolver answered
+    async fn forward(&self, query: &[u8]) -> Option<Vec<u8>> {
+        for upstream in &self.upstream {
+            match upstream.exchange(query).await {
+                Ok(reply) => return Some(reply),
+                Err(e) => debug!("Resolver {} failed: {}", upstream.name(), e),
+            }
+        }
+        None
+    }
+
+    pub fn render(&self, writer: &mut MetricsWriter) {
+        writer.header(
+            "llp_dns_queries_total",
+            "Queries to the DNS forwarder, by outcome",
+            "counter",
+        );
+        for (result, count) in [
+            ("forwarded", &self.forwarded),
+            ("blocked", &self.blocked),
+            ("failed", &self.failed),
+        ] {
+            writer.sample(
+                "llp_dns_queries_total",
+                &[("result", result)],
+                count.load(Ordering::Relaxed),
+            );
+        }
+        writer.gauge(
+            "llp_dns_blocklist_domains",
+            "Domains on the DNS forwarder's blocklists",
+            self.blocklist().len(),
+        );
+
+        writer.header(
+            "llp_dns_upstream_seconds",
+            "Time upstream resolvers took to answer, by upstream",
+            "histogram",
+        );
+        for upstream in &self.upstream {
+            writer.histogram_series(
+                "llp_dns_upstream_seconds",
+                &[("upstream", upstream.name())],
+                upstream.latency(),
+            );
+        }
+        writer.header(
+            "llp_dns_upstream_failures_total",
+            "Queries upstream resolvers failed or didn't answer in time, by upstream",
+            "counter",
+        );
+        for upstream in &self.upstream {
+            writer.sample(
+                "llp_dns_upstream_failures_total",
+                &[("upstream", upstream.name())],
+                upstream.failures(),
+            );
+        }
+    }
+}
+
+fn parse_address(name: &str, address: &str) -> Result<SocketAddr> {
+    address.parse().map_err(|_| {
+        LostLoveError::Config(format!("{} must be an ip:port, got {:?}", name, address))
+    })
+}
+
+/// Answer for a blocked name
+fn blocked_response(query: &[u8], question: &Question, block_response: BlockResponse) -> Vec<u8> {
+    match (block_response, question.qtype) {
+        (BlockResponse::Nxdomain, _) => answer(query, question, RCODE_NXDOMAIN, None),
+        (BlockResponse::Null, TYPE_A) => answer(query, question, 0, Some(&[0; 4])),
+        (BlockResponse::Null, TYPE_AAAA) => answer(query, question, 0, Some(&[0; 16])),
+        (BlockResponse::Null, _) => answer(query, question, 0, None),
+    }
+}
+
+/// Answer to `query` from the forwarder itself, echoing its question, with
+/// one record of the question's type if `rdata` is set
+fn answer(query: &[u8], question: &Question, rcode: u8, rdata: Option<&[u8]>) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MAX_MESSAGE_SIZE);
+    message.extend_from_slice(&query[..2]);
+    // QR set and RD copied from the query, RA set as a recursive resolver
+    message.push(0x80 | (query[2] & 0x01));
+    message.push(0x80 | rcode);
+    message.extend_from_slice(&1u16.to_be_bytes());
+    message.extend_from_slice(&(rdata.is_some() as u16).to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0]);
+    message.extend_from_slice(&query[12..question.end]);
+
+    if let Some(rdata) = rdata {
+        message.extend_from_slice(&0xC00Cu16.to_be_bytes());
+        message.extend_from_slice(&question.qtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&BLOCKED_TTL.to_be_bytes());
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend_from_slice(rdata);
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserPolicy;
+    use crate::network::IpPool;
+    use crate::protocol::Handshake;
+    use std::net::Ipv4Addr;
+
+    const HOSTS: &str = "\
+# Ad servers
+0.0.0.0 ads.example.com tracker.example.net
+127.0.0.1 localhost
+::1 ip6-localhost ip6-loopback
+metrics.example.org.   # a plain list line
+";
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = Vec::new();
+        query.extend_from_slice(&id.to_be_bytes());
+        query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    fn labels(name: &str) -> Vec<String> {
+        name.split('.').map(str::to_string).collect()
+    }
+
+    /// Forwarder with the test blocklist and an upstream that answers
+    /// every query with its own bytes and the QR bit set
+    async fn forwarder(config: &mut Config, manager: Arc<ConnectionManager>) -> DnsForwarder {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_address = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = upstream.recv_from(&mut buf).await {
+                buf[2] |= 0x80;
+                let _ = upstream.send_to(&buf[..n], peer).await;
+            }
+        });
+
+        let blocklist =
+            std::env::temp_dir().join(format!("llp-blocklist-{}.hosts", uuid::Uuid::new_v4()));
+        std::fs::write(&blocklist, HOSTS).unwrap();
+        config.resolver.enabled = true;
+        config.resolver.upstream = vec![upstream_address.to_string()];
+        config.resolver.blocklists = vec![blocklist.to_string_lossy().into_owned()];
+        let forwarder =
+            DnsForwarder::new(config, IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1)), manager).unwrap();
+        std::fs::remove_file(blocklist).unwrap();
+        forwarder
+    }
+
+    #[test]
+    fn test_blocklist_parsing() {
+        let list = Blocklist::parse(HOSTS);
+        assert_eq!(list.len(), 3);
+        assert!(list.blocks(&labels("ads.example.com")));
+        assert!(list.blocks(&labels("eu.tracker.example.net")));
+        assert!(list.blocks(&labels("metrics.example.org")));
+        assert!(!list.blocks(&labels("example.com")));
+        assert!(!list.blocks(&labels("localhost")));
+        assert!(!Blocklist::default().blocks(&labels("ads.example.com")));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_and_forwarded_queries() {
+        let mut config = Config::default_for_testing();
+        let forwarder = forwarder(&mut config, Arc::new(ConnectionManager::new(10))).await;
+        assert_eq!(forwarder.address(), "10.8.0.1:53".parse().unwrap());
+        let client = IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2));
+
+        let blocked = forwarder
+            .handle(&query(7, "ads.example.com", TYPE_A), client)
+            .await
+            .unwrap();
+        assert_eq!(&blocked[..2], &7u16.to_be_bytes());
+        assert_eq!(blocked[3] & 0x0F, RCODE_NXDOMAIN);
+
+        let allowed = query(8, "www.example.com", TYPE_A);
+        let reply = forwarder.handle(&allowed, client).await.unwrap();
+        assert_eq!(reply[2] & 0x80, 0x80);
+        assert_eq!(reply[3..], allowed[3..]);
+
+        assert_eq!(forwarder.handle(b"not dns", client).await, None);
+
+        let mut writer = MetricsWriter::new();
+        forwarder.render(&mut writer);
+        let output = writer.finish();
+        assert!(
+            output.contains("llp_dns_queries_total{result=\"blocked\"} 1\n"),
+            "{}",
+            output
+        );
+        assert!(output.contains("llp_dns_queries_total{result=\"forwarded\"} 1\n"));
+        assert!(output.contains("llp_dns_blocklist_domains 3\n"));
+        let upstream = &config.resolver.upstream[0];
+        assert!(output.contains(&format!(
+            "llp_dns_upstream_seconds_count{{upstream=\"{}\"}} 1\n",
+            upstream
+        )));
+        assert!(output.contains(&format!(
+            "llp_dns_upstream_failures_total{{upstream=\"{}\"}} 0\n",
+            upstream
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_null_answers() {
+        let mut config = Config::default_for_testing();
+        config.resolver.block_response = BlockResponse::Null;
+        let forwarder = forwarder(&mut config, Arc::new(ConnectionManager::new(10))).await;
+        let client = IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2));
+
+        let reply = forwarder
+            .handle(&query(9, "ads.example.com", TYPE_AAAA), client)
+            .await
+            .unwrap();
+        assert_eq!(reply[3] & 0x0F, 0);
+        assert_eq!(reply[6..8], [0, 1]);
+        assert!(reply.ends_with(&[0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+
+        // Other types get no records at all
+        let reply = forwarder
+            .handle(&query(10, "ads.example.com", 16), client)
+            .await
+            .unwrap();
+        assert_eq!((reply[3] & 0x0F, &reply[6..8]), (0, &[0, 0][..]));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_per_user() {
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 40000);
+        let conn = manager.create_connection(peer).unwrap();
+        let hello = Handshake::new_client()
+            .with_user("alice")
+            .generate_client_hello()
+            .unwrap();
+        conn.handshake()
+            .write()
+            .await
+            .process_client_hello(&hello)
+            .unwrap();
+        let lease = conn.session().tunnel_address().unwrap();
+
+        let mut config = Config::default_for_testing();
+        config.policies.insert(
+            "alice".to_string(),
+            UserPolicy {
+                dns_blocking: Some(false),
+                ..UserPolicy::default()
+            },
+        );
+        let forwarder = forwarder(&mut config, manager).await;
+
+        // Alice opted out, everyone else gets the default
+        let reply = forwarder
+            .handle(&query(11, "ads.example.com", TYPE_A), lease)
+            .await
+            .unwrap();
+        assert_eq!(reply[2] & 0x80, 0x80);
+        assert_eq!(reply[3] & 0x0F, 0);
+        assert!(
+            forwarder
+                .blocking_for(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 99)))
+                .await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_upstream_fails() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default_for_testing();
+        let mut forwarder = forwarder(&mut config, Arc::new(ConnectionManager::new(10))).await;
+        let silent = silent.local_addr().unwrap().to_string();
+        forwarder.upstream = vec![Upstream::parse(&silent).unwrap()];
+
+        let reply = forwarder
+            .handle(
+                &query(12, "www.example.com", TYPE_A),
+                IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply[3] & 0x0F, RCODE_SERVFAIL);
+        assert_eq!(forwarder.failed.load(Ordering::Relaxed), 1);
+        assert_eq!(forwarder.upstream[0].failures(), 1);
+    }
+}
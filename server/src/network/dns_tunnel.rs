@@ -0,0 +1,402 @@
+use bytes::Buf;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{debug, info};
+
+use crate::config::DnsTunnelConfig;
+use crate::core::memory::MemoryBudget;
+use crate::error::{LostLoveError, Result};
+use crate::network::bridge::LoopbackBridge;
+
+/// Longest domain name (RFC 1035)
+const MAX_NAME_LEN: usize = 253;
+
+/// Longest label of a domain name
+const MAX_LABEL_LEN: usize = 63;
+
+/// Message size every resolver handles, without EDNS
+pub(crate) const MAX_MESSAGE_SIZE: usize = 512;
+
+/// Longest tunnel ID label
+const MAX_TUNNEL_ID_LEN: usize = 16;
+
+const TYPE_TXT: u16 = 16;
+pub(crate) const CLASS_IN: u16 = 1;
+
+pub(crate) const RCODE_SERVFAIL: u8 = 2;
+pub(crate) const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_REFUSED: u8 = 5;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encode bytes as unpadded lowercase base32, the alphabet DNS names survive
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    out
+}
+
+/// Decode unpadded base32 in either case, None on foreign characters
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in text.bytes() {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+/// Query name carrying `data` upstream: `<data labels>.<seq>.<tunnel>.<zone>`
+///
+/// `seq` is in hex and must change for every new query of a tunnel; a query
+/// repeating the previous sequence number is treated as a resolver retry.
+pub fn query_name(tunnel: &str, seq: u32, data: &[u8], zone: &str) -> Result<String> {
+    let encoded = base32_encode(data);
+    let mut labels: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(MAX_LABEL_LEN)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap())
+        .collect();
+
+    let seq = format!("{:x}", seq);
+    labels.extend([seq.as_str(), tunnel, zone.trim_end_matches('.')]);
+
+    let name = labels.join(".");
+    if name.len() > MAX_NAME_LEN {
+        return Err(LostLoveError::Network(format!(
+            "{} bytes don't fit in one query name",
+            data.len()
+        )));
+    }
+    Ok(name)
+}
+
+/// The question of a query
+pub(crate) struct Question {
+    pub(crate) labels: Vec<String>,
+    pub(crate) qtype: u16,
+    /// Offset right after the question in the message
+    pub(crate) end: usize,
+}
+
+pub(crate) fn parse_question(message: &[u8]) -> Option<Question> {
+    let mut labels = Vec::new();
+    let mut offset = 12;
+
+    loop {
+        let len = *message.get(offset)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers never appear in a lone question
+        if len > MAX_LABEL_LEN {
+            return None;
+        }
+        let label = message.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += len;
+    }
+
+    let mut fixed = message.get(offset..offset + 4)?;
+    let qtype = fixed.get_u16();
+    let qclass = fixed.get_u16();
+    (qclass == CLASS_IN).then_some(Question {
+        labels,
+        qtype,
+        end: offset + 4,
+    })
+}
+
+/// Answer to `query`, echoing its question, with one TXT record if `txt` is set
+fn response(query: &[u8], question_end: usize, rcode: u8, txt: Option<&[u8]>) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MAX_MESSAGE_SIZE);
+    message.extend_from_slice(&query[..2]);
+    // QR and AA set, RD copied from the query
+    message.push(0x84 | (query[2] & 0x01));
+    message.push(rcode);
+    message.extend_from_slice(&1u16.to_be_bytes());
+    message.extend_from_slice(&(txt.is_some() as u16).to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0]);
+    message.extend_from_slice(&query[12..question_end]);
+
+    if let Some(txt) = txt {
+        message.extend_from_slice(&0xC00Cu16.to_be_bytes());
+        message.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes());
+        message.extend_from_slice(&(txt.len() as u16 + 1).to_be_bytes());
+        message.push(txt.len() as u8);
+        message.extend_from_slice(txt);
+    }
+
+    message
+}
+
+/// Server bytes of a response fitting in one message after the question
+fn downstream_budget(question_end: usize) -> usize {
+    // Answer: name pointer, type, class, TTL, length and string length
+    let overhead = question_end + 2 + 2 + 2 + 4 + 2 + 1;
+    MAX_MESSAGE_SIZE.saturating_sub(overhead).min(u8::MAX as usize)
+}
+
+/// Experimental DNS transport for networks that let nothing else out
+///
+/// The server acts as the authoritative name server of `zone`. Clients send
+/// TXT queries whose names carry base32 encoded bytes of an ordinary LLP
+/// connection; answers carry the server's bytes as raw TXT strings. Empty
+/// queries poll, tunnels are bridged to the listener by `LoopbackBridge`.
+pub struct DnsTunnelServer {
+    address: String,
+    zone: Vec<String>,
+    bridge: LoopbackBridge<String>,
+}
+
+impl DnsTunnelServer {
+    /// Create the name server on `address`, bridging tunnels to `target`
+    pub fn new(address: impl Into<String>, target: SocketAddr, config: &DnsTunnelConfig) -> Self {
+        Self {
+            address: address.into(),
+            zone: config
+                .zone
+                .trim_end_matches('.')
+                .split('.')
+                .map(str::to_ascii_lowercase)
+                .collect(),
+            bridge: LoopbackBridge::new(
+                "DNS",
+                target,
+                config.max_tunnels,
+                Duration::from_secs(config.idle_timeout),
+            ),
+        }
+    }
+
+    /// Charge buffered server bytes to `memory`
+    pub fn with_memory(mut self, memory: Arc<MemoryBudget>) -> Self {
+        self.bridge = self.bridge.with_memory(memory);
+        self
+    }
+
+    /// Whether a connection to the listener comes from a DNS tunnel
+    pub fn is_tunnel(&self, peer: SocketAddr) -> bool {
+        self.bridge.is_tunnel(peer)
+    }
+
+    /// Answer queries until the task is cancelled
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let socket = UdpSocket::bind(&self.address).await?;
+        info!(
+            "DNS transport answering for {} on udp://{}",
+            self.zone.join("."),
+            self.address
+        );
+
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let mut expiry = time::interval(self.bridge.idle_timeout() / 2);
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (n, peer) = received?;
+                    match self.handle(&buf[..n]).await {
+                        Some(reply) => {
+                            if let Err(e) = socket.send_to(&reply, peer).await {
+                                debug!("Failed to answer DNS query from {}: {}", peer, e);
+                            }
+                        }
+                        None => debug!("Ignoring {} byte datagram from {}", n, peer),
+                    }
+                }
+                _ = expiry.tick() => self.bridge.expire_idle(),
+            }
+        }
+    }
+
+    /// Answer one query message, None if it isn't a query at all
+    pub async fn handle(&self, query: &[u8]) -> Option<Vec<u8>> {
+        // Standard queries only: QR clear, opcode 0, exactly one question
+        if query.len() < 12 || query[2] & 0xF8 != 0 || query[4..6] != [0, 1] {
+            return None;
+        }
+        let question = parse_question(query)?;
+
+        let Some(prefix) = question.labels.strip_suffix(self.zone.as_slice()) else {
+            return Some(response(query, question.end, RCODE_REFUSED, None));
+        };
+
+        let parsed = match prefix {
+            [data @ .., seq, tunnel] if question.qtype == TYPE_TXT => {
+                let seq = u32::from_str_radix(seq, 16).ok();
+                let data = base32_decode(&data.concat());
+                let valid_id = !tunnel.is_empty()
+                    && tunnel.len() <= MAX_TUNNEL_ID_LEN
+                    && tunnel.bytes().all(|c| c.is_ascii_alphanumeric());
+                seq.zip(data).filter(|_| valid_id)
+            }
+            _ => None,
+        };
+        let Some((seq, data)) = parsed else {
+            return Some(response(query, question.end, RCODE_NXDOMAIN, None));
+        };
+
+        let tunnel = &prefix[prefix.len() - 1];
+        let budget = downstream_budget(question.end);
+        match self.bridge.exchange(tunnel, seq, &data, budget).await {
+            Ok(Some(answer)) => Some(response(query, question.end, 0, Some(&answer))),
+            Ok(None) => Some(response(query, question.end, RCODE_NXDOMAIN, None)),
+            Err(e) => {
+                debug!("DNS tunnel {} failed: {}", tunnel, e);
+                Some(response(query, question.end, RCODE_SERVFAIL, None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const ZONE: &str = "t.example.com";
+
+    fn config() -> DnsTunnelConfig {
+        DnsTunnelConfig {
+            enabled: true,
+            zone: ZONE.to_string(),
+            ..DnsTunnelConfig::default()
+        }
+    }
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+        message.push(0);
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message
+    }
+
+    fn rcode(reply: &[u8]) -> u8 {
+        reply[3] & 0x0F
+    }
+
+    /// TXT string of a reply to `query`
+    fn txt<'a>(query: &[u8], reply: &'a [u8]) -> &'a [u8] {
+        &reply[query.len() + 13..]
+    }
+
+    #[test]
+    fn test_base32_roundtrip() {
+        for data in [&b""[..], b"f", b"fo", b"foobar", &[0xff; 37]] {
+            let encoded = base32_encode(data);
+            assert!(encoded.bytes().all(|c| BASE32_ALPHABET.contains(&c)));
+            assert_eq!(base32_decode(&encoded).unwrap(), data);
+            assert_eq!(base32_decode(&encoded.to_uppercase()).unwrap(), data);
+        }
+        assert!(base32_decode("not-base32").is_none());
+    }
+
+    #[test]
+    fn test_query_name_limits() {
+        let name = query_name("c0ffee", 1, &[0u8; 100], ZONE).unwrap();
+        assert!(name.ends_with(".1.c0ffee.t.example.com"));
+        assert!(name.split('.').all(|label| label.len() <= MAX_LABEL_LEN));
+
+        assert!(query_name("c0ffee", 1, &[0u8; 200], ZONE).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_foreign_and_malformed_queries() {
+        let target: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let server = DnsTunnelServer::new("127.0.0.1:0", target, &config());
+
+        let foreign = query(1, "www.example.org", TYPE_TXT);
+        let reply = server.handle(&foreign).await.unwrap();
+        assert_eq!(&reply[..2], &[0, 1]);
+        assert_eq!(rcode(&reply), RCODE_REFUSED);
+
+        let bad_seq = query(2, "xyz.c0ffee.t.example.com", TYPE_TXT);
+        assert_eq!(rcode(&server.handle(&bad_seq).await.unwrap()), RCODE_NXDOMAIN);
+
+        let wrong_type = query(3, "1.c0ffee.t.example.com", 1);
+        assert_eq!(rcode(&server.handle(&wrong_type).await.unwrap()), RCODE_NXDOMAIN);
+
+        assert!(server.handle(b"short").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_bridges_to_listener() {
+        // Echo server standing in for the LLP listener
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let (mut stream, peer) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&buf[..n]).await.unwrap();
+            peer
+        });
+
+        let server = DnsTunnelServer::new("127.0.0.1:0", target, &config());
+        let upstream = query(1, &query_name("c0ffee", 1, b"hello", ZONE).unwrap(), TYPE_TXT);
+        let reply = server.handle(&upstream).await.unwrap();
+        assert_eq!(rcode(&reply), 0);
+        let mut answer = txt(&upstream, &reply).to_vec();
+
+        let peer = accepted.await.unwrap();
+        assert!(server.is_tunnel(peer));
+
+        // Poll until the echo arrives, a retry then replays the same answer
+        for seq in 2..50 {
+            if !answer.is_empty() {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+
+            let poll = query(seq as u16, &query_name("c0ffee", seq, b"", ZONE).unwrap(), TYPE_TXT);
+            let reply = server.handle(&poll).await.unwrap();
+            assert_eq!(rcode(&reply), 0);
+            answer = txt(&poll, &reply).to_vec();
+            if !answer.is_empty() {
+                assert_eq!(server.handle(&poll).await.unwrap(), reply);
+            }
+        }
+        assert_eq!(answer, b"hello");
+    }
+}
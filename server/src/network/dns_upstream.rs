@@ -0,0 +1,669 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::error::{LostLoveError, Result};
+use crate::metrics::histogram::Histogram;
+
+/// How long an upstream resolver gets before the next one is tried
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Largest message relayed, for clients advertising EDNS buffers
+pub const MAX_RELAYED_SIZE: usize = 4096;
+
+/// Bucket bounds in seconds for upstream answers, up to the timeout
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Open connections kept per encrypted upstream between queries
+const MAX_IDLE: usize = 4;
+
+/// Largest HTTP response head, and chunk headers, read from a DoH upstream
+const MAX_HEAD_SIZE: usize = 8192;
+
+/// An upstream resolver as configured
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamSpec {
+    /// `ip:port`, plain DNS over UDP
+    Udp(SocketAddr),
+    /// `tls://host[:port]`, DNS over TLS (RFC 7858)
+    Tls { host: String, port: u16 },
+    /// `https://host[:port][/path]`, DNS over HTTPS (RFC 8484)
+    Https {
+        host: String,
+        port: u16,
+        path: String,
+    },
+}
+
+impl UpstreamSpec {
+    /// Parse an upstream; hosts may be names, resolved by the system when
+    /// connecting, or addresses the certificate must then be issued for
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || {
+            LostLoveError::Config(format!(
+                "resolver.upstream must be ip:port, tls://host[:port] or https://host[:port][/path], got {:?}",
+                spec
+            ))
+        };
+
+        let Some((scheme, rest)) = spec.split_once("://") else {
+            return spec.parse().map(Self::Udp).map_err(|_| invalid());
+        };
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let default_port = match scheme {
+            "tls" => 853,
+            "https" => 443,
+            _ => return Err(invalid()),
+        };
+        let (host, port) = split_host_port(authority, default_port).ok_or_else(invalid)?;
+        ServerName::try_from(host.clone()).map_err(|_| invalid())?;
+
+        match scheme {
+            "tls" if path.is_empty() => Ok(Self::Tls { host, port }),
+            "https" => Ok(Self::Https {
+                host,
+                port,
+                path: if path.is_empty() {
+                    "/dns-query".to_string()
+                } else {
+                    path.to_string()
+                },
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Host and port of `host`, `host:port`, `[v6]` or `[v6]:port`
+fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest.split_once(']')?;
+            host.parse::<Ipv6Addr>().ok()?;
+            (host, port)
+        }
+        None => match authority.split_once(':') {
+            Some((host, _)) => (host, &authority[host.len()..]),
+            None => (authority, ""),
+        },
+    };
+    let port = match port {
+        "" => default_port,
+        port => port
+            .strip_prefix(':')?
+            .parse()
+            .ok()
+            .filter(|&port| port != 0)?,
+    };
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+/// An upstream resolver with its connections and statistics
+pub struct Upstream {
+    /// The upstream as configured, also its metrics label
+    name: String,
+    target: Target,
+    latency: Histogram,
+    failures: AtomicU64,
+}
+
+enum Target {
+    Udp(SocketAddr),
+    Encrypted(Encrypted),
+}
+
+/// A DoT or DoH upstream
+struct Encrypted {
+    host: String,
+    port: u16,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    /// Path queries are posted to, None for DoT
+    doh_path: Option<String>,
+    idle: Mutex<Vec<Connection>>,
+}
+
+type Connection = BufReader<TlsStream<TcpStream>>;
+
+impl Upstream {
+    /// Upstream `spec`, verifying certificates against the bundled web roots
+    pub fn parse(spec: &str) -> Result<Self> {
+        let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        Self::with_roots(spec, Arc::new(roots))
+    }
+
+    fn with_roots(spec: &str, roots: Arc<RootCertStore>) -> Result<Self> {
+        let target = match UpstreamSpec::parse(spec)? {
+            UpstreamSpec::Udp(address) => Target::Udp(address),
+            UpstreamSpec::Tls { host, port } => {
+                Target::Encrypted(Encrypted::new(host, port, None, roots)?)
+            }
+            UpstreamSpec::Https { host, port, path } => {
+                Target::Encrypted(Encrypted::new(host, port, Some(path), roots)?)
+            }
+        };
+        Ok(Self {
+            name: spec.to_string(),
+            target,
+            latency: Histogram::new(LATENCY_BUCKETS),
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Time answers took, from sending the query or connecting
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+
+    /// Queries that failed or weren't answered in time
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Send `query` and wait for the answer with its ID
+    pub async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        let exchange = async {
+            match &self.target {
+                Target::Udp(address) => exchange_udp(*address, query).await,
+                Target::Encrypted(encrypted) => encrypted.exchange(query).await,
+            }
+        };
+        let result = match time::timeout(UPSTREAM_TIMEOUT, exchange).await {
+            Ok(Ok(reply)) if reply.len() < 12 || reply[..2] != query[..2] => Err(
+                LostLoveError::Network("answer to another query".to_string()),
+            ),
+            Ok(result) => result,
+            Err(_) => Err(LostLoveError::Network("no answer".to_string())),
+        };
+
+        match result {
+            Ok(_) => self.latency.observe(started.elapsed()),
+            Err(_) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+/// Send `query` over UDP and wait for the answer with its ID
+async fn exchange_udp(upstream: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let local: SocketAddr = match upstream {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0u8; MAX_RELAYED_SIZE];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        // Stray datagrams don't end the wait
+        if n >= 12 && buf[..2] == query[..2] {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+    }
+}
+
+impl Encrypted {
+    fn new(
+        host: String,
+        port: u16,
+        doh_path: Option<String>,
+        roots: Arc<RootCertStore>,
+    ) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| LostLoveError::Config(format!("TLS setup failed: {}", e)))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        if doh_path.is_some() {
+            config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        }
+        let server_name = ServerName::try_from(host.clone()).map_err(|e| {
+            LostLoveError::Config(format!("Invalid resolver host {:?}: {}", host, e))
+        })?;
+
+        Ok(Self {
+            host,
+            port,
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+            doh_path,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        // The resolver may have closed an idle connection meanwhile, so a
+        // failure on one is retried on a new connection
+        let idle = self.idle.lock().unwrap().pop();
+        if let Some(connection) = idle {
+            if let Ok(reply) = self.transact(connection, query).await {
+                return Ok(reply);
+            }
+        }
+        let connection = self.connect().await?;
+        self.transact(connection, query).await
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        stream.set_nodelay(true)?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// Exchange `query` on `connection`, keeping the connection if the
+    /// resolver lets it stay open
+    async fn transact(&self, mut connection: Connection, query: &[u8]) -> Result<Vec<u8>> {
+        let (reply, reusable) = match &self.doh_path {
+            None => (exchange_dot(&mut connection, query).await?, true),
+            Some(path) => exchange_doh(&mut connection, &self.host_header(), path, query).await?,
+        };
+        if reusable {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE {
+                idle.push(connection);
+            }
+        }
+        Ok(reply)
+    }
+
+    fn host_header(&self) -> String {
+        let host = match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]", self.host),
+            _ => self.host.clone(),
+        };
+        if self.port == 443 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+/// One query on a DoT stream: both messages prefixed with their length
+async fn exchange_dot<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    query: &[u8],
+) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(2 + query.len());
+    message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    message.extend_from_slice(query);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    let length = stream.read_u16().await? as usize;
+    let mut reply = vec![0u8; length];
+    stream.read_exact(&mut reply).await?;
+    Ok(reply)
+}
+
+/// One query posted on a DoH connection, with whether the connection stays
+/// open for the next
+async fn exchange_doh<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    host: &str,
+    path: &str,
+    query: &[u8],
+) -> Result<(Vec<u8>, bool)> {
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\n\
+         Accept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+        path,
+        host,
+        query.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+
+    let mut budget = MAX_HEAD_SIZE;
+    let status = read_line(stream, &mut budget).await?;
+    let mut words = status.split_whitespace();
+    let version = words.next().unwrap_or_default();
+    if !version.starts_with("HTTP/1.") {
+        return Err(LostLoveError::Network(format!(
+            "Not an HTTP response: {:?}",
+            status
+        )));
+    }
+    if words.next() != Some("200") {
+        return Err(LostLoveError::Network(format!(
+            "Resolver answered {:?}",
+            status
+        )));
+    }
+
+    let mut length = None;
+    let mut chunked = false;
+    let mut reusable = version != "HTTP/1.0";
+    loop {
+        let line = read_line(stream, &mut budget).await?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_ascii_lowercase();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                length = Some(value.parse::<usize>().map_err(|_| {
+                    LostLoveError::Network(format!("Invalid Content-Length {:?}", value))
+                })?)
+            }
+            "transfer-encoding" => chunked = value.contains("chunked"),
+            "connection" if value.contains("close") => reusable = false,
+            "connection" if value.contains("keep-alive") => reusable = true,
+            _ => {}
+        }
+    }
+
+    let body = if chunked {
+        read_chunked(stream, &mut budget).await?
+    } else if let Some(length) = length {
+        if length > MAX_RELAYED_SIZE {
+            return Err(LostLoveError::Network(format!("{} byte answer", length)));
+        }
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).await?;
+        body
+    } else {
+        // Without a length the body runs until the resolver closes
+        let mut body = Vec::new();
+        (&mut *stream)
+            .take(MAX_RELAYED_SIZE as u64)
+            .read_to_end(&mut body)
+            .await?;
+        reusable = false;
+        body
+    };
+    Ok((body, reusable))
+}
+
+/// A chunked body and its trailers
+async fn read_chunked<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    budget: &mut usize,
+) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(stream, budget).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| LostLoveError::Network(format!("Invalid chunk size {:?}", size)))?;
+        if size == 0 {
+            while !read_line(stream, budget).await?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len() + size > MAX_RELAYED_SIZE {
+            return Err(LostLoveError::Network(format!(
+                "{} byte answer",
+                body.len() + size
+            )));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..]).await?;
+        if !read_line(stream, budget).await?.is_empty() {
+            return Err(LostLoveError::Network(
+                "Chunk longer than its size".to_string(),
+            ));
+        }
+    }
+}
+
+/// A line without its CRLF, failing once `budget` bytes have been read
+async fn read_line<S: AsyncBufRead + Unpin>(stream: &mut S, budget: &mut usize) -> Result<String> {
+    let mut line = Vec::new();
+    let n = (&mut *stream)
+        .take(*budget as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if !line.ends_with(b"\n") {
+        return Err(LostLoveError::Network(
+            "Truncated HTTP response".to_string(),
+        ));
+    }
+    *budget -= n;
+    let line = String::from_utf8(line)
+        .map_err(|_| LostLoveError::Network("HTTP response is not UTF-8".to_string()))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    const QUERY: &[u8] = &[
+        0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1,
+    ];
+
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let mut answer = query.to_vec();
+        answer[2] |= 0x80;
+        answer
+    }
+
+    /// Read one DoH request from `server`, check its body and send `response`
+    async fn serve_http(server: &mut BufReader<DuplexStream>, response: &[u8]) {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            server.read_line(&mut line).await.unwrap();
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0u8; length];
+        server.read_exact(&mut body).await.unwrap();
+        assert_eq!(body, QUERY);
+        server.write_all(response).await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_specs() {
+        assert_eq!(
+            UpstreamSpec::parse("1.1.1.1:53").unwrap(),
+            UpstreamSpec::Udp("1.1.1.1:53".parse().unwrap())
+        );
+        assert_eq!(
+            UpstreamSpec::parse("tls://9.9.9.9").unwrap(),
+            UpstreamSpec::Tls {
+                host: "9.9.9.9".to_string(),
+                port: 853
+            }
+        );
+        assert_eq!(
+            UpstreamSpec::parse("tls://dns.quad9.net:8853").unwrap(),
+            UpstreamSpec::Tls {
+                host: "dns.quad9.net".to_string(),
+                port: 8853
+            }
+        );
+        assert_eq!(
+            UpstreamSpec::parse("https://[2606:4700:4700::1111]").unwrap(),
+            UpstreamSpec::Https {
+                host: "2606:4700:4700::1111".to_string(),
+                port: 443,
+                path: "/dns-query".to_string()
+            }
+        );
+        assert_eq!(
+            UpstreamSpec::parse("https://dns.google:8443/resolve").unwrap(),
+            UpstreamSpec::Https {
+                host: "dns.google".to_string(),
+                port: 8443,
+                path: "/resolve".to_string()
+            }
+        );
+
+        for spec in [
+            "resolver.example.org",
+            "quic://9.9.9.9",
+            "tls://9.9.9.9/path",
+            "tls://",
+            "tls://9.9.9.9:0",
+            "https://2606:4700::1111/",
+        ] {
+            let err = UpstreamSpec::parse(spec).unwrap_err();
+            assert_eq!(err.code(), "config", "{}", spec);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dot_framing() {
+        let (mut client, mut server) = duplex(1024);
+        let resolver = tokio::spawn(async move {
+            let length = server.read_u16().await.unwrap() as usize;
+            let mut query = vec![0u8; length];
+            server.read_exact(&mut query).await.unwrap();
+            let answer = answer(&query);
+            server.write_u16(answer.len() as u16).await.unwrap();
+            server.write_all(&answer).await.unwrap();
+        });
+
+        assert_eq!(
+            exchange_dot(&mut client, QUERY).await.unwrap(),
+            answer(QUERY)
+        );
+        resolver.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_doh_responses() {
+        let (client, server) = duplex(4096);
+        let mut client = BufReader::new(client);
+        let mut server = BufReader::new(server);
+        let answer = answer(QUERY);
+
+        let mut sized = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            answer.len()
+        )
+        .into_bytes();
+        sized.extend_from_slice(&answer);
+        let mut chunked =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n5\r\n"
+                .to_vec();
+        chunked.extend_from_slice(&answer[..5]);
+        chunked.extend_from_slice(format!("\r\n{:x};ext=1\r\n", answer.len() - 5).as_bytes());
+        chunked.extend_from_slice(&answer[5..]);
+        chunked.extend_from_slice(b"\r\n0\r\n\r\n");
+
+        let resolver = tokio::spawn(async move {
+            serve_http(&mut server, &sized).await;
+            serve_http(&mut server, &chunked).await;
+            serve_http(
+                &mut server,
+                b"HTTP/1.1 415 Unsupported Media Type\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await;
+        });
+
+        let reply = exchange_doh(&mut client, "dns.example", "/dns-query", QUERY)
+            .await
+            .unwrap();
+        assert_eq!(reply, (answer.clone(), true));
+        let reply = exchange_doh(&mut client, "dns.example", "/dns-query", QUERY)
+            .await
+            .unwrap();
+        assert_eq!(reply, (answer, false));
+        let err = exchange_doh(&mut client, "dns.example", "/dns-query", QUERY)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("415"), "{}", err);
+        resolver.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dot_upstream_reuses_connections() {
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            certified.signing_key.serialize_der(),
+        ));
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![certified.cert.der().clone()], key)
+                .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicU64::new(0));
+        {
+            let accepted = accepted.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    accepted.fetch_add(1, Ordering::Relaxed);
+                    let acceptor = acceptor.clone();
+                    tokio::spawn(async move {
+                        let Ok(mut stream) = acceptor.accept(stream).await else {
+                            return;
+                        };
+                        while let Ok(length) = stream.read_u16().await {
+                            let mut query = vec![0u8; length as usize];
+                            stream.read_exact(&mut query).await.unwrap();
+                            let answer = answer(&query);
+                            stream.write_u16(answer.len() as u16).await.unwrap();
+                            stream.write_all(&answer).await.unwrap();
+                            stream.flush().await.unwrap();
+                        }
+                    });
+                }
+            });
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let spec = format!("tls://127.0.0.1:{}", port);
+        let upstream = Upstream::with_roots(&spec, Arc::new(roots)).unwrap();
+        for _ in 0..3 {
+            assert_eq!(upstream.exchange(QUERY).await.unwrap(), answer(QUERY));
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+        assert_eq!(upstream.latency().cumulative().last().unwrap().1, 3);
+        assert_eq!(upstream.name(), spec);
+
+        // A certificate the web roots don't vouch for is refused
+        let untrusted = Upstream::parse(&spec).unwrap();
+        assert!(untrusted.exchange(QUERY).await.is_err());
+        assert_eq!(untrusted.failures(), 1);
+    }
+}
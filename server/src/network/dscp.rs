@@ -0,0 +1,147 @@
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::config::QosConfig;
+
+/// Highest DSCP value, the field is 6 bits
+pub const MAX_DSCP: u8 = 63;
+
+/// DSCP of an IPv4 or IPv6 packet
+pub fn inner_dscp(packet: &[u8]) -> Option<u8> {
+    let (first, second) = (*packet.first()?, *packet.get(1)?);
+    match first >> 4 {
+        // Type of service, DSCP in the upper six bits
+        4 => Some(second >> 2),
+        // Traffic class spans the low nibble of byte 0 and high nibble of byte 1
+        6 => Some((((first & 0x0F) << 4) | (second >> 4)) >> 2),
+        _ => None,
+    }
+}
+
+/// Set the DSCP of the packets a socket sends, leaving the ECN bits clear
+///
+/// IPv6 sockets get both options since IPv4-mapped peers take IP_TOS.
+pub fn set_dscp(socket: &SockRef<'_>, dscp: u8) -> io::Result<()> {
+    let tos = u32::from(dscp) << 2;
+    if let Some(SocketAddr::V6(_)) = socket.local_addr()?.as_socket() {
+        socket.set_tclass_v6(tos)?;
+    }
+    socket.set_tos(tos)
+}
+
+/// Marks the outer packets of one socket
+///
+/// With `copy_inner` each tunneled packet's DSCP carries over to its outer
+/// packet; the socket option is only changed when the value does.
+#[derive(Debug)]
+pub struct DscpMarker {
+    fixed: Option<u8>,
+    copy_inner: bool,
+    current: Option<u8>,
+}
+
+impl DscpMarker {
+    pub fn new(config: &QosConfig) -> Self {
+        Self {
+            fixed: config.dscp,
+            copy_inner: config.copy_inner,
+            current: None,
+        }
+    }
+
+    /// Give a new socket the configured DSCP, if any
+    pub fn apply(&mut self, socket: &SockRef<'_>) -> io::Result<()> {
+        match self.fixed {
+            Some(dscp) => self.set(socket, dscp),
+            None => Ok(()),
+        }
+    }
+
+    /// Mark the socket for a tunneled packet about to be sent over it
+    pub fn mark(&mut self, socket: &SockRef<'_>, packet: &[u8]) -> io::Result<()> {
+        let inner = inner_dscp(packet).filter(|_| self.copy_inner);
+        match inner.or(self.fixed) {
+            Some(dscp) => self.set(socket, dscp),
+            None => Ok(()),
+        }
+    }
+
+    fn set(&mut self, socket: &SockRef<'_>, dscp: u8) -> io::Result<()> {
+        if self.current != Some(dscp) {
+            set_dscp(socket, dscp)?;
+            self.current = Some(dscp);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    /// Expedited forwarding, the usual class of voice traffic
+    const EF: u8 = 46;
+
+    fn ipv4_packet(dscp: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[1] = dscp << 2 | 0x01;
+        packet
+    }
+
+    fn ipv6_packet(dscp: u8) -> Vec<u8> {
+        let traffic_class = dscp << 2;
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x60 | traffic_class >> 4;
+        packet[1] = traffic_class << 4;
+        packet
+    }
+
+    #[test]
+    fn test_inner_dscp() {
+        assert_eq!(inner_dscp(&ipv4_packet(EF)), Some(EF));
+        assert_eq!(inner_dscp(&ipv6_packet(EF)), Some(EF));
+        assert_eq!(inner_dscp(&ipv6_packet(MAX_DSCP)), Some(MAX_DSCP));
+        assert_eq!(inner_dscp(&[0x45]), None);
+        assert_eq!(inner_dscp(&[0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn test_fixed_marking() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = SockRef::from(&socket);
+        let mut marker = DscpMarker::new(&QosConfig {
+            dscp: Some(10),
+            copy_inner: false,
+        });
+
+        marker.apply(&socket).unwrap();
+        assert_eq!(socket.tos().unwrap(), 10 << 2);
+
+        // Without copying, inner markings are ignored
+        marker.mark(&socket, &ipv4_packet(EF)).unwrap();
+        assert_eq!(socket.tos().unwrap(), 10 << 2);
+    }
+
+    #[test]
+    fn test_copy_inner_marking() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = SockRef::from(&socket);
+        let mut marker = DscpMarker::new(&QosConfig {
+            dscp: Some(10),
+            copy_inner: true,
+        });
+
+        marker.mark(&socket, &ipv4_packet(EF)).unwrap();
+        assert_eq!(socket.tos().unwrap(), u32::from(EF) << 2);
+
+        marker.mark(&socket, &ipv6_packet(0)).unwrap();
+        assert_eq!(socket.tos().unwrap(), 0);
+
+        // Packets without an IP header fall back to the fixed marking
+        marker.mark(&socket, &[]).unwrap();
+        assert_eq!(socket.tos().unwrap(), 10 << 2);
+    }
+}
@@ -0,0 +1,256 @@
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::FirewallConfig;
+use crate::core::session::SessionId;
+use crate::network::inner_packet::{InnerPacket, IpProtocol};
+
+/// Why the firewall dropped a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Inbound packet that doesn't belong to a flow the client opened
+    NoEstablishedFlow,
+    /// Flow table is full, new flows are refused
+    FlowTableFull,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::NoEstablishedFlow => "no_established_flow",
+            DropReason::FlowTableFull => "flow_table_full",
+        }
+    }
+}
+
+/// Firewall decision for one packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Drop(DropReason),
+}
+
+/// Inner TCP/UDP flow as seen from the client side
+///
+/// Keyed by session too, so a recycled lease never inherits the flows of the
+/// previous holder.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FlowKey {
+    session: SessionId,
+    protocol: IpProtocol,
+    client: IpAddr,
+    client_port: u16,
+    remote: IpAddr,
+    remote_port: u16,
+}
+
+impl FlowKey {
+    /// Key of the flow a client packet belongs to
+    fn outbound(session: &SessionId, packet: &InnerPacket) -> Option<Self> {
+        Some(Self {
+            session: *session,
+            protocol: packet.protocol,
+            client: packet.source,
+            client_port: packet.source_port?,
+            remote: packet.destination,
+            remote_port: packet.destination_port?,
+        })
+    }
+}
+
+/// Firewall statistics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirewallStats {
+    pub active_flows: usize,
+    pub no_established_flow: u64,
+    pub flow_table_full: u64,
+}
+
+/// Stateful filter for tunneled traffic
+///
+/// Client packets open flows; packets towards a client are only let through
+/// for flows the client opened. Protocols without ports (ICMP, ...) are not
+/// flow-tracked. Source addresses are validated by the router beforehand.
+pub struct Firewall {
+    flows: DashMap<FlowKey, Instant>,
+    flow_timeout: Duration,
+    max_flows: usize,
+    no_established_flow: AtomicU64,
+    flow_table_full: AtomicU64,
+}
+
+impl Firewall {
+    /// Create firewall from config
+    pub fn new(config: &FirewallConfig) -> Self {
+        Self {
+            flows: DashMap::new(),
+            flow_timeout: Duration::from_secs(config.flow_timeout),
+            max_flows: config.max_flows,
+            no_established_flow: AtomicU64::new(0),
+            flow_table_full: AtomicU64::new(0),
+        }
+    }
+
+    /// Check a packet sent by a client into the tunnel
+    pub fn check_outbound(&self, session: &SessionId, packet: &InnerPacket) -> Verdict {
+        let Some(key) = FlowKey::outbound(session, packet) else {
+            return Verdict::Accept;
+        };
+
+        if !self.flows.contains_key(&key) && self.flows.len() >= self.max_flows {
+            return self.drop(DropReason::FlowTableFull);
+        }
+
+        self.flows.insert(key, Instant::now());
+        Verdict::Accept
+    }
+
+    /// Check a packet leaving the tunnel towards a client
+    pub fn check_inbound(&self, session: &SessionId, packet: &InnerPacket) -> Verdict {
+        let (Some(remote_port), Some(client_port)) = (packet.source_port, packet.destination_port) else {
+            return Verdict::Accept;
+        };
+
+        let key = FlowKey {
+            session: *session,
+            protocol: packet.protocol,
+            client: packet.destination,
+            client_port,
+            remote: packet.source,
+            remote_port,
+        };
+
+        match self.flows.get_mut(&key) {
+            Some(mut last_seen) if last_seen.elapsed() < self.flow_timeout => {
+                *last_seen = Instant::now();
+                Verdict::Accept
+            }
+            _ => self.drop(DropReason::NoEstablishedFlow),
+        }
+    }
+
+    /// Check an ICMP error towards a client by the client packet it quotes
+    ///
+    /// Errors are let through only for flows the client opened; they don't
+    /// refresh the flow.
+    pub fn check_related(&self, session: &SessionId, quoted: &InnerPacket) -> Verdict {
+        let Some(key) = FlowKey::outbound(session, quoted) else {
+            return Verdict::Accept;
+        };
+
+        match self.flows.get(&key) {
+            Some(last_seen) if last_seen.elapsed() < self.flow_timeout => Verdict::Accept,
+            _ => self.drop(DropReason::NoEstablishedFlow),
+        }
+    }
+
+    /// Forget flows idle for longer than the flow timeout
+    pub fn expire_flows(&self) {
+        self.flows.retain(|_, last_seen| last_seen.elapsed() < self.flow_timeout);
+    }
+
+    /// Get statistics
+    pub fn stats(&self) -> FirewallStats {
+        FirewallStats {
+            active_flows: self.flows.len(),
+            no_established_flow: self.no_established_flow.load(Ordering::Relaxed),
+            flow_table_full: self.flow_table_full.load(Ordering::Relaxed),
+        }
+    }
+
+    fn drop(&self, reason: DropReason) -> Verdict {
+        let counter = match reason {
+            DropReason::NoEstablishedFlow => &self.no_established_flow,
+            DropReason::FlowTableFull => &self.flow_table_full,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        Verdict::Drop(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::inner_packet::tests::ipv4_packet;
+
+    fn parse(data: &[u8]) -> InnerPacket {
+        InnerPacket::parse(data).unwrap()
+    }
+
+    #[test]
+    fn test_return_traffic_needs_flow() {
+        let firewall = Firewall::new(&FirewallConfig::default());
+        let session = SessionId::new();
+
+        let reply = parse(&ipv4_packet(6, [1, 1, 1, 1], [10, 8, 0, 2], (443, 40000)));
+        assert_eq!(
+            firewall.check_inbound(&session, &reply),
+            Verdict::Drop(DropReason::NoEstablishedFlow)
+        );
+
+        let request = parse(&ipv4_packet(6, [10, 8, 0, 2], [1, 1, 1, 1], (40000, 443)));
+        assert_eq!(firewall.check_outbound(&session, &request), Verdict::Accept);
+        assert_eq!(firewall.check_inbound(&session, &reply), Verdict::Accept);
+
+        // Same remote, different client port: not the same flow
+        let other = parse(&ipv4_packet(6, [1, 1, 1, 1], [10, 8, 0, 2], (443, 40001)));
+        assert_eq!(
+            firewall.check_inbound(&session, &other),
+            Verdict::Drop(DropReason::NoEstablishedFlow)
+        );
+    }
+
+    #[test]
+    fn test_flow_table_limit() {
+        let config = FirewallConfig {
+            max_flows: 1,
+            ..FirewallConfig::default()
+        };
+        let firewall = Firewall::new(&config);
+        let session = SessionId::new();
+
+        let first = parse(&ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (5000, 53)));
+        let second = parse(&ipv4_packet(17, [10, 8, 0, 2], [8, 8, 8, 8], (5001, 53)));
+
+        assert_eq!(firewall.check_outbound(&session, &first), Verdict::Accept);
+        assert_eq!(
+            firewall.check_outbound(&session, &second),
+            Verdict::Drop(DropReason::FlowTableFull)
+        );
+        // Existing flows keep working
+        assert_eq!(firewall.check_outbound(&session, &first), Verdict::Accept);
+    }
+
+    #[test]
+    fn test_related_errors_need_flow() {
+        let firewall = Firewall::new(&FirewallConfig::default());
+        let session = SessionId::new();
+        let request = parse(&ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (5000, 53)));
+
+        assert_eq!(
+            firewall.check_related(&session, &request),
+            Verdict::Drop(DropReason::NoEstablishedFlow)
+        );
+
+        firewall.check_outbound(&session, &request);
+        assert_eq!(firewall.check_related(&session, &request), Verdict::Accept);
+    }
+
+    #[test]
+    fn test_flows_are_per_session() {
+        let firewall = Firewall::new(&FirewallConfig::default());
+        let session = SessionId::new();
+        let request = parse(&ipv4_packet(6, [10, 8, 0, 2], [1, 1, 1, 1], (40000, 443)));
+        let reply = parse(&ipv4_packet(6, [1, 1, 1, 1], [10, 8, 0, 2], (443, 40000)));
+        firewall.check_outbound(&session, &request);
+
+        // Lease handed to a new session after the old one disconnected
+        assert_eq!(
+            firewall.check_inbound(&SessionId::new(), &reply),
+            Verdict::Drop(DropReason::NoEstablishedFlow)
+        );
+    }
+}
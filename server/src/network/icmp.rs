@@ -0,0 +1,222 @@
+use std::net::IpAddr;
+
+use crate::network::inner_packet::{InnerPacket, IpProtocol, IPV4_HEADER_MIN, IPV6_HEADER_SIZE};
+
+/// ICMPv4 destination unreachable, code "fragmentation needed"
+const ICMPV4_DEST_UNREACHABLE: u8 = 3;
+const ICMPV4_FRAGMENTATION_NEEDED: u8 = 4;
+
+/// ICMPv6 packet too big
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+
+/// ICMP header size (type, code, checksum, 4 bytes of rest-of-header)
+const ICMP_HEADER_SIZE: usize = 8;
+
+/// Minimum IPv6 MTU, ICMPv6 errors must not exceed it
+const IPV6_MIN_MTU: usize = 1280;
+
+/// Hop limit / TTL of generated messages
+const DEFAULT_TTL: u8 = 64;
+
+/// Check if an ICMP/ICMPv6 packet is an error message
+pub fn is_error(packet: &[u8], inner: &InnerPacket) -> bool {
+    let Some(&icmp_type) = packet.get(inner.transport_offset) else {
+        return false;
+    };
+
+    match inner.protocol {
+        // Destination unreachable, source quench, redirect, time exceeded, parameter problem
+        IpProtocol::Icmp => matches!(icmp_type, 3 | 4 | 5 | 11 | 12),
+        // Error messages have the high bit clear
+        IpProtocol::Icmpv6 => icmp_type < 128,
+        _ => false,
+    }
+}
+
+/// Parse the original packet quoted inside an ICMP error
+///
+/// The quote is usually truncated, but always covers the IP header and the
+/// first 8 bytes of the transport header, so addresses and ports are there.
+pub fn quoted_packet(packet: &[u8], inner: &InnerPacket) -> Option<InnerPacket> {
+    if !is_error(packet, inner) {
+        return None;
+    }
+
+    let quote = packet.get(inner.transport_offset + ICMP_HEADER_SIZE..)?;
+    InnerPacket::parse(quote).ok()
+}
+
+/// Build a "fragmentation needed" / "packet too big" reply for an oversize packet
+///
+/// `source` is the address the reply comes from (the server's tunnel address)
+/// and must match the family of the packet. The server never fragments, so a
+/// reply is generated whether or not the IPv4 DF bit is set. Returns None for
+/// packets that must not trigger an ICMP error, i.e. ICMP errors themselves.
+pub fn packet_too_big(packet: &[u8], inner: &InnerPacket, mtu: usize, source: IpAddr) -> Option<Vec<u8>> {
+    if is_error(packet, inner) {
+        return None;
+    }
+
+    let mtu = mtu.min(u16::MAX as usize) as u16;
+
+    match (source, inner.source) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            // Original IP header plus the first 8 bytes of its payload
+            let quote = &packet[..packet.len().min(inner.transport_offset + 8)];
+
+            let mut icmp = vec![0u8; ICMP_HEADER_SIZE];
+            icmp[0] = ICMPV4_DEST_UNREACHABLE;
+            icmp[1] = ICMPV4_FRAGMENTATION_NEEDED;
+            icmp[6..8].copy_from_slice(&mtu.to_be_bytes());
+            icmp.extend_from_slice(quote);
+
+            let sum = checksum(&icmp);
+            icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+            let total_length = (IPV4_HEADER_MIN + icmp.len()) as u16;
+            let mut reply = vec![0u8; IPV4_HEADER_MIN];
+            reply[0] = 0x45;
+            reply[2..4].copy_from_slice(&total_length.to_be_bytes());
+            reply[8] = DEFAULT_TTL;
+            reply[9] = IpProtocol::Icmp.as_u8();
+            reply[12..16].copy_from_slice(&source.octets());
+            reply[16..20].copy_from_slice(&destination.octets());
+
+            let sum = checksum(&reply);
+            reply[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            reply.extend_from_slice(&icmp);
+            Some(reply)
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            // As much of the original packet as fits in the minimum MTU
+            let room = IPV6_MIN_MTU - IPV6_HEADER_SIZE - ICMP_HEADER_SIZE;
+            let quote = &packet[..packet.len().min(room)];
+
+            let mut icmp = vec![0u8; ICMP_HEADER_SIZE];
+            icmp[0] = ICMPV6_PACKET_TOO_BIG;
+            icmp[4..8].copy_from_slice(&(mtu as u32).to_be_bytes());
+            icmp.extend_from_slice(quote);
+
+            let mut pseudo = Vec::with_capacity(40 + icmp.len());
+            pseudo.extend_from_slice(&source.octets());
+            pseudo.extend_from_slice(&destination.octets());
+            pseudo.extend_from_slice(&(icmp.len() as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, IpProtocol::Icmpv6.as_u8()]);
+            pseudo.extend_from_slice(&icmp);
+
+            let sum = checksum(&pseudo);
+            icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+            let mut reply = vec![0u8; IPV6_HEADER_SIZE];
+            reply[0] = 0x60;
+            reply[4..6].copy_from_slice(&(icmp.len() as u16).to_be_bytes());
+            reply[6] = IpProtocol::Icmpv6.as_u8();
+            reply[7] = DEFAULT_TTL;
+            reply[8..24].copy_from_slice(&source.octets());
+            reply[24..40].copy_from_slice(&destination.octets());
+
+            reply.extend_from_slice(&icmp);
+            Some(reply)
+        }
+        _ => None,
+    }
+}
+
+/// Internet checksum (RFC 1071)
+pub(crate) fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::inner_packet::tests::ipv4_packet;
+    use std::net::Ipv4Addr;
+
+    const SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1));
+
+    fn oversize_packet() -> Vec<u8> {
+        let mut packet = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (5000, 53));
+        packet.resize(1500, 0xAB);
+        packet[2..4].copy_from_slice(&1500u16.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_fragmentation_needed_v4() {
+        let packet = oversize_packet();
+        let inner = InnerPacket::parse(&packet).unwrap();
+
+        let reply = packet_too_big(&packet, &inner, 1400, SERVER).unwrap();
+        let parsed = InnerPacket::parse(&reply).unwrap();
+
+        assert_eq!(parsed.protocol, IpProtocol::Icmp);
+        assert_eq!(parsed.source, SERVER);
+        assert_eq!(parsed.destination, inner.source);
+        assert_eq!(parsed.total_length, reply.len());
+        // Header and ICMP checksums verify to zero
+        assert_eq!(checksum(&reply[..20]), 0);
+        assert_eq!(checksum(&reply[20..]), 0);
+        assert_eq!(&reply[20..22], &[3, 4]);
+        assert_eq!(u16::from_be_bytes([reply[26], reply[27]]), 1400);
+
+        // The quote leads back to the original flow
+        let quoted = quoted_packet(&reply, &parsed).unwrap();
+        assert_eq!(quoted.source, inner.source);
+        assert_eq!(quoted.destination_port, Some(53));
+    }
+
+    #[test]
+    fn test_packet_too_big_v6() {
+        let mut packet = vec![0u8; 1500];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&1460u16.to_be_bytes());
+        packet[6] = 17;
+        packet[23] = 2;
+        packet[39] = 3;
+        let inner = InnerPacket::parse(&packet).unwrap();
+        let server: IpAddr = "::1".parse().unwrap();
+
+        let reply = packet_too_big(&packet, &inner, 1400, server).unwrap();
+        assert_eq!(reply.len(), IPV6_MIN_MTU);
+
+        let parsed = InnerPacket::parse(&reply).unwrap();
+        assert_eq!(parsed.protocol, IpProtocol::Icmpv6);
+        assert_eq!(parsed.destination, inner.source);
+        assert_eq!(reply[40], ICMPV6_PACKET_TOO_BIG);
+        assert_eq!(u32::from_be_bytes([reply[44], reply[45], reply[46], reply[47]]), 1400);
+    }
+
+    #[test]
+    fn test_no_error_for_errors() {
+        let packet = oversize_packet();
+        let inner = InnerPacket::parse(&packet).unwrap();
+        let mut reply = packet_too_big(&packet, &inner, 1400, SERVER).unwrap();
+        reply.resize(1500, 0);
+        let parsed = InnerPacket::parse(&reply).unwrap();
+
+        assert!(packet_too_big(&reply, &parsed, 1400, SERVER).is_none());
+
+        // Family mismatch
+        assert!(packet_too_big(&packet, &inner, 1400, "::1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_echo_is_not_an_error() {
+        let packet = ipv4_packet(1, [1, 1, 1, 1], [10, 8, 0, 2], (0x0800, 0));
+        let inner = InnerPacket::parse(&packet).unwrap();
+
+        assert!(!is_error(&packet, &inner));
+        assert!(quoted_packet(&packet, &inner).is_none());
+    }
+}
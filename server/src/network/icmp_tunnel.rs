@@ -0,0 +1,246 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{debug, info};
+
+use crate::config::IcmpTunnelConfig;
+use crate::core::memory::MemoryBudget;
+use crate::error::{LostLoveError, Result};
+use crate::network::bridge::LoopbackBridge;
+use crate::network::icmp::checksum;
+
+/// Echo request payloads of tunnel traffic start with this
+pub const REQUEST_MAGIC: &[u8; 4] = b"LLPI";
+
+/// Start of replies carrying server bytes
+///
+/// Differs from the request magic, so clients can tell them apart from the
+/// kernel's own echo replies, which mirror the request payload.
+pub const REPLY_MAGIC: &[u8; 4] = b"LLPO";
+
+/// Start of the reply telling a client its tunnel is gone
+pub const CLOSED_MAGIC: &[u8; 4] = b"LLPC";
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HEADER_SIZE: usize = 8;
+
+/// Most server bytes per reply, keeping replies below common path MTUs
+const MAX_REPLY_DATA: usize = 1024;
+
+/// Length of the client data in a request, before the data and padding
+const LENGTH_SIZE: usize = 2;
+
+/// Largest packet read from the raw socket
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// Optional transport carrying LLP bytes in ICMP echo payloads (IPv4)
+///
+/// For captive networks that pass nothing but ping. Each echo request brings
+/// client bytes after `REQUEST_MAGIC` and their length, then padding, and is
+/// answered with an echo reply of the same identifier and sequence number
+/// holding the server's bytes. A reply is never larger than its request, so
+/// requests with a spoofed source can't be amplified; clients pad their
+/// polls to make room for what they expect back.
+/// A tunnel is one client address and echo identifier, bridged to the
+/// listener by `LoopbackBridge`. Needs a raw socket, i.e. root or CAP_NET_RAW.
+pub struct IcmpTunnelServer {
+    bridge: LoopbackBridge<String>,
+}
+
+impl IcmpTunnelServer {
+    /// Create transport bridging tunnels to `target`
+    pub fn new(target: SocketAddr, config: &IcmpTunnelConfig) -> Self {
+        Self {
+            bridge: LoopbackBridge::new(
+                "ICMP",
+                target,
+                config.max_tunnels,
+                Duration::from_secs(config.idle_timeout),
+            ),
+        }
+    }
+
+    /// Fail early when the process may not open raw sockets
+    pub fn check_capability() -> Result<()> {
+        raw_socket().map(drop)
+    }
+
+    /// Charge buffered server bytes to `memory`
+    pub fn with_memory(mut self, memory: Arc<MemoryBudget>) -> Self {
+        self.bridge = self.bridge.with_memory(memory);
+        self
+    }
+
+    /// Whether a connection to the listener comes from an ICMP tunnel
+    pub fn is_tunnel(&self, peer: SocketAddr) -> bool {
+        self.bridge.is_tunnel(peer)
+    }
+
+    /// Answer echo requests until the task is cancelled
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let socket = raw_socket()?;
+        info!("ICMP transport answering echo requests");
+
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        let mut expiry = time::interval(self.bridge.idle_timeout() / 2);
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (n, peer) = received?;
+                    if let Some(reply) = self.handle(peer.ip(), &buf[..n]).await {
+                        if let Err(e) = socket.send_to(&reply, peer).await {
+                            debug!("Failed to answer echo request from {}: {}", peer, e);
+                        }
+                    }
+                }
+                _ = expiry.tick() => self.bridge.expire_idle(),
+            }
+        }
+    }
+
+    /// Answer one received IPv4 packet, None unless it's a tunnel echo request
+    ///
+    /// Ordinary pings are left to the kernel.
+    pub async fn handle(&self, source: IpAddr, packet: &[u8]) -> Option<Vec<u8>> {
+        let header_len = (*packet.first()? & 0x0F) as usize * 4;
+        let icmp = packet.get(header_len..)?;
+        if icmp.len() < ICMP_HEADER_SIZE || icmp[0] != ICMP_ECHO_REQUEST || icmp[1] != 0 {
+            return None;
+        }
+
+        let request = icmp[ICMP_HEADER_SIZE..].strip_prefix(REQUEST_MAGIC)?;
+        let length = u16::from_be_bytes([*request.first()?, *request.get(1)?]) as usize;
+        let data = request.get(LENGTH_SIZE..LENGTH_SIZE + length)?;
+        // Both magics are four bytes, so the reply matches the request's size
+        let budget = request.len().min(MAX_REPLY_DATA);
+        let identifier = u16::from_be_bytes([icmp[4], icmp[5]]);
+        let seq = u16::from_be_bytes([icmp[6], icmp[7]]);
+
+        let tunnel = format!("{}#{}", source, identifier);
+        let answer = match self.bridge.exchange(&tunnel, seq as u32, data, budget).await {
+            Ok(Some(answer)) => [&REPLY_MAGIC[..], &answer].concat(),
+            Ok(None) => CLOSED_MAGIC.to_vec(),
+            Err(e) => {
+                debug!("ICMP tunnel {} failed: {}", tunnel, e);
+                CLOSED_MAGIC.to_vec()
+            }
+        };
+
+        Some(echo_reply(&icmp[4..ICMP_HEADER_SIZE], &answer))
+    }
+}
+
+/// Echo reply with the identifier and sequence number (`rest`) of the request
+fn echo_reply(rest: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut reply = Vec::with_capacity(ICMP_HEADER_SIZE + payload.len());
+    reply.extend_from_slice(&[ICMP_ECHO_REPLY, 0, 0, 0]);
+    reply.extend_from_slice(rest);
+    reply.extend_from_slice(payload);
+
+    let sum = checksum(&reply);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    reply
+}
+
+/// Raw ICMPv4 socket; received packets include the IP header, sent ones don't
+fn raw_socket() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            LostLoveError::Network("ICMP transport needs root or CAP_NET_RAW".to_string())
+        } else {
+            e.into()
+        }
+    })?;
+    socket.set_nonblocking(true)?;
+
+    Ok(UdpSocket::from_std(std::net::UdpSocket::from(socket))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(198, 51, 100, 4));
+
+    /// IPv4 packet holding an echo request
+    fn echo_request(identifier: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+        let mut icmp = vec![ICMP_ECHO_REQUEST, 0, 0, 0];
+        icmp.extend_from_slice(&identifier.to_be_bytes());
+        icmp.extend_from_slice(&seq.to_be_bytes());
+        icmp.extend_from_slice(payload);
+        let sum = checksum(&icmp);
+        icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0];
+        packet.extend_from_slice(&[198, 51, 100, 4, 203, 0, 113, 10]);
+        packet.extend_from_slice(&icmp);
+        packet
+    }
+
+    fn request(identifier: u16, seq: u16, data: &[u8], padding: usize) -> Vec<u8> {
+        let mut payload = REQUEST_MAGIC.to_vec();
+        payload.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        payload.extend_from_slice(data);
+        payload.resize(payload.len() + padding, 0);
+        echo_request(identifier, seq, &payload)
+    }
+
+    fn server(target: SocketAddr) -> IcmpTunnelServer {
+        IcmpTunnelServer::new(target, &IcmpTunnelConfig::default())
+    }
+
+    #[tokio::test]
+    async fn test_ordinary_pings_ignored() {
+        let server = server("127.0.0.1:9".parse().unwrap());
+
+        assert!(server.handle(CLIENT, &echo_request(1, 1, b"abcdefgh")).await.is_none());
+        assert!(server.handle(CLIENT, &[0x45]).await.is_none());
+
+        let mut reply = request(1, 1, b"", 0);
+        reply[20] = ICMP_ECHO_REPLY;
+        assert!(server.handle(CLIENT, &reply).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_echo_tunnel() {
+        // Echo server standing in for the LLP listener
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let (mut stream, peer) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&buf[..n]).await.unwrap();
+            peer
+        });
+
+        let server = server(target);
+        let reply = server.handle(CLIENT, &request(7, 1, b"ping", 0)).await.unwrap();
+        assert_eq!(checksum(&reply), 0);
+        assert_eq!(&reply[..2], &[ICMP_ECHO_REPLY, 0]);
+        assert_eq!(&reply[4..8], &[0, 7, 0, 1]);
+        assert!(reply[8..].starts_with(REPLY_MAGIC));
+        let mut answer = reply[12..].to_vec();
+
+        assert!(server.is_tunnel(accepted.await.unwrap()));
+
+        // Polls padded by one byte get three bytes back at most
+        for seq in 2..50 {
+            if answer.len() == 4 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+            let poll = request(7, seq, b"", 1);
+            let reply = server.handle(CLIENT, &poll).await.unwrap();
+            assert!(reply.len() <= poll.len() - 20);
+            answer.extend_from_slice(&reply[12..]);
+        }
+        assert_eq!(answer, b"ping");
+    }
+}
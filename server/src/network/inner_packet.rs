@@ -0,0 +1,266 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::error::{LostLoveError, Result};
+
+/// Minimum IPv4 header size
+pub const IPV4_HEADER_MIN: usize = 20;
+
+/// Fixed IPv6 header size
+pub const IPV6_HEADER_SIZE: usize = 40;
+
+/// Transport protocol carried by an inner IP packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+    Other(u8),
+}
+
+impl IpProtocol {
+    /// Map an IP protocol / next-header number
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => IpProtocol::Icmp,
+            6 => IpProtocol::Tcp,
+            17 => IpProtocol::Udp,
+            58 => IpProtocol::Icmpv6,
+            other => IpProtocol::Other(other),
+        }
+    }
+
+    /// Get the raw protocol number
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            IpProtocol::Icmp => 1,
+            IpProtocol::Tcp => 6,
+            IpProtocol::Udp => 17,
+            IpProtocol::Icmpv6 => 58,
+            IpProtocol::Other(value) => *value,
+        }
+    }
+
+    /// Check if the protocol carries port numbers
+    pub fn has_ports(&self) -> bool {
+        matches!(self, IpProtocol::Tcp | IpProtocol::Udp)
+    }
+}
+
+impl fmt::Display for IpProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpProtocol::Tcp => write!(f, "tcp"),
+            IpProtocol::Udp => write!(f, "udp"),
+            IpProtocol::Icmp => write!(f, "icmp"),
+            IpProtocol::Icmpv6 => write!(f, "icmpv6"),
+            IpProtocol::Other(value) => write!(f, "ip-{}", value),
+        }
+    }
+}
+
+/// Header summary of an IP packet carried inside the tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InnerPacket {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: IpProtocol,
+    /// Offset of the transport header from the start of the packet
+    pub transport_offset: usize,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// Total length as declared by the IP header
+    pub total_length: usize,
+}
+
+impl InnerPacket {
+    /// Parse the IP and transport headers of a tunneled packet
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let version = data
+            .first()
+            .map(|b| b >> 4)
+            .ok_or(LostLoveError::InsufficientData { expected: 1, actual: 0 })?;
+
+        match version {
+            4 => Self::parse_ipv4(data),
+            6 => Self::parse_ipv6(data),
+            other => Err(LostLoveError::MalformedInnerPacket(format!(
+                "Unknown IP version {}",
+                other
+            ))),
+        }
+    }
+
+    fn parse_ipv4(data: &[u8]) -> Result<Self> {
+        if data.len() < IPV4_HEADER_MIN {
+            return Err(LostLoveError::InsufficientData {
+                expected: IPV4_HEADER_MIN,
+                actual: data.len(),
+            });
+        }
+
+        let header_len = ((data[0] & 0x0F) as usize) * 4;
+        if header_len < IPV4_HEADER_MIN || header_len > data.len() {
+            return Err(LostLoveError::MalformedInnerPacket(format!(
+                "Invalid IPv4 header length {}",
+                header_len
+            )));
+        }
+
+        let total_length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let fragment_offset = u16::from_be_bytes([data[6], data[7]]) & 0x1FFF;
+        let protocol = IpProtocol::from_u8(data[9]);
+        let source = Ipv4Addr::new(data[12], data[13], data[14], data[15]);
+        let destination = Ipv4Addr::new(data[16], data[17], data[18], data[19]);
+
+        // Only the first fragment carries the transport header
+        let (source_port, destination_port) = if fragment_offset == 0 {
+            parse_ports(protocol, data, header_len)
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            source: IpAddr::V4(source),
+            destination: IpAddr::V4(destination),
+            protocol,
+            transport_offset: header_len,
+            source_port,
+            destination_port,
+            total_length,
+        })
+    }
+
+    fn parse_ipv6(data: &[u8]) -> Result<Self> {
+        if data.len() < IPV6_HEADER_SIZE {
+            return Err(LostLoveError::InsufficientData {
+                expected: IPV6_HEADER_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let payload_length = u16::from_be_bytes([data[4], data[5]]) as usize;
+        // Extension headers are not walked; they show up as Other(next_header)
+        let protocol = IpProtocol::from_u8(data[6]);
+
+        let mut source = [0u8; 16];
+        source.copy_from_slice(&data[8..24]);
+        let mut destination = [0u8; 16];
+        destination.copy_from_slice(&data[24..40]);
+
+        let (source_port, destination_port) = parse_ports(protocol, data, IPV6_HEADER_SIZE);
+
+        Ok(Self {
+            source: IpAddr::V6(Ipv6Addr::from(source)),
+            destination: IpAddr::V6(Ipv6Addr::from(destination)),
+            protocol,
+            transport_offset: IPV6_HEADER_SIZE,
+            source_port,
+            destination_port,
+            total_length: IPV6_HEADER_SIZE + payload_length,
+        })
+    }
+}
+
+/// Read source/destination ports if the transport header is present
+fn parse_ports(protocol: IpProtocol, data: &[u8], offset: usize) -> (Option<u16>, Option<u16>) {
+    if !protocol.has_ports() || data.len() < offset + 4 {
+        return (None, None);
+    }
+
+    let source_port = u16::from_be_bytes([data[offset], data[offset + 1]]);
+    let destination_port = u16::from_be_bytes([data[offset + 2], data[offset + 3]]);
+
+    (Some(source_port), Some(destination_port))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build a minimal IPv4 packet with a transport header stub
+    pub(crate) fn ipv4_packet(protocol: u8, src: [u8; 4], dst: [u8; 4], ports: (u16, u16)) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&40u16.to_be_bytes());
+        packet[8] = 64;
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&src);
+        packet[16..20].copy_from_slice(&dst);
+        packet[20..22].copy_from_slice(&ports.0.to_be_bytes());
+        packet[22..24].copy_from_slice(&ports.1.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_parse_ipv4_tcp() {
+        let data = ipv4_packet(6, [10, 8, 0, 2], [93, 184, 216, 34], (51000, 443));
+        let packet = InnerPacket::parse(&data).unwrap();
+
+        assert_eq!(packet.protocol, IpProtocol::Tcp);
+        assert_eq!(packet.source, "10.8.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.destination, "93.184.216.34".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.source_port, Some(51000));
+        assert_eq!(packet.destination_port, Some(443));
+        assert_eq!(packet.transport_offset, 20);
+        assert_eq!(packet.total_length, 40);
+    }
+
+    #[test]
+    fn test_parse_ipv4_icmp_has_no_ports() {
+        let data = ipv4_packet(1, [10, 8, 0, 2], [8, 8, 8, 8], (0x0800, 0));
+        let packet = InnerPacket::parse(&data).unwrap();
+
+        assert_eq!(packet.protocol, IpProtocol::Icmp);
+        assert_eq!(packet.destination_port, None);
+    }
+
+    #[test]
+    fn test_parse_ipv4_non_first_fragment() {
+        let mut data = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (5353, 53));
+        data[7] = 0x10;
+        let packet = InnerPacket::parse(&data).unwrap();
+
+        assert_eq!(packet.protocol, IpProtocol::Udp);
+        assert_eq!(packet.destination_port, None);
+    }
+
+    #[test]
+    fn test_parse_ipv6_udp() {
+        let mut data = vec![0u8; 48];
+        data[0] = 0x60;
+        data[4..6].copy_from_slice(&8u16.to_be_bytes());
+        data[6] = 17;
+        data[23] = 1;
+        data[39] = 2;
+        data[40..42].copy_from_slice(&40000u16.to_be_bytes());
+        data[42..44].copy_from_slice(&53u16.to_be_bytes());
+
+        let packet = InnerPacket::parse(&data).unwrap();
+        assert_eq!(packet.protocol, IpProtocol::Udp);
+        assert_eq!(packet.source, "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.destination, "::2".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.destination_port, Some(53));
+        assert_eq!(packet.total_length, 48);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(InnerPacket::parse(&[]).is_err());
+        assert!(InnerPacket::parse(&[0x45, 0, 0]).is_err());
+        assert!(InnerPacket::parse(&[0x70; 40]).is_err());
+
+        // IHL smaller than the minimum header
+        let mut data = ipv4_packet(6, [10, 8, 0, 2], [10, 8, 0, 1], (1, 2));
+        data[0] = 0x43;
+        assert!(InnerPacket::parse(&data).is_err());
+    }
+
+    #[test]
+    fn test_protocol_display() {
+        assert_eq!(IpProtocol::Tcp.to_string(), "tcp");
+        assert_eq!(IpProtocol::Other(47).to_string(), "ip-47");
+        assert_eq!(IpProtocol::from_u8(47).as_u8(), 47);
+    }
+}
@@ -0,0 +1,354 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::error::{LostLoveError, Result};
+
+/// IPv4 address with a prefix length, e.g. `10.8.0.1/24`
+///
+/// Host bits are kept: a TUN address names both the interface address and
+/// its network. Use `trunc` for the network alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Ipv4Net {
+    address: Ipv4Addr,
+    prefix: u8,
+}
+
+/// IPv6 address with a prefix length, e.g. `fd00:8::1/64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Ipv6Net {
+    address: Ipv6Addr,
+    prefix: u8,
+}
+
+/// IPv4 or IPv6 network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum IpNet {
+    V4(Ipv4Net),
+    V6(Ipv6Net),
+}
+
+fn invalid(cidr: &str, reason: &'static str) -> LostLoveError {
+    LostLoveError::InvalidCidr {
+        cidr: cidr.to_string(),
+        reason,
+    }
+}
+
+impl Ipv4Net {
+    pub fn new(address: Ipv4Addr, prefix: u8) -> Result<Self> {
+        if prefix > 32 {
+            return Err(invalid(&format!("{}/{}", address, prefix), "prefix length must be at most 32"));
+        }
+        Ok(Self { address, prefix })
+    }
+
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.mask())
+    }
+
+    /// Address with the host bits cleared
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) & self.mask())
+    }
+
+    /// Address with all host bits set
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) | !self.mask())
+    }
+
+    /// Same network without the host bits
+    pub fn trunc(&self) -> Self {
+        Self {
+            address: self.network(),
+            prefix: self.prefix,
+        }
+    }
+
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        u32::from(address) & self.mask() == u32::from(self.network())
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0)
+    }
+}
+
+impl Ipv6Net {
+    pub fn new(address: Ipv6Addr, prefix: u8) -> Result<Self> {
+        if prefix > 128 {
+            return Err(invalid(&format!("{}/{}", address, prefix), "prefix length must be at most 128"));
+        }
+        Ok(Self { address, prefix })
+    }
+
+    pub fn address(&self) -> Ipv6Addr {
+        self.address
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn netmask(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.mask())
+    }
+
+    /// Address with the host bits cleared
+    pub fn network(&self) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.address) & self.mask())
+    }
+
+    /// Same network without the host bits
+    pub fn trunc(&self) -> Self {
+        Self {
+            address: self.network(),
+            prefix: self.prefix,
+        }
+    }
+
+    pub fn contains(&self, address: Ipv6Addr) -> bool {
+        u128::from(address) & self.mask() == u128::from(self.network())
+    }
+
+    fn mask(&self) -> u128 {
+        u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0)
+    }
+}
+
+impl IpNet {
+    /// `0.0.0.0/0`, the IPv4 default route
+    pub const DEFAULT_V4: IpNet = IpNet::V4(Ipv4Net {
+        address: Ipv4Addr::UNSPECIFIED,
+        prefix: 0,
+    });
+
+    pub fn address(&self) -> IpAddr {
+        match self {
+            IpNet::V4(net) => IpAddr::V4(net.address()),
+            IpNet::V6(net) => IpAddr::V6(net.address()),
+        }
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        match self {
+            IpNet::V4(net) => net.prefix_len(),
+            IpNet::V6(net) => net.prefix_len(),
+        }
+    }
+
+    pub fn netmask(&self) -> IpAddr {
+        match self {
+            IpNet::V4(net) => IpAddr::V4(net.netmask()),
+            IpNet::V6(net) => IpAddr::V6(net.netmask()),
+        }
+    }
+
+    /// Address with the host bits cleared
+    pub fn network(&self) -> IpAddr {
+        match self {
+            IpNet::V4(net) => IpAddr::V4(net.network()),
+            IpNet::V6(net) => IpAddr::V6(net.network()),
+        }
+    }
+
+    /// Same network without the host bits
+    pub fn trunc(&self) -> Self {
+        match self {
+            IpNet::V4(net) => IpNet::V4(net.trunc()),
+            IpNet::V6(net) => IpNet::V6(net.trunc()),
+        }
+    }
+
+    /// Whether the address is in this network, never across families
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self, address) {
+            (IpNet::V4(net), IpAddr::V4(address)) => net.contains(address),
+            (IpNet::V6(net), IpAddr::V6(address)) => net.contains(address),
+            _ => false,
+        }
+    }
+
+    /// The IPv4 network, or an error naming `what` for IPv4-only uses
+    pub fn ipv4(&self, what: &str) -> Result<Ipv4Net> {
+        match self {
+            IpNet::V4(net) => Ok(*net),
+            IpNet::V6(net) => Err(LostLoveError::Network(format!(
+                "{} {} is IPv6, only IPv4 is supported there",
+                what, net
+            ))),
+        }
+    }
+}
+
+/// Split `address/prefix`, checking the prefix is a plain decimal number
+fn split(cidr: &str) -> Result<(&str, u8)> {
+    let (address, prefix) = cidr
+        .split_once('/')
+        .ok_or_else(|| invalid(cidr, "expected address/prefix"))?;
+    if prefix.is_empty() || !prefix.bytes().all(|b| b.is_ascii_digit()) || prefix.len() > 3 {
+        return Err(invalid(cidr, "invalid prefix length"));
+    }
+    let prefix = prefix.parse().map_err(|_| invalid(cidr, "invalid prefix length"))?;
+    Ok((address, prefix))
+}
+
+impl FromStr for Ipv4Net {
+    type Err = LostLoveError;
+
+    fn from_str(cidr: &str) -> Result<Self> {
+        let (address, prefix) = split(cidr)?;
+        let address = address.parse().map_err(|_| invalid(cidr, "invalid IPv4 address"))?;
+        Self::new(address, prefix).map_err(|_| invalid(cidr, "prefix length must be at most 32"))
+    }
+}
+
+impl FromStr for Ipv6Net {
+    type Err = LostLoveError;
+
+    fn from_str(cidr: &str) -> Result<Self> {
+        let (address, prefix) = split(cidr)?;
+        let address = address.parse().map_err(|_| invalid(cidr, "invalid IPv6 address"))?;
+        Self::new(address, prefix).map_err(|_| invalid(cidr, "prefix length must be at most 128"))
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = LostLoveError;
+
+    fn from_str(cidr: &str) -> Result<Self> {
+        let (address, _) = split(cidr)?;
+        match address.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => cidr.parse().map(IpNet::V4),
+            Ok(IpAddr::V6(_)) => cidr.parse().map(IpNet::V6),
+            Err(_) => Err(invalid(cidr, "invalid IP address")),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl fmt::Display for Ipv6Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpNet::V4(net) => net.fmt(f),
+            IpNet::V6(net) => net.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(cidr: &str) -> IpNet {
+        cidr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_ipv4() {
+        let tun = net("10.8.0.1/24");
+        assert_eq!(tun.address(), "10.8.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(tun.prefix_len(), 24);
+        assert_eq!(tun.netmask(), "255.255.255.0".parse::<IpAddr>().unwrap());
+        assert_eq!(tun.network(), "10.8.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(tun.trunc().to_string(), "10.8.0.0/24");
+        assert_eq!(tun.to_string(), "10.8.0.1/24");
+
+        let IpNet::V4(v4) = net("192.168.1.1/16") else { panic!() };
+        assert_eq!(v4.netmask(), Ipv4Addr::new(255, 255, 0, 0));
+        assert_eq!(v4.broadcast(), Ipv4Addr::new(192, 168, 255, 255));
+
+        assert_eq!(net("0.0.0.0/0").netmask(), "0.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(net("10.0.0.7/32").network(), "10.0.0.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_parse_ipv6() {
+        let tun = net("fd00:8::1/64");
+        assert_eq!(tun.network(), "fd00:8::".parse::<IpAddr>().unwrap());
+        assert_eq!(tun.netmask(), "ffff:ffff:ffff:ffff::".parse::<IpAddr>().unwrap());
+        assert_eq!(tun.trunc().to_string(), "fd00:8::/64");
+
+        assert_eq!(net("::/0").netmask(), "::".parse::<IpAddr>().unwrap());
+        assert_eq!(net("2001:db8::1/128").network(), "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(net("2001:DB8::/32").to_string(), "2001:db8::/32");
+        assert!(matches!(net("::ffff:10.0.0.1/120"), IpNet::V6(_)));
+    }
+
+    #[test]
+    fn test_contains() {
+        let v4 = net("10.20.5.1/24");
+        assert!(v4.contains("10.20.5.255".parse().unwrap()));
+        assert!(!v4.contains("10.20.6.0".parse().unwrap()));
+        assert!(net("0.0.0.0/0").contains("203.0.113.9".parse().unwrap()));
+
+        let v6 = net("2001:db8:a::/48");
+        assert!(v6.contains("2001:db8:a:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db8:b::1".parse().unwrap()));
+
+        // Never across families, not even for the default routes
+        assert!(!net("::/0").contains("10.0.0.1".parse().unwrap()));
+        assert!(!net("0.0.0.0/0").contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_cidr() {
+        for cidr in [
+            "10.8.0.1",
+            "invalid/24",
+            "10.8.0.1/33",
+            "10.8.0.1/",
+            "10.8.0.1/-1",
+            "10.8.0.1/+8",
+            "10.8.0.1/ 8",
+            "10.8.0.1/0024",
+            "10.8.0.1/24/8",
+            "10.8.0/24",
+            "fd00::1/129",
+            "fd00::g/64",
+            "/24",
+            "",
+        ] {
+            assert!(matches!(cidr.parse::<IpNet>(), Err(LostLoveError::InvalidCidr { .. })), "{:?}", cidr);
+        }
+
+        // Family-specific parsers refuse the other family
+        assert!("fd00::1/64".parse::<Ipv4Net>().is_err());
+        assert!("10.8.0.1/24".parse::<Ipv6Net>().is_err());
+    }
+
+    #[test]
+    fn test_ipv4_only_uses() {
+        assert_eq!(net("10.8.0.1/24").ipv4("tun_address").unwrap().prefix_len(), 24);
+        let error = net("fd00::1/64").ipv4("tun_address").unwrap_err();
+        assert!(error.to_string().contains("tun_address fd00::1/64 is IPv6"));
+    }
+
+    #[test]
+    fn test_roundtrip_and_order() {
+        for cidr in ["10.8.0.1/24", "0.0.0.0/0", "fd00:8::1/64", "::/0", "2001:db8::/32"] {
+            assert_eq!(net(cidr).to_string(), cidr);
+            assert_eq!(net(&net(cidr).to_string()), net(cidr));
+        }
+        assert!(net("10.0.0.0/8") < net("::/0"));
+    }
+}
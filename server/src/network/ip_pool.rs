@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use crate::core::session::SessionId;
+use crate::error::{LostLoveError, Result};
+use crate::network::ip_net::IpNet;
+
+/// Pool of tunnel addresses leased to sessions
+///
+/// Built from the server's `tun_address`: the server keeps its own address,
+/// the network and broadcast addresses are never handed out.
+pub struct IpPool {
+    server_address: Ipv4Addr,
+    first: u32,
+    last: u32,
+    leases: Mutex<PoolState>,
+}
+
+struct PoolState {
+    leased: HashMap<Ipv4Addr, SessionId>,
+    next: u32,
+}
+
+impl IpPool {
+    /// Create pool from the server's tunnel address in CIDR notation
+    pub fn from_cidr(cidr: &str) -> Result<Self> {
+        let tun_address = cidr.parse::<IpNet>()?.ipv4("tun_address")?;
+        let server_address = tun_address.address();
+        let network = u32::from(tun_address.network());
+        let broadcast = u32::from(tun_address.broadcast());
+
+        if broadcast - network < 2 {
+            return Err(LostLoveError::Network(format!(
+                "Tunnel network {} has no room for clients",
+                cidr
+            )));
+        }
+
+        Ok(Self {
+            server_address,
+            first: network + 1,
+            last: broadcast - 1,
+            leases: Mutex::new(PoolState {
+                leased: HashMap::new(),
+                next: network + 1,
+            }),
+        })
+    }
+
+    /// Server's own tunnel address
+    pub fn server_address(&self) -> Ipv4Addr {
+        self.server_address
+    }
+
+    /// Check whether an address belongs to the pool's network
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        (self.first..=self.last).contains(&u32::from(address))
+    }
+
+    /// Lease a free address to a session
+    pub fn allocate(&self, session_id: &SessionId) -> Result<Ipv4Addr> {
+        let mut state = self.leases.lock().unwrap();
+        let size = self.last - self.first + 1;
+
+        for _ in 0..size {
+            let candidate = Ipv4Addr::from(state.next);
+            state.next = if state.next >= self.last { self.first } else { state.next + 1 };
+
+            if candidate != self.server_address && !state.leased.contains_key(&candidate) {
+                state.leased.insert(candidate, *session_id);
+                return Ok(candidate);
+            }
+        }
+
+        Err(LostLoveError::Network("Tunnel address pool exhausted".to_string()))
+    }
+
+    /// Return a leased address to the pool
+    pub fn release(&self, address: Ipv4Addr) {
+        self.leases.lock().unwrap().leased.remove(&address);
+    }
+
+    /// Session holding an address
+    pub fn lease_holder(&self, address: Ipv4Addr) -> Option<SessionId> {
+        self.leases.lock().unwrap().leased.get(&address).cloned()
+    }
+
+    /// Number of active leases
+    pub fn leased_count(&self) -> usize {
+        self.leases.lock().unwrap().leased.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocation_skips_server_address() {
+        let pool = IpPool::from_cidr("10.8.0.1/30").unwrap();
+
+        // /30 has two usable addresses, one of them is the server's
+        let lease = pool.allocate(&SessionId::new()).unwrap();
+        assert_eq!(lease, Ipv4Addr::new(10, 8, 0, 2));
+        assert!(pool.allocate(&SessionId::new()).is_err());
+
+        pool.release(lease);
+        assert_eq!(pool.allocate(&SessionId::new()).unwrap(), lease);
+    }
+
+    #[test]
+    fn test_lease_holder() {
+        let pool = IpPool::from_cidr("10.8.0.1/24").unwrap();
+        let session_id = SessionId::new();
+
+        let lease = pool.allocate(&session_id).unwrap();
+        assert_eq!(pool.lease_holder(lease), Some(session_id));
+        assert_eq!(pool.leased_count(), 1);
+        assert!(pool.contains(lease));
+        assert!(!pool.contains(Ipv4Addr::new(10, 9, 0, 2)));
+    }
+
+    #[test]
+    fn test_network_too_small() {
+        assert!(IpPool::from_cidr("10.8.0.1/31").is_err());
+    }
+
+    #[test]
+    fn test_ipv6_refused() {
+        assert!(IpPool::from_cidr("fd00:8::1/64").is_err());
+        assert!(IpPool::from_cidr("10.8.0.1").is_err());
+    }
+}
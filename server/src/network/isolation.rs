@@ -0,0 +1,126 @@
+use std::net::{IpAddr, Ipv4Addr};
+use tracing::debug;
+
+use crate::config::NetworkConfig;
+use crate::error::{LostLoveError, Result};
+use crate::network::nat::{RuleCommand, RuleGuard};
+use crate::network::ip_net::{IpNet, Ipv4Net};
+use crate::network::netlink::{Route, Rule};
+
+/// Per-client routing tables for multi-tenant deployments (Linux)
+///
+/// The server has a single TUN device, so instead of a namespace per client
+/// every leased address gets a policy rule into its own table. That table
+/// only holds the egress default route: traffic towards other clients or the
+/// server's internal networks leaves through the egress interface instead of
+/// being delivered. Tunnel-to-tunnel forwarding is additionally dropped by the
+/// NAT rules.
+#[derive(Debug, Clone)]
+pub struct ClientIsolation {
+    tun_name: String,
+    network: Ipv4Addr,
+    interface: String,
+    gateway: Option<Ipv4Addr>,
+    table_base: u32,
+}
+
+impl ClientIsolation {
+    /// Create isolation from network config, None when disabled
+    pub fn new(config: &NetworkConfig) -> Result<Option<Self>> {
+        if !config.isolation.enabled {
+            return Ok(None);
+        }
+
+        let tun_address = config.tun_address.parse::<IpNet>()?.ipv4("tun_address")?;
+        let interface = config.egress.interface.clone().ok_or_else(|| {
+            LostLoveError::Config("network.isolation requires network.egress.interface".to_string())
+        })?;
+
+        Ok(Some(Self {
+            tun_name: config.tun_name.clone(),
+            network: tun_address.network(),
+            interface,
+            gateway: config.egress.gateway,
+            table_base: config.isolation.table_base,
+        }))
+    }
+
+    /// Routing table of a leased address
+    pub fn table(&self, lease: Ipv4Addr) -> u32 {
+        self.table_base + (u32::from(lease) - u32::from(self.network))
+    }
+
+    /// Rules confining one client
+    pub fn commands(&self, lease: Ipv4Addr) -> Vec<RuleCommand> {
+        let table = self.table(lease);
+        let source = Ipv4Net::new(lease, 32).expect("a /32 is always valid");
+
+        vec![
+            RuleCommand::route(Route {
+                destination: IpNet::DEFAULT_V4,
+                gateway: self.gateway.map(IpAddr::V4),
+                device: Some(self.interface.clone()),
+                table: Some(table),
+            }),
+            RuleCommand::rule(Rule {
+                source: Some(IpNet::V4(source)),
+                fwmark: None,
+                input_interface: Some(self.tun_name.clone()),
+                table,
+            }),
+        ]
+    }
+
+    /// Confine a client for as long as the returned guard lives
+    pub fn attach(&self, lease: Ipv4Addr) -> Result<RuleGuard> {
+        debug!("Isolating {} in routing table {}", lease, self.table(lease));
+        RuleGuard::install(self.commands(lease))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn isolation() -> ClientIsolation {
+        let mut network = Config::default_for_testing().network;
+        network.manage_nat = true;
+        network.egress.interface = Some("eth1".to_string());
+        network.isolation.enabled = true;
+
+        ClientIsolation::new(&network).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let network = Config::default_for_testing().network;
+        assert!(ClientIsolation::new(&network).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_table_per_lease() {
+        let isolation = isolation();
+
+        assert_eq!(isolation.table(Ipv4Addr::new(10, 8, 0, 2)), 1002);
+        assert_eq!(isolation.table(Ipv4Addr::new(10, 8, 0, 200)), 1200);
+    }
+
+    #[test]
+    fn test_client_rules() {
+        let isolation = isolation();
+        let lines: Vec<String> = isolation
+            .commands(Ipv4Addr::new(10, 8, 0, 2))
+            .iter()
+            .map(|c| c.display(&c.install_args()))
+            .collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                "ip route add default dev eth1 table 1002",
+                "ip rule add from 10.8.0.2/32 iif hfp0 table 1002",
+            ]
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use dashmap::DashMap;
+use hkdf::hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::config::KnockConfig;
+use crate::core::clock::{self, Clock};
+use crate::crypto::ct;
+use crate::error::{LostLoveError, Result};
+use crate::network::nat::{RuleCommand, RuleGuard};
+
+/// Current knock packet format
+pub const KNOCK_VERSION: u8 = 1;
+
+/// Version, timestamp, nonce and HMAC-SHA256 tag
+pub const KNOCK_SIZE: usize = 1 + 8 + 16 + 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &[u8], message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac
+}
+
+/// Build a knock packet (client side)
+pub fn knock_packet(secret: &[u8], timestamp: u64, nonce: [u8; 16]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(KNOCK_SIZE);
+    packet.push(KNOCK_VERSION);
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&nonce);
+
+    let tag = mac(secret, &packet).finalize().into_bytes();
+    packet.extend_from_slice(&tag);
+    packet
+}
+
+/// An address allowed to connect, with its firewall exception if any
+struct Opening {
+    until: Instant,
+    _rules: Option<RuleGuard>,
+}
+
+/// Single packet authorization gate in front of the TCP listener
+///
+/// The server never answers on the knock port. A knock is accepted once:
+/// its nonce is remembered for as long as its timestamp is acceptable.
+/// Connections already established outlive the window.
+pub struct KnockGate {
+    secret: Vec<u8>,
+    address: String,
+    tcp_port: u16,
+    window: Duration,
+    max_skew: u64,
+    firewall: bool,
+    openings: DashMap<IpAddr, Opening>,
+    nonces: DashMap<[u8; 16], u64>,
+    clock: Arc<dyn Clock>,
+}
+
+impl KnockGate {
+    /// Create gate from config, None when disabled
+    pub fn new(config: &KnockConfig, bind_address: &str, tcp_port: u16) -> Option<Self> {
+        config.enabled.then(|| Self {
+            secret: config.secret.as_bytes().to_vec(),
+            address: format!("{}:{}", bind_address, config.port),
+            tcp_port,
+            window: Duration::from_secs(config.window),
+            max_skew: config.max_skew,
+            firewall: config.firewall,
+            openings: DashMap::new(),
+            nonces: DashMap::new(),
+            clock: clock::system(),
+        })
+    }
+
+    /// Check timestamps and windows against `clock` instead of the system clocks
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether a source address may connect right now
+    ///
+    /// Loopback needs a knock like any other address; the DNS and ICMP
+    /// bridges connect from there, so they can't run behind knocks.
+    pub fn is_allowed(&self, address: IpAddr) -> bool {
+        self.openings
+            .get(&address)
+            .is_some_and(|opening| self.clock.now() < opening.until)
+    }
+
+    /// Rules hiding the listener, installed for the server's lifetime (firewall mode)
+    pub fn base_rules(&self) -> Vec<RuleCommand> {
+        if !self.firewall {
+            return Vec::new();
+        }
+
+        let port = self.tcp_port.to_string();
+        vec![
+            RuleCommand::iptables(
+                "filter",
+                "INPUT",
+                &["-p", "tcp", "--dport", &port, "-m", "state", "--state", "RELATED,ESTABLISHED", "-j", "ACCEPT"],
+            )
+            .prepended(),
+            RuleCommand::iptables("filter", "INPUT", &["-p", "tcp", "--dport", &port, "-j", "DROP"]),
+        ]
+    }
+
+    /// Rule letting one address through for the window (firewall mode)
+    fn opening_rule(&self, address: IpAddr) -> RuleCommand {
+        let source = address.to_string();
+        let port = self.tcp_port.to_string();
+        RuleCommand::iptables(
+            "filter",
+            "INPUT",
+            &["-p", "tcp", "-s", &source, "--dport", &port, "-j", "ACCEPT"],
+        )
+        .prepended()
+    }
+
+    /// Check a knock packet, remembering its nonce
+    fn verify(&self, packet: &[u8]) -> Result<()> {
+        if packet.len() != KNOCK_SIZE || packet[0] != KNOCK_VERSION {
+            return Err(LostLoveError::AuthFailed {
+                reason: "malformed knock".to_string(),
+            });
+        }
+
+        let (message, tag) = packet.split_at(KNOCK_SIZE - 32);
+        if !ct::eq(&mac(&self.secret, message).finalize().into_bytes(), tag) {
+            return Err(LostLoveError::AuthFailed {
+                reason: "bad knock signature".to_string(),
+            });
+        }
+
+        let timestamp = u64::from_be_bytes(message[1..9].try_into().unwrap());
+        if self.clock.unix_now().abs_diff(timestamp) > self.max_skew {
+            return Err(LostLoveError::AuthFailed {
+                reason: "stale knock".to_string(),
+            });
+        }
+
+        let nonce: [u8; 16] = message[9..25].try_into().unwrap();
+        if self.nonces.insert(nonce, timestamp).is_some() {
+            return Err(LostLoveError::AuthFailed {
+                reason: "replayed knock".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Handle a knock from `source`, opening the window if it's valid
+    pub fn knock(&self, source: IpAddr, packet: &[u8]) -> Result<()> {
+        self.verify(packet)?;
+
+        let until = self.clock.now() + self.window;
+        if let Some(mut opening) = self.openings.get_mut(&source) {
+            opening.until = until;
+            return Ok(());
+        }
+
+        let rules = match source {
+            IpAddr::V4(_) if self.firewall => match RuleGuard::install(vec![self.opening_rule(source)]) {
+                Ok(guard) => Some(guard),
+                Err(e) => {
+                    warn!("Failed to open firewall for {}: {}", source, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        info!("Knock from {} accepted, open for {:?}", source, self.window);
+        self.openings.insert(source, Opening { until, _rules: rules });
+        Ok(())
+    }
+
+    /// Close expired windows and forget nonces too old to be replayed
+    pub fn expire(&self) {
+        let now = self.clock.now();
+        self.openings.retain(|_, opening| now < opening.until);
+
+        let oldest = self.clock.unix_now().saturating_sub(self.max_skew);
+        self.nonces.retain(|_, timestamp| *timestamp >= oldest);
+    }
+
+    /// Receive knocks until the task is cancelled, never answering
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let socket = UdpSocket::bind(&self.address).await?;
+        info!("Listening for knocks on udp://{}", self.address);
+
+        let mut buf = [0u8; KNOCK_SIZE + 1];
+        let mut expiry = time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (n, peer) = received?;
+                    if let Err(e) = self.knock(peer.ip(), &buf[..n]) {
+                        debug!("Ignoring knock from {}: {}", peer, e);
+                    }
+                }
+                _ = expiry.tick() => self.expire(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::ManualClock;
+    use crate::network::port_hopping::unix_now;
+
+    const SECRET: &[u8] = b"correct horse";
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(198, 51, 100, 4));
+
+    fn gate(window: u64) -> KnockGate {
+        let config = KnockConfig {
+            enabled: true,
+            secret: String::from_utf8(SECRET.to_vec()).unwrap(),
+            window,
+            ..KnockConfig::default()
+        };
+        KnockGate::new(&config, "127.0.0.1", 8443).unwrap()
+    }
+
+    #[test]
+    fn test_valid_knock_opens_window() {
+        let gate = gate(30);
+        assert!(!gate.is_allowed(CLIENT));
+
+        gate.knock(CLIENT, &knock_packet(SECRET, unix_now(), [1; 16])).unwrap();
+        assert!(gate.is_allowed(CLIENT));
+        assert!(!gate.is_allowed("198.51.100.5".parse().unwrap()));
+        assert!(!gate.is_allowed("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_bad_knocks_rejected() {
+        let gate = gate(30);
+        let now = unix_now();
+
+        assert!(gate.knock(CLIENT, &knock_packet(b"wrong", now, [1; 16])).is_err());
+        assert!(gate.knock(CLIENT, &knock_packet(SECRET, now - 600, [2; 16])).is_err());
+        assert!(gate.knock(CLIENT, &knock_packet(SECRET, now, [3; 16])[..40]).is_err());
+
+        let knock = knock_packet(SECRET, now, [4; 16]);
+        gate.knock(CLIENT, &knock).unwrap();
+        assert!(gate.knock("198.51.100.5".parse().unwrap(), &knock).is_err());
+    }
+
+    #[test]
+    fn test_window_closes() {
+        let gate = gate(0);
+        gate.knock(CLIENT, &knock_packet(SECRET, unix_now(), [1; 16])).unwrap();
+        assert!(!gate.is_allowed(CLIENT));
+
+        gate.expire();
+        assert!(gate.openings.is_empty());
+        assert_eq!(gate.nonces.len(), 1);
+    }
+
+    #[test]
+    fn test_clock_skew_and_expiry() {
+        let clock = Arc::new(ManualClock::new());
+        let gate = gate(30).with_clock(clock.clone());
+        let client_time = clock.unix_now();
+
+        // Server's wall clock a minute ahead of the client's
+        clock.set_system_time(std::time::UNIX_EPOCH + Duration::from_secs(client_time + 60));
+        assert!(gate.knock(CLIENT, &knock_packet(SECRET, client_time, [1; 16])).is_err());
+        gate.knock(CLIENT, &knock_packet(SECRET, client_time + 75, [2; 16])).unwrap();
+
+        clock.advance(Duration::from_secs(29));
+        gate.expire();
+        assert!(gate.is_allowed(CLIENT));
+
+        clock.advance(Duration::from_secs(1));
+        gate.expire();
+        assert!(!gate.is_allowed(CLIENT));
+        assert_eq!(gate.nonces.len(), 1);
+
+        // Remembered until its timestamp falls out of the skew allowance
+        clock.advance(Duration::from_secs(16));
+        gate.expire();
+        assert!(gate.nonces.is_empty());
+    }
+
+    #[test]
+    fn test_firewall_rules() {
+        let mut gate = gate(30);
+        assert!(gate.base_rules().is_empty());
+
+        gate.firewall = true;
+        let lines: Vec<String> = gate
+            .base_rules()
+            .iter()
+            .chain([&gate.opening_rule(CLIENT)])
+            .map(|rule| rule.display(&rule.install_args()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "iptables -t filter -I INPUT -p tcp --dport 8443 -m state --state RELATED,ESTABLISHED -j ACCEPT",
+                "iptables -t filter -A INPUT -p tcp --dport 8443 -j DROP",
+                "iptables -t filter -I INPUT -p tcp -s 198.51.100.4 --dport 8443 -j ACCEPT",
+            ]
+        );
+    }
+}
@@ -1,5 +1,56 @@
-pub mod tun_interface;
 pub mod router;
+pub mod data_path;
+pub mod inner_packet;
+pub mod ip_pool;
+pub mod firewall;
+pub mod mss;
+pub mod icmp;
+pub mod nat;
+pub mod isolation;
+pub mod site_routes;
+pub mod relay;
+pub mod discovery;
+pub mod udp_transport;
+pub mod port_hopping;
+pub mod bridge;
+pub mod dns_tunnel;
+pub mod dns_forwarder;
+pub mod dns_upstream;
+pub mod icmp_tunnel;
+pub mod knock;
+pub mod tcp_tuning;
+pub mod dscp;
+pub mod udp_socket;
+pub mod path_mtu;
+pub mod descriptor_server;
+pub mod ip_net;
+pub mod offload;
+pub mod netlink;
+#[cfg(target_os = "linux")]
+pub mod tun_queue;
+#[cfg(target_os = "linux")]
+pub mod tun_device;
+// Network impairment for tests only, never compiled into the server
+#[cfg(test)]
+pub mod chaos;
 
-pub use tun_interface::TunInterface;
 pub use router::PacketRouter;
+pub use data_path::DataPath;
+pub use ip_pool::IpPool;
+pub use firewall::Firewall;
+pub use nat::{NatRules, RuleGuard};
+pub use isolation::ClientIsolation;
+pub use site_routes::SiteRoutes;
+pub use relay::Relay;
+pub use discovery::DiscoveryServer;
+pub use udp_transport::UdpTransport;
+pub use port_hopping::PortSchedule;
+pub use dns_tunnel::DnsTunnelServer;
+pub use dns_forwarder::DnsForwarder;
+pub use icmp_tunnel::IcmpTunnelServer;
+pub use knock::KnockGate;
+pub use dscp::DscpMarker;
+pub use descriptor_server::DescriptorServer;
+pub use ip_net::IpNet;
+#[cfg(target_os = "linux")]
+pub use tun_device::TunDevice;
@@ -0,0 +1,205 @@
+use tracing::trace;
+
+use crate::network::inner_packet::{InnerPacket, IpProtocol, IPV4_HEADER_MIN, IPV6_HEADER_SIZE};
+
+/// Minimum TCP header size
+const TCP_HEADER_MIN: usize = 20;
+
+/// TCP SYN flag
+const TCP_FLAG_SYN: u8 = 0x02;
+
+/// TCP option kinds
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+/// Rewrites the MSS option of TCP SYNs so segments fit the tunnel MTU
+///
+/// Clients behind paths that drop ICMP "fragmentation needed" would otherwise
+/// negotiate an MSS based on their local link and stall on full-size segments.
+#[derive(Debug, Clone, Copy)]
+pub struct MssClamp {
+    max_mss_v4: u16,
+    max_mss_v6: u16,
+}
+
+impl MssClamp {
+    /// Create clamp for a tunnel MTU
+    pub fn new(mtu: usize) -> Self {
+        let mss = |ip_header: usize| mtu.saturating_sub(ip_header + TCP_HEADER_MIN).min(u16::MAX as usize) as u16;
+
+        Self {
+            max_mss_v4: mss(IPV4_HEADER_MIN),
+            max_mss_v6: mss(IPV6_HEADER_SIZE),
+        }
+    }
+
+    /// Clamp the MSS of a TCP SYN in place
+    ///
+    /// Returns the original and new MSS when the packet was rewritten.
+    pub fn apply(&self, packet: &mut [u8]) -> Option<(u16, u16)> {
+        let inner = InnerPacket::parse(packet).ok()?;
+
+        // Non-first fragments and truncated packets have no ports
+        if inner.protocol != IpProtocol::Tcp || inner.source_port.is_none() {
+            return None;
+        }
+
+        let max_mss = if inner.source.is_ipv4() {
+            self.max_mss_v4
+        } else {
+            self.max_mss_v6
+        };
+
+        let tcp = inner.transport_offset;
+        if packet.len() < tcp + TCP_HEADER_MIN || packet[tcp + 13] & TCP_FLAG_SYN == 0 {
+            return None;
+        }
+
+        let header_len = ((packet[tcp + 12] >> 4) as usize) * 4;
+        if header_len < TCP_HEADER_MIN || packet.len() < tcp + header_len {
+            return None;
+        }
+
+        let option = find_mss_option(&packet[tcp + TCP_HEADER_MIN..tcp + header_len])?;
+        let position = tcp + TCP_HEADER_MIN + option;
+
+        let mss = u16::from_be_bytes([packet[position], packet[position + 1]]);
+        if mss <= max_mss {
+            return None;
+        }
+
+        packet[position..position + 2].copy_from_slice(&max_mss.to_be_bytes());
+
+        let checksum = u16::from_be_bytes([packet[tcp + 16], packet[tcp + 17]]);
+        let checksum = update_checksum(checksum, mss, max_mss);
+        packet[tcp + 16..tcp + 18].copy_from_slice(&checksum.to_be_bytes());
+
+        trace!("Clamped TCP MSS {} -> {}", mss, max_mss);
+
+        Some((mss, max_mss))
+    }
+}
+
+/// Offset of the MSS value within the TCP options, if present
+fn find_mss_option(options: &[u8]) -> Option<usize> {
+    let mut i = 0;
+
+    while i < options.len() {
+        match options[i] {
+            TCP_OPTION_END => return None,
+            TCP_OPTION_NOP => i += 1,
+            kind => {
+                let len = *options.get(i + 1)? as usize;
+                if len < 2 || i + len > options.len() {
+                    return None;
+                }
+                if kind == TCP_OPTION_MSS && len == 4 {
+                    return Some(i + 2);
+                }
+                i += len;
+            }
+        }
+    }
+
+    None
+}
+
+/// Incrementally update a ones' complement checksum for a changed 16-bit word (RFC 1624)
+fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!checksum as u32) + (!old as u32) + new as u32;
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IPv4 TCP SYN with an MSS option and a valid checksum
+    fn syn_packet(mss: u16, flags: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 44];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&44u16.to_be_bytes());
+        packet[8] = 64;
+        packet[9] = 6;
+        packet[12..16].copy_from_slice(&[10, 8, 0, 2]);
+        packet[16..20].copy_from_slice(&[1, 1, 1, 1]);
+
+        packet[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&443u16.to_be_bytes());
+        packet[32] = 6 << 4;
+        packet[33] = flags;
+        packet[40] = TCP_OPTION_MSS;
+        packet[41] = 4;
+        packet[42..44].copy_from_slice(&mss.to_be_bytes());
+
+        let checksum = tcp_checksum(&packet);
+        packet[36..38].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    /// Full TCP checksum over the IPv4 pseudo header
+    fn tcp_checksum(packet: &[u8]) -> u16 {
+        let segment = &packet[20..];
+        let mut data = Vec::new();
+        data.extend_from_slice(&packet[12..20]);
+        data.extend_from_slice(&[0, 6]);
+        data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        for (i, byte) in segment.iter().enumerate() {
+            // Checksum field counts as zero
+            data.push(if i == 16 || i == 17 { 0 } else { *byte });
+        }
+
+        let mut sum = data
+            .chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+            .sum::<u32>();
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    #[test]
+    fn test_clamp_syn() {
+        let clamp = MssClamp::new(1400);
+        let mut packet = syn_packet(1460, TCP_FLAG_SYN);
+
+        assert_eq!(clamp.apply(&mut packet), Some((1460, 1360)));
+        assert_eq!(u16::from_be_bytes([packet[42], packet[43]]), 1360);
+        assert_eq!(
+            u16::from_be_bytes([packet[36], packet[37]]),
+            tcp_checksum(&packet)
+        );
+    }
+
+    #[test]
+    fn test_small_mss_untouched() {
+        let clamp = MssClamp::new(1400);
+        let mut packet = syn_packet(1200, TCP_FLAG_SYN);
+        let original = packet.clone();
+
+        assert_eq!(clamp.apply(&mut packet), None);
+        assert_eq!(packet, original);
+    }
+
+    #[test]
+    fn test_non_syn_untouched() {
+        let clamp = MssClamp::new(1400);
+        // ACK only
+        let mut packet = syn_packet(1460, 0x10);
+
+        assert_eq!(clamp.apply(&mut packet), None);
+    }
+
+    #[test]
+    fn test_find_mss_after_nops() {
+        let options = [TCP_OPTION_NOP, TCP_OPTION_NOP, 4, 2, TCP_OPTION_MSS, 4, 0x05, 0xB4];
+        assert_eq!(find_mss_option(&options), Some(6));
+        assert_eq!(find_mss_option(&[TCP_OPTION_END, TCP_OPTION_MSS, 4, 0, 0]), None);
+        assert_eq!(find_mss_option(&[TCP_OPTION_MSS, 40]), None);
+    }
+}
@@ -0,0 +1,362 @@
+use std::net::IpAddr;
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::config::{EgressConfig, NetworkConfig};
+use crate::error::{LostLoveError, Result};
+use crate::network::ip_net::IpNet;
+#[cfg(target_os = "linux")]
+use crate::network::netlink::Netlink;
+use crate::network::netlink::{Request, Route, Rule};
+
+/// One system command installing a NAT/forwarding/policy routing rule
+///
+/// Routes and policy rules are programmed over netlink; they keep the
+/// equivalent `ip` command line for logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleCommand {
+    program: &'static str,
+    /// Arguments with the action ("-A"/"add") left out, see `install_args`
+    args: Vec<String>,
+    /// Position of the action within the arguments
+    action_at: usize,
+    /// Insert at the head of the chain instead of appending
+    prepend: bool,
+    /// Netlink request doing the work instead of `program`
+    netlink: Option<Request>,
+}
+
+impl RuleCommand {
+    pub(crate) fn iptables(table: &str, chain: &str, rule: &[&str]) -> Self {
+        let mut args = vec!["-t".to_string(), table.to_string(), chain.to_string()];
+        args.extend(rule.iter().map(|s| s.to_string()));
+
+        Self {
+            program: "iptables",
+            args,
+            action_at: 2,
+            prepend: false,
+            netlink: None,
+        }
+    }
+
+    pub(crate) fn route(route: Route) -> Self {
+        Self::ip("route", route.args(), Request::Route(route))
+    }
+
+    pub(crate) fn rule(rule: Rule) -> Self {
+        Self::ip("rule", rule.args(), Request::Rule(rule))
+    }
+
+    fn ip(object: &str, rule: Vec<String>, request: Request) -> Self {
+        let mut args = vec![object.to_string()];
+        args.extend(rule);
+
+        Self {
+            program: "ip",
+            args,
+            action_at: 1,
+            prepend: false,
+            netlink: Some(request),
+        }
+    }
+
+    /// Install an iptables rule ahead of the chain's existing rules
+    pub(crate) fn prepended(mut self) -> Self {
+        self.prepend = true;
+        self
+    }
+
+    fn with_action(&self, action: &str) -> Vec<String> {
+        let mut args = self.args.clone();
+        args.insert(self.action_at, action.to_string());
+        args
+    }
+
+    /// Arguments that add the rule
+    pub fn install_args(&self) -> Vec<String> {
+        match self.program {
+            "iptables" if self.prepend => self.with_action("-I"),
+            "iptables" => self.with_action("-A"),
+            _ => self.with_action("add"),
+        }
+    }
+
+    /// Arguments that delete the rule again
+    pub fn remove_args(&self) -> Vec<String> {
+        match self.program {
+            "iptables" => self.with_action("-D"),
+            _ => self.with_action("del"),
+        }
+    }
+
+    /// Human readable command line
+    pub fn display(&self, args: &[String]) -> String {
+        format!("{} {}", self.program, args.join(" "))
+    }
+
+    fn install(&self) -> Result<()> {
+        self.execute(true)
+    }
+
+    fn remove(&self) -> Result<()> {
+        self.execute(false)
+    }
+
+    fn execute(&self, install: bool) -> Result<()> {
+        let args = if install { self.install_args() } else { self.remove_args() };
+        debug!("{}: {}", if install { "Installing" } else { "Removing" }, self.display(&args));
+
+        #[cfg(target_os = "linux")]
+        if let Some(request) = &self.netlink {
+            return Netlink::open()?.apply(request, install);
+        }
+        self.run(&args)
+    }
+
+    fn run(&self, args: &[String]) -> Result<()> {
+        let output = Command::new(self.program).args(args).output().map_err(|e| {
+            LostLoveError::Network(format!("Failed to run {}: {}", self.program, e))
+        })?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(LostLoveError::Network(format!(
+                "`{}` failed: {}",
+                self.display(args),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+/// NAT, forwarding and egress policy routing rules for the tunnel subnet
+///
+/// Tunneled traffic leaves through `egress.interface` (any interface if
+/// unset), translated to `egress.snat_address` (masqueraded if unset). With a
+/// `fwmark`, packets from the TUN interface are marked and routed through a
+/// dedicated table (numbered after the mark) with a default route via the
+/// egress interface, so the choice holds on multi-homed servers regardless
+/// of the main routing table.
+#[derive(Debug, Clone)]
+pub struct NatRules {
+    commands: Vec<RuleCommand>,
+}
+
+impl NatRules {
+    /// Build rules from network config
+    pub fn new(config: &NetworkConfig) -> Result<Self> {
+        // iptables rules here are IPv4 only
+        let subnet = config
+            .tun_address
+            .parse::<IpNet>()?
+            .ipv4("tun_address")?
+            .trunc()
+            .to_string();
+
+        Ok(Self {
+            commands: Self::build(
+                &subnet,
+                &config.tun_name,
+                &config.egress,
+                config.isolation.enabled,
+            ),
+        })
+    }
+
+    fn build(subnet: &str, tun: &str, egress: &EgressConfig, isolate: bool) -> Vec<RuleCommand> {
+        let mut commands = Vec::new();
+
+        let out_interface: Vec<&str> = match &egress.interface {
+            Some(interface) => vec!["-o", interface],
+            None => vec![],
+        };
+        let in_interface: Vec<&str> = match &egress.interface {
+            Some(interface) => vec!["-i", interface],
+            None => vec![],
+        };
+
+        // Source NAT
+        let snat_address = egress.snat_address.map(|address| address.to_string());
+        let mut rule = vec!["-s", subnet];
+        rule.extend(&out_interface);
+        match &snat_address {
+            Some(address) => rule.extend(["-j", "SNAT", "--to-source", address.as_str()]),
+            None => rule.extend(["-j", "MASQUERADE"]),
+        }
+        commands.push(RuleCommand::iptables("nat", "POSTROUTING", &rule));
+
+        // Forwarding between the tunnel and the egress interface
+        let mut rule = vec!["-i", tun];
+        rule.extend(&out_interface);
+        rule.extend(["-j", "ACCEPT"]);
+        commands.push(RuleCommand::iptables("filter", "FORWARD", &rule));
+
+        let mut rule = in_interface.clone();
+        rule.extend(["-o", tun, "-m", "state", "--state", "RELATED,ESTABLISHED", "-j", "ACCEPT"]);
+        commands.push(RuleCommand::iptables("filter", "FORWARD", &rule));
+
+        // Clients never reach each other through the server
+        if isolate {
+            commands.push(RuleCommand::iptables(
+                "filter",
+                "FORWARD",
+                &["-i", tun, "-o", tun, "-j", "DROP"],
+            ));
+        }
+
+        // Policy routing
+        if let (Some(mark), Some(interface)) = (egress.fwmark, &egress.interface) {
+            commands.push(RuleCommand::iptables(
+                "mangle",
+                "PREROUTING",
+                &["-i", tun, "-j", "MARK", "--set-mark", &mark.to_string()],
+            ));
+
+            commands.push(RuleCommand::route(Route {
+                destination: IpNet::DEFAULT_V4,
+                gateway: egress.gateway.map(IpAddr::V4),
+                device: Some(interface.clone()),
+                table: Some(mark),
+            }));
+            commands.push(RuleCommand::rule(Rule {
+                source: None,
+                fwmark: Some(mark),
+                input_interface: None,
+                table: mark,
+            }));
+        }
+
+        commands
+    }
+
+    /// Get the rule commands in install order
+    pub fn commands(&self) -> &[RuleCommand] {
+        &self.commands
+    }
+
+    /// Install all rules, see `RuleGuard::install`
+    pub fn install(self) -> Result<RuleGuard> {
+        let guard = RuleGuard::install(self.commands)?;
+        info!("Installed {} NAT/egress rules", guard.commands.len());
+        Ok(guard)
+    }
+}
+
+/// Installed rules, removed again on drop
+pub struct RuleGuard {
+    commands: Vec<RuleCommand>,
+}
+
+impl RuleGuard {
+    /// Install rules in order; on failure the already installed ones are removed
+    pub fn install(commands: Vec<RuleCommand>) -> Result<Self> {
+        for (i, command) in commands.iter().enumerate() {
+            if let Err(e) = command.install() {
+                Self::remove(&commands[..i]);
+                return Err(e);
+            }
+        }
+
+        Ok(Self { commands })
+    }
+
+    fn remove(commands: &[RuleCommand]) {
+        for command in commands.iter().rev() {
+            if let Err(e) = command.remove() {
+                warn!("Failed to remove rule: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for RuleGuard {
+    fn drop(&mut self) {
+        Self::remove(&self.commands);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn command_lines(network: &NetworkConfig) -> Vec<String> {
+        NatRules::new(network)
+            .unwrap()
+            .commands()
+            .iter()
+            .map(|c| c.display(&c.install_args()))
+            .collect()
+    }
+
+    #[test]
+    fn test_default_masquerade() {
+        let network = Config::default_for_testing().network;
+        let lines = command_lines(&network);
+
+        assert_eq!(
+            lines,
+            vec![
+                "iptables -t nat -A POSTROUTING -s 10.8.0.0/24 -j MASQUERADE",
+                "iptables -t filter -A FORWARD -i hfp0 -j ACCEPT",
+                "iptables -t filter -A FORWARD -o hfp0 -m state --state RELATED,ESTABLISHED -j ACCEPT",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_egress_snat_and_policy_routing() {
+        let mut network = Config::default_for_testing().network;
+        network.egress = EgressConfig {
+            interface: Some("eth1".to_string()),
+            snat_address: Some("203.0.113.7".parse().unwrap()),
+            fwmark: Some(51),
+            gateway: Some("203.0.113.1".parse().unwrap()),
+        };
+        let lines = command_lines(&network);
+
+        assert_eq!(
+            lines,
+            vec![
+                "iptables -t nat -A POSTROUTING -s 10.8.0.0/24 -o eth1 -j SNAT --to-source 203.0.113.7",
+                "iptables -t filter -A FORWARD -i hfp0 -o eth1 -j ACCEPT",
+                "iptables -t filter -A FORWARD -i eth1 -o hfp0 -m state --state RELATED,ESTABLISHED -j ACCEPT",
+                "iptables -t mangle -A PREROUTING -i hfp0 -j MARK --set-mark 51",
+                "ip route add default via 203.0.113.1 dev eth1 table 51",
+                "ip rule add fwmark 51 table 51",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_isolation_drops_client_to_client() {
+        let mut network = Config::default_for_testing().network;
+        network.egress.interface = Some("eth1".to_string());
+        network.isolation.enabled = true;
+        let lines = command_lines(&network);
+
+        assert!(lines.contains(&"iptables -t filter -A FORWARD -i hfp0 -o hfp0 -j DROP".to_string()));
+    }
+
+    #[test]
+    fn test_remove_args() {
+        let network = Config::default_for_testing().network;
+        let rules = NatRules::new(&network).unwrap();
+        let command = &rules.commands()[0];
+
+        assert_eq!(
+            command.display(&command.remove_args()),
+            "iptables -t nat -D POSTROUTING -s 10.8.0.0/24 -j MASQUERADE"
+        );
+
+        let route = RuleCommand::rule(Rule {
+            source: None,
+            fwmark: Some(1),
+            input_interface: None,
+            table: 1,
+        });
+        assert_eq!(route.remove_args()[..2], ["rule".to_string(), "del".to_string()]);
+    }
+}
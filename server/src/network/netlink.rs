@@ -0,0 +1,497 @@
+use std::ffi::CString;
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tracing::debug;
+
+use crate::error::{LostLoveError, Result};
+use crate::network::ip_net::IpNet;
+
+// Message types, flags and attributes of rtnetlink (linux/rtnetlink.h and friends)
+const NLMSG_HDR_SIZE: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x001;
+const NLM_F_ACK: u16 = 0x004;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+
+const IFLA_MTU: u16 = 4;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_TABLE: u16 = 15;
+const FRA_SRC: u16 = 2;
+const FRA_IIFNAME: u16 = 3;
+const FRA_FWMARK: u16 = 10;
+const FRA_TABLE: u16 = 15;
+
+const RT_TABLE_UNSPEC: u8 = 0;
+const RT_TABLE_MAIN: u32 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+const FR_ACT_TO_TBL: u8 = 1;
+
+/// Route to program, `ip route <destination> [via <gateway>] [dev <device>] [table <table>]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub destination: IpNet,
+    pub gateway: Option<IpAddr>,
+    pub device: Option<String>,
+    /// Main table if unset
+    pub table: Option<u32>,
+}
+
+impl Route {
+    /// Route of `destination` through an interface
+    pub fn via_device(destination: IpNet, device: &str) -> Self {
+        Self {
+            destination,
+            gateway: None,
+            device: Some(device.to_string()),
+            table: None,
+        }
+    }
+
+    /// Arguments of the equivalent `ip route` command, for logs
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![if self.destination.prefix_len() == 0 {
+            "default".to_string()
+        } else {
+            self.destination.to_string()
+        }];
+        if let Some(gateway) = self.gateway {
+            args.extend(["via".to_string(), gateway.to_string()]);
+        }
+        if let Some(device) = &self.device {
+            args.extend(["dev".to_string(), device.clone()]);
+        }
+        if let Some(table) = self.table {
+            args.extend(["table".to_string(), table.to_string()]);
+        }
+        args
+    }
+}
+
+/// Policy routing rule, `ip rule [from <source>] [fwmark <mark>] [iif <interface>] table <table>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub source: Option<IpNet>,
+    pub fwmark: Option<u32>,
+    pub input_interface: Option<String>,
+    pub table: u32,
+}
+
+impl Rule {
+    /// Arguments of the equivalent `ip rule` command, for logs
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(source) = self.source {
+            args.extend(["from".to_string(), source.to_string()]);
+        }
+        if let Some(fwmark) = self.fwmark {
+            args.extend(["fwmark".to_string(), fwmark.to_string()]);
+        }
+        if let Some(interface) = &self.input_interface {
+            args.extend(["iif".to_string(), interface.clone()]);
+        }
+        args.extend(["table".to_string(), self.table.to_string()]);
+        args
+    }
+}
+
+/// Something a `RuleGuard` can install over netlink and remove again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Route(Route),
+    Rule(Rule),
+}
+
+/// rtnetlink socket programming links, addresses, routes and rules
+///
+/// Each call sends one request and waits for the kernel's acknowledgement,
+/// so failures come back as `LostLoveError::Netlink` with the errno (e.g.
+/// `EEXIST` for a route that is already there) instead of command output.
+#[cfg(target_os = "linux")]
+pub struct Netlink {
+    fd: OwnedFd,
+    sequence: AtomicU32,
+}
+
+#[cfg(target_os = "linux")]
+impl Netlink {
+    pub fn open() -> Result<Self> {
+        // SAFETY: plain socket call, the result is checked
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(failure("open", io::Error::last_os_error()));
+        }
+        Ok(Self {
+            // SAFETY: fd was just opened and is owned by nothing else
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            sequence: AtomicU32::new(1),
+        })
+    }
+
+    /// Set the MTU of an interface and bring it up
+    pub fn set_link_up(&self, device: &str, mtu: Option<u32>) -> Result<()> {
+        let index = link_index(device)?;
+        let mut message = Message::new(RTM_NEWLINK, NLM_F_ACK);
+        // ifinfomsg: family, type, index, flags, change
+        message.put(&[libc::AF_UNSPEC as u8, 0, 0, 0]);
+        message.put(&(index as i32).to_ne_bytes());
+        message.put(&(libc::IFF_UP as u32).to_ne_bytes());
+        message.put(&(libc::IFF_UP as u32).to_ne_bytes());
+        if let Some(mtu) = mtu {
+            message.attribute(IFLA_MTU, &mtu.to_ne_bytes());
+        }
+        self.execute(&format!("link set dev {} up", device), message)
+    }
+
+    pub fn add_address(&self, device: &str, address: IpNet) -> Result<()> {
+        self.address(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, device, address)
+    }
+
+    pub fn delete_address(&self, device: &str, address: IpNet) -> Result<()> {
+        self.address(RTM_DELADDR, 0, device, address)
+    }
+
+    pub fn add_route(&self, route: &Route) -> Result<()> {
+        self.route(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, route)
+    }
+
+    pub fn delete_route(&self, route: &Route) -> Result<()> {
+        self.route(RTM_DELROUTE, 0, route)
+    }
+
+    pub fn add_rule(&self, rule: &Rule) -> Result<()> {
+        self.rule(RTM_NEWRULE, NLM_F_CREATE | NLM_F_EXCL, rule)
+    }
+
+    pub fn delete_rule(&self, rule: &Rule) -> Result<()> {
+        self.rule(RTM_DELRULE, 0, rule)
+    }
+
+    /// Install or remove what `request` describes
+    pub fn apply(&self, request: &Request, install: bool) -> Result<()> {
+        match (request, install) {
+            (Request::Route(route), true) => self.add_route(route),
+            (Request::Route(route), false) => self.delete_route(route),
+            (Request::Rule(rule), true) => self.add_rule(rule),
+            (Request::Rule(rule), false) => self.delete_rule(rule),
+        }
+    }
+
+    fn address(&self, kind: u16, flags: u16, device: &str, address: IpNet) -> Result<()> {
+        let index = link_index(device)?;
+        let mut message = Message::new(kind, flags | NLM_F_ACK);
+        // ifaddrmsg: family, prefix length, flags, scope, index
+        message.put(&[family(address.address()), address.prefix_len(), 0, RT_SCOPE_UNIVERSE]);
+        message.put(&index.to_ne_bytes());
+        message.attribute(IFA_LOCAL, &octets(address.address()));
+        message.attribute(IFA_ADDRESS, &octets(address.address()));
+        let action = if kind == RTM_NEWADDR { "add" } else { "del" };
+        self.execute(&format!("address {} {} dev {}", action, address, device), message)
+    }
+
+    fn route(&self, kind: u16, flags: u16, route: &Route) -> Result<()> {
+        let message = route_message(kind, flags, route, route.device.as_deref().map(link_index).transpose()?);
+        let action = if kind == RTM_NEWROUTE { "add" } else { "del" };
+        self.execute(&format!("route {} {}", action, route.args().join(" ")), message)
+    }
+
+    fn rule(&self, kind: u16, flags: u16, rule: &Rule) -> Result<()> {
+        let action = if kind == RTM_NEWRULE { "add" } else { "del" };
+        self.execute(&format!("rule {} {}", action, rule.args().join(" ")), rule_message(kind, flags, rule))
+    }
+
+    /// Send `message` and wait for its acknowledgement
+    fn execute(&self, request: &str, mut message: Message) -> Result<()> {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let bytes = message.finish(sequence);
+        debug!("Netlink: {}", request);
+
+        // SAFETY: bytes is valid for reads of its length
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), bytes.as_ptr() as *const libc::c_void, bytes.len(), 0) };
+        if sent < 0 {
+            return Err(failure(request, io::Error::last_os_error()));
+        }
+
+        let mut buf = vec![0u8; 8192];
+        loop {
+            // SAFETY: buf is valid for writes of its length
+            let n = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if n < 0 {
+                return Err(failure(request, io::Error::last_os_error()));
+            }
+            if let Some(errno) = acknowledgement(&buf[..n as usize], sequence) {
+                return match errno {
+                    0 => Ok(()),
+                    errno => Err(failure(request, io::Error::from_raw_os_error(errno))),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn failure(request: &str, error: io::Error) -> LostLoveError {
+    LostLoveError::Netlink {
+        request: request.to_string(),
+        errno: error.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn link_index(device: &str) -> Result<u32> {
+    let name = CString::new(device).map_err(|_| failure(device, io::Error::from_raw_os_error(libc::EINVAL)))?;
+    // SAFETY: name is NUL-terminated
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(failure(&format!("link {}", device), io::Error::last_os_error())),
+        index => Ok(index),
+    }
+}
+
+fn family(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn octets(address: IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec(),
+    }
+}
+
+/// Table id for the one-byte header field; larger ids go in an attribute
+fn header_table(table: u32) -> u8 {
+    u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC)
+}
+
+fn route_message(kind: u16, flags: u16, route: &Route, device: Option<u32>) -> Message {
+    let table = route.table.unwrap_or(RT_TABLE_MAIN);
+    let destination = route.destination.trunc();
+    // Routes straight out of an interface reach only its link
+    let scope = match (route.gateway, device) {
+        (None, Some(_)) if kind == RTM_NEWROUTE => RT_SCOPE_LINK,
+        _ => RT_SCOPE_UNIVERSE,
+    };
+
+    let mut message = Message::new(kind, flags | NLM_F_ACK);
+    // rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type, flags
+    message.put(&[family(destination.address()), destination.prefix_len(), 0, 0]);
+    message.put(&[header_table(table), RTPROT_BOOT, scope, RTN_UNICAST]);
+    message.put(&0u32.to_ne_bytes());
+    if destination.prefix_len() > 0 {
+        message.attribute(RTA_DST, &octets(destination.address()));
+    }
+    if let Some(gateway) = route.gateway {
+        message.attribute(RTA_GATEWAY, &octets(gateway));
+    }
+    if let Some(index) = device {
+        message.attribute(RTA_OIF, &index.to_ne_bytes());
+    }
+    message.attribute(RTA_TABLE, &table.to_ne_bytes());
+    message
+}
+
+fn rule_message(kind: u16, flags: u16, rule: &Rule) -> Message {
+    let family = rule
+        .source
+        .map(|source| family(source.address()))
+        .unwrap_or(libc::AF_INET as u8);
+    let source_len = rule.source.map(|source| source.prefix_len()).unwrap_or(0);
+
+    let mut message = Message::new(kind, flags | NLM_F_ACK);
+    // fib_rule_hdr: family, dst_len, src_len, tos, table, reserved x2, action, flags
+    message.put(&[family, 0, source_len, 0]);
+    message.put(&[header_table(rule.table), 0, 0, FR_ACT_TO_TBL]);
+    message.put(&0u32.to_ne_bytes());
+    if let Some(source) = rule.source {
+        message.attribute(FRA_SRC, &octets(source.trunc().address()));
+    }
+    if let Some(interface) = &rule.input_interface {
+        let mut name = interface.as_bytes().to_vec();
+        name.push(0);
+        message.attribute(FRA_IIFNAME, &name);
+    }
+    if let Some(fwmark) = rule.fwmark {
+        message.attribute(FRA_FWMARK, &fwmark.to_ne_bytes());
+    }
+    message.attribute(FRA_TABLE, &rule.table.to_ne_bytes());
+    message
+}
+
+/// Error code of the acknowledgement for `sequence` among `data`'s messages, if there
+fn acknowledgement(data: &[u8], sequence: u32) -> Option<i32> {
+    let mut rest = data;
+    while rest.len() >= NLMSG_HDR_SIZE {
+        let length = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes([rest[4], rest[5]]);
+        let seq = u32::from_ne_bytes(rest[8..12].try_into().unwrap());
+        if length < NLMSG_HDR_SIZE || length > rest.len() {
+            return None;
+        }
+        if kind == NLMSG_ERROR && seq == sequence && length >= NLMSG_HDR_SIZE + 4 {
+            let error = i32::from_ne_bytes(rest[16..20].try_into().unwrap());
+            return Some(-error);
+        }
+        rest = &rest[align(length).min(rest.len())..];
+    }
+    None
+}
+
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// Netlink request under construction
+struct Message {
+    kind: u16,
+    flags: u16,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn new(kind: u16, flags: u16) -> Self {
+        Self {
+            kind,
+            flags: flags | NLM_F_REQUEST,
+            body: Vec::with_capacity(64),
+        }
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        self.body.extend_from_slice(bytes);
+    }
+
+    /// Append an rtattr, padded to four bytes
+    fn attribute(&mut self, kind: u16, payload: &[u8]) {
+        let length = 4 + payload.len();
+        self.body.extend_from_slice(&(length as u16).to_ne_bytes());
+        self.body.extend_from_slice(&kind.to_ne_bytes());
+        self.body.extend_from_slice(payload);
+        self.body.resize(align(self.body.len()), 0);
+    }
+
+    /// Bytes with the header in front
+    fn finish(&mut self, sequence: u32) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(NLMSG_HDR_SIZE + self.body.len());
+        bytes.extend_from_slice(&((NLMSG_HDR_SIZE + self.body.len()) as u32).to_ne_bytes());
+        bytes.extend_from_slice(&self.kind.to_ne_bytes());
+        bytes.extend_from_slice(&self.flags.to_ne_bytes());
+        bytes.extend_from_slice(&sequence.to_ne_bytes());
+        bytes.extend_from_slice(&0u32.to_ne_bytes());
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Attributes of a finished message after a body header of `header` bytes
+    fn attributes(bytes: &[u8], header: usize) -> Vec<(u16, Vec<u8>)> {
+        let mut rest = &bytes[NLMSG_HDR_SIZE + header..];
+        let mut attributes = Vec::new();
+        while !rest.is_empty() {
+            let length = u16::from_ne_bytes([rest[0], rest[1]]) as usize;
+            attributes.push((u16::from_ne_bytes([rest[2], rest[3]]), rest[4..length].to_vec()));
+            rest = &rest[align(length)..];
+        }
+        attributes
+    }
+
+    #[test]
+    fn test_route_message() {
+        let route = Route {
+            destination: "0.0.0.0/0".parse().unwrap(),
+            gateway: Some("203.0.113.1".parse().unwrap()),
+            device: Some("eth1".to_string()),
+            table: Some(1002),
+        };
+        assert_eq!(route.args().join(" "), "default via 203.0.113.1 dev eth1 table 1002");
+
+        let bytes = route_message(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, &route, Some(3)).finish(7);
+        assert_eq!(u32::from_ne_bytes(bytes[0..4].try_into().unwrap()) as usize, bytes.len());
+        assert_eq!(u16::from_ne_bytes([bytes[4], bytes[5]]), RTM_NEWROUTE);
+        assert_eq!(u16::from_ne_bytes([bytes[6], bytes[7]]), 0x605);
+        // Family, no destination prefix, table id too large for the header
+        assert_eq!(bytes[16..24], [libc::AF_INET as u8, 0, 0, 0, RT_TABLE_UNSPEC, RTPROT_BOOT, 0, 1]);
+        assert_eq!(
+            attributes(&bytes, 12),
+            [
+                (RTA_GATEWAY, vec![203, 0, 113, 1]),
+                (RTA_OIF, 3u32.to_ne_bytes().to_vec()),
+                (RTA_TABLE, 1002u32.to_ne_bytes().to_vec()),
+            ]
+        );
+
+        // Device routes get link scope and their destination, host bits cleared
+        let route = Route::via_device("10.20.1.9/16".parse().unwrap(), "tun0");
+        let bytes = route_message(RTM_NEWROUTE, 0, &route, Some(5)).finish(8);
+        assert_eq!(bytes[16..24], [libc::AF_INET as u8, 16, 0, 0, 254, RTPROT_BOOT, RT_SCOPE_LINK, 1]);
+        assert_eq!(attributes(&bytes, 12)[0], (RTA_DST, vec![10, 20, 0, 0]));
+    }
+
+    #[test]
+    fn test_rule_message() {
+        let rule = Rule {
+            source: Some("10.8.0.2/32".parse().unwrap()),
+            fwmark: None,
+            input_interface: Some("hfp0".to_string()),
+            table: 51,
+        };
+        assert_eq!(rule.args().join(" "), "from 10.8.0.2/32 iif hfp0 table 51");
+
+        let bytes = rule_message(RTM_NEWRULE, 0, &rule).finish(1);
+        assert_eq!(bytes[16..24], [libc::AF_INET as u8, 0, 32, 0, 51, 0, 0, FR_ACT_TO_TBL]);
+        assert_eq!(
+            attributes(&bytes, 12),
+            [
+                (FRA_SRC, vec![10, 8, 0, 2]),
+                (FRA_IIFNAME, b"hfp0\0".to_vec()),
+                (FRA_TABLE, 51u32.to_ne_bytes().to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_acknowledgement() {
+        let mut ack = Message::new(NLMSG_ERROR, 0);
+        ack.put(&(-libc::EEXIST).to_ne_bytes());
+        let other = Message::new(RTM_NEWROUTE, 0).finish(4);
+        let data = [other, ack.finish(5)].concat();
+
+        assert_eq!(acknowledgement(&data, 5), Some(libc::EEXIST));
+        assert_eq!(acknowledgement(&data, 6), None);
+        assert_eq!(acknowledgement(&data[..10], 5), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_unknown_device() {
+        let netlink = Netlink::open().unwrap();
+        let route = Route::via_device("10.20.0.0/16".parse().unwrap(), "llp-missing0");
+        assert!(matches!(
+            netlink.add_route(&route),
+            Err(LostLoveError::Netlink { errno: libc::ENODEV, .. })
+        ));
+    }
+}
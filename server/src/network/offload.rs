@@ -0,0 +1,340 @@
+use crate::error::{LostLoveError, Result};
+use crate::network::inner_packet::{InnerPacket, IpProtocol};
+
+/// Size of the virtio-net header in front of each packet on a TUN with IFF_VNET_HDR
+pub const VNET_HDR_SIZE: usize = 10;
+
+/// Largest coalesced packet, bounded by the IP length fields
+pub const GSO_MAX_SIZE: usize = 65535;
+
+/// Checksum is partial and must be completed from `csum_start`
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+/// Offset of the checksum in the TCP header
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_CWR: u8 = 0x80;
+
+/// Header the kernel puts before each packet on a TUN with offloads (virtio_net_hdr)
+///
+/// Fields are in host byte order, as the kernel uses them without TUNSETVNETLE.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    /// Length of the IP and TCP headers repeated in every segment
+    pub hdr_len: u16,
+    /// Payload bytes per segment
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < VNET_HDR_SIZE {
+            return Err(LostLoveError::InsufficientData {
+                expected: VNET_HDR_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let field = |at: usize| u16::from_ne_bytes([data[at], data[at + 1]]);
+        Ok(Self {
+            flags: data[0],
+            gso_type: data[1],
+            hdr_len: field(2),
+            gso_size: field(4),
+            csum_start: field(6),
+            csum_offset: field(8),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; VNET_HDR_SIZE] {
+        let mut bytes = [0u8; VNET_HDR_SIZE];
+        bytes[0] = self.flags;
+        bytes[1] = self.gso_type;
+        bytes[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        bytes[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        bytes[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        bytes[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        bytes
+    }
+
+    /// Check if the packet is a coalesced one still to be segmented
+    pub fn is_gso(&self) -> bool {
+        self.gso_type & !VIRTIO_NET_HDR_GSO_ECN != VIRTIO_NET_HDR_GSO_NONE
+    }
+
+    /// Header to write `packet` to the TUN with
+    ///
+    /// Packets within `mtu` go as they are. A larger TCP packet is handed to
+    /// the kernel to be segmented to `mtu`, its checksum field is replaced by
+    /// the partial pseudo-header sum the kernel completes per segment.
+    pub fn for_write(packet: &mut [u8], mtu: usize) -> Result<Self> {
+        if packet.len() <= mtu {
+            return Ok(Self::default());
+        }
+
+        let inner = InnerPacket::parse(packet)?;
+        if inner.protocol != IpProtocol::Tcp || packet.len() > GSO_MAX_SIZE {
+            return Err(LostLoveError::PacketTooBig { size: packet.len(), mtu });
+        }
+        let (ip_len, header_len) = tcp_headers(packet, &inner)?;
+        let gso_size = mtu
+            .checked_sub(header_len)
+            .filter(|size| *size > 0)
+            .ok_or(LostLoveError::PacketTooBig { size: packet.len(), mtu })?;
+
+        let length = packet.len();
+        let partial = fold(pseudo_header_sum(packet, &inner, length - ip_len));
+        let checksum = ip_len + TCP_CHECKSUM_OFFSET;
+        packet[checksum..checksum + 2].copy_from_slice(&partial.to_be_bytes());
+
+        Ok(Self {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: if inner.source.is_ipv4() {
+                VIRTIO_NET_HDR_GSO_TCPV4
+            } else {
+                VIRTIO_NET_HDR_GSO_TCPV6
+            },
+            hdr_len: header_len as u16,
+            gso_size: gso_size as u16,
+            csum_start: ip_len as u16,
+            csum_offset: TCP_CHECKSUM_OFFSET as u16,
+        })
+    }
+
+    /// Complete a checksum the kernel left partial, in place
+    pub fn complete_checksum(&self, packet: &mut [u8]) -> Result<()> {
+        if self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+            return Ok(());
+        }
+
+        let start = self.csum_start as usize;
+        let field = start + self.csum_offset as usize;
+        if field + 2 > packet.len() {
+            return Err(LostLoveError::MalformedInnerPacket(format!(
+                "Checksum offset {} beyond packet of {} bytes",
+                field,
+                packet.len()
+            )));
+        }
+
+        // The field holds the pseudo-header sum, so summing over it finishes the job
+        let checksum = !fold(sum(&packet[start..], 0));
+        packet[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// Split a TCP packet into segments of at most `mss` payload bytes
+///
+/// Every segment gets its own IP length, IPv4 ID, sequence number and
+/// checksums; FIN and PSH stay on the last segment, CWR on the first.
+pub fn segment(packet: &[u8], mss: usize) -> Result<Vec<Vec<u8>>> {
+    let inner = InnerPacket::parse(packet)?;
+    if inner.protocol != IpProtocol::Tcp {
+        return Err(LostLoveError::MalformedInnerPacket(format!(
+            "Only TCP can be segmented, got {}",
+            inner.protocol
+        )));
+    }
+    let (ip_len, header_len) = tcp_headers(packet, &inner)?;
+    if mss == 0 {
+        return Err(LostLoveError::MalformedInnerPacket("Segment size 0".to_string()));
+    }
+
+    let payload = &packet[header_len..];
+    if payload.len() <= mss {
+        return Ok(vec![packet.to_vec()]);
+    }
+
+    let sequence = u32::from_be_bytes(packet[ip_len + 4..ip_len + 8].try_into().unwrap());
+    let ipv4_id = u16::from_be_bytes([packet[4], packet[5]]);
+    let count = payload.len().div_ceil(mss);
+
+    let segments = payload
+        .chunks(mss)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut segment = Vec::with_capacity(header_len + chunk.len());
+            segment.extend_from_slice(&packet[..header_len]);
+            segment.extend_from_slice(chunk);
+
+            let length = segment.len();
+            if inner.source.is_ipv4() {
+                segment[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+                segment[4..6].copy_from_slice(&ipv4_id.wrapping_add(index as u16).to_be_bytes());
+                segment[10..12].fill(0);
+                let checksum = !fold(sum(&segment[..ip_len], 0));
+                segment[10..12].copy_from_slice(&checksum.to_be_bytes());
+            } else {
+                segment[4..6].copy_from_slice(&((length - ip_len) as u16).to_be_bytes());
+            }
+
+            let tcp = ip_len;
+            let offset = (index * mss) as u32;
+            segment[tcp + 4..tcp + 8].copy_from_slice(&sequence.wrapping_add(offset).to_be_bytes());
+            if index + 1 < count {
+                segment[tcp + 13] &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+            }
+            if index > 0 {
+                segment[tcp + 13] &= !TCP_FLAG_CWR;
+            }
+
+            let checksum = tcp + TCP_CHECKSUM_OFFSET;
+            segment[checksum..checksum + 2].fill(0);
+            let pseudo = pseudo_header_sum(&segment, &inner, length - ip_len);
+            let value = !fold(sum(&segment[tcp..], pseudo));
+            segment[checksum..checksum + 2].copy_from_slice(&value.to_be_bytes());
+            segment
+        })
+        .collect();
+    Ok(segments)
+}
+
+/// IP header length and IP plus TCP header length
+fn tcp_headers(packet: &[u8], inner: &InnerPacket) -> Result<(usize, usize)> {
+    let ip_len = inner.transport_offset;
+    let tcp_len = packet
+        .get(ip_len + 12)
+        .map(|offset| ((offset >> 4) as usize) * 4)
+        .filter(|len| *len >= 20 && ip_len + len <= packet.len())
+        .ok_or_else(|| LostLoveError::MalformedInnerPacket("Truncated TCP header".to_string()))?;
+    Ok((ip_len, ip_len + tcp_len))
+}
+
+/// Unfolded sum of the TCP pseudo header for `length` bytes of transport data
+fn pseudo_header_sum(packet: &[u8], inner: &InnerPacket, length: usize) -> u32 {
+    let protocol = IpProtocol::Tcp.as_u8() as u32;
+    if inner.source.is_ipv4() {
+        sum(&packet[12..20], protocol + length as u32)
+    } else {
+        sum(&packet[8..40], protocol + length as u32)
+    }
+}
+
+fn sum(data: &[u8], initial: u32) -> u32 {
+    data.chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .fold(initial, |sum, word| {
+            let sum = sum + word;
+            (sum & 0xFFFF) + (sum >> 16)
+        })
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// IPv4 TCP packet with `payload` bytes and correct checksums
+    pub(crate) fn tcp_packet(payload: usize, flags: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 40 + payload];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(40 + payload as u16).to_be_bytes());
+        packet[4..6].copy_from_slice(&100u16.to_be_bytes());
+        packet[8] = 64;
+        packet[9] = 6;
+        packet[12..16].copy_from_slice(&[1, 1, 1, 1]);
+        packet[16..20].copy_from_slice(&[10, 8, 0, 2]);
+        packet[20..22].copy_from_slice(&443u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&40000u16.to_be_bytes());
+        packet[24..28].copy_from_slice(&1000u32.to_be_bytes());
+        packet[32] = 5 << 4;
+        packet[33] = flags;
+        for (i, byte) in packet[40..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let ip = !fold(sum(&packet[..20], 0));
+        packet[10..12].copy_from_slice(&ip.to_be_bytes());
+        let inner = InnerPacket::parse(&packet).unwrap();
+        let tcp = !fold(sum(&packet[20..], pseudo_header_sum(&packet, &inner, payload + 20)));
+        packet[36..38].copy_from_slice(&tcp.to_be_bytes());
+        packet
+    }
+
+    fn checksums_valid(packet: &[u8]) -> bool {
+        let inner = InnerPacket::parse(packet).unwrap();
+        let pseudo = pseudo_header_sum(packet, &inner, packet.len() - 20);
+        fold(sum(&packet[..20], 0)) == 0xFFFF && fold(sum(&packet[20..], pseudo)) == 0xFFFF
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_ECN,
+            hdr_len: 40,
+            gso_size: 1360,
+            csum_start: 20,
+            csum_offset: 16,
+        };
+        assert_eq!(VirtioNetHdr::parse(&header.to_bytes()).unwrap(), header);
+        assert!(header.is_gso());
+        assert!(!VirtioNetHdr::default().is_gso());
+        assert!(VirtioNetHdr::parse(&[0u8; 9]).is_err());
+    }
+
+    #[test]
+    fn test_segment() {
+        let packet = tcp_packet(3000, TCP_FLAG_FIN | TCP_FLAG_PSH | TCP_FLAG_CWR | 0x10);
+        let segments = segment(&packet, 1360).unwrap();
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!(
+            segments.iter().map(|segment| segment.len()).collect::<Vec<_>>(),
+            [1400, 1400, 320]
+        );
+        for (index, segment) in segments.iter().enumerate() {
+            assert!(checksums_valid(segment), "segment {}", index);
+            assert_eq!(u16::from_be_bytes([segment[4], segment[5]]), 100 + index as u16);
+            let sequence = u32::from_be_bytes(segment[24..28].try_into().unwrap());
+            assert_eq!(sequence, 1000 + 1360 * index as u32);
+            assert_eq!(segment[40], (1360 * index) as u8);
+        }
+        assert_eq!(segments[0][33], TCP_FLAG_CWR | 0x10);
+        assert_eq!(segments[1][33], 0x10);
+        assert_eq!(segments[2][33], TCP_FLAG_FIN | TCP_FLAG_PSH | 0x10);
+
+        // Small packets and non-TCP pass or fail whole
+        assert_eq!(segment(&tcp_packet(100, 0x10), 1360).unwrap(), [tcp_packet(100, 0x10)]);
+        let mut udp = tcp_packet(100, 0);
+        udp[9] = 17;
+        assert!(segment(&udp, 1360).is_err());
+    }
+
+    #[test]
+    fn test_write_header_and_checksum() {
+        let mut small = tcp_packet(100, 0x10);
+        assert_eq!(VirtioNetHdr::for_write(&mut small, 1400).unwrap(), VirtioNetHdr::default());
+
+        let mut large = tcp_packet(3000, 0x10);
+        let header = VirtioNetHdr::for_write(&mut large, 1400).unwrap();
+        assert_eq!(header.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!((header.hdr_len, header.gso_size), (40, 1360));
+        assert!(!checksums_valid(&large));
+
+        // What the kernel does with NEEDS_CSUM gives the original checksum back
+        header.complete_checksum(&mut large).unwrap();
+        assert_eq!(large, tcp_packet(3000, 0x10));
+
+        let mut udp = tcp_packet(3000, 0);
+        udp[9] = 17;
+        assert!(VirtioNetHdr::for_write(&mut udp, 1400).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use dashmap::DashMap;
+use std::net::SocketAddr;
+
+/// MTU plateaus of RFC 1191, for when the kernel can't tell the real MTU
+const PLATEAUS: &[usize] = &[1492, 1280, 1006, 576];
+
+/// Smallest MTU of an IPv4 path assumed
+const IPV4_MIN_MTU: usize = 576;
+
+/// Smallest MTU an IPv6 path may have
+const IPV6_MIN_MTU: usize = 1280;
+
+/// IP and UDP headers in front of each datagram
+pub fn header_overhead(peer: &SocketAddr) -> usize {
+    if peer.is_ipv6() {
+        40 + 8
+    } else {
+        20 + 8
+    }
+}
+
+/// Smallest MTU the path to `peer` is assumed to have
+pub fn min_mtu(peer: &SocketAddr) -> usize {
+    if peer.is_ipv6() {
+        IPV6_MIN_MTU
+    } else {
+        IPV4_MIN_MTU
+    }
+}
+
+/// Path MTU towards each UDP peer, learned from sends refused as too big
+///
+/// With don't-fragment set the kernel refuses datagrams larger than the
+/// path with EMSGSIZE instead of fragmenting them; each refusal lowers the
+/// peer's estimate.
+#[derive(Debug, Default)]
+pub struct PathMtu {
+    peers: DashMap<SocketAddr, usize>,
+}
+
+impl PathMtu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that a datagram of `size` bytes to `peer` didn't fit, with
+    /// the path MTU the kernel reported if it knew one; returns the new MTU
+    pub fn record_too_big(&self, peer: SocketAddr, size: usize, reported: Option<usize>) -> usize {
+        let packet_size = size + header_overhead(&peer);
+        let floor = min_mtu(&peer);
+
+        let estimate = reported
+            .filter(|mtu| *mtu < packet_size)
+            .or_else(|| PLATEAUS.iter().copied().find(|plateau| *plateau < packet_size))
+            .unwrap_or(floor)
+            .max(floor);
+
+        let mut mtu = self.peers.entry(peer).or_insert(estimate);
+        *mtu = (*mtu).min(estimate);
+        *mtu
+    }
+
+    /// Path MTU towards `peer`, if a send to it was ever too big
+    pub fn mtu(&self, peer: &SocketAddr) -> Option<usize> {
+        self.peers.get(peer).map(|mtu| *mtu)
+    }
+
+    /// Largest datagram that fits the path to `peer`
+    pub fn max_datagram(&self, peer: &SocketAddr) -> Option<usize> {
+        self.mtu(peer).map(|mtu| mtu - header_overhead(peer))
+    }
+
+    /// Drop what was learned about a peer that went away
+    pub fn forget(&self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reported_mtu() {
+        let path_mtu = PathMtu::new();
+        let peer: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+        assert_eq!(path_mtu.mtu(&peer), None);
+
+        assert_eq!(path_mtu.record_too_big(peer, 1472, Some(1400)), 1400);
+        assert_eq!(path_mtu.max_datagram(&peer), Some(1372));
+
+        // A larger report for a later refusal doesn't raise the estimate
+        assert_eq!(path_mtu.record_too_big(peer, 1472, Some(1450)), 1400);
+    }
+
+    #[test]
+    fn test_plateaus() {
+        let path_mtu = PathMtu::new();
+        let peer: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+
+        assert_eq!(path_mtu.record_too_big(peer, 1472, None), 1492);
+        assert_eq!(path_mtu.record_too_big(peer, 1464, None), 1280);
+        assert_eq!(path_mtu.record_too_big(peer, 300, None), IPV4_MIN_MTU);
+
+        path_mtu.forget(&peer);
+        assert_eq!(path_mtu.mtu(&peer), None);
+    }
+
+    #[test]
+    fn test_ipv6_floor() {
+        let path_mtu = PathMtu::new();
+        let peer: SocketAddr = "[2001:db8::4]:40000".parse().unwrap();
+
+        assert_eq!(path_mtu.record_too_big(peer, 1400, Some(1000)), IPV6_MIN_MTU);
+        assert_eq!(path_mtu.max_datagram(&peer), Some(1232));
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::PortHoppingConfig;
+use crate::crypto::derive_keys;
+
+/// Ports a hopping UDP listener uses over time
+///
+/// Time is cut into epochs of `interval` seconds; the port of an epoch is
+/// derived from the shared secret and the epoch number with HKDF, so clients
+/// holding the secret compute the same schedule and observers can't.
+#[derive(Debug, Clone)]
+pub struct PortSchedule {
+    secret: Vec<u8>,
+    port_min: u16,
+    ports: u32,
+    interval: u64,
+}
+
+impl PortSchedule {
+    /// Create from config, None when port hopping is disabled
+    pub fn new(config: &PortHoppingConfig) -> Option<Self> {
+        config.enabled.then(|| Self {
+            secret: config.secret.as_bytes().to_vec(),
+            port_min: config.port_min,
+            ports: (config.port_max - config.port_min) as u32 + 1,
+            interval: config.interval,
+        })
+    }
+
+    /// Epoch of a Unix time in seconds
+    pub fn epoch(&self, unix_secs: u64) -> u64 {
+        unix_secs / self.interval
+    }
+
+    /// Port of an epoch
+    pub fn port(&self, epoch: u64) -> u16 {
+        let mut info = b"LLP-v1-port-hop".to_vec();
+        info.extend_from_slice(&epoch.to_be_bytes());
+
+        let okm = derive_keys(&self.secret, b"", &info, 4).expect("4 bytes are within HKDF limits");
+        let value = u32::from_be_bytes([okm[0], okm[1], okm[2], okm[3]]);
+        self.port_min + (value % self.ports) as u16
+    }
+
+    /// Ports to listen on at a Unix time
+    ///
+    /// Besides the current epoch's port, the previous and next one stay open
+    /// so clients whose clocks are off by up to one interval still get in.
+    pub fn listening_ports(&self, unix_secs: u64) -> BTreeSet<u16> {
+        let epoch = self.epoch(unix_secs);
+        [epoch.saturating_sub(1), epoch, epoch + 1]
+            .into_iter()
+            .map(|epoch| self.port(epoch))
+            .collect()
+    }
+
+    /// Time from a Unix time until the next epoch starts
+    pub fn until_next_epoch(&self, unix_secs: u64) -> Duration {
+        Duration::from_secs(self.interval - unix_secs % self.interval)
+    }
+}
+
+/// Current Unix time in seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(secret: &str) -> PortSchedule {
+        PortSchedule::new(&PortHoppingConfig {
+            enabled: true,
+            secret: secret.to_string(),
+            port_min: 40000,
+            port_max: 40099,
+            interval: 30,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(PortSchedule::new(&PortHoppingConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_schedule_is_shared_and_in_range() {
+        let server = schedule("correct horse");
+        let client = schedule("correct horse");
+        let other = schedule("battery staple");
+
+        let ports: Vec<u16> = (0..50).map(|epoch| server.port(epoch)).collect();
+        assert!(ports.iter().all(|port| (40000..=40099).contains(port)));
+        assert_eq!(ports, (0..50).map(|epoch| client.port(epoch)).collect::<Vec<_>>());
+        assert_ne!(ports, (0..50).map(|epoch| other.port(epoch)).collect::<Vec<_>>());
+        // It does hop
+        assert!(ports.iter().collect::<BTreeSet<_>>().len() > 10);
+    }
+
+    #[test]
+    fn test_listening_window() {
+        let schedule = schedule("correct horse");
+        let now = 1_700_000_025;
+        let epoch = schedule.epoch(now);
+
+        let ports = schedule.listening_ports(now);
+        for epoch in [epoch - 1, epoch, epoch + 1] {
+            assert!(ports.contains(&schedule.port(epoch)));
+        }
+        assert_eq!(schedule.until_next_epoch(now), Duration::from_secs(15));
+    }
+}
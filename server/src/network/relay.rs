@@ -0,0 +1,148 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::config::{RateClass, RelayConfig};
+use crate::core::rate_limiter::TokenBucket;
+use crate::error::{LostLoveError, Result};
+
+/// Traffic relayed along one path, as reported over the control socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayPathStats {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub packets: u64,
+    pub bytes: u64,
+    /// Packets dropped by the path's rate limit
+    pub dropped: u64,
+}
+
+/// Counters and bucket of one source/destination pair
+#[derive(Debug)]
+struct RelayPath {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+/// Forwards traffic between clients inside the server
+///
+/// Each direction between two tunnel addresses is a separate path with its
+/// own byte counters and token bucket.
+#[derive(Debug)]
+pub struct Relay {
+    class: Option<RateClass>,
+    paths: DashMap<(IpAddr, IpAddr), RelayPath>,
+}
+
+impl Relay {
+    /// Create from config, None when relaying is disabled
+    pub fn new(config: &RelayConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        Some(Self {
+            class: (config.rate_limit_per_path > 0).then_some(RateClass {
+                rate: config.rate_limit_per_path,
+                burst: config.burst_per_path,
+            }),
+            paths: DashMap::new(),
+        })
+    }
+
+    /// Account a packet on its path, `RateLimited` if it exceeds the limit
+    pub fn admit(&self, source: IpAddr, destination: IpAddr, size: usize) -> Result<()> {
+        let path = self.paths.entry((source, destination)).or_insert_with(|| RelayPath {
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            bucket: self.class.map(|class| Mutex::new(TokenBucket::new(class))),
+        });
+
+        let over_limit = path
+            .bucket
+            .as_ref()
+            .is_some_and(|bucket| !bucket.lock().unwrap().try_consume(size as u64));
+        if over_limit {
+            path.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(LostLoveError::RateLimited);
+        }
+
+        path.packets.fetch_add(1, Ordering::Relaxed);
+        path.bytes.fetch_add(size as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Drop all paths from or to an address that is no longer leased
+    pub fn forget(&self, address: IpAddr) {
+        self.paths
+            .retain(|(source, destination), _| *source != address && *destination != address);
+    }
+
+    /// Per-path counters, busiest first
+    pub fn snapshot(&self) -> Vec<RelayPathStats> {
+        let mut paths: Vec<_> = self
+            .paths
+            .iter()
+            .map(|entry| {
+                let (source, destination) = *entry.key();
+                let path = entry.value();
+                RelayPathStats {
+                    source,
+                    destination,
+                    packets: path.packets.load(Ordering::Relaxed),
+                    bytes: path.bytes.load(Ordering::Relaxed),
+                    dropped: path.dropped.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+
+        paths.sort_by_key(|path| Reverse(path.bytes));
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(host: u8) -> IpAddr {
+        IpAddr::from([10, 8, 0, host])
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(Relay::new(&RelayConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_path_accounting_and_limit() {
+        let relay = Relay::new(&RelayConfig {
+            enabled: true,
+            rate_limit_per_path: 1000,
+            burst_per_path: Some(1500),
+        })
+        .unwrap();
+
+        relay.admit(address(2), address(3), 1000).unwrap();
+        assert!(matches!(
+            relay.admit(address(2), address(3), 1000),
+            Err(LostLoveError::RateLimited)
+        ));
+        // The reverse direction has its own bucket
+        relay.admit(address(3), address(2), 1000).unwrap();
+
+        let paths = relay.snapshot();
+        assert_eq!(paths.len(), 2);
+        let forward = paths.iter().find(|path| path.source == address(2)).unwrap();
+        assert_eq!((forward.packets, forward.bytes, forward.dropped), (1, 1000, 1));
+
+        relay.forget(address(3));
+        assert!(relay.snapshot().is_empty());
+    }
+}
@@ -1,19 +1,86 @@
+use bytes::Bytes;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tracing::{debug, warn};
 
-use crate::core::connection::ConnectionManager;
+use crate::core::connection::{Connection, ConnectionManager};
 use crate::core::session::SessionId;
-use crate::error::Result;
+use crate::error::{LostLoveError, Result};
+use crate::network::firewall::{Firewall, Verdict};
+use crate::network::icmp;
+use crate::network::inner_packet::{InnerPacket, IpProtocol};
+use crate::network::mss::MssClamp;
+use crate::network::offload;
+use crate::network::relay::Relay;
+use crate::network::site_routes::SiteRoutes;
 
 /// Packet router for forwarding packets between TUN and connections
 pub struct PacketRouter {
     connection_manager: Arc<ConnectionManager>,
+    traffic_breakdown: bool,
+    validate_source: bool,
+    firewall: Option<Arc<Firewall>>,
+    mss_clamp: Option<MssClamp>,
+    path_mtu: Option<usize>,
+    site_routes: Option<Arc<SiteRoutes>>,
+    relay: Option<Arc<Relay>>,
 }
 
 impl PacketRouter {
     /// Create new packet router
     pub fn new(connection_manager: Arc<ConnectionManager>) -> Self {
-        Self { connection_manager }
+        Self {
+            connection_manager,
+            traffic_breakdown: false,
+            validate_source: true,
+            firewall: None,
+            mss_clamp: None,
+            path_mtu: None,
+            site_routes: None,
+            relay: None,
+        }
+    }
+
+    /// Answer client packets larger than the path MTU with ICMP "packet too big"
+    pub fn with_path_mtu(mut self, mtu: usize) -> Self {
+        self.path_mtu = Some(mtu);
+        self
+    }
+
+    /// Clamp the MSS of client TCP SYNs to fit the tunnel MTU
+    pub fn with_mss_clamp(mut self, mtu: usize) -> Self {
+        self.mss_clamp = Some(MssClamp::new(mtu));
+        self
+    }
+
+    /// Drop client packets whose inner source isn't the session's lease
+    pub fn with_source_validation(mut self, enabled: bool) -> Self {
+        self.validate_source = enabled;
+        self
+    }
+
+    /// Filter tunneled traffic through a stateful firewall
+    pub fn with_firewall(mut self, firewall: Arc<Firewall>) -> Self {
+        self.firewall = Some(firewall);
+        self
+    }
+
+    /// Forward packets for subnets learned from federated sites
+    pub fn with_site_routes(mut self, site_routes: Arc<SiteRoutes>) -> Self {
+        self.site_routes = Some(site_routes);
+        self
+    }
+
+    /// Relay traffic between clients with per-path accounting and limits
+    pub fn with_relay(mut self, relay: Arc<Relay>) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    /// Enable per-session breakdown of client traffic by inner protocol/port
+    pub fn with_traffic_breakdown(mut self, enabled: bool) -> Self {
+        self.traffic_breakdown = enabled;
+        self
     }
 
     /// Route packet from TUN interface to client
@@ -26,18 +93,20 @@ impl PacketRouter {
 
         // Get connection
         if let Some(connection) = self.connection_manager.get_connection(session_id) {
-            // Check if connection is active
-            if connection.session().is_active().await {
-                // In Phase 1, we just log. Actual sending will be implemented later
-                debug!("Would send packet to session {}", session_id);
-                connection.session().record_packet_sent(packet.len()).await;
-                Ok(())
-            } else {
-                warn!("Session {} is not active", session_id);
-                Err(crate::error::LostLoveError::Connection(
-                    "Session not active".to_string(),
-                ))
+            if let Some(firewall) = &self.firewall {
+                let inner = InnerPacket::parse(packet)?;
+                // ICMP errors belong to the flow of the packet they quote
+                let verdict = match icmp::quoted_packet(packet, &inner) {
+                    Some(quoted) => firewall.check_related(session_id, &quoted),
+                    None => firewall.check_inbound(session_id, &inner),
+                };
+                if let Verdict::Drop(reason) = verdict {
+                    debug!("Firewall dropped packet to session {}: {}", session_id, reason.as_str());
+                    return Err(LostLoveError::Filtered(reason.as_str()));
+                }
             }
+
+            Self::deliver(&connection, packet).await
         } else {
             warn!("Session {} not found", session_id);
             Err(crate::error::LostLoveError::SessionNotFound(
@@ -46,6 +115,86 @@ impl PacketRouter {
         }
     }
 
+    /// Route a coalesced TCP packet read from TUN, segmenting it late
+    ///
+    /// Peers that negotiated `gso_max_size` get the packet whole and segment
+    /// it themselves; everyone else gets segments of `mss` payload bytes.
+    pub async fn route_coalesced_from_tun(&self, packet: &[u8], mss: usize, session_id: &SessionId) -> Result<()> {
+        let Some(connection) = self.connection_manager.get_connection(session_id) else {
+            return Err(LostLoveError::SessionNotFound(session_id.to_string()));
+        };
+
+        if packet.len() <= Self::coalesced_limit(&connection).await {
+            return self.route_from_tun(packet, session_id).await;
+        }
+        for segment in offload::segment(packet, mss)? {
+            self.route_from_tun(&segment, session_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Largest coalesced packet the session's peer takes whole
+    async fn coalesced_limit(connection: &Connection) -> usize {
+        let handshake = connection.handshake().read().await;
+        handshake.negotiated_extensions().gso_max_size.unwrap_or(0) as usize
+    }
+
+    /// Find the session a packet read from TUN is addressed to
+    ///
+    /// ICMP errors generated for NATed traffic are matched by the client
+    /// packet they quote when the destination isn't a leased address.
+    pub fn session_for_packet(&self, packet: &[u8]) -> Option<SessionId> {
+        let pool = self.connection_manager.ip_pool()?;
+        let inner = InnerPacket::parse(packet).ok()?;
+
+        let holder = |address: IpAddr| match address {
+            IpAddr::V4(address) => pool.lease_holder(address),
+            IpAddr::V6(_) => None,
+        };
+
+        holder(inner.destination).or_else(|| {
+            let quoted = icmp::quoted_packet(packet, &inner)?;
+            holder(quoted.source)
+        })
+    }
+
+    /// Find the federated site a packet read from TUN is addressed to
+    pub fn site_for_packet(&self, packet: &[u8]) -> Option<String> {
+        let site_routes = self.site_routes.as_ref()?;
+        let inner = InnerPacket::parse(packet).ok()?;
+        site_routes.lookup(inner.destination)
+    }
+
+    /// Find the session a client packet is relayed to
+    ///
+    /// None unless relaying is enabled and the destination is another
+    /// client's leased address.
+    pub fn relay_target(&self, packet: &[u8], from_session: &SessionId) -> Option<SessionId> {
+        self.relay.as_ref()?;
+        let pool = self.connection_manager.ip_pool()?;
+        let inner = InnerPacket::parse(packet).ok()?;
+
+        let IpAddr::V4(destination) = inner.destination else {
+            return None;
+        };
+        pool.lease_holder(destination)
+            .filter(|holder| holder != from_session)
+    }
+
+    /// Queue a packet for a client's transport to send
+    async fn deliver(connection: &Connection, packet: &[u8]) -> Result<()> {
+        let session_id = connection.session().id();
+
+        if connection.session().is_active().await {
+            connection.send_tunneled(Bytes::copy_from_slice(packet))
+        } else {
+            warn!("Session {} is not active", session_id);
+            Err(crate::error::LostLoveError::Connection(
+                "Session not active".to_string(),
+            ))
+        }
+    }
+
     /// Route packet from client to TUN interface
     pub async fn route_to_tun(&self, packet: &[u8], session_id: &SessionId) -> Result<Vec<u8>> {
         debug!(
@@ -59,9 +208,92 @@ impl PacketRouter {
             connection.session().record_packet_received(packet.len()).await;
             connection.update_activity().await;
 
-            // In Phase 1, just return the packet as-is
-            // Later this will extract the inner IP packet
-            Ok(packet.to_vec())
+            let lease = connection.session().tunnel_address();
+            let validate_source = self.validate_source && lease.is_some();
+
+            let mut oversize = self.path_mtu.filter(|mtu| packet.len() > *mtu);
+
+            let inner = if self.traffic_breakdown
+                || validate_source
+                || self.firewall.is_some()
+                || oversize.is_some()
+            {
+                match InnerPacket::parse(packet) {
+                    Ok(inner) => Some(inner),
+                    Err(e) => {
+                        debug!("Not an IP packet from session {}: {}", session_id, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            if validate_source || self.firewall.is_some() {
+                let Some(inner) = &inner else {
+                    connection.session().record_error().await;
+                    return Err(LostLoveError::MalformedInnerPacket(
+                        "not an IP packet".to_string(),
+                    ));
+                };
+
+                if let Some(leased) = lease.filter(|_| validate_source) {
+                    if inner.source != leased {
+                        connection.session().record_source_violation().await;
+                        warn!(
+                            "Dropped packet from session {} with source {} (leased {})",
+                            session_id, inner.source, leased
+                        );
+                        return Err(LostLoveError::SourceAddressViolation {
+                            leased,
+                            actual: inner.source,
+                        });
+                    }
+                }
+            }
+
+            // Peers with segmentation offload send TCP coalesced up to the negotiated size
+            let coalesced = inner.as_ref().is_some_and(|inner| inner.protocol == IpProtocol::Tcp);
+            if oversize.is_some() && coalesced && packet.len() <= Self::coalesced_limit(&connection).await {
+                oversize = None;
+            }
+
+            if let Some(mtu) = oversize {
+                connection.session().record_error().await;
+                self.reply_packet_too_big(&connection, packet, inner.as_ref(), mtu)
+                    .await;
+                return Err(LostLoveError::PacketTooBig {
+                    size: packet.len(),
+                    mtu,
+                });
+            }
+
+            if let (Some(firewall), Some(inner)) = (&self.firewall, &inner) {
+                if let Verdict::Drop(reason) = firewall.check_outbound(session_id, inner) {
+                    warn!(
+                        "Firewall dropped packet from session {}: {}",
+                        session_id,
+                        reason.as_str()
+                    );
+                    return Err(LostLoveError::Filtered(reason.as_str()));
+                }
+            }
+
+            if self.traffic_breakdown {
+                if let Some(inner) = &inner {
+                    connection.session().record_inner_packet(inner, packet.len()).await;
+                }
+            }
+
+            let mut packet = packet.to_vec();
+
+            if let Some(mss_clamp) = &self.mss_clamp {
+                if let Some((from, to)) = mss_clamp.apply(&mut packet) {
+                    debug!("Clamped MSS {} -> {} for session {}", from, to, session_id);
+                }
+            }
+
+            Ok(packet)
         } else {
             warn!("Session {} not found", session_id);
             Err(crate::error::LostLoveError::SessionNotFound(
@@ -70,6 +302,44 @@ impl PacketRouter {
         }
     }
 
+    /// Tell the client to lower its path MTU instead of dropping silently
+    async fn reply_packet_too_big(
+        &self,
+        connection: &Connection,
+        packet: &[u8],
+        inner: Option<&InnerPacket>,
+        mtu: usize,
+    ) {
+        let source = self
+            .connection_manager
+            .ip_pool()
+            .map(|pool| IpAddr::V4(pool.server_address()));
+
+        let reply = match (inner, source) {
+            (Some(inner), Some(source)) => icmp::packet_too_big(packet, inner, mtu, source),
+            _ => None,
+        };
+
+        match reply {
+            Some(reply) => {
+                debug!(
+                    "Packet of {} bytes from session {} exceeds MTU {}, sending ICMP",
+                    packet.len(),
+                    connection.session().id(),
+                    mtu
+                );
+                if let Err(e) = Self::deliver(connection, &reply).await {
+                    debug!("Failed to deliver ICMP packet too big: {}", e);
+                }
+            }
+            None => debug!(
+                "Dropped oversize packet of {} bytes from session {}",
+                packet.len(),
+                connection.session().id()
+            ),
+        }
+    }
+
     /// Route packet between two sessions (peer-to-peer)
     pub async fn route_p2p(
         &self,
@@ -99,14 +369,18 @@ impl PacketRouter {
                 crate::error::LostLoveError::SessionNotFound(to_session.to_string())
             })?;
 
-        // Update stats
-        from_conn.session().record_packet_sent(packet.len()).await;
-        to_conn.session().record_packet_received(packet.len()).await;
-
-        // In Phase 1, just log
-        debug!("Would forward packet from {} to {}", from_session, to_session);
+        if let Some(relay) = &self.relay {
+            let source = from_conn.session().tunnel_address();
+            let destination = to_conn.session().tunnel_address();
+            if let (Some(source), Some(destination)) = (source, destination) {
+                if let Err(e) = relay.admit(source, destination, packet.len()) {
+                    debug!("Relay path {} -> {} over its limit", source, destination);
+                    return Err(e);
+                }
+            }
+        }
 
-        Ok(())
+        Self::deliver(&to_conn, packet).await
     }
 
     /// Get active routes count
@@ -119,6 +393,12 @@ impl PacketRouter {
 mod tests {
     use super::*;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use tokio::sync::mpsc;
+
+    /// Packets queued for a client so far
+    fn drain(tunnel: &mut mpsc::Receiver<Bytes>) -> Vec<Bytes> {
+        std::iter::from_fn(|| tunnel.try_recv().ok()).collect()
+    }
 
     #[tokio::test]
     async fn test_router_creation() {
@@ -148,21 +428,319 @@ mod tests {
         // Create connection
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
         let conn = manager.create_connection(addr).unwrap();
-        let session_id = conn.session().id().clone();
+        let session_id = *conn.session().id();
+
+        // Inactive sessions get nothing
+        let packet = vec![0u8; 100];
+        assert!(router.route_from_tun(&packet, &session_id).await.is_err());
 
-        // Set session as active
         conn.session()
-            .set_state(crate::core::session::SessionState::Active)
-            .await;
+            .apply(crate::core::session::SessionEvent::HandshakeCompleted)
+            .await
+            .unwrap();
+        // Nor do those whose transport doesn't take packets yet
+        assert!(router.route_from_tun(&packet, &session_id).await.is_err());
 
-        // Route packet
-        let packet = vec![0u8; 100];
-        let result = router.route_from_tun(&packet, &session_id).await;
-        assert!(result.is_ok());
+        let mut tunnel = conn.open_tunnel().unwrap();
+        router.route_from_tun(&packet, &session_id).await.unwrap();
+        assert_eq!(drain(&mut tunnel), [Bytes::from(packet)]);
+    }
+
+    #[test]
+    fn test_site_for_packet() {
+        use crate::network::inner_packet::tests::ipv4_packet;
+        use crate::protocol::AnnouncedRoute;
+
+        let site_routes = Arc::new(SiteRoutes::new());
+        site_routes
+            .replace("paris", &[AnnouncedRoute::new("192.168.20.0/24", 1)])
+            .unwrap();
+        let router = PacketRouter::new(Arc::new(ConnectionManager::new(10)))
+            .with_site_routes(site_routes);
+
+        let packet = ipv4_packet(6, [10, 8, 0, 2], [192, 168, 20, 5], (40000, 22));
+        assert_eq!(router.site_for_packet(&packet).as_deref(), Some("paris"));
+
+        let packet = ipv4_packet(6, [10, 8, 0, 2], [1, 1, 1, 1], (40000, 443));
+        assert_eq!(router.site_for_packet(&packet), None);
+    }
+
+    #[tokio::test]
+    async fn test_firewall_filters_traffic() {
+        use crate::config::FirewallConfig;
+        use crate::network::inner_packet::tests::ipv4_packet;
+        use crate::network::IpPool;
+
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let firewall = Arc::new(Firewall::new(&FirewallConfig::default()));
+        let router = PacketRouter::new(manager.clone()).with_firewall(firewall.clone());
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        let session_id = *conn.session().id();
+        conn.session()
+            .apply(crate::core::session::SessionEvent::HandshakeCompleted)
+            .await
+            .unwrap();
+        let mut tunnel = conn.open_tunnel().unwrap();
+
+        // Lease is 10.8.0.2; anything else is spoofed
+        let spoofed = ipv4_packet(6, [10, 8, 0, 3], [1, 1, 1, 1], (40000, 443));
+        assert!(router.route_to_tun(&spoofed, &session_id).await.is_err());
+
+        let reply = ipv4_packet(6, [1, 1, 1, 1], [10, 8, 0, 2], (443, 40000));
+        assert!(router.route_from_tun(&reply, &session_id).await.is_err());
+
+        let request = ipv4_packet(6, [10, 8, 0, 2], [1, 1, 1, 1], (40000, 443));
+        router.route_to_tun(&request, &session_id).await.unwrap();
+        router.route_from_tun(&reply, &session_id).await.unwrap();
+        assert_eq!(drain(&mut tunnel).len(), 1);
+
+        assert_eq!(conn.session().stats().await.source_violations, 1);
+        assert_eq!(firewall.stats().no_established_flow, 1);
+    }
+
+    #[tokio::test]
+    async fn test_relay_between_clients() {
+        use crate::config::RelayConfig;
+        use crate::network::inner_packet::tests::ipv4_packet;
+        use crate::network::IpPool;
+
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let relay = Arc::new(
+            Relay::new(&RelayConfig {
+                enabled: true,
+                rate_limit_per_path: 10,
+                burst_per_path: Some(60),
+            })
+            .unwrap(),
+        );
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let alice = manager.create_connection(addr).unwrap();
+        let bob = manager.create_connection(addr).unwrap();
+        bob.session()
+            .apply(crate::core::session::SessionEvent::HandshakeCompleted)
+            .await
+            .unwrap();
+        let mut bob_tunnel = bob.open_tunnel().unwrap();
+
+        // Alice (10.8.0.2) to Bob (10.8.0.3)
+        let packet = ipv4_packet(17, [10, 8, 0, 2], [10, 8, 0, 3], (5000, 5000));
+        assert_eq!(
+            PacketRouter::new(manager.clone()).relay_target(&packet, alice.session().id()),
+            None
+        );
+
+        let router = PacketRouter::new(manager.clone()).with_relay(relay.clone());
+        let target = router.relay_target(&packet, alice.session().id()).unwrap();
+        assert_eq!(&target, bob.session().id());
+
+        let to_internet = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (5000, 53));
+        assert_eq!(router.relay_target(&to_internet, alice.session().id()), None);
+
+        router
+            .route_p2p(&packet, alice.session().id(), &target)
+            .await
+            .unwrap();
+        assert!(matches!(
+            router.route_p2p(&packet, alice.session().id(), &target).await,
+            Err(LostLoveError::RateLimited)
+        ));
+        assert_eq!(drain(&mut bob_tunnel), [Bytes::from(packet)]);
+        // 40 byte packets against a 60 byte burst
+        assert_eq!(relay.snapshot()[0].bytes, 40);
+        assert_eq!(relay.snapshot()[0].dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_source_validation() {
+        use crate::network::inner_packet::tests::ipv4_packet;
+        use crate::network::IpPool;
+
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let router = PacketRouter::new(manager.clone());
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let alice = manager.create_connection(addr).unwrap();
+        let bob = manager.create_connection(addr).unwrap();
+
+        // Alice (10.8.0.2) pretending to be Bob (10.8.0.3)
+        let packet = ipv4_packet(17, [10, 8, 0, 3], [1, 1, 1, 1], (5000, 53));
+        let result = router.route_to_tun(&packet, alice.session().id()).await;
+        assert!(matches!(
+            result,
+            Err(LostLoveError::SourceAddressViolation { .. })
+        ));
+        assert_eq!(alice.session().stats().await.source_violations, 1);
+
+        router.route_to_tun(&packet, bob.session().id()).await.unwrap();
+
+        // Non-IP payloads can't be validated and are dropped too
+        assert!(router.route_to_tun(&[0u8; 10], bob.session().id()).await.is_err());
+
+        // Disabled: anything goes
+        let router = PacketRouter::new(manager.clone()).with_source_validation(false);
+        router.route_to_tun(&packet, alice.session().id()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversize_packet_answered_with_icmp() {
+        use crate::network::inner_packet::tests::ipv4_packet;
+        use crate::network::IpPool;
+
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let router = PacketRouter::new(manager.clone()).with_path_mtu(1400);
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        conn.session()
+            .apply(crate::core::session::SessionEvent::HandshakeCompleted)
+            .await
+            .unwrap();
+        let mut tunnel = conn.open_tunnel().unwrap();
+
+        let mut packet = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (5000, 53));
+        packet.resize(1500, 0);
+        packet[2..4].copy_from_slice(&1500u16.to_be_bytes());
+
+        let result = router.route_to_tun(&packet, conn.session().id()).await;
+        assert!(matches!(
+            result,
+            Err(LostLoveError::PacketTooBig { size: 1500, mtu: 1400 })
+        ));
+        // The ICMP reply went back to the client
+        let replies = drain(&mut tunnel);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0][9], 1);
+
+        packet.truncate(1400);
+        router.route_to_tun(&packet, conn.session().id()).await.unwrap();
+    }
+
+    /// Negotiate `gso_max_size` on the connection as a client offering it would
+    async fn negotiate_gso(conn: &Connection, size: u32) {
+        use crate::protocol::{Handshake, HelloExtensions};
+
+        let extensions = HelloExtensions {
+            gso_max_size: Some(size),
+            ..HelloExtensions::default()
+        };
+        let hello = Handshake::new_client()
+            .with_extensions(extensions.clone())
+            .generate_client_hello()
+            .unwrap();
+        let mut handshake = conn.handshake().write().await;
+        handshake.set_extensions(extensions);
+        handshake.process_client_hello(&hello).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_packets() {
+        use crate::network::offload::tests::tcp_packet;
+        use crate::network::IpPool;
+
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let router = PacketRouter::new(manager.clone()).with_path_mtu(1400);
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        conn.session()
+            .apply(crate::core::session::SessionEvent::HandshakeCompleted)
+            .await
+            .unwrap();
+        let session_id = *conn.session().id();
+        let mut tunnel = conn.open_tunnel().unwrap();
+
+        // Without offload the peer gets MTU-sized segments
+        let reply = tcp_packet(4000, 0x10);
+        router.route_coalesced_from_tun(&reply, 1360, &session_id).await.unwrap();
+        assert_eq!(drain(&mut tunnel).len(), 3);
+
+        // Coalesced TCP from the client is refused like any oversize packet
+        let mut request = tcp_packet(4000, 0x10);
+        request[12..20].copy_from_slice(&[10, 8, 0, 2, 1, 1, 1, 1]);
+        assert!(router.route_to_tun(&request, &session_id).await.is_err());
+
+        // With offload both directions pass whole up to the negotiated size
+        negotiate_gso(&conn, 8192).await;
+        router.route_coalesced_from_tun(&reply, 1360, &session_id).await.unwrap();
+        assert_eq!(drain(&mut tunnel).len(), 2);
+        assert_eq!(router.route_to_tun(&request, &session_id).await.unwrap().len(), 4040);
+
+        router.route_coalesced_from_tun(&tcp_packet(9000, 0x10), 1360, &session_id).await.unwrap();
+        assert_eq!(drain(&mut tunnel).len(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_icmp_error_mapped_to_session() {
+        use crate::network::inner_packet::tests::ipv4_packet;
+        use crate::network::IpPool;
+
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let router = PacketRouter::new(manager.clone());
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+
+        // Port unreachable from a remote host, quoting the client's datagram,
+        // addressed to the server itself
+        let quoted = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (5000, 53));
+        let mut error = ipv4_packet(1, [1, 1, 1, 1], [10, 8, 0, 1], (0x0303, 0));
+        error.truncate(28);
+        error.extend_from_slice(&quoted[..28]);
+        error[2..4].copy_from_slice(&56u16.to_be_bytes());
+
+        assert_eq!(router.session_for_packet(&error), Some(*conn.session().id()));
+
+        let reply = ipv4_packet(17, [1, 1, 1, 1], [10, 8, 0, 2], (53, 5000));
+        assert_eq!(router.session_for_packet(&reply), Some(*conn.session().id()));
+
+        let stray = ipv4_packet(17, [1, 1, 1, 1], [10, 8, 0, 9], (53, 5000));
+        assert_eq!(router.session_for_packet(&stray), None);
+    }
+
+    #[tokio::test]
+    async fn test_mss_clamped_on_the_way_in() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let router = PacketRouter::new(manager.clone()).with_mss_clamp(1400);
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+
+        // IPv4 + TCP SYN with MSS 1460
+        let mut syn = crate::network::inner_packet::tests::ipv4_packet(6, [10, 8, 0, 2], [1, 1, 1, 1], (40000, 443));
+        syn[2..4].copy_from_slice(&44u16.to_be_bytes());
+        syn[32] = 6 << 4;
+        syn[33] = 0x02;
+        syn.extend_from_slice(&[2, 4, 0x05, 0xB4]);
+
+        let routed = router.route_to_tun(&syn, conn.session().id()).await.unwrap();
+        assert_eq!(u16::from_be_bytes([routed[42], routed[43]]), 1360);
+    }
+
+    #[tokio::test]
+    async fn test_traffic_breakdown_recorded() {
+        use crate::network::inner_packet::tests::ipv4_packet;
+
+        let manager = Arc::new(ConnectionManager::new(10));
+        let router = PacketRouter::new(manager.clone()).with_traffic_breakdown(true);
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let conn = manager.create_connection(addr).unwrap();
+        let session_id = *conn.session().id();
+
+        let packet = ipv4_packet(6, [10, 8, 0, 2], [1, 1, 1, 1], (40000, 443));
+        router.route_to_tun(&packet, &session_id).await.unwrap();
 
-        // Check stats
-        let stats = conn.session().stats().await;
-        assert_eq!(stats.packets_sent, 1);
-        assert_eq!(stats.bytes_sent, 100);
+        let traffic = conn.session().traffic().await;
+        assert_eq!(traffic.tcp.packets, 1);
+        assert_eq!(traffic.top_ports(1)[0].0 .1, 443);
     }
 }
@@ -0,0 +1,230 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use crate::error::{LostLoveError, Result};
+use crate::network::nat::RuleCommand;
+use crate::network::ip_net::IpNet;
+use crate::network::netlink::Route;
+use crate::protocol::route_announce::{AnnouncedRoute, MAX_METRIC};
+
+/// One subnet reachable through a federated site
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SiteRoute {
+    /// Without host bits
+    network: IpNet,
+    metric: u32,
+    site: String,
+}
+
+impl SiteRoute {
+    fn contains(&self, address: IpAddr) -> bool {
+        self.network.contains(address)
+    }
+
+    fn cidr(&self) -> String {
+        self.network.to_string()
+    }
+
+    fn announced(&self) -> AnnouncedRoute {
+        AnnouncedRoute::new(self.cidr(), self.metric)
+    }
+
+    /// Preference among routes to the same address: most specific, then
+    /// fewest hops, then site name so the choice is stable
+    fn rank(&self) -> (u8, Reverse<u32>, Reverse<&str>) {
+        (self.network.prefix_len(), Reverse(self.metric), Reverse(self.site.as_str()))
+    }
+}
+
+/// Subnets learned from federated sites, looked up by longest prefix
+///
+/// Several sites may announce the same subnet; the one with the lowest
+/// metric is used.
+#[derive(Debug, Default)]
+pub struct SiteRoutes {
+    routes: RwLock<Vec<SiteRoute>>,
+}
+
+impl SiteRoutes {
+    /// Create an empty table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace all routes of a site, returns true if the table changed
+    ///
+    /// Nothing changes if any subnet is invalid. Unreachable routes (metric
+    /// of `MAX_METRIC` or more) are dropped.
+    pub fn replace(&self, site: &str, announced: &[AnnouncedRoute]) -> Result<bool> {
+        let mut parsed = Vec::with_capacity(announced.len());
+        for route in announced {
+            let network = route.prefix.parse::<IpNet>().map_err(|e| {
+                LostLoveError::Network(format!("Invalid route {:?}: {}", route.prefix, e))
+            })?;
+            if route.metric >= MAX_METRIC {
+                continue;
+            }
+            parsed.push(SiteRoute {
+                network: network.trunc(),
+                metric: route.metric,
+                site: site.to_string(),
+            });
+        }
+
+        let mut routes = self.routes.write().unwrap();
+        let previous: Vec<_> = routes.iter().filter(|route| route.site == site).cloned().collect();
+        if previous == parsed {
+            return Ok(false);
+        }
+
+        routes.retain(|route| route.site != site);
+        routes.extend(parsed);
+        Ok(true)
+    }
+
+    /// Forget all routes of a site, returns true if it had any
+    pub fn remove_site(&self, site: &str) -> bool {
+        let mut routes = self.routes.write().unwrap();
+        let before = routes.len();
+        routes.retain(|route| route.site != site);
+        routes.len() != before
+    }
+
+    /// Site whose most specific subnet contains the address
+    pub fn lookup(&self, address: IpAddr) -> Option<String> {
+        self.routes
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|route| route.contains(address))
+            .max_by(|a, b| a.rank().cmp(&b.rank()))
+            .map(|route| route.site.clone())
+    }
+
+    /// Best route of every known subnet, with the site it goes through
+    pub fn best_routes(&self) -> Vec<(AnnouncedRoute, String)> {
+        let routes = self.routes.read().unwrap();
+        let mut best: BTreeMap<String, &SiteRoute> = BTreeMap::new();
+
+        for route in routes.iter() {
+            best.entry(route.cidr())
+                .and_modify(|current| {
+                    if route.rank() > current.rank() {
+                        *current = route;
+                    }
+                })
+                .or_insert(route);
+        }
+
+        best.into_values()
+            .map(|route| (route.announced(), route.site.clone()))
+            .collect()
+    }
+
+    /// All subnets with at least one route
+    pub fn prefixes(&self) -> BTreeSet<IpNet> {
+        self.routes.read().unwrap().iter().map(|route| route.network).collect()
+    }
+
+    /// Routes by site
+    pub fn snapshot(&self) -> BTreeMap<String, Vec<AnnouncedRoute>> {
+        let mut sites: BTreeMap<String, Vec<AnnouncedRoute>> = BTreeMap::new();
+        for route in self.routes.read().unwrap().iter() {
+            sites.entry(route.site.clone()).or_default().push(route.announced());
+        }
+        sites
+    }
+}
+
+/// Kernel routes sending the subnets into the TUN interface
+pub fn route_commands(subnets: &[IpNet], tun_name: &str) -> Vec<RuleCommand> {
+    subnets
+        .iter()
+        .map(|subnet| RuleCommand::route(Route::via_device(*subnet, tun_name)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(prefixes: &[(&str, u32)]) -> Vec<AnnouncedRoute> {
+        prefixes
+            .iter()
+            .map(|(prefix, metric)| AnnouncedRoute::new(*prefix, *metric))
+            .collect()
+    }
+
+    #[test]
+    fn test_longest_prefix_lookup() {
+        let table = SiteRoutes::new();
+        table.replace("berlin", &routes(&[("10.20.0.0/16", 1)])).unwrap();
+        table.replace("paris", &routes(&[("10.20.5.1/24", 3)])).unwrap();
+
+        assert_eq!(table.lookup("10.20.5.9".parse().unwrap()).as_deref(), Some("paris"));
+        assert_eq!(table.lookup("10.20.6.9".parse().unwrap()).as_deref(), Some("berlin"));
+        assert_eq!(table.lookup("10.21.0.1".parse().unwrap()), None);
+        assert_eq!(table.snapshot()["paris"], routes(&[("10.20.5.0/24", 3)]));
+    }
+
+    #[test]
+    fn test_ipv6_routes() {
+        let table = SiteRoutes::new();
+        table
+            .replace("berlin", &routes(&[("2001:db8::/32", 1), ("10.20.0.0/16", 1)]))
+            .unwrap();
+        table.replace("paris", &routes(&[("2001:db8:5::1/48", 1)])).unwrap();
+
+        assert_eq!(table.lookup("2001:db8:5::9".parse().unwrap()).as_deref(), Some("paris"));
+        assert_eq!(table.lookup("2001:db8:6::9".parse().unwrap()).as_deref(), Some("berlin"));
+        assert_eq!(table.lookup("2001:db9::1".parse().unwrap()), None);
+        assert_eq!(table.snapshot()["paris"], routes(&[("2001:db8:5::/48", 1)]));
+    }
+
+    #[test]
+    fn test_lowest_metric_wins() {
+        let table = SiteRoutes::new();
+        table.replace("berlin", &routes(&[("10.30.0.0/16", 2)])).unwrap();
+        table.replace("paris", &routes(&[("10.30.0.0/16", 1)])).unwrap();
+        assert_eq!(table.lookup("10.30.0.1".parse().unwrap()).as_deref(), Some("paris"));
+        assert_eq!(
+            table.best_routes(),
+            vec![(AnnouncedRoute::new("10.30.0.0/16", 1), "paris".to_string())]
+        );
+
+        // Falls back to the longer path once the shorter one is gone
+        assert!(table.remove_site("paris"));
+        assert_eq!(table.lookup("10.30.0.1".parse().unwrap()).as_deref(), Some("berlin"));
+    }
+
+    #[test]
+    fn test_replace_and_remove() {
+        let table = SiteRoutes::new();
+        assert!(table.replace("berlin", &routes(&[("10.20.0.0/16", 1)])).unwrap());
+        assert!(!table.replace("berlin", &routes(&[("10.20.0.0/16", 1)])).unwrap());
+        assert!(table.replace("berlin", &routes(&[("bogus", 1)])).is_err());
+
+        // Unreachable routes are dropped
+        table
+            .replace("berlin", &routes(&[("10.30.0.0/16", 1), ("10.40.0.0/16", MAX_METRIC)]))
+            .unwrap();
+        assert_eq!(table.lookup("10.20.0.1".parse().unwrap()), None);
+        assert_eq!(table.prefixes().len(), 1);
+
+        assert!(table.remove_site("berlin"));
+        assert!(!table.remove_site("berlin"));
+        assert!(table.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_route_commands() {
+        let subnets = vec!["10.20.0.0/16".parse().unwrap()];
+        let commands = route_commands(&subnets, "tun0");
+        assert_eq!(
+            commands[0].display(&commands[0].install_args()),
+            "ip route add 10.20.0.0/16 dev tun0"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+
+use crate::config::TcpConfig;
+
+/// Pending connections the kernel queues before they are accepted
+const BACKLOG: i32 = 1024;
+
+/// Bind the TCP listener at `addr` with the options of `[server.tcp]`
+///
+/// Buffer sizes are set before `listen` so the window scale offered in the
+/// SYN-ACK matches them; accepted sockets inherit the listener's options.
+pub async fn bind(addr: &str, config: &TcpConfig) -> io::Result<TcpListener> {
+    let addr = lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing"))?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    apply(&SockRef::from(&socket), config)?;
+    socket.bind(&addr.into())?;
+    if config.fastopen_queue > 0 {
+        set_fastopen(&socket, config.fastopen_queue)?;
+    }
+    socket.listen(BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Set the options of `[server.tcp]` on an accepted connection
+///
+/// Linux already copies them from the listener; setting them again keeps
+/// other platforms, which don't, in line.
+pub fn tune(stream: &TcpStream, config: &TcpConfig) -> io::Result<()> {
+    apply(&SockRef::from(stream), config)
+}
+
+fn apply(socket: &SockRef<'_>, config: &TcpConfig) -> io::Result<()> {
+    socket.set_nodelay(config.nodelay)?;
+    if config.keepalive_idle > 0 {
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(config.keepalive_idle))
+            .with_interval(Duration::from_secs(config.keepalive_interval))
+            .with_retries(config.keepalive_count);
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = config.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_fastopen(socket: &Socket, queue: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let queue = queue as libc::c_int;
+    // SAFETY: the option value is a c_int that outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &queue as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fastopen(_socket: &Socket, _queue: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "TCP fast open needs Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tuned_connection() {
+        let config = TcpConfig {
+            keepalive_idle: 30,
+            keepalive_interval: 5,
+            keepalive_count: 3,
+            recv_buffer: Some(256 * 1024),
+            ..TcpConfig::default()
+        };
+        let listener = bind("127.0.0.1:0", &config).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        tune(&stream, &config).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_untuned_connection() {
+        let config = TcpConfig {
+            nodelay: false,
+            keepalive_idle: 0,
+            ..TcpConfig::default()
+        };
+        let listener = bind("127.0.0.1:0", &config).await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        tune(&stream, &config).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(!socket.nodelay().unwrap());
+        assert!(!socket.keepalive().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_fastopen_listener() {
+        let config = TcpConfig {
+            fastopen_queue: 16,
+            ..TcpConfig::default()
+        };
+        let listener = bind("127.0.0.1:0", &config).await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        assert!(listener.accept().await.is_ok());
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::config::NetworkConfig;
+use crate::error::Result;
+use crate::network::nat::{RuleCommand, RuleGuard};
+use crate::network::router::PacketRouter;
+use crate::network::tun_queue::TunQueues;
+
+/// First pause before re-creating a lost device, doubled after each failure
+const RECREATE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest pause between two attempts
+const MAX_RECREATE_DELAY: Duration = Duration::from_secs(30);
+
+type Opener = Box<dyn Fn() -> Result<TunQueues> + Send + Sync>;
+type Rules = Box<dyn Fn() -> Vec<RuleCommand> + Send + Sync>;
+
+/// Device lifecycle events since start
+#[derive(Debug, Default)]
+pub struct DeviceStats {
+    pub lost: AtomicU64,
+    pub recreated: AtomicU64,
+    /// Attempts to re-create the device that failed
+    pub failed: AtomicU64,
+}
+
+/// TUN device kept up across deletion and suspend/resume
+///
+/// When the queues report the interface gone, the device is opened again
+/// with the same config and the rules depending on it (NAT, routes through
+/// the interface) are installed anew. Writers take the device through
+/// `queues`, which is `None` while it is being re-created.
+pub struct TunDevice {
+    name: String,
+    opener: Opener,
+    rules: Rules,
+    current: watch::Sender<Option<Arc<TunQueues>>>,
+    guard: Mutex<Option<RuleGuard>>,
+    retry_delay: Duration,
+    stats: DeviceStats,
+}
+
+impl TunDevice {
+    /// Create the device from config and install the commands `rules` returns
+    ///
+    /// `rules` is asked again after each re-creation, so it can include
+    /// routes learned in the meantime.
+    pub fn open<F>(config: &NetworkConfig, rules: F) -> Result<Self>
+    where
+        F: Fn() -> Vec<RuleCommand> + Send + Sync + 'static,
+    {
+        let name = config.tun_name.clone();
+        let config = config.clone();
+        Self::with_opener(&name, move || TunQueues::open(&config), rules)
+    }
+
+    /// Device whose queues come from `opener`
+    pub(crate) fn with_opener<O, F>(name: &str, opener: O, rules: F) -> Result<Self>
+    where
+        O: Fn() -> Result<TunQueues> + Send + Sync + 'static,
+        F: Fn() -> Vec<RuleCommand> + Send + Sync + 'static,
+    {
+        let device = Self {
+            name: name.to_string(),
+            opener: Box::new(opener),
+            rules: Box::new(rules),
+            current: watch::channel(None).0,
+            guard: Mutex::new(None),
+            retry_delay: RECREATE_DELAY,
+            stats: DeviceStats::default(),
+        };
+        device.create()?;
+        Ok(device)
+    }
+
+    pub(crate) fn with_retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queues of the device as it is now, `None` while re-creating
+    pub fn queues(&self) -> Option<Arc<TunQueues>> {
+        self.current.borrow().clone()
+    }
+
+    /// Follow the device through re-creations
+    pub fn subscribe(&self) -> watch::Receiver<Option<Arc<TunQueues>>> {
+        self.current.subscribe()
+    }
+
+    pub fn stats(&self) -> &DeviceStats {
+        &self.stats
+    }
+
+    /// Read every queue into `router`, re-creating the device whenever it is lost
+    pub async fn run(self: Arc<Self>, router: Arc<PacketRouter>) {
+        loop {
+            let Some(queues) = self.queues() else {
+                self.recreate().await;
+                continue;
+            };
+
+            let readers = queues.spawn_readers(router.clone());
+            queues.lost().await;
+            for reader in readers {
+                reader.abort();
+                let _ = reader.await;
+            }
+
+            self.stats.lost.fetch_add(1, Ordering::Relaxed);
+            warn!("TUN interface {} lost, re-creating it", self.name);
+            // The old descriptors must be closed before the name can be taken again
+            self.current.send_replace(None);
+            drop(queues);
+            self.recreate().await;
+        }
+    }
+
+    /// Open the device again until it works, backing off between attempts
+    async fn recreate(&self) {
+        // Rules naming the old interface go before their replacements come
+        drop(self.guard.lock().unwrap().take());
+
+        let mut delay = self.retry_delay;
+        loop {
+            tokio::time::sleep(delay).await;
+            match self.create() {
+                Ok(()) => {
+                    self.stats.recreated.fetch_add(1, Ordering::Relaxed);
+                    info!("TUN interface {} re-created", self.name);
+                    return;
+                }
+                Err(e) => {
+                    self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                    delay = (delay * 2).min(MAX_RECREATE_DELAY);
+                    error!(
+                        "Failed to re-create TUN interface {}: {}, retrying in {:?}",
+                        self.name, e, delay
+                    );
+                }
+            }
+        }
+    }
+
+    fn create(&self) -> Result<()> {
+        let queues = (self.opener)()?;
+        let commands = (self.rules)();
+        if !commands.is_empty() {
+            *self.guard.lock().unwrap() = Some(RuleGuard::install(commands)?);
+        }
+        self.current.send_replace(Some(Arc::new(queues)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::connection::ConnectionManager;
+    use crate::error::LostLoveError;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Opener over socket pairs, failing while `failures` is above zero
+    fn opener(opened: Arc<AtomicUsize>, failures: Arc<AtomicUsize>) -> impl Fn() -> Result<TunQueues> + Send + Sync + 'static {
+        move || {
+            if failures.load(Ordering::SeqCst) > 0 {
+                failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(LostLoveError::Network("no /dev/net/tun".to_string()));
+            }
+            opened.fetch_add(1, Ordering::SeqCst);
+            let (queue, peer) = UnixDatagram::pair().unwrap();
+            queue.set_nonblocking(true).unwrap();
+            // Keep the other end open, like the kernel side of a TUN queue
+            std::mem::forget(peer);
+            TunQueues::from_fds("llp-test", 1400, vec![OwnedFd::from(queue)])
+        }
+    }
+
+    async fn settle(device: &TunDevice, recreated: u64) {
+        for _ in 0..500 {
+            if device.stats().recreated.load(Ordering::Relaxed) == recreated && device.queues().is_some() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("device was not re-created");
+    }
+
+    #[tokio::test]
+    async fn test_recreated_after_loss() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let failures = Arc::new(AtomicUsize::new(0));
+        let rules_built = Arc::new(AtomicUsize::new(0));
+        let device = Arc::new(
+            TunDevice::with_opener("llp-test", opener(opened.clone(), failures.clone()), {
+                let rules_built = rules_built.clone();
+                move || {
+                    rules_built.fetch_add(1, Ordering::SeqCst);
+                    Vec::new()
+                }
+            })
+            .unwrap()
+            .with_retry_delay(Duration::from_millis(1)),
+        );
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+        let router = Arc::new(PacketRouter::new(Arc::new(ConnectionManager::new(10))));
+        let runner = tokio::spawn(device.clone().run(router));
+
+        // Lost once: opened again with its rules
+        let first = device.queues().unwrap();
+        first.mark_lost();
+        settle(&device, 1).await;
+        assert!(!Arc::ptr_eq(&first, &device.queues().unwrap()));
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        assert_eq!(rules_built.load(Ordering::SeqCst), 2);
+
+        // Lost again while /dev/net/tun is unavailable for two attempts
+        failures.store(2, Ordering::SeqCst);
+        device.queues().unwrap().mark_lost();
+        settle(&device, 2).await;
+        let stats = device.stats();
+        assert_eq!(stats.lost.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.failed.load(Ordering::Relaxed), 2);
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+
+        runner.abort();
+    }
+
+    #[test]
+    fn test_open_fails_without_device() {
+        let failures = Arc::new(AtomicUsize::new(1));
+        let device = TunDevice::with_opener("llp-test", opener(Arc::default(), failures), Vec::new);
+        assert!(device.is_err());
+    }
+}
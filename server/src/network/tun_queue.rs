@@ -0,0 +1,496 @@
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::config::NetworkConfig;
+use crate::error::{LostLoveError, Result};
+use crate::network::ip_net::IpNet;
+use crate::network::netlink::Netlink;
+use crate::network::offload::{VirtioNetHdr, GSO_MAX_SIZE, VNET_HDR_SIZE};
+use crate::network::router::PacketRouter;
+
+/// Most queues Linux allows on one TUN device
+pub const MAX_TUN_QUEUES: usize = 256;
+
+/// Packet counters of one queue
+#[derive(Debug, Default)]
+struct QueueStats {
+    packets_read: AtomicU64,
+    bytes_read: AtomicU64,
+    packets_written: AtomicU64,
+    bytes_written: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Counters of one queue at one point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueSnapshot {
+    pub queue: usize,
+    pub packets_read: u64,
+    pub bytes_read: u64,
+    pub packets_written: u64,
+    pub bytes_written: u64,
+    /// Read packets no session or route took
+    pub dropped: u64,
+}
+
+/// TUN device with several queues, each its own file descriptor (Linux)
+///
+/// The kernel spreads packets leaving through the device across queues by
+/// flow, so one reader task per queue scales reading over cores while
+/// packets of a flow stay in order. Writes from clients go to the queue
+/// picked by the caller, any queue reaches the same interface.
+///
+/// With offload the kernel hands over TCP coalesced up to 64 KiB behind a
+/// virtio-net header and takes coalesced packets back, segmenting them.
+pub struct TunQueues {
+    name: String,
+    mtu: usize,
+    offload: bool,
+    queues: Vec<AsyncFd<OwnedFd>>,
+    stats: Vec<QueueStats>,
+    lost: watch::Sender<bool>,
+}
+
+impl TunQueues {
+    /// Create the device with `network.tun_queues` queues and bring it up
+    ///
+    /// The device disappears once every queue is closed.
+    pub fn open(config: &NetworkConfig) -> Result<Self> {
+        let tun_address = config.tun_address.parse::<IpNet>()?.ipv4("tun_address")?;
+        let fds = (0..config.tun_queues)
+            .map(|_| sys::open_queue(&config.tun_name, config.tun_offload))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| LostLoveError::Network(format!("Failed to open TUN queue: {}", e)))?;
+        let netlink = Netlink::open()?;
+        netlink.add_address(&config.tun_name, IpNet::V4(tun_address))?;
+        netlink.set_link_up(&config.tun_name, Some(config.mtu as u32))?;
+
+        info!(
+            "TUN interface {} created with {} queues{}",
+            config.tun_name,
+            fds.len(),
+            if config.tun_offload { " and segmentation offload" } else { "" }
+        );
+        Ok(Self::from_fds(&config.tun_name, config.mtu, fds)?.with_offload(config.tun_offload))
+    }
+
+    /// Queues over already open, non-blocking descriptors
+    pub(crate) fn from_fds(name: &str, mtu: usize, fds: Vec<OwnedFd>) -> Result<Self> {
+        let queues = fds.into_iter().map(AsyncFd::new).collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            name: name.to_string(),
+            mtu,
+            offload: false,
+            stats: queues.iter().map(|_| QueueStats::default()).collect(),
+            queues,
+            lost: watch::channel(false).0,
+        })
+    }
+
+    /// Descriptors carry a virtio-net header in front of each packet
+    pub(crate) fn with_offload(mut self, offload: bool) -> Self {
+        self.offload = offload;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Read one packet from a queue, behind a virtio-net header with offload
+    pub async fn read(&self, queue: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.queues[queue]
+            .async_io(Interest::READABLE, |fd| sys::read(fd.as_raw_fd(), buf))
+            .await
+            .inspect_err(|e| self.check_lost(e))?;
+        let stats = &self.stats[queue];
+        stats.packets_read.fetch_add(1, Ordering::Relaxed);
+        stats.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    /// Write one packet to a queue
+    ///
+    /// With offload a TCP packet above the MTU is segmented by the kernel.
+    pub async fn write(&self, queue: usize, packet: &[u8]) -> io::Result<()> {
+        let limit = if self.offload { GSO_MAX_SIZE } else { self.mtu };
+        if packet.len() > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Packet size {} exceeds MTU {}", packet.len(), self.mtu),
+            ));
+        }
+
+        let frame = if self.offload {
+            let mut frame = vec![0u8; VNET_HDR_SIZE];
+            frame.extend_from_slice(packet);
+            let header = VirtioNetHdr::for_write(&mut frame[VNET_HDR_SIZE..], self.mtu)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            frame[..VNET_HDR_SIZE].copy_from_slice(&header.to_bytes());
+            frame
+        } else {
+            packet.to_vec()
+        };
+        self.queues[queue]
+            .async_io(Interest::WRITABLE, |fd| sys::write(fd.as_raw_fd(), &frame))
+            .await
+            .inspect_err(|e| self.check_lost(e))?;
+        let stats = &self.stats[queue];
+        stats.packets_written.fetch_add(1, Ordering::Relaxed);
+        stats.bytes_written.fetch_add(packet.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Wait until a read or write found the device gone
+    pub async fn lost(&self) {
+        let _ = self.lost.subscribe().wait_for(|lost| *lost).await;
+    }
+
+    pub fn is_lost(&self) -> bool {
+        *self.lost.borrow()
+    }
+
+    /// Mark the device gone, waking everyone waiting in `lost`
+    pub(crate) fn mark_lost(&self) {
+        self.lost.send_replace(true);
+    }
+
+    fn check_lost(&self, error: &io::Error) {
+        if is_device_lost(error) && !self.is_lost() {
+            warn!("TUN interface {} is gone: {}", self.name, error);
+            self.mark_lost();
+        }
+    }
+
+    /// Counters of every queue, in queue order
+    pub fn snapshot(&self) -> Vec<QueueSnapshot> {
+        self.stats
+            .iter()
+            .enumerate()
+            .map(|(queue, stats)| QueueSnapshot {
+                queue,
+                packets_read: stats.packets_read.load(Ordering::Relaxed),
+                bytes_read: stats.bytes_read.load(Ordering::Relaxed),
+                packets_written: stats.packets_written.load(Ordering::Relaxed),
+                bytes_written: stats.bytes_written.load(Ordering::Relaxed),
+                dropped: stats.dropped.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Start one task per queue handing read packets to the router
+    pub fn spawn_readers(self: &Arc<Self>, router: Arc<PacketRouter>) -> Vec<JoinHandle<()>> {
+        (0..self.len())
+            .map(|queue| {
+                let queues = self.clone();
+                let router = router.clone();
+                tokio::spawn(async move { queues.run_reader(queue, &router).await })
+            })
+            .collect()
+    }
+
+    async fn run_reader(&self, queue: usize, router: &PacketRouter) {
+        let mut buf = vec![0u8; if self.offload { VNET_HDR_SIZE + GSO_MAX_SIZE } else { self.mtu }];
+        loop {
+            let n = match self.read(queue, &mut buf).await {
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("TUN queue {} of {} failed: {}", queue, self.name, e);
+                    // Nobody reads this queue anymore, so have the device re-created
+                    self.mark_lost();
+                    return;
+                }
+            };
+            let routed = if self.offload {
+                Self::route_offloaded(router, &mut buf[..n]).await
+            } else {
+                Self::route(router, &buf[..n]).await
+            };
+            if let Err(e) = routed {
+                debug!("Dropped {} bytes read from TUN queue {}: {}", n, queue, e);
+                self.stats[queue].dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    async fn route(router: &PacketRouter, packet: &[u8]) -> Result<()> {
+        match router.session_for_packet(packet) {
+            Some(session_id) => router.route_from_tun(packet, &session_id).await,
+            None => Err(LostLoveError::Network("No session for packet".to_string())),
+        }
+    }
+
+    /// Route a packet read behind a virtio-net header, coalesced ones segmented late
+    async fn route_offloaded(router: &PacketRouter, frame: &mut [u8]) -> Result<()> {
+        let header = VirtioNetHdr::parse(frame)?;
+        let packet = &mut frame[VNET_HDR_SIZE..];
+        header.complete_checksum(packet)?;
+        if !header.is_gso() {
+            return Self::route(router, packet).await;
+        }
+
+        match router.session_for_packet(packet) {
+            Some(session_id) => {
+                router
+                    .route_coalesced_from_tun(packet, header.gso_size as usize, &session_id)
+                    .await
+            }
+            None => Err(LostLoveError::Network("No session for packet".to_string())),
+        }
+    }
+}
+
+/// Errors a TUN descriptor returns once its interface was deleted
+pub fn is_device_lost(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EBADFD | libc::EIO | libc::ENODEV | libc::ENXIO)
+    )
+}
+
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result)
+    }
+
+    fn request(name: &str) -> io::Result<libc::ifreq> {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"));
+        }
+        // SAFETY: ifreq is plain data, all zeroes is a valid value
+        let mut request: libc::ifreq = unsafe { mem::zeroed() };
+        for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        Ok(request)
+    }
+
+    /// Attach one more queue to the TUN device, creating it with the first
+    pub fn open_queue(name: &str, offload: bool) -> io::Result<OwnedFd> {
+        let path = b"/dev/net/tun\0";
+        // SAFETY: path is NUL-terminated
+        let fd = check(unsafe {
+            libc::open(path.as_ptr() as *const libc::c_char, libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC)
+        })?;
+        // SAFETY: fd was just opened and is owned by nothing else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut request = request(name)?;
+        let mut flags = libc::IFF_TUN | libc::IFF_NO_PI | libc::IFF_MULTI_QUEUE;
+        if offload {
+            flags |= libc::IFF_VNET_HDR;
+        }
+        request.ifr_ifru.ifru_flags = flags as libc::c_short;
+        // SAFETY: request is a valid ifreq for TUNSETIFF
+        check(unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut request) })?;
+
+        if offload {
+            let features = libc::TUN_F_CSUM | libc::TUN_F_TSO4 | libc::TUN_F_TSO6;
+            // SAFETY: TUNSETOFFLOAD takes the feature bits by value
+            check(unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETOFFLOAD, features as libc::c_ulong) })?;
+        }
+        Ok(fd)
+    }
+
+    pub fn read(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: buf is valid for writes of its length
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    pub fn write(fd: RawFd, packet: &[u8]) -> io::Result<usize> {
+        // SAFETY: packet is valid for reads of its length
+        let n = unsafe { libc::write(fd, packet.as_ptr() as *const libc::c_void, packet.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    use crate::core::connection::ConnectionManager;
+    use crate::core::session::SessionEvent;
+    use crate::network::inner_packet::tests::ipv4_packet;
+    use crate::network::offload::tests::tcp_packet;
+    use crate::network::IpPool;
+
+    /// Queues over datagram socket pairs, which keep packet boundaries like TUN
+    fn queues(count: usize, offload: bool) -> (Arc<TunQueues>, Vec<UnixDatagram>) {
+        let (fds, peers): (Vec<OwnedFd>, Vec<UnixDatagram>) = (0..count)
+            .map(|_| {
+                let (queue, peer) = UnixDatagram::pair().unwrap();
+                queue.set_nonblocking(true).unwrap();
+                (OwnedFd::from(queue), peer)
+            })
+            .unzip();
+        let queues = TunQueues::from_fds("llp-test", 1400, fds).unwrap().with_offload(offload);
+        (Arc::new(queues), peers)
+    }
+
+    #[tokio::test]
+    async fn test_readers_feed_router() {
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let connection = manager.create_connection("127.0.0.1:8080".parse::<SocketAddr>().unwrap()).unwrap();
+        connection.session().apply(SessionEvent::HandshakeCompleted).await.unwrap();
+        let mut tunnel = connection.open_tunnel().unwrap();
+        let router = Arc::new(PacketRouter::new(manager.clone()));
+
+        let (queues, peers) = queues(4, false);
+        let readers = queues.spawn_readers(router);
+        assert_eq!(readers.len(), 4);
+
+        // Every queue gets packets for the client's lease, the last one also a stray one
+        let reply = ipv4_packet(6, [1, 1, 1, 1], [10, 8, 0, 2], (443, 40000));
+        let stray = ipv4_packet(6, [1, 1, 1, 1], [10, 8, 0, 99], (443, 40000));
+        for (queue, peer) in peers.iter().enumerate() {
+            for _ in 0..=queue {
+                peer.send(&reply).unwrap();
+            }
+        }
+        peers[3].send(&stray).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            for _ in 0..10 {
+                assert_eq!(tunnel.recv().await.unwrap(), reply);
+            }
+            while queues.snapshot()[3].dropped < 1 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let snapshot = queues.snapshot();
+        let read: Vec<u64> = snapshot.iter().map(|queue| queue.packets_read).collect();
+        assert_eq!(read, [1, 2, 3, 5]);
+        assert_eq!(snapshot[3].bytes_read, 5 * reply.len() as u64);
+        assert_eq!(snapshot.iter().map(|queue| queue.dropped).sum::<u64>(), 1);
+
+        for reader in readers {
+            reader.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_counts_and_mtu() {
+        let (queues, peers) = queues(2, false);
+        let packet = ipv4_packet(17, [10, 8, 0, 2], [1, 1, 1, 1], (40000, 53));
+
+        queues.write(1, &packet).await.unwrap();
+        let mut buf = [0u8; 1500];
+        assert_eq!(peers[1].recv(&mut buf).unwrap(), packet.len());
+
+        assert!(queues.write(0, &[0u8; 1401]).await.is_err());
+        let snapshot = queues.snapshot();
+        assert_eq!((snapshot[0].packets_written, snapshot[1].packets_written), (0, 1));
+        assert_eq!(snapshot[1].bytes_written, packet.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_offload_segments_late() {
+        let pool = Arc::new(IpPool::from_cidr("10.8.0.1/24").unwrap());
+        let manager = Arc::new(ConnectionManager::new(10).with_ip_pool(pool));
+        let connection = manager.create_connection("127.0.0.1:8080".parse::<SocketAddr>().unwrap()).unwrap();
+        connection.session().apply(SessionEvent::HandshakeCompleted).await.unwrap();
+        let mut tunnel = connection.open_tunnel().unwrap();
+        let router = Arc::new(PacketRouter::new(manager.clone()));
+
+        let (queues, peers) = queues(1, true);
+        let readers = queues.spawn_readers(router);
+
+        // The kernel hands over 4000 bytes of TCP with the checksum left partial
+        let mut packet = tcp_packet(4000, 0x10);
+        let header = VirtioNetHdr::for_write(&mut packet, 1400).unwrap();
+        peers[0].send(&[&header.to_bytes()[..], &packet].concat()).unwrap();
+
+        // The client never negotiated offload, so it gets three segments
+        tokio::time::timeout(Duration::from_secs(5), async {
+            for _ in 0..3 {
+                assert!(tunnel.recv().await.unwrap().len() <= 1400);
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(queues.snapshot()[0].dropped, 0);
+        for reader in readers {
+            reader.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offload_write() {
+        let (queues, peers) = queues(1, true);
+        let mut buf = vec![0u8; VNET_HDR_SIZE + GSO_MAX_SIZE];
+
+        queues.write(0, &tcp_packet(3000, 0x10)).await.unwrap();
+        assert_eq!(peers[0].recv(&mut buf).unwrap(), VNET_HDR_SIZE + 3040);
+        let header = VirtioNetHdr::parse(&buf).unwrap();
+        assert!(header.is_gso());
+        assert_eq!(header.gso_size, 1360);
+
+        let small = tcp_packet(100, 0x10);
+        queues.write(0, &small).await.unwrap();
+        assert_eq!(peers[0].recv(&mut buf).unwrap(), VNET_HDR_SIZE + small.len());
+        assert_eq!(VirtioNetHdr::parse(&buf).unwrap(), VirtioNetHdr::default());
+        assert_eq!(&buf[VNET_HDR_SIZE..VNET_HDR_SIZE + small.len()], &small[..]);
+    }
+
+    #[tokio::test]
+    async fn test_device_loss_detected() {
+        assert!(is_device_lost(&io::Error::from_raw_os_error(libc::EBADFD)));
+        assert!(is_device_lost(&io::Error::from_raw_os_error(libc::ENODEV)));
+        assert!(!is_device_lost(&io::Error::from_raw_os_error(libc::EINVAL)));
+
+        let (queues, _peers) = queues(2, false);
+        assert!(!queues.is_lost());
+        let waiter = tokio::spawn({
+            let queues = queues.clone();
+            async move { queues.lost().await }
+        });
+
+        queues.check_lost(&io::Error::from_raw_os_error(libc::EINVAL));
+        assert!(!queues.is_lost());
+        queues.check_lost(&io::Error::from_raw_os_error(libc::EBADFD));
+        tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        assert!(queues.is_lost());
+    }
+
+    #[test]
+    fn test_open_needs_valid_name() {
+        assert!(sys::open_queue("", false).is_err());
+        assert!(sys::open_queue("a-name-much-too-long", true).is_err());
+    }
+}
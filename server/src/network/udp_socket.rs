@@ -0,0 +1,334 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+
+/// Whether a send failed because the datagram is larger than the path MTU
+pub fn is_too_big(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use socket2::{Domain, SockAddr, Socket, Type};
+    use std::io;
+    use std::mem;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::os::fd::{AsRawFd, RawFd};
+    use tokio::io::Interest;
+    use tokio::net::UdpSocket;
+
+    /// Room for one IPv4 or IPv6 packet info control message
+    const CONTROL_LEN: usize = 64;
+
+    fn set_int(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        // SAFETY: the option value is a c_int that outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn get_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len describe a c_int the kernel writes into
+        let result = unsafe {
+            libc::getsockopt(fd, level, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value)
+    }
+
+    /// IPv6 sockets get the IPv4 option too, for IPv4-mapped peers
+    fn is_ipv6(socket: &UdpSocket) -> io::Result<bool> {
+        Ok(socket.local_addr()?.is_ipv6())
+    }
+
+    pub fn set_dont_fragment(socket: &UdpSocket, enabled: bool) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+        let (v4, v6) = if enabled {
+            (libc::IP_PMTUDISC_DO, libc::IPV6_PMTUDISC_DO)
+        } else {
+            (libc::IP_PMTUDISC_DONT, libc::IPV6_PMTUDISC_DONT)
+        };
+        if is_ipv6(socket)? {
+            set_int(fd, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, v6)?;
+        }
+        set_int(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, v4)
+    }
+
+    pub fn enable_pktinfo(socket: &UdpSocket) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+        if is_ipv6(socket)? {
+            set_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
+        }
+        set_int(fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)
+    }
+
+    pub fn path_mtu_to(peer: SocketAddr) -> io::Result<usize> {
+        let socket = Socket::new(Domain::for_address(peer), Type::DGRAM, None)?;
+        socket.connect(&peer.into())?;
+        let (level, name) = match peer {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        };
+        Ok(get_int(socket.as_raw_fd(), level, name)? as usize)
+    }
+
+    pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        socket
+            .async_io(Interest::READABLE, || recvmsg(socket.as_raw_fd(), buf))
+            .await
+    }
+
+    pub fn try_recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        socket.try_io(Interest::READABLE, || recvmsg(socket.as_raw_fd(), buf))
+    }
+
+    pub async fn send_to(socket: &UdpSocket, buf: &[u8], peer: SocketAddr, source: IpAddr) -> io::Result<usize> {
+        socket
+            .async_io(Interest::WRITABLE, || sendmsg(socket.as_raw_fd(), buf, peer, source))
+            .await
+    }
+
+    fn recvmsg(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        let mut control = [0u64; CONTROL_LEN / 8];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+
+        // SAFETY: msghdr points at the iovec, control buffer and address
+        // storage, all of which live until the kernel has filled them in
+        let ((received, msg), peer) = unsafe {
+            SockAddr::try_init(|storage, len| {
+                let mut msg: libc::msghdr = mem::zeroed();
+                msg.msg_name = storage.cast();
+                msg.msg_namelen = *len;
+                msg.msg_iov = &mut iov;
+                msg.msg_iovlen = 1;
+                msg.msg_control = control.as_mut_ptr().cast();
+                msg.msg_controllen = mem::size_of_val(&control);
+
+                let received = libc::recvmsg(fd, &mut msg, 0);
+                if received < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                *len = msg.msg_namelen;
+                Ok((received as usize, msg))
+            })?
+        };
+        let peer = peer
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram from a non-IP address"))?;
+
+        // SAFETY: msg's control buffer is still alive and was filled in above
+        let destination = unsafe { destination_address(&msg) };
+        Ok((received, peer, destination))
+    }
+
+    /// Address a datagram was sent to, from its packet info
+    unsafe fn destination_address(msg: &libc::msghdr) -> Option<IpAddr> {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            if header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_PKTINFO {
+                let info = (libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo).read_unaligned();
+                return Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr))));
+            }
+            if header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_PKTINFO {
+                let info = (libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo).read_unaligned();
+                return Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+        None
+    }
+
+    fn sendmsg(fd: RawFd, buf: &[u8], peer: SocketAddr, source: IpAddr) -> io::Result<usize> {
+        let peer = SockAddr::from(peer);
+        let mut control = [0u64; CONTROL_LEN / 8];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+
+        // SAFETY: msghdr points at the iovec, control buffer and peer
+        // address, which outlive the call; the control message written fits
+        // the buffer as CMSG_SPACE of either packet info is below CONTROL_LEN
+        let sent = unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_name = peer.as_ptr() as *mut libc::c_void;
+            msg.msg_namelen = peer.len();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+
+            // IPv4-mapped sources go out of an IPv6 socket as IPv6 info
+            let source = match (source, peer.is_ipv6()) {
+                (IpAddr::V4(v4), true) => IpAddr::V6(v4.to_ipv6_mapped()),
+                (source, _) => source,
+            };
+            match source {
+                IpAddr::V4(v4) => {
+                    let size = mem::size_of::<libc::in_pktinfo>() as libc::c_uint;
+                    msg.msg_controllen = libc::CMSG_SPACE(size) as usize;
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                    (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(size) as usize;
+                    let info = libc::in_pktinfo {
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: libc::in_addr { s_addr: u32::from(v4).to_be() },
+                        ipi_addr: libc::in_addr { s_addr: 0 },
+                    };
+                    (libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo).write_unaligned(info);
+                }
+                IpAddr::V6(v6) => {
+                    let size = mem::size_of::<libc::in6_pktinfo>() as libc::c_uint;
+                    msg.msg_controllen = libc::CMSG_SPACE(size) as usize;
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                    (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(size) as usize;
+                    let info = libc::in6_pktinfo {
+                        ipi6_addr: libc::in6_addr { s6_addr: v6.octets() },
+                        ipi6_ifindex: 0,
+                    };
+                    (libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo).write_unaligned(info);
+                }
+            }
+
+            libc::sendmsg(fd, &msg, 0)
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+/// Set or clear don't-fragment on outgoing datagrams
+///
+/// With it set the kernel does path MTU discovery and refuses datagrams
+/// larger than the path with EMSGSIZE, rather than fragmenting them.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &UdpSocket, enabled: bool) -> io::Result<()> {
+    sys::set_dont_fragment(socket, enabled)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_socket: &UdpSocket, _enabled: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "don't-fragment needs Linux"))
+}
+
+/// Have received datagrams report the local address they were sent to
+///
+/// A socket bound to the wildcard address on a host with several addresses
+/// must answer from the address the client used, or the client drops it.
+#[cfg(target_os = "linux")]
+pub fn enable_pktinfo(socket: &UdpSocket) -> io::Result<()> {
+    sys::enable_pktinfo(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_pktinfo(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "packet info needs Linux"))
+}
+
+/// Path MTU the kernel has cached for the route to `peer`
+#[cfg(target_os = "linux")]
+pub fn path_mtu_to(peer: SocketAddr) -> io::Result<usize> {
+    sys::path_mtu_to(peer)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn path_mtu_to(_peer: SocketAddr) -> io::Result<usize> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "path MTU lookup needs Linux"))
+}
+
+/// Receive a datagram with the local address it was sent to, when packet
+/// info is enabled
+#[cfg(target_os = "linux")]
+pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    sys::recv_from(socket, buf).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    let (received, peer) = socket.recv_from(buf).await?;
+    Ok((received, peer, None))
+}
+
+/// Receive a datagram already waiting, `WouldBlock` if there is none
+#[cfg(target_os = "linux")]
+pub fn try_recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    sys::try_recv_from(socket, buf)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn try_recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    let (received, peer) = socket.try_recv_from(buf)?;
+    Ok((received, peer, None))
+}
+
+/// Send a datagram from `source`, or from the address the kernel picks
+#[cfg(target_os = "linux")]
+pub async fn send_to(socket: &UdpSocket, buf: &[u8], peer: SocketAddr, source: Option<IpAddr>) -> io::Result<usize> {
+    match source {
+        Some(source) => sys::send_to(socket, buf, peer, source).await,
+        None => socket.send_to(buf, peer).await,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_to(socket: &UdpSocket, buf: &[u8], peer: SocketAddr, _source: Option<IpAddr>) -> io::Result<usize> {
+    socket.send_to(buf, peer).await
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pktinfo_round_trip() {
+        let server = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        enable_pktinfo(&server).unwrap();
+        set_dont_fragment(&server, true).unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", ("127.0.0.1", port)).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (received, peer, destination) = recv_from(&server, &mut buf).await.unwrap();
+        assert_eq!(&buf[..received], b"ping");
+        assert_eq!(peer, client.local_addr().unwrap());
+        assert_eq!(destination, Some("127.0.0.1".parse().unwrap()));
+
+        send_to(&server, b"pong", peer, destination).await.unwrap();
+        let (received, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..received], b"pong");
+        assert_eq!(from, ("127.0.0.1".parse::<IpAddr>().unwrap(), port).into());
+    }
+
+    #[test]
+    fn test_path_mtu() {
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        assert!(path_mtu_to(peer).unwrap() >= 1280);
+
+        assert!(is_too_big(&io::Error::from_raw_os_error(libc::EMSGSIZE)));
+        assert!(!is_too_big(&io::Error::from_raw_os_error(libc::ECONNREFUSED)));
+    }
+}
@@ -0,0 +1,646 @@
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use socket2::SockRef;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::core::connection::ConnectionManager;
+use crate::core::diagnose::PathDiagnostics;
+use crate::core::engine::{EngineEvent, ProtocolEngine};
+use crate::core::priority::{Priority, PriorityQueue};
+use crate::core::session::SessionId;
+use crate::crypto::ct;
+use crate::error::{LostLoveError, Result};
+use crate::config::UdpConfig;
+use crate::metrics::errors::ErrorCounters;
+use crate::network::data_path::DataPath;
+use crate::network::dscp;
+use crate::network::path_mtu::PathMtu;
+use crate::network::port_hopping::{unix_now, PortSchedule};
+use crate::network::udp_socket;
+use crate::protocol::address_discovery::Transport;
+use crate::protocol::registry::PacketRegistry;
+use crate::protocol::{Packet, PacketType, TransportAttach, HEADER_SIZE};
+
+/// Largest datagram read
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// Datagrams read before answering any, control ones first
+const RECEIVE_BATCH: usize = 64;
+
+/// Datagrams read but not answered yet, with sender and local address
+type ReceiveBatch = PriorityQueue<(Vec<u8>, SocketAddr, Option<IpAddr>)>;
+
+/// Socket and source address a peer attached through, for sends of our own
+#[derive(Debug, Clone)]
+struct PeerRoute {
+    socket: Arc<UdpSocket>,
+    source: Option<IpAddr>,
+}
+
+/// UDP path for sessions established over TCP
+///
+/// The handshake always runs over TCP, which stays open as the control
+/// channel and the fallback path. ServerHello carries a token; a client that
+/// can use UDP attaches with it and sends its data as datagrams instead.
+/// Datagrams are only accepted from the address that attached last, so a
+/// client changing networks re-attaches without a new handshake.
+///
+/// With a `PortSchedule` the listener hops across the schedule's ports
+/// instead of using the fixed port.
+pub struct UdpTransport {
+    bind_address: String,
+    port: u16,
+    schedule: Option<PortSchedule>,
+    connection_manager: Arc<ConnectionManager>,
+    tokens: DashMap<String, SessionId>,
+    bindings: DashMap<SocketAddr, SessionId>,
+    engines: DashMap<SocketAddr, ProtocolEngine>,
+    routes: DashMap<SocketAddr, PeerRoute>,
+    registry: Arc<PacketRegistry>,
+    diagnostics: Option<Arc<PathDiagnostics>>,
+    data_path: Option<Arc<DataPath>>,
+    dscp: Option<u8>,
+    dont_fragment: bool,
+    pktinfo: bool,
+    path_mtu: PathMtu,
+    max_packet_size: usize,
+    error_counters: Arc<ErrorCounters>,
+}
+
+impl UdpTransport {
+    /// Create transport listening on `bind_address:port`
+    pub fn new(
+        bind_address: impl Into<String>,
+        port: u16,
+        connection_manager: Arc<ConnectionManager>,
+    ) -> Self {
+        Self {
+            bind_address: bind_address.into(),
+            port,
+            schedule: None,
+            connection_manager,
+            tokens: DashMap::new(),
+            bindings: DashMap::new(),
+            engines: DashMap::new(),
+            routes: DashMap::new(),
+            registry: Arc::new(PacketRegistry::default()),
+            diagnostics: None,
+            data_path: None,
+            dscp: None,
+            dont_fragment: false,
+            pktinfo: false,
+            path_mtu: PathMtu::new(),
+            max_packet_size: MAX_DATAGRAM_SIZE,
+            error_counters: Arc::new(ErrorCounters::new()),
+        }
+    }
+
+    /// Hop across the ports of a schedule
+    pub fn with_port_schedule(mut self, schedule: PortSchedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Handle extension packet types with these handlers
+    pub fn with_registry(mut self, registry: Arc<PacketRegistry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Pass path probes echoed by clients to the diagnostics
+    pub fn with_diagnostics(mut self, diagnostics: Arc<PathDiagnostics>) -> Self {
+        self.diagnostics = Some(diagnostics);
+        self
+    }
+
+    /// Forward tunneled packets from clients through `data_path`
+    pub fn with_data_path(mut self, data_path: Arc<DataPath>) -> Self {
+        self.data_path = Some(data_path);
+        self
+    }
+
+    /// Mark outgoing datagrams with a DSCP
+    pub fn with_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.dscp = dscp;
+        self
+    }
+
+    /// Set don't-fragment and answer from the address each datagram came in on
+    pub fn with_socket_options(mut self, config: &UdpConfig) -> Self {
+        self.dont_fragment = config.dont_fragment;
+        self.pktinfo = config.pktinfo;
+        self
+    }
+
+    /// Drop datagrams over `max_packet_size` unread, counting them in
+    /// `error_counters`
+    pub fn with_max_packet_size(mut self, max_packet_size: usize, error_counters: Arc<ErrorCounters>) -> Self {
+        self.max_packet_size = max_packet_size;
+        self.error_counters = error_counters;
+        self
+    }
+
+    /// Create the attach token for a session
+    pub fn issue(&self, session_id: &SessionId) -> String {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        self.tokens.insert(token.clone(), *session_id);
+        token
+    }
+
+    /// Forget a closed session's token and UDP address
+    pub fn release(&self, session_id: &SessionId) {
+        self.tokens.retain(|_, bound| bound != session_id);
+        self.bindings.retain(|peer, bound| {
+            let keep = bound != session_id;
+            if !keep {
+                self.path_mtu.forget(peer);
+                self.engines.remove(peer);
+                self.routes.remove(peer);
+            }
+            keep
+        });
+    }
+
+    /// Session a token was issued to
+    ///
+    /// The map hashes with a random per-process key, so lookup timing says
+    /// nothing about how close a guess was; the stored token is still
+    /// compared in constant time before it's accepted.
+    fn lookup_token(&self, token: &str) -> Option<SessionId> {
+        let entry = self.tokens.get(token)?;
+        ct::eq(entry.key().as_bytes(), token.as_bytes()).then(|| *entry.value())
+    }
+
+    /// UDP address a session's datagrams are accepted from
+    pub fn bound_address(&self, session_id: &SessionId) -> Option<SocketAddr> {
+        self.bindings
+            .iter()
+            .find(|entry| entry.value() == session_id)
+            .map(|entry| *entry.key())
+    }
+
+    /// Drop the packet handling state of an idle session's UDP address,
+    /// rebuilt when its next datagram arrives
+    pub fn hibernate(&self, session_id: &SessionId) {
+        if let Some(peer) = self.bound_address(session_id) {
+            self.engines.remove(&peer);
+        }
+    }
+
+    /// Largest datagram that fits the path to a session's UDP address, once
+    /// a send to it was refused as too big
+    pub fn max_datagram(&self, session_id: &SessionId) -> Option<usize> {
+        self.path_mtu.max_datagram(&self.bound_address(session_id)?)
+    }
+
+    /// Send a datagram of our own to a session's UDP address
+    ///
+    /// Fails with the send's error, e.g. EMSGSIZE for a datagram larger than
+    /// the path, which also lowers the path MTU.
+    pub async fn send_to_session(&self, session_id: &SessionId, datagram: &[u8]) -> std::io::Result<()> {
+        let not_attached = || std::io::Error::new(std::io::ErrorKind::NotConnected, "no UDP path attached");
+        let peer = self.bound_address(session_id).ok_or_else(not_attached)?;
+        let route = self.routes.get(&peer).map(|route| route.clone()).ok_or_else(not_attached)?;
+
+        match udp_socket::send_to(&route.socket, datagram, peer, route.source).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if udp_socket::is_too_big(&e) {
+                    let reported = udp_socket::path_mtu_to(peer).ok();
+                    self.path_mtu.record_too_big(peer, datagram.len(), reported);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Serve datagrams until the task is cancelled
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let Some(schedule) = self.schedule.clone() else {
+            let address = format!("{}:{}", self.bind_address, self.port);
+            let socket = UdpSocket::bind(&address).await?;
+            info!("UDP transport listening on udp://{}", address);
+            return self.serve(socket).await;
+        };
+
+        info!("UDP transport hopping ports on {}", self.bind_address);
+        let mut listeners: HashMap<u16, JoinHandle<()>> = HashMap::new();
+        loop {
+            let now = unix_now();
+            let ports = schedule.listening_ports(now);
+
+            listeners.retain(|port, listener| {
+                let keep = ports.contains(port);
+                if !keep {
+                    debug!("UDP transport leaving port {}", port);
+                    listener.abort();
+                }
+                keep
+            });
+
+            for port in ports {
+                if listeners.contains_key(&port) {
+                    continue;
+                }
+                match UdpSocket::bind((self.bind_address.as_str(), port)).await {
+                    Ok(socket) => {
+                        debug!("UDP transport listening on port {}", port);
+                        let transport = self.clone();
+                        listeners.insert(
+                            port,
+                            tokio::spawn(async move {
+                                if let Err(e) = transport.serve(socket).await {
+                                    warn!("UDP transport on port {} failed: {}", port, e);
+                                }
+                            }),
+                        );
+                    }
+                    Err(e) => warn!("UDP transport can't listen on port {}: {}", port, e),
+                }
+            }
+
+            time::sleep(schedule.until_next_epoch(now)).await;
+        }
+    }
+
+    /// Answer datagrams arriving on one socket
+    pub(crate) async fn serve(&self, socket: UdpSocket) -> Result<()> {
+        if let Some(dscp) = self.dscp {
+            dscp::set_dscp(&SockRef::from(&socket), dscp)?;
+        }
+        if self.dont_fragment {
+            udp_socket::set_dont_fragment(&socket, true)?;
+        }
+        // Only a wildcard socket leaves the source address to the kernel
+        if self.pktinfo && socket.local_addr()?.ip().is_unspecified() {
+            udp_socket::enable_pktinfo(&socket)?;
+        }
+
+        let socket = Arc::new(socket);
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut batch = PriorityQueue::new();
+        loop {
+            let (n, peer, local) = udp_socket::recv_from(&socket, &mut buf).await?;
+            self.enqueue(&mut batch, &buf[..n], peer, local);
+
+            // Whatever else is waiting joins the batch, so keepalives and
+            // key updates are answered before data that arrived with them
+            while batch.len() < RECEIVE_BATCH {
+                match udp_socket::try_recv_from(&socket, &mut buf) {
+                    Ok((n, peer, local)) => self.enqueue(&mut batch, &buf[..n], peer, local),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            while let Some((datagram, peer, local)) = batch.pop() {
+                self.answer(&socket, &datagram, peer, local).await;
+            }
+        }
+    }
+
+    /// Queue a received datagram for answering, unless it is oversized
+    fn enqueue(&self, batch: &mut ReceiveBatch, datagram: &[u8], peer: SocketAddr, local: Option<IpAddr>) {
+        if datagram.len() > self.max_packet_size {
+            let e = LostLoveError::PacketTooLarge {
+                size: datagram.len(),
+                limit: self.max_packet_size,
+            };
+            self.error_counters.record(&e);
+            debug!("Dropped datagram from {}: {}", peer, e);
+            return;
+        }
+        batch.push(Priority::of(datagram), (datagram.to_vec(), peer, local));
+    }
+
+    /// Handle one received datagram and send its reply, if any
+    async fn answer(&self, socket: &Arc<UdpSocket>, datagram: &[u8], peer: SocketAddr, local: Option<IpAddr>) {
+        let Some(reply) = self.handle(datagram, peer).await else {
+            return;
+        };
+        // Our own sends to an attached peer leave the way it attached
+        if datagram[2] == PacketType::TransportAttach as u8 {
+            self.routes.insert(
+                peer,
+                PeerRoute {
+                    socket: socket.clone(),
+                    source: local,
+                },
+            );
+        }
+        self.send(socket, &reply, peer, local).await;
+    }
+
+    /// Send a datagram from `source`, lowering the peer's path MTU if it
+    /// was too big
+    async fn send(&self, socket: &UdpSocket, datagram: &[u8], peer: SocketAddr, source: Option<IpAddr>) {
+        match udp_socket::send_to(socket, datagram, peer, source).await {
+            Ok(_) => {}
+            Err(e) if udp_socket::is_too_big(&e) => {
+                let reported = udp_socket::path_mtu_to(peer).ok();
+                let mtu = self.path_mtu.record_too_big(peer, datagram.len(), reported);
+                debug!("Datagram of {} bytes too big for {}, path MTU now {}", datagram.len(), peer, mtu);
+            }
+            Err(e) => debug!("Failed to answer datagram from {}: {}", peer, e),
+        }
+    }
+
+    /// Process one datagram, returning the serialized reply to send back
+    ///
+    /// Datagrams from addresses no session attached from are dropped
+    /// without an answer.
+    pub async fn handle(&self, datagram: &[u8], peer: SocketAddr) -> Option<BytesMut> {
+        if datagram.get(2) == Some(&(PacketType::TransportAttach as u8)) {
+            let packet = Packet::deserialize(datagram).ok()?;
+            return self.attach(&packet, peer).await.map(|reply| reply.serialize());
+        }
+
+        let session_id = *self.bindings.get(&peer)?;
+        let connection = self.connection_manager.get_connection(&session_id)?;
+        let session = connection.session();
+
+        // Garbage from a bound address goes unanswered, as from any other
+        let events = self
+            .engines
+            .entry(peer)
+            .or_insert_with(|| {
+                ProtocolEngine::new(peer, Transport::Udp)
+                    .with_error_limit(0)
+                    .with_registry(self.registry.clone())
+            })
+            .handle(datagram);
+
+        let mut reply = None;
+        for event in events {
+            match event {
+                EngineEvent::Received { header, size } => {
+                    // A replayed datagram goes no further than this
+                    if let Err(e) = session.check_sequence(&header).await {
+                        self.error_counters.record(&e);
+                        debug!(code = e.code(), "Dropped datagram from {}: {}", peer, e);
+                        break;
+                    }
+                    session.record_packet_received(size).await;
+                    connection.update_activity().await;
+                }
+                EngineEvent::Data(packet) => {
+                    if session.set_transport(Transport::Udp).await {
+                        info!("Session {} switched to UDP", session_id);
+                    }
+                    if let (Some(data_path), false) = (&self.data_path, packet.payload.is_empty()) {
+                        if let Err(e) = data_path.forward(&session_id, &packet.payload).await {
+                            self.error_counters.record(&e);
+                            debug!(code = e.code(), "Dropped tunneled packet: {}", e);
+                        }
+                    }
+                }
+                EngineEvent::Transmit(packet) => match session.stamp(packet).await {
+                    Ok(packet) => {
+                        session.record_packet_sent(packet.size()).await;
+                        reply = Some(packet.serialize());
+                    }
+                    Err(e) => debug!("Not answering {}: {}", peer, e),
+                },
+                EngineEvent::Extension { received, reply: answer } => {
+                    session.record_packet_received(HEADER_SIZE + received.payload.len()).await;
+                    connection.update_activity().await;
+                    if let Some(answer) = answer {
+                        let answer = answer.serialize();
+                        session.record_packet_sent(answer.len()).await;
+                        reply = Some(answer);
+                    }
+                }
+                EngineEvent::Rejected(e) => debug!("Dropped datagram from {}: {}", peer, e),
+                EngineEvent::Control(packet) if packet.header.packet_type == PacketType::PathProbe => {
+                    if let Some(diagnostics) = &self.diagnostics {
+                        diagnostics.echo(&session_id, &packet);
+                    }
+                }
+                EngineEvent::Control(packet) => {
+                    debug!("Unhandled datagram type from {}: {:?}", peer, packet.header.packet_type);
+                }
+                EngineEvent::Closed => {
+                    // Sessions end over their TCP control channel, so no Ack either
+                    reply = None;
+                    debug!("Ignoring disconnect over UDP from {}", peer);
+                }
+            }
+        }
+        reply
+    }
+
+    /// Bind the sender's address to the session named by the token
+    async fn attach(&self, packet: &Packet, peer: SocketAddr) -> Option<Packet> {
+        let attach = TransportAttach::from_bytes(&packet.payload).ok()?;
+        let session_id = self.lookup_token(&attach.token)?;
+        let connection = self.connection_manager.get_connection(&session_id)?;
+
+        // Only the newest address of a session stays bound
+        self.bindings.retain(|bound_peer, bound| {
+            let keep = *bound != session_id;
+            if !keep {
+                self.engines.remove(bound_peer);
+                self.routes.remove(bound_peer);
+            }
+            keep
+        });
+        self.bindings.insert(peer, session_id);
+
+        connection.session().set_transport(Transport::Udp).await;
+        connection.update_activity().await;
+        info!("Session {} attached UDP path from {}", session_id, peer);
+
+        Some(Packet::new(PacketType::TransportAttach, Bytes::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(packet_type: PacketType, payload: Bytes) -> Vec<u8> {
+        Packet::new(packet_type, payload).serialize().to_vec()
+    }
+
+    fn attach_request(token: &str) -> Vec<u8> {
+        datagram(
+            PacketType::TransportAttach,
+            TransportAttach::new(token).to_bytes().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_attach_and_data() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let connection = manager.create_connection("198.51.100.4:50000".parse().unwrap()).unwrap();
+        let session_id = *connection.session().id();
+
+        let transport = UdpTransport::new("127.0.0.1", 0, manager);
+        let token = transport.issue(&session_id);
+        let peer: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+        let data = datagram(PacketType::Data, Bytes::from_static(b"payload"));
+
+        // Nothing is accepted before attaching, nor with a wrong token
+        assert!(transport.handle(&data, peer).await.is_none());
+        assert!(transport.handle(&attach_request("bogus"), peer).await.is_none());
+
+        let ack = transport.handle(&attach_request(&token), peer).await.unwrap();
+        let ack = Packet::deserialize(&ack[..]).unwrap();
+        assert_eq!(ack.header.packet_type, PacketType::TransportAttach);
+        assert_eq!(connection.session().transport().await, Transport::Udp);
+
+        // Tunnel data over UDP is datagrams, taken without an Ack
+        assert!(transport.handle(&data, peer).await.is_none());
+        assert_eq!(connection.session().stats().await.packets_received, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reattach_moves_binding() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let connection = manager.create_connection("198.51.100.4:50000".parse().unwrap()).unwrap();
+        let session_id = *connection.session().id();
+
+        let transport = UdpTransport::new("127.0.0.1", 0, manager);
+        let token = transport.issue(&session_id);
+        let first: SocketAddr = "198.51.100.4:40000".parse().unwrap();
+        let second: SocketAddr = "203.0.113.9:41000".parse().unwrap();
+
+        transport.handle(&attach_request(&token), first).await.unwrap();
+        transport.handle(&attach_request(&token), second).await.unwrap();
+        assert_eq!(transport.bound_address(&session_id), Some(second));
+
+        let keepalive = datagram(PacketType::KeepAlive, Bytes::new());
+        assert!(transport.handle(&keepalive, first).await.is_none());
+        assert!(transport.handle(&keepalive, second).await.is_some());
+
+        transport.release(&session_id);
+        assert!(transport.handle(&keepalive, second).await.is_none());
+        assert!(transport.handle(&attach_request(&token), second).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_answers_from_destination_address() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let connection = manager.create_connection("127.0.0.1:50000".parse().unwrap()).unwrap();
+        let session_id = *connection.session().id();
+
+        let transport = Arc::new(
+            UdpTransport::new("0.0.0.0", 0, manager).with_socket_options(&UdpConfig::default()),
+        );
+        let token = transport.issue(&session_id);
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let server = transport.clone();
+        let serving = tokio::spawn(async move { server.serve(socket).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&attach_request(&token), ("127.0.0.1", port)).await.unwrap();
+
+        let mut buf = [0u8; 256];
+        let (n, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, SocketAddr::from(([127, 0, 0, 1], port)));
+        let reply = Packet::deserialize(&buf[..n]).unwrap();
+        assert_eq!(reply.header.packet_type, PacketType::TransportAttach);
+        assert_eq!(transport.max_datagram(&session_id), None);
+
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn test_control_answered_before_data() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let connection = manager.create_connection("127.0.0.1:50000".parse().unwrap()).unwrap();
+        let session_id = *connection.session().id();
+
+        let transport = Arc::new(UdpTransport::new("127.0.0.1", 0, manager));
+        let token = transport.issue(&session_id);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_address = client.local_addr().unwrap();
+        transport.handle(&attach_request(&token), client_address).await.unwrap();
+
+        // The burst is waiting in the socket before the server reads any of it
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = socket.local_addr().unwrap();
+        for sequence_number in 0..8 {
+            let data = Packet::new_with_metadata(PacketType::Data, 1, sequence_number, Bytes::from_static(b"bulk"));
+            client.send_to(&data.serialize(), server_address).await.unwrap();
+        }
+        let keepalive = Packet::new_with_metadata(PacketType::KeepAlive, 0, 8, Bytes::new());
+        client.send_to(&keepalive.serialize(), server_address).await.unwrap();
+
+        let server = transport.clone();
+        let serving = tokio::spawn(async move { server.serve(socket).await });
+
+        let mut buf = [0u8; 256];
+        let (n, _) = client.recv_from(&mut buf).await.unwrap();
+        let first = Packet::deserialize(&buf[..n]).unwrap();
+        assert_eq!(first.header.packet_type, PacketType::KeepAlive);
+        // The data is taken without an answer
+        let more = time::timeout(std::time::Duration::from_millis(100), client.recv_from(&mut buf)).await;
+        assert!(more.is_err());
+        assert_eq!(connection.session().stats().await.packets_received, 9);
+
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn test_replayed_datagram_dropped() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let connection = manager.create_connection("127.0.0.1:50000".parse().unwrap()).unwrap();
+        let counters = Arc::new(ErrorCounters::new());
+        let transport = UdpTransport::new("127.0.0.1", 0, manager).with_max_packet_size(MAX_DATAGRAM_SIZE, counters.clone());
+        let token = transport.issue(connection.session().id());
+        let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        transport.handle(&attach_request(&token), peer).await.unwrap();
+
+        let keepalive = Packet::new_with_metadata(PacketType::KeepAlive, 0, 5, Bytes::new()).serialize();
+        let reply = transport.handle(&keepalive, peer).await.unwrap();
+        assert!(transport.handle(&keepalive, peer).await.is_none());
+        assert_eq!(counters.snapshot().get("invalid_sequence"), Some(&1));
+
+        // Answers are numbered in the server's own space
+        let answer = Packet::deserialize(reply).unwrap();
+        assert_eq!(answer.header.packet_type, PacketType::KeepAlive);
+        assert_eq!(connection.session().next_sequence().await.unwrap(), answer.header.sequence_number + 1);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_datagram_dropped() {
+        let manager = Arc::new(ConnectionManager::new(10));
+        let connection = manager.create_connection("127.0.0.1:50000".parse().unwrap()).unwrap();
+        let session_id = *connection.session().id();
+
+        let counters = Arc::new(ErrorCounters::new());
+        let transport = Arc::new(UdpTransport::new("127.0.0.1", 0, manager).with_max_packet_size(100, counters.clone()));
+        let token = transport.issue(&session_id);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        transport.handle(&attach_request(&token), client.local_addr().unwrap()).await.unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = socket.local_addr().unwrap();
+        let oversized = datagram(PacketType::Data, Bytes::from(vec![0u8; 200]));
+        client.send_to(&oversized, server_address).await.unwrap();
+        client.send_to(&datagram(PacketType::KeepAlive, Bytes::new()), server_address).await.unwrap();
+
+        let server = transport.clone();
+        let serving = tokio::spawn(async move { server.serve(socket).await });
+
+        // Only the keepalive is answered, the data never reaches the engine
+        let mut buf = [0u8; 256];
+        let (n, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(Packet::deserialize(&buf[..n]).unwrap().header.packet_type, PacketType::KeepAlive);
+        assert_eq!(counters.snapshot().get("packet_too_large"), Some(&1));
+        assert_eq!(connection.session().stats().await.packets_received, 1);
+
+        serving.abort();
+    }
+}
@@ -0,0 +1,60 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+use crate::error::{LostLoveError, Result};
+
+/// Transport the reported address was observed on, also the one a session's data uses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Udp,
+    /// Bridged from the DNS transport to the TCP listener
+    Dns,
+    /// Bridged from the ICMP echo transport to the TCP listener
+    Icmp,
+}
+
+/// Payload of a server's `PacketType::AddressDiscovery` answer
+///
+/// Clients send the packet with an empty (TCP) or padded (UDP) payload and
+/// get back their address as the server sees it, i.e. after any NAT.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressReport {
+    /// Reflexive (public) address and port of the client
+    pub address: SocketAddr,
+    pub transport: Transport,
+}
+
+impl AddressReport {
+    pub fn new(address: SocketAddr, transport: Transport) -> Self {
+        Self { address, transport }
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Network(format!("Invalid address report: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_report_roundtrip() {
+        let report = AddressReport::new("203.0.113.7:40000".parse().unwrap(), Transport::Udp);
+        let bytes = report.to_bytes().unwrap();
+
+        assert_eq!(AddressReport::from_bytes(&bytes).unwrap(), report);
+        assert!(String::from_utf8_lossy(&bytes).contains(r#""transport":"udp""#));
+    }
+}
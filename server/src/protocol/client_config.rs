@@ -0,0 +1,83 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::error::{LostLoveError, Result};
+
+/// Network settings pushed to a client in `PacketType::ConfigUpdate`
+///
+/// Sent right after the handshake, whenever the server's pushed settings
+/// change, and again every `renew_after` seconds. Clients apply the newest
+/// `serial` and ignore older ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientConfig {
+    /// Increases with every change of the pushed settings
+    pub serial: u64,
+    #[serde(default)]
+    pub tunnel_address: Option<IpAddr>,
+    pub mtu: usize,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// Destinations (CIDR) to route through the tunnel
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Seconds until the next renewal
+    pub renew_after: u64,
+    /// Seconds between the client's QualityReports, 0 for none
+    #[serde(default)]
+    pub quality_report_interval: u64,
+    /// URLs to fetch outside the tunnel to detect captive portals, none to not probe
+    #[serde(default)]
+    pub portal_probes: Vec<String>,
+    /// Longest the client may bypass the tunnel to sign in to a portal, seconds
+    #[serde(default)]
+    pub portal_bypass: u64,
+    /// Route the prefixes of the client's LAN outside the tunnel
+    #[serde(default)]
+    pub lan_bypass: bool,
+    /// Destinations (CIDR) to route outside the tunnel
+    #[serde(default)]
+    pub exclude_routes: Vec<String>,
+}
+
+impl ClientConfig {
+    /// Serialize config
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Config(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    /// Deserialize config
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Config(format!("Invalid client config: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_config_roundtrip() {
+        let config = ClientConfig {
+            serial: 3,
+            tunnel_address: Some("10.8.0.2".parse().unwrap()),
+            mtu: 1400,
+            dns: vec!["1.1.1.1".parse().unwrap()],
+            routes: vec!["0.0.0.0/0".to_string()],
+            renew_after: 3600,
+            quality_report_interval: 60,
+            portal_probes: vec!["http://cp.cloudflare.com/generate_204".to_string()],
+            portal_bypass: 300,
+            lan_bypass: true,
+            exclude_routes: vec!["192.168.50.0/24".to_string()],
+        };
+
+        let decoded = ClientConfig::from_bytes(&config.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, config);
+
+        assert!(ClientConfig::from_bytes(b"{}").is_err());
+    }
+}
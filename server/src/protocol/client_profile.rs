@@ -0,0 +1,247 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::IpAddr;
+
+/// Prefix of the compact encoding, bumped if the format ever changes
+pub const COMPACT_PREFIX: &str = "LLP1:";
+
+/// Characters of base45 (RFC 9285), all in the QR code alphanumeric mode
+const BASE45_ALPHABET: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+/// A profile that can't be encoded or decoded
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct ProfileError(pub(crate) String);
+
+/// Everything a client needs to connect as one user, like `wg genconf`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientProfile {
+    /// User the client connects as
+    pub user: String,
+    /// Endpoints as `transport://host:port`, preferred first
+    pub endpoints: Vec<String>,
+    pub mtu: usize,
+    /// Destinations (CIDR) to route through the tunnel
+    #[serde(default)]
+    pub routes: Vec<String>,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// Handshake obfuscation key in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_key: Option<String>,
+    /// Where the signed server descriptor is served
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub descriptor_url: Option<String>,
+    /// Public Ed25519 key the descriptor is verified with, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub descriptor_key: Option<String>,
+    /// Key management commands are verified with, in hex, derived for this
+    /// profile's user; clients without it don't accept any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub management_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub knock: Option<KnockProfile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_hopping: Option<HoppingProfile>,
+    /// Kill switch the client keeps while the tunnel should be up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lockdown: Option<LockdownProfile>,
+    /// The user's overrides of the pushed LAN bypass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lan: Option<LanProfile>,
+    /// Tunnel only some applications instead of the whole host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split: Option<SplitProfile>,
+}
+
+/// Port knock to send before connecting
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnockProfile {
+    pub port: u16,
+    pub secret: String,
+}
+
+/// Schedule the UDP port hops on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HoppingProfile {
+    pub secret: String,
+    pub port_min: u16,
+    pub port_max: u16,
+    /// Seconds per hop
+    pub interval: u64,
+}
+
+/// Block everything but the tunnel, see `protocol::lockdown`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockdownProfile {
+    /// Still let the local network through, e.g. printers
+    #[serde(default)]
+    pub allow_lan: bool,
+}
+
+/// Networks the user keeps off or on the tunnel, over what the server pushes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LanProfile {
+    /// Whether to bypass the detected LAN, the server's choice if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bypass: Option<bool>,
+    /// More destinations (CIDR) to route outside the tunnel
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Destinations (CIDR) kept in the tunnel even if on the LAN
+    #[serde(default)]
+    pub tunnel: Vec<String>,
+}
+
+/// Applications routed through the tunnel, see `protocol::split_tunnel`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitProfile {
+    /// Absolute paths of executables whose processes use the tunnel
+    #[serde(default)]
+    pub apps: Vec<String>,
+    /// cgroup v2 paths, relative to the cgroup root, whose processes use
+    /// the tunnel
+    #[serde(default)]
+    pub cgroups: Vec<String>,
+    /// Mark of the applications' packets, and their routing table
+    #[serde(default = "default_split_fwmark")]
+    pub fwmark: u32,
+    #[serde(default = "default_split_fwmark")]
+    pub table: u32,
+}
+
+fn default_split_fwmark() -> u32 {
+    0x4c4c
+}
+
+impl ClientProfile {
+    /// Client config file
+    pub fn to_toml(&self) -> Result<String, ProfileError> {
+        toml::to_string_pretty(self).map_err(|e| ProfileError(format!("Serialization error: {}", e)))
+    }
+
+    /// `LLP1:` and the deflated JSON in base45, small enough for a QR code
+    /// in alphanumeric mode
+    pub fn to_compact(&self) -> Result<String, ProfileError> {
+        let json = serde_json::to_vec(self).map_err(|e| ProfileError(format!("Serialization error: {}", e)))?;
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        let deflated = encoder
+            .write_all(&json)
+            .and_then(|_| encoder.finish())
+            .map_err(|e| ProfileError(format!("Compression error: {}", e)))?;
+        Ok(format!("{}{}", COMPACT_PREFIX, base45_encode(&deflated)))
+    }
+
+    /// Read the compact encoding back, e.g. from a scanned QR code
+    pub fn from_compact(text: &str) -> Result<Self, ProfileError> {
+        let encoded = text
+            .trim()
+            .strip_prefix(COMPACT_PREFIX)
+            .ok_or_else(|| ProfileError(format!("Compact profile must start with {}", COMPACT_PREFIX)))?;
+        let deflated = base45_decode(encoded)?;
+        let mut json = Vec::new();
+        DeflateDecoder::new(&deflated[..])
+            .read_to_end(&mut json)
+            .map_err(|e| ProfileError(format!("Invalid compact profile: {}", e)))?;
+        serde_json::from_slice(&json).map_err(|e| ProfileError(format!("Invalid compact profile: {}", e)))
+    }
+}
+
+/// Base45 of RFC 9285: two bytes become three characters, a last odd byte two
+fn base45_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len() / 2 * 3 + 2);
+    for chunk in data.chunks(2) {
+        let (mut n, digits) = match chunk {
+            [a, b] => ((*a as usize) << 8 | *b as usize, 3),
+            [a] => (*a as usize, 2),
+            _ => unreachable!("chunks of at most two"),
+        };
+        for _ in 0..digits {
+            encoded.push(BASE45_ALPHABET[n % 45] as char);
+            n /= 45;
+        }
+    }
+    encoded
+}
+
+fn base45_decode(text: &str) -> Result<Vec<u8>, ProfileError> {
+    let digits = text
+        .bytes()
+        .map(|c| BASE45_ALPHABET.iter().position(|a| *a == c))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| ProfileError("Invalid base45 character".to_string()))?;
+
+    let mut data = Vec::with_capacity(digits.len() / 3 * 2 + 1);
+    for chunk in digits.chunks(3) {
+        let n = chunk.iter().rev().fold(0, |n, digit| n * 45 + digit);
+        match chunk.len() {
+            3 if n <= 0xFFFF => data.extend_from_slice(&[(n >> 8) as u8, n as u8]),
+            2 if n <= 0xFF => data.push(n as u8),
+            _ => return Err(ProfileError("Invalid base45 length or value".to_string())),
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> ClientProfile {
+        ClientProfile {
+            user: "alice".to_string(),
+            endpoints: vec!["tcp://vpn.example.org:8443".to_string(), "udp://vpn.example.org:8443".to_string()],
+            mtu: 1400,
+            routes: vec!["0.0.0.0/0".to_string()],
+            dns: vec!["1.1.1.1".parse().unwrap()],
+            server_key: Some("00".repeat(32)),
+            descriptor_url: None,
+            descriptor_key: None,
+            management_key: None,
+            knock: Some(KnockProfile {
+                port: 62201,
+                secret: "knock secret".to_string(),
+            }),
+            port_hopping: None,
+            lockdown: Some(LockdownProfile { allow_lan: true }),
+            lan: Some(LanProfile {
+                bypass: Some(true),
+                exclude: vec!["192.168.50.0/24".to_string()],
+                tunnel: Vec::new(),
+            }),
+            split: None,
+        }
+    }
+
+    #[test]
+    fn test_base45_vectors() {
+        // Examples of RFC 9285 section 4.3
+        assert_eq!(base45_encode(b"AB"), "BB8");
+        assert_eq!(base45_encode(b"Hello!!"), "%69 VD92EX0");
+        assert_eq!(base45_encode(b"base-45"), "UJCLQE7W581");
+        assert_eq!(base45_decode("QED8WEX0").unwrap(), b"ietf!");
+        assert!(base45_decode("GGW").is_err());
+        assert!(base45_decode("abc").is_err());
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let compact = profile().to_compact().unwrap();
+        assert!(compact.starts_with(COMPACT_PREFIX));
+        assert!(compact.bytes().all(|c| BASE45_ALPHABET.contains(&c)));
+        assert_eq!(ClientProfile::from_compact(&compact).unwrap(), profile());
+
+        assert!(ClientProfile::from_compact("LLP2:AB").is_err());
+    }
+
+    #[test]
+    fn test_toml_roundtrip() {
+        let text = profile().to_toml().unwrap();
+        assert!(text.contains("user = \"alice\""));
+        assert!(!text.contains("descriptor_url"));
+        assert_eq!(toml::from_str::<ClientProfile>(&text).unwrap(), profile());
+    }
+}
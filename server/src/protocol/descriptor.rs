@@ -0,0 +1,199 @@
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::error::{LostLoveError, Result};
+
+/// Format version of the descriptor
+pub const DESCRIPTOR_VERSION: u8 = 1;
+
+/// How a client reaches an endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndpointTransport {
+    Tcp,
+    Udp,
+    /// Tunnel through queries for names under `host`
+    Dns,
+    Icmp,
+}
+
+impl EndpointTransport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EndpointTransport::Tcp => "tcp",
+            EndpointTransport::Udp => "udp",
+            EndpointTransport::Dns => "dns",
+            EndpointTransport::Icmp => "icmp",
+        }
+    }
+}
+
+/// One address a client can connect to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    pub transport: EndpointTransport,
+}
+
+impl fmt::Display for Endpoint {
+    /// `transport://host:port`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}:{}", self.transport.as_str(), self.host, self.port)
+    }
+}
+
+/// What a client needs to bootstrap its configuration from one URL
+///
+/// Clients keep the last descriptor and reconnect elsewhere when a fetched
+/// one `differs_from` it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerDescriptor {
+    pub version: u8,
+    /// Unix time the descriptor was issued and stops being valid
+    pub issued_at: u64,
+    pub expires_at: u64,
+    /// In the order clients should prefer them
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl ServerDescriptor {
+    /// Whether endpoints changed, ignoring when it was issued
+    pub fn differs_from(&self, other: &ServerDescriptor) -> bool {
+        self.endpoints != other.endpoints
+    }
+}
+
+/// Descriptor as served: its JSON and an Ed25519 signature of exactly those
+/// bytes
+///
+/// The payload stays a string so the signature covers the bytes as sent,
+/// not a re-serialization that might order or escape fields differently.
+/// Clients only hold the public key, so none of them can sign a descriptor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedDescriptor {
+    pub payload: String,
+    /// Hex Ed25519 signature of `payload`
+    pub signature: String,
+}
+
+impl SignedDescriptor {
+    /// Sign a descriptor with the private key `seed` (32 bytes)
+    pub fn sign(descriptor: &ServerDescriptor, seed: &[u8]) -> Result<Self> {
+        let payload = serde_json::to_string(descriptor)
+            .map_err(|e| LostLoveError::Config(format!("Serialization error: {}", e)))?;
+        let signature = hex::encode(key_pair(seed)?.sign(payload.as_bytes()));
+        Ok(Self { payload, signature })
+    }
+
+    /// Check the signature against `public_key` and the expiry at unix time
+    /// `now`, returning the descriptor
+    pub fn verify(&self, public_key: &[u8], now: u64) -> Result<ServerDescriptor> {
+        let signature = hex::decode(&self.signature).map_err(|_| LostLoveError::AuthFailed {
+            reason: "descriptor signature is not hex".to_string(),
+        })?;
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(self.payload.as_bytes(), &signature)
+            .map_err(|_| LostLoveError::AuthFailed {
+                reason: "descriptor signature mismatch".to_string(),
+            })?;
+
+        let descriptor: ServerDescriptor = serde_json::from_str(&self.payload)
+            .map_err(|e| LostLoveError::Config(format!("Invalid server descriptor: {}", e)))?;
+        if descriptor.version != DESCRIPTOR_VERSION {
+            return Err(LostLoveError::Config(format!(
+                "Unsupported server descriptor version {}",
+                descriptor.version
+            )));
+        }
+        if now >= descriptor.expires_at {
+            return Err(LostLoveError::AuthFailed {
+                reason: "descriptor expired".to_string(),
+            });
+        }
+        Ok(descriptor)
+    }
+
+    /// Serialize the document
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self)
+            .map_err(|e| LostLoveError::Config(format!("Serialization error: {}", e)))
+    }
+
+    /// Parse a fetched document
+    pub fn from_json(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Config(format!("Invalid signed descriptor: {}", e)))
+    }
+}
+
+/// Public key clients verify descriptors signed with `seed` against
+pub fn public_key(seed: &[u8]) -> Result<Vec<u8>> {
+    Ok(key_pair(seed)?.public_key().as_ref().to_vec())
+}
+
+fn key_pair(seed: &[u8]) -> Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_seed_unchecked(seed)
+        .map_err(|e| LostLoveError::Config(format!("Invalid descriptor key: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: [u8; 32] = [7; 32];
+
+    fn descriptor() -> ServerDescriptor {
+        ServerDescriptor {
+            version: DESCRIPTOR_VERSION,
+            issued_at: 1_000,
+            expires_at: 2_000,
+            endpoints: vec![
+                Endpoint {
+                    host: "vpn.example.org".to_string(),
+                    port: 8443,
+                    transport: EndpointTransport::Tcp,
+                },
+                Endpoint {
+                    host: "vpn.example.org".to_string(),
+                    port: 8443,
+                    transport: EndpointTransport::Udp,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = public_key(&SEED).unwrap();
+        let signed = SignedDescriptor::sign(&descriptor(), &SEED).unwrap();
+        let fetched = SignedDescriptor::from_json(signed.to_json().unwrap().as_bytes()).unwrap();
+
+        assert_eq!(fetched.verify(&key, 1_500).unwrap(), descriptor());
+        assert!(fetched.verify(&public_key(&[8; 32]).unwrap(), 1_500).is_err());
+        // The private key is not the one clients verify with
+        assert!(fetched.verify(&SEED, 1_500).is_err());
+        assert!(fetched.verify(&key, 2_000).is_err());
+    }
+
+    #[test]
+    fn test_tampered_payload() {
+        let mut signed = SignedDescriptor::sign(&descriptor(), &SEED).unwrap();
+        signed.payload = signed.payload.replace("8443", "9443");
+        let key = public_key(&SEED).unwrap();
+        assert!(matches!(signed.verify(&key, 1_500), Err(LostLoveError::AuthFailed { .. })));
+    }
+
+    #[test]
+    fn test_endpoint_changes() {
+        let mut reissued = descriptor();
+        reissued.issued_at = 1_900;
+        reissued.expires_at = 2_900;
+        assert!(!reissued.differs_from(&descriptor()));
+
+        reissued.endpoints.pop();
+        assert!(reissued.differs_from(&descriptor()));
+        assert_eq!(reissued.endpoints[0].to_string(), "tcp://vpn.example.org:8443");
+    }
+}
@@ -0,0 +1,120 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LostLoveError, Result};
+
+/// Why the server ends a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisconnectReason {
+    /// Regular close (also assumed for an empty Disconnect payload)
+    Normal,
+    /// User's allowed hours are over
+    OutsideAllowedHours,
+    /// User used up the daily data quota
+    DailyQuotaExceeded,
+    /// User used up the monthly data quota
+    MonthlyQuotaExceeded,
+    /// Server finished draining for an upgrade
+    ServerDraining,
+    /// Operator's connect hook turned the session down
+    Refused,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::Normal => "normal",
+            DisconnectReason::OutsideAllowedHours => "outside-allowed-hours",
+            DisconnectReason::DailyQuotaExceeded => "daily-quota-exceeded",
+            DisconnectReason::MonthlyQuotaExceeded => "monthly-quota-exceeded",
+            DisconnectReason::ServerDraining => "server-draining",
+            DisconnectReason::Refused => "refused",
+        }
+    }
+}
+
+/// Payload of a `PacketType::Disconnect` packet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisconnectMessage {
+    pub reason: DisconnectReason,
+}
+
+impl DisconnectMessage {
+    pub fn new(reason: DisconnectReason) -> Self {
+        Self { reason }
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Connection(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.is_empty() {
+            return Ok(Self::new(DisconnectReason::Normal));
+        }
+
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Connection(format!("Invalid disconnect message: {}", e)))
+    }
+}
+
+/// Payload of a `PacketType::Warning` packet, sent once before a policy cutoff
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CutoffWarning {
+    /// Reason the session will be disconnected with
+    pub reason: DisconnectReason,
+    /// Seconds until the allowed hours end
+    #[serde(default)]
+    pub seconds_left: Option<u64>,
+    /// Bytes left in the quota
+    #[serde(default)]
+    pub bytes_left: Option<u64>,
+}
+
+impl CutoffWarning {
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Connection(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Connection(format!("Invalid cutoff warning: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disconnect_message() {
+        let message = DisconnectMessage::new(DisconnectReason::DailyQuotaExceeded);
+        let bytes = message.to_bytes().unwrap();
+
+        assert_eq!(&bytes[..], br#"{"reason":"daily-quota-exceeded"}"#);
+        assert_eq!(DisconnectMessage::from_bytes(&bytes).unwrap(), message);
+
+        // Legacy empty payload
+        assert_eq!(
+            DisconnectMessage::from_bytes(&[]).unwrap().reason,
+            DisconnectReason::Normal
+        );
+    }
+
+    #[test]
+    fn test_cutoff_warning() {
+        let warning = CutoffWarning {
+            reason: DisconnectReason::OutsideAllowedHours,
+            seconds_left: Some(300),
+            bytes_left: None,
+        };
+
+        let decoded = CutoffWarning::from_bytes(&warning.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, warning);
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt::Write;
+
+use super::wire::{header_layout, FieldKind, PacketType, FLAGS, HEADER_SIZE, LENGTH_OFFSET, PROTOCOL_ID};
+
+/// Wireshark Lua dissector for the packet header
+///
+/// Generated from the wire definitions rather than written by hand, so a
+/// changed header layout shows up in the dissector too. Packets end where
+/// their length field says; TCP reassembles those spanning segments.
+pub fn lua_dissector(port: u16) -> String {
+    let mut lua = String::new();
+
+    lua.push_str(
+        r#"-- LostLove Protocol (LLP) dissector, generated by `llpctl dissector`
+-- Regenerate it whenever the packet header changes.
+
+local llp = Proto("llp", "LostLove Protocol")
+
+"#,
+    );
+    let _ = writeln!(lua, "local HEADER_SIZE = {}", HEADER_SIZE);
+    let _ = writeln!(lua, "local LENGTH_OFFSET = {}", LENGTH_OFFSET);
+    let _ = writeln!(lua, "local PROTOCOL_ID = 0x{:04X}\n", PROTOCOL_ID);
+
+    lua.push_str("local packet_types = {\n");
+    for packet_type in PacketType::ALL {
+        let _ = writeln!(lua, "    [0x{:02X}] = \"{:?}\",", packet_type as u8, packet_type);
+    }
+    lua.push_str("}\n\nlocal f = llp.fields\n");
+
+    for (_, field) in header_layout() {
+        let bits = field.size * 8;
+        let (base, values) = match field.kind {
+            FieldKind::Decimal => ("base.DEC", ""),
+            FieldKind::Hex | FieldKind::Flags => ("base.HEX", ""),
+            FieldKind::PacketType => ("base.HEX", ", packet_types"),
+        };
+        let _ = writeln!(
+            lua,
+            "f.{0} = ProtoField.uint{1}(\"llp.{0}\", \"{2}\", {3}{4})",
+            field.name, bits, field.label, base, values
+        );
+
+        if field.kind == FieldKind::Flags {
+            for (bit, flag) in FLAGS {
+                let _ = writeln!(
+                    lua,
+                    "f.{0}_{1} = ProtoField.bool(\"llp.{0}.{1}\", \"{2}\", {3}, nil, 0x{4:02X})",
+                    field.name,
+                    flag.to_lowercase(),
+                    flag,
+                    bits,
+                    bit
+                );
+            }
+        }
+    }
+
+    lua.push_str(
+        r#"f.payload = ProtoField.bytes("llp.payload", "Payload")
+
+local function dissect(tvb, pinfo, tree)
+    if tvb:len() < HEADER_SIZE or tvb(0, 2):uint() ~= PROTOCOL_ID then
+        return 0
+    end
+
+    local size = HEADER_SIZE + tvb(LENGTH_OFFSET, 2):uint()
+    if tvb:len() < size then
+        pinfo.desegment_len = size - tvb:len()
+        return tvb:len()
+    end
+
+    pinfo.cols.protocol = "LLP"
+    local header = tree:add(llp, tvb(0, HEADER_SIZE), "LostLove Protocol")
+"#,
+    );
+
+    for (offset, field) in header_layout() {
+        let range = format!("tvb({}, {})", offset, field.size);
+        match field.kind {
+            FieldKind::Decimal | FieldKind::Hex => {
+                let _ = writeln!(lua, "    header:add(f.{}, {})", field.name, range);
+            }
+            FieldKind::PacketType => {
+                let _ = writeln!(lua, "    header:add(f.{}, {})", field.name, range);
+                let _ = writeln!(lua, "    pinfo.cols.info = packet_types[{}:uint()] or \"Unknown\"", range);
+            }
+            FieldKind::Flags => {
+                let _ = writeln!(lua, "    local {0} = header:add(f.{0}, {1})", field.name, range);
+                for (_, flag) in FLAGS {
+                    let _ = writeln!(lua, "    {0}:add(f.{0}_{1}, {2})", field.name, flag.to_lowercase(), range);
+                }
+            }
+        }
+    }
+
+    lua.push_str(
+        r#"    if size > HEADER_SIZE then
+        tree:add(f.payload, tvb(HEADER_SIZE, size - HEADER_SIZE))
+    end
+    return size
+end
+
+function llp.dissector(tvb, pinfo, tree)
+    return dissect(tvb, pinfo, tree)
+end
+
+llp:register_heuristic("tcp", function(tvb, pinfo, tree)
+    return dissect(tvb, pinfo, tree) > 0
+end)
+
+"#,
+    );
+    let _ = writeln!(lua, "DissectorTable.get(\"tcp.port\"):add({}, llp)", port);
+
+    lua
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dissector_covers_definitions() {
+        let lua = lua_dissector(8443);
+
+        for packet_type in PacketType::ALL {
+            let entry = format!("[0x{:02X}] = \"{:?}\"", packet_type as u8, packet_type);
+            assert!(lua.contains(&entry), "missing {}", entry);
+        }
+        for (_, field) in header_layout() {
+            assert!(lua.contains(&format!("f.{} = ProtoField.uint{}(", field.name, field.size * 8)));
+        }
+        for (bit, flag) in FLAGS {
+            assert!(lua.contains(&format!("\"{}\", 8, nil, 0x{:02X})", flag, bit)));
+        }
+        assert!(lua.contains("local HEADER_SIZE = 26"));
+        assert!(lua.contains("local LENGTH_OFFSET = 22"));
+        assert!(lua.contains("local PROTOCOL_ID = 0x4C4C"));
+        assert!(lua.contains("DissectorTable.get(\"tcp.port\"):add(8443, llp)"));
+    }
+
+    #[test]
+    fn test_dissector_offsets() {
+        let lua = lua_dissector(8443);
+
+        assert!(lua.contains("header:add(f.protocol_id, tvb(0, 2))"));
+        assert!(lua.contains("header:add(f.packet_type, tvb(2, 1))"));
+        assert!(lua.contains("header:add(f.stream_id, tvb(3, 2))"));
+        assert!(lua.contains("header:add(f.sequence_number, tvb(5, 8))"));
+        assert!(lua.contains("header:add(f.timestamp, tvb(13, 8))"));
+        assert!(lua.contains("local flags = header:add(f.flags, tvb(21, 1))"));
+        assert!(lua.contains("header:add(f.length, tvb(22, 2))"));
+        assert!(lua.contains("header:add(f.checksum, tvb(24, 2))"));
+    }
+
+    #[test]
+    fn test_balanced_blocks() {
+        let lua = lua_dissector(8443);
+        let opened = lua.matches("function").count() + lua.matches(" then").count();
+        let closed = lua.lines().filter(|line| line.trim_start().starts_with("end")).count();
+        assert_eq!(opened, closed);
+    }
+}
@@ -0,0 +1,132 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::error::{LostLoveError, Result};
+
+/// Size of an encoded error message
+pub const ERROR_MESSAGE_SIZE: usize = 10;
+
+/// Error codes carried by `PacketType::Error`
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    BadChecksum = 0x0001,
+    MalformedPacket = 0x0002,
+    UnknownStream = 0x0003,
+    DecryptFailure = 0x0004,
+    RateLimited = 0x0005,
+    UnsupportedPacketType = 0x0006,
+}
+
+impl ErrorCode {
+    pub fn from_u16(value: u16) -> Result<Self> {
+        match value {
+            0x0001 => Ok(ErrorCode::BadChecksum),
+            0x0002 => Ok(ErrorCode::MalformedPacket),
+            0x0003 => Ok(ErrorCode::UnknownStream),
+            0x0004 => Ok(ErrorCode::DecryptFailure),
+            0x0005 => Ok(ErrorCode::RateLimited),
+            0x0006 => Ok(ErrorCode::UnsupportedPacketType),
+            _ => Err(LostLoveError::Connection(format!("Unknown error code: {:#06x}", value))),
+        }
+    }
+
+    /// Code to report to the peer for a packet processing error, if any
+    ///
+    /// Only errors caused by what the client sent are reported; internal
+    /// failures stay in the server log.
+    pub fn for_error(error: &LostLoveError) -> Option<Self> {
+        match error {
+            LostLoveError::ChecksumMismatch { .. } => Some(ErrorCode::BadChecksum),
+            LostLoveError::InvalidProtocolId(_)
+            | LostLoveError::InvalidPacketType(_)
+            | LostLoveError::InsufficientData { .. }
+            | LostLoveError::LengthMismatch { .. }
+            | LostLoveError::MalformedInnerPacket(_) => Some(ErrorCode::MalformedPacket),
+            LostLoveError::AeadFailure { .. } => Some(ErrorCode::DecryptFailure),
+            LostLoveError::RateLimited => Some(ErrorCode::RateLimited),
+            LostLoveError::UnsupportedPacketType(_) => Some(ErrorCode::UnsupportedPacketType),
+            _ => None,
+        }
+    }
+}
+
+/// Payload of a `PacketType::Error` packet
+///
+/// Wire format: error code (u16) followed by the sequence number of the
+/// offending packet (u64, 0 when unknown), both big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub sequence_number: u64,
+}
+
+impl ErrorMessage {
+    /// Create new error message
+    pub fn new(code: ErrorCode, sequence_number: u64) -> Self {
+        Self {
+            code,
+            sequence_number,
+        }
+    }
+
+    /// Serialize to packet payload
+    pub fn to_bytes(self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(ERROR_MESSAGE_SIZE);
+        buf.put_u16(self.code as u16);
+        buf.put_u64(self.sequence_number);
+        buf.freeze()
+    }
+
+    /// Deserialize from packet payload
+    pub fn from_bytes(mut data: &[u8]) -> Result<Self> {
+        if data.len() < ERROR_MESSAGE_SIZE {
+            return Err(LostLoveError::InsufficientData {
+                expected: ERROR_MESSAGE_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let code = ErrorCode::from_u16(data.get_u16())?;
+        let sequence_number = data.get_u64();
+
+        Ok(Self::new(code, sequence_number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_message_roundtrip() {
+        let message = ErrorMessage::new(ErrorCode::DecryptFailure, 42);
+        let bytes = message.to_bytes();
+
+        assert_eq!(bytes.len(), ERROR_MESSAGE_SIZE);
+        assert_eq!(ErrorMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_invalid_error_message() {
+        assert!(ErrorMessage::from_bytes(&[0x00, 0x01]).is_err());
+        assert!(ErrorMessage::from_bytes(&[0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_code_for_error() {
+        let error = LostLoveError::ChecksumMismatch {
+            expected: 1,
+            actual: 2,
+        };
+        assert_eq!(ErrorCode::for_error(&error), Some(ErrorCode::BadChecksum));
+        assert_eq!(
+            ErrorCode::for_error(&LostLoveError::RateLimited),
+            Some(ErrorCode::RateLimited)
+        );
+        assert_eq!(
+            ErrorCode::for_error(&LostLoveError::UnsupportedPacketType(0x40)),
+            Some(ErrorCode::UnsupportedPacketType)
+        );
+        assert_eq!(ErrorCode::for_error(&LostLoveError::TooManyConnections), None);
+    }
+}
@@ -1,15 +1,66 @@
 use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
+use crate::core::session::SessionId;
+use crate::crypto::rng::{self, RandomSource};
 use crate::error::{LostLoveError, Result};
+use crate::protocol::hello_extensions::HelloExtensions;
+use crate::protocol::state_machine::{HandshakeEvent, Role};
+
+pub use crate::protocol::state_machine::HandshakeState;
+
+/// Protocol versions this implementation speaks, most preferred first
+pub const SUPPORTED_VERSIONS: &[u8] = &[1];
+
+/// Cipher suites this implementation supports, most preferred first
+pub const SUPPORTED_CIPHER_SUITES: &[CipherSuite] = &[
+    CipherSuite::Hse,
+    CipherSuite::ChaCha20Poly1305,
+    CipherSuite::Aes256Gcm,
+];
+
+/// Negotiable payload cipher suites
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CipherSuite {
+    Hse,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+impl CipherSuite {
+    /// Name on the wire
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherSuite::Hse => "hse",
+            CipherSuite::ChaCha20Poly1305 => "chacha20-poly1305",
+            CipherSuite::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+}
 
-/// Handshake state machine
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum HandshakeState {
-    Init,
-    ClientHelloSent,
-    ServerHelloReceived,
-    Completed,
-    Failed,
+/// Why the server refused a handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HandshakeFailureCategory {
+    /// None of the client's protocol versions is supported
+    UnsupportedVersion,
+    /// None of the client's cipher suites is supported
+    NoCommonCipherSuite,
+    /// Message was not the one expected at this point of the handshake
+    UnexpectedMessage,
+    /// Message could not be decoded
+    Malformed,
+    /// Server is draining for an upgrade and accepts no new sessions
+    ServerDraining,
+    /// Server is at capacity; the same server may be retried later
+    ServerBusy,
+    /// Another server of the fleet takes this client, `retry_at` names it
+    ServerSteered,
 }
 
 /// Handshake message types
@@ -18,10 +69,33 @@ pub enum HandshakeMessage {
     ClientHello {
         client_random: [u8; 32],
         protocol_version: u8,
+        /// Offered cipher suites in preference order (empty = server default)
+        #[serde(default)]
+        cipher_suites: Vec<CipherSuite>,
+        /// User the client connects as, for per-user access policies
+        #[serde(default)]
+        user: Option<String>,
+        /// Session labels claimed by the client, e.g. `team` or `device`
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+        /// Optional features the client supports
+        #[serde(default, skip_serializing_if = "HelloExtensions::is_empty")]
+        extensions: HelloExtensions,
     },
     ServerHello {
         server_random: [u8; 32],
-        session_id: String,
+        session_id: SessionId,
+        #[serde(default = "default_cipher_suite")]
+        cipher_suite: CipherSuite,
+        /// Tunnel address leased to the client
+        #[serde(default)]
+        tunnel_address: Option<IpAddr>,
+        /// Token attaching a UDP path to this session (`server.protocol` udp or both)
+        #[serde(default)]
+        transport_token: Option<String>,
+        /// Offered features the server accepted
+        #[serde(default, skip_serializing_if = "HelloExtensions::is_empty")]
+        extensions: HelloExtensions,
     },
     ClientFinish {
         verification_data: Vec<u8>,
@@ -29,6 +103,23 @@ pub enum HandshakeMessage {
     ServerFinish {
         verification_data: Vec<u8>,
     },
+    /// Sent by the server instead of ServerHello when it refuses the handshake
+    Failure {
+        category: HandshakeFailureCategory,
+        reason: String,
+        supported_versions: Vec<u8>,
+        supported_cipher_suites: Vec<CipherSuite>,
+        /// Server to connect to instead (`host:port`), set while draining
+        #[serde(default)]
+        retry_at: Option<String>,
+        /// Seconds to wait before retrying this server, set when busy
+        #[serde(default)]
+        retry_after_secs: Option<u64>,
+    },
+}
+
+fn default_cipher_suite() -> CipherSuite {
+    SUPPORTED_CIPHER_SUITES[0]
 }
 
 impl HandshakeMessage {
@@ -41,38 +132,235 @@ impl HandshakeMessage {
 
     /// Deserialize handshake message from bytes
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        serde_json::from_slice(data)
-            .map_err(|e| LostLoveError::HandshakeFailed(format!("Deserialization error: {}", e)))
+        serde_json::from_slice(data).map_err(|e| LostLoveError::HandshakeRejected {
+            category: HandshakeFailureCategory::Malformed,
+            reason: format!("Deserialization error: {}", e),
+        })
+    }
+
+    /// Build a Failure message advertising what this server supports
+    pub fn failure(
+        category: HandshakeFailureCategory,
+        reason: impl Into<String>,
+        cipher_suites: &[CipherSuite],
+    ) -> Self {
+        HandshakeMessage::Failure {
+            category,
+            reason: reason.into(),
+            supported_versions: SUPPORTED_VERSIONS.to_vec(),
+            supported_cipher_suites: cipher_suites.to_vec(),
+            retry_at: None,
+            retry_after_secs: None,
+        }
+    }
+
+    /// Build the Failure message sent while the server is draining
+    pub fn draining(retry_at: Option<String>, cipher_suites: &[CipherSuite]) -> Self {
+        HandshakeMessage::Failure {
+            category: HandshakeFailureCategory::ServerDraining,
+            reason: "Server is draining, connect to another server".to_string(),
+            supported_versions: SUPPORTED_VERSIONS.to_vec(),
+            supported_cipher_suites: cipher_suites.to_vec(),
+            retry_at,
+            retry_after_secs: None,
+        }
+    }
+
+    /// Build the Failure message sending a new client to another fleet node
+    pub fn steered(retry_at: String, cipher_suites: &[CipherSuite]) -> Self {
+        HandshakeMessage::Failure {
+            category: HandshakeFailureCategory::ServerSteered,
+            reason: "Another server takes this client".to_string(),
+            supported_versions: SUPPORTED_VERSIONS.to_vec(),
+            supported_cipher_suites: cipher_suites.to_vec(),
+            retry_at: Some(retry_at),
+            retry_after_secs: None,
+        }
+    }
+
+    /// Build the Failure message sent while the server sheds load
+    pub fn busy(reason: impl Into<String>, retry_after_secs: u64, cipher_suites: &[CipherSuite]) -> Self {
+        HandshakeMessage::Failure {
+            category: HandshakeFailureCategory::ServerBusy,
+            reason: reason.into(),
+            supported_versions: SUPPORTED_VERSIONS.to_vec(),
+            supported_cipher_suites: cipher_suites.to_vec(),
+            retry_at: None,
+            retry_after_secs: Some(retry_after_secs),
+        }
     }
 }
 
 /// Handshake handler
 pub struct Handshake {
+    role: Role,
     state: HandshakeState,
     client_random: Option<[u8; 32]>,
     server_random: Option<[u8; 32]>,
-    session_id: Option<String>,
+    session_id: Option<SessionId>,
+    protocol_version: u8,
+    cipher_suites: Vec<CipherSuite>,
+    cipher_suite: Option<CipherSuite>,
+    tunnel_address: Option<IpAddr>,
+    transport_token: Option<String>,
+    user: Option<String>,
+    labels: BTreeMap<String, String>,
+    /// Offered (client side) or supported (server side) extensions
+    extensions: HelloExtensions,
+    negotiated: HelloExtensions,
+    random: Arc<dyn RandomSource>,
 }
 
 impl Handshake {
     /// Create new handshake (server side)
     pub fn new_server() -> Self {
         Self {
+            role: Role::Server,
             state: HandshakeState::Init,
             client_random: None,
             server_random: None,
             session_id: None,
+            protocol_version: SUPPORTED_VERSIONS[0],
+            cipher_suites: SUPPORTED_CIPHER_SUITES.to_vec(),
+            cipher_suite: None,
+            tunnel_address: None,
+            transport_token: None,
+            user: None,
+            labels: BTreeMap::new(),
+            extensions: HelloExtensions::default(),
+            negotiated: HelloExtensions::default(),
+            random: rng::system(),
         }
     }
 
     /// Create new handshake (client side)
     pub fn new_client() -> Self {
         Self {
+            role: Role::Client,
             state: HandshakeState::Init,
-            client_random: Some(generate_random()),
+            client_random: None,
             server_random: None,
             session_id: None,
+            protocol_version: SUPPORTED_VERSIONS[0],
+            cipher_suites: SUPPORTED_CIPHER_SUITES.to_vec(),
+            cipher_suite: None,
+            tunnel_address: None,
+            transport_token: None,
+            user: None,
+            labels: BTreeMap::new(),
+            extensions: HelloExtensions::default(),
+            negotiated: HelloExtensions::default(),
+            random: rng::system(),
+        }
+    }
+
+    /// Draw the randoms and session ID from `random` instead of the system RNG
+    pub fn with_random(mut self, random: Arc<dyn RandomSource>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Announce the client's leased tunnel address in ServerHello (server side)
+    pub fn with_tunnel_address(mut self, address: IpAddr) -> Self {
+        self.tunnel_address = Some(address);
+        self
+    }
+
+    /// Hand out a UDP transport token in ServerHello (server side)
+    ///
+    /// Set on the connection's handshake after it was created, hence not a builder.
+    pub fn set_transport_token(&mut self, token: String) {
+        self.transport_token = Some(token);
+    }
+
+    /// Offer these extensions (client side) or accept these (server side)
+    pub fn with_extensions(mut self, extensions: HelloExtensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Accept these extensions (server side), set like the transport token
+    pub fn set_extensions(&mut self, extensions: HelloExtensions) {
+        self.extensions = extensions;
+    }
+
+    /// Negotiate only these cipher suites, most preferred first (server side)
+    pub fn with_cipher_suites(mut self, cipher_suites: &[CipherSuite]) -> Self {
+        self.cipher_suites = cipher_suites.to_vec();
+        self
+    }
+
+    /// Cipher suites offered (client side) or accepted (server side)
+    pub fn cipher_suites(&self) -> &[CipherSuite] {
+        &self.cipher_suites
+    }
+
+    /// Offer a specific protocol version and cipher suites (client side)
+    pub fn with_offer(mut self, protocol_version: u8, cipher_suites: Vec<CipherSuite>) -> Self {
+        self.protocol_version = protocol_version;
+        self.cipher_suites = cipher_suites;
+        self
+    }
+
+    /// Connect as a user (client side)
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Claim session labels (client side)
+    pub fn with_labels(mut self, labels: BTreeMap<String, String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Build a fresh client handshake that fits what the server said it supports
+    ///
+    /// Returns `None` if the failure can't be fixed by changing the offer.
+    pub fn retry_after(&self, failure: &HandshakeMessage) -> Option<Handshake> {
+        let HandshakeMessage::Failure {
+            category,
+            supported_versions,
+            supported_cipher_suites,
+            ..
+        } = failure
+        else {
+            return None;
+        };
+
+        match category {
+            HandshakeFailureCategory::UnsupportedVersion
+            | HandshakeFailureCategory::NoCommonCipherSuite => {}
+            _ => return None,
         }
+
+        let version = SUPPORTED_VERSIONS
+            .iter()
+            .find(|v| supported_versions.contains(v))?;
+
+        let suites: Vec<CipherSuite> = self
+            .cipher_suites
+            .iter()
+            .chain(SUPPORTED_CIPHER_SUITES)
+            .filter(|s| supported_cipher_suites.contains(s))
+            .fold(Vec::new(), |mut acc, s| {
+                if !acc.contains(s) {
+                    acc.push(*s);
+                }
+                acc
+            });
+
+        if suites.is_empty() {
+            return None;
+        }
+
+        let mut retry = Handshake::new_client()
+            .with_offer(*version, suites)
+            .with_random(self.random.clone());
+        retry.user = self.user.clone();
+        retry.labels = self.labels.clone();
+        retry.extensions = self.extensions.clone();
+        Some(retry)
     }
 
     /// Get current state
@@ -85,83 +373,171 @@ impl Handshake {
         self.state == HandshakeState::Completed
     }
 
+    /// Free what only negotiating needed: offers, token and claimed labels
+    ///
+    /// The outcome (suite, user, negotiated extensions) stays.
+    pub fn release_negotiation(&mut self) {
+        self.cipher_suites = Vec::new();
+        self.transport_token = None;
+        self.labels = BTreeMap::new();
+        self.extensions = HelloExtensions::default();
+    }
+
+    /// Side of the handshake this is
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// State `event` leads to, or an error if it's not allowed now
+    fn check(&self, event: HandshakeEvent) -> Result<HandshakeState> {
+        self.state.next(self.role, event).ok_or_else(|| {
+            LostLoveError::HandshakeFailed(format!(
+                "Invalid state transition: {:?} in {:?} ({:?} side)",
+                event, self.state, self.role
+            ))
+        })
+    }
+
+    /// Fail the handshake after refusing a message
+    fn reject(&mut self, error: LostLoveError) -> LostLoveError {
+        if let Some(state) = self.state.next(self.role, HandshakeEvent::Reject) {
+            self.state = state;
+        }
+        error
+    }
+
     /// Generate ClientHello message
     pub fn generate_client_hello(&mut self) -> Result<HandshakeMessage> {
-        if self.state != HandshakeState::Init {
-            return Err(LostLoveError::HandshakeFailed(
-                "Invalid state for ClientHello".to_string(),
-            ));
-        }
+        let next = self.check(HandshakeEvent::SendClientHello)?;
 
-        let client_random = self.client_random.unwrap_or_else(generate_random);
+        let client_random = self
+            .client_random
+            .unwrap_or_else(|| rng::random_32(self.random.as_ref()));
         self.client_random = Some(client_random);
-        self.state = HandshakeState::ClientHelloSent;
+        self.state = next;
 
         Ok(HandshakeMessage::ClientHello {
             client_random,
-            protocol_version: 1,
+            protocol_version: self.protocol_version,
+            cipher_suites: self.cipher_suites.clone(),
+            user: self.user.clone(),
+            labels: self.labels.clone(),
+            extensions: self.extensions.clone(),
         })
     }
 
     /// Process ClientHello message (server side)
+    ///
+    /// The handshake completes once `confirm_keys` is called after the
+    /// ServerHello went out.
     pub fn process_client_hello(&mut self, msg: &HandshakeMessage) -> Result<HandshakeMessage> {
-        if self.state != HandshakeState::Init {
-            return Err(LostLoveError::HandshakeFailed(
-                "Invalid state for processing ClientHello".to_string(),
-            ));
-        }
+        let next = self.check(HandshakeEvent::AcceptClientHello)?;
 
         if let HandshakeMessage::ClientHello {
             client_random,
             protocol_version,
+            cipher_suites,
+            user,
+            labels,
+            extensions,
         } = msg
         {
-            if *protocol_version != 1 {
-                return Err(LostLoveError::HandshakeFailed(format!(
-                    "Unsupported protocol version: {}",
-                    protocol_version
-                )));
+            if !SUPPORTED_VERSIONS.contains(protocol_version) {
+                return Err(self.reject(LostLoveError::HandshakeRejected {
+                    category: HandshakeFailureCategory::UnsupportedVersion,
+                    reason: format!("Unsupported protocol version: {}", protocol_version),
+                }));
             }
 
+            let cipher_suite = if cipher_suites.is_empty() {
+                self.cipher_suites[0]
+            } else {
+                match cipher_suites
+                    .iter()
+                    .find(|s| self.cipher_suites.contains(s))
+                {
+                    Some(suite) => *suite,
+                    None => {
+                        return Err(self.reject(LostLoveError::HandshakeRejected {
+                            category: HandshakeFailureCategory::NoCommonCipherSuite,
+                            reason: "No common cipher suite".to_string(),
+                        }));
+                    }
+                }
+            };
+
+            self.protocol_version = *protocol_version;
+            self.cipher_suite = Some(cipher_suite);
             self.client_random = Some(*client_random);
+            self.user = user.clone();
+            self.labels = labels.clone();
+            self.negotiated = HelloExtensions::negotiate(extensions, &self.extensions);
 
-            let server_random = generate_random();
+            let server_random = rng::random_32(self.random.as_ref());
             self.server_random = Some(server_random);
 
-            let session_id = uuid::Uuid::new_v4().to_string();
-            self.session_id = Some(session_id.clone());
+            let mut uuid = [0u8; 16];
+            self.random.fill(&mut uuid);
+            let session_id = SessionId::from_bytes(uuid::Builder::from_random_bytes(uuid).into_uuid().into_bytes());
+            self.session_id = Some(session_id);
 
-            self.state = HandshakeState::ServerHelloReceived;
+            self.state = next;
 
             Ok(HandshakeMessage::ServerHello {
                 server_random,
                 session_id,
+                cipher_suite,
+                tunnel_address: self.tunnel_address,
+                transport_token: self.transport_token.clone(),
+                extensions: self.negotiated.clone(),
             })
         } else {
-            Err(LostLoveError::HandshakeFailed(
-                "Expected ClientHello message".to_string(),
-            ))
+            Err(self.reject(LostLoveError::HandshakeRejected {
+                category: HandshakeFailureCategory::UnexpectedMessage,
+                reason: "Expected ClientHello message".to_string(),
+            }))
         }
     }
 
+    /// Complete the handshake once ServerHello was delivered (server side)
+    ///
+    /// Protocol version 1 has no Finish exchange, so delivering ServerHello
+    /// is what puts the session keys in place on both sides.
+    pub fn confirm_keys(&mut self) -> Result<()> {
+        self.state = self.check(HandshakeEvent::ConfirmKeys)?;
+        Ok(())
+    }
+
     /// Process ServerHello message (client side)
     pub fn process_server_hello(&mut self, msg: &HandshakeMessage) -> Result<()> {
-        if self.state != HandshakeState::ClientHelloSent {
-            return Err(LostLoveError::HandshakeFailed(
-                "Invalid state for processing ServerHello".to_string(),
-            ));
-        }
+        let next = self.check(HandshakeEvent::AcceptServerHello)?;
 
         if let HandshakeMessage::ServerHello {
             server_random,
             session_id,
+            cipher_suite,
+            tunnel_address,
+            transport_token,
+            extensions,
         } = msg
         {
+            if let Err(e) = extensions.check_accepted(&self.extensions) {
+                return Err(self.reject(e));
+            }
             self.server_random = Some(*server_random);
-            self.session_id = Some(session_id.clone());
-            self.state = HandshakeState::Completed;
+            self.session_id = Some(*session_id);
+            self.cipher_suite = Some(*cipher_suite);
+            self.tunnel_address = *tunnel_address;
+            self.transport_token = transport_token.clone();
+            self.negotiated = extensions.clone();
+            self.state = next;
 
             Ok(())
+        } else if let HandshakeMessage::Failure { category, reason, .. } = msg {
+            Err(self.reject(LostLoveError::HandshakeRejected {
+                category: *category,
+                reason: reason.clone(),
+            }))
         } else {
             Err(LostLoveError::HandshakeFailed(
                 "Expected ServerHello message".to_string(),
@@ -170,8 +546,38 @@ impl Handshake {
     }
 
     /// Get session ID
-    pub fn session_id(&self) -> Option<&str> {
-        self.session_id.as_deref()
+    pub fn session_id(&self) -> Option<SessionId> {
+        self.session_id
+    }
+
+    /// Get negotiated cipher suite
+    pub fn cipher_suite(&self) -> Option<CipherSuite> {
+        self.cipher_suite
+    }
+
+    /// Get tunnel address (leased by the server)
+    pub fn tunnel_address(&self) -> Option<IpAddr> {
+        self.tunnel_address
+    }
+
+    /// Get the UDP transport token, if the server offers UDP
+    pub fn transport_token(&self) -> Option<&str> {
+        self.transport_token.as_deref()
+    }
+
+    /// Get the user the client connected as
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Get the labels the client claimed
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    /// Extensions both sides agreed on, once the hellos were exchanged
+    pub fn negotiated_extensions(&self) -> &HelloExtensions {
+        &self.negotiated
     }
 
     /// Get client random
@@ -185,15 +591,6 @@ impl Handshake {
     }
 }
 
-/// Generate random bytes
-fn generate_random() -> [u8; 32] {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
-    let mut bytes = [0u8; 32];
-    rng.fill(&mut bytes);
-    bytes
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -208,14 +605,23 @@ mod tests {
         let mut server_handshake = Handshake::new_server();
         let server_hello = server_handshake.process_client_hello(&client_hello).unwrap();
 
-        // Client processes server hello
+        // Client processes server hello, sent as the 16 bytes of the session ID
+        let server_hello = HandshakeMessage::from_bytes(&server_hello.to_bytes().unwrap()).unwrap();
         client_handshake.process_server_hello(&server_hello).unwrap();
+        assert!(client_handshake.session_id().is_some());
+        assert_eq!(client_handshake.session_id(), server_handshake.session_id());
 
         assert!(client_handshake.is_completed());
         assert_eq!(
             server_handshake.state(),
-            HandshakeState::ServerHelloReceived
+            HandshakeState::ServerHelloSent
         );
+
+        // The server completes only once ServerHello was delivered
+        assert!(!server_handshake.is_completed());
+        server_handshake.confirm_keys().unwrap();
+        assert!(server_handshake.is_completed());
+        assert!(server_handshake.confirm_keys().is_err());
     }
 
     #[test]
@@ -223,6 +629,10 @@ mod tests {
         let msg = HandshakeMessage::ClientHello {
             client_random: [0u8; 32],
             protocol_version: 1,
+            cipher_suites: vec![CipherSuite::Aes256Gcm],
+            user: None,
+            labels: BTreeMap::new(),
+            extensions: HelloExtensions::default(),
         };
 
         let bytes = msg.to_bytes().unwrap();
@@ -236,6 +646,218 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cipher_suite_negotiation() {
+        let mut client = Handshake::new_client()
+            .with_offer(1, vec![CipherSuite::Aes256Gcm, CipherSuite::Hse])
+            .with_user("alice")
+            .with_labels(BTreeMap::from([("team".to_string(), "ops".to_string())]));
+        let client_hello = client.generate_client_hello().unwrap();
+
+        let lease: IpAddr = "10.8.0.2".parse().unwrap();
+        let mut server = Handshake::new_server().with_tunnel_address(lease);
+        let server_hello = server.process_client_hello(&client_hello).unwrap();
+        client.process_server_hello(&server_hello).unwrap();
+
+        assert_eq!(server.cipher_suite(), Some(CipherSuite::Aes256Gcm));
+        assert_eq!(client.cipher_suite(), Some(CipherSuite::Aes256Gcm));
+        assert_eq!(client.tunnel_address(), Some(lease));
+        assert_eq!(client.transport_token(), None);
+        assert_eq!(server.user(), Some("alice"));
+        assert_eq!(server.labels().get("team").map(String::as_str), Some("ops"));
+    }
+
+    #[test]
+    fn test_legacy_client_hello_defaults() {
+        let msg = HandshakeMessage::from_bytes(
+            br#"{"ClientHello":{"client_random":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"protocol_version":1}}"#,
+        )
+        .unwrap();
+
+        let mut server = Handshake::new_server();
+        server.process_client_hello(&msg).unwrap();
+        assert_eq!(server.cipher_suite(), Some(SUPPORTED_CIPHER_SUITES[0]));
+        assert_eq!(server.user(), None);
+        assert!(server.labels().is_empty());
+    }
+
+    #[test]
+    fn test_extension_negotiation() {
+        let offer = HelloExtensions {
+            compression: vec!["deflate".to_string()],
+            max_streams: Some(1024),
+            resumption: true,
+            ..HelloExtensions::default()
+        };
+        let mut client = Handshake::new_client().with_extensions(offer);
+        let client_hello = HandshakeMessage::from_bytes(&client.generate_client_hello().unwrap().to_bytes().unwrap())
+            .unwrap();
+
+        let mut server = Handshake::new_server().with_extensions(HelloExtensions {
+            max_streams: Some(256),
+            ..HelloExtensions::default()
+        });
+        let server_hello = server.process_client_hello(&client_hello).unwrap();
+        client.process_server_hello(&server_hello).unwrap();
+
+        let expected = HelloExtensions {
+            max_streams: Some(256),
+            ..HelloExtensions::default()
+        };
+        assert_eq!(client.negotiated_extensions(), &expected);
+        assert_eq!(server.negotiated_extensions(), &expected);
+    }
+
+    #[test]
+    fn test_unoffered_extension_fails_client() {
+        let mut client = Handshake::new_client();
+        let client_hello = client.generate_client_hello().unwrap();
+        let mut server = Handshake::new_server().with_extensions(HelloExtensions {
+            fec: true,
+            ..HelloExtensions::default()
+        });
+        let mut server_hello = server.process_client_hello(&client_hello).unwrap();
+
+        // A server answering with something the client never offered
+        if let HandshakeMessage::ServerHello { extensions, .. } = &mut server_hello {
+            extensions.fec = true;
+        }
+        assert!(client.process_server_hello(&server_hello).is_err());
+        assert!(!client.is_completed());
+    }
+
+    #[test]
+    fn test_unsupported_version_and_retry() {
+        let mut client = Handshake::new_client().with_offer(9, vec![CipherSuite::ChaCha20Poly1305]);
+        let client_hello = client.generate_client_hello().unwrap();
+
+        let mut server = Handshake::new_server();
+        let err = server.process_client_hello(&client_hello).unwrap_err();
+        assert_eq!(server.state(), HandshakeState::Failed);
+
+        let LostLoveError::HandshakeRejected { category, reason } = err else {
+            panic!("Unexpected error: {:?}", err);
+        };
+        assert_eq!(category, HandshakeFailureCategory::UnsupportedVersion);
+
+        let failure = HandshakeMessage::failure(category, reason, SUPPORTED_CIPHER_SUITES);
+        assert!(client.process_server_hello(&failure).is_err());
+        assert_eq!(client.state(), HandshakeState::Failed);
+
+        let mut retry = client.retry_after(&failure).unwrap();
+        let client_hello = retry.generate_client_hello().unwrap();
+        let mut server = Handshake::new_server();
+        server.process_client_hello(&client_hello).unwrap();
+        assert_eq!(server.cipher_suite(), Some(CipherSuite::ChaCha20Poly1305));
+    }
+
+    #[test]
+    fn test_no_common_cipher_suite() {
+        let client = Handshake::new_client().with_offer(1, vec![]);
+        let msg = HandshakeMessage::ClientHello {
+            client_random: [0u8; 32],
+            protocol_version: 1,
+            cipher_suites: vec![],
+            user: None,
+            labels: BTreeMap::new(),
+            extensions: HelloExtensions::default(),
+        };
+        let failure = HandshakeMessage::Failure {
+            category: HandshakeFailureCategory::NoCommonCipherSuite,
+            reason: "No common cipher suite".to_string(),
+            supported_versions: vec![1],
+            supported_cipher_suites: vec![],
+            retry_at: None,
+            retry_after_secs: None,
+        };
+
+        // An empty offer means "server default", so it always succeeds
+        assert!(Handshake::new_server().process_client_hello(&msg).is_ok());
+        // Nothing the server supports is known to us: no point retrying
+        assert!(client.retry_after(&failure).is_none());
+    }
+
+    #[test]
+    fn test_restricted_cipher_suites() {
+        let mut client = Handshake::new_client()
+            .with_offer(1, vec![CipherSuite::Hse, CipherSuite::ChaCha20Poly1305]);
+        let client_hello = client.generate_client_hello().unwrap();
+
+        let mut server = Handshake::new_server().with_cipher_suites(&[CipherSuite::Aes256Gcm]);
+        let LostLoveError::HandshakeRejected { category, reason } =
+            server.process_client_hello(&client_hello).unwrap_err()
+        else {
+            panic!("Expected a rejection");
+        };
+        assert_eq!(category, HandshakeFailureCategory::NoCommonCipherSuite);
+
+        // The client learns what's allowed and retries with it
+        let failure = HandshakeMessage::failure(category, reason, server.cipher_suites());
+        let mut retry = client.retry_after(&failure).unwrap();
+        let client_hello = retry.generate_client_hello().unwrap();
+
+        let mut server = Handshake::new_server().with_cipher_suites(&[CipherSuite::Aes256Gcm]);
+        server.process_client_hello(&client_hello).unwrap();
+        assert_eq!(server.cipher_suite(), Some(CipherSuite::Aes256Gcm));
+
+        // An empty offer gets the server's first allowed suite
+        let mut server = Handshake::new_server().with_cipher_suites(&[CipherSuite::Aes256Gcm]);
+        let mut client = Handshake::new_client().with_offer(1, vec![]);
+        server.process_client_hello(&client.generate_client_hello().unwrap()).unwrap();
+        assert_eq!(server.cipher_suite(), Some(CipherSuite::Aes256Gcm));
+    }
+
+    #[test]
+    fn test_draining_failure() {
+        let failure = HandshakeMessage::draining(Some("llp2.example.com:8443".to_string()), SUPPORTED_CIPHER_SUITES);
+        let bytes = failure.to_bytes().unwrap();
+
+        match HandshakeMessage::from_bytes(&bytes).unwrap() {
+            HandshakeMessage::Failure { category, retry_at, .. } => {
+                assert_eq!(category, HandshakeFailureCategory::ServerDraining);
+                assert_eq!(retry_at.as_deref(), Some("llp2.example.com:8443"));
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        // Retrying the same server with another offer won't help
+        assert!(Handshake::new_client().retry_after(&failure).is_none());
+    }
+
+    #[test]
+    fn test_steered_failure() {
+        let failure = HandshakeMessage::steered("fra-2.example.com:8443".to_string(), SUPPORTED_CIPHER_SUITES);
+        let json = String::from_utf8(failure.to_bytes().unwrap().to_vec()).unwrap();
+        assert!(json.contains(r#""category":"server-steered""#));
+
+        match HandshakeMessage::from_bytes(json.as_bytes()).unwrap() {
+            HandshakeMessage::Failure { category, retry_at, .. } => {
+                assert_eq!(category, HandshakeFailureCategory::ServerSteered);
+                assert_eq!(retry_at.as_deref(), Some("fra-2.example.com:8443"));
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert!(Handshake::new_client().retry_after(&failure).is_none());
+    }
+
+    #[test]
+    fn test_busy_failure() {
+        let failure = HandshakeMessage::busy("Server is at capacity", 15, SUPPORTED_CIPHER_SUITES);
+        let bytes = failure.to_bytes().unwrap();
+
+        match HandshakeMessage::from_bytes(&bytes).unwrap() {
+            HandshakeMessage::Failure { category, retry_at, retry_after_secs, .. } => {
+                assert_eq!(category, HandshakeFailureCategory::ServerBusy);
+                assert_eq!(retry_at, None);
+                assert_eq!(retry_after_secs, Some(15));
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        // Waiting helps, a different offer doesn't
+        assert!(Handshake::new_client().retry_after(&failure).is_none());
+    }
+
     #[test]
     fn test_invalid_state_transition() {
         let mut handshake = Handshake::new_server();
@@ -244,4 +866,56 @@ mod tests {
         let result = handshake.generate_client_hello();
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_property_server_never_completes_unconfirmed() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let hello = Handshake::new_client().generate_client_hello().unwrap();
+        let bad_version = Handshake::new_client()
+            .with_offer(99, vec![CipherSuite::Aes256Gcm])
+            .generate_client_hello()
+            .unwrap();
+        let mut rng = StdRng::seed_from_u64(164);
+
+        for _ in 0..1000 {
+            let mut server = Handshake::new_server();
+            let mut accepted = false;
+            let mut confirmed = false;
+
+            for _ in 0..rng.gen_range(0..8) {
+                match rng.gen_range(0..5) {
+                    0 => accepted |= server.process_client_hello(&hello).is_ok(),
+                    1 => assert!(server.process_client_hello(&bad_version).is_err()),
+                    2 => confirmed |= server.confirm_keys().is_ok(),
+                    3 => assert!(server.generate_client_hello().is_err()),
+                    _ => assert!(server.process_server_hello(&hello).is_err()),
+                }
+
+                if server.is_completed() {
+                    assert!(accepted && confirmed);
+                }
+                if confirmed {
+                    assert!(accepted);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeded_handshake_is_reproducible() {
+        use crate::crypto::rng::SeededRandom;
+
+        let run = |seed| {
+            let mut client = Handshake::new_client().with_random(Arc::new(SeededRandom::new(seed)));
+            let mut server = Handshake::new_server().with_random(Arc::new(SeededRandom::new(seed + 1)));
+            let hello = client.generate_client_hello().unwrap();
+            server.process_client_hello(&hello).unwrap();
+            (client.client_random(), server.server_random(), server.session_id())
+        };
+
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(3));
+    }
 }
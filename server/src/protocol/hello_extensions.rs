@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::error::{LostLoveError, Result};
+
+/// Optional features, offered in ClientHello and accepted in ServerHello
+///
+/// Sent as a JSON object keyed by extension name. A known extension with a
+/// value of the wrong shape fails to decode, which fails the handshake.
+/// The server ignores extensions it does not know and answers only those
+/// it accepted; a client seeing anything it did not offer aborts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelloExtensions {
+    /// Payload compression algorithms, most preferred first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compression: Vec<String>,
+    /// Forward error correction on the data path
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub fec: bool,
+    /// Largest coalesced TCP packet a side takes in one DATA packet, segmented by the receiver
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gso_max_size: Option<u32>,
+    /// Streams a side opens at most on one connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_streams: Option<u16>,
+    /// Obfuscation modes, most preferred first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub obfuscation: Vec<String>,
+    /// Session resumption without a full handshake
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub resumption: bool,
+    /// 0.5-RTT data: the server's first CONFIG_UPDATE follows ServerHello
+    /// in the same write, so the client can send Data right away
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub early_data: bool,
+    /// Extensions this implementation does not know, by name
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl HelloExtensions {
+    pub fn is_empty(&self) -> bool {
+        *self == HelloExtensions::default()
+    }
+
+    /// What the server accepts of `offered`, given what it `supported`s (server side)
+    ///
+    /// Lists come back with the single chosen entry, or empty when nothing
+    /// matched; unknown extensions are dropped.
+    pub fn negotiate(offered: &HelloExtensions, supported: &HelloExtensions) -> HelloExtensions {
+        let first_common = |offered: &[String], supported: &[String]| -> Vec<String> {
+            offered
+                .iter()
+                .find(|entry| supported.contains(entry))
+                .cloned()
+                .into_iter()
+                .collect()
+        };
+
+        HelloExtensions {
+            compression: first_common(&offered.compression, &supported.compression),
+            fec: offered.fec && supported.fec,
+            gso_max_size: offered
+                .gso_max_size
+                .zip(supported.gso_max_size)
+                .map(|(offered, limit)| offered.min(limit)),
+            max_streams: offered
+                .max_streams
+                .map(|streams| supported.max_streams.map_or(streams, |limit| streams.min(limit))),
+            obfuscation: first_common(&offered.obfuscation, &supported.obfuscation),
+            resumption: offered.resumption && supported.resumption,
+            early_data: offered.early_data && supported.early_data,
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    /// Check the server accepted only what was `offered` (client side)
+    pub fn check_accepted(&self, offered: &HelloExtensions) -> Result<()> {
+        let refuse = |name: &str| {
+            Err(LostLoveError::HandshakeFailed(format!(
+                "Server accepted extension {} that was not offered",
+                name
+            )))
+        };
+
+        if let Some(name) = self.unknown.keys().next() {
+            return refuse(name);
+        }
+        if self.compression.len() > 1 || !self.compression.iter().all(|c| offered.compression.contains(c)) {
+            return refuse("compression");
+        }
+        if self.fec && !offered.fec {
+            return refuse("fec");
+        }
+        match (self.gso_max_size, offered.gso_max_size) {
+            (Some(accepted), Some(limit)) if accepted > limit => return refuse("gso_max_size"),
+            (Some(_), None) => return refuse("gso_max_size"),
+            _ => {}
+        }
+        match (self.max_streams, offered.max_streams) {
+            (Some(accepted), Some(limit)) if accepted > limit => return refuse("max_streams"),
+            (Some(_), None) => return refuse("max_streams"),
+            _ => {}
+        }
+        if self.obfuscation.len() > 1 || !self.obfuscation.iter().all(|m| offered.obfuscation.contains(m)) {
+            return refuse("obfuscation");
+        }
+        if self.resumption && !offered.resumption {
+            return refuse("resumption");
+        }
+        if self.early_data && !offered.early_data {
+            return refuse("early_data");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer() -> HelloExtensions {
+        HelloExtensions {
+            compression: vec!["zstd".to_string(), "deflate".to_string()],
+            fec: true,
+            gso_max_size: Some(65535),
+            max_streams: Some(512),
+            obfuscation: vec!["handshake".to_string()],
+            resumption: true,
+            early_data: true,
+            unknown: BTreeMap::from([("future".to_string(), Value::Bool(true))]),
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let supported = HelloExtensions {
+            compression: vec!["deflate".to_string()],
+            max_streams: Some(256),
+            ..HelloExtensions::default()
+        };
+
+        let accepted = HelloExtensions::negotiate(&offer(), &supported);
+        assert_eq!(accepted.compression, ["deflate"]);
+        assert!(!accepted.fec && !accepted.resumption && !accepted.early_data);
+        assert_eq!(accepted.max_streams, Some(256));
+        assert_eq!(accepted.gso_max_size, None);
+        assert!(accepted.obfuscation.is_empty());
+        assert!(accepted.unknown.is_empty());
+        assert!(accepted.check_accepted(&offer()).is_ok());
+
+        let supported = HelloExtensions {
+            gso_max_size: Some(16384),
+            ..HelloExtensions::default()
+        };
+        assert_eq!(HelloExtensions::negotiate(&offer(), &supported).gso_max_size, Some(16384));
+
+        // Nothing offered, nothing accepted
+        let accepted = HelloExtensions::negotiate(&HelloExtensions::default(), &supported);
+        assert!(accepted.is_empty());
+    }
+
+    #[test]
+    fn test_unoffered_extensions_refused() {
+        let offered = HelloExtensions {
+            max_streams: Some(16),
+            ..HelloExtensions::default()
+        };
+        let refused = [
+            HelloExtensions {
+                fec: true,
+                ..HelloExtensions::default()
+            },
+            HelloExtensions {
+                early_data: true,
+                ..HelloExtensions::default()
+            },
+            HelloExtensions {
+                max_streams: Some(17),
+                ..HelloExtensions::default()
+            },
+            HelloExtensions {
+                gso_max_size: Some(65535),
+                ..HelloExtensions::default()
+            },
+            HelloExtensions {
+                compression: vec!["deflate".to_string()],
+                ..HelloExtensions::default()
+            },
+            HelloExtensions {
+                unknown: BTreeMap::from([("future".to_string(), Value::Null)]),
+                ..HelloExtensions::default()
+            },
+        ];
+        for accepted in refused {
+            assert!(accepted.check_accepted(&offered).is_err(), "{:?}", accepted);
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let json = serde_json::to_string(&offer()).unwrap();
+        let decoded: HelloExtensions = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, offer());
+        assert_eq!(serde_json::to_string(&HelloExtensions::default()).unwrap(), "{}");
+
+        // Known extensions must have the right shape
+        assert!(serde_json::from_str::<HelloExtensions>(r#"{"max_streams":"many"}"#).is_err());
+        assert!(serde_json::from_str::<HelloExtensions>(r#"{"compression":"zstd"}"#).is_err());
+    }
+}
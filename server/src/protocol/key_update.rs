@@ -0,0 +1,46 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LostLoveError, Result};
+
+/// Payload of a `PacketType::KeyUpdate` packet
+///
+/// Announces that the sender moved to the keys of `epoch`. The peer rotates
+/// to the same epoch and answers with a KeyUpdate carrying it; a KeyUpdate
+/// for the epoch the receiver is already at is such an answer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyUpdate {
+    pub epoch: u64,
+}
+
+impl KeyUpdate {
+    pub fn new(epoch: u64) -> Self {
+        Self { epoch }
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Network(format!("Invalid key update: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_update_roundtrip() {
+        let update = KeyUpdate::new(3);
+        let bytes = update.to_bytes().unwrap();
+
+        assert_eq!(&bytes[..], br#"{"epoch":3}"#);
+        assert_eq!(KeyUpdate::from_bytes(&bytes).unwrap(), update);
+        assert!(KeyUpdate::from_bytes(b"{}").is_err());
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::net::{IpAddr, ToSocketAddrs};
+
+use super::client_profile::{ClientProfile, ProfileError};
+
+/// Name of the nftables table and pf anchor holding the rules
+pub const LOCKDOWN_TABLE: &str = "llp_lockdown";
+
+/// Private, link-local and unique local ranges let through with `allow_lan`
+const LAN_V4: &str = "10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, 169.254.0.0/16";
+const LAN_V6: &str = "fc00::/7, fe80::/10";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AllowedProtocol {
+    Tcp,
+    Udp,
+    /// Echo requests, as the ICMP transport sends
+    Icmp,
+}
+
+/// A destination outside the tunnel the client must still reach
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Allowed {
+    pub protocol: AllowedProtocol,
+    pub address: IpAddr,
+    /// Inclusive, unused for ICMP
+    pub ports: (u16, u16),
+}
+
+/// Kill switch of a client profile: while the tunnel is supposed to be up,
+/// nothing but the tunnel interface and the server's own addresses
+///
+/// Endpoint hosts are resolved once, when the rules are built, so the
+/// client needs no DNS outside the tunnel while they are installed.
+/// Clients install them when connecting, keep them through reconnects and
+/// remove them only when the user disconnects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lockdown {
+    pub user: String,
+    /// Tunnel interface all other traffic must use
+    pub interface: String,
+    pub allow_lan: bool,
+    pub allowed: BTreeSet<Allowed>,
+    /// Endpoints the rules can't express, e.g. the DNS transport's zone
+    pub skipped: Vec<String>,
+}
+
+impl Lockdown {
+    /// Rules letting `profile`'s client reach its endpoints, descriptor and
+    /// knock port besides `interface`
+    pub fn from_profile(profile: &ClientProfile, interface: &str) -> Result<Self, ProfileError> {
+        let mut lockdown = Self {
+            user: profile.user.clone(),
+            interface: interface.to_string(),
+            allow_lan: profile.lockdown.as_ref().is_some_and(|lockdown| lockdown.allow_lan),
+            allowed: BTreeSet::new(),
+            skipped: Vec::new(),
+        };
+
+        let mut hosts = Vec::new();
+        for endpoint in &profile.endpoints {
+            let (transport, host, port) =
+                split_endpoint(endpoint).ok_or_else(|| ProfileError(format!("Invalid endpoint {:?}", endpoint)))?;
+            let protocol = match transport {
+                "tcp" => AllowedProtocol::Tcp,
+                "udp" => AllowedProtocol::Udp,
+                "icmp" => AllowedProtocol::Icmp,
+                _ => {
+                    lockdown.skipped.push(endpoint.clone());
+                    continue;
+                }
+            };
+            lockdown.allow(protocol, host, (port, port))?;
+            if !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+
+        // Hopping and knock ports aren't endpoints but go to the same hosts
+        for host in hosts {
+            if let Some(hopping) = &profile.port_hopping {
+                lockdown.allow(AllowedProtocol::Udp, host, (hopping.port_min, hopping.port_max))?;
+            }
+            if let Some(knock) = &profile.knock {
+                lockdown.allow(AllowedProtocol::Udp, host, (knock.port, knock.port))?;
+            }
+        }
+        if let Some(url) = &profile.descriptor_url {
+            let (host, port) = url
+                .strip_prefix("http://")
+                .and_then(|rest| split_host_port(rest.split('/').next().unwrap_or_default()))
+                .ok_or_else(|| ProfileError(format!("Invalid descriptor URL {:?}", url)))?;
+            lockdown.allow(AllowedProtocol::Tcp, host, (port.unwrap_or(80), port.unwrap_or(80)))?;
+        }
+
+        Ok(lockdown)
+    }
+
+    fn allow(&mut self, protocol: AllowedProtocol, host: &str, ports: (u16, u16)) -> Result<(), ProfileError> {
+        let addresses = (host, ports.0)
+            .to_socket_addrs()
+            .map_err(|e| ProfileError(format!("Failed to resolve {}: {}", host, e)))?;
+        for address in addresses {
+            self.allowed.insert(Allowed {
+                protocol,
+                address: address.ip(),
+                ports,
+            });
+        }
+        Ok(())
+    }
+
+    /// Ruleset for `nft -f`, replacing an earlier one atomically
+    pub fn nftables(&self) -> String {
+        let mut rules = String::new();
+        let _ = writeln!(rules, "#!/usr/sbin/nft -f");
+        self.header(&mut rules, &format!("nft delete table inet {}", LOCKDOWN_TABLE));
+        // Declaring the table first lets the delete succeed on a first load
+        let _ = writeln!(rules, "table inet {0}\ndelete table inet {0}\n", LOCKDOWN_TABLE);
+        let _ = writeln!(rules, "table inet {} {{", LOCKDOWN_TABLE);
+
+        rules.push_str("    chain output {\n        type filter hook output priority 0; policy drop;\n");
+        let _ = writeln!(rules, "        oifname \"lo\" accept");
+        let _ = writeln!(rules, "        oifname \"{}\" accept", self.interface);
+        rules.push_str("        ct state established,related accept\n");
+        rules.push_str("        udp sport 68 udp dport 67 accept\n");
+        rules.push_str("        icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept\n");
+        for allowed in &self.allowed {
+            let family = if allowed.address.is_ipv4() { "ip" } else { "ip6" };
+            let rule = match allowed.protocol {
+                AllowedProtocol::Tcp => format!("tcp dport {}", nft_ports(allowed.ports)),
+                AllowedProtocol::Udp => format!("udp dport {}", nft_ports(allowed.ports)),
+                AllowedProtocol::Icmp if allowed.address.is_ipv4() => "icmp type echo-request".to_string(),
+                AllowedProtocol::Icmp => "icmpv6 type echo-request".to_string(),
+            };
+            let _ = writeln!(rules, "        {} daddr {} {} accept", family, allowed.address, rule);
+        }
+        if self.allow_lan {
+            let _ = writeln!(rules, "        ip daddr {{ {} }} accept", LAN_V4);
+            let _ = writeln!(rules, "        ip6 daddr {{ {} }} accept", LAN_V6);
+        }
+        rules.push_str("    }\n\n");
+
+        rules.push_str("    chain input {\n        type filter hook input priority 0; policy drop;\n");
+        let _ = writeln!(rules, "        iifname \"lo\" accept");
+        let _ = writeln!(rules, "        iifname \"{}\" accept", self.interface);
+        rules.push_str("        ct state established,related accept\n");
+        rules.push_str("        udp sport 67 udp dport 68 accept\n");
+        rules.push_str("        icmpv6 type { nd-router-advert, nd-neighbor-solicit, nd-neighbor-advert } accept\n");
+        if self.allow_lan {
+            let _ = writeln!(rules, "        ip saddr {{ {} }} accept", LAN_V4);
+            let _ = writeln!(rules, "        ip6 saddr {{ {} }} accept", LAN_V6);
+        }
+        rules.push_str("    }\n}\n");
+        rules
+    }
+
+    /// Rules for `pfctl -a llp_lockdown -f`, under an anchor pf.conf refers to
+    pub fn pf(&self) -> String {
+        let mut rules = String::new();
+        self.header(&mut rules, &format!("pfctl -a {} -F all", LOCKDOWN_TABLE));
+        let _ = writeln!(
+            rules,
+            "# Needs `anchor \"{}\"` in pf.conf and pf enabled (pfctl -e).\n",
+            LOCKDOWN_TABLE
+        );
+        rules.push_str("block drop all\n");
+        rules.push_str("pass quick on lo0 all\n");
+        let _ = writeln!(rules, "pass quick on {} all", self.interface);
+        rules.push_str("pass out quick inet proto udp from any port 68 to any port 67\n");
+        rules.push_str("pass quick inet6 proto icmp6 icmp6-type { routersol, routeradv, neighbrsol, neighbradv }\n");
+        for allowed in &self.allowed {
+            let family = if allowed.address.is_ipv4() { "inet" } else { "inet6" };
+            let rule = match allowed.protocol {
+                AllowedProtocol::Tcp => format!("proto tcp to {} port {}", allowed.address, pf_ports(allowed.ports)),
+                AllowedProtocol::Udp => format!("proto udp to {} port {}", allowed.address, pf_ports(allowed.ports)),
+                AllowedProtocol::Icmp if allowed.address.is_ipv4() => {
+                    format!("proto icmp to {} icmp-type echoreq", allowed.address)
+                }
+                AllowedProtocol::Icmp => format!("proto icmp6 to {} icmp6-type echoreq", allowed.address),
+            };
+            let _ = writeln!(rules, "pass out quick {} {}", family, rule);
+        }
+        if self.allow_lan {
+            let _ = writeln!(rules, "pass quick inet from any to {{ {} }}", LAN_V4);
+            let _ = writeln!(rules, "pass quick inet6 from any to {{ {} }}", LAN_V6);
+        }
+        rules
+    }
+
+    fn header(&self, rules: &mut String, remove: &str) {
+        let _ = writeln!(
+            rules,
+            "# LostLove kill switch for {}, generated by `llpctl lockdown-rules`",
+            self.user
+        );
+        let _ = writeln!(
+            rules,
+            "# Only {} and the server's own addresses are reachable.",
+            self.interface
+        );
+        let _ = writeln!(rules, "# Remove with: {}", remove);
+        for skipped in &self.skipped {
+            let _ = writeln!(rules, "# Not covered: {}", skipped);
+        }
+    }
+}
+
+/// Transport, host and port of `transport://host:port`
+fn split_endpoint(endpoint: &str) -> Option<(&str, &str, u16)> {
+    let (transport, rest) = endpoint.split_once("://")?;
+    let (host, port) = split_host_port(rest)?;
+    Some((transport, host, port?))
+}
+
+/// `host`, `host:port`, `[v6]:port` or a bare IPv6 address followed by `:port`
+fn split_host_port(authority: &str) -> Option<(&str, Option<u16>)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, port) = rest.split_once(']')?;
+        let port = match port {
+            "" => None,
+            port => Some(port.strip_prefix(':')?.parse().ok()?),
+        };
+        return Some((host, port));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host, Some(port.parse().ok()?))),
+        None => Some((authority, None)),
+    }
+    .filter(|(host, _)| !host.is_empty())
+}
+
+fn nft_ports((first, last): (u16, u16)) -> String {
+    if first == last {
+        first.to_string()
+    } else {
+        format!("{}-{}", first, last)
+    }
+}
+
+fn pf_ports((first, last): (u16, u16)) -> String {
+    if first == last {
+        first.to_string()
+    } else {
+        format!("{}:{}", first, last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::client_profile::{HoppingProfile, KnockProfile, LockdownProfile};
+    use super::*;
+
+    fn profile() -> ClientProfile {
+        ClientProfile {
+            user: "alice".to_string(),
+            endpoints: vec![
+                "tcp://203.0.113.5:8443".to_string(),
+                "icmp://203.0.113.5:0".to_string(),
+                "tcp://2001:db8::5:8443".to_string(),
+                "dns://t.example.org:53".to_string(),
+            ],
+            mtu: 1400,
+            routes: vec!["0.0.0.0/0".to_string()],
+            dns: Vec::new(),
+            server_key: None,
+            descriptor_url: Some("http://203.0.113.5:8080/descriptor.json".to_string()),
+            descriptor_key: None,
+            management_key: None,
+            knock: Some(KnockProfile {
+                port: 7000,
+                secret: "knock".to_string(),
+            }),
+            port_hopping: Some(HoppingProfile {
+                secret: "hop".to_string(),
+                port_min: 40000,
+                port_max: 40100,
+                interval: 30,
+            }),
+            lockdown: Some(LockdownProfile { allow_lan: true }),
+            lan: None,
+            split: None,
+        }
+    }
+
+    #[test]
+    fn test_allowed_destinations() {
+        let lockdown = Lockdown::from_profile(&profile(), "llp0").unwrap();
+        let v4: IpAddr = "203.0.113.5".parse().unwrap();
+        let allowed: Vec<_> = lockdown
+            .allowed
+            .iter()
+            .filter(|allowed| allowed.address == v4)
+            .map(|allowed| (allowed.protocol, allowed.ports))
+            .collect();
+        assert_eq!(
+            allowed,
+            [
+                (AllowedProtocol::Tcp, (8080, 8080)),
+                (AllowedProtocol::Tcp, (8443, 8443)),
+                (AllowedProtocol::Udp, (7000, 7000)),
+                (AllowedProtocol::Udp, (40000, 40100)),
+                (AllowedProtocol::Icmp, (0, 0)),
+            ]
+        );
+        // Knock and hops go to every endpoint host, here one per family
+        assert_eq!(lockdown.allowed.len(), 8);
+        assert!(lockdown.allow_lan);
+        assert_eq!(lockdown.skipped, ["dns://t.example.org:53"]);
+
+        let mut broken = profile();
+        broken.endpoints.push("tcp://203.0.113.6".to_string());
+        assert!(Lockdown::from_profile(&broken, "llp0").is_err());
+    }
+
+    #[test]
+    fn test_nftables_rules() {
+        let rules = Lockdown::from_profile(&profile(), "llp0").unwrap().nftables();
+        assert!(rules.contains("table inet llp_lockdown\ndelete table inet llp_lockdown\n"));
+        assert!(rules.contains("type filter hook output priority 0; policy drop;"));
+        assert!(rules.contains("        oifname \"llp0\" accept\n"));
+        assert!(rules.contains("        ip daddr 203.0.113.5 tcp dport 8443 accept\n"));
+        assert!(rules.contains("        ip daddr 203.0.113.5 udp dport 40000-40100 accept\n"));
+        assert!(rules.contains("        ip daddr 203.0.113.5 icmp type echo-request accept\n"));
+        assert!(rules.contains("        ip6 daddr 2001:db8::5 tcp dport 8443 accept\n"));
+        assert!(rules.contains("        ip daddr { 10.0.0.0/8,"));
+        assert!(rules.contains("# Not covered: dns://t.example.org:53\n"));
+        assert_eq!(rules.matches('{').count(), rules.matches('}').count());
+    }
+
+    #[test]
+    fn test_pf_rules() {
+        let mut profile = profile();
+        profile.lockdown = None;
+        let rules = Lockdown::from_profile(&profile, "utun7").unwrap().pf();
+        assert!(rules.starts_with("# LostLove kill switch for alice"));
+        assert!(rules.contains("block drop all\n"));
+        assert!(rules.contains("pass quick on utun7 all\n"));
+        assert!(rules.contains("pass out quick inet proto udp to 203.0.113.5 port 40000:40100\n"));
+        assert!(rules.contains("pass out quick inet6 proto tcp to 2001:db8::5 port 8443\n"));
+        assert!(!rules.contains("192.168.0.0/16"));
+    }
+}
@@ -0,0 +1,279 @@
+use bytes::Bytes;
+use hkdf::hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::IpAddr;
+use zeroize::Zeroizing;
+
+use crate::crypto::derive_keys;
+use crate::error::{LostLoveError, Result};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Seconds a signed command stays valid, against replays of recorded ones
+pub const COMMAND_VALIDITY: u64 = 60;
+
+/// Seconds a command's `issued_at` may be ahead of the client's clock
+pub const CLOCK_SKEW: u64 = 30;
+
+/// HKDF info of a user's management key, followed by the user name
+const USER_KEY_INFO: &[u8] = b"LLP-v1-management-user:";
+
+/// Key a user's commands are signed with, HKDF of the management key and
+/// the user name
+///
+/// Each profile carries only its own user's key, so a client that leaks it
+/// can't sign commands other users' clients accept.
+pub fn user_key(key: &[u8], user: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let mut info = USER_KEY_INFO.to_vec();
+    info.extend_from_slice(user.as_bytes());
+    derive_keys(key, b"", &info, 32)
+}
+
+/// What the operator asks of a client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ManagementCommand {
+    /// Report the client's view of the tunnel: version, addresses, routes, recent errors
+    Diagnostics,
+    /// Drop the session and connect again after `delay_secs`
+    Reconnect {
+        #[serde(default)]
+        delay_secs: u64,
+    },
+    /// Use these routes and DNS servers instead of the pushed ones until reconnecting
+    Settings {
+        #[serde(default)]
+        routes: Vec<String>,
+        #[serde(default)]
+        dns: Vec<IpAddr>,
+    },
+}
+
+/// A command for one session, as signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManagementOrder {
+    pub id: u64,
+    /// Session the command is for; clients refuse those for other sessions
+    pub session_id: String,
+    /// Unix time the command was issued and stops being valid
+    pub issued_at: u64,
+    pub expires_at: u64,
+    #[serde(flatten)]
+    pub command: ManagementCommand,
+}
+
+/// Command as sent on the `manage` channel: its JSON and an HMAC-SHA256 of
+/// exactly those bytes under the session user's key from [`user_key`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedOrder {
+    pub payload: String,
+    /// Hex HMAC-SHA256 of `payload`
+    pub signature: String,
+}
+
+impl SignedOrder {
+    pub fn sign(order: &ManagementOrder, key: &[u8]) -> Result<Self> {
+        let payload = serde_json::to_string(order)
+            .map_err(|e| LostLoveError::Admin(format!("Serialization error: {}", e)))?;
+        let signature = hex::encode(mac(key, payload.as_bytes()).finalize().into_bytes());
+        Ok(Self { payload, signature })
+    }
+
+    /// Check the signature, session, issue time and expiry at unix time `now`
+    ///
+    /// Doesn't catch a command replayed while still valid; clients check
+    /// through [`OrderVerifier`], which also refuses `id`s already seen.
+    pub fn verify(&self, key: &[u8], session_id: &str, now: u64) -> Result<ManagementOrder> {
+        let signature = hex::decode(&self.signature).map_err(|_| LostLoveError::AuthFailed {
+            reason: "management signature is not hex".to_string(),
+        })?;
+        mac(key, self.payload.as_bytes())
+            .verify_slice(&signature)
+            .map_err(|_| LostLoveError::AuthFailed {
+                reason: "management signature mismatch".to_string(),
+            })?;
+
+        let order: ManagementOrder = serde_json::from_str(&self.payload)
+            .map_err(|e| LostLoveError::Admin(format!("Invalid management command: {}", e)))?;
+        if order.session_id != session_id {
+            return Err(LostLoveError::AuthFailed {
+                reason: "management command is for another session".to_string(),
+            });
+        }
+        if order.issued_at > now.saturating_add(CLOCK_SKEW) {
+            return Err(LostLoveError::AuthFailed {
+                reason: "management command issued in the future".to_string(),
+            });
+        }
+        if now >= order.expires_at {
+            return Err(LostLoveError::AuthFailed {
+                reason: "management command expired".to_string(),
+            });
+        }
+        Ok(order)
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Admin(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data).map_err(|e| LostLoveError::Admin(format!("Invalid signed command: {}", e)))
+    }
+}
+
+/// Client side check of one session's commands
+///
+/// Keeps the highest `id` accepted so far and refuses commands at or below
+/// it, so a recorded command can't be replayed while it's still valid. The
+/// server numbers commands in increasing order.
+pub struct OrderVerifier {
+    key: Zeroizing<Vec<u8>>,
+    session_id: String,
+    last_id: Option<u64>,
+}
+
+impl OrderVerifier {
+    pub fn new(key: &[u8], session_id: &str) -> Self {
+        Self {
+            key: Zeroizing::new(key.to_vec()),
+            session_id: session_id.to_string(),
+            last_id: None,
+        }
+    }
+
+    /// Verify `signed` at unix time `now` and remember its `id`
+    pub fn verify(&mut self, signed: &SignedOrder, now: u64) -> Result<ManagementOrder> {
+        let order = signed.verify(&self.key, &self.session_id, now)?;
+        if self.last_id.is_some_and(|last| order.id <= last) {
+            return Err(LostLoveError::AuthFailed {
+                reason: "management command replayed".to_string(),
+            });
+        }
+        self.last_id = Some(order.id);
+        Ok(order)
+    }
+}
+
+/// A client's answer to a command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManagementReply {
+    pub id: u64,
+    /// Whether the client carried the command out
+    pub ok: bool,
+    /// Diagnostics, or why the command was refused
+    #[serde(default)]
+    pub output: String,
+}
+
+impl ManagementReply {
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Admin(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data).map_err(|e| LostLoveError::Admin(format!("Invalid management reply: {}", e)))
+    }
+}
+
+fn mac(key: &[u8], message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"management key for tests";
+
+    fn order() -> ManagementOrder {
+        ManagementOrder {
+            id: 7,
+            session_id: "abc".to_string(),
+            issued_at: 1_000,
+            expires_at: 1_000 + COMMAND_VALIDITY,
+            command: ManagementCommand::Settings {
+                routes: vec!["10.20.0.0/16".to_string()],
+                dns: vec!["10.20.0.53".parse().unwrap()],
+            },
+        }
+    }
+
+    #[test]
+    fn test_signed_order_roundtrip() {
+        let signed = SignedOrder::sign(&order(), KEY).unwrap();
+        assert!(signed.payload.contains(r#""command":"settings""#), "{}", signed.payload);
+
+        let parsed = SignedOrder::from_bytes(&signed.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.verify(KEY, "abc", 1_010).unwrap(), order());
+    }
+
+    #[test]
+    fn test_forged_or_misdirected_orders_refused() {
+        let signed = SignedOrder::sign(&order(), KEY).unwrap();
+        assert!(signed.verify(b"other key", "abc", 1_010).is_err());
+        assert!(signed.verify(KEY, "def", 1_010).is_err());
+        assert!(signed.verify(KEY, "abc", 1_060).is_err());
+
+        let mut tampered = signed.clone();
+        tampered.payload = tampered.payload.replace("10.20.0.0/16", "0.0.0.0/0");
+        let err = tampered.verify(KEY, "abc", 1_010).unwrap_err();
+        assert_eq!(err.code(), "auth_failed");
+    }
+
+    #[test]
+    fn test_orders_from_the_future_refused() {
+        let signed = SignedOrder::sign(&order(), KEY).unwrap();
+        assert!(signed.verify(KEY, "abc", 1_000 - CLOCK_SKEW).is_ok());
+        let err = signed.verify(KEY, "abc", 1_000 - CLOCK_SKEW - 1).unwrap_err();
+        assert!(err.to_string().contains("future"), "{}", err);
+    }
+
+    #[test]
+    fn test_replayed_orders_refused() {
+        let mut verifier = OrderVerifier::new(KEY, "abc");
+        let signed = SignedOrder::sign(&order(), KEY).unwrap();
+        assert_eq!(verifier.verify(&signed, 1_010).unwrap().id, 7);
+
+        let err = verifier.verify(&signed, 1_020).unwrap_err();
+        assert!(err.to_string().contains("replayed"), "{}", err);
+        let older = SignedOrder::sign(&ManagementOrder { id: 6, ..order() }, KEY).unwrap();
+        assert!(verifier.verify(&older, 1_020).is_err());
+
+        let newer = SignedOrder::sign(&ManagementOrder { id: 8, ..order() }, KEY).unwrap();
+        assert_eq!(verifier.verify(&newer, 1_020).unwrap().id, 8);
+
+        // A refused command doesn't move the highest id
+        let forged = SignedOrder::sign(&ManagementOrder { id: 100, ..order() }, b"other key").unwrap();
+        assert!(verifier.verify(&forged, 1_020).is_err());
+        let next = SignedOrder::sign(&ManagementOrder { id: 9, ..order() }, KEY).unwrap();
+        assert!(verifier.verify(&next, 1_020).is_ok());
+    }
+
+    #[test]
+    fn test_user_keys_differ() {
+        let alice = user_key(KEY, "alice").unwrap();
+        assert_eq!(alice.len(), 32);
+        assert_eq!(alice, user_key(KEY, "alice").unwrap());
+        assert_ne!(alice, user_key(KEY, "bob").unwrap());
+        assert_ne!(alice, user_key(b"other key", "alice").unwrap());
+
+        let signed = SignedOrder::sign(&order(), &user_key(KEY, "bob").unwrap()).unwrap();
+        assert!(signed.verify(&alice, "abc", 1_010).is_err());
+    }
+
+    #[test]
+    fn test_reply_defaults() {
+        let reply = ManagementReply::from_bytes(br#"{"id":7,"ok":true}"#).unwrap();
+        assert_eq!(reply.output, "");
+        let command: ManagementCommand = serde_json::from_str(r#"{"command":"reconnect"}"#).unwrap();
+        assert_eq!(command, ManagementCommand::Reconnect { delay_secs: 0 });
+    }
+}
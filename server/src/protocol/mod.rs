@@ -1,7 +1,41 @@
 pub mod packet;
 pub mod handshake;
 pub mod stream;
+pub mod error_message;
+pub mod client_config;
+pub mod disconnect;
+pub mod route_announce;
+pub mod peer_signal;
+pub mod address_discovery;
+pub mod transport_attach;
+pub mod key_update;
+pub mod quality_report;
+pub mod path_probe;
+pub mod stream_open;
+pub mod state_machine;
+pub mod wire;
+pub mod dissector;
+pub mod descriptor;
+pub mod management;
+pub mod portal;
+pub mod client_profile;
+// lockdown.rs and split_tunnel.rs are client side, built only into llpctl
+pub mod registry;
+pub mod hello_extensions;
 
-pub use packet::{Packet, PacketHeader, PacketType};
-pub use handshake::{Handshake, HandshakeState};
-pub use stream::StreamId;
+pub use packet::{Packet, PacketHeader, PacketType, HEADER_SIZE, MAX_PAYLOAD_SIZE, PROTOCOL_ID};
+pub use handshake::{CipherSuite, Handshake, HandshakeFailureCategory, HandshakeMessage};
+pub use stream::{StreamId, StreamMode};
+pub use error_message::{ErrorCode, ErrorMessage};
+pub use client_config::ClientConfig;
+pub use disconnect::{CutoffWarning, DisconnectMessage, DisconnectReason};
+pub use route_announce::{AnnouncedRoute, RouteAnnouncement};
+pub use peer_signal::{FallbackReason, PeerSignal};
+pub use address_discovery::AddressReport;
+pub use transport_attach::TransportAttach;
+pub use key_update::KeyUpdate;
+pub use quality_report::QualityReport;
+pub use portal::{PortalReport, PortalState};
+pub use path_probe::PathProbe;
+pub use stream_open::StreamOpen;
+pub use hello_extensions::HelloExtensions;
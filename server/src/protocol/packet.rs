@@ -2,23 +2,7 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::time::{SystemTime, UNIX_EPOCH};
 use crate::error::{LostLoveError, Result};
 
-/// Protocol identifier
-pub const PROTOCOL_ID: u16 = 0x4C4C; // "LL" in hex (LostLove)
-
-/// Header size in bytes
-pub const HEADER_SIZE: usize = 24;
-
-/// Packet types
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum PacketType {
-    Data = 0x01,
-    Ack = 0x02,
-    HandshakeInit = 0x03,
-    HandshakeResponse = 0x04,
-    KeepAlive = 0x05,
-    Disconnect = 0x06,
-}
+pub use crate::protocol::wire::{PacketType, FLAG_KEY_PHASE, HEADER_SIZE, MAX_PAYLOAD_SIZE, PROTOCOL_ID};
 
 impl PacketType {
     pub fn from_u8(value: u8) -> Result<Self> {
@@ -29,13 +13,24 @@ impl PacketType {
             0x04 => Ok(PacketType::HandshakeResponse),
             0x05 => Ok(PacketType::KeepAlive),
             0x06 => Ok(PacketType::Disconnect),
+            0x07 => Ok(PacketType::Error),
+            0x08 => Ok(PacketType::ConfigUpdate),
+            0x09 => Ok(PacketType::Warning),
+            0x0A => Ok(PacketType::RouteAnnounce),
+            0x0B => Ok(PacketType::PeerSignal),
+            0x0C => Ok(PacketType::AddressDiscovery),
+            0x0D => Ok(PacketType::TransportAttach),
+            0x0E => Ok(PacketType::KeyUpdate),
+            0x0F => Ok(PacketType::QualityReport),
+            0x10 => Ok(PacketType::PathProbe),
+            0x11 => Ok(PacketType::StreamOpen),
             _ => Err(LostLoveError::InvalidPacketType(value)),
         }
     }
 }
 
 /// Packet header structure
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PacketHeader {
     pub protocol_id: u16,
     pub packet_type: PacketType,
@@ -43,6 +38,8 @@ pub struct PacketHeader {
     pub sequence_number: u64,
     pub timestamp: u64,
     pub flags: u8,
+    /// Payload bytes following the header
+    pub length: u16,
     pub checksum: u16,
 }
 
@@ -56,6 +53,7 @@ impl PacketHeader {
             sequence_number: 0,
             timestamp: current_timestamp(),
             flags: 0,
+            length: 0,
             checksum: 0,
         }
     }
@@ -68,6 +66,7 @@ impl PacketHeader {
         buf.put_u64(self.sequence_number);
         buf.put_u64(self.timestamp);
         buf.put_u8(self.flags);
+        buf.put_u16(self.length);
         buf.put_u16(self.checksum);
     }
 
@@ -90,6 +89,7 @@ impl PacketHeader {
         let sequence_number = buf.get_u64();
         let timestamp = buf.get_u64();
         let flags = buf.get_u8();
+        let length = buf.get_u16();
         let checksum = buf.get_u16();
 
         Ok(Self {
@@ -99,14 +99,27 @@ impl PacketHeader {
             sequence_number,
             timestamp,
             flags,
+            length,
             checksum,
         })
     }
 
+    /// Mark the packet as encrypted under the keys of `epoch`
+    pub fn set_key_epoch(&mut self, epoch: u64) {
+        if epoch & 1 == 1 {
+            self.flags |= FLAG_KEY_PHASE;
+        } else {
+            self.flags &= !FLAG_KEY_PHASE;
+        }
+    }
+
+    /// Low bit of the key epoch the packet is encrypted under
+    pub fn key_phase(&self) -> bool {
+        self.flags & FLAG_KEY_PHASE != 0
+    }
+
     /// Calculate CRC16 checksum
     pub fn calculate_checksum(&self, payload: &[u8]) -> u16 {
-        let mut crc = 0xFFFFu16;
-
         // Hash header fields
         let mut data = Vec::new();
         data.extend_from_slice(&self.protocol_id.to_be_bytes());
@@ -115,20 +128,9 @@ impl PacketHeader {
         data.extend_from_slice(&self.sequence_number.to_be_bytes());
         data.extend_from_slice(&self.timestamp.to_be_bytes());
         data.push(self.flags);
+        data.extend_from_slice(&self.length.to_be_bytes());
 
-        // CRC16-CCITT algorithm
-        for byte in data.iter().chain(payload.iter()) {
-            crc ^= (*byte as u16) << 8;
-            for _ in 0..8 {
-                if (crc & 0x8000) != 0 {
-                    crc = (crc << 1) ^ 0x1021;
-                } else {
-                    crc <<= 1;
-                }
-            }
-        }
-
-        crc
+        crc16(data.iter().chain(payload))
     }
 
     /// Verify checksum
@@ -138,8 +140,24 @@ impl PacketHeader {
     }
 }
 
+/// CRC16-CCITT of header fields and payload, as carried in the checksum field
+pub fn crc16<'a>(data: impl IntoIterator<Item = &'a u8>) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if (crc & 0x8000) != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
 /// Complete packet structure
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Packet {
     pub header: PacketHeader,
     pub payload: Bytes,
@@ -148,10 +166,7 @@ pub struct Packet {
 impl Packet {
     /// Create a new packet
     pub fn new(packet_type: PacketType, payload: Bytes) -> Self {
-        let mut header = PacketHeader::new(packet_type);
-        header.checksum = header.calculate_checksum(&payload);
-
-        Self { header, payload }
+        Self::with_header(PacketHeader::new(packet_type), payload)
     }
 
     /// Create a packet with specific stream ID and sequence number
@@ -164,6 +179,16 @@ impl Packet {
         let mut header = PacketHeader::new(packet_type);
         header.stream_id = stream_id;
         header.sequence_number = sequence_number;
+
+        Self::with_header(header, payload)
+    }
+
+    /// Packet under `header`, its length and checksum filled in
+    ///
+    /// Payloads are at most `MAX_PAYLOAD_SIZE` bytes, the most the length
+    /// field holds.
+    pub fn with_header(mut header: PacketHeader, payload: Bytes) -> Self {
+        header.length = u16::try_from(payload.len()).expect("payload longer than MAX_PAYLOAD_SIZE");
         header.checksum = header.calculate_checksum(&payload);
 
         Self { header, payload }
@@ -180,6 +205,12 @@ impl Packet {
     /// Deserialize packet from bytes
     pub fn deserialize(mut buf: impl Buf) -> Result<Self> {
         let header = PacketHeader::deserialize(&mut buf)?;
+        if buf.remaining() != header.length as usize {
+            return Err(LostLoveError::LengthMismatch {
+                length: header.length as usize,
+                actual: buf.remaining(),
+            });
+        }
         let payload = buf.copy_to_bytes(buf.remaining());
 
         let packet = Self { header, payload };
@@ -208,6 +239,17 @@ impl Packet {
                 | PacketType::HandshakeResponse
                 | PacketType::KeepAlive
                 | PacketType::Disconnect
+                | PacketType::Error
+                | PacketType::ConfigUpdate
+                | PacketType::Warning
+                | PacketType::RouteAnnounce
+                | PacketType::PeerSignal
+                | PacketType::AddressDiscovery
+                | PacketType::TransportAttach
+                | PacketType::KeyUpdate
+                | PacketType::QualityReport
+                | PacketType::PathProbe
+                | PacketType::StreamOpen
         )
     }
 }
@@ -228,6 +270,7 @@ mod tests {
     fn test_packet_type_conversion() {
         assert_eq!(PacketType::from_u8(0x01).unwrap(), PacketType::Data);
         assert_eq!(PacketType::from_u8(0x05).unwrap(), PacketType::KeepAlive);
+        assert_eq!(PacketType::from_u8(0x07).unwrap(), PacketType::Error);
         assert!(PacketType::from_u8(0xFF).is_err());
     }
 
@@ -273,4 +316,179 @@ mod tests {
 
         assert_eq!(buf.len(), HEADER_SIZE);
     }
+
+    /// Wire format pinned by an encoder independent of this one
+    const GOLDEN_VECTORS: &str = include_str!("testdata/packet_vectors.txt");
+
+    fn golden_vectors() -> Vec<(String, Packet, Vec<u8>)> {
+        GOLDEN_VECTORS
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let byte = |field: &str| u8::from_str_radix(field.trim_start_matches("0x"), 16).unwrap();
+                let payload = match fields[6] {
+                    "-" => Bytes::new(),
+                    payload => Bytes::from(hex::decode(payload).unwrap()),
+                };
+
+                let mut header = PacketHeader::new(PacketType::from_u8(byte(fields[1])).unwrap());
+                header.stream_id = fields[2].parse().unwrap();
+                header.sequence_number = fields[3].parse().unwrap();
+                header.timestamp = fields[4].parse().unwrap();
+                header.flags = byte(fields[5]);
+
+                (fields[0].to_string(), Packet::with_header(header, payload), hex::decode(fields[7]).unwrap())
+            })
+            .collect()
+    }
+
+    fn packet_types() -> Vec<PacketType> {
+        (0..=u8::MAX).filter_map(|value| PacketType::from_u8(value).ok()).collect()
+    }
+
+    /// Packets with every field random, reproducible through the fixed seed
+    fn arbitrary_packets(count: usize) -> Vec<Packet> {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let types = packet_types();
+        let mut rng = StdRng::seed_from_u64(165);
+        (0..count)
+            .map(|_| {
+                let len = rng.gen_range(0..600);
+                let payload: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                let header = PacketHeader {
+                    protocol_id: PROTOCOL_ID,
+                    packet_type: types[rng.gen_range(0..types.len())],
+                    stream_id: rng.gen(),
+                    sequence_number: rng.gen(),
+                    timestamp: rng.gen(),
+                    flags: rng.gen(),
+                    length: 0,
+                    checksum: 0,
+                };
+                Packet::with_header(header, Bytes::from(payload))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_golden_vectors() {
+        let vectors = golden_vectors();
+
+        // Every packet type is pinned, new ones need a vector too
+        for packet_type in packet_types() {
+            assert!(
+                vectors.iter().any(|(_, packet, _)| packet.header.packet_type == packet_type),
+                "no golden vector for {:?}",
+                packet_type
+            );
+        }
+
+        for (name, packet, wire) in &vectors {
+            assert_eq!(packet.serialize().to_vec(), *wire, "{}", name);
+            assert_eq!(Packet::deserialize(&wire[..]).unwrap(), *packet, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_property_round_trip() {
+        for packet in arbitrary_packets(1000) {
+            let wire = packet.serialize();
+            assert_eq!(wire.len(), packet.size());
+            assert_eq!(Packet::deserialize(wire).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_property_matches_field_layout() {
+        for packet in arbitrary_packets(200) {
+            let header = &packet.header;
+            let mut expected = Vec::new();
+            expected.extend_from_slice(&header.protocol_id.to_be_bytes());
+            expected.push(header.packet_type as u8);
+            expected.extend_from_slice(&header.stream_id.to_be_bytes());
+            expected.extend_from_slice(&header.sequence_number.to_be_bytes());
+            expected.extend_from_slice(&header.timestamp.to_be_bytes());
+            expected.push(header.flags);
+            expected.extend_from_slice(&(packet.payload.len() as u16).to_be_bytes());
+            expected.extend_from_slice(&header.checksum.to_be_bytes());
+            expected.extend_from_slice(&packet.payload);
+
+            assert_eq!(packet.serialize().to_vec(), expected);
+        }
+    }
+
+    #[test]
+    fn test_key_phase_follows_epoch() {
+        let mut header = PacketHeader::new(PacketType::Data);
+        header.flags = 0x01;
+
+        for epoch in [1, 2, 3, 0, u64::MAX] {
+            header.set_key_epoch(epoch);
+            assert_eq!(header.key_phase(), epoch % 2 == 1);
+            // Other flags are left alone
+            assert_eq!(header.flags & !FLAG_KEY_PHASE, 0x01);
+        }
+    }
+
+    #[test]
+    fn test_property_matches_wire_definitions() {
+        use crate::protocol::wire::header_layout;
+
+        for packet in arbitrary_packets(200) {
+            let header = &packet.header;
+            let wire = packet.serialize();
+            let field = |name: &str| -> u64 {
+                let (offset, field) = header_layout().find(|(_, field)| field.name == name).unwrap();
+                wire[offset..offset + field.size].iter().fold(0, |value, byte| value << 8 | *byte as u64)
+            };
+
+            // What the Wireshark dissector decodes must be what was sent
+            assert_eq!(field("protocol_id"), header.protocol_id as u64);
+            assert_eq!(field("packet_type"), header.packet_type as u64);
+            assert_eq!(field("stream_id"), header.stream_id as u64);
+            assert_eq!(field("sequence_number"), header.sequence_number);
+            assert_eq!(field("timestamp"), header.timestamp);
+            assert_eq!(field("flags"), header.flags as u64);
+            assert_eq!(field("length"), packet.payload.len() as u64);
+            assert_eq!(field("checksum"), header.checksum as u64);
+        }
+
+        for value in 0..=u8::MAX {
+            assert_eq!(PacketType::from_u8(value).ok(), PacketType::ALL.into_iter().find(|t| *t as u8 == value));
+        }
+    }
+
+    #[test]
+    fn test_property_corruption_rejected() {
+        for (i, packet) in arbitrary_packets(300).into_iter().enumerate() {
+            let wire = packet.serialize().to_vec();
+
+            // CRC-16 catches every single bit error, the other fields are checked directly
+            for bit in (i % 8..wire.len() * 8).step_by(wire.len().max(8) / 4 + 1) {
+                let mut corrupted = wire.clone();
+                corrupted[bit / 8] ^= 1 << (bit % 8);
+                assert!(Packet::deserialize(&corrupted[..]).is_err(), "bit {} of {:?}", bit, packet);
+            }
+
+            for len in 0..HEADER_SIZE {
+                assert!(Packet::deserialize(&wire[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_length_must_match_payload() {
+        let wire = Packet::new(PacketType::Data, Bytes::from("test data")).serialize().to_vec();
+
+        let err = Packet::deserialize(&wire[..wire.len() - 1]).unwrap_err();
+        assert!(matches!(err, LostLoveError::LengthMismatch { length: 9, actual: 8 }));
+
+        let mut longer = wire.clone();
+        longer.push(0);
+        let err = Packet::deserialize(&longer[..]).unwrap_err();
+        assert!(matches!(err, LostLoveError::LengthMismatch { length: 9, actual: 10 }));
+    }
 }
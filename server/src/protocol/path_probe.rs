@@ -0,0 +1,54 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LostLoveError, Result};
+
+/// Payload of a `PacketType::PathProbe` packet
+///
+/// Only the server sends probes, padded with spaces after the JSON to the
+/// size being tested. The client echoes each probe back unchanged in a
+/// PathProbe of its own; the server never answers one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathProbe {
+    pub probe: u32,
+}
+
+impl PathProbe {
+    pub fn new(probe: u32) -> Self {
+        Self { probe }
+    }
+
+    /// Serialize probe, padded to `size` bytes if it is shorter
+    pub fn to_padded_bytes(&self, size: usize) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+
+        let mut payload = BytesMut::with_capacity(size.max(json.len()));
+        payload.put_slice(&json);
+        payload.put_bytes(b' ', size.saturating_sub(json.len()));
+        Ok(payload.freeze())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Network(format!("Invalid path probe: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_probe_padding() {
+        let probe = PathProbe::new(7);
+
+        let padded = probe.to_padded_bytes(64).unwrap();
+        assert_eq!(padded.len(), 64);
+        assert!(padded.starts_with(br#"{"probe":7} "#));
+        assert_eq!(PathProbe::from_bytes(&padded).unwrap(), probe);
+
+        assert_eq!(&probe.to_padded_bytes(0).unwrap()[..], br#"{"probe":7}"#);
+        assert!(PathProbe::from_bytes(b"{} x").is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::error::{LostLoveError, Result};
+
+/// Maximum UDP candidates a client may offer
+pub const MAX_CANDIDATES: usize = 8;
+
+/// Why two clients keep talking through the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FallbackReason {
+    /// No session holds the requested tunnel address
+    PeerNotFound,
+    /// The other client declined the offer
+    Declined,
+    /// The other client didn't answer in time
+    Timeout,
+}
+
+/// Payload of a `PacketType::PeerSignal` packet
+///
+/// Peers are named by their tunnel address. A client sends `Connect`; the
+/// server passes it on as `Offer`, and on an accepting `Answer` tells both
+/// clients to `Punch` at the same moment. Whenever a direct path isn't
+/// possible the server answers `Fallback` and traffic stays on the relay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum PeerSignal {
+    /// Ask to reach a peer directly (client -> server)
+    Connect {
+        peer: IpAddr,
+        candidates: Vec<SocketAddr>,
+    },
+    /// A peer wants to connect (server -> client)
+    Offer {
+        peer: IpAddr,
+        candidates: Vec<SocketAddr>,
+    },
+    /// Answer to an offer (client -> server)
+    Answer {
+        peer: IpAddr,
+        accept: bool,
+        #[serde(default)]
+        candidates: Vec<SocketAddr>,
+    },
+    /// Send UDP probes to the candidates from `punch_at` (Unix ms) on (server -> client)
+    Punch {
+        peer: IpAddr,
+        candidates: Vec<SocketAddr>,
+        punch_at: u64,
+    },
+    /// Keep using the server for this peer (server -> client)
+    Fallback { peer: IpAddr, reason: FallbackReason },
+    /// Whether hole punching gave a direct path (client -> server)
+    Outcome { peer: IpAddr, direct: bool },
+}
+
+impl PeerSignal {
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Network(format!("Invalid peer signal: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_signal_roundtrip() {
+        let signal = PeerSignal::Connect {
+            peer: "10.8.0.3".parse().unwrap(),
+            candidates: vec!["192.168.1.20:40000".parse().unwrap()],
+        };
+        let bytes = signal.to_bytes().unwrap();
+        assert_eq!(PeerSignal::from_bytes(&bytes).unwrap(), signal);
+
+        let json = br#"{"type": "fallback", "peer": "10.8.0.2", "reason": "peer-not-found"}"#;
+        assert_eq!(
+            PeerSignal::from_bytes(json).unwrap(),
+            PeerSignal::Fallback {
+                peer: "10.8.0.2".parse().unwrap(),
+                reason: FallbackReason::PeerNotFound,
+            }
+        );
+    }
+}
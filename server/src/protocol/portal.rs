@@ -0,0 +1,87 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LostLoveError, Result};
+
+/// What a client's captive portal probes found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PortalState {
+    /// A probe got its 204, the network is open
+    Open,
+    /// A probe got any other answer, typically a redirect to a sign-in page
+    Captive,
+    /// No probe got an answer
+    Offline,
+}
+
+impl PortalState {
+    pub const ALL: [PortalState; 3] = [
+        PortalState::Open,
+        PortalState::Captive,
+        PortalState::Offline,
+    ];
+
+    /// Value of the `state` label and in reports
+    pub fn name(self) -> &'static str {
+        match self {
+            PortalState::Open => "open",
+            PortalState::Captive => "captive",
+            PortalState::Offline => "offline",
+        }
+    }
+}
+
+/// Sent by the client on the `portal` channel whenever its probes find a
+/// different state, once the tunnel is up again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortalReport {
+    pub state: PortalState,
+    /// Probe URL whose answer decided the state
+    pub probe: String,
+    /// Where the portal redirected the probe, if it did
+    #[serde(default)]
+    pub location: Option<String>,
+    /// Unix seconds the client first found the portal, 0 if it found none
+    #[serde(default)]
+    pub detected_at: u64,
+    /// Seconds the client routed around the tunnel to sign in
+    #[serde(default)]
+    pub bypassed_secs: u64,
+}
+
+impl PortalReport {
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Network(format!("Invalid portal report: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_portal_report_roundtrip() {
+        let report = PortalReport {
+            state: PortalState::Captive,
+            probe: "http://cp.cloudflare.com/generate_204".to_string(),
+            location: Some("http://wifi.example.net/login".to_string()),
+            detected_at: 1_700_000_000,
+            bypassed_secs: 42,
+        };
+        let bytes = report.to_bytes().unwrap();
+        assert!(bytes.starts_with(br#"{"state":"captive""#));
+        assert_eq!(PortalReport::from_bytes(&bytes).unwrap(), report);
+
+        let open = PortalReport::from_bytes(br#"{"state":"open","probe":"http://a/"}"#).unwrap();
+        assert_eq!((open.location, open.bypassed_secs), (None, 0));
+        assert!(PortalReport::from_bytes(br#"{"state":"walled","probe":"http://a/"}"#).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LostLoveError, Result};
+
+/// Payload of a `PacketType::QualityReport` packet
+///
+/// Sent by the client every `quality_report_interval` seconds of its pushed
+/// config with what it observed since the previous report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QualityReport {
+    /// Seconds covered by this report
+    pub interval: u64,
+    /// Smoothed round trip time at the end of the interval, milliseconds
+    pub rtt_ms: u32,
+    /// Packets the client sent
+    pub packets_sent: u64,
+    /// Packets of `packets_sent` the client considers lost
+    pub packets_lost: u64,
+    /// Packets the client sent again
+    #[serde(default)]
+    pub retransmits: u64,
+    /// Times the tunnel woke the device, for keepalives, timers and rekeys
+    #[serde(default)]
+    pub wakeups: u64,
+}
+
+impl QualityReport {
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let report: Self = serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Network(format!("Invalid quality report: {}", e)))?;
+
+        if report.interval == 0 {
+            return Err(LostLoveError::Network("Quality report covers no time".to_string()));
+        }
+        if report.packets_lost > report.packets_sent {
+            return Err(LostLoveError::Network(format!(
+                "Quality report loses {} of {} packets",
+                report.packets_lost, report.packets_sent
+            )));
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality_report_roundtrip() {
+        let report = QualityReport {
+            interval: 60,
+            rtt_ms: 42,
+            packets_sent: 1000,
+            packets_lost: 3,
+            retransmits: 5,
+            wakeups: 4,
+        };
+        let bytes = report.to_bytes().unwrap();
+        assert_eq!(QualityReport::from_bytes(&bytes).unwrap(), report);
+
+        let minimal = QualityReport::from_bytes(br#"{"interval":60,"rtt_ms":9,"packets_sent":2,"packets_lost":0}"#);
+        assert_eq!(minimal.unwrap().wakeups, 0);
+    }
+
+    #[test]
+    fn test_quality_report_rejects_impossible_values() {
+        assert!(QualityReport::from_bytes(b"{}").is_err());
+        assert!(QualityReport::from_bytes(br#"{"interval":0,"rtt_ms":9,"packets_sent":2,"packets_lost":0}"#).is_err());
+        assert!(QualityReport::from_bytes(br#"{"interval":60,"rtt_ms":9,"packets_sent":2,"packets_lost":3}"#).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::error::{LostLoveError, Result};
+use crate::protocol::packet::{crc16, current_timestamp};
+use crate::protocol::wire::{TypeRange, HEADER_SIZE, PROTOCOL_ID};
+
+/// What to do with a packet in the extension or experimental range nobody registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownTypePolicy {
+    /// Drop it silently, so older servers stay invisible to newer clients
+    #[default]
+    Drop,
+    /// Answer with an `UnsupportedPacketType` error
+    Error,
+}
+
+impl UnknownTypePolicy {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "drop" => Ok(UnknownTypePolicy::Drop),
+            "error" => Ok(UnknownTypePolicy::Error),
+            other => Err(LostLoveError::Config(format!(
+                "Unknown packet type policy {:?}, expected drop or error",
+                other
+            ))),
+        }
+    }
+}
+
+/// Packet of a type outside `PacketType`, same header layout as core packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionPacket {
+    pub packet_type: u8,
+    pub stream_id: u16,
+    pub sequence_number: u64,
+    pub timestamp: u64,
+    pub flags: u8,
+    pub payload: Bytes,
+}
+
+impl ExtensionPacket {
+    pub fn new(packet_type: u8, payload: Bytes) -> Self {
+        Self {
+            packet_type,
+            stream_id: 0,
+            sequence_number: 0,
+            timestamp: current_timestamp(),
+            flags: 0,
+            payload,
+        }
+    }
+
+    /// Serialize with the length and checksum filled in
+    ///
+    /// Payloads are at most `MAX_PAYLOAD_SIZE` bytes, as for core packets.
+    pub fn serialize(&self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(HEADER_SIZE + self.payload.len());
+        buf.put_u16(PROTOCOL_ID);
+        buf.put_u8(self.packet_type);
+        buf.put_u16(self.stream_id);
+        buf.put_u64(self.sequence_number);
+        buf.put_u64(self.timestamp);
+        buf.put_u8(self.flags);
+        buf.put_u16(u16::try_from(self.payload.len()).expect("payload longer than MAX_PAYLOAD_SIZE"));
+        let checksum = crc16(buf.iter().chain(self.payload.iter()));
+        buf.put_u16(checksum);
+        buf.put_slice(&self.payload);
+        buf
+    }
+
+    /// Parse and verify the length and checksum
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err(LostLoveError::InsufficientData {
+                expected: HEADER_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let mut buf = data;
+        let protocol_id = buf.get_u16();
+        if protocol_id != PROTOCOL_ID {
+            return Err(LostLoveError::InvalidProtocolId(protocol_id));
+        }
+        let packet = Self {
+            packet_type: buf.get_u8(),
+            stream_id: buf.get_u16(),
+            sequence_number: buf.get_u64(),
+            timestamp: buf.get_u64(),
+            flags: buf.get_u8(),
+            payload: Bytes::copy_from_slice(&data[HEADER_SIZE..]),
+        };
+        let length = buf.get_u16() as usize;
+        let checksum = buf.get_u16();
+        if length != packet.payload.len() {
+            return Err(LostLoveError::LengthMismatch {
+                length,
+                actual: packet.payload.len(),
+            });
+        }
+
+        let actual = crc16(data[..HEADER_SIZE - 2].iter().chain(&data[HEADER_SIZE..]));
+        if checksum != actual {
+            return Err(LostLoveError::ChecksumMismatch {
+                expected: checksum,
+                actual,
+            });
+        }
+        Ok(packet)
+    }
+}
+
+/// Handles one extension packet type, optionally answering on the same path
+pub trait PacketHandler: Send + Sync {
+    fn handle(&self, packet: &ExtensionPacket) -> Result<Option<ExtensionPacket>>;
+}
+
+struct Registration {
+    name: String,
+    handler: Arc<dyn PacketHandler>,
+}
+
+/// Handlers for packet types outside the core protocol
+///
+/// Core types stay with `PacketType` and the engine; only the extension
+/// and experimental ranges can be registered.
+#[derive(Default)]
+pub struct PacketRegistry {
+    handlers: BTreeMap<u8, Registration>,
+    unknown: UnknownTypePolicy,
+}
+
+impl PacketRegistry {
+    pub fn new(unknown: UnknownTypePolicy) -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+            unknown,
+        }
+    }
+
+    /// Register `handler` for `packet_type`, refusing core, reserved and taken types
+    pub fn register(
+        &mut self,
+        packet_type: u8,
+        name: &str,
+        handler: Arc<dyn PacketHandler>,
+    ) -> Result<()> {
+        match TypeRange::of(packet_type) {
+            TypeRange::Extension | TypeRange::Experimental => {}
+            range => {
+                return Err(LostLoveError::Config(format!(
+                    "Packet type {:#04x} is in the {:?} range, not registrable",
+                    packet_type, range
+                )))
+            }
+        }
+        if let Some(existing) = self.handlers.get(&packet_type) {
+            return Err(LostLoveError::Config(format!(
+                "Packet type {:#04x} already registered by {}",
+                packet_type, existing.name
+            )));
+        }
+        self.handlers.insert(
+            packet_type,
+            Registration {
+                name: name.to_string(),
+                handler,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn handler(&self, packet_type: u8) -> Option<&Arc<dyn PacketHandler>> {
+        self.handlers.get(&packet_type).map(|registration| &registration.handler)
+    }
+
+    pub fn name(&self, packet_type: u8) -> Option<&str> {
+        self.handlers.get(&packet_type).map(|registration| registration.name.as_str())
+    }
+
+    pub fn unknown_policy(&self) -> UnknownTypePolicy {
+        self.unknown
+    }
+
+    /// Registered types and their names, in type order
+    pub fn registered(&self) -> impl Iterator<Item = (u8, &str)> {
+        self.handlers
+            .iter()
+            .map(|(packet_type, registration)| (*packet_type, registration.name.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl PacketHandler for Echo {
+        fn handle(&self, packet: &ExtensionPacket) -> Result<Option<ExtensionPacket>> {
+            Ok(Some(packet.clone()))
+        }
+    }
+
+    #[test]
+    fn test_extension_packet_roundtrip() {
+        let mut packet = ExtensionPacket::new(0x40, Bytes::from_static(b"probe"));
+        packet.sequence_number = 7;
+        let bytes = packet.serialize();
+        assert_eq!(ExtensionPacket::deserialize(&bytes).unwrap(), packet);
+
+        let mut damaged = bytes.to_vec();
+        damaged[HEADER_SIZE] ^= 1;
+        assert!(matches!(
+            ExtensionPacket::deserialize(&damaged),
+            Err(LostLoveError::ChecksumMismatch { .. })
+        ));
+        assert!(ExtensionPacket::deserialize(&bytes[..HEADER_SIZE - 1]).is_err());
+        assert!(matches!(
+            ExtensionPacket::deserialize(&bytes[..bytes.len() - 1]),
+            Err(LostLoveError::LengthMismatch { length: 5, actual: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_register_ranges() {
+        let mut registry = PacketRegistry::new(UnknownTypePolicy::Drop);
+        registry.register(0x40, "echo", Arc::new(Echo)).unwrap();
+        registry.register(0xC0, "lab", Arc::new(Echo)).unwrap();
+
+        assert!(registry.register(0x40, "again", Arc::new(Echo)).is_err());
+        assert!(registry.register(0x01, "data", Arc::new(Echo)).is_err());
+        assert!(registry.register(0x00, "zero", Arc::new(Echo)).is_err());
+        assert!(registry.register(0xFF, "all ones", Arc::new(Echo)).is_err());
+
+        assert_eq!(registry.name(0x40), Some("echo"));
+        assert!(registry.handler(0x41).is_none());
+        assert_eq!(registry.registered().collect::<Vec<_>>(), [(0x40, "echo"), (0xC0, "lab")]);
+    }
+
+    #[test]
+    fn test_policy_parse() {
+        assert_eq!(UnknownTypePolicy::parse("drop").unwrap(), UnknownTypePolicy::Drop);
+        assert_eq!(UnknownTypePolicy::parse("error").unwrap(), UnknownTypePolicy::Error);
+        assert!(UnknownTypePolicy::parse("ignore").is_err());
+    }
+}
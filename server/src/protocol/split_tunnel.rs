@@ -0,0 +1,298 @@
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::client_profile::{ProfileError, SplitProfile};
+
+/// Name of the nftables table marking the applications' packets
+pub const SPLIT_TABLE: &str = "llp_split";
+
+/// Root of the cgroup v2 hierarchy
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// cgroup the client moves processes of the profile's `apps` into
+pub const APPS_CGROUP: &str = "lostlove.apps";
+
+/// Per-app split tunneling on Linux: only processes in the profile's
+/// cgroups use the tunnel, everything else the host's own routes
+///
+/// nftables marks packets of sockets in those cgroups and re-routes them,
+/// a policy rule sends marked packets to a table whose default route is
+/// the tunnel, and NAT gives them the tunnel's source address. Clients put
+/// the pushed routes in that table instead of the main one and move
+/// processes of `apps` into `APPS_CGROUP` as they start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitTunnel {
+    /// Tunnel interface marked packets are routed to
+    pub interface: String,
+    pub fwmark: u32,
+    pub table: u32,
+    pub apps: Vec<PathBuf>,
+    /// cgroup v2 paths relative to `CGROUP_ROOT`, `APPS_CGROUP` included
+    /// if there are apps
+    pub cgroups: Vec<String>,
+}
+
+impl SplitTunnel {
+    pub fn from_profile(profile: &SplitProfile, interface: &str) -> Result<Self, ProfileError> {
+        let mut cgroups = Vec::new();
+        if !profile.apps.is_empty() {
+            cgroups.push(APPS_CGROUP.to_string());
+        }
+        for cgroup in &profile.cgroups {
+            let path = cgroup.trim_matches('/');
+            let valid = path
+                .split('/')
+                .all(|part| !part.is_empty() && part != "." && part != ".." && !part.contains('"'));
+            if !valid {
+                return Err(ProfileError(format!("Invalid cgroup {:?}", cgroup)));
+            }
+            if !cgroups.iter().any(|known| known == path) {
+                cgroups.push(path.to_string());
+            }
+        }
+        if cgroups.is_empty() {
+            return Err(ProfileError("Split tunnel needs apps or cgroups".to_string()));
+        }
+        if let Some(app) = profile.apps.iter().find(|app| !app.starts_with('/')) {
+            return Err(ProfileError(format!("App {:?} must be an absolute path", app)));
+        }
+        if profile.fwmark == 0 || profile.table == 0 {
+            return Err(ProfileError("Split tunnel fwmark and table must not be 0".to_string()));
+        }
+
+        Ok(Self {
+            interface: interface.to_string(),
+            fwmark: profile.fwmark,
+            table: profile.table,
+            apps: profile.apps.iter().map(PathBuf::from).collect(),
+            cgroups,
+        })
+    }
+
+    /// Ruleset for `nft -f`, replacing an earlier one atomically; the
+    /// cgroups must exist when it is loaded
+    pub fn nftables(&self) -> String {
+        let mut rules = String::new();
+        // Declaring the table first lets the delete succeed on a first load
+        let _ = writeln!(rules, "table inet {0}\ndelete table inet {0}\n", SPLIT_TABLE);
+        let _ = writeln!(rules, "table inet {} {{", SPLIT_TABLE);
+        rules.push_str("    chain output {\n        type route hook output priority mangle; policy accept;\n");
+        for cgroup in &self.cgroups {
+            let _ = writeln!(
+                rules,
+                "        socket cgroupv2 level {} \"{}\" meta mark set {:#x}",
+                cgroup.split('/').count(),
+                cgroup,
+                self.fwmark
+            );
+        }
+        rules.push_str("    }\n\n");
+        // Sockets bound before the re-route still carry the LAN's address
+        rules.push_str("    chain postrouting {\n        type nat hook postrouting priority srcnat; policy accept;\n");
+        let _ = writeln!(
+            rules,
+            "        oifname \"{}\" meta mark {:#x} masquerade",
+            self.interface, self.fwmark
+        );
+        rules.push_str("    }\n}\n");
+        rules
+    }
+
+    /// Commands adding the policy routing for marked packets
+    pub fn setup(&self) -> Vec<String> {
+        let mut commands = Vec::new();
+        for family in ["-4", "-6"] {
+            commands.push(format!(
+                "ip {} rule add fwmark {:#x} lookup {} priority {}",
+                family, self.fwmark, self.table, self.table
+            ));
+            commands.push(format!(
+                "ip {} route replace default dev {} table {}",
+                family, self.interface, self.table
+            ));
+        }
+        // Replies arrive on the tunnel while the route back is the LAN's
+        commands.push("sysctl -qw net.ipv4.conf.all.rp_filter=2".to_string());
+        commands
+    }
+
+    /// Commands undoing `setup` and the nftables rules
+    pub fn teardown(&self) -> Vec<String> {
+        let mut commands = Vec::new();
+        for family in ["-4", "-6"] {
+            commands.push(format!(
+                "ip {} rule del fwmark {:#x} lookup {}",
+                family, self.fwmark, self.table
+            ));
+            commands.push(format!("ip {} route flush table {}", family, self.table));
+        }
+        commands.push(format!("nft delete table inet {}", SPLIT_TABLE));
+        commands
+    }
+
+    /// Shell script creating the cgroups and installing everything
+    pub fn script(&self) -> String {
+        let mut script = String::new();
+        script.push_str("#!/bin/sh\n");
+        let _ = writeln!(
+            script,
+            "# LostLove split tunnel through {}, generated by `llpctl split-rules`",
+            self.interface
+        );
+        for app in &self.apps {
+            let _ = writeln!(
+                script,
+                "# Move {} into {} with `llpctl split-assign`",
+                app.display(),
+                APPS_CGROUP
+            );
+        }
+        let _ = writeln!(script, "# Remove with: {}", self.teardown().join("; "));
+        script.push_str("set -e\n\n");
+        for cgroup in &self.cgroups {
+            let _ = writeln!(script, "mkdir -p \"{}/{}\"", CGROUP_ROOT, cgroup);
+        }
+        script.push_str("nft -f - <<'EOF'\n");
+        script.push_str(&self.nftables());
+        script.push_str("EOF\n");
+        for command in self.setup() {
+            let _ = writeln!(script, "{}", command);
+        }
+        script
+    }
+}
+
+/// Processes under `proc_root` running one of `apps`
+pub fn matching_pids(proc_root: &Path, apps: &[PathBuf]) -> io::Result<Vec<u32>> {
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir(proc_root)? {
+        let entry = entry?;
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        // Kernel threads have no executable, other users' processes can't
+        // be read without privileges
+        let Ok(exe) = std::fs::read_link(entry.path().join("exe")) else {
+            continue;
+        };
+        // The kernel marks executables replaced since the process started
+        let exe = exe
+            .to_str()
+            .and_then(|exe| exe.strip_suffix(" (deleted)"))
+            .map_or(exe.clone(), PathBuf::from);
+        if apps.contains(&exe) {
+            pids.push(pid);
+        }
+    }
+    pids.sort_unstable();
+    Ok(pids)
+}
+
+/// Move `pids` into the cgroup at `cgroup_dir`, returning how many moved;
+/// processes that exited meanwhile are skipped
+pub fn move_to_cgroup(cgroup_dir: &Path, pids: &[u32]) -> io::Result<usize> {
+    let mut procs = OpenOptions::new().append(true).open(cgroup_dir.join("cgroup.procs"))?;
+    let mut moved = 0;
+    for pid in pids {
+        match procs.write_all(format!("{}\n", pid).as_bytes()) {
+            Ok(()) => moved += 1,
+            // ESRCH
+            Err(e) if e.raw_os_error() == Some(3) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> SplitProfile {
+        SplitProfile {
+            apps: vec!["/usr/bin/firefox".to_string()],
+            cgroups: vec!["/user.slice/torrent.scope/".to_string()],
+            fwmark: 0x4c4c,
+            table: 0x4c4c,
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let split = SplitTunnel::from_profile(&profile(), "llp0").unwrap();
+        assert_eq!(split.cgroups, ["lostlove.apps", "user.slice/torrent.scope"]);
+
+        let rules = split.nftables();
+        assert!(rules.contains("table inet llp_split\ndelete table inet llp_split\n"));
+        assert!(rules.contains("        socket cgroupv2 level 1 \"lostlove.apps\" meta mark set 0x4c4c\n"));
+        assert!(rules.contains("        socket cgroupv2 level 2 \"user.slice/torrent.scope\" meta mark set 0x4c4c\n"));
+        assert!(rules.contains("        oifname \"llp0\" meta mark 0x4c4c masquerade\n"));
+        assert_eq!(rules.matches('{').count(), rules.matches('}').count());
+
+        assert!(split
+            .setup()
+            .contains(&"ip -4 rule add fwmark 0x4c4c lookup 19532 priority 19532".to_string()));
+        assert!(split
+            .setup()
+            .contains(&"ip -6 route replace default dev llp0 table 19532".to_string()));
+        assert!(split
+            .teardown()
+            .contains(&"nft delete table inet llp_split".to_string()));
+
+        let script = split.script();
+        assert!(script.contains("mkdir -p \"/sys/fs/cgroup/user.slice/torrent.scope\"\n"));
+        assert!(script.find("mkdir -p").unwrap() < script.find("nft -f").unwrap());
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        let mut invalid = profile();
+        invalid.cgroups.push("user.slice/../system.slice".to_string());
+        assert!(SplitTunnel::from_profile(&invalid, "llp0").is_err());
+
+        let mut invalid = profile();
+        invalid.apps.push("firefox".to_string());
+        assert!(SplitTunnel::from_profile(&invalid, "llp0").is_err());
+
+        let empty = SplitProfile {
+            apps: Vec::new(),
+            cgroups: Vec::new(),
+            fwmark: 1,
+            table: 1,
+        };
+        assert!(SplitTunnel::from_profile(&empty, "llp0").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_assign_processes() {
+        let root = std::env::temp_dir().join(format!("llp-split-{}", uuid::Uuid::new_v4()));
+        let proc_root = root.join("proc");
+        for (pid, exe) in [
+            ("101", "/usr/bin/firefox"),
+            ("102", "/usr/bin/bash"),
+            ("103", "/usr/bin/firefox (deleted)"),
+        ] {
+            std::fs::create_dir_all(proc_root.join(pid)).unwrap();
+            std::os::unix::fs::symlink(exe, proc_root.join(pid).join("exe")).unwrap();
+        }
+        std::fs::create_dir_all(proc_root.join("self")).unwrap();
+        std::fs::create_dir_all(proc_root.join("104")).unwrap();
+
+        let pids = matching_pids(&proc_root, &[PathBuf::from("/usr/bin/firefox")]).unwrap();
+        assert_eq!(pids, [101, 103]);
+
+        let cgroup = root.join(APPS_CGROUP);
+        std::fs::create_dir_all(&cgroup).unwrap();
+        std::fs::write(cgroup.join("cgroup.procs"), "").unwrap();
+        assert_eq!(move_to_cgroup(&cgroup, &pids).unwrap(), 2);
+        assert_eq!(
+            std::fs::read_to_string(cgroup.join("cgroup.procs")).unwrap(),
+            "101\n103\n"
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
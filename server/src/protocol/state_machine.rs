@@ -0,0 +1,273 @@
+/// Side of the handshake a state machine runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Handshake state machine
+///
+/// States only change through `next`, which returns None for transitions
+/// the protocol doesn't allow; callers turn that into an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    Init,
+    /// Client sent ClientHello, waiting for the server's answer
+    ClientHelloSent,
+    /// Server answered ClientHello, keys not confirmed yet
+    ServerHelloSent,
+    Completed,
+    Failed,
+}
+
+/// Inputs driving the handshake state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeEvent {
+    /// Client sends ClientHello
+    SendClientHello,
+    /// Server accepts a ClientHello and answers with ServerHello
+    AcceptClientHello,
+    /// Client accepts the server's ServerHello
+    AcceptServerHello,
+    /// Server has delivered ServerHello, both sides hold the session keys
+    ConfirmKeys,
+    /// A message was refused
+    Reject,
+}
+
+impl HandshakeState {
+    /// State after `event` on `role`'s side, None if the transition is illegal
+    pub fn next(self, role: Role, event: HandshakeEvent) -> Option<HandshakeState> {
+        use HandshakeEvent::*;
+        use HandshakeState::*;
+
+        match (role, self, event) {
+            (Role::Client, Init, SendClientHello) => Some(ClientHelloSent),
+            (Role::Client, ClientHelloSent, AcceptServerHello) => Some(Completed),
+            (Role::Server, Init, AcceptClientHello) => Some(ServerHelloSent),
+            (Role::Server, ServerHelloSent, ConfirmKeys) => Some(Completed),
+            (_, Completed | Failed, _) => None,
+            (_, _, Reject) => Some(Failed),
+            _ => None,
+        }
+    }
+
+    /// Whether no further transitions are possible
+    pub fn is_terminal(self) -> bool {
+        matches!(self, HandshakeState::Completed | HandshakeState::Failed)
+    }
+}
+
+/// Session state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Handshaking,
+    Active,
+    Disconnecting,
+    Closed,
+}
+
+/// Inputs driving the session lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The handshake completed with confirmed keys
+    HandshakeCompleted,
+    /// A Disconnect was sent or received
+    Disconnect,
+    /// The connection is gone
+    Close,
+}
+
+impl SessionState {
+    /// All states, in lifecycle order
+    pub const ALL: [SessionState; 4] = [
+        SessionState::Handshaking,
+        SessionState::Active,
+        SessionState::Disconnecting,
+        SessionState::Closed,
+    ];
+
+    /// Name used in admin output and queries
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SessionState::Handshaking => "handshaking",
+            SessionState::Active => "active",
+            SessionState::Disconnecting => "disconnecting",
+            SessionState::Closed => "closed",
+        }
+    }
+
+    /// State after `event`, None if the transition is illegal
+    pub fn next(self, event: SessionEvent) -> Option<SessionState> {
+        use SessionEvent::*;
+        use SessionState::*;
+
+        match (self, event) {
+            (Handshaking, HandshakeCompleted) => Some(Active),
+            (Active, Disconnect) => Some(Disconnecting),
+            (Closed, _) => None,
+            (_, Close) => Some(Closed),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const ROLES: [Role; 2] = [Role::Client, Role::Server];
+
+    const HANDSHAKE_STATES: [HandshakeState; 5] = [
+        HandshakeState::Init,
+        HandshakeState::ClientHelloSent,
+        HandshakeState::ServerHelloSent,
+        HandshakeState::Completed,
+        HandshakeState::Failed,
+    ];
+
+    const HANDSHAKE_EVENTS: [HandshakeEvent; 5] = [
+        HandshakeEvent::SendClientHello,
+        HandshakeEvent::AcceptClientHello,
+        HandshakeEvent::AcceptServerHello,
+        HandshakeEvent::ConfirmKeys,
+        HandshakeEvent::Reject,
+    ];
+
+    const SESSION_STATES: [SessionState; 4] = [
+        SessionState::Handshaking,
+        SessionState::Active,
+        SessionState::Disconnecting,
+        SessionState::Closed,
+    ];
+
+    const SESSION_EVENTS: [SessionEvent; 3] = [
+        SessionEvent::HandshakeCompleted,
+        SessionEvent::Disconnect,
+        SessionEvent::Close,
+    ];
+
+    /// Random event sequences, reproducible through the fixed seed
+    fn sequences<T: Copy>(events: &[T], seed: u64) -> Vec<Vec<T>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..2000)
+            .map(|_| {
+                let len = rng.gen_range(0..12);
+                (0..len).map(|_| events[rng.gen_range(0..events.len())]).collect()
+            })
+            .collect()
+    }
+
+    /// Apply a sequence, skipping illegal events, returning the states passed
+    fn run_handshake(role: Role, events: &[HandshakeEvent]) -> Vec<(HandshakeEvent, HandshakeState)> {
+        let mut state = HandshakeState::Init;
+        let mut trace = Vec::new();
+        for &event in events {
+            if let Some(next) = state.next(role, event) {
+                state = next;
+                trace.push((event, state));
+            }
+        }
+        trace
+    }
+
+    #[test]
+    fn test_handshake_happy_paths() {
+        let client = run_handshake(
+            Role::Client,
+            &[HandshakeEvent::SendClientHello, HandshakeEvent::AcceptServerHello],
+        );
+        assert_eq!(client.last().unwrap().1, HandshakeState::Completed);
+
+        let server = run_handshake(
+            Role::Server,
+            &[HandshakeEvent::AcceptClientHello, HandshakeEvent::ConfirmKeys],
+        );
+        assert_eq!(server.last().unwrap().1, HandshakeState::Completed);
+    }
+
+    #[test]
+    fn test_terminal_states_are_final() {
+        for role in ROLES {
+            for state in HANDSHAKE_STATES.into_iter().filter(|s| s.is_terminal()) {
+                for event in HANDSHAKE_EVENTS {
+                    assert_eq!(state.next(role, event), None, "{:?} {:?} {:?}", role, state, event);
+                }
+            }
+        }
+
+        for event in SESSION_EVENTS {
+            assert_eq!(SessionState::Closed.next(event), None);
+        }
+    }
+
+    #[test]
+    fn test_property_server_completes_only_after_key_confirmation() {
+        for events in sequences(&HANDSHAKE_EVENTS, 164) {
+            let trace = run_handshake(Role::Server, &events);
+            let accepted = trace
+                .iter()
+                .position(|(event, _)| *event == HandshakeEvent::AcceptClientHello);
+
+            for (i, (event, state)) in trace.iter().enumerate() {
+                assert_ne!(*state, HandshakeState::ClientHelloSent);
+                if *state == HandshakeState::Completed {
+                    assert_eq!(*event, HandshakeEvent::ConfirmKeys, "{:?}", events);
+                    assert!(accepted.is_some_and(|accepted| accepted < i), "{:?}", events);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_property_client_completes_only_after_server_hello() {
+        for events in sequences(&HANDSHAKE_EVENTS, 165) {
+            let trace = run_handshake(Role::Client, &events);
+            let sent = trace
+                .iter()
+                .position(|(event, _)| *event == HandshakeEvent::SendClientHello);
+
+            for (i, (event, state)) in trace.iter().enumerate() {
+                assert_ne!(*state, HandshakeState::ServerHelloSent);
+                if *state == HandshakeState::Completed {
+                    assert_eq!(*event, HandshakeEvent::AcceptServerHello, "{:?}", events);
+                    assert!(sent.is_some_and(|sent| sent < i), "{:?}", events);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_property_session_lifecycle() {
+        for events in sequences(&SESSION_EVENTS, 166) {
+            let mut state = SessionState::Handshaking;
+            let mut was_active = false;
+
+            for event in events {
+                let Some(next) = state.next(event) else {
+                    continue;
+                };
+
+                // Active only straight from the handshake; never back to handshaking
+                if next == SessionState::Active {
+                    assert_eq!(state, SessionState::Handshaking);
+                    assert_eq!(event, SessionEvent::HandshakeCompleted);
+                    assert!(!was_active);
+                    was_active = true;
+                }
+                if next == SessionState::Disconnecting {
+                    assert_eq!(state, SessionState::Active);
+                }
+                assert_ne!(next, SessionState::Handshaking);
+                state = next;
+            }
+        }
+
+        // Every state is covered by the table
+        for state in SESSION_STATES {
+            assert!(SESSION_EVENTS.iter().any(|e| state.next(*e).is_some()) || state == SessionState::Closed);
+        }
+    }
+}
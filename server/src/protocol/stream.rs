@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 /// Stream identifier
@@ -42,6 +43,18 @@ impl From<StreamId> for u16 {
     }
 }
 
+/// How the packets of a stream are delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamMode {
+    /// Acknowledged and in order, for control and application channels
+    #[default]
+    Reliable,
+    /// Unacknowledged and unordered, never retransmitted or reassembled;
+    /// for tunnelled IP packets, whose own protocols recover from loss
+    Datagram,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
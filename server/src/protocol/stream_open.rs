@@ -0,0 +1,107 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LostLoveError, Result};
+use crate::protocol::stream::StreamMode;
+
+/// Weight of a stream opened without one
+pub const DEFAULT_WEIGHT: u16 = 16;
+/// Largest stream weight, as in HTTP/2
+pub const MAX_WEIGHT: u16 = 256;
+
+/// Payload of a `PacketType::StreamOpen` packet
+///
+/// Opens the stream in the packet header, or changes the weight of a stream
+/// already open. A stream's mode, and the application channel it carries
+/// if any, are set when it opens. The sender shares the tunnel between streams with queued
+/// data in proportion to their weights, so an interactive stream with a
+/// high weight isn't stuck behind a bulk transfer with a low one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamOpen {
+    #[serde(default = "default_weight")]
+    pub weight: u16,
+    #[serde(default)]
+    pub mode: StreamMode,
+    /// Name of the application channel the stream carries, always reliable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+}
+
+fn default_weight() -> u16 {
+    DEFAULT_WEIGHT
+}
+
+impl StreamOpen {
+    pub fn new(weight: u16) -> Self {
+        Self {
+            weight,
+            mode: StreamMode::Reliable,
+            channel: None,
+        }
+    }
+
+    /// Open the stream for application channel `name`
+    pub fn with_channel(mut self, name: impl Into<String>) -> Self {
+        self.channel = Some(name.into());
+        self
+    }
+
+    pub fn with_mode(mut self, mode: StreamMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let open: Self = serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Network(format!("Invalid stream open: {}", e)))?;
+
+        if !(1..=MAX_WEIGHT).contains(&open.weight) {
+            return Err(LostLoveError::Network(format!(
+                "Stream weight {} is outside 1-{}",
+                open.weight, MAX_WEIGHT
+            )));
+        }
+        if open.channel.is_some() && open.mode != StreamMode::Reliable {
+            return Err(LostLoveError::Network("Channels are reliable streams".to_string()));
+        }
+        Ok(open)
+    }
+}
+
+impl Default for StreamOpen {
+    fn default() -> Self {
+        Self::new(DEFAULT_WEIGHT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_open_round_trip() {
+        let open = StreamOpen::new(200);
+        let bytes = open.to_bytes().unwrap();
+        assert_eq!(&bytes[..], br#"{"weight":200,"mode":"reliable"}"#);
+        assert_eq!(StreamOpen::from_bytes(&bytes).unwrap(), open);
+
+        let datagram = StreamOpen::from_bytes(br#"{"weight":8,"mode":"datagram"}"#).unwrap();
+        assert_eq!(datagram, StreamOpen::new(8).with_mode(StreamMode::Datagram));
+        assert_eq!(StreamOpen::from_bytes(b"{}").unwrap(), StreamOpen::default());
+        assert!(StreamOpen::from_bytes(br#"{"mode":"lossy"}"#).is_err());
+
+        let channel = StreamOpen::default().with_channel("logs");
+        let bytes = channel.to_bytes().unwrap();
+        assert_eq!(&bytes[..], br#"{"weight":16,"mode":"reliable","channel":"logs"}"#);
+        assert_eq!(StreamOpen::from_bytes(&bytes).unwrap(), channel);
+        assert!(StreamOpen::from_bytes(br#"{"mode":"datagram","channel":"logs"}"#).is_err());
+        assert!(StreamOpen::from_bytes(br#"{"weight":0}"#).is_err());
+        assert!(StreamOpen::from_bytes(br#"{"weight":257}"#).is_err());
+    }
+}
@@ -0,0 +1,45 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LostLoveError, Result};
+
+/// Payload of a client's `PacketType::TransportAttach` datagram
+///
+/// Sent over UDP with the token from ServerHello to move the session's data
+/// onto UDP. The session keeps its keys, so switching needs no new handshake;
+/// the server acknowledges with an empty `TransportAttach` packet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransportAttach {
+    pub token: String,
+}
+
+impl TransportAttach {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self { token: token.into() }
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| LostLoveError::Network(format!("Serialization error: {}", e)))?;
+        Ok(Bytes::from(json))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data)
+            .map_err(|e| LostLoveError::Network(format!("Invalid transport attach: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_attach_roundtrip() {
+        let attach = TransportAttach::new("5f0c2b9e");
+        let bytes = attach.to_bytes().unwrap();
+
+        assert_eq!(TransportAttach::from_bytes(&bytes).unwrap(), attach);
+        assert!(TransportAttach::from_bytes(b"{}").is_err());
+    }
+}
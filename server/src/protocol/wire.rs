@@ -0,0 +1,183 @@
+/// Protocol identifier
+pub const PROTOCOL_ID: u16 = 0x4C4C; // "LL" in hex (LostLove)
+
+/// Header size in bytes
+pub const HEADER_SIZE: usize = 26;
+
+/// Offset of the payload length field in the header
+pub const LENGTH_OFFSET: usize = 22;
+
+/// Largest payload the length field can describe
+pub const MAX_PAYLOAD_SIZE: usize = u16::MAX as usize;
+
+/// Last packet of a stream
+pub const FLAG_FIN: u8 = 0x01;
+/// Stream reset
+pub const FLAG_RST: u8 = 0x02;
+/// High priority packet
+pub const FLAG_PRIORITY: u8 = 0x04;
+/// Low bit of the key epoch the packet is encrypted under
+pub const FLAG_KEY_PHASE: u8 = 0x08;
+
+/// Header flag bits and their names
+pub const FLAGS: [(u8, &str); 4] = [
+    (FLAG_FIN, "FIN"),
+    (FLAG_RST, "RST"),
+    (FLAG_PRIORITY, "PRIORITY"),
+    (FLAG_KEY_PHASE, "KEY_PHASE"),
+];
+
+/// Packet types
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0x01,
+    Ack = 0x02,
+    HandshakeInit = 0x03,
+    HandshakeResponse = 0x04,
+    KeepAlive = 0x05,
+    Disconnect = 0x06,
+    Error = 0x07,
+    ConfigUpdate = 0x08,
+    Warning = 0x09,
+    RouteAnnounce = 0x0A,
+    PeerSignal = 0x0B,
+    AddressDiscovery = 0x0C,
+    TransportAttach = 0x0D,
+    KeyUpdate = 0x0E,
+    QualityReport = 0x0F,
+    PathProbe = 0x10,
+    StreamOpen = 0x11,
+}
+
+impl PacketType {
+    /// Every packet type, in wire value order
+    pub const ALL: [PacketType; 17] = [
+        PacketType::Data,
+        PacketType::Ack,
+        PacketType::HandshakeInit,
+        PacketType::HandshakeResponse,
+        PacketType::KeepAlive,
+        PacketType::Disconnect,
+        PacketType::Error,
+        PacketType::ConfigUpdate,
+        PacketType::Warning,
+        PacketType::RouteAnnounce,
+        PacketType::PeerSignal,
+        PacketType::AddressDiscovery,
+        PacketType::TransportAttach,
+        PacketType::KeyUpdate,
+        PacketType::QualityReport,
+        PacketType::PathProbe,
+        PacketType::StreamOpen,
+    ];
+}
+
+/// Packet types of the core protocol, all in `PacketType`
+pub const CORE_TYPES: std::ops::RangeInclusive<u8> = 0x01..=0x3F;
+/// Packet types assigned to extensions, handled through the packet registry
+pub const EXTENSION_TYPES: std::ops::RangeInclusive<u8> = 0x40..=0xBF;
+/// Packet types for private and experimental use, never assigned
+pub const EXPERIMENTAL_TYPES: std::ops::RangeInclusive<u8> = 0xC0..=0xFE;
+
+/// Which range a packet type byte falls in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypeRange {
+    /// 0x00 and 0xFF, never valid
+    Reserved,
+    Core,
+    Extension,
+    Experimental,
+}
+
+impl TypeRange {
+    pub fn of(packet_type: u8) -> Self {
+        if CORE_TYPES.contains(&packet_type) {
+            TypeRange::Core
+        } else if EXTENSION_TYPES.contains(&packet_type) {
+            TypeRange::Extension
+        } else if EXPERIMENTAL_TYPES.contains(&packet_type) {
+            TypeRange::Experimental
+        } else {
+            TypeRange::Reserved
+        }
+    }
+}
+
+/// How a header field's value is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    Decimal,
+    Hex,
+    /// One of `PacketType::ALL`
+    PacketType,
+    /// Bits from `FLAGS`
+    Flags,
+}
+
+/// A header field, big-endian on the wire
+#[derive(Debug, Clone, Copy)]
+pub struct HeaderField {
+    pub name: &'static str,
+    pub label: &'static str,
+    pub size: usize,
+    pub kind: FieldKind,
+}
+
+/// Header fields in wire order; their sizes add up to `HEADER_SIZE`
+pub const HEADER_FIELDS: [HeaderField; 8] = [
+    HeaderField { name: "protocol_id", label: "Protocol ID", size: 2, kind: FieldKind::Hex },
+    HeaderField { name: "packet_type", label: "Packet Type", size: 1, kind: FieldKind::PacketType },
+    HeaderField { name: "stream_id", label: "Stream ID", size: 2, kind: FieldKind::Decimal },
+    HeaderField { name: "sequence_number", label: "Sequence Number", size: 8, kind: FieldKind::Decimal },
+    HeaderField { name: "timestamp", label: "Timestamp (ms)", size: 8, kind: FieldKind::Decimal },
+    HeaderField { name: "flags", label: "Flags", size: 1, kind: FieldKind::Flags },
+    HeaderField { name: "length", label: "Payload Length", size: 2, kind: FieldKind::Decimal },
+    HeaderField { name: "checksum", label: "Checksum", size: 2, kind: FieldKind::Hex },
+];
+
+/// Fields with their offsets from the start of the header
+pub fn header_layout() -> impl Iterator<Item = (usize, HeaderField)> {
+    HEADER_FIELDS.iter().scan(0, |offset, field| {
+        let start = *offset;
+        *offset += field.size;
+        Some((start, *field))
+    })
+}
+
+/// Payload length a serialized header declares
+pub fn payload_length(header: &[u8; HEADER_SIZE]) -> usize {
+    u16::from_be_bytes([header[LENGTH_OFFSET], header[LENGTH_OFFSET + 1]]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields_fill_header() {
+        let (offset, last) = header_layout().last().unwrap();
+        assert_eq!(offset + last.size, HEADER_SIZE);
+        let (length_offset, _) = header_layout().find(|(_, field)| field.name == "length").unwrap();
+        assert_eq!(length_offset, LENGTH_OFFSET);
+    }
+
+    #[test]
+    fn test_all_packet_types_in_order() {
+        for (i, packet_type) in PacketType::ALL.iter().enumerate() {
+            assert_eq!(*packet_type as usize, i + 1);
+            assert_eq!(TypeRange::of(*packet_type as u8), TypeRange::Core);
+        }
+    }
+
+    #[test]
+    fn test_type_ranges() {
+        assert_eq!(TypeRange::of(0x00), TypeRange::Reserved);
+        assert_eq!(TypeRange::of(0x3F), TypeRange::Core);
+        assert_eq!(TypeRange::of(0x40), TypeRange::Extension);
+        assert_eq!(TypeRange::of(0xBF), TypeRange::Extension);
+        assert_eq!(TypeRange::of(0xC0), TypeRange::Experimental);
+        assert_eq!(TypeRange::of(0xFE), TypeRange::Experimental);
+        assert_eq!(TypeRange::of(0xFF), TypeRange::Reserved);
+    }
+}
@@ -0,0 +1,269 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+const HEADER_SIZE: usize = 26;
+const LENGTH_OFFSET: usize = 22;
+const PROTOCOL_ID: u16 = 0x4C4C;
+
+const DATA: u8 = 0x01;
+const ACK: u8 = 0x02;
+const HANDSHAKE_INIT: u8 = 0x03;
+const HANDSHAKE_RESPONSE: u8 = 0x04;
+const KEEPALIVE: u8 = 0x05;
+const DISCONNECT: u8 = 0x06;
+const CONFIG_UPDATE: u8 = 0x08;
+
+const TUNNEL_NETWORK: &str = "10.77.0.1/24";
+const MTU: u64 = 1380;
+
+/// Server process killed when the test ends, passing or not
+struct ServerProcess {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl ServerProcess {
+    fn start(port: u16, metrics_port: u16) -> Self {
+        let dir = std::env::temp_dir().join(format!("llp-loopback-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = format!(
+            r#"
+[server]
+bind_address = "127.0.0.1"
+port = {port}
+
+[network]
+tun_address = "{TUNNEL_NETWORK}"
+mtu = {MTU}
+
+[monitoring]
+metrics_address = "127.0.0.1"
+metrics_port = {metrics_port}
+
+[admin]
+enable_control_socket = false
+"#
+        );
+        let config_path = dir.join("server.toml");
+        std::fs::write(&config_path, config).unwrap();
+        let log = std::fs::File::create(dir.join("server.log")).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_lostlove-server"))
+            .arg("--config")
+            .arg(&config_path)
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+
+        Self { child, dir }
+    }
+
+    /// Connect once the server listens, failing with its output if it
+    /// exited instead
+    fn connect(&mut self, port: u16) -> TcpStream {
+        eventually("the server to listen", || {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                let log = std::fs::read_to_string(self.dir.join("server.log")).unwrap_or_default();
+                panic!("server exited with {} before listening:\n{}", status, log);
+            }
+            TcpStream::connect(("127.0.0.1", port)).ok()
+        })
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Retry until `check` succeeds or the deadline passes
+fn eventually<T>(what: &str, mut check: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Some(value) = check() {
+            return value;
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// CRC-16/CCITT with initial value 0xFFFF, as in the packet checksum
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Encode a packet independently of the server's own encoder
+fn encode(packet_type: u8, sequence_number: u64, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+    packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet.push(packet_type);
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(&sequence_number.to_be_bytes());
+    packet.extend_from_slice(&1_700_000_000_000u64.to_be_bytes());
+    packet.push(0);
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+
+    let mut checked = packet.clone();
+    checked.extend_from_slice(payload);
+    packet.extend_from_slice(&crc16(&checked).to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Client side of one tunnel connection
+struct Client {
+    stream: TcpStream,
+    received: Vec<u8>,
+}
+
+impl Client {
+    fn connect(server: &mut ServerProcess, port: u16) -> Self {
+        let stream = server.connect(port);
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Self { stream, received: Vec::new() }
+    }
+
+    fn send(&mut self, packet_type: u8, sequence_number: u64, payload: &[u8]) {
+        self.stream.write_all(&encode(packet_type, sequence_number, payload)).unwrap();
+    }
+
+    fn fill(&mut self, len: usize) {
+        let mut buf = [0u8; 4096];
+        while self.received.len() < len {
+            let n = self.stream.read(&mut buf).unwrap();
+            assert!(n > 0, "server closed the connection");
+            self.received.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Next packet's type, JSON payload if it carries one, and size
+    fn receive(&mut self) -> (u8, Option<Value>, usize) {
+        self.fill(HEADER_SIZE);
+        assert_eq!(&self.received[..2], PROTOCOL_ID.to_be_bytes());
+        let packet_type = self.received[2];
+        let length = u16::from_be_bytes([self.received[LENGTH_OFFSET], self.received[LENGTH_OFFSET + 1]]);
+        let end = HEADER_SIZE + length as usize;
+        self.fill(end);
+
+        let checksum = u16::from_be_bytes([self.received[HEADER_SIZE - 2], self.received[HEADER_SIZE - 1]]);
+        let mut checked = self.received[..HEADER_SIZE - 2].to_vec();
+        checked.extend_from_slice(&self.received[HEADER_SIZE..end]);
+        assert_eq!(crc16(&checked), checksum, "bad checksum on {:#04x}", packet_type);
+
+        let payload: Vec<u8> = self.received.drain(..end).skip(HEADER_SIZE).collect();
+        let value = (!payload.is_empty()).then(|| serde_json::from_slice(&payload).expect("JSON payload"));
+        (packet_type, value, end)
+    }
+}
+
+fn metrics(port: u16) -> Option<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    Some(response)
+}
+
+fn metric(metrics: &str, name: &str) -> Option<u64> {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Wait until the exported metrics match the expected values
+fn expect_metrics(port: u16, expected: &[(&str, u64)]) {
+    eventually(&format!("metrics {:?}", expected), || {
+        let metrics = metrics(port)?;
+        expected
+            .iter()
+            .all(|(name, value)| metric(&metrics, name) == Some(*value))
+            .then_some(())
+    });
+}
+
+/// Server binary and a client over loopback: handshake, config push,
+/// acknowledged data and the stats the server exports for it
+///
+/// The data packets are empty, so nothing is written to the TUN device and
+/// delivery is checked up to the server's acknowledgement. The server opens
+/// its TUN device, so run as root with
+/// `cargo test --test loopback -- --ignored`.
+#[test]
+#[ignore = "spawns the server binary, binds local ports and opens a TUN device"]
+fn test_loopback_session() {
+    let port = free_port();
+    let metrics_port = free_port();
+    let mut server = ServerProcess::start(port, metrics_port);
+
+    let mut client = Client::connect(&mut server, port);
+    let client_random = [7u8; 32];
+    let client_hello = json!({"ClientHello": {
+        "client_random": client_random,
+        "protocol_version": 1,
+    }});
+    client.send(HANDSHAKE_INIT, 0, client_hello.to_string().as_bytes());
+
+    let (packet_type, server_hello, _) = client.receive();
+    assert_eq!(packet_type, HANDSHAKE_RESPONSE);
+    let server_hello = &server_hello.unwrap()["ServerHello"];
+    assert!(!server_hello["session_id"].as_str().unwrap().is_empty());
+    let tunnel_address: IpAddr = server_hello["tunnel_address"].as_str().unwrap().parse().unwrap();
+    assert!(tunnel_address.to_string().starts_with("10.77.0."));
+    assert_ne!(tunnel_address.to_string(), "10.77.0.1");
+
+    // The leased address and pushed settings follow right away
+    let (packet_type, config, config_size) = client.receive();
+    assert_eq!(packet_type, CONFIG_UPDATE);
+    let config = config.unwrap();
+    assert_eq!(config["tunnel_address"].as_str().unwrap(), tunnel_address.to_string());
+    assert_eq!(config["mtu"].as_u64().unwrap(), MTU);
+
+    const DATA_PACKETS: u64 = 5;
+    for sequence_number in 1..=DATA_PACKETS {
+        client.send(DATA, sequence_number, &[]);
+        assert_eq!(client.receive().0, ACK);
+    }
+    client.send(KEEPALIVE, DATA_PACKETS + 1, &[]);
+    assert_eq!(client.receive().0, KEEPALIVE);
+
+    let received = DATA_PACKETS + 1;
+    expect_metrics(
+        metrics_port,
+        &[
+            ("llp_sessions_active", 1),
+            ("llp_connections_total", 1),
+            ("llp_packets_received_total", received),
+            ("llp_bytes_received_total", received * HEADER_SIZE as u64),
+            ("llp_packets_sent_total", 1 + received),
+            ("llp_bytes_sent_total", config_size as u64 + received * HEADER_SIZE as u64),
+            ("llp_errors_total", 0),
+        ],
+    );
+
+    client.send(DISCONNECT, DATA_PACKETS + 2, &[]);
+    expect_metrics(metrics_port, &[("llp_sessions_active", 0), ("llp_connections_total", 1)]);
+}